@@ -0,0 +1,382 @@
+use crate::command_table::{self, COMMAND_TABLE, CommandFlags};
+use crate::glob::glob_match;
+use crate::modules::ModuleRegistry;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+
+/// Command categories usable in `+@category` / `-@category` rules
+pub const CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "string",
+    "list",
+    "set",
+    "sortedset",
+    "pubsub",
+    "admin",
+    "dangerous",
+    "blocking",
+    "scripting",
+    "transaction",
+    "connection",
+];
+
+/// Categories a command belongs to, from its flags and documentation group
+pub fn command_categories(flags: CommandFlags, group: &str) -> Vec<&'static str> {
+    let mut categories: Vec<&'static str> = [
+        (CommandFlags::READONLY, "read"),
+        (CommandFlags::WRITE, "write"),
+        (CommandFlags::ADMIN, "admin"),
+        (CommandFlags::ADMIN, "dangerous"),
+        (CommandFlags::PUBSUB, "pubsub"),
+        (CommandFlags::BLOCKING, "blocking"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, category)| category)
+    .collect();
+    let group = match group {
+        "generic" => Some("keyspace"),
+        "string" => Some("string"),
+        "list" => Some("list"),
+        "set" => Some("set"),
+        "sorted-set" => Some("sortedset"),
+        "scripting" => Some("scripting"),
+        "transactions" => Some("transaction"),
+        "connection" => Some("connection"),
+        _ => None,
+    };
+    categories.extend(group);
+    categories
+}
+
+/// An ACL user
+#[derive(Clone, Debug, Default)]
+pub struct User {
+    enabled: bool,
+    /// Any password is accepted
+    nopass: bool,
+    /// SHA-256 digests (hex) of the accepted passwords
+    passwords: BTreeSet<String>,
+    /// Glob patterns of the keys the user may access
+    keys: Vec<String>,
+    /// `+name`, `-name`, `+@category` and `-@category` rules in the order
+    /// given; the last rule matching a command decides whether it may run
+    commands: Vec<String>,
+}
+
+impl User {
+    /// The built-in `default` user, which can do everything
+    fn superuser() -> Self {
+        Self {
+            enabled: true,
+            nopass: true,
+            keys: vec!["*".to_string()],
+            commands: vec!["+@all".to_string()],
+            ..Self::default()
+        }
+    }
+
+    /// Apply one ACL SETUSER rule
+    fn apply(&mut self, rule: &str, modules: &ModuleRegistry) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".to_string()],
+            "resetkeys" => self.keys.clear(),
+            "allcommands" => self.commands = vec!["+@all".to_string()],
+            "nocommands" => self.commands = vec!["-@all".to_string()],
+            "reset" => {
+                *self = Self {
+                    commands: vec!["-@all".to_string()],
+                    ..Self::default()
+                }
+            }
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.passwords.insert(hash_password(password));
+                    self.nopass = false;
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    self.passwords.remove(&hash_password(password));
+                } else if let Some(hash) = rule.strip_prefix('#') {
+                    self.passwords.insert(parse_hash(hash)?);
+                    self.nopass = false;
+                } else if let Some(hash) = rule.strip_prefix('!') {
+                    self.passwords.remove(&parse_hash(hash)?);
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    if !self.keys.iter().any(|key| key == "*") {
+                        self.keys.push(pattern.to_string());
+                    }
+                } else if rule.starts_with(['+', '-']) {
+                    self.add_command_rule(rule, modules)?;
+                } else {
+                    return Err("Syntax error".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_command_rule(&mut self, rule: &str, modules: &ModuleRegistry) -> Result<(), String> {
+        let (sign, target) = rule.split_at(1);
+        let target = target.to_lowercase();
+        if target == "@all" {
+            self.commands.clear();
+        } else if let Some(category) = target.strip_prefix('@') {
+            if !CATEGORIES.contains(&category) {
+                return Err("Unknown command or category name in ACL".to_string());
+            }
+        } else {
+            let name = target.to_uppercase();
+            if command_table::lookup(&name).is_none() && modules.get(&name).is_none() {
+                return Err("Unknown command or category name in ACL".to_string());
+            }
+        }
+        // A rule for the same target replaces the earlier one
+        self.commands.retain(|existing| existing[1..] != target);
+        self.commands.push(format!("{}{}", sign, target));
+        Ok(())
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        self.nopass || self.passwords.contains(&hash_password(password))
+    }
+
+    fn can_run(&self, name: &str, categories: &[&str]) -> bool {
+        let name = name.to_lowercase();
+        let mut allowed = false;
+        for rule in &self.commands {
+            let (sign, target) = rule.split_at(1);
+            let matches = match target.strip_prefix('@') {
+                Some("all") => true,
+                Some(category) => categories.contains(&category),
+                None => target == name,
+            };
+            if matches {
+                allowed = sign == "+";
+            }
+        }
+        allowed
+    }
+
+    fn can_access(&self, key: &str) -> bool {
+        self.keys.iter().any(|pattern| glob_match(pattern, key))
+    }
+
+    fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    /// The user's rules as a single line, as shown by ACL LIST
+    fn describe(&self) -> String {
+        let mut parts: Vec<String> = self.flags().iter().map(|flag| flag.to_string()).collect();
+        parts.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        if self.keys.is_empty() {
+            parts.push("resetkeys".to_string());
+        }
+        parts.extend(self.keys.iter().map(|pattern| format!("~{}", pattern)));
+        parts.push(self.commands_rule());
+        parts.join(" ")
+    }
+
+    fn commands_rule(&self) -> String {
+        if self.commands.is_empty() {
+            "-@all".to_string()
+        } else {
+            self.commands.join(" ")
+        }
+    }
+
+    /// Field/value pairs describing the user (ACL GETUSER)
+    pub fn info(&self) -> Vec<(&'static str, UserField)> {
+        vec![
+            (
+                "flags",
+                UserField::List(self.flags().iter().map(|f| f.to_string()).collect()),
+            ),
+            (
+                "passwords",
+                UserField::List(self.passwords.iter().cloned().collect()),
+            ),
+            ("commands", UserField::Text(self.commands_rule())),
+            (
+                "keys",
+                UserField::Text(
+                    self.keys
+                        .iter()
+                        .map(|pattern| format!("~{}", pattern))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+            ),
+        ]
+    }
+}
+
+/// A value in ACL GETUSER's reply
+#[derive(Clone, Debug, PartialEq)]
+pub enum UserField {
+    Text(String),
+    List(Vec<String>),
+}
+
+fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_hash(hash: &str) -> Result<String, String> {
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters".to_string());
+    }
+    Ok(hash.to_lowercase())
+}
+
+/// ACL users by name, always including `default`
+#[derive(Clone)]
+pub struct AclRegistry {
+    users: Arc<RwLock<BTreeMap<String, User>>>,
+    /// Resolves module commands in rules and permission checks
+    modules: ModuleRegistry,
+}
+
+impl AclRegistry {
+    pub fn new(modules: ModuleRegistry) -> Self {
+        let users = BTreeMap::from([("default".to_string(), User::superuser())]);
+        Self {
+            users: Arc::new(RwLock::new(users)),
+            modules,
+        }
+    }
+
+    /// Create or modify a user (ACL SETUSER). New users start disabled with
+    /// no passwords, keys or commands. Either every rule applies or none does
+    pub fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(|| User {
+            commands: vec!["-@all".to_string()],
+            ..User::default()
+        });
+        for rule in rules {
+            user.apply(rule, &self.modules).map_err(|reason| {
+                format!("ERR Error in ACL SETUSER modifier '{}': {}", rule, reason)
+            })?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    pub fn get_user(&self, name: &str) -> Option<User> {
+        self.users.read().unwrap().get(name).cloned()
+    }
+
+    /// Remove users (ACL DELUSER); returns how many existed
+    pub fn delete_users(&self, names: &[String]) -> Result<usize, String> {
+        if names.iter().any(|name| name == "default") {
+            return Err("ERR The 'default' user cannot be removed".to_string());
+        }
+        let mut users = self.users.write().unwrap();
+        Ok(names
+            .iter()
+            .filter(|name| users.remove(name.as_str()).is_some())
+            .count())
+    }
+
+    /// User names, sorted
+    pub fn usernames(&self) -> Vec<String> {
+        self.users.read().unwrap().keys().cloned().collect()
+    }
+
+    /// One `user <name> <rules>` line per user (ACL LIST)
+    pub fn list(&self) -> Vec<String> {
+        self.users
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, user)| format!("user {} {}", name, user.describe()))
+            .collect()
+    }
+
+    /// Whether `password` logs in as `name`. `requirepass`, when set, is the
+    /// `default` user's password and replaces its `nopass` flag
+    pub fn authenticate(&self, name: &str, password: &str, requirepass: &str) -> bool {
+        let users = self.users.read().unwrap();
+        let Some(user) = users.get(name).filter(|user| user.enabled) else {
+            return false;
+        };
+        if name == "default" && !requirepass.is_empty() {
+            return password == requirepass || user.passwords.contains(&hash_password(password));
+        }
+        user.check_password(password)
+    }
+
+    /// Whether new connections are logged in as `default` without AUTH
+    pub fn default_login(&self, requirepass: &str) -> bool {
+        let users = self.users.read().unwrap();
+        requirepass.is_empty()
+            && users
+                .get("default")
+                .is_some_and(|user| user.enabled && user.nopass)
+    }
+
+    /// Check that user `name` may run a command with `args` (starting with
+    /// the command name) and access every key it touches
+    pub fn check(&self, name: &str, args: &[&str]) -> Result<(), String> {
+        let users = self.users.read().unwrap();
+        let Some(user) = users.get(name).filter(|user| user.enabled) else {
+            return Err(format!("NOPERM User {} is disabled or was deleted", name));
+        };
+        let command = args.first().copied().unwrap_or_default();
+        let (categories, keys) = match command_table::lookup(&command.to_uppercase()) {
+            Some(spec) => (command_categories(spec.flags, spec.group), spec.keys(args)),
+            None => match self.modules.get(&command.to_uppercase()) {
+                Some(module) => (command_categories(module.flags(), "module"), Vec::new()),
+                None => return Ok(()),
+            },
+        };
+        if !user.can_run(command, &categories) {
+            return Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                name,
+                command.to_lowercase()
+            ));
+        }
+        if !keys.iter().all(|key| user.can_access(key)) {
+            return Err("NOPERM No permissions to access a key".to_string());
+        }
+        Ok(())
+    }
+
+    /// Lower-case names of the built-in commands in `category` (ACL CAT)
+    pub fn category_commands(category: &str) -> Option<Vec<String>> {
+        let category = category.to_lowercase();
+        if !CATEGORIES.contains(&category.as_str()) {
+            return None;
+        }
+        Some(
+            COMMAND_TABLE
+                .iter()
+                .filter(|spec| {
+                    command_categories(spec.flags, spec.group).contains(&category.as_str())
+                })
+                .map(|spec| spec.name.to_lowercase())
+                .collect(),
+        )
+    }
+}
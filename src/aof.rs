@@ -1,185 +1,931 @@
-use crate::protocol::RespValue;
+use crate::commands::handle_command;
+use crate::config::{AppendFsync, ConfigValues, RdbCompression, ServerConfig};
+use crate::connection::ConnectionContext;
+use crate::latency::{self, LatencyMonitor};
+use crate::persistance;
+use crate::protocol::{BulkStr, Decoded, RespDecoder, RespValue};
+use crate::replication::Replication;
+use crate::storage::{DataType, FerroStore};
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::mpsc;
-use tokio::time::{Duration, interval};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Duration, Instant, interval};
+
+/// Where an AOF lives: a directory (`appenddirname`) holding a manifest and
+/// the files it lists, all named after `appendfilename`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AofPaths {
+    pub dir: PathBuf,
+    pub name: String,
+}
+
+impl AofPaths {
+    pub fn new(dir: impl Into<PathBuf>, name: &str) -> Self {
+        Self {
+            dir: dir.into(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn manifest(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.name))
+    }
+
+    /// Path of a file listed in the manifest
+    pub fn file(&self, file: &AofFile) -> PathBuf {
+        self.dir.join(&file.name)
+    }
+
+    /// The single-file AOF of earlier versions, next to the directory
+    pub fn legacy(&self) -> PathBuf {
+        self.dir.parent().unwrap_or(Path::new("")).join(&self.name)
+    }
+
+    /// Where a rewrite writes the next base file
+    fn temp(&self) -> PathBuf {
+        self.dir.join(format!("temp-rewrite-{}", self.name))
+    }
+
+    fn base(&self, seq: u64, format: BaseFormat) -> AofFile {
+        AofFile {
+            name: format!("{}.{}.base.{}", self.name, seq, format.extension()),
+            seq,
+        }
+    }
+
+    fn incr(&self, seq: u64) -> AofFile {
+        AofFile {
+            name: format!("{}.{}.incr.aof", self.name, seq),
+            seq,
+        }
+    }
+}
+
+/// A file listed in a manifest, with its sequence number among files of
+/// its type
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+}
+
+/// The files making up an AOF, replayed in order: the base, a rewrite of
+/// the dataset, then incremental files holding the commands logged since.
+/// Commands are appended to the last incremental file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub base: Option<AofFile>,
+    pub incrs: Vec<AofFile>,
+}
+
+impl Manifest {
+    /// Parse a manifest's `file <name> seq <n> type <b|i>` lines
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid AOF manifest line: {}", line),
+            )
+        };
+        let mut manifest = Manifest::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ["file", name, "seq", seq, "type", kind] = fields[..] else {
+                return Err(invalid(line));
+            };
+            let file = AofFile {
+                name: name.to_string(),
+                seq: seq.parse().map_err(|_| invalid(line))?,
+            };
+            match kind {
+                "b" if manifest.base.is_none() => manifest.base = Some(file),
+                "i" => manifest.incrs.push(file),
+                _ => return Err(invalid(line)),
+            }
+        }
+        manifest.incrs.sort_by_key(|file| file.seq);
+        Ok(manifest)
+    }
+
+    pub fn encode(&self) -> String {
+        let mut text = String::new();
+        if let Some(base) = &self.base {
+            text += &format!("file {} seq {} type b\n", base.name, base.seq);
+        }
+        for incr in &self.incrs {
+            text += &format!("file {} seq {} type i\n", incr.name, incr.seq);
+        }
+        text
+    }
+
+    /// The manifest at `paths`, or `None` if there isn't one yet
+    pub async fn read(paths: &AofPaths) -> io::Result<Option<Self>> {
+        match tokio::fs::read_to_string(paths.manifest()).await {
+            Ok(text) => Self::parse(&text).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the manifest at `paths` with this one, atomically
+    async fn write(&self, paths: &AofPaths) -> io::Result<()> {
+        let manifest = paths.manifest();
+        let temp = manifest.with_extension("manifest.tmp");
+        let mut file = tokio::fs::File::create(&temp).await?;
+        file.write_all(self.encode().as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temp, &manifest).await
+    }
+
+    /// Files in replay order
+    pub fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(&self.incrs)
+    }
+
+    /// Sequence number for a new incremental file
+    fn next_incr_seq(&self) -> u64 {
+        let last = self.incrs.last().map_or(0, |file| file.seq);
+        let base = self.base.as_ref().map_or(0, |file| file.seq);
+        last.max(base) + 1
+    }
+
+    /// Switch to the base file written at `paths.temp()`, followed by the
+    /// incremental files from `first_incr` on, and delete the files it
+    /// replaces, a single-file AOF of an earlier version included
+    async fn install_base(
+        &self,
+        paths: &AofPaths,
+        format: BaseFormat,
+        first_incr: usize,
+    ) -> io::Result<Self> {
+        let seq = self.base.as_ref().map_or(0, |file| file.seq) + 1;
+        let base = paths.base(seq, format);
+        tokio::fs::rename(paths.temp(), paths.file(&base)).await?;
+        let installed = Manifest {
+            base: Some(base),
+            incrs: self.incrs[first_incr..].to_vec(),
+        };
+        installed.write(paths).await?;
+        let replaced = self
+            .files()
+            .filter(|file| !installed.incrs.contains(file))
+            .map(|file| paths.file(file));
+        for old in replaced.chain([paths.legacy()]) {
+            match tokio::fs::remove_file(&old).await {
+                // An incremental file nothing was logged to was never created
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    eprintln!("Can't remove old AOF file {}: {}", old.display(), e);
+                }
+                _ => {}
+            }
+        }
+        Ok(installed)
+    }
+}
+
 #[derive(Clone)]
 pub struct AofWriter {
-    sender: mpsc::UnboundedSender<String>,
+    sender: mpsc::UnboundedSender<AofMessage>,
+    paths: Arc<AofPaths>,
+    /// Whether commands are logged (`appendonly`)
+    enabled: Arc<AtomicBool>,
+    /// Set from `start_rewrite` until the rewrite is installed or abandoned
+    rewriting: Arc<AtomicBool>,
+    /// Sent every logged command, whether or not the AOF is enabled
+    replication: Replication,
+}
+
+/// What the writer task is asked to do
+enum AofMessage {
+    /// Append a command run against a database
+    Command(usize, String),
+    /// Append to a new incremental file from now on, the first one a
+    /// rewrite's base will be followed by
+    StartRewrite,
+    /// Install the base file the rewrite has written
+    FinishRewrite(BaseFormat, oneshot::Sender<io::Result<()>>),
+    /// Forget a rewrite that failed
+    AbortRewrite,
+    /// Start appending to a new incremental file, and rewrite from there
+    Enable,
+    /// Stop appending, syncing what was logged
+    Disable,
 }
 
 pub struct AofHandle {
-    receiver: mpsc::UnboundedReceiver<String>,
-    path: String,
+    receiver: mpsc::UnboundedReceiver<AofMessage>,
+    paths: Arc<AofPaths>,
+    /// Cleared if enabling fails
+    enabled: Arc<AtomicBool>,
+    /// Read for the appendfsync policy, which can change at runtime
+    config: ServerConfig,
+    /// Records slow flushes
+    latency: LatencyMonitor,
+}
+
+/// The writer task's view of the AOF
+#[derive(Default)]
+struct AofState {
+    /// Read once the AOF is first opened or rewritten
+    manifest: Option<Manifest>,
+    /// The last incremental file, open while commands are logged
+    file: Option<tokio::fs::File>,
+    /// Commands not written to `file` yet
+    buffer: Vec<String>,
+    /// Database of the last logged command; a SELECT precedes the first
+    /// command of each file and every change of database
+    current_db: Option<usize>,
+    /// Index in the manifest's incremental files of the first one a
+    /// rewrite's base will be followed by
+    rewrite_incr: Option<usize>,
 }
 
 impl AofWriter {
-    pub fn new(path: String) -> (Self, AofHandle) {
+    /// A writer for the AOF at `paths`, logging commands if `appendonly`
+    /// is set in `config`, and feeding them to `replication`
+    pub fn new(
+        paths: AofPaths,
+        config: ServerConfig,
+        latency: LatencyMonitor,
+        replication: Replication,
+    ) -> (Self, AofHandle) {
         let (sender, receiver) = mpsc::unbounded_channel();
-        let handle = AofHandle { receiver, path };
-        (AofWriter { sender }, handle)
+        let paths = Arc::new(paths);
+        let enabled = Arc::new(AtomicBool::new(config.read().appendonly));
+        let writer = AofWriter {
+            sender,
+            paths: paths.clone(),
+            enabled: enabled.clone(),
+            rewriting: Arc::new(AtomicBool::new(false)),
+            replication,
+        };
+        let handle = AofHandle {
+            receiver,
+            paths,
+            enabled,
+            config,
+            latency,
+        };
+        (writer, handle)
     }
 
-    pub fn log_command(&self, command: &RespValue) {
+    /// Log a write command run against database `db`, if the AOF is
+    /// enabled, and send it to the replicas
+    pub fn log_command(&self, db: usize, command: &RespValue) {
+        self.replication.feed(db, command);
+        if !self.is_enabled() {
+            return;
+        }
         let encoded = command.encode();
-        let _ = self.sender.send(encoded);
+        let _ = self.sender.send(AofMessage::Command(db, encoded));
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Start logging commands (CONFIG SET appendonly yes) to a new
+    /// incremental file, seeding the AOF with a rewrite: as for
+    /// `start_rewrite`, the dataset should be captured with no command
+    /// running and passed to `finish_rewrite`. False, leaving the AOF
+    /// disabled, if a rewrite is already in progress
+    pub fn enable(&self) -> bool {
+        if self.rewriting.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.enabled.store(true, Ordering::Release);
+        let _ = self.sender.send(AofMessage::Enable);
+        true
+    }
+
+    /// Stop logging commands (CONFIG SET appendonly no), once those logged
+    /// so far are synced to disk
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Release);
+        let _ = self.sender.send(AofMessage::Disable);
+    }
+
+    /// Begin a rewrite (BGREWRITEAOF): commands logged from now on go to a
+    /// new incremental file, so the dataset should be captured with no
+    /// command running. While the AOF is disabled the new base replaces all
+    /// of it. False if a rewrite is already in progress
+    pub fn start_rewrite(&self) -> bool {
+        if self.rewriting.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        let _ = self.sender.send(AofMessage::StartRewrite);
+        true
+    }
+
+    /// Complete the rewrite begun by `start_rewrite`: write `databases`, as
+    /// captured then, as the new base file in `format` and have the writer
+    /// task list it in the manifest in place of the files it replaces
+    pub async fn finish_rewrite(
+        &self,
+        databases: Vec<(usize, DatabaseData)>,
+        format: BaseFormat,
+    ) -> io::Result<()> {
+        let result = async {
+            tokio::fs::create_dir_all(&self.paths.dir).await?;
+            write_base(databases, format, &self.paths.temp()).await?;
+            let (done, installed) = oneshot::channel();
+            self.sender
+                .send(AofMessage::FinishRewrite(format, done))
+                .map_err(|_| io::Error::other("AOF writer has stopped"))?;
+            installed
+                .await
+                .map_err(|_| io::Error::other("AOF writer has stopped"))?
+        }
+        .await;
+        if result.is_err() {
+            let _ = self.sender.send(AofMessage::AbortRewrite);
+        }
+        self.rewriting.store(false, Ordering::Release);
+        result
     }
 }
 
 impl AofHandle {
     pub async fn run(mut self) -> io::Result<()> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.path)
-            .await?;
-        let mut buffer: Vec<String> = Vec::new();
+        let mut state = AofState::default();
+        if self.enabled.load(Ordering::Acquire) {
+            self.open(&mut state, false).await?;
+        }
         let mut sync_interval = interval(Duration::from_secs(1));
 
         loop {
             tokio::select! {
 
-                Some(command) = self.receiver.recv() => {
-
-                    buffer.push(command);
-                }
-                _=sync_interval.tick() => {
-                    if !buffer.is_empty() {
-
-                        for cmd in buffer.drain(..) {
-                            file.write_all(cmd.as_bytes()).await?;
+                Some(message) = self.receiver.recv() => match message {
+                    // Dropped if logged just before the AOF was disabled
+                    AofMessage::Command(db, command) if state.file.is_some() => {
+                        if state.current_db != Some(db) {
+                            state.buffer.push(select_command(db).encode());
+                            state.current_db = Some(db);
+                        }
+                        state.buffer.push(command);
+                        let fsync = self.config.read().appendfsync;
+                        if fsync == AppendFsync::Always {
+                            self.flush(&mut state, true).await?;
                         }
-                        file.sync_data().await?;
-                        println!("AOF Flushed and synced to disk");
+                    }
+                    AofMessage::Command(..) => {}
+                    AofMessage::StartRewrite => {
+                        if let Err(e) = self.start_rewrite(&mut state).await {
+                            // The rewrite fails when it comes to finish
+                            eprintln!("Can't start the AOF rewrite: {}", e);
+                        }
+                    }
+                    AofMessage::FinishRewrite(format, done) => {
+                        let installed = match (state.rewrite_incr.take(), &state.manifest) {
+                            (Some(first), Some(manifest)) => {
+                                manifest.install_base(&self.paths, format, first).await
+                            }
+                            _ => Err(io::Error::other("no new AOF file was opened")),
+                        };
+                        let reply = installed.map(|installed| state.manifest = Some(installed));
+                        let _ = done.send(reply);
+                    }
+                    AofMessage::AbortRewrite => state.rewrite_incr = None,
+                    AofMessage::Enable => match self.open(&mut state, true).await {
+                        Ok(()) => state.rewrite_incr = Some(last_incr(&state)),
+                        Err(e) => {
+                            eprintln!("Can't enable AOF: {}", e);
+                            self.enabled.store(false, Ordering::Release);
+                        }
+                    },
+                    AofMessage::Disable => {
+                        self.flush(&mut state, true).await?;
+                        state.file = None;
+                        println!("AOF disabled");
+                    }
+                },
+                _=sync_interval.tick() => {
+                    if !state.buffer.is_empty() {
+                        let fsync = self.config.read().appendfsync;
+                        self.flush(&mut state, fsync != AppendFsync::No).await?;
+                        println!("AOF Flushed to disk");
                     }
                 }
             }
         }
     }
+
+    /// Start appending to the AOF's last incremental file, or to a new one
+    /// if `new_incr` is set or there is none
+    async fn open(&self, state: &mut AofState, new_incr: bool) -> io::Result<()> {
+        let mut manifest = load_manifest(&self.paths).await?;
+        if new_incr || manifest.incrs.is_empty() {
+            manifest
+                .incrs
+                .push(self.paths.incr(manifest.next_incr_seq()));
+            manifest.write(&self.paths).await?;
+        }
+        state.file = Some(open_incr(&self.paths, &manifest).await?);
+        state.manifest = Some(manifest);
+        state.current_db = None;
+        Ok(())
+    }
+
+    /// Note where the base a rewrite is writing will go: before a new
+    /// incremental file if commands are being logged, in place of all the
+    /// files otherwise
+    async fn start_rewrite(&self, state: &mut AofState) -> io::Result<()> {
+        if state.file.is_some() {
+            self.flush(state, true).await?;
+            self.open(state, true).await?;
+            state.rewrite_incr = Some(last_incr(state));
+        } else {
+            let manifest = match state.manifest.take() {
+                Some(manifest) => manifest,
+                None => {
+                    tokio::fs::create_dir_all(&self.paths.dir).await?;
+                    Manifest::read(&self.paths).await?.unwrap_or_default()
+                }
+            };
+            state.rewrite_incr = Some(manifest.incrs.len());
+            state.manifest = Some(manifest);
+        }
+        Ok(())
+    }
+
+    /// Write the buffered commands, syncing them to disk if `sync` is set
+    async fn flush(&self, state: &mut AofState, sync: bool) -> io::Result<()> {
+        let Some(file) = state.file.as_mut() else {
+            return Ok(());
+        };
+        let started = Instant::now();
+        for cmd in state.buffer.drain(..) {
+            file.write_all(cmd.as_bytes()).await?;
+        }
+        if sync {
+            file.sync_data().await?;
+        }
+        let threshold = self.config.read().latency_monitor_threshold;
+        self.latency
+            .record(latency::AOF_FSYNC, started.elapsed(), threshold);
+        Ok(())
+    }
+}
+
+/// Index of the last incremental file of an open AOF
+fn last_incr(state: &AofState) -> usize {
+    state
+        .manifest
+        .as_ref()
+        .map_or(0, |manifest| manifest.incrs.len() - 1)
+}
+
+/// Read the manifest, creating the directory if need be; without a
+/// manifest, a single-file AOF from an earlier version becomes the base
+async fn load_manifest(paths: &AofPaths) -> io::Result<Manifest> {
+    tokio::fs::create_dir_all(&paths.dir).await?;
+    if let Some(manifest) = Manifest::read(paths).await? {
+        return Ok(manifest);
+    }
+    let mut manifest = Manifest::default();
+    if tokio::fs::try_exists(paths.legacy()).await? {
+        // Linked first, so the AOF is found whenever the process stops; a
+        // link left by an earlier attempt isn't listed anywhere yet
+        let base = paths.base(1, BaseFormat::Commands);
+        let _ = tokio::fs::remove_file(paths.file(&base)).await;
+        tokio::fs::hard_link(paths.legacy(), paths.file(&base)).await?;
+        manifest.base = Some(base);
+        manifest.write(paths).await?;
+        tokio::fs::remove_file(paths.legacy()).await?;
+        println!(
+            "Moved {} into {} as its base",
+            paths.name,
+            paths.dir.display()
+        );
+    }
+    Ok(manifest)
+}
+
+/// Open the manifest's last incremental file for appending
+async fn open_incr(paths: &AofPaths, manifest: &Manifest) -> io::Result<tokio::fs::File> {
+    let incr = manifest
+        .incrs
+        .last()
+        .expect("manifest without an incremental file");
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(paths.file(incr))
+        .await
 }
 
-pub async fn load_aof<F>(path: &str, mut replay_fn: F) -> io::Result<usize>
+fn select_command(db: usize) -> RespValue {
+    RespValue::Array(vec![
+        RespValue::BulkString("SELECT".into()),
+        RespValue::BulkString(db.to_string().into()),
+    ])
+}
+
+/// What an AOF file holds: commands, or for an RDB base, keys
+enum AofEntry {
+    Command(RespValue),
+    /// Database index, key, value and expiry as a Unix time in milliseconds
+    Key(usize, String, DataType, Option<u64>),
+}
+
+/// Replay the AOF at `paths`: the files its manifest lists, in order, or
+/// the single file of an earlier version if there is no manifest yet.
+/// Keys of an RDB base are passed as the commands a rewrite would recreate
+/// them with. Returns the number of commands read
+///
+/// A command cut short at the end of the last file, as a crash while it was
+/// appended leaves it, is truncated away if `load_truncated` is set (the
+/// `aof-load-truncated` parameter) and an error otherwise.
+pub async fn load_aof<F>(
+    paths: &AofPaths,
+    load_truncated: bool,
+    mut replay_fn: F,
+) -> io::Result<usize>
 where
     F: FnMut(RespValue),
 {
-    let file = match tokio::fs::File::open(path).await {
-        Ok(f) => f,
-        Err(e) if e.kind() == io::ErrorKind::NotFound => {
-            println!("No AOF file found at {}", path);
-            return Ok(0);
+    let mut command_count = 0;
+    let mut current_db = None;
+    read_aof(paths, load_truncated, &mut |entry| {
+        let commands = match entry {
+            AofEntry::Command(command) => vec![command],
+            AofEntry::Key(db, key, data, expiry) => {
+                let mut commands = Vec::new();
+                if current_db != Some(db) {
+                    commands.push(select_command(db));
+                    current_db = Some(db);
+                }
+                commands.extend(key_commands(key, data, expiry));
+                commands
+            }
+        };
+        command_count += commands.len();
+        commands.into_iter().for_each(&mut replay_fn);
+        Ok(())
+    })
+    .await?;
+    Ok(command_count)
+}
+
+/// Load the AOF at `paths` into `store`: the keys of an RDB base straight
+/// into their databases, then the commands, run one after the other in the
+/// order they were logged, without logging them back, as one connection
+/// that follows the log's SELECTs. Returns the number of keys and commands
+/// read
+pub async fn replay_aof(
+    paths: &AofPaths,
+    load_truncated: bool,
+    store: &FerroStore,
+) -> io::Result<usize> {
+    let mut count = 0;
+    let mut commands = Vec::new();
+    let mut base = store.clone();
+    read_aof(paths, load_truncated, &mut |entry| {
+        count += 1;
+        match entry {
+            AofEntry::Command(command) => commands.push(command),
+            AofEntry::Key(db, key, data, expiry) => {
+                if base.db_index() != db {
+                    base = store
+                        .database(db)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                }
+                base.load_entry(key, data, expiry);
+            }
         }
-        Err(e) => return Err(e),
-    };
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+        Ok(())
+    })
+    .await?;
+    let mut conn = ConnectionContext::new();
+    for cmd in commands {
+        handle_command(cmd, store, None, Some(&mut conn)).await;
+    }
+    Ok(count)
+}
 
-    let mut command_count = 0;
-    let mut buffer = String::new();
-    while let Some(line) = lines.next_line().await? {
-        buffer.push_str(&line);
-        buffer.push_str("\r\n");
-        if let Ok(command) = crate::protocol::parse_resp(&buffer) {
-            replay_fn(command);
-            command_count += 1;
-            buffer.clear();
+/// Read the files of the AOF at `paths` in order, passing what they hold
+/// to `visit`
+async fn read_aof(
+    paths: &AofPaths,
+    load_truncated: bool,
+    visit: &mut impl FnMut(AofEntry) -> io::Result<()>,
+) -> io::Result<()> {
+    let files = match Manifest::read(paths).await? {
+        Some(manifest) => manifest.files().map(|file| paths.file(file)).collect(),
+        None if tokio::fs::try_exists(paths.legacy()).await? => vec![paths.legacy()],
+        None => {
+            println!("No AOF found in {}", paths.dir.display());
+            return Ok(());
         }
+    };
+    for (i, path) in files.iter().enumerate() {
+        let last = i == files.len() - 1;
+        let truncate = last && load_truncated;
+        read_aof_file(path, last, truncate, visit).await?;
     }
-    Ok(command_count)
+    Ok(())
 }
 
-pub async fn rewrite_aof(
-    current_data: Vec<(
-        String,
-        crate::storage::DataType,
-        Option<std::time::Duration>,
-    )>,
-    path: &str,
+/// Read one file of an AOF, an RDB file or commands, truncating a command
+/// cut short at its end if `truncate` is set. A missing file is only fine
+/// if it is the `last`
+async fn read_aof_file(
+    path: &Path,
+    last: bool,
+    truncate: bool,
+    visit: &mut impl FnMut(AofEntry) -> io::Result<()>,
 ) -> io::Result<()> {
-    let temp_path = format!("{}.tmp", path);
-    let mut file = tokio::fs::File::create(&temp_path).await?;
-    for (key, data, ttl) in current_data {
-        match data {
-            crate::storage::DataType::String(value) => {
-                let cmd = if let Some(ttl_duration) = ttl {
-                    RespValue::Array(vec![
-                        RespValue::BulkString("SETEX".to_string()),
-                        RespValue::BulkString(key),
-                        RespValue::BulkString(ttl_duration.as_secs().to_string()),
-                        RespValue::BulkString(value),
-                    ])
-                } else {
-                    RespValue::Array(vec![
-                        RespValue::BulkString("SET".to_string()),
-                        RespValue::BulkString(key),
-                        RespValue::BulkString(value),
-                    ])
-                };
-                file.write_all(cmd.encode().as_bytes()).await?;
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(f) => f,
+        // Created by the writer only once the manifest lists it
+        Err(e) if last && e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("Can't open AOF file {}: {}", path.display(), e),
+            ));
+        }
+    };
+    let mut decoder = RespDecoder::new();
+    let mut chunk = vec![0u8; 64 * 1024];
+
+    let mut command_count = 0;
+    // Bytes read, and those up to the end of the last complete command
+    let mut read = 0;
+    let mut complete = 0;
+    loop {
+        let n = file.read(&mut chunk).await?;
+        if read == 0 && persistance::is_rdb(&chunk[..n]) {
+            let mut contents = chunk[..n].to_vec();
+            file.read_to_end(&mut contents).await?;
+            persistance::read_rdb(&contents, |db, key, data, expiry| {
+                visit(AofEntry::Key(db, key, data, expiry))
+            })
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("Bad RDB in {}: {}", path.display(), e))
+            })?;
+            return Ok(());
+        }
+        if n == 0 {
+            if complete == read {
+                return Ok(());
             }
-            crate::storage::DataType::List(list) => {
-                if !list.is_empty() {
-                    let mut cmd_parts = vec![
-                        RespValue::BulkString("RPUSH".to_string()),
-                        RespValue::BulkString(key.clone()),
-                    ];
-                    for item in list {
-                        cmd_parts.push(RespValue::BulkString(item));
-                    }
-                    let cmd = RespValue::Array(cmd_parts);
-                    file.write_all(cmd.encode().as_bytes()).await?;
-                }
-                write_ttl(&mut file, &key, ttl).await?;
+            if !truncate {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "AOF file {} ends in a command cut short after {} commands; set \
+                         aof-load-truncated yes or run ferrodb-check-aof --fix to load it",
+                        path.display(),
+                        command_count
+                    ),
+                ));
             }
-            crate::storage::DataType::Set(set) => {
-                if !set.is_empty() {
-                    let mut cmd_parts = vec![
-                        RespValue::BulkString("SADD".to_string()),
-                        RespValue::BulkString(key.clone()),
-                    ];
-                    for member in set {
-                        cmd_parts.push(RespValue::BulkString(member));
-                    }
-                    let cmd = RespValue::Array(cmd_parts);
-                    file.write_all(cmd.encode().as_bytes()).await?;
+            // Commands appended from now on must not follow the partial one
+            eprintln!(
+                "AOF file {} ends in a command cut short; truncating it from {} to {} bytes",
+                path.display(),
+                read,
+                complete
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .await?
+                .set_len(complete as u64)
+                .await?;
+            return Ok(());
+        }
+        read += n;
+        decoder.extend(&chunk[..n]);
+        loop {
+            match decoder.decode() {
+                Decoded::Frame(command) => {
+                    visit(AofEntry::Command(command))?;
+                    command_count += 1;
+                    complete = read - decoder.buffered();
+                }
+                Decoded::NeedMoreData => break,
+                Decoded::Error(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Bad AOF format in {} after {} commands: {}; ferrodb-check-aof \
+                             --fix truncates the file to the commands before",
+                            path.display(),
+                            command_count,
+                            e
+                        ),
+                    ));
                 }
-                write_ttl(&mut file, &key, ttl).await?;
             }
-            crate::storage::DataType::SortedSet(zset) => {
-                if !zset.is_empty() {
-                    let mut cmd_parts = vec![
-                        RespValue::BulkString("ZADD".to_string()),
-                        RespValue::BulkString(key.clone()),
-                    ];
-                    for (member, score) in &zset.members {
-                        cmd_parts.push(RespValue::BulkString(score.0.to_string()));
-                        cmd_parts.push(RespValue::BulkString(member.clone()));
-                    }
+        }
+    }
+}
+
+/// What `check_aof` found in an AOF
+#[derive(Debug, PartialEq, Eq)]
+pub struct AofCheck {
+    /// Well-formed commands at the start of the file
+    pub commands: usize,
+    /// Bytes those commands take; anything after them is damaged
+    pub valid_len: u64,
+    /// What is wrong after them, if anything
+    pub error: Option<String>,
+}
 
-                    let cmd = RespValue::Array(cmd_parts);
-                    file.write_all(cmd.encode().as_bytes()).await?;
-                    write_ttl(&mut file, &key, ttl).await?;
+/// Parse an AOF up to its first malformed or incomplete command
+/// (ferrodb-check-aof)
+pub fn check_aof(contents: &[u8]) -> AofCheck {
+    let mut decoder = RespDecoder::new();
+    decoder.extend(contents);
+    let mut check = AofCheck {
+        commands: 0,
+        valid_len: 0,
+        error: None,
+    };
+    loop {
+        match decoder.decode() {
+            Decoded::Frame(RespValue::Array(args))
+                if !args.is_empty()
+                    && args
+                        .iter()
+                        .all(|arg| matches!(arg, RespValue::BulkString(_))) =>
+            {
+                check.commands += 1;
+                check.valid_len = (contents.len() - decoder.buffered()) as u64;
+            }
+            Decoded::Frame(_) => {
+                check.error = Some("Expected an array of bulk strings".to_string());
+                break;
+            }
+            Decoded::NeedMoreData => {
+                if check.valid_len < contents.len() as u64 {
+                    check.error = Some("Unexpected end of file inside a command".to_string());
                 }
+                break;
+            }
+            Decoded::Error(e) => {
+                check.error = Some(e);
+                break;
             }
         }
     }
-    file.sync_all().await?;
-    drop(file);
-    tokio::fs::rename(&temp_path, path).await?;
+    check
+}
+
+/// Most collection items a rewritten command adds, so replaying a large
+/// key takes several commands rather than one enormous one
+const REWRITE_ITEMS_PER_COMMAND: usize = 64;
+
+/// Keys of one database: name, value and expiry as a Unix time in milliseconds
+pub type DatabaseData = Vec<(String, DataType, Option<u64>)>;
+
+/// How a rewrite writes the AOF's base file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BaseFormat {
+    /// Commands recreating the dataset
+    Commands,
+    /// An RDB file compressed with the given codec, quicker to write and
+    /// to load (`aof-use-rdb-preamble`)
+    Rdb(RdbCompression),
+}
+
+impl BaseFormat {
+    /// The format the configuration asks for
+    pub fn of(config: &ConfigValues) -> Self {
+        if config.aof_use_rdb_preamble {
+            BaseFormat::Rdb(config.rdbcompression)
+        } else {
+            BaseFormat::Commands
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            BaseFormat::Commands => "aof",
+            BaseFormat::Rdb(_) => "rdb",
+        }
+    }
+}
+
+/// Rewrite the AOF at `paths` as a base file recreating `databases`, given
+/// as (index, keys) pairs, replacing every file it had
+///
+/// Only for when nothing is being logged to it; a running server rewrites
+/// its AOF through `AofWriter::start_rewrite` instead.
+pub async fn rewrite_aof(
+    databases: Vec<(usize, DatabaseData)>,
+    paths: &AofPaths,
+    format: BaseFormat,
+) -> io::Result<()> {
+    tokio::fs::create_dir_all(&paths.dir).await?;
+    let manifest = Manifest::read(paths).await?.unwrap_or_default();
+    write_base(databases, format, &paths.temp()).await?;
+    manifest
+        .install_base(paths, format, manifest.incrs.len())
+        .await?;
     Ok(())
 }
-pub async fn write_ttl(
-    file: &mut tokio::fs::File,
-    key: &str,
-    ttl: Option<Duration>,
+
+/// Write a base file recreating `databases` at `path`
+async fn write_base(
+    databases: Vec<(usize, DatabaseData)>,
+    format: BaseFormat,
+    path: &Path,
 ) -> io::Result<()> {
-    if let Some(ttl_duration) = ttl {
-        let expire_cmd = RespValue::Array(vec![
-            RespValue::BulkString("EXPIRE".to_string()),
-            RespValue::BulkString(String::from(key)),
-            RespValue::BulkString(ttl_duration.as_secs().to_string()),
-        ]);
-        file.write_all(expire_cmd.encode().as_bytes()).await?;
+    match format {
+        BaseFormat::Commands => write_aof_file(databases, path).await,
+        BaseFormat::Rdb(codec) => persistance::write_rdb_data(&databases, codec, path).await,
+    }
+}
+
+/// Write commands recreating `databases` to a new file at `path`
+async fn write_aof_file(databases: Vec<(usize, DatabaseData)>, path: &Path) -> io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    for (db, current_data) in databases {
+        if current_data.is_empty() {
+            continue;
+        }
+        file.write_all(select_command(db).encode().as_bytes())
+            .await?;
+        for (key, data, expiry) in current_data {
+            for cmd in key_commands(key, data, expiry) {
+                file.write_all(cmd.encode().as_bytes()).await?;
+            }
+        }
     }
+    file.sync_all().await?;
     Ok(())
 }
+
+/// Commands recreating `key`, with its expiry
+fn key_commands(key: String, data: DataType, expiry: Option<u64>) -> Vec<RespValue> {
+    let mut commands = match data {
+        DataType::String(value) => {
+            let mut cmd_parts = vec![
+                RespValue::BulkString("SET".into()),
+                RespValue::BulkString(key.into()),
+                RespValue::BulkString(String::from(value).into()),
+            ];
+            if let Some(unix_ms) = expiry {
+                cmd_parts.push(RespValue::BulkString("PXAT".into()));
+                cmd_parts.push(RespValue::BulkString(unix_ms.to_string().into()));
+            }
+            return vec![RespValue::Array(cmd_parts)];
+        }
+        DataType::List(list) => {
+            let items = list.iter().map(|item| item.into()).collect();
+            chunked_commands("RPUSH", &key, items, 1)
+        }
+        DataType::Set(set) => {
+            let members = set.iter().map(|member| member.into()).collect();
+            chunked_commands("SADD", &key, members, 1)
+        }
+        DataType::SortedSet(zset) => {
+            let pairs = zset
+                .iter()
+                .flat_map(|(member, score)| [score.to_string().into(), member.into()])
+                .collect();
+            chunked_commands("ZADD", &key, pairs, 2)
+        }
+    };
+    commands.extend(expiry_command(key, expiry));
+    commands
+}
+
+/// `command key args...` for the items of a collection, `arity` arguments
+/// each, split over as many commands as it takes to keep each to
+/// `REWRITE_ITEMS_PER_COMMAND` items
+fn chunked_commands(command: &str, key: &str, args: Vec<BulkStr>, arity: usize) -> Vec<RespValue> {
+    args.chunks(REWRITE_ITEMS_PER_COMMAND * arity)
+        .map(|chunk| {
+            let mut cmd_parts = Vec::with_capacity(chunk.len() + 2);
+            cmd_parts.push(RespValue::BulkString(command.into()));
+            cmd_parts.push(RespValue::BulkString(key.into()));
+            cmd_parts.extend(chunk.iter().cloned().map(RespValue::BulkString));
+            RespValue::Array(cmd_parts)
+        })
+        .collect()
+}
+
+/// A PEXPIREAT restoring `key`'s expiry, given as a Unix time in
+/// milliseconds, if it has one
+fn expiry_command(key: String, expiry: Option<u64>) -> Option<RespValue> {
+    expiry.map(|unix_ms| {
+        RespValue::Array(vec![
+            RespValue::BulkString("PEXPIREAT".into()),
+            RespValue::BulkString(key.into()),
+            RespValue::BulkString(unix_ms.to_string().into()),
+        ])
+    })
+}
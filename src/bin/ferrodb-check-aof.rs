@@ -0,0 +1,71 @@
+use FerroDB::aof::check_aof;
+use clap::Parser;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Check an append-only file offline, reporting where the first malformed
+/// or incomplete command starts
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Truncate the file to the commands before the damage
+    #[arg(long)]
+    fix: bool,
+    /// AOF to check
+    file: PathBuf,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let contents = match std::fs::read(&cli.file) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", cli.file.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let check = check_aof(&contents);
+    let size = contents.len() as u64;
+    println!(
+        "AOF analyzed: size={}, ok_up_to={}, ok_up_to_commands={}, diff={}",
+        size,
+        check.valid_len,
+        check.commands,
+        size - check.valid_len
+    );
+    let Some(error) = check.error else {
+        println!("AOF is valid");
+        return ExitCode::SUCCESS;
+    };
+    println!("0x{:x}: {}", check.valid_len, error);
+    if !cli.fix {
+        println!("AOF is not valid. Use the --fix option to try fixing it.");
+        return ExitCode::FAILURE;
+    }
+
+    println!(
+        "Shrinking the AOF from {} bytes, dropping {} bytes, to {} bytes",
+        size,
+        size - check.valid_len,
+        check.valid_len
+    );
+    let truncated = OpenOptions::new()
+        .write(true)
+        .open(&cli.file)
+        .and_then(|file| {
+            file.set_len(check.valid_len)?;
+            file.sync_all()
+        });
+    match truncated {
+        Ok(()) => {
+            println!("Successfully truncated AOF");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to truncate AOF: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
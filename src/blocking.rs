@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Registry of clients blocked on keys (BLPOP, BLMOVE, ...)
+///
+/// A blocked client registers a `Notify` under every key it waits on, and
+/// writers call `notify` after pushing to a key. `Notify` keeps a permit when
+/// nobody is currently awaiting it, so a push landing between a client's
+/// failed attempt and its `wait()` is never lost.
+#[derive(Clone, Default)]
+pub struct KeyWaiters {
+    waiters: Arc<Mutex<HashMap<String, Vec<Arc<Notify>>>>>,
+}
+
+impl KeyWaiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register interest in `keys` until the returned guard is dropped
+    pub fn register(&self, keys: &[String]) -> WaitGuard {
+        let notify = Arc::new(Notify::new());
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            waiters
+                .entry(key.clone())
+                .or_default()
+                .push(Arc::clone(&notify));
+        }
+
+        WaitGuard {
+            registry: self.clone(),
+            keys: keys.to_vec(),
+            notify,
+        }
+    }
+
+    /// Wake every client blocked on `key` so it can retry
+    pub fn notify(&self, key: &str) {
+        let waiters = self.waiters.lock().unwrap();
+        if let Some(list) = waiters.get(key) {
+            for notify in list {
+                notify.notify_one();
+            }
+        }
+    }
+
+    /// Number of clients currently blocked on `key`
+    pub fn num_waiters(&self, key: &str) -> usize {
+        let waiters = self.waiters.lock().unwrap();
+        waiters.get(key).map_or(0, Vec::len)
+    }
+}
+
+/// A client's registration in `KeyWaiters`; unregisters on drop
+pub struct WaitGuard {
+    registry: KeyWaiters,
+    keys: Vec<String>,
+    notify: Arc<Notify>,
+}
+
+impl WaitGuard {
+    /// Wait until one of the registered keys is written to
+    pub async fn wait(&self) {
+        self.notify.notified().await;
+    }
+}
+
+impl Drop for WaitGuard {
+    fn drop(&mut self) {
+        let mut waiters = self.registry.waiters.lock().unwrap();
+        for key in &self.keys {
+            if let Some(list) = waiters.get_mut(key) {
+                list.retain(|notify| !Arc::ptr_eq(notify, &self.notify));
+                if list.is_empty() {
+                    waiters.remove(key);
+                }
+            }
+        }
+    }
+}
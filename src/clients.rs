@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Which replies a client receives (CLIENT REPLY)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplyMode {
+    #[default]
+    On,
+    Off,
+    /// Just set by CLIENT REPLY SKIP, whose own reply is dropped too
+    Skip,
+    /// Drop the reply to the next command, then turn back on
+    SkipNext,
+}
+
+/// Which commands CLIENT PAUSE holds back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PauseMode {
+    /// Commands that may modify data
+    Write,
+    All,
+}
+
+#[derive(Clone, Copy)]
+struct Pause {
+    mode: PauseMode,
+    until: Instant,
+}
+
+/// A connected client, as shown by CLIENT LIST and CLIENT INFO
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub id: u64,
+    /// The client's address
+    pub addr: SocketAddr,
+    /// The server address it connected to
+    pub laddr: SocketAddr,
+    /// Set with CLIENT SETNAME; empty for none
+    pub name: String,
+    pub db: usize,
+    /// Channels subscribed to
+    pub subscriptions: usize,
+    /// Shard channels subscribed to
+    pub shard_subscriptions: usize,
+    pub user: String,
+    /// Lower-case name of the last command, empty before the first
+    pub last_command: String,
+    pub reply: ReplyMode,
+    /// Attached as a replica (SYNC / PSYNC); sent the replication stream
+    /// rather than replies
+    pub replica: bool,
+    created: Instant,
+    last_interaction: Instant,
+    /// Signalled by CLIENT KILL
+    kill: Arc<Notify>,
+    /// Bytes of output the connection's writer has yet to write
+    output: Arc<AtomicUsize>,
+}
+
+impl ClientInfo {
+    /// Seconds since the client connected
+    pub fn age(&self) -> u64 {
+        self.created.elapsed().as_secs()
+    }
+
+    /// Seconds since the client's last command
+    pub fn idle(&self) -> u64 {
+        self.last_interaction.elapsed().as_secs()
+    }
+
+    /// Bytes of replies and messages waiting for the client to read them
+    /// (`omem`)
+    pub fn output_memory(&self) -> usize {
+        self.output.load(Ordering::Relaxed)
+    }
+
+    /// `replica` for replicas, `pubsub` for subscribers, `normal` otherwise
+    /// (CLIENT LIST TYPE)
+    pub fn kind(&self) -> &'static str {
+        if self.replica {
+            "replica"
+        } else if self.subscriptions > 0 || self.shard_subscriptions > 0 {
+            "pubsub"
+        } else {
+            "normal"
+        }
+    }
+
+    /// The client as `field=value` pairs on one line
+    pub fn describe(&self) -> String {
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} db={} sub={} ssub={} omem={} cmd={} user={}",
+            self.id,
+            self.addr,
+            self.laddr,
+            self.name,
+            self.age(),
+            self.idle(),
+            self.db,
+            self.subscriptions,
+            self.shard_subscriptions,
+            self.output_memory(),
+            if self.last_command.is_empty() {
+                "NULL"
+            } else {
+                &self.last_command
+            },
+            self.user,
+        )
+    }
+}
+
+/// Connected clients by id. Ids start at 1 and are never reused
+#[derive(Clone, Default)]
+pub struct ClientRegistry {
+    clients: Arc<RwLock<BTreeMap<u64, ClientInfo>>>,
+    last_id: Arc<AtomicU64>,
+    /// Set by CLIENT PAUSE until it expires or CLIENT UNPAUSE
+    pause: Arc<Mutex<Option<Pause>>>,
+    /// Wakes paused commands on CLIENT UNPAUSE
+    unpaused: Arc<Notify>,
+    /// Clients closed for going over client-output-buffer-limit
+    output_limit_disconnections: Arc<AtomicU64>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a connection from `addr` to `laddr`; it is listed until the
+    /// returned `Client` is dropped
+    pub fn register(&self, addr: SocketAddr, laddr: SocketAddr) -> Client {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Instant::now();
+        let kill = Arc::new(Notify::new());
+        let output = Arc::new(AtomicUsize::new(0));
+        let info = ClientInfo {
+            id,
+            addr,
+            laddr,
+            name: String::new(),
+            db: 0,
+            subscriptions: 0,
+            shard_subscriptions: 0,
+            user: "default".to_string(),
+            last_command: String::new(),
+            reply: ReplyMode::On,
+            replica: false,
+            created: now,
+            last_interaction: now,
+            kill: kill.clone(),
+            output: output.clone(),
+        };
+        self.clients.write().unwrap().insert(id, info);
+        Client {
+            registry: self.clone(),
+            id,
+            kill,
+            output,
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<ClientInfo> {
+        self.clients.read().unwrap().get(&id).cloned()
+    }
+
+    /// Every client, by id
+    pub fn list(&self) -> Vec<ClientInfo> {
+        self.clients.read().unwrap().values().cloned().collect()
+    }
+
+    /// Change client `id`'s entry, if it is still connected
+    pub fn update(&self, id: u64, change: impl FnOnce(&mut ClientInfo)) {
+        if let Some(info) = self.clients.write().unwrap().get_mut(&id) {
+            change(info);
+        }
+    }
+
+    /// Ask client `id`'s connection to close (CLIENT KILL). It finishes the
+    /// command it is running, if any, and stays listed until it is gone.
+    /// Returns whether the client exists
+    pub fn kill(&self, id: u64) -> bool {
+        match self.clients.read().unwrap().get(&id) {
+            Some(info) => {
+                info.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Client `id`'s kind, see `ClientInfo::kind`
+    pub fn kind(&self, id: u64) -> Option<&'static str> {
+        self.clients.read().unwrap().get(&id).map(ClientInfo::kind)
+    }
+
+    /// Close client `id` for going over its client-output-buffer-limit
+    pub fn kill_for_output(&self, id: u64) {
+        if self.kill(id) {
+            self.output_limit_disconnections
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Clients closed for going over client-output-buffer-limit since startup
+    pub fn output_limit_disconnections(&self) -> u64 {
+        self.output_limit_disconnections.load(Ordering::Relaxed)
+    }
+
+    /// Whether the reply to the command client `id` just ran should be
+    /// sent, moving a CLIENT REPLY SKIP along. Replicas get none
+    pub fn take_reply(&self, id: u64) -> bool {
+        let mut send = true;
+        self.update(id, |info| {
+            send = info.reply == ReplyMode::On && !info.replica;
+            info.reply = match info.reply {
+                ReplyMode::Skip => ReplyMode::SkipNext,
+                ReplyMode::SkipNext => ReplyMode::On,
+                mode => mode,
+            };
+        });
+        send
+    }
+
+    /// Hold back clients' commands (all, or only writes) for `duration`
+    /// (CLIENT PAUSE). A new pause replaces the current one
+    pub fn pause(&self, mode: PauseMode, duration: Duration) {
+        *self.pause.lock().unwrap() = Some(Pause {
+            mode,
+            until: Instant::now() + duration,
+        });
+    }
+
+    /// End the pause early (CLIENT UNPAUSE)
+    pub fn unpause(&self) {
+        *self.pause.lock().unwrap() = None;
+        self.unpaused.notify_waiters();
+    }
+
+    /// Whether a CLIENT PAUSE is in effect
+    pub fn is_paused(&self) -> bool {
+        self.current_pause().is_some()
+    }
+
+    fn current_pause(&self) -> Option<Pause> {
+        let pause = *self.pause.lock().unwrap();
+        pause.filter(|pause| pause.until > Instant::now())
+    }
+
+    /// Wait until a command may run: any time no pause is in effect, and
+    /// during a WRITE pause only if it doesn't `write`
+    pub async fn wait_unpaused(&self, write: bool) {
+        loop {
+            let unpaused = self.unpaused.notified();
+            let mut unpaused = std::pin::pin!(unpaused);
+            // Registered before checking, so an UNPAUSE in between isn't missed
+            unpaused.as_mut().enable();
+            let Some(pause) = self.current_pause() else {
+                return;
+            };
+            if pause.mode == PauseMode::Write && !write {
+                return;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(pause.until.into()) => {}
+                _ = unpaused => {}
+            }
+        }
+    }
+
+    /// Note that client `id` is running `command`, resetting its idle time
+    pub fn command_started(&self, id: u64, command: &str) {
+        self.update(id, |info| {
+            info.last_command = command.to_lowercase();
+            info.last_interaction = Instant::now();
+        });
+    }
+}
+
+/// A registered connection; dropping it removes the client from the registry
+pub struct Client {
+    registry: ClientRegistry,
+    id: u64,
+    kill: Arc<Notify>,
+    output: Arc<AtomicUsize>,
+}
+
+impl Client {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Where the connection's writer reports how much output it holds
+    pub fn output_memory(&self) -> Arc<AtomicUsize> {
+        self.output.clone()
+    }
+
+    /// Completes once CLIENT KILL has targeted this client
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.registry.clients.write().unwrap().remove(&self.id);
+    }
+}
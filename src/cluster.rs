@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Keys are spread over this many hash slots in cluster mode
+pub const SLOTS: u16 = 16384;
+
+/// The part of `key` that decides its slot: the text between the first `{`
+/// and the next `}` if that isn't empty, or else the whole key. Keys sharing
+/// a tag (`{user1000}.following`, `{user1000}.followers`) share a slot
+pub fn hash_tag(key: &str) -> &str {
+    if let Some(open) = key.find('{')
+        && let Some(close) = key[open + 1..].find('}')
+        && close > 0
+    {
+        return &key[open + 1..open + 1 + close];
+    }
+    key
+}
+
+/// The hash slot of `key`, as Redis Cluster computes it
+pub fn key_slot(key: &str) -> u16 {
+    crc16(hash_tag(key).as_bytes()) % SLOTS
+}
+
+/// Keys of one request that hash to more than one slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CrossSlot;
+
+impl fmt::Display for CrossSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CROSSSLOT Keys in request don't hash to the same slot")
+    }
+}
+
+/// The slot all of `keys` hash to, None without keys
+pub fn common_slot<'a>(keys: impl IntoIterator<Item = &'a str>) -> Result<Option<u16>, CrossSlot> {
+    let mut slot = None;
+    for key in keys {
+        let key_slot = key_slot(key);
+        if *slot.get_or_insert(key_slot) != key_slot {
+            return Err(CrossSlot);
+        }
+    }
+    Ok(slot)
+}
+
+/// Another node of the cluster, met with CLUSTER MEET
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Where a command whose keys hash to one slot is to run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// Here
+    Local,
+    /// At the node serving the slot (-MOVED)
+    Moved(u16, Node),
+    /// At the node the slot is migrating to, as the keys aren't here
+    /// (-ASK)
+    Ask(u16, Node),
+    /// Nowhere for now: some of the keys have been migrated and some not
+    /// (-TRYAGAIN)
+    TryAgain,
+}
+
+/// This node's view of the cluster: its ID, the nodes it has met, the slots
+/// they serve, and the slots being moved between them (CLUSTER SETSLOT).
+/// This node serves every slot not assigned to another
+#[derive(Clone)]
+pub struct Cluster {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    myid: String,
+    /// Met nodes, by ID
+    nodes: HashMap<String, Node>,
+    /// Slots served by other nodes, with their IDs
+    owners: HashMap<u16, String>,
+    /// Slots of this node being moved to another (SETSLOT MIGRATING)
+    migrating: HashMap<u16, String>,
+    /// Slots of another node being moved here (SETSLOT IMPORTING)
+    importing: HashMap<u16, String>,
+}
+
+impl Default for Cluster {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                myid: (0..40)
+                    .map(|_| char::from_digit(fastrand::u32(..16), 16).unwrap())
+                    .collect(),
+                nodes: HashMap::new(),
+                owners: HashMap::new(),
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl Cluster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// This node's ID (CLUSTER MYID)
+    pub fn myid(&self) -> String {
+        self.state.lock().unwrap().myid.clone()
+    }
+
+    /// Add node `id`, serving at `node`, to the cluster (CLUSTER MEET)
+    pub fn meet(&self, id: String, node: Node) {
+        self.state.lock().unwrap().nodes.insert(id, node);
+    }
+
+    /// Met nodes, by ID
+    pub fn nodes(&self) -> Vec<(String, Node)> {
+        let state = self.state.lock().unwrap();
+        let mut nodes: Vec<_> = state
+            .nodes
+            .iter()
+            .map(|(id, node)| (id.clone(), node.clone()))
+            .collect();
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        nodes
+    }
+
+    /// The ID of the node serving `slot`
+    pub fn owner(&self, slot: u16) -> String {
+        let state = self.state.lock().unwrap();
+        state.owners.get(&slot).unwrap_or(&state.myid).clone()
+    }
+
+    /// Start moving `slot`, served here, to node `id` (SETSLOT MIGRATING)
+    pub fn set_migrating(&self, slot: u16, id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.owners.contains_key(&slot) {
+            return Err(format!("ERR I'm not the owner of hash slot {}", slot));
+        }
+        if id == state.myid {
+            return Err("ERR Can't MIGRATE to myself".to_string());
+        }
+        if !state.nodes.contains_key(id) {
+            return Err(format!("ERR I don't know about node {}", id));
+        }
+        state.migrating.insert(slot, id.to_string());
+        Ok(())
+    }
+
+    /// Start taking `slot` over from node `id` (SETSLOT IMPORTING)
+    pub fn set_importing(&self, slot: u16, id: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if !state.owners.contains_key(&slot) {
+            return Err(format!("ERR I'm already the owner of hash slot {}", slot));
+        }
+        if id == state.myid {
+            return Err("ERR Can't IMPORT from myself".to_string());
+        }
+        if !state.nodes.contains_key(id) {
+            return Err(format!("ERR I don't know about node {}", id));
+        }
+        state.importing.insert(slot, id.to_string());
+        Ok(())
+    }
+
+    /// Stop moving `slot` either way (SETSLOT STABLE)
+    pub fn set_stable(&self, slot: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+    }
+
+    /// Assign `slot` to node `id` (SETSLOT NODE), ending its migration away
+    /// from here or import to here. `holds_keys` tells whether this node
+    /// still has keys in the slot, which it can't then give away
+    pub fn set_node(&self, slot: u16, id: &str, holds_keys: bool) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if id == state.myid {
+            state.owners.remove(&slot);
+            state.importing.remove(&slot);
+            return Ok(());
+        }
+        if !state.nodes.contains_key(id) {
+            return Err(format!("ERR I don't know about node {}", id));
+        }
+        if !state.owners.contains_key(&slot) && holds_keys {
+            return Err(format!(
+                "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                slot
+            ));
+        }
+        state.owners.insert(slot, id.to_string());
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+        Ok(())
+    }
+
+    /// Where a command on keys in `slot` runs. `present` counts the keys
+    /// found here out of `keys`, and `asking` is set after ASKING
+    pub fn route(&self, slot: u16, keys: usize, present: usize, asking: bool) -> Route {
+        let state = self.state.lock().unwrap();
+        let node = |id: &String| state.nodes.get(id).cloned();
+        if let Some(owner) = state.owners.get(&slot) {
+            if asking && state.importing.contains_key(&slot) {
+                // Keys come over one at a time; a command on several of
+                // them waits until all have
+                return match present < keys && keys > 1 {
+                    true => Route::TryAgain,
+                    false => Route::Local,
+                };
+            }
+            return match node(owner) {
+                Some(node) => Route::Moved(slot, node),
+                None => Route::Local,
+            };
+        }
+        match state.migrating.get(&slot).and_then(node) {
+            Some(_) if present == keys => Route::Local,
+            Some(_) if present > 0 => Route::TryAgain,
+            Some(target) => Route::Ask(slot, target),
+            None => Route::Local,
+        }
+    }
+}
+
+/// CRC-16/XMODEM (polynomial 0x1021, initial value 0)
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::ops::BitOr;
+use std::sync::OnceLock;
+
+/// Properties of a command relevant to the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CommandFlags(u32);
+
+impl CommandFlags {
+    pub const NONE: Self = Self(0);
+    /// Modifies the dataset; logged to the AOF
+    pub const WRITE: Self = Self(1);
+    /// Only reads the dataset
+    pub const READONLY: Self = Self(1 << 1);
+    /// Server administration (persistence, scripting caches...)
+    pub const ADMIN: Self = Self(1 << 2);
+    pub const PUBSUB: Self = Self(1 << 3);
+    /// May wait for other clients; logs its own effects to the AOF
+    pub const BLOCKING: Self = Self(1 << 4);
+    /// Not callable from scripts and functions
+    pub const NOSCRIPT: Self = Self(1 << 5);
+    /// Keys can't be found from the key positions alone (e.g. `numkeys` arguments)
+    pub const MOVABLEKEYS: Self = Self(1 << 6);
+    /// May grow the dataset, so refused while over `maxmemory`
+    pub const DENYOOM: Self = Self(1 << 7);
+    /// Allowed on a replica whose link to its master is down even with
+    /// replica-serve-stale-data off, as it doesn't read the dataset
+    pub const STALE: Self = Self(1 << 8);
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Lower-case names of the set flags, as listed by COMMAND
+    pub fn names(self) -> Vec<&'static str> {
+        [
+            (Self::WRITE, "write"),
+            (Self::READONLY, "readonly"),
+            (Self::ADMIN, "admin"),
+            (Self::PUBSUB, "pubsub"),
+            (Self::BLOCKING, "blocking"),
+            (Self::NOSCRIPT, "noscript"),
+            (Self::MOVABLEKEYS, "movablekeys"),
+            (Self::DENYOOM, "denyoom"),
+            (Self::STALE, "stale"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| self.contains(*flag))
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+impl BitOr for CommandFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+/// Whether `argc` arguments satisfy a Redis-style `arity`: exactly `arity`
+/// when positive, at least `-arity` when negative
+pub fn arity_matches(arity: i32, argc: usize) -> bool {
+    if arity >= 0 {
+        argc == arity as usize
+    } else {
+        argc >= arity.unsigned_abs() as usize
+    }
+}
+
+/// Metadata of a built-in command
+///
+/// Argument counts include the command name. Key positions follow Redis:
+/// `first_key` is the index of the first key (0 if the command takes none),
+/// a negative `last_key` counts from the end, and `key_step` is the distance
+/// between keys (2 for MSET's key/value pairs).
+#[derive(Debug, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Documentation group (`string`, `list`...), as reported by COMMAND DOCS
+    pub group: &'static str,
+    pub summary: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    pub flags: CommandFlags,
+    pub first_key: i32,
+    pub last_key: i32,
+    pub key_step: i32,
+}
+
+impl CommandSpec {
+    pub fn accepts(&self, argc: usize) -> bool {
+        (self.min_args..=self.max_args).contains(&argc)
+    }
+
+    /// Redis-style arity: the exact count, or minus the minimum when the
+    /// count varies
+    pub fn arity(&self) -> i32 {
+        if self.min_args == self.max_args {
+            self.min_args as i32
+        } else {
+            -(self.min_args as i32)
+        }
+    }
+
+    /// The keys a call with `args` (including the name) touches. Besides the
+    /// key positions, this reads the `numkeys` argument, SORT's `STORE`
+    /// option and MIGRATE's key or `KEYS` option of `MOVABLEKEYS` commands
+    pub fn keys<'a>(&self, args: &[&'a str]) -> Vec<&'a str> {
+        let mut keys = Vec::new();
+        if self.first_key > 0 {
+            let last = if self.last_key < 0 {
+                args.len() as i64 + self.last_key as i64
+            } else {
+                self.last_key as i64
+            };
+            let step = self.key_step.max(1) as usize;
+            let mut index = self.first_key as usize;
+            while index < args.len() && index as i64 <= last {
+                keys.push(args[index]);
+                index += step;
+            }
+        }
+        if self.flags.contains(MOVABLEKEYS) {
+            match self.name {
+                "EVAL" | "EVALSHA" | "FCALL" => keys.extend(numkeys_keys(args, 2)),
+                "LMPOP" | "ZMPOP" => keys.extend(numkeys_keys(args, 1)),
+                "SORT" => {
+                    let store = args
+                        .iter()
+                        .skip(2)
+                        .position(|arg| arg.eq_ignore_ascii_case("STORE"));
+                    if let Some(destination) = store.and_then(|i| args.get(i + 3)) {
+                        keys.push(destination);
+                    }
+                }
+                "MIGRATE" => match args
+                    .iter()
+                    .skip(6)
+                    .position(|arg| arg.eq_ignore_ascii_case("KEYS"))
+                {
+                    Some(i) => keys.extend(&args[i + 7..]),
+                    None => keys.extend(args.get(3)),
+                },
+                _ => {}
+            }
+        }
+        keys
+    }
+}
+
+/// The keys following a `numkeys` argument at `position`
+fn numkeys_keys<'a>(args: &[&'a str], position: usize) -> Vec<&'a str> {
+    let Some(numkeys) = args.get(position).and_then(|n| n.parse::<usize>().ok()) else {
+        return Vec::new();
+    };
+    args.iter()
+        .skip(position + 1)
+        .take(numkeys)
+        .copied()
+        .collect()
+}
+
+/// No upper bound on the number of arguments
+const ANY: usize = usize::MAX;
+
+const NONE: CommandFlags = CommandFlags::NONE;
+const WRITE: CommandFlags = CommandFlags::WRITE;
+const READONLY: CommandFlags = CommandFlags::READONLY;
+const ADMIN: CommandFlags = CommandFlags::ADMIN;
+const PUBSUB: CommandFlags = CommandFlags::PUBSUB;
+const BLOCKING: CommandFlags = CommandFlags::BLOCKING;
+const NOSCRIPT: CommandFlags = CommandFlags::NOSCRIPT;
+const MOVABLEKEYS: CommandFlags = CommandFlags::MOVABLEKEYS;
+const DENYOOM: CommandFlags = CommandFlags::DENYOOM;
+const STALE: CommandFlags = CommandFlags::STALE;
+
+const fn command(
+    name: &'static str,
+    args: (usize, usize),
+    flags: CommandFlags,
+    keys: (i32, i32, i32),
+    group: &'static str,
+    summary: &'static str,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        group,
+        summary,
+        min_args: args.0,
+        max_args: args.1,
+        flags,
+        first_key: keys.0,
+        last_key: keys.1,
+        key_step: keys.2,
+    }
+}
+
+const NO_KEYS: (i32, i32, i32) = (0, 0, 0);
+const ONE_KEY: (i32, i32, i32) = (1, 1, 1);
+const ALL_KEYS: (i32, i32, i32) = (1, -1, 1);
+const TWO_KEYS: (i32, i32, i32) = (1, 2, 1);
+
+/// Every built-in command
+#[rustfmt::skip]
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    // Keys and strings
+    command("SET", (3, ANY), WRITE.union(DENYOOM), ONE_KEY, "string", "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist"),
+    command("GET", (2, 2), READONLY, ONE_KEY, "string", "Returns the string value of a key"),
+    command("GETDEL", (2, 2), WRITE, ONE_KEY, "string", "Returns the string value of a key after deleting the key"),
+    command("GETEX", (2, ANY), WRITE, ONE_KEY, "string", "Returns the string value of a key after setting its expiration time"),
+    command("AUTH", (2, 3), NOSCRIPT.union(STALE), NO_KEYS, "connection", "Authenticates the connection"),
+    command("HELLO", (1, ANY), NOSCRIPT.union(STALE), NO_KEYS, "connection", "Handshakes with the server, optionally switching protocol version"),
+    command("ACL", (2, ANY), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "Manages users and their permissions"),
+    command("PING", (1, 2), NONE, NO_KEYS, "connection", "Returns the server's liveliness response"),
+    command("CLIENT", (2, ANY), NOSCRIPT.union(STALE), NO_KEYS, "connection", "A container for client connection commands"),
+    command("EXISTS", (2, ANY), READONLY, ALL_KEYS, "generic", "Determines whether one or more keys exist"),
+    command("DEL", (2, ANY), WRITE, ALL_KEYS, "generic", "Deletes one or more keys"),
+    command("UNLINK", (2, ANY), WRITE, ALL_KEYS, "generic", "Asynchronously deletes one or more keys"),
+    command("COPY", (3, ANY), WRITE.union(DENYOOM), TWO_KEYS, "generic", "Copies the value of a key to a new key"),
+    command("MOVE", (3, 3), WRITE, ONE_KEY, "generic", "Moves a key to another database"),
+    command("DUMP", (2, 2), READONLY, ONE_KEY, "generic", "Returns a serialized representation of the value stored at a key"),
+    command("RESTORE", (4, ANY), WRITE.union(DENYOOM), ONE_KEY, "generic", "Creates a key from the serialized representation of a value"),
+    command("RESTORE-ASKING", (4, ANY), WRITE.union(DENYOOM), ONE_KEY, "generic", "An internal command for migrating keys in a cluster"),
+    command("MIGRATE", (6, ANY), WRITE.union(MOVABLEKEYS), NO_KEYS, "generic", "Atomically transfers a key from one FerroDB instance to another"),
+    command("SCAN", (2, ANY), READONLY, NO_KEYS, "generic", "Iterates over the key names in the database"),
+    command("RANDOMKEY", (1, 1), READONLY, NO_KEYS, "generic", "Returns a random key name from the database"),
+    command("TOUCH", (2, ANY), READONLY, ALL_KEYS, "generic", "Returns the number of existing keys out of those specified after updating the time they were last accessed"),
+    command("OBJECT", (2, ANY), READONLY, (2, 2, 1), "generic", "Returns internal information about a key"),
+    command("MEMORY", (2, ANY), READONLY, (2, 2, 1), "server", "A container for memory diagnostics commands"),
+    command("SORT", (2, ANY), WRITE.union(MOVABLEKEYS).union(DENYOOM), ONE_KEY, "generic", "Sorts the elements in a list, a set, or a sorted set, optionally storing the result"),
+    command("MGET", (2, ANY), READONLY, ALL_KEYS, "string", "Atomically returns the string values of one or more keys"),
+    command("MSET", (3, ANY), WRITE.union(DENYOOM), (1, -1, 2), "string", "Atomically creates or modifies the string values of one or more keys"),
+    command("SETNX", (3, 3), WRITE.union(DENYOOM), ONE_KEY, "string", "Set the string value of a key only when the key doesn't exist"),
+    command("MSETNX", (3, ANY), WRITE.union(DENYOOM), (1, -1, 2), "string", "Atomically modifies the string values of one or more keys only when all keys don't exist"),
+    command("EXPIRE", (3, ANY), WRITE, ONE_KEY, "generic", "Sets the expiration time of a key in seconds"),
+    command("PEXPIRE", (3, ANY), WRITE, ONE_KEY, "generic", "Sets the expiration time of a key in milliseconds"),
+    command("EXPIREAT", (3, ANY), WRITE, ONE_KEY, "generic", "Sets the expiration time of a key to a Unix timestamp"),
+    command("PEXPIREAT", (3, ANY), WRITE, ONE_KEY, "generic", "Sets the expiration time of a key to a Unix milliseconds timestamp"),
+    command("EXPIRETIME", (2, 2), READONLY, ONE_KEY, "generic", "Returns the expiration time of a key as a Unix timestamp"),
+    command("PEXPIRETIME", (2, 2), READONLY, ONE_KEY, "generic", "Returns the expiration time of a key as a Unix milliseconds timestamp"),
+    command("TTL", (2, 2), READONLY, ONE_KEY, "generic", "Returns the expiration time in seconds of a key"),
+    command("PTTL", (2, 2), READONLY, ONE_KEY, "generic", "Returns the expiration time in milliseconds of a key"),
+    command("PERSIST", (2, 2), WRITE, ONE_KEY, "generic", "Removes the expiration time of a key"),
+    command("SETEX", (4, 4), WRITE.union(DENYOOM), ONE_KEY, "string", "Sets the string value and expiration time of a key"),
+    command("PSETEX", (4, 4), WRITE.union(DENYOOM), ONE_KEY, "string", "Sets both string value and expiration time in milliseconds of a key"),
+    // Lists
+    command("LPUSH", (3, ANY), WRITE.union(DENYOOM), ONE_KEY, "list", "Prepends one or more elements to a list. Creates the key if it doesn't exist"),
+    command("RPUSH", (3, ANY), WRITE.union(DENYOOM), ONE_KEY, "list", "Appends one or more elements to a list. Creates the key if it doesn't exist"),
+    command("LPOP", (2, 3), WRITE, ONE_KEY, "list", "Returns the first elements in a list after removing it. Deletes the list if the last element was popped"),
+    command("RPOP", (2, 3), WRITE, ONE_KEY, "list", "Returns and removes the last elements of a list. Deletes the list if the last element was popped"),
+    command("LLEN", (2, 2), READONLY, ONE_KEY, "list", "Returns the length of a list"),
+    command("LRANGE", (4, 4), READONLY, ONE_KEY, "list", "Returns a range of elements from a list"),
+    command("LINDEX", (3, 3), READONLY, ONE_KEY, "list", "Returns an element from a list by its index"),
+    command("LSET", (4, 4), WRITE.union(DENYOOM), ONE_KEY, "list", "Sets the value of an element in a list by its index"),
+    command("LINSERT", (5, 5), WRITE.union(DENYOOM), ONE_KEY, "list", "Inserts an element before or after another element in a list"),
+    command("LMOVE", (5, 5), WRITE.union(DENYOOM), TWO_KEYS, "list", "Returns an element after popping it from one list and pushing it to another"),
+    command("RPOPLPUSH", (3, 3), WRITE.union(DENYOOM), TWO_KEYS, "list", "Returns the last element of a list after removing and pushing it to another list"),
+    command("LMPOP", (4, ANY), WRITE.union(MOVABLEKEYS), NO_KEYS, "list", "Returns multiple elements from a list after removing them"),
+    command("BLPOP", (3, ANY), WRITE.union(BLOCKING), (1, -2, 1), "list", "Removes and returns the first element in a list. Blocks until an element is available otherwise"),
+    command("BRPOP", (3, ANY), WRITE.union(BLOCKING), (1, -2, 1), "list", "Removes and returns the last element in a list. Blocks until an element is available otherwise"),
+    command("BLMOVE", (6, 6), WRITE.union(BLOCKING).union(DENYOOM), TWO_KEYS, "list", "Pops an element from a list, pushes it to another list and returns it. Blocks until an element is available otherwise"),
+    // Persistence and server
+    command("SAVE", (1, 1), ADMIN.union(NOSCRIPT), NO_KEYS, "server", "Synchronously saves the database to disk"),
+    command("BGSAVE", (1, 2), ADMIN.union(NOSCRIPT), NO_KEYS, "server", "Asynchronously saves the database to disk"),
+    command("LASTSAVE", (1, 1), ADMIN.union(STALE), NO_KEYS, "server", "Returns the Unix timestamp of the last successful save to disk"),
+    command("DBSIZE", (1, 1), READONLY, NO_KEYS, "server", "Returns the number of keys in the database"),
+    command("INFO", (1, ANY), STALE, NO_KEYS, "server", "Returns information and statistics about the server"),
+    command("SELECT", (2, 2), STALE, NO_KEYS, "connection", "Changes the selected database"),
+    command("SWAPDB", (3, 3), WRITE, NO_KEYS, "server", "Swaps two databases"),
+    command("CONFIG", (2, ANY), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "Gets or sets configuration parameters at runtime"),
+    command("COMMAND", (1, ANY), STALE, NO_KEYS, "server", "Returns detailed information about all commands"),
+    command("BGREWRITEAOF", (1, 1), ADMIN.union(NOSCRIPT), NO_KEYS, "server", "Asynchronously rewrites the append-only file to disk"),
+    command("DEBUG", (2, ANY), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "A container for debugging commands"),
+    command("LATENCY", (2, ANY), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "A container for latency diagnostics commands"),
+    // Replication
+    command("REPLCONF", (1, ANY), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "An internal command for configuring the replication stream"),
+    command("SYNC", (1, 1), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "An internal command used in replication"),
+    command("PSYNC", (3, 3), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "An internal command used in replication"),
+    command("REPLICAOF", (3, 3), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "Configures a server as replica of another, or promotes it to a master"),
+    command("SLAVEOF", (3, 3), ADMIN.union(NOSCRIPT).union(STALE), NO_KEYS, "server", "Sets a server as a replica of another, or promotes it to being a master"),
+    command("ROLE", (1, 1), NOSCRIPT.union(STALE), NO_KEYS, "server", "Returns the replication role"),
+    command("CLUSTER", (2, ANY), STALE, NO_KEYS, "cluster", "A container for Redis Cluster commands"),
+    command("ASKING", (1, 1), STALE, NO_KEYS, "cluster", "Signals that a cluster client is following an -ASK redirect"),
+    command("WAIT", (3, 3), BLOCKING.union(NOSCRIPT), NO_KEYS, "generic", "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed"),
+    // Sorted sets
+    command("ZADD", (4, ANY), WRITE.union(DENYOOM), ONE_KEY, "sorted-set", "Adds one or more members to a sorted set, or updates their scores"),
+    command("ZREM", (3, ANY), WRITE, ONE_KEY, "sorted-set", "Removes one or more members from a sorted set"),
+    command("ZPOPMIN", (2, 3), WRITE, ONE_KEY, "sorted-set", "Returns the lowest-scoring members from a sorted set after removing them"),
+    command("ZPOPMAX", (2, 3), WRITE, ONE_KEY, "sorted-set", "Returns the highest-scoring members from a sorted set after removing them"),
+    command("ZMPOP", (4, ANY), WRITE.union(MOVABLEKEYS), NO_KEYS, "sorted-set", "Returns the highest- or lowest-scoring members from one or more sorted sets after removing them"),
+    command("ZSCAN", (3, ANY), READONLY, ONE_KEY, "sorted-set", "Iterates over members and scores of a sorted set"),
+    command("BZPOPMIN", (3, ANY), WRITE.union(BLOCKING), (1, -2, 1), "sorted-set", "Removes and returns the member with the lowest score from one or more sorted sets. Blocks until a member is available otherwise"),
+    command("BZPOPMAX", (3, ANY), WRITE.union(BLOCKING), (1, -2, 1), "sorted-set", "Removes and returns the member with the highest score from one or more sorted sets. Blocks until a member is available otherwise"),
+    command("ZSCORE", (3, 3), READONLY, ONE_KEY, "sorted-set", "Returns the score of a member in a sorted set"),
+    command("ZMSCORE", (3, ANY), READONLY, ONE_KEY, "sorted-set", "Returns the score of one or more members in a sorted set"),
+    command("ZRANDMEMBER", (2, 4), READONLY, ONE_KEY, "sorted-set", "Returns one or more random members from a sorted set"),
+    command("ZRANGE", (4, ANY), READONLY, ONE_KEY, "sorted-set", "Returns members in a sorted set within a range of indexes, scores or lexicographical values"),
+    command("ZREVRANGE", (4, 5), READONLY, ONE_KEY, "sorted-set", "Returns members in a sorted set within a range of indexes in reverse order"),
+    command("ZRANGEBYSCORE", (4, ANY), READONLY, ONE_KEY, "sorted-set", "Returns members in a sorted set within a range of scores"),
+    command("ZCOUNT", (4, 4), READONLY, ONE_KEY, "sorted-set", "Returns the count of members in a sorted set that have scores within a range"),
+    command("ZRANGEBYLEX", (4, ANY), READONLY, ONE_KEY, "sorted-set", "Returns members in a sorted set within a lexicographical range"),
+    command("ZLEXCOUNT", (4, 4), READONLY, ONE_KEY, "sorted-set", "Returns the number of members in a sorted set within a lexicographical range"),
+    command("ZRANK", (3, 4), READONLY, ONE_KEY, "sorted-set", "Returns the index of a member in a sorted set ordered by ascending scores"),
+    command("ZREVRANK", (3, 4), READONLY, ONE_KEY, "sorted-set", "Returns the index of a member in a sorted set ordered by descending scores"),
+    command("ZCARD", (2, 2), READONLY, ONE_KEY, "sorted-set", "Returns the number of members in a sorted set"),
+    // Sets
+    command("SADD", (3, ANY), WRITE.union(DENYOOM), ONE_KEY, "set", "Adds one or more members to a set. Creates the key if it doesn't exist"),
+    command("SREM", (3, ANY), WRITE, ONE_KEY, "set", "Removes one or more members from a set. Deletes the set if the last member was removed"),
+    command("SMOVE", (4, 4), WRITE, TWO_KEYS, "set", "Moves a member from one set to another"),
+    command("SSCAN", (3, ANY), READONLY, ONE_KEY, "set", "Iterates over members of a set"),
+    command("SMEMBERS", (2, 2), READONLY, ONE_KEY, "set", "Returns all members of a set"),
+    command("SISMEMBER", (3, 3), READONLY, ONE_KEY, "set", "Determines whether a member belongs to a set"),
+    command("SCARD", (2, 2), READONLY, ONE_KEY, "set", "Returns the number of members in a set"),
+    command("SINTER", (2, ANY), READONLY, ALL_KEYS, "set", "Returns the intersect of multiple sets"),
+    command("SUNION", (2, ANY), READONLY, ALL_KEYS, "set", "Returns the union of multiple sets"),
+    command("SDIFF", (2, ANY), READONLY, ALL_KEYS, "set", "Returns the difference of multiple sets"),
+    // Pub/Sub
+    command("SUBSCRIBE", (2, ANY), PUBSUB.union(NOSCRIPT).union(STALE), NO_KEYS, "pubsub", "Listens for messages published to channels"),
+    command("UNSUBSCRIBE", (1, ANY), PUBSUB.union(NOSCRIPT).union(STALE), NO_KEYS, "pubsub", "Stops listening to messages posted to channels"),
+    command("PUBLISH", (3, 3), PUBSUB.union(STALE), NO_KEYS, "pubsub", "Posts a message to a channel"),
+    command("SSUBSCRIBE", (2, ANY), PUBSUB.union(NOSCRIPT).union(STALE), NO_KEYS, "pubsub", "Listens for messages published to shard channels"),
+    command("SUNSUBSCRIBE", (1, ANY), PUBSUB.union(NOSCRIPT).union(STALE), NO_KEYS, "pubsub", "Stops listening to messages posted to shard channels"),
+    command("SPUBLISH", (3, 3), PUBSUB.union(STALE), NO_KEYS, "pubsub", "Post a message to a shard channel"),
+    // Scripting and functions
+    command("EVAL", (3, ANY), NOSCRIPT.union(MOVABLEKEYS), NO_KEYS, "scripting", "Executes a server-side Lua script"),
+    command("EVALSHA", (3, ANY), NOSCRIPT.union(MOVABLEKEYS), NO_KEYS, "scripting", "Executes a server-side Lua script by SHA1 digest"),
+    command("SCRIPT", (2, ANY), NOSCRIPT, NO_KEYS, "scripting", "Manages the server-side Lua script cache"),
+    command("FCALL", (3, ANY), NOSCRIPT.union(MOVABLEKEYS), NO_KEYS, "scripting", "Invokes a function"),
+    command("FUNCTION", (2, ANY), NOSCRIPT, NO_KEYS, "scripting", "Manages function libraries"),
+    // Transactions
+    command("MULTI", (1, 1), NOSCRIPT.union(STALE), NO_KEYS, "transactions", "Starts a transaction"),
+    command("EXEC", (1, 1), NOSCRIPT.union(STALE), NO_KEYS, "transactions", "Executes all commands in a transaction"),
+    command("DISCARD", (1, 1), NOSCRIPT.union(STALE), NO_KEYS, "transactions", "Discards a transaction"),
+    command("WATCH", (2, ANY), NOSCRIPT.union(STALE), ALL_KEYS, "transactions", "Monitors changes to keys to determine the execution of a transaction"),
+    command("UNWATCH", (1, 1), NOSCRIPT.union(STALE), NO_KEYS, "transactions", "Forgets about watched keys of a transaction"),
+];
+
+/// The built-in command called `name` (upper-case)
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    static INDEX: OnceLock<HashMap<&'static str, &'static CommandSpec>> = OnceLock::new();
+    INDEX
+        .get_or_init(|| COMMAND_TABLE.iter().map(|spec| (spec.name, spec)).collect())
+        .get(name)
+        .copied()
+}
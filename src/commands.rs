@@ -1,77 +1,660 @@
+use crate::acl::{AclRegistry, CATEGORIES, UserField};
 use crate::aof::AofWriter;
-use crate::protocol::RespValue;
-use crate::pubsub::{ClientSubscriptions, PubSubHub};
-use crate::storage::FerroStore;
+use crate::clients::{PauseMode, ReplyMode};
+use crate::cluster::{self, CrossSlot, Node, Route};
+use crate::command_table::{self, COMMAND_TABLE, CommandFlags, arity_matches};
+use crate::connection::ConnectionContext;
+use crate::latency;
+use crate::lazyfree;
+use crate::modules::CommandModule;
+use crate::protocol::{Decoded, RESP2, RESP3, RespDecoder, RespValue};
+use crate::pubsub::ChannelKind;
+use crate::replication::LinkState;
+use crate::scripting;
+use crate::stats;
+use crate::storage::{
+    ExpireCondition, FerroStore, LexBound, ListEnd, ScoreBound, ScoreEnd, ScoredMember,
+    SetCondition, SetExpiry, SetOptions, SortOptions, ZRangeBy, ZRangeQuery,
+};
+use crate::transaction::{ExecOutcome, Transaction};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::future::Future;
+use std::net::IpAddr;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::RuntimeFlavor;
 
+/// Run one command. `conn` is the state of the client connection it came
+/// from; without one (tests) the command runs on `store`'s database as no
+/// client or ACL user, and transactions and SUBSCRIBE are unavailable
 pub async fn handle_command(
     value: RespValue,
     store: &FerroStore,
     aof: Option<&AofWriter>,
-    pubsub: Option<&PubSubHub>,
-    client_subs: Option<&mut ClientSubscriptions>,
+    conn: Option<&mut ConnectionContext>,
 ) -> RespValue {
+    let mut internal;
+    let (conn, connected) = match conn {
+        Some(conn) => (conn, true),
+        None => {
+            internal = ConnectionContext {
+                db: store.db_index(),
+                ..ConnectionContext::new()
+            };
+            (&mut internal, false)
+        }
+    };
+    // ASKING only covers the command right after it
+    let asking = std::mem::take(&mut conn.asking);
     // 1. Ensure that we recieved an array (Redis commands are always arrays)
-    let cmd_array = match value {
+    let mut cmd_array = match value {
         RespValue::Array(a) => a,
-        _ => return RespValue::SimpleString("ERR expected array".to_string()),
+        _ => return RespValue::Error("ERR expected array".to_string()),
     };
     // 2. Extract the command name
     //
     let cmd_name = match &cmd_array[0] {
         RespValue::BulkString(s) => s.to_uppercase(),
-        _ => return RespValue::BulkString("ERR command must be a bulk string".to_string()),
+        _ => return RespValue::Error("ERR command must be a bulk string".to_string()),
+    };
+    let user = conn.user.as_deref();
+    let cmd_name = match resolve_renamed(&mut cmd_array, cmd_name, store, user) {
+        Ok(name) => name,
+        Err(error) => return error,
+    };
+
+    if let Some(error) = check_permissions(&cmd_array, store, user) {
+        if command_table::lookup(&cmd_name).is_some() || store.modules().get(&cmd_name).is_some() {
+            store.command_stats().reject(&cmd_name);
+        }
+        // Like other errors while queueing, this makes EXEC fail
+        if conn.transaction.in_multi() {
+            conn.transaction.mark_dirty();
+        }
+        return error;
+    }
+    // RESTORE-ASKING is RESTORE sent right after ASKING, as MIGRATE sends
+    // keys to a node importing their slot
+    let asking = asking || cmd_name == "RESTORE-ASKING";
+    if let Some(error) = cluster_redirect(&cmd_name, &cmd_array, &selected(store, conn), asking) {
+        store.command_stats().reject(&cmd_name);
+        if conn.transaction.in_multi() {
+            conn.transaction.mark_dirty();
+        }
+        return error;
+    }
+    let cmd_name = match cmd_name.as_str() {
+        "RESTORE-ASKING" => {
+            cmd_array[0] = RespValue::bulk("RESTORE");
+            "RESTORE".to_string()
+        }
+        _ => cmd_name,
     };
 
-    if let Some(subs) = client_subs.as_ref()
-        && subs.is_subscribed()
+    // CLIENT PAUSE holds back connections' commands, except CLIENT itself so
+    // the pause can still be inspected and lifted
+    if conn.id.is_some() && cmd_name != "CLIENT" {
+        store
+            .clients()
+            .wait_unpaused(pausable_write(&cmd_name, store))
+            .await;
+    }
+
+    // Over maxmemory, evict before anything that may grow the dataset
+    if may_grow(&cmd_name, store)
+        && let Err(error) = free_memory(store, aof)
     {
+        if conn.transaction.in_multi() {
+            conn.transaction.mark_dirty();
+        }
+        return error;
+    }
+
+    // RESP3 tells messages apart from replies, so subscribers may run
+    // any command there
+    if conn.subscriptions.is_subscribed() && conn.protocol < RESP3 {
         // In subscribe mode, only allow certain commands
         match cmd_name.as_str() {
-            "SUBSCRIBE" | "UNSUBSCRIBE" | "PING" | "QUIT" => {
+            "SUBSCRIBE" | "UNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" | "PING" | "QUIT" => {
                 // Allowed in subscribe mode
             }
             _ => {
-                return RespValue::SimpleString(
-                    "ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT allowed in this context"
+                return RespValue::Error(
+                    "ERR only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT allowed in this context"
                         .to_string(),
                 );
             }
         }
     }
 
-    let should_log = matches!(
+    // Transaction control is per connection; inside MULTI everything else is queued
+    if connected {
+        match cmd_name.as_str() {
+            "MULTI" => return handle_multi(&cmd_array, &mut conn.transaction),
+            "EXEC" => return handle_exec(&cmd_array, store, aof, conn).await,
+            "DISCARD" => return handle_discard(&cmd_array, &mut conn.transaction),
+            "WATCH" => {
+                return handle_watch(&cmd_array, &selected(store, conn), &mut conn.transaction);
+            }
+            "UNWATCH" => {
+                conn.transaction.unwatch();
+                return RespValue::SimpleString("OK".to_string());
+            }
+            _ if conn.transaction.in_multi() => {
+                return queue_command(&cmd_name, cmd_array, store, &mut conn.transaction);
+            }
+            _ => {}
+        }
+    } else if matches!(
         cmd_name.as_str(),
-        "SET"
-            | "DEL"
-            | "EXPIRE"
-            | "PERSIST"
-            | "SETEX"
-            | "MSET"
-            | "LPUSH"
-            | "RPUSH"
-            | "LPOP"
-            | "RPOP"
-            | "SADD"
-            | "SREM"
-            | "ZADD"
-            | "ZREM"
-    );
-    if should_log && let Some(aof_writer) = aof {
-        aof_writer.log_command(&RespValue::Array(cmd_array.clone()));
+        "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "UNWATCH" | "SUBSCRIBE" | "SSUBSCRIBE"
+    ) {
+        return RespValue::Error(format!("ERR {} is not allowed in this context", cmd_name));
+    }
+
+    // Blocking commands may wait indefinitely, so they must not hold off EXEC:
+    // they take the shared lock only while trying (see `block_on_keys`).
+    // Scripts and functions run atomically, like EXEC
+    let lock = store.exec_lock();
+    let blocking = is_blocking_command(&cmd_name);
+    // SCRIPT KILL gets through to the script holding the lock
+    if cmd_name == "SCRIPT"
+        && matches!(cmd_array.get(1), Some(RespValue::BulkString(sub)) if sub.eq_ignore_ascii_case("KILL"))
+    {
+        return handle_script(&cmd_array, store);
+    }
+    let exclusive = match cmd_name.as_str() {
+        // DEBUG SLEEP and RELOAD stall the whole server, as in Redis
+        "EVAL" | "EVALSHA" | "FCALL" | "DEBUG" => true,
+        // Commands are logged and run under the shared lock, so with none
+        // running each lands in either the captured dataset or the new
+        // incremental file, not both
+        "BGREWRITEAOF" => true,
+        // Turning appendonly on captures the dataset the same way, as does
+        // attaching a replica
+        "CONFIG" | "SYNC" | "PSYNC" => true,
+        // MIGRATE holds its keys still until the target has them, stalling
+        // the server like in Redis
+        "MIGRATE" => true,
+        _ => false,
+    };
+    let locked = match (blocking, exclusive) {
+        (true, _) => Ok((None, None)),
+        (false, true) => unless_busy(store, lock.write())
+            .await
+            .map(|lock| (None, Some(lock))),
+        (false, false) => unless_busy(store, lock.read())
+            .await
+            .map(|lock| (Some(lock), None)),
+    };
+    let (_shared, _exclusive) = match locked {
+        Ok(locks) => locks,
+        Err(busy) => {
+            store.command_stats().reject(&cmd_name);
+            return busy;
+        }
+    };
+    let started = Instant::now();
+    // Replication commands and ASKING act on the connection they come from
+    if connected && matches!(cmd_name.as_str(), "REPLCONF" | "SYNC" | "PSYNC" | "ASKING") {
+        let reply = match command_table::lookup(&cmd_name) {
+            Some(spec) if !spec.accepts(cmd_array.len()) => {
+                store.command_stats().reject(&cmd_name);
+                return wrong_arity(&cmd_name);
+            }
+            _ if cmd_name == "REPLCONF" => handle_replconf(&cmd_array, store, conn),
+            _ if cmd_name == "ASKING" => handle_asking(store, conn),
+            _ => handle_sync(&cmd_name, &cmd_array, store, conn),
+        };
+        let failed = reply.error_message().is_some();
+        store
+            .command_stats()
+            .record(&cmd_name, Some(started.elapsed()), failed);
+        return reply;
+    }
+    let reply = execute_command(&cmd_name, cmd_array, store, aof, conn, true).await;
+    // Time spent waiting for a push isn't latency
+    if !blocking {
+        store.record_latency(latency::COMMAND, started.elapsed());
+    }
+    reply
+}
+
+/// Enforce `requirepass` for one connection before its commands reach
+/// `handle_command`: AUTH is answered here (marking the connection
+/// authenticated), and any other command from an unauthenticated client is
+/// refused. Returns None when the command may run
+pub fn authenticate(
+    value: &RespValue,
+    store: &FerroStore,
+    conn: &mut ConnectionContext,
+) -> Option<RespValue> {
+    let RespValue::Array(cmd_array) = value else {
+        return None;
+    };
+    let is_auth = matches!(cmd_array.first(), Some(RespValue::BulkString(name)) if name.eq_ignore_ascii_case("AUTH"));
+    if is_auth {
+        let reply = handle_auth(cmd_array, store, conn);
+        if reply == RespValue::SimpleString("OK".to_string()) {
+            conn.authenticated = true;
+        }
+        return Some(reply);
+    }
+    // HELLO 3 AUTH user pass logs in as it switches protocol
+    let is_hello_auth = matches!(cmd_array.first(), Some(RespValue::BulkString(name)) if name.eq_ignore_ascii_case("HELLO"))
+        && cmd_array.iter().skip(2).any(
+            |arg| matches!(arg, RespValue::BulkString(option) if option.eq_ignore_ascii_case("AUTH")),
+        );
+    if is_hello_auth {
+        let reply = handle_hello(cmd_array, store, conn);
+        if reply.error_message().is_none() {
+            conn.authenticated = true;
+        }
+        return Some(reply);
+    }
+    let requirepass = store.config().read().requirepass.clone();
+    if !conn.authenticated && !store.acl().default_login(&requirepass) {
+        return Some(RespValue::Error(
+            "NOAUTH Authentication required.".to_string(),
+        ));
+    }
+    None
+}
+
+/// Protected mode: while the default user needs no password, only clients
+/// on the loopback interface may connect. Returns the error sent before
+/// closing any other connection
+pub fn protected_mode_denial(store: &FerroStore, peer: IpAddr) -> Option<RespValue> {
+    let config = store.config().read();
+    if !config.protected_mode
+        || !store.acl().default_login(&config.requirepass)
+        || peer.to_canonical().is_loopback()
+    {
+        return None;
+    }
+    Some(RespValue::Error(
+        "DENIED FerroDB is running in protected mode because protected mode is enabled and no password is set for the default user. \
+         In this mode connections are only accepted from the loopback interface. \
+         To accept remote clients, set a password with requirepass or ACL SETUSER default, \
+         or disable protected mode with CONFIG SET protected-mode no from a local connection."
+            .to_string(),
+    ))
+}
+
+fn handle_auth(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    conn: &mut ConnectionContext,
+) -> RespValue {
+    // AUTH password | AUTH username password
+    let (username, password) = match cmd_array {
+        [_, RespValue::BulkString(password)] => ("default", password),
+        [
+            _,
+            RespValue::BulkString(username),
+            RespValue::BulkString(password),
+        ] => (username.as_str(), password),
+        _ => {
+            return RespValue::Error(
+                "ERR wrong number of arguments for 'auth' command".to_string(),
+            );
+        }
+    };
+    let requirepass = store.config().read().requirepass.clone();
+    if cmd_array.len() == 2 && store.acl().default_login(&requirepass) {
+        return RespValue::Error(
+            "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"
+                .to_string(),
+        );
+    }
+    if store.acl().authenticate(username, password, &requirepass) {
+        conn.user = Some(username.to_string());
+        RespValue::SimpleString("OK".to_string())
+    } else {
+        RespValue::Error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+        )
+    }
+}
+
+fn handle_hello(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    conn: &mut ConnectionContext,
+) -> RespValue {
+    // HELLO [protover [AUTH username password] [SETNAME clientname]]
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(s.as_str());
+    }
+
+    let mut protover = conn.protocol;
+    let mut name = None;
+    if let Some((version, mut options)) = args.split_first() {
+        protover = match version.parse::<i64>() {
+            Ok(v) if v == RESP2 as i64 || v == RESP3 as i64 => v as u8,
+            Ok(_) => {
+                return RespValue::Error("NOPROTO unsupported protocol version".to_string());
+            }
+            Err(_) => {
+                return RespValue::Error(
+                    "ERR Protocol version is not an integer or out of range".to_string(),
+                );
+            }
+        };
+        let mut credentials = None;
+        while let Some((option, rest)) = options.split_first() {
+            match (option.to_uppercase().as_str(), rest) {
+                ("AUTH", [username, password, rest @ ..]) => {
+                    credentials = Some((*username, *password));
+                    options = rest;
+                }
+                ("SETNAME", [clientname, rest @ ..]) => {
+                    name = Some(*clientname);
+                    options = rest;
+                }
+                _ => {
+                    return RespValue::Error(format!(
+                        "ERR Syntax error in HELLO option '{}'",
+                        option
+                    ));
+                }
+            }
+        }
+        if let Some((username, password)) = credentials {
+            let reply = handle_auth(
+                &[
+                    RespValue::BulkString("AUTH".into()),
+                    RespValue::BulkString(username.to_string().into()),
+                    RespValue::BulkString(password.to_string().into()),
+                ],
+                store,
+                conn,
+            );
+            if reply.error_message().is_some() {
+                return reply;
+            }
+        }
+    }
+
+    if let Some(name) = name {
+        if name.chars().any(|c| !('!'..='~').contains(&c)) {
+            return RespValue::Error(
+                "ERR Client names cannot contain spaces, newlines or special characters."
+                    .to_string(),
+            );
+        }
+        set_client_name(store, conn, name);
+    }
+    conn.protocol = protover;
+
+    let field = |name: &str| RespValue::BulkString(name.to_string().into());
+    RespValue::Map(vec![
+        (field("server"), field("ferrodb")),
+        (field("version"), field(env!("CARGO_PKG_VERSION"))),
+        (field("proto"), RespValue::Integer(protover as i64)),
+        (
+            field("id"),
+            RespValue::Integer(conn.id.unwrap_or_default() as i64),
+        ),
+        (field("mode"), field("standalone")),
+        (field("role"), field("master")),
+        (field("modules"), RespValue::Array(vec![])),
+    ])
+}
+
+/// Whether CLIENT PAUSE WRITE holds `cmd_name` back: writes, plus commands
+/// that may write (scripts, EXEC) or reach other clients (PUBLISH, SPUBLISH)
+fn pausable_write(cmd_name: &str, store: &FerroStore) -> bool {
+    if matches!(
+        cmd_name,
+        "EVAL" | "EVALSHA" | "FCALL" | "EXEC" | "PUBLISH" | "SPUBLISH"
+    ) {
+        return true;
+    }
+    match command_table::lookup(cmd_name) {
+        Some(spec) => spec.flags.contains(CommandFlags::WRITE),
+        None => store
+            .modules()
+            .get(cmd_name)
+            .is_some_and(|module| module.flags().contains(CommandFlags::WRITE)),
+    }
+}
+
+/// Whether `cmd_name` may add to the dataset: DENYOOM commands, and scripts
+/// and functions, which may run any of them
+fn may_grow(cmd_name: &str, store: &FerroStore) -> bool {
+    if matches!(cmd_name, "EVAL" | "EVALSHA" | "FCALL") {
+        return true;
+    }
+    match command_table::lookup(cmd_name) {
+        Some(spec) => spec.flags.contains(CommandFlags::DENYOOM),
+        None => store
+            .modules()
+            .get(cmd_name)
+            .is_some_and(|module| module.flags().contains(CommandFlags::DENYOOM)),
+    }
+}
+
+/// Evict keys following `maxmemory-policy` until the dataset fits in
+/// `maxmemory` again, logging the deletions to the AOF. Fails with an OOM
+/// error when nothing more can be evicted
+fn free_memory(store: &FerroStore, aof: Option<&AofWriter>) -> Result<(), RespValue> {
+    let (maxmemory, policy, samples) = {
+        let config = store.config().read();
+        (
+            config.maxmemory,
+            config.maxmemory_policy,
+            config.maxmemory_samples,
+        )
+    };
+    if maxmemory == 0 {
+        return Ok(());
+    }
+    while store.used_memory() as u64 > maxmemory {
+        let Some((db, key)) = store.evict(policy, samples) else {
+            return Err(RespValue::Error(
+                "OOM command not allowed when used memory > 'maxmemory'.".to_string(),
+            ));
+        };
+        if let Some(aof_writer) = aof {
+            let del = vec![
+                RespValue::BulkString("DEL".into()),
+                RespValue::BulkString(key.into()),
+            ];
+            aof_writer.log_command(db, &RespValue::Array(del));
+        }
+    }
+    Ok(())
+}
+
+/// Apply `rename-command` to a client's command, replacing a new name with
+/// the original so ACL rules, queueing and the AOF all see the real command.
+/// Internal callers, running as no `user` (AOF replay), are unaffected, as
+/// the log holds real names
+fn resolve_renamed(
+    cmd_array: &mut [RespValue],
+    cmd_name: String,
+    store: &FerroStore,
+    user: Option<&str>,
+) -> Result<String, RespValue> {
+    if user.is_none() {
+        return Ok(cmd_name);
+    }
+    let Some(name) = store.config().resolve_command(&cmd_name) else {
+        return Err(unknown_command(&cmd_name));
+    };
+    if name != cmd_name {
+        cmd_array[0] = RespValue::BulkString(name.clone().into());
+    }
+    Ok(name)
+}
+
+/// Refuse a command `user` may not run, or whose keys it may not access
+fn check_permissions(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    user: Option<&str>,
+) -> Option<RespValue> {
+    let user = user?;
+    let args: Vec<&str> = cmd_array
+        .iter()
+        .map(|arg| match arg {
+            RespValue::BulkString(s) => s.as_str(),
+            _ => "",
+        })
+        .collect();
+    store.acl().check(user, &args).err().map(RespValue::Error)
+}
+
+/// In cluster mode, refuse a command whose keys hash to more than one
+/// slot, and send one whose slot is served by another node there: -MOVED,
+/// or -ASK while the slot migrates and the keys have already gone
+fn cluster_redirect(
+    cmd_name: &str,
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    asking: bool,
+) -> Option<RespValue> {
+    if !store.config().read().cluster_enabled {
+        return None;
+    }
+    let args = slot_args(cmd_name, cmd_array);
+    let slot = match cluster::common_slot(args.iter().copied()) {
+        Ok(Some(slot)) => slot,
+        Ok(None) => return None,
+        Err(e) => return Some(RespValue::Error(e.to_string())),
+    };
+    // Channels stay with the node serving their slot
+    let present = match is_shard_channel_command(cmd_name) {
+        true => args.len(),
+        false => args.iter().filter(|key| store.exists(key)).count(),
+    };
+    match store.cluster().route(slot, args.len(), present, asking) {
+        Route::Local => None,
+        Route::Moved(slot, node) => Some(RespValue::Error(format!("MOVED {} {}", slot, node))),
+        Route::Ask(slot, node) => Some(RespValue::Error(format!("ASK {} {}", slot, node))),
+        Route::TryAgain => Some(RespValue::Error(
+            "TRYAGAIN Multiple keys request during rehashing of slot".to_string(),
+        )),
+    }
+}
+
+/// The hash slot of a command's keys, None if it has none
+fn command_slot(cmd_name: &str, cmd_array: &[RespValue]) -> Result<Option<u16>, CrossSlot> {
+    cluster::common_slot(slot_args(cmd_name, cmd_array))
+}
+
+/// The arguments of a command that decide its slot: its keys, or the
+/// channels of a shard channel command, which aren't keys but belong to
+/// the slot they hash to
+fn slot_args<'a>(cmd_name: &str, cmd_array: &'a [RespValue]) -> Vec<&'a str> {
+    let Some(spec) = command_table::lookup(cmd_name) else {
+        return Vec::new();
+    };
+    let args: Vec<&str> = cmd_array
+        .iter()
+        .map(|arg| match arg {
+            RespValue::BulkString(s) => s.as_str(),
+            _ => "",
+        })
+        .collect();
+    match cmd_name {
+        "SPUBLISH" => args[1..2].to_vec(),
+        _ if is_shard_channel_command(cmd_name) => args[1..].to_vec(),
+        _ => spec.keys(&args),
+    }
+}
+
+fn is_shard_channel_command(cmd_name: &str) -> bool {
+    matches!(cmd_name, "SSUBSCRIBE" | "SUNSUBSCRIBE" | "SPUBLISH")
+}
+
+/// Run a parsed command, then log it if it changed the dataset
+/// With `may_block` unset, blocking commands time out immediately instead of
+/// waiting, as they do inside MULTI/EXEC
+async fn execute_command(
+    cmd_name: &str,
+    cmd_array: Vec<RespValue>,
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    conn: &mut ConnectionContext,
+    may_block: bool,
+) -> RespValue {
+    // Commands run on the database the connection has selected
+    let view = selected(store, conn);
+    let store = &*view;
+    let Some(spec) = command_table::lookup(cmd_name) else {
+        return match store.modules().get(cmd_name) {
+            Some(module) => {
+                if let Some(error) = replica_refusal(store, conn, module.flags()) {
+                    store.command_stats().reject(cmd_name);
+                    return error;
+                }
+                let started = Instant::now();
+                let reply = execute_module(module.as_ref(), &cmd_array, store, aof).await;
+                let failed = reply.error_message().is_some();
+                store
+                    .command_stats()
+                    .record(cmd_name, Some(started.elapsed()), failed);
+                reply
+            }
+            None => unknown_command(cmd_name),
+        };
+    };
+    if !spec.accepts(cmd_array.len()) {
+        store.command_stats().reject(cmd_name);
+        return wrong_arity(cmd_name);
     }
+    if let Some(error) = replica_refusal(store, conn, spec.flags) {
+        store.command_stats().reject(cmd_name);
+        return error;
+    }
+
     // 3. Dispatch the correct logic
-    match cmd_name.as_str() {
+    let writes = store.writes();
+    let started = Instant::now();
+    let reply = match cmd_name {
         "SET" => handle_set(&cmd_array, store),
         "GET" => handle_get(&cmd_array, store),
+        "GETDEL" => handle_getdel(&cmd_array, store),
+        "GETEX" => handle_getex(&cmd_array, store),
         "PING" => handle_ping(&cmd_array),
         "EXISTS" => handle_exists(&cmd_array, store),
         "DEL" => handle_del(&cmd_array, store),
+        "UNLINK" => handle_unlink(&cmd_array, store),
+        "COPY" => handle_copy(&cmd_array, store),
+        "MOVE" => handle_move(&cmd_array, store),
+        "DUMP" => handle_dump(&cmd_array, store),
+        "RESTORE" => handle_restore(&cmd_array, store),
+        "MIGRATE" => handle_migrate(&cmd_array, store, aof).await,
+        "SCAN" => handle_scan(&cmd_array, store),
+        "RANDOMKEY" => handle_randomkey(&cmd_array, store),
+        "TOUCH" => handle_touch(&cmd_array, store),
+        "OBJECT" => handle_object(&cmd_array, store),
+        "MEMORY" => handle_memory(&cmd_array, store),
+        "SORT" => handle_sort(&cmd_array, store),
         "MGET" => handle_mget(&cmd_array, store),
         "MSET" => handle_mset(&cmd_array, store),
-        "EXPIRE" => handle_expire(&cmd_array, store),
-        "TTL" => handle_ttl(&cmd_array, store),
+        "SETNX" => handle_setnx(&cmd_array, store),
+        "MSETNX" => handle_msetnx(&cmd_array, store),
+        "EXPIRE" => handle_expire(&cmd_array, store, TimeUnit::Seconds),
+        "PEXPIRE" => handle_expire(&cmd_array, store, TimeUnit::Milliseconds),
+        "EXPIREAT" => handle_expireat(&cmd_array, store, TimeUnit::Seconds),
+        "PEXPIREAT" => handle_expireat(&cmd_array, store, TimeUnit::Milliseconds),
+        "EXPIRETIME" => handle_expiretime(&cmd_array, store, TimeUnit::Seconds),
+        "PEXPIRETIME" => handle_expiretime(&cmd_array, store, TimeUnit::Milliseconds),
+        "TTL" => handle_ttl(&cmd_array, store, TimeUnit::Seconds),
+        "PTTL" => handle_ttl(&cmd_array, store, TimeUnit::Milliseconds),
         "PERSIST" => handle_persist(&cmd_array, store),
-        "SETEX" => handle_setex(&cmd_array, store),
+        "SETEX" => handle_setex(&cmd_array, store, TimeUnit::Seconds),
+        "PSETEX" => handle_setex(&cmd_array, store, TimeUnit::Milliseconds),
         // List Commands
         "LPUSH" => handle_lpush(&cmd_array, store),
         "RPUSH" => handle_rpush(&cmd_array, store),
@@ -79,24 +662,68 @@ pub async fn handle_command(
         "RPOP" => handle_rpop(&cmd_array, store),
         "LLEN" => handle_llen(&cmd_array, store),
         "LRANGE" => handle_lrange(&cmd_array, store),
+        "LINDEX" => handle_lindex(&cmd_array, store),
+        "LSET" => handle_lset(&cmd_array, store),
+        "LINSERT" => handle_linsert(&cmd_array, store),
+        "LMOVE" => handle_lmove(&cmd_array, store),
+        "RPOPLPUSH" => handle_rpoplpush(&cmd_array, store),
+        "LMPOP" => handle_lmpop(&cmd_array, store),
+        "BLPOP" => handle_blpop(&cmd_array, store, ListEnd::Left, aof, may_block).await,
+        "BRPOP" => handle_blpop(&cmd_array, store, ListEnd::Right, aof, may_block).await,
+        "BLMOVE" => handle_blmove(&cmd_array, store, aof, may_block).await,
         // Save operations
         "SAVE" => handle_save(&cmd_array, store).await,
         "BGSAVE" => handle_bgsave(&cmd_array, store),
         "LASTSAVE" => handle_lastsave(&cmd_array, store),
         "DBSIZE" => handle_dbsize(&cmd_array, store),
-        "BGREWRITEAOF" => handle_bgrewriteaof(&cmd_array, store),
+        "INFO" => handle_info(&cmd_array, store),
+        "SELECT" => handle_select(&cmd_array, conn),
+        "SWAPDB" => handle_swapdb(&cmd_array, store),
+        "COMMAND" => handle_command_introspection(&cmd_array, store),
+        "CONFIG" => handle_config(&cmd_array, store, aof),
+        "CLIENT" => handle_client(&cmd_array, store, conn),
+        "LATENCY" => handle_latency(&cmd_array, store),
+        "REPLICAOF" | "SLAVEOF" => handle_replicaof(&cmd_array, store, aof),
+        "WAIT" => handle_wait(&cmd_array, store, may_block).await,
+        "ROLE" => handle_role(store),
+        "CLUSTER" => handle_cluster(&cmd_array, store).await,
+        // Handled with the connection by `handle_command`
+        "REPLCONF" | "SYNC" | "PSYNC" | "ASKING" => {
+            RespValue::Error(format!("ERR {} is not allowed in this context", cmd_name))
+        }
+        "AUTH" => handle_auth(&cmd_array, store, conn),
+        "HELLO" => handle_hello(&cmd_array, store, conn),
+        "ACL" => handle_acl(&cmd_array, store, conn),
+        "BGREWRITEAOF" => handle_bgrewriteaof(&cmd_array, store, aof),
+        "DEBUG" => handle_debug(&cmd_array, store).await,
 
         // Sorted Set Operations
         "ZADD" => handle_zadd(&cmd_array, store),
         "ZREM" => handle_zrem(&cmd_array, store),
+        "ZPOPMIN" => handle_zpop(&cmd_array, store, ScoreEnd::Min),
+        "ZPOPMAX" => handle_zpop(&cmd_array, store, ScoreEnd::Max),
+        "ZMPOP" => handle_zmpop(&cmd_array, store),
+        "ZSCAN" => handle_zscan(&cmd_array, store),
+        "BZPOPMIN" => handle_bzpop(&cmd_array, store, ScoreEnd::Min, aof, may_block).await,
+        "BZPOPMAX" => handle_bzpop(&cmd_array, store, ScoreEnd::Max, aof, may_block).await,
         "ZSCORE" => handle_zscore(&cmd_array, store),
+        "ZMSCORE" => handle_zmscore(&cmd_array, store),
+        "ZRANDMEMBER" => handle_zrandmember(&cmd_array, store),
         "ZRANGE" => handle_zrange(&cmd_array, store),
-        "ZRANK" => handle_zrank(&cmd_array, store),
+        "ZREVRANGE" => handle_zrange_variant(&cmd_array, store, "zrevrange", &["REV"]),
+        "ZRANGEBYSCORE" => handle_zrange_variant(&cmd_array, store, "zrangebyscore", &["BYSCORE"]),
+        "ZCOUNT" => handle_zcount(&cmd_array, store),
+        "ZRANGEBYLEX" => handle_zrange_variant(&cmd_array, store, "zrangebylex", &["BYLEX"]),
+        "ZLEXCOUNT" => handle_zlexcount(&cmd_array, store),
+        "ZRANK" => handle_zrank(&cmd_array, store, false),
+        "ZREVRANK" => handle_zrank(&cmd_array, store, true),
         "ZCARD" => handle_zcard(&cmd_array, store),
 
         // Set commands
         "SADD" => handle_sadd(&cmd_array, store),
         "SREM" => handle_srem(&cmd_array, store),
+        "SMOVE" => handle_smove(&cmd_array, store),
+        "SSCAN" => handle_sscan(&cmd_array, store),
         "SMEMBERS" => handle_smembers(&cmd_array, store),
         "SISMEMBER" => handle_sismember(&cmd_array, store),
         "SCARD" => handle_scard(&cmd_array, store),
@@ -104,37 +731,417 @@ pub async fn handle_command(
         "SUNION" => handle_sunion(&cmd_array, store),
         "SDIFF" => handle_sdiff(&cmd_array, store),
 
-        "SUBSCRIBE" => handle_subscribe(&cmd_array, pubsub, client_subs),
-        "UNSUBSCRIBE" => handle_unsubscribe(&cmd_array, client_subs),
-        "PUBLISH" => handle_publish(&cmd_array, pubsub),
+        "SUBSCRIBE" => handle_subscribe(&cmd_array, store, conn, ChannelKind::Global),
+        "UNSUBSCRIBE" => handle_unsubscribe(&cmd_array, conn, ChannelKind::Global),
+        "PUBLISH" => handle_publish(&cmd_array, store, ChannelKind::Global),
+        "SSUBSCRIBE" => handle_subscribe(&cmd_array, store, conn, ChannelKind::Shard),
+        "SUNSUBSCRIBE" => handle_unsubscribe(&cmd_array, conn, ChannelKind::Shard),
+        "SPUBLISH" => handle_publish(&cmd_array, store, ChannelKind::Shard),
+
+        // Scripting
+        "EVAL" => handle_eval(&cmd_array, store, aof, conn, false),
+        "EVALSHA" => handle_eval(&cmd_array, store, aof, conn, true),
+        "SCRIPT" => handle_script(&cmd_array, store),
+        "FCALL" => handle_fcall(&cmd_array, store, aof, conn),
+        "FUNCTION" => handle_function(&cmd_array, store),
+
+        _ => unknown_command(cmd_name),
+    };
+    // Time spent waiting for a push isn't counted
+    let elapsed =
+        (!may_block || !spec.flags.contains(CommandFlags::BLOCKING)).then(|| started.elapsed());
+    let failed = reply.error_message().is_some();
+    store.command_stats().record(cmd_name, elapsed, failed);
+
+    // Only commands that changed something are logged, so failures and
+    // no-ops (DEL of a missing key, LPUSH to a set) aren't replayed.
+    // Blocking commands and MIGRATE log what they end up doing themselves
+    let changed = !failed && store.writes() != writes;
+    if changed
+        && spec.flags.contains(CommandFlags::WRITE)
+        && !spec.flags.contains(CommandFlags::BLOCKING)
+        && cmd_name != "MIGRATE"
+        && let Some(aof_writer) = aof
+    {
+        aof_writer.log_command(
+            store.db_index(),
+            &RespValue::Array(with_absolute_expiry(&cmd_array)),
+        );
+    }
+    reply
+}
+
+/// A handle on the database `conn` has selected
+fn selected<'a>(store: &'a FerroStore, conn: &ConnectionContext) -> Cow<'a, FerroStore> {
+    if store.db_index() == conn.db {
+        Cow::Borrowed(store)
+    } else {
+        Cow::Owned(store.database(conn.db).expect("SELECT checks the index"))
+    }
+}
+
+/// The error refusing a client a command with `flags` because this server
+/// is a replica: writes come from its master only (replica-read-only), and
+/// only commands flagged STALE run while the link to the master is down
+/// unless replica-serve-stale-data allows serving possibly outdated data
+fn replica_refusal(
+    store: &FerroStore,
+    conn: &ConnectionContext,
+    flags: CommandFlags,
+) -> Option<RespValue> {
+    // The master's writes are applied with no client
+    conn.id?;
+    let master = store.replication().master()?;
+    let (read_only, serve_stale_data) = {
+        let config = store.config().read();
+        (config.replica_read_only, config.replica_serve_stale_data)
+    };
+    if master.state != LinkState::Connected
+        && !serve_stale_data
+        && !flags.contains(CommandFlags::STALE)
+    {
+        return Some(RespValue::Error(
+            "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                .to_string(),
+        ));
+    }
+    if read_only && flags.contains(CommandFlags::WRITE) {
+        return Some(RespValue::Error(
+            "READONLY You can't write against a read only replica.".to_string(),
+        ));
+    }
+    None
+}
+
+fn unknown_command(cmd_name: &str) -> RespValue {
+    RespValue::Error(format!("ERR unknown command {}", cmd_name))
+}
+
+fn wrong_arity(cmd_name: &str) -> RespValue {
+    RespValue::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        cmd_name.to_lowercase()
+    ))
+}
+
+/// Check that a command exists and accepts its number of arguments
+fn validate_command(
+    cmd_name: &str,
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+) -> Option<RespValue> {
+    let arity_ok = match command_table::lookup(cmd_name) {
+        Some(spec) => spec.accepts(cmd_array.len()),
+        None => match store.modules().get(cmd_name) {
+            Some(module) => arity_matches(module.arity(), cmd_array.len()),
+            None => return Some(unknown_command(cmd_name)),
+        },
+    };
+    (!arity_ok).then(|| wrong_arity(cmd_name))
+}
+
+fn handle_command_introspection(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // COMMAND | COMMAND COUNT | COMMAND INFO [name ...] | COMMAND DOCS [name ...]
+    let Some(RespValue::BulkString(subcommand)) = cmd_array.get(1) else {
+        return RespValue::Array(
+            all_command_names(store)
+                .map(|name| command_info(&name, store))
+                .collect(),
+        );
+    };
+    let mut names = Vec::with_capacity(cmd_array.len() - 2);
+    for arg in &cmd_array[2..] {
+        let RespValue::BulkString(name) = arg else {
+            return RespValue::Error("ERR command names must be bulk strings".to_string());
+        };
+        names.push(name.to_uppercase());
+    }
+
+    if names.is_empty() {
+        names = all_command_names(store).collect();
+    } else if subcommand.eq_ignore_ascii_case("COUNT") {
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'command|count' command".to_string(),
+        );
+    }
+
+    match subcommand.to_uppercase().as_str() {
+        "COUNT" => RespValue::Integer(names.len() as i64),
+        "INFO" => RespValue::Array(names.iter().map(|name| command_info(name, store)).collect()),
+        "DOCS" => {
+            // Name/doc pairs, leaving out unknown commands
+            let mut reply = Vec::new();
+            for name in names {
+                let (group, summary) = match command_table::lookup(&name) {
+                    Some(spec) => (spec.group, spec.summary),
+                    None if store.modules().get(&name).is_some() => ("module", ""),
+                    None => continue,
+                };
+                reply.push(RespValue::BulkString(name.to_lowercase().into()));
+                reply.push(RespValue::Array(vec![
+                    RespValue::BulkString("summary".into()),
+                    RespValue::BulkString(summary.to_string().into()),
+                    RespValue::BulkString("group".into()),
+                    RespValue::BulkString(group.to_string().into()),
+                ]));
+            }
+            RespValue::Array(reply)
+        }
+        _ => RespValue::Error(format!(
+            "ERR unknown subcommand '{}'. Try COMMAND HELP.",
+            subcommand.as_str()
+        )),
+    }
+}
+
+/// Upper-case names of the built-in and module commands
+fn all_command_names(store: &FerroStore) -> impl Iterator<Item = String> {
+    COMMAND_TABLE
+        .iter()
+        .map(|spec| spec.name.to_string())
+        .chain(
+            store
+                .modules()
+                .list()
+                .into_iter()
+                .map(|module| module.name().to_uppercase()),
+        )
+}
+
+/// COMMAND INFO entry: name, arity, flags, first key, last key, key step,
+/// then ACL categories, tips, key specs and subcommands (not tracked)
+fn command_info(name: &str, store: &FerroStore) -> RespValue {
+    let (arity, flags, keys) = match command_table::lookup(name) {
+        Some(spec) => (
+            spec.arity(),
+            spec.flags,
+            (spec.first_key, spec.last_key, spec.key_step),
+        ),
+        None => match store.modules().get(name) {
+            Some(module) => (module.arity(), module.flags(), (0, 0, 0)),
+            None => return RespValue::Null,
+        },
+    };
+    RespValue::Array(vec![
+        RespValue::BulkString(name.to_lowercase().into()),
+        RespValue::Integer(arity as i64),
+        RespValue::Array(
+            flags
+                .names()
+                .into_iter()
+                .map(|flag| RespValue::SimpleString(flag.to_string()))
+                .collect(),
+        ),
+        RespValue::Integer(keys.0 as i64),
+        RespValue::Integer(keys.1 as i64),
+        RespValue::Integer(keys.2 as i64),
+        RespValue::Array(vec![]),
+        RespValue::Array(vec![]),
+        RespValue::Array(vec![]),
+        RespValue::Array(vec![]),
+    ])
+}
 
-        _ => RespValue::SimpleString(format!("ERR unknown command {}", cmd_name)),
+/// Run a command registered through the module API
+async fn execute_module(
+    module: &dyn CommandModule,
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+) -> RespValue {
+    let mut args = Vec::with_capacity(cmd_array.len());
+    for arg in cmd_array {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(s.to_string());
+    }
+    if !arity_matches(module.arity(), args.len()) {
+        return wrong_arity(module.name());
+    }
+    let reply = module.execute(store, &args).await;
+    // A module may keep state of its own, so any write that succeeded is
+    // logged
+    if module.flags().contains(CommandFlags::WRITE)
+        && reply.error_message().is_none()
+        && let Some(aof_writer) = aof
+    {
+        aof_writer.log_command(store.db_index(), &RespValue::Array(cmd_array.to_vec()));
     }
+    reply
 }
 
 fn handle_set(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 3 {
-        return RespValue::SimpleString("ERR wrong number of arguments for 'set'".to_string());
+    // SET key value [NX | XX] [GET] [EX seconds | PX milliseconds |
+    //     EXAT unix-time-seconds | PXAT unix-time-milliseconds | KEEPTTL]
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'set'".to_string());
     }
-    if let (RespValue::BulkString(k), RespValue::BulkString(v)) = (&cmd_array[1], &cmd_array[2]) {
-        store.set(k.clone(), v.clone());
-        RespValue::SimpleString("OK".to_string())
+    let (RespValue::BulkString(k), RespValue::BulkString(v)) = (&cmd_array[1], &cmd_array[2])
+    else {
+        return RespValue::Error("ERR arguments must be bulk strings".to_string());
+    };
+
+    let options = match parse_set_options(&cmd_array[3..]) {
+        Ok(options) => options,
+        Err(e) => return RespValue::Error(e),
+    };
+
+    match store.set_with_options(k.to_string(), v.to_string(), options) {
+        Ok((written, old_value)) => {
+            if options.get {
+                match old_value {
+                    Some(old) => RespValue::BulkString(old.into()),
+                    None => RespValue::Null,
+                }
+            } else if written {
+                RespValue::SimpleString("OK".to_string())
+            } else {
+                RespValue::Null
+            }
+        }
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn parse_set_options(args: &[RespValue]) -> Result<SetOptions, String> {
+    let mut options = SetOptions::default();
+    let mut has_condition = false;
+    let mut has_expiry = false;
+
+    let mut i = 0;
+    while i < args.len() {
+        let RespValue::BulkString(arg) = &args[i] else {
+            return Err("ERR syntax error".to_string());
+        };
+        match arg.to_uppercase().as_str() {
+            "NX" | "XX" if !has_condition => {
+                has_condition = true;
+                options.condition = if arg.eq_ignore_ascii_case("NX") {
+                    SetCondition::IfNotExists
+                } else {
+                    SetCondition::IfExists
+                };
+            }
+            "GET" => options.get = true,
+            "KEEPTTL" if !has_expiry => {
+                has_expiry = true;
+                options.expiry = SetExpiry::Keep;
+            }
+            unit @ ("EX" | "PX" | "EXAT" | "PXAT") if !has_expiry => {
+                has_expiry = true;
+                i += 1;
+                let ttl = parse_expiry_option(unit, args.get(i), "set")?;
+                options.expiry = SetExpiry::After(ttl);
+            }
+            _ => return Err("ERR syntax error".to_string()),
+        }
+        i += 1;
+    }
+    Ok(options)
+}
+
+/// Parse the argument of an EX/PX/EXAT/PXAT option into the time remaining from now
+fn parse_expiry_option(
+    unit: &str,
+    arg: Option<&RespValue>,
+    cmd_name: &str,
+) -> Result<Duration, String> {
+    let amount = match arg {
+        Some(RespValue::BulkString(n)) => n
+            .parse::<i64>()
+            .map_err(|_| "ERR value is not an integer or out of range".to_string())?,
+        _ => return Err("ERR syntax error".to_string()),
+    };
+    let (time_unit, base) = match unit {
+        "EX" => (TimeUnit::Seconds, unix_millis()),
+        "PX" => (TimeUnit::Milliseconds, unix_millis()),
+        "EXAT" => (TimeUnit::Seconds, 0),
+        _ => (TimeUnit::Milliseconds, 0),
+    };
+    let at = match time_unit.millis_after(amount, base) {
+        Some(at) if amount > 0 => at,
+        _ => return Err(format!("ERR invalid expire time in '{}' command", cmd_name)),
+    };
+    Ok(duration_until_unix(Duration::from_millis(at as u64)))
+}
+
+fn handle_getdel(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'getdel' command".to_string());
+    }
+    if let RespValue::BulkString(k) = &cmd_array[1] {
+        match store.getdel(k) {
+            Ok(Some(v)) => RespValue::BulkString(v.into()),
+            Ok(None) => RespValue::Null,
+            Err(e) => RespValue::Error(e),
+        }
     } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_getex(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+    //     PXAT unix-time-milliseconds | PERSIST]
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'getex' command".to_string());
+    }
+    let RespValue::BulkString(k) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+
+    let expiry = match &cmd_array[2..] {
+        [] => SetExpiry::Keep,
+        [RespValue::BulkString(opt)] if opt.eq_ignore_ascii_case("PERSIST") => SetExpiry::Clear,
+        [RespValue::BulkString(unit), arg] => {
+            let unit = unit.to_uppercase();
+            if !matches!(unit.as_str(), "EX" | "PX" | "EXAT" | "PXAT") {
+                return RespValue::Error("ERR syntax error".to_string());
+            }
+            match parse_expiry_option(&unit, Some(arg), "getex") {
+                Ok(ttl) => SetExpiry::After(ttl),
+                Err(e) => return RespValue::Error(e),
+            }
+        }
+        _ => return RespValue::Error("ERR syntax error".to_string()),
+    };
+
+    match store.getex(k, expiry) {
+        Ok(Some(v)) => RespValue::BulkString(v.into()),
+        Ok(None) => RespValue::Null,
+        Err(e) => RespValue::Error(e),
     }
 }
 
+/// Convert an absolute unix timestamp into the time remaining from now
+/// (zero if the timestamp is already in the past)
+fn duration_until_unix(timestamp: Duration) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    timestamp.saturating_sub(now)
+}
+
+/// Current Unix time in milliseconds
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
 fn handle_get(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
     if cmd_array.len() != 2 {
-        return RespValue::SimpleString("ERR wrong number of arguments for get".to_string());
+        return RespValue::Error("ERR wrong number of arguments for get".to_string());
     }
     if let RespValue::BulkString(k) = &cmd_array[1] {
         match store.get(k) {
-            Some(v) => RespValue::BulkString(v),
+            Some(v) => RespValue::BulkString(v.into()),
             None => RespValue::Null,
         }
     } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+        RespValue::Error("ERR key must be a bulk string".to_string())
     }
 }
 
@@ -146,18 +1153,16 @@ fn handle_ping(cmd_array: &[RespValue]) -> RespValue {
         if let RespValue::BulkString(msg) = &cmd_array[1] {
             RespValue::BulkString(msg.clone())
         } else {
-            RespValue::SimpleString("ERR wrong argument type".to_string())
+            RespValue::Error("ERR wrong argument type".to_string())
         }
     } else {
-        RespValue::SimpleString("ERR wrong number of arguments for 'ping'".to_string())
+        RespValue::Error("ERR wrong number of arguments for 'ping'".to_string())
     }
 }
 
 fn handle_exists(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
     if cmd_array.len() < 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'exists' command".to_string(),
-        );
+        return RespValue::Error("ERR wrong number of arguments for 'exists' command".to_string());
     }
     let mut exists_count = 0;
 
@@ -167,7 +1172,7 @@ fn handle_exists(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
                 exists_count += 1;
             }
         } else {
-            return RespValue::SimpleString("ERR all keys must be bulk strings".to_string());
+            return RespValue::Error("ERR all keys must be bulk strings".to_string());
         }
     }
     RespValue::Integer(exists_count)
@@ -176,9 +1181,7 @@ fn handle_exists(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
 fn handle_del(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
     // DEL requires at least one key
     if cmd_array.len() < 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'del' command".to_string(),
-        );
+        return RespValue::Error("ERR wrong number of arguments for 'del' command".to_string());
     }
 
     let mut deleted_count = 0;
@@ -191,762 +1194,3966 @@ fn handle_del(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
                 deleted_count += 1;
             }
         } else {
-            return RespValue::SimpleString("ERR all keys must be bulk strings".to_string());
+            return RespValue::Error("ERR all keys must be bulk strings".to_string());
         }
     }
 
     RespValue::Integer(deleted_count)
 }
 
-fn handle_mget(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+fn handle_unlink(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
     if cmd_array.len() < 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'mget' command".to_string(),
-        );
+        return RespValue::Error("ERR wrong number of arguments for 'unlink' command".to_string());
     }
-    let mut res: Vec<RespValue> = vec![];
+
+    let mut unlinked_count = 0;
     for key_value in &cmd_array[1..] {
-        if let RespValue::BulkString(s) = key_value {
-            res.push(match store.get(s) {
-                Some(value) => RespValue::BulkString(value),
-                None => RespValue::Null,
-            })
+        if let RespValue::BulkString(key) = key_value {
+            if store.unlink(key) {
+                unlinked_count += 1;
+            }
         } else {
-            return RespValue::SimpleString("ERR all keys must be bulk strings".to_string());
+            return RespValue::Error("ERR all keys must be bulk strings".to_string());
         }
     }
-    RespValue::Array(res)
+
+    RespValue::Integer(unlinked_count)
 }
 
-fn handle_mset(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 2 {
-        return RespValue::SimpleString("ERR Wrong number of arguments for 'mset'".to_string());
-    }
-    if cmd_array.len() % 2 != 1 {
-        return RespValue::SimpleString("ERR Wrong number of arguments for 'mset'".to_string());
-    }
-    for key_value in &cmd_array[1..] {
-        if let RespValue::BulkString(_) = key_value {
-            continue;
-        } else {
-            return RespValue::SimpleString(
-                "ERR all arguments to mset must be bulk strings".to_string(),
-            );
-        }
-    }
-    for i in (1..cmd_array.len()).step_by(2) {
-        let key = &cmd_array[i];
-        let value = &cmd_array[i + 1];
-        if let RespValue::BulkString(k) = key
-            && let RespValue::BulkString(v) = value
-        {
-            store.set(k.clone(), v.clone());
-        }
+fn handle_copy(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // COPY source destination [REPLACE]
+    if cmd_array.len() < 3 || cmd_array.len() > 4 {
+        return RespValue::Error("ERR wrong number of arguments for 'copy' command".to_string());
     }
-    RespValue::SimpleString("OK".to_string())
+    let (RespValue::BulkString(src), RespValue::BulkString(dst)) = (&cmd_array[1], &cmd_array[2])
+    else {
+        return RespValue::Error("ERR arguments must be bulk strings".to_string());
+    };
+    let replace = match cmd_array.get(3) {
+        None => false,
+        Some(RespValue::BulkString(opt)) if opt.eq_ignore_ascii_case("REPLACE") => true,
+        Some(_) => return RespValue::Error("ERR syntax error".to_string()),
+    };
+
+    let copied = store.copy(src, dst, replace);
+    RespValue::Integer(if copied { 1 } else { 0 })
 }
 
-fn handle_expire(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+fn handle_move(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // MOVE key db
     if cmd_array.len() != 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'expire' command".to_string(),
-        );
+        return RespValue::Error("ERR wrong number of arguments for 'move' command".to_string());
     }
-
-    if let (RespValue::BulkString(key), RespValue::BulkString(seconds_str)) =
-        (&cmd_array[1], &cmd_array[2])
-    {
-        // Parse seconds
-        match seconds_str.parse::<u64>() {
-            Ok(seconds) => {
-                let result = store.expire(key, seconds);
-                RespValue::Integer(if result { 1 } else { 0 })
-            }
-            Err(_) => {
-                RespValue::SimpleString("ERR value is not an integer or out of range".to_string())
-            }
-        }
-    } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+    match parse_db_index(&cmd_array[2]).and_then(|db| store.move_key(key, db)) {
+        Ok(moved) => RespValue::Integer(if moved { 1 } else { 0 }),
+        Err(e) => RespValue::Error(e),
     }
 }
 
-fn handle_ttl(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+fn handle_dump(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
     if cmd_array.len() != 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'ttl' command".to_string(),
-        );
+        return RespValue::Error("ERR wrong number of arguments for 'dump' command".to_string());
     }
-
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        match store.ttl(key) {
-            Some(ttl) => RespValue::Integer(ttl),
-            None => RespValue::Integer(-2), // Key doesn't exist
-        }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+    match store.get_value(key) {
+        // Arguments are decoded as UTF-8 text, invalid sequences replaced, so
+        // a raw payload sent back to RESTORE would arrive corrupted; hex
+        // survives the trip. The payload is FerroDB's own format, which Redis
+        // can't RESTORE either way
+        Some(data) => RespValue::BulkString(to_hex(&crate::persistance::dump_value(&data)).into()),
+        None => RespValue::Null,
     }
 }
 
-fn handle_persist(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'persist' command".to_string(),
-        );
+fn handle_restore(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // RESTORE key ttl serialized-value [REPLACE] [ABSTTL]
+    if cmd_array.len() < 4 {
+        return RespValue::Error("ERR wrong number of arguments for 'restore' command".to_string());
     }
+    let (
+        RespValue::BulkString(key),
+        RespValue::BulkString(ttl_str),
+        RespValue::BulkString(payload),
+    ) = (&cmd_array[1], &cmd_array[2], &cmd_array[3])
+    else {
+        return RespValue::Error("ERR arguments must be bulk strings".to_string());
+    };
 
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        let result = store.persist(key);
-        RespValue::Integer(if result { 1 } else { 0 })
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+    let mut replace = false;
+    let mut absttl = false;
+    for opt in &cmd_array[4..] {
+        match opt {
+            RespValue::BulkString(o) if o.eq_ignore_ascii_case("REPLACE") => replace = true,
+            RespValue::BulkString(o) if o.eq_ignore_ascii_case("ABSTTL") => absttl = true,
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        }
     }
-}
 
-fn handle_setex(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    // SETEX key seconds value
-    if cmd_array.len() != 4 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'setex' command".to_string(),
-        );
-    }
+    let ttl_ms = match ttl_str.parse::<i64>() {
+        Ok(t) if t >= 0 => t as u64,
+        _ => return RespValue::Error("ERR Invalid TTL value, must be >= 0".to_string()),
+    };
 
-    if let (
-        RespValue::BulkString(key),
-        RespValue::BulkString(seconds_str),
-        RespValue::BulkString(value),
-    ) = (&cmd_array[1], &cmd_array[2], &cmd_array[3])
+    let data = match from_hex(payload)
+        .and_then(|bytes| crate::persistance::restore_value(&bytes).ok())
     {
-        match seconds_str.parse::<u64>() {
-            Ok(seconds) => {
-                store.set_with_expiry(key.clone(), value.clone(), seconds);
-                RespValue::SimpleString("OK".to_string())
-            }
-            Err(_) => {
-                RespValue::SimpleString("ERR value is not an integer or out of range".to_string())
-            }
+        Some(data) => data,
+        None => {
+            return RespValue::Error("ERR DUMP payload version or checksum are wrong".to_string());
         }
+    };
+
+    let ttl = if ttl_ms == 0 {
+        None
+    } else if absttl {
+        // A time already past comes out as zero: the key is deleted
+        Some(duration_until_unix(Duration::from_millis(ttl_ms)))
     } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
+        Some(Duration::from_millis(ttl_ms))
+    };
+
+    match store.restore(key.to_string(), data, ttl, replace) {
+        Ok(()) => RespValue::SimpleString("OK".to_string()),
+        Err(e) => RespValue::Error(e),
     }
 }
 
-fn handle_lpush(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 3 {
-        return RespValue::SimpleString(
-            "ERR Wrong number of arguments for 'lpush' command".to_string(),
-        );
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [AUTH password | AUTH2 username password] [KEYS key [key ...]]: RESTORE
+/// the keys' DUMP on another server, then delete them here unless COPY.
+/// Nothing else runs meanwhile, so the keys can't change on the way
+async fn handle_migrate(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+) -> RespValue {
+    let mut args = Vec::with_capacity(cmd_array.len());
+    for arg in cmd_array {
+        let RespValue::BulkString(arg) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(arg.as_str());
     }
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        let mut values = Vec::new();
-        for val in &cmd_array[2..] {
-            if let RespValue::BulkString(s) = val {
-                values.push(s.clone());
-            } else {
-                return RespValue::SimpleString("ERR all values must be bulk strings".to_string());
+    if args.len() < 6 {
+        return RespValue::Error("ERR wrong number of arguments for 'migrate' command".to_string());
+    }
+    let (Ok(port), Ok(db), Ok(timeout)) = (
+        args[2].parse::<u16>(),
+        args[4].parse::<usize>(),
+        args[5].parse::<i64>(),
+    ) else {
+        return RespValue::Error("ERR value is not an integer or out of range".to_string());
+    };
+    // As in Redis, a timeout that isn't positive means a second
+    let timeout = Duration::from_millis(if timeout > 0 { timeout as u64 } else { 1000 });
+
+    let mut copy = false;
+    let mut replace = false;
+    let mut auth = Vec::new();
+    let mut keys = vec![args[3]];
+    let mut i = 6;
+    while i < args.len() {
+        match args[i].to_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "AUTH" if i + 1 < args.len() => {
+                auth = vec!["AUTH", args[i + 1]];
+                i += 1;
             }
+            "AUTH2" if i + 2 < args.len() => {
+                auth = vec!["AUTH", args[i + 1], args[i + 2]];
+                i += 2;
+            }
+            "KEYS" => {
+                if !args[3].is_empty() {
+                    return RespValue::Error(
+                        "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                            .to_string(),
+                    );
+                }
+                keys = args[i + 1..].to_vec();
+                break;
+            }
+            _ => return RespValue::Error("ERR syntax error".to_string()),
         }
-        match store.lpush(key, values) {
-            Ok(len) => RespValue::Integer(len as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
-        }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+        i += 1;
     }
-}
 
-fn handle_rpush(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 3 {
-        return RespValue::SimpleString(
-            "ERR Wrong number of arguments for 'lpush' command".to_string(),
-        );
-    }
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        let mut values = Vec::new();
-        for val in &cmd_array[2..] {
-            if let RespValue::BulkString(s) = val {
-                values.push(s.clone());
-            } else {
-                return RespValue::SimpleString("ERR all values must be bulk strings".to_string());
+    // A node importing the keys' slot only takes them as if asked
+    let restore_command = match store.config().read().cluster_enabled {
+        true => "RESTORE-ASKING",
+        false => "RESTORE",
+    };
+    let restores: Vec<(&str, RespValue)> = keys
+        .iter()
+        .filter_map(|&key| {
+            let data = store.get_value(key)?;
+            // The rest of the key's time to live goes with it; 0 is none
+            let ttl = match store.pttl(key) {
+                Some(ms) if ms >= 0 => ms.max(1),
+                _ => 0,
+            };
+            let mut restore = vec![
+                RespValue::bulk(restore_command),
+                RespValue::bulk(key.to_string()),
+                RespValue::bulk(ttl.to_string()),
+                RespValue::bulk(to_hex(&crate::persistance::dump_value(&data))),
+            ];
+            if replace {
+                restore.push(RespValue::bulk("REPLACE"));
             }
+            Some((key, RespValue::Array(restore)))
+        })
+        .collect();
+    if restores.is_empty() {
+        return RespValue::SimpleString("NOKEY".to_string());
+    }
+
+    let mut target = match tokio::time::timeout(timeout, TcpStream::connect((args[1], port))).await
+    {
+        Ok(Ok(socket)) => MigrateTarget {
+            socket,
+            decoder: RespDecoder::new(),
+            timeout,
+        },
+        _ => {
+            return RespValue::Error("IOERR error or timeout connecting to the client".to_string());
         }
-        match store.rpush(key, values) {
-            Ok(len) => RespValue::Integer(len as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
-        }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+    };
+    // Nothing is restored unless the target took the password and database
+    let mut setup = Vec::new();
+    if !auth.is_empty() {
+        setup.push(RespValue::Array(
+            auth.into_iter()
+                .map(|arg| RespValue::bulk(arg.to_string()))
+                .collect(),
+        ));
     }
-}
-fn handle_lpop(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 2 || cmd_array.len() > 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'lpop' command".to_string(),
-        );
+    setup.push(RespValue::Array(vec![
+        RespValue::bulk("SELECT"),
+        RespValue::bulk(db.to_string()),
+    ]));
+    let replies = match target.send(&setup).await {
+        Ok(replies) => replies,
+        Err(e) => return e,
+    };
+    if let Some(e) = replies.iter().find_map(RespValue::error_message) {
+        return target_error(e);
     }
 
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        let count = if cmd_array.len() == 3 {
-            if let RespValue::BulkString(count_str) = &cmd_array[2] {
-                match count_str.parse::<usize>() {
-                    Ok(c) => Some(c),
-                    Err(_) => {
-                        return RespValue::SimpleString("ERR value is not an integer".to_string());
-                    }
-                }
-            } else {
-                return RespValue::SimpleString("ERR count must be a bulk string".to_string());
-            }
-        } else {
-            None
-        };
-
-        match store.lpop(key, count) {
-            Ok(values) => {
-                if values.is_empty() {
-                    RespValue::Null
-                } else if count.is_none() {
-                    // Single pop returns single value
-                    RespValue::BulkString(values[0].clone())
-                } else {
-                    // Multiple pop returns array
-                    RespValue::Array(values.into_iter().map(RespValue::BulkString).collect())
-                }
-            }
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
+    let replies = match target
+        .send(
+            &restores
+                .iter()
+                .map(|(_, restore)| restore.clone())
+                .collect::<Vec<_>>(),
+        )
+        .await
+    {
+        Ok(replies) => replies,
+        Err(e) => return e,
+    };
+    // Keys the target took are gone from here even if others failed
+    let mut error = None;
+    let mut deleted = vec![RespValue::bulk("DEL")];
+    for ((key, _), reply) in restores.iter().zip(&replies) {
+        if let Some(e) = reply.error_message() {
+            error.get_or_insert_with(|| target_error(e));
+        } else if !copy && store.delete(key) {
+            deleted.push(RespValue::bulk(key.to_string()));
         }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
     }
+    if deleted.len() > 1
+        && let Some(aof_writer) = aof
+    {
+        aof_writer.log_command(store.db_index(), &RespValue::Array(deleted));
+    }
+    error.unwrap_or_else(|| RespValue::SimpleString("OK".to_string()))
 }
 
-fn handle_rpop(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 2 || cmd_array.len() > 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'rpop' command".to_string(),
-        );
-    }
+fn target_error(e: &str) -> RespValue {
+    RespValue::Error(format!("ERR Target instance replied with error: {}", e))
+}
 
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        let count = if cmd_array.len() == 3 {
-            if let RespValue::BulkString(count_str) = &cmd_array[2] {
-                match count_str.parse::<usize>() {
-                    Ok(c) => Some(c),
-                    Err(_) => {
-                        return RespValue::SimpleString("ERR value is not an integer".to_string());
-                    }
-                }
-            } else {
-                return RespValue::SimpleString("ERR count must be a bulk string".to_string());
-            }
-        } else {
-            None
-        };
+/// MIGRATE's client connection to the server it moves keys to
+struct MigrateTarget {
+    socket: TcpStream,
+    decoder: RespDecoder,
+    /// How long each write or read may take
+    timeout: Duration,
+}
 
-        match store.rpop(key, count) {
-            Ok(values) => {
-                if values.is_empty() {
-                    RespValue::Null
-                } else if count.is_none() {
-                    RespValue::BulkString(values[0].clone())
-                } else {
-                    RespValue::Array(values.into_iter().map(RespValue::BulkString).collect())
+impl MigrateTarget {
+    /// Pipeline `requests`, returning a reply to each
+    async fn send(&mut self, requests: &[RespValue]) -> Result<Vec<RespValue>, RespValue> {
+        let encoded: String = requests.iter().map(RespValue::encode).collect();
+        if !matches!(
+            tokio::time::timeout(self.timeout, self.socket.write_all(encoded.as_bytes())).await,
+            Ok(Ok(()))
+        ) {
+            return Err(RespValue::Error(
+                "IOERR error or timeout writing to target instance".to_string(),
+            ));
+        }
+        let mut replies = Vec::with_capacity(requests.len());
+        let mut buffer = [0; 16 * 1024];
+        while replies.len() < requests.len() {
+            match self.decoder.decode() {
+                Decoded::Frame(reply) => {
+                    replies.push(reply);
+                    continue;
                 }
+                Decoded::NeedMoreData => {}
+                Decoded::Error(_) => break,
+            }
+            match tokio::time::timeout(self.timeout, self.socket.read(&mut buffer)).await {
+                Ok(Ok(read)) if read > 0 => self.decoder.extend(&buffer[..read]),
+                _ => break,
             }
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
         }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+        if replies.len() < requests.len() {
+            return Err(RespValue::Error(
+                "IOERR error or timeout reading to target instance".to_string(),
+            ));
+        }
+        Ok(replies)
     }
 }
 
-fn handle_llen(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'llen' command".to_string(),
-        );
-    }
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        match store.llen(key) {
-            Ok(len) => RespValue::Integer(len as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
-        }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
     }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
 }
 
-fn handle_lrange(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 4 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'lrange' command".to_string(),
-        );
-    }
+/// Options shared by SCAN, SSCAN and ZSCAN
+struct ScanOptions<'a> {
+    pattern: Option<&'a str>,
+    count: usize,
+    type_filter: Option<&'a str>,
+}
 
-    if let (
-        RespValue::BulkString(key),
-        RespValue::BulkString(start_str),
-        RespValue::BulkString(stop_str),
-    ) = (&cmd_array[1], &cmd_array[2], &cmd_array[3])
-    {
-        let start = match start_str.parse::<i64>() {
-            Ok(s) => s,
-            Err(_) => return RespValue::SimpleString("ERR value is not an integer".to_string()),
-        };
+/// Parse a cursor argument
+fn parse_cursor(arg: &RespValue) -> Result<u64, String> {
+    match arg {
+        RespValue::BulkString(c) => c
+            .parse::<u64>()
+            .map_err(|_| "ERR invalid cursor".to_string()),
+        _ => Err("ERR invalid cursor".to_string()),
+    }
+}
 
-        let stop = match stop_str.parse::<i64>() {
-            Ok(s) => s,
-            Err(_) => return RespValue::SimpleString("ERR value is not an integer".to_string()),
+/// Parse `[MATCH pattern] [COUNT count]` (plus `[TYPE type]` if `allow_type`)
+fn parse_scan_options(args: &[RespValue], allow_type: bool) -> Result<ScanOptions<'_>, String> {
+    let mut options = ScanOptions {
+        pattern: None,
+        count: 10,
+        type_filter: None,
+    };
+    let mut i = 0;
+    while i < args.len() {
+        let (RespValue::BulkString(opt), Some(RespValue::BulkString(arg))) =
+            (&args[i], args.get(i + 1))
+        else {
+            return Err("ERR syntax error".to_string());
         };
-
-        match store.lrange(key, start, stop) {
-            Ok(values) => RespValue::Array(values.into_iter().map(RespValue::BulkString).collect()),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
+        match opt.to_uppercase().as_str() {
+            "MATCH" => options.pattern = Some(arg.as_str()),
+            "COUNT" => match arg.parse::<usize>() {
+                Ok(c) if c > 0 => options.count = c,
+                Ok(_) => return Err("ERR syntax error".to_string()),
+                Err(_) => return Err("ERR value is not an integer or out of range".to_string()),
+            },
+            "TYPE" if allow_type => options.type_filter = Some(arg.as_str()),
+            _ => return Err("ERR syntax error".to_string()),
         }
-    } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
+        i += 2;
     }
+    Ok(options)
 }
 
-async fn handle_save(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 1 {
-        return RespValue::SimpleString(
-            "ERR Wrong number of arguments for 'save' command".to_string(),
-        );
+fn handle_scan(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'scan' command".to_string());
     }
+    let cursor = match parse_cursor(&cmd_array[1]) {
+        Ok(c) => c,
+        Err(e) => return RespValue::Error(e),
+    };
+    let options = match parse_scan_options(&cmd_array[2..], true) {
+        Ok(options) => options,
+        Err(e) => return RespValue::Error(e),
+    };
 
-    match crate::persistance::save_rdb(store, "dump.rdb").await {
-        Ok(_) => RespValue::SimpleString("OK".to_string()),
-        Err(e) => RespValue::SimpleString(format!("ERR {}", e)),
-    }
+    let (next_cursor, keys) =
+        store.scan(cursor, options.count, options.pattern, options.type_filter);
+    RespValue::Array(vec![
+        RespValue::BulkString(next_cursor.to_string().into()),
+        RespValue::Array(keys.into_iter().map(RespValue::bulk).collect()),
+    ])
 }
 
-fn handle_bgsave(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 1 {
-        return RespValue::SimpleString(
-            "ERR Wrong number of arguments for 'save' command".to_string(),
-        );
+fn handle_sscan(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // SSCAN key cursor [MATCH pattern] [COUNT count]
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'sscan' command".to_string());
+    }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+    let cursor = match parse_cursor(&cmd_array[2]) {
+        Ok(c) => c,
+        Err(e) => return RespValue::Error(e),
+    };
+    let options = match parse_scan_options(&cmd_array[3..], false) {
+        Ok(options) => options,
+        Err(e) => return RespValue::Error(e),
+    };
+
+    match store.sscan(key, cursor, options.count, options.pattern) {
+        Ok((next_cursor, members)) => RespValue::Array(vec![
+            RespValue::BulkString(next_cursor.to_string().into()),
+            RespValue::Array(members.into_iter().map(RespValue::bulk).collect()),
+        ]),
+        Err(e) => RespValue::Error(e),
     }
-    let store_clone = store.clone();
-    tokio::spawn(async move {
-        match crate::persistance::save_rdb(&store_clone, "dump.rdb").await {
-            Ok(_) => println!("Background save completed"),
-            Err(e) => println!("Background save failed : {}", e),
-        }
-    });
-    RespValue::SimpleString("Background saving started".to_string())
-}
-fn handle_lastsave(_cmd_array: &[RespValue], _store: &FerroStore) -> RespValue {
-    // TODO: Track last save timestamp
-    RespValue::Integer(0)
 }
 
-fn handle_dbsize(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 1 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'dbsize' command".to_string(),
-        );
+fn handle_zscan(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // ZSCAN key cursor [MATCH pattern] [COUNT count]
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'zscan' command".to_string());
     }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+    let cursor = match parse_cursor(&cmd_array[2]) {
+        Ok(c) => c,
+        Err(e) => return RespValue::Error(e),
+    };
+    let options = match parse_scan_options(&cmd_array[3..], false) {
+        Ok(options) => options,
+        Err(e) => return RespValue::Error(e),
+    };
 
-    RespValue::Integer(store.dbsize() as i64)
+    match store.zscan(key, cursor, options.count, options.pattern) {
+        Ok((next_cursor, members)) => RespValue::Array(vec![
+            RespValue::BulkString(next_cursor.to_string().into()),
+            RespValue::Array(
+                members
+                    .into_iter()
+                    .flat_map(|(member, score)| {
+                        [
+                            RespValue::BulkString(member.into()),
+                            RespValue::BulkString(score.to_string().into()),
+                        ]
+                    })
+                    .collect(),
+            ),
+        ]),
+        Err(e) => RespValue::Error(e),
+    }
 }
-fn handle_bgrewriteaof(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+
+fn handle_randomkey(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
     if cmd_array.len() != 1 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'bgrewriteaof' command".to_string(),
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'randomkey' command".to_string(),
         );
     }
-
-    let data = store.get_all_data();
-
-    tokio::spawn(async move {
-        match crate::aof::rewrite_aof(data, "appendonly.aof").await {
-            Ok(_) => println!("AOF rewrite completed"),
-            Err(e) => eprintln!("AOF rewrite failed: {}", e),
-        }
-    });
-
-    RespValue::SimpleString("Background AOF rewrite started".to_string())
+    match store.random_key() {
+        Some(key) => RespValue::BulkString(key.into()),
+        None => RespValue::Null,
+    }
 }
 
-fn handle_sadd(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'sadd' command".to_string(),
-        );
+fn handle_touch(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'touch' command".to_string());
+    }
+    let mut keys = Vec::new();
+    for val in &cmd_array[1..] {
+        if let RespValue::BulkString(k) = val {
+            keys.push(k.to_string());
+        } else {
+            return RespValue::Error("ERR all keys must be bulk strings".to_string());
+        }
+    }
+    RespValue::Integer(store.touch(&keys) as i64)
+}
+
+fn handle_object(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // OBJECT IDLETIME key | OBJECT FREQ key | OBJECT ENCODING key
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'object' command".to_string());
+    }
+    let RespValue::BulkString(subcommand) = &cmd_array[1] else {
+        return RespValue::Error("ERR subcommand must be a bulk string".to_string());
+    };
+
+    match subcommand.to_uppercase().as_str() {
+        "IDLETIME" => {
+            let [_, _, RespValue::BulkString(key)] = cmd_array else {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'object|idletime' command".to_string(),
+                );
+            };
+            match store.idle_time(key) {
+                Some(idle) => RespValue::Integer(idle as i64),
+                None => RespValue::Null,
+            }
+        }
+        "FREQ" => {
+            let [_, _, RespValue::BulkString(key)] = cmd_array else {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'object|freq' command".to_string(),
+                );
+            };
+            match store.frequency(key) {
+                Some(frequency) => RespValue::Integer(frequency as i64),
+                None => RespValue::Null,
+            }
+        }
+        "ENCODING" => {
+            let [_, _, RespValue::BulkString(key)] = cmd_array else {
+                return RespValue::Error(
+                    "ERR wrong number of arguments for 'object|encoding' command".to_string(),
+                );
+            };
+            match store.encoding(key) {
+                Some(encoding) => RespValue::BulkString(encoding.into()),
+                None => RespValue::Null,
+            }
+        }
+        _ => RespValue::Error(format!("ERR unknown subcommand '{}'", subcommand.as_str())),
+    }
+}
+
+fn handle_memory(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // MEMORY USAGE key [SAMPLES count]
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(s.clone());
+    }
+
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("USAGE", [key, options @ ..]) => {
+            // Like Redis, collections are estimated from 5 elements by default
+            let samples = match options {
+                [] => 5,
+                [option, count] if option.eq_ignore_ascii_case("SAMPLES") => {
+                    match count.parse::<i64>() {
+                        Ok(count) if count >= 0 => count as usize,
+                        Ok(_) => return RespValue::Error("ERR syntax error".to_string()),
+                        Err(_) => {
+                            return RespValue::Error(
+                                "ERR value is not an integer or out of range".to_string(),
+                            );
+                        }
+                    }
+                }
+                _ => return RespValue::Error("ERR syntax error".to_string()),
+            };
+            match store.memory_usage(key, samples) {
+                Some(bytes) => RespValue::Integer(bytes as i64),
+                None => RespValue::Null,
+            }
+        }
+        ("USAGE", _) => {
+            RespValue::Error("ERR wrong number of arguments for 'memory|usage' command".to_string())
+        }
+        _ => RespValue::Error(format!(
+            "ERR unknown subcommand '{}'. Try MEMORY HELP.",
+            args[0].as_str()
+        )),
+    }
+}
+
+fn handle_mget(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'mget' command".to_string());
+    }
+    let mut res: Vec<RespValue> = vec![];
+    for key_value in &cmd_array[1..] {
+        if let RespValue::BulkString(s) = key_value {
+            res.push(match store.get(s) {
+                Some(value) => RespValue::BulkString(value.into()),
+                None => RespValue::Null,
+            })
+        } else {
+            return RespValue::Error("ERR all keys must be bulk strings".to_string());
+        }
+    }
+    RespValue::Array(res)
+}
+
+fn handle_mset(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR Wrong number of arguments for 'mset'".to_string());
+    }
+    if cmd_array.len() % 2 != 1 {
+        return RespValue::Error("ERR Wrong number of arguments for 'mset'".to_string());
+    }
+    for key_value in &cmd_array[1..] {
+        if let RespValue::BulkString(_) = key_value {
+            continue;
+        } else {
+            return RespValue::Error("ERR all arguments to mset must be bulk strings".to_string());
+        }
+    }
+    for i in (1..cmd_array.len()).step_by(2) {
+        let key = &cmd_array[i];
+        let value = &cmd_array[i + 1];
+        if let RespValue::BulkString(k) = key
+            && let RespValue::BulkString(v) = value
+        {
+            store.set(k.to_string(), v.to_string());
+        }
+    }
+    RespValue::SimpleString("OK".to_string())
+}
+
+fn handle_setnx(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'setnx' command".to_string());
+    }
+    if let (RespValue::BulkString(k), RespValue::BulkString(v)) = (&cmd_array[1], &cmd_array[2]) {
+        let result = store.setnx(k.to_string(), v.to_string());
+        RespValue::Integer(if result { 1 } else { 0 })
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_msetnx(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 3 || cmd_array.len() % 2 != 1 {
+        return RespValue::Error("ERR wrong number of arguments for 'msetnx' command".to_string());
+    }
+    let mut pairs = Vec::new();
+    for i in (1..cmd_array.len()).step_by(2) {
+        if let (RespValue::BulkString(k), RespValue::BulkString(v)) =
+            (&cmd_array[i], &cmd_array[i + 1])
+        {
+            pairs.push((k.to_string(), v.to_string()));
+        } else {
+            return RespValue::Error(
+                "ERR all arguments to msetnx must be bulk strings".to_string(),
+            );
+        }
+    }
+    let result = store.msetnx(pairs);
+    RespValue::Integer(if result { 1 } else { 0 })
+}
+
+/// Unit of a relative expiry argument (EXPIRE vs PEXPIRE, TTL vs PTTL, ...)
+#[derive(Clone, Copy, PartialEq)]
+enum TimeUnit {
+    Seconds,
+    Milliseconds,
+}
+
+impl TimeUnit {
+    /// Unix milliseconds `amount` units after `base`, or None past the
+    /// range of i64 milliseconds, where Redis refuses expire times too
+    fn millis_after(self, amount: i64, base: i64) -> Option<i64> {
+        let millis = match self {
+            TimeUnit::Seconds => amount.checked_mul(1000)?,
+            TimeUnit::Milliseconds => amount,
+        };
+        millis.checked_add(base)
+    }
+}
+
+/// Parse the trailing NX/XX/GT/LT flags of EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT
+fn parse_expire_condition(args: &[RespValue]) -> Result<ExpireCondition, String> {
+    let mut condition = ExpireCondition::default();
+    for arg in args {
+        let RespValue::BulkString(flag) = arg else {
+            return Err("ERR syntax error".to_string());
+        };
+        match flag.to_uppercase().as_str() {
+            "NX" => condition.nx = true,
+            "XX" => condition.xx = true,
+            "GT" => condition.gt = true,
+            "LT" => condition.lt = true,
+            other => return Err(format!("ERR Unsupported option {}", other)),
+        }
+    }
+
+    if condition.nx && (condition.xx || condition.gt || condition.lt) {
+        return Err(
+            "ERR NX and XX, GT or LT options at the same time are not compatible".to_string(),
+        );
+    }
+    if condition.gt && condition.lt {
+        return Err("ERR GT and LT options at the same time are not compatible".to_string());
+    }
+    Ok(condition)
+}
+
+fn handle_expire(cmd_array: &[RespValue], store: &FerroStore, unit: TimeUnit) -> RespValue {
+    let name = if unit == TimeUnit::Seconds {
+        "expire"
+    } else {
+        "pexpire"
+    };
+    if cmd_array.len() < 3 {
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+    let condition = match parse_expire_condition(&cmd_array[3..]) {
+        Ok(condition) => condition,
+        Err(e) => return RespValue::Error(e),
+    };
+
+    if let (RespValue::BulkString(key), RespValue::BulkString(amount_str)) =
+        (&cmd_array[1], &cmd_array[2])
+    {
+        let Ok(amount) = amount_str.parse::<i64>() else {
+            return RespValue::Error("ERR value is not an integer or out of range".to_string());
+        };
+        // A time in the past deletes the key
+        match unit.millis_after(amount, unix_millis()) {
+            Some(at) => {
+                let result = store.pexpire_at(key, at.max(0) as u64, condition);
+                RespValue::Integer(if result { 1 } else { 0 })
+            }
+            None => RespValue::Error(format!("ERR invalid expire time in '{}' command", name)),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_expireat(cmd_array: &[RespValue], store: &FerroStore, unit: TimeUnit) -> RespValue {
+    let name = if unit == TimeUnit::Seconds {
+        "expireat"
+    } else {
+        "pexpireat"
+    };
+    if cmd_array.len() < 3 {
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+    let condition = match parse_expire_condition(&cmd_array[3..]) {
+        Ok(condition) => condition,
+        Err(e) => return RespValue::Error(e),
+    };
+
+    if let (RespValue::BulkString(key), RespValue::BulkString(timestamp_str)) =
+        (&cmd_array[1], &cmd_array[2])
+    {
+        let Ok(timestamp) = timestamp_str.parse::<i64>() else {
+            return RespValue::Error("ERR value is not an integer or out of range".to_string());
+        };
+        match unit.millis_after(timestamp, 0) {
+            Some(at) => {
+                let result = store.pexpire_at(key, at.max(0) as u64, condition);
+                RespValue::Integer(if result { 1 } else { 0 })
+            }
+            None => RespValue::Error(format!("ERR invalid expire time in '{}' command", name)),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_expiretime(cmd_array: &[RespValue], store: &FerroStore, unit: TimeUnit) -> RespValue {
+    if cmd_array.len() != 2 {
+        let name = if unit == TimeUnit::Seconds {
+            "expiretime"
+        } else {
+            "pexpiretime"
+        };
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        match store.pexpire_time(key) {
+            Some(ms) if ms >= 0 && unit == TimeUnit::Seconds => RespValue::Integer(ms / 1000),
+            Some(ms) => RespValue::Integer(ms),
+            None => RespValue::Integer(-2), // Key doesn't exist
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_ttl(cmd_array: &[RespValue], store: &FerroStore, unit: TimeUnit) -> RespValue {
+    if cmd_array.len() != 2 {
+        let name = if unit == TimeUnit::Seconds {
+            "ttl"
+        } else {
+            "pttl"
+        };
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let ttl = match unit {
+            TimeUnit::Seconds => store.ttl(key),
+            TimeUnit::Milliseconds => store.pttl(key),
+        };
+        match ttl {
+            Some(ttl) => RespValue::Integer(ttl),
+            None => RespValue::Integer(-2), // Key doesn't exist
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_persist(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'persist' command".to_string());
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let result = store.persist(key);
+        RespValue::Integer(if result { 1 } else { 0 })
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_setex(cmd_array: &[RespValue], store: &FerroStore, unit: TimeUnit) -> RespValue {
+    // SETEX key seconds value / PSETEX key milliseconds value
+    let name = if unit == TimeUnit::Seconds {
+        "setex"
+    } else {
+        "psetex"
+    };
+    if cmd_array.len() != 4 {
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+
+    if let (
+        RespValue::BulkString(key),
+        amount @ RespValue::BulkString(_),
+        RespValue::BulkString(value),
+    ) = (&cmd_array[1], &cmd_array[2], &cmd_array[3])
+    {
+        let unit = if unit == TimeUnit::Seconds {
+            "EX"
+        } else {
+            "PX"
+        };
+        match parse_expiry_option(unit, Some(amount), name) {
+            Ok(ttl) => {
+                store.set_with_expiry_ms(
+                    key.to_string(),
+                    value.to_string(),
+                    ttl.as_millis() as u64,
+                );
+                RespValue::SimpleString("OK".to_string())
+            }
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_lpush(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR Wrong number of arguments for 'lpush' command".to_string());
+    }
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let mut values = Vec::new();
+        for val in &cmd_array[2..] {
+            if let RespValue::BulkString(s) = val {
+                values.push(s.to_string());
+            } else {
+                return RespValue::Error("ERR all values must be bulk strings".to_string());
+            }
+        }
+        match store.lpush(key, values) {
+            Ok(len) => RespValue::Integer(len as i64),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_rpush(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR Wrong number of arguments for 'lpush' command".to_string());
+    }
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let mut values = Vec::new();
+        for val in &cmd_array[2..] {
+            if let RespValue::BulkString(s) = val {
+                values.push(s.to_string());
+            } else {
+                return RespValue::Error("ERR all values must be bulk strings".to_string());
+            }
+        }
+        match store.rpush(key, values) {
+            Ok(len) => RespValue::Integer(len as i64),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+fn handle_lpop(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 2 || cmd_array.len() > 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'lpop' command".to_string());
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let count = if cmd_array.len() == 3 {
+            if let RespValue::BulkString(count_str) = &cmd_array[2] {
+                match count_str.parse::<usize>() {
+                    Ok(c) => Some(c),
+                    Err(_) => {
+                        return RespValue::Error("ERR value is not an integer".to_string());
+                    }
+                }
+            } else {
+                return RespValue::Error("ERR count must be a bulk string".to_string());
+            }
+        } else {
+            None
+        };
+
+        match store.lpop(key, count) {
+            Ok(values) => {
+                if values.is_empty() {
+                    RespValue::Null
+                } else if count.is_none() {
+                    // Single pop returns single value
+                    RespValue::BulkString(values[0].clone().into())
+                } else {
+                    // Multiple pop returns array
+                    RespValue::Array(values.into_iter().map(RespValue::bulk).collect())
+                }
+            }
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_rpop(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 2 || cmd_array.len() > 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'rpop' command".to_string());
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let count = if cmd_array.len() == 3 {
+            if let RespValue::BulkString(count_str) = &cmd_array[2] {
+                match count_str.parse::<usize>() {
+                    Ok(c) => Some(c),
+                    Err(_) => {
+                        return RespValue::Error("ERR value is not an integer".to_string());
+                    }
+                }
+            } else {
+                return RespValue::Error("ERR count must be a bulk string".to_string());
+            }
+        } else {
+            None
+        };
+
+        match store.rpop(key, count) {
+            Ok(values) => {
+                if values.is_empty() {
+                    RespValue::Null
+                } else if count.is_none() {
+                    RespValue::BulkString(values[0].clone().into())
+                } else {
+                    RespValue::Array(values.into_iter().map(RespValue::bulk).collect())
+                }
+            }
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_llen(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'llen' command".to_string());
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        match store.llen(key) {
+            Ok(len) => RespValue::Integer(len as i64),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_lrange(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 4 {
+        return RespValue::Error("ERR wrong number of arguments for 'lrange' command".to_string());
+    }
+
+    if let (
+        RespValue::BulkString(key),
+        RespValue::BulkString(start_str),
+        RespValue::BulkString(stop_str),
+    ) = (&cmd_array[1], &cmd_array[2], &cmd_array[3])
+    {
+        let start = match start_str.parse::<i64>() {
+            Ok(s) => s,
+            Err(_) => return RespValue::Error("ERR value is not an integer".to_string()),
+        };
+
+        let stop = match stop_str.parse::<i64>() {
+            Ok(s) => s,
+            Err(_) => return RespValue::Error("ERR value is not an integer".to_string()),
+        };
+
+        match store.lrange(key, start, stop) {
+            Ok(values) => RespValue::Array(values.into_iter().map(RespValue::bulk).collect()),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn parse_list_end(arg: &RespValue) -> Option<ListEnd> {
+    match arg {
+        RespValue::BulkString(s) => match s.to_uppercase().as_str() {
+            "LEFT" => Some(ListEnd::Left),
+            "RIGHT" => Some(ListEnd::Right),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn handle_lmove(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // LMOVE source destination LEFT|RIGHT LEFT|RIGHT
+    if cmd_array.len() != 5 {
+        return RespValue::Error("ERR wrong number of arguments for 'lmove' command".to_string());
+    }
+
+    let (Some(from), Some(to)) = (parse_list_end(&cmd_array[3]), parse_list_end(&cmd_array[4]))
+    else {
+        return RespValue::Error("ERR syntax error".to_string());
+    };
+    if let (RespValue::BulkString(src), RespValue::BulkString(dst)) = (&cmd_array[1], &cmd_array[2])
+    {
+        match store.lmove(src, dst, from, to) {
+            Ok(Some(value)) => RespValue::BulkString(value.into()),
+            Ok(None) => RespValue::Null,
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_rpoplpush(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // RPOPLPUSH source destination == LMOVE source destination RIGHT LEFT
+    if cmd_array.len() != 3 {
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'rpoplpush' command".to_string(),
+        );
+    }
+
+    if let (RespValue::BulkString(src), RespValue::BulkString(dst)) = (&cmd_array[1], &cmd_array[2])
+    {
+        match store.lmove(src, dst, ListEnd::Right, ListEnd::Left) {
+            Ok(Some(value)) => RespValue::BulkString(value.into()),
+            Ok(None) => RespValue::Null,
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+/// Parse the shared `numkeys key [key ...] <direction> [COUNT count]` tail of
+/// LMPOP / ZMPOP, starting at `cmd_array[1]`
+/// Returns the keys, the uppercased direction and the count
+fn parse_mpop_args(
+    cmd_array: &[RespValue],
+    name: &str,
+) -> Result<(Vec<String>, String, usize), String> {
+    if cmd_array.len() < 4 {
+        return Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        match arg {
+            RespValue::BulkString(s) => args.push(s.to_string()),
+            _ => return Err("ERR arguments must be bulk strings".to_string()),
+        }
+    }
+
+    let numkeys = match args[0].parse::<usize>() {
+        Ok(n) if n > 0 => n,
+        _ => return Err("ERR numkeys should be greater than 0".to_string()),
+    };
+    if args.len() < numkeys + 2 {
+        return Err("ERR syntax error".to_string());
+    }
+    let keys = args[1..=numkeys].to_vec();
+    let direction = args[numkeys + 1].to_uppercase();
+
+    let count = match &args[numkeys + 2..] {
+        [] => 1,
+        [opt, n] if opt.eq_ignore_ascii_case("COUNT") => match n.parse::<usize>() {
+            Ok(c) if c > 0 => c,
+            _ => return Err("ERR count should be greater than 0".to_string()),
+        },
+        _ => return Err("ERR syntax error".to_string()),
+    };
+
+    Ok((keys, direction, count))
+}
+
+fn handle_lmpop(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count]
+    let (keys, direction, count) = match parse_mpop_args(cmd_array, "lmpop") {
+        Ok(args) => args,
+        Err(e) => return RespValue::Error(e),
+    };
+    let end = match direction.as_str() {
+        "LEFT" => ListEnd::Left,
+        "RIGHT" => ListEnd::Right,
+        _ => return RespValue::Error("ERR syntax error".to_string()),
+    };
+
+    match store.lmpop(&keys, end, count) {
+        Ok(Some((key, values))) => RespValue::Array(vec![
+            RespValue::BulkString(key.into()),
+            RespValue::Array(values.into_iter().map(RespValue::bulk).collect()),
+        ]),
+        Ok(None) => RespValue::Null,
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+/// Parse the timeout argument of a blocking command (seconds, fractional allowed)
+/// Zero means block indefinitely
+fn parse_block_timeout(arg: &RespValue) -> Result<Option<Duration>, String> {
+    let secs = match arg {
+        RespValue::BulkString(s) => s
+            .parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite())
+            .ok_or_else(|| "ERR timeout is not a float or out of range".to_string())?,
+        _ => return Err("ERR timeout is not a float or out of range".to_string()),
+    };
+    if secs < 0.0 {
+        return Err("ERR timeout is negative".to_string());
+    }
+    Ok((secs > 0.0).then(|| Duration::from_secs_f64(secs)))
+}
+
+/// Parse the `key [key ...] timeout` arguments shared by BLPOP, BRPOP and BZPOPMIN/MAX
+fn parse_blocking_keys(
+    cmd_array: &[RespValue],
+    name: &str,
+) -> Result<(Vec<String>, Option<Duration>), String> {
+    if cmd_array.len() < 3 {
+        return Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+
+    let mut keys = Vec::with_capacity(cmd_array.len() - 2);
+    for arg in &cmd_array[1..cmd_array.len() - 1] {
+        match arg {
+            RespValue::BulkString(key) => keys.push(key.to_string()),
+            _ => return Err("ERR keys must be bulk strings".to_string()),
+        }
+    }
+    let timeout = parse_block_timeout(&cmd_array[cmd_array.len() - 1])?;
+    Ok((keys, timeout))
+}
+
+/// Run `attempt` until it produces a value, sleeping on `keys` in between
+/// Returns Ok(None) if `timeout` elapses first, or right away if `may_block`
+/// is unset. `attempt` makes its change and logs it, so when blocking it
+/// runs under the shared exec lock like any other command; the lock is
+/// released while waiting. Without `may_block` the caller (EXEC, a script)
+/// already holds the lock
+async fn block_on_keys<T>(
+    store: &FerroStore,
+    keys: &[String],
+    timeout: Option<Duration>,
+    may_block: bool,
+    mut attempt: impl FnMut() -> Result<Option<T>, String>,
+) -> Result<Option<T>, String> {
+    if !may_block {
+        return attempt();
+    }
+    // Register before the first attempt so a push in between still wakes us
+    let guard = store.waiters().register(keys);
+    let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+
+    loop {
+        let shared = store.exec_lock().read().await;
+        if let Some(value) = attempt()? {
+            return Ok(Some(value));
+        }
+        drop(shared);
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, guard.wait())
+                    .await
+                    .is_err()
+                {
+                    return Ok(None);
+                }
+            }
+            None => guard.wait().await,
+        }
+    }
+}
+
+async fn handle_blpop(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    end: ListEnd,
+    aof: Option<&AofWriter>,
+    may_block: bool,
+) -> RespValue {
+    // BLPOP key [key ...] timeout
+    let (name, pop_cmd) = match end {
+        ListEnd::Left => ("blpop", "LPOP"),
+        ListEnd::Right => ("brpop", "RPOP"),
+    };
+    let (keys, timeout) = match parse_blocking_keys(cmd_array, name) {
+        Ok(args) => args,
+        Err(e) => return RespValue::Error(e),
+    };
+
+    match block_on_keys(store, &keys, timeout, may_block, || {
+        let popped = store.lmpop(&keys, end, 1)?;
+        // Log the pop that actually happened so replay never blocks
+        if let (Some(aof_writer), Some((key, _))) = (aof, &popped) {
+            aof_writer.log_command(
+                store.db_index(),
+                &RespValue::Array(vec![
+                    RespValue::BulkString(pop_cmd.to_string().into()),
+                    RespValue::BulkString(key.clone().into()),
+                ]),
+            );
+        }
+        Ok(popped)
+    })
+    .await
+    {
+        Ok(Some((key, mut values))) => RespValue::Array(vec![
+            RespValue::BulkString(key.into()),
+            RespValue::BulkString(values.remove(0).into()),
+        ]),
+        Ok(None) => RespValue::Null,
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+async fn handle_blmove(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    may_block: bool,
+) -> RespValue {
+    // BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout
+    if cmd_array.len() != 6 {
+        return RespValue::Error("ERR wrong number of arguments for 'blmove' command".to_string());
+    }
+
+    let (Some(from), Some(to)) = (parse_list_end(&cmd_array[3]), parse_list_end(&cmd_array[4]))
+    else {
+        return RespValue::Error("ERR syntax error".to_string());
+    };
+    let (RespValue::BulkString(src), RespValue::BulkString(dst)) = (&cmd_array[1], &cmd_array[2])
+    else {
+        return RespValue::Error("ERR arguments must be bulk strings".to_string());
+    };
+    let timeout = match parse_block_timeout(&cmd_array[5]) {
+        Ok(timeout) => timeout,
+        Err(e) => return RespValue::Error(e),
+    };
+
+    let keys = [src.to_string()];
+    match block_on_keys(store, &keys, timeout, may_block, || {
+        let moved = store.lmove(src, dst, from, to)?;
+        if let (Some(aof_writer), Some(_)) = (aof, &moved) {
+            let mut logged = cmd_array[..5].to_vec();
+            logged[0] = RespValue::BulkString("LMOVE".into());
+            aof_writer.log_command(store.db_index(), &RespValue::Array(logged));
+        }
+        Ok(moved)
+    })
+    .await
+    {
+        Ok(Some(value)) => RespValue::BulkString(value.into()),
+        Ok(None) => RespValue::Null,
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn handle_lindex(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'lindex' command".to_string());
+    }
+
+    if let (RespValue::BulkString(key), RespValue::BulkString(index_str)) =
+        (&cmd_array[1], &cmd_array[2])
+    {
+        let index = match index_str.parse::<i64>() {
+            Ok(i) => i,
+            Err(_) => return RespValue::Error("ERR value is not an integer".to_string()),
+        };
+
+        match store.lindex(key, index) {
+            Ok(Some(value)) => RespValue::BulkString(value.into()),
+            Ok(None) => RespValue::Null,
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_lset(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 4 {
+        return RespValue::Error("ERR wrong number of arguments for 'lset' command".to_string());
+    }
+
+    if let (
+        RespValue::BulkString(key),
+        RespValue::BulkString(index_str),
+        RespValue::BulkString(value),
+    ) = (&cmd_array[1], &cmd_array[2], &cmd_array[3])
+    {
+        let index = match index_str.parse::<i64>() {
+            Ok(i) => i,
+            Err(_) => return RespValue::Error("ERR value is not an integer".to_string()),
+        };
+
+        match store.lset(key, index, value.to_string()) {
+            Ok(()) => RespValue::SimpleString("OK".to_string()),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_linsert(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // LINSERT key BEFORE|AFTER pivot element
+    if cmd_array.len() != 5 {
+        return RespValue::Error("ERR wrong number of arguments for 'linsert' command".to_string());
+    }
+
+    if let (
+        RespValue::BulkString(key),
+        RespValue::BulkString(position),
+        RespValue::BulkString(pivot),
+        RespValue::BulkString(value),
+    ) = (&cmd_array[1], &cmd_array[2], &cmd_array[3], &cmd_array[4])
+    {
+        let before = match position.to_uppercase().as_str() {
+            "BEFORE" => true,
+            "AFTER" => false,
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        };
+
+        match store.linsert(key, before, pivot, value.to_string()) {
+            Ok(len) => RespValue::Integer(len),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+async fn handle_save(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 1 {
+        return RespValue::Error("ERR Wrong number of arguments for 'save' command".to_string());
+    }
+
+    let path = store.config().read().dbfilename.clone();
+    match crate::persistance::save_rdb(store, &path).await {
+        Ok(_) => RespValue::SimpleString("OK".to_string()),
+        Err(e) => RespValue::Error(format!("ERR {}", e)),
+    }
+}
+
+fn handle_bgsave(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 1 {
+        return RespValue::Error("ERR Wrong number of arguments for 'save' command".to_string());
+    }
+    let path = store.config().read().dbfilename.clone();
+    match crate::persistance::bgsave(store, &path) {
+        Ok(()) => RespValue::SimpleString("Background saving started".to_string()),
+        Err(e) => RespValue::Error(format!("ERR {}", e)),
+    }
+}
+fn handle_lastsave(_cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    RespValue::Integer(store.last_save() as i64)
+}
+
+fn handle_acl(cmd_array: &[RespValue], store: &FerroStore, conn: &ConnectionContext) -> RespValue {
+    // ACL SETUSER username [rule ...] | ACL GETUSER username | ACL DELUSER username [...]
+    // ACL LIST | ACL USERS | ACL WHOAMI | ACL CAT [category]
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(s.to_string());
+    }
+    let bulk_strings =
+        |items: Vec<String>| RespValue::Array(items.into_iter().map(RespValue::bulk).collect());
+
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("SETUSER", [name, rules @ ..]) => match store.acl().set_user(name, rules) {
+            Ok(()) => RespValue::SimpleString("OK".to_string()),
+            Err(e) => RespValue::Error(e),
+        },
+        ("GETUSER", [name]) => match store.acl().get_user(name) {
+            Some(user) => RespValue::Array(
+                user.info()
+                    .into_iter()
+                    .flat_map(|(field, value)| {
+                        let value = match value {
+                            UserField::Text(text) => RespValue::BulkString(text.into()),
+                            UserField::List(items) => bulk_strings(items),
+                        };
+                        [RespValue::BulkString(field.to_string().into()), value]
+                    })
+                    .collect(),
+            ),
+            None => RespValue::Null,
+        },
+        ("DELUSER", names) if !names.is_empty() => match store.acl().delete_users(names) {
+            Ok(deleted) => RespValue::Integer(deleted as i64),
+            Err(e) => RespValue::Error(e),
+        },
+        ("LIST", []) => bulk_strings(store.acl().list()),
+        ("USERS", []) => bulk_strings(store.acl().usernames()),
+        ("WHOAMI", []) => RespValue::bulk(conn.user.as_deref().unwrap_or("default")),
+        ("CAT", []) => bulk_strings(CATEGORIES.iter().map(|c| c.to_string()).collect()),
+        ("CAT", [category]) => match AclRegistry::category_commands(category) {
+            Some(commands) => bulk_strings(commands),
+            None => RespValue::Error(format!(
+                "ERR Unknown category '{}'",
+                category.to_lowercase()
+            )),
+        },
+        ("SETUSER" | "GETUSER" | "DELUSER" | "LIST" | "USERS" | "WHOAMI" | "CAT", _) => {
+            RespValue::Error(format!(
+                "ERR wrong number of arguments for 'acl|{}' command",
+                subcommand.to_lowercase()
+            ))
+        }
+        _ => RespValue::Error(format!(
+            "ERR unknown subcommand '{}'. Try ACL HELP.",
+            args[0]
+        )),
+    }
+}
+
+async fn handle_debug(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // DEBUG SLEEP seconds | DEBUG OBJECT key | DEBUG SET-ACTIVE-EXPIRE 0|1
+    // DEBUG RELOAD
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(s.as_str());
+    }
+
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("SLEEP", [seconds]) => match seconds.parse::<f64>() {
+            Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => {
+                tokio::time::sleep(Duration::from_secs_f64(seconds)).await;
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => RespValue::Error("ERR value is not a valid float".to_string()),
+        },
+        ("OBJECT", [key]) => {
+            let Some(data) = store.get_value(key) else {
+                return RespValue::Error("ERR no such key".to_string());
+            };
+            RespValue::SimpleString(format!(
+                "type:{} encoding:{} serializedlength:{} lru_seconds_idle:{} ttl:{}",
+                data.type_name(),
+                data.encoding(),
+                crate::persistance::serialized_length(&data),
+                store.idle_time(key).unwrap_or(0),
+                store.ttl(key).unwrap_or(-2),
+            ))
+        }
+        ("SET-ACTIVE-EXPIRE", [flag]) => match *flag {
+            "0" | "1" => {
+                store.set_active_expire(*flag == "1");
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => RespValue::Error("ERR value is not an integer or out of range".to_string()),
+        },
+        // Save the snapshot, empty every database and load it back
+        ("RELOAD", []) => {
+            let path = store.config().read().dbfilename.clone();
+            if let Err(e) = crate::persistance::save_rdb(store, &path).await {
+                return RespValue::Error(format!("ERR Error trying to save the DB: {}", e));
+            }
+            store.flush_all();
+            match crate::persistance::load_rdb(store, &path).await {
+                Ok(()) => RespValue::SimpleString("OK".to_string()),
+                Err(e) => RespValue::Error(format!("ERR Error trying to load the RDB dump: {}", e)),
+            }
+        }
+        ("SLEEP" | "OBJECT" | "SET-ACTIVE-EXPIRE" | "RELOAD", _) => RespValue::Error(format!(
+            "ERR wrong number of arguments for 'debug|{}' command",
+            subcommand.to_lowercase()
+        )),
+        _ => RespValue::Error(format!("ERR unknown subcommand '{}'", args[0])),
+    }
+}
+
+fn handle_config(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+) -> RespValue {
+    // CONFIG GET pattern [pattern ...] | CONFIG SET parameter value [parameter value ...]
+    // CONFIG REWRITE | CONFIG RESETSTAT
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(s.to_string());
+    }
+
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("GET", patterns) if !patterns.is_empty() => RespValue::Map(
+            store
+                .config()
+                .get_matching(patterns)
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        RespValue::BulkString(name.to_string().into()),
+                        RespValue::BulkString(value.into()),
+                    )
+                })
+                .collect(),
+        ),
+        ("SET", pairs) if !pairs.is_empty() && pairs.len().is_multiple_of(2) => {
+            let pairs: Vec<(String, String)> = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            match apply_config(store, aof, &pairs) {
+                Ok(()) => RespValue::SimpleString("OK".to_string()),
+                Err(e) => RespValue::Error(e),
+            }
+        }
+        ("REWRITE", []) => match store.config().rewrite() {
+            Ok(()) => RespValue::SimpleString("OK".to_string()),
+            Err(e) => RespValue::Error(e),
+        },
+        ("RESETSTAT", []) => {
+            store.command_stats().reset();
+            RespValue::SimpleString("OK".to_string())
+        }
+        ("GET" | "SET" | "REWRITE" | "RESETSTAT", _) => RespValue::Error(format!(
+            "ERR wrong number of arguments for 'config|{}' command",
+            subcommand.to_lowercase()
+        )),
+        _ => RespValue::Error(format!(
+            "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+            args[0]
+        )),
+    }
+}
+
+/// Set `name value` pairs as CONFIG SET does, and act on the ones that
+/// need more than a new value (CONFIG SET, and SIGHUP reloading the config
+/// file)
+pub fn apply_config(
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    pairs: &[(String, String)],
+) -> Result<(), String> {
+    let appendonly = store.config().read().appendonly;
+    store.config().set(pairs)?;
+    let (backlog_size, output_limit) = {
+        let config = store.config().read();
+        (
+            config.repl_backlog_size,
+            config.client_output_buffer_limit.replica,
+        )
+    };
+    store.replication().set_backlog_size(backlog_size as usize);
+    store.replication().set_output_limit(output_limit);
+    match aof {
+        Some(aof) if store.config().read().appendonly != appendonly => {
+            toggle_aof(store, aof, !appendonly)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Start or stop logging to the AOF after CONFIG SET appendonly. Starting
+/// rewrites the AOF from the dataset, which fails if a rewrite is already
+/// in progress; appendonly is then set back
+fn toggle_aof(store: &FerroStore, aof: &AofWriter, enable: bool) -> Result<(), String> {
+    if !enable {
+        aof.disable();
+    } else if aof.enable() {
+        spawn_aof_rewrite(store, aof);
+    } else {
+        let _ = store
+            .config()
+            .set(&[("appendonly".to_string(), "no".to_string())]);
+        return Err("ERR Background append only file rewriting already in progress".to_string());
+    }
+    Ok(())
+}
+
+fn handle_client(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    conn: &mut ConnectionContext,
+) -> RespValue {
+    // CLIENT ID | CLIENT INFO | CLIENT LIST [TYPE normal|pubsub|replica] [ID id [id ...]]
+    // CLIENT KILL addr:port | CLIENT KILL <filter value> [<filter value> ...]
+    // CLIENT SETNAME name | CLIENT GETNAME | CLIENT PAUSE timeout [WRITE | ALL]
+    // CLIENT UNPAUSE | CLIENT REPLY ON | OFF | SKIP
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(s.to_string());
+    }
+
+    let subcommand = args[0].to_uppercase();
+    let this_client = || {
+        conn.id
+            .and_then(|id| store.clients().get(id))
+            .ok_or_else(|| RespValue::Error("ERR no client for this connection".to_string()))
+    };
+    match (subcommand.as_str(), &args[1..]) {
+        ("ID", []) => match this_client() {
+            Ok(client) => RespValue::Integer(client.id as i64),
+            Err(e) => e,
+        },
+        ("INFO", []) => match this_client() {
+            Ok(client) => RespValue::bulk(format!("{}\n", client.describe())),
+            Err(e) => e,
+        },
+        ("LIST", filters) => match client_list(store, filters) {
+            Ok(lines) => RespValue::BulkString(lines.into()),
+            Err(e) => RespValue::Error(e),
+        },
+        ("PAUSE", [timeout, mode @ ..]) if mode.len() <= 1 => {
+            let Ok(timeout) = timeout.parse::<u64>() else {
+                return RespValue::Error(
+                    "ERR timeout is not an integer or out of range".to_string(),
+                );
+            };
+            let mode = match mode.first().map(|m| m.to_uppercase()).as_deref() {
+                None | Some("ALL") => PauseMode::All,
+                Some("WRITE") => PauseMode::Write,
+                Some(_) => return RespValue::Error("ERR syntax error".to_string()),
+            };
+            store.clients().pause(mode, Duration::from_millis(timeout));
+            RespValue::SimpleString("OK".to_string())
+        }
+        ("UNPAUSE", []) => {
+            store.clients().unpause();
+            RespValue::SimpleString("OK".to_string())
+        }
+        ("REPLY", [mode]) => {
+            let mode = match mode.to_uppercase().as_str() {
+                "ON" => ReplyMode::On,
+                "OFF" => ReplyMode::Off,
+                "SKIP" => ReplyMode::Skip,
+                _ => return RespValue::Error("ERR syntax error".to_string()),
+            };
+            match this_client() {
+                Ok(client) => {
+                    store.clients().update(client.id, |info| info.reply = mode);
+                    RespValue::SimpleString("OK".to_string())
+                }
+                Err(e) => e,
+            }
+        }
+        // The old form, CLIENT KILL addr:port
+        ("KILL", [addr]) => {
+            let target = store
+                .clients()
+                .list()
+                .into_iter()
+                .find(|client| client.addr.to_string() == *addr);
+            match target {
+                Some(client) if store.clients().kill(client.id) => {
+                    RespValue::SimpleString("OK".to_string())
+                }
+                _ => RespValue::Error("ERR No such client".to_string()),
+            }
+        }
+        ("KILL", filters) if !filters.is_empty() && filters.len().is_multiple_of(2) => {
+            match client_kill(store, conn, filters) {
+                Ok(killed) => RespValue::Integer(killed as i64),
+                Err(e) => RespValue::Error(e),
+            }
+        }
+        ("KILL", _) => RespValue::Error("ERR syntax error".to_string()),
+        ("SETNAME", [name]) => {
+            if name.chars().any(|c| !('!'..='~').contains(&c)) {
+                return RespValue::Error(
+                    "ERR Client names cannot contain spaces, newlines or special characters."
+                        .to_string(),
+                );
+            }
+            match this_client() {
+                Ok(_) => {
+                    set_client_name(store, conn, name);
+                    RespValue::SimpleString("OK".to_string())
+                }
+                Err(e) => e,
+            }
+        }
+        ("GETNAME", []) => match this_client() {
+            Ok(_) if conn.name.is_empty() => RespValue::Null,
+            Ok(_) => RespValue::bulk(conn.name.as_str()),
+            Err(e) => e,
+        },
+        ("ID" | "INFO" | "SETNAME" | "GETNAME" | "PAUSE" | "UNPAUSE" | "REPLY", _) => {
+            RespValue::Error(format!(
+                "ERR wrong number of arguments for 'client|{}' command",
+                subcommand.to_lowercase()
+            ))
+        }
+        _ => RespValue::Error(format!(
+            "ERR unknown subcommand '{}'. Try CLIENT HELP.",
+            args[0]
+        )),
+    }
+}
+
+/// Name the connection, also in its `clients` entry for CLIENT LIST
+fn set_client_name(store: &FerroStore, conn: &mut ConnectionContext, name: &str) {
+    conn.name = name.to_string();
+    if let Some(id) = conn.id {
+        store
+            .clients()
+            .update(id, |info| info.name = name.to_string());
+    }
+}
+
+/// Close every client matching all the CLIENT KILL filters (ID, ADDR, LADDR,
+/// TYPE, USER), except the caller unless SKIPME is no. Returns how many
+fn client_kill(
+    store: &FerroStore,
+    conn: &ConnectionContext,
+    filters: &[String],
+) -> Result<usize, String> {
+    let mut id = None;
+    let mut addr = None;
+    let mut laddr = None;
+    let mut kind = None;
+    let mut user = None;
+    let mut skipme = true;
+    for pair in filters.chunks(2) {
+        let value = pair[1].clone();
+        match pair[0].to_uppercase().as_str() {
+            "ID" => id = Some(value.parse::<u64>().map_err(|_| "ERR Invalid client ID")?),
+            "ADDR" => addr = Some(value),
+            "LADDR" => laddr = Some(value),
+            "TYPE" => kind = Some(client_type(&value)?),
+            "USER" => user = Some(value),
+            "SKIPME" => {
+                skipme = match value.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err("ERR syntax error".to_string()),
+                }
+            }
+            _ => return Err("ERR syntax error".to_string()),
+        }
+    }
+
+    let me = conn.id;
+    let targets: Vec<u64> = store
+        .clients()
+        .list()
+        .into_iter()
+        .filter(|client| id.is_none_or(|id| client.id == id))
+        .filter(|client| {
+            addr.as_ref()
+                .is_none_or(|addr| client.addr.to_string() == *addr)
+        })
+        .filter(|client| {
+            laddr
+                .as_ref()
+                .is_none_or(|laddr| client.laddr.to_string() == *laddr)
+        })
+        .filter(|client| kind.as_ref().is_none_or(|kind| client.kind() == kind))
+        .filter(|client| user.as_ref().is_none_or(|user| client.user == *user))
+        .filter(|client| !(skipme && Some(client.id) == me))
+        .map(|client| client.id)
+        .collect();
+    Ok(targets
+        .into_iter()
+        .filter(|id| store.clients().kill(*id))
+        .count())
+}
+
+/// A client TYPE filter, as `ClientInfo::kind` names it; `slave` is an
+/// alias for `replica`
+fn client_type(value: &str) -> Result<String, String> {
+    match value.to_lowercase().as_str() {
+        "slave" => Ok("replica".to_string()),
+        kind @ ("normal" | "pubsub" | "replica") => Ok(kind.to_string()),
+        kind => Err(format!("ERR Unknown client type '{}'", kind)),
+    }
+}
+
+/// CLIENT LIST's reply: one line per client matching the TYPE and ID filters
+fn client_list(store: &FerroStore, filters: &[String]) -> Result<String, String> {
+    let mut kind = None;
+    let mut ids: Option<Vec<u64>> = None;
+    let mut rest = filters;
+    while !rest.is_empty() {
+        match rest {
+            [option, value, tail @ ..] if option.eq_ignore_ascii_case("TYPE") => {
+                kind = Some(client_type(value)?);
+                rest = tail;
+            }
+            [option, values @ ..] if option.eq_ignore_ascii_case("ID") && !values.is_empty() => {
+                let parsed = values
+                    .iter()
+                    .map(|id| id.parse().map_err(|_| "ERR Invalid client ID".to_string()))
+                    .collect::<Result<_, _>>()?;
+                ids = Some(parsed);
+                rest = &[];
+            }
+            _ => return Err("ERR syntax error".to_string()),
+        }
+    }
+
+    Ok(store
+        .clients()
+        .list()
+        .into_iter()
+        .filter(|client| kind.as_ref().is_none_or(|kind| client.kind() == kind))
+        .filter(|client| ids.as_ref().is_none_or(|ids| ids.contains(&client.id)))
+        .map(|client| format!("{}\n", client.describe()))
+        .collect())
+}
+
+fn handle_latency(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // LATENCY LATEST | LATENCY HISTORY event | LATENCY RESET [event ...]
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(s.to_string());
+    }
+
+    let subcommand = args[0].to_uppercase();
+    match (subcommand.as_str(), &args[1..]) {
+        ("LATEST", []) => RespValue::Array(
+            store
+                .latency()
+                .latest()
+                .into_iter()
+                .map(|(event, last, max)| {
+                    RespValue::Array(vec![
+                        RespValue::BulkString(event.into()),
+                        RespValue::Integer(last.time as i64),
+                        RespValue::Integer(last.latency as i64),
+                        RespValue::Integer(max as i64),
+                    ])
+                })
+                .collect(),
+        ),
+        ("HISTORY", [event]) => RespValue::Array(
+            store
+                .latency()
+                .history(event)
+                .into_iter()
+                .map(|sample| {
+                    RespValue::Array(vec![
+                        RespValue::Integer(sample.time as i64),
+                        RespValue::Integer(sample.latency as i64),
+                    ])
+                })
+                .collect(),
+        ),
+        ("RESET", events) => RespValue::Integer(store.latency().reset(events) as i64),
+        ("LATEST" | "HISTORY", _) => RespValue::Error(format!(
+            "ERR wrong number of arguments for 'latency|{}' command",
+            subcommand.to_lowercase()
+        )),
+        _ => RespValue::Error(format!(
+            "ERR unknown subcommand '{}'. Try LATENCY HELP.",
+            args[0]
+        )),
+    }
+}
+
+/// INFO sections in the order they are printed, and whether a bare INFO
+/// includes them
+const INFO_SECTIONS: [(&str, bool); 9] = [
+    ("server", true),
+    ("clients", true),
+    ("memory", true),
+    ("persistence", true),
+    ("stats", true),
+    ("replication", true),
+    ("commandstats", false),
+    ("latencystats", false),
+    ("keyspace", true),
+];
+
+fn handle_info(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // INFO [section [section ...]]
+    let mut requested = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        requested.push(s.to_lowercase());
+    }
+    let all = requested.iter().any(|s| s == "all" || s == "everything");
+    let default = requested.is_empty() || requested.iter().any(|s| s == "default");
+
+    let sections: Vec<String> = INFO_SECTIONS
+        .iter()
+        .filter(|(section, by_default)| {
+            all || (default && *by_default) || requested.iter().any(|s| s == section)
+        })
+        .map(|(section, _)| info_section(section, store))
+        .collect();
+    RespValue::BulkString((sections.join("\r\n")).into())
+}
+
+/// One INFO section: a `# Title` header and `field:value` lines
+fn info_section(section: &str, store: &FerroStore) -> String {
+    let lines: Vec<String> = match section {
+        "server" => {
+            let config = store.config().read();
+            vec![
+                format!("ferrodb_version:{}", env!("CARGO_PKG_VERSION")),
+                format!("process_id:{}", std::process::id()),
+                format!("tcp_port:{}", config.port),
+                format!("uptime_in_seconds:{}", store.uptime()),
+                format!("hz:{}", config.hz),
+            ]
+        }
+        "clients" => vec![format!("connected_clients:{}", store.clients().list().len())],
+        "memory" => {
+            let config = store.config().read();
+            vec![
+                format!("used_memory:{}", store.used_memory()),
+                format!("maxmemory:{}", config.maxmemory),
+                format!("maxmemory_policy:{}", config.maxmemory_policy.name()),
+                format!("lazyfree_pending_objects:{}", lazyfree::pending_objects()),
+            ]
+        }
+        "persistence" => {
+            let error = store.last_save_error();
+            let mut lines = vec![
+                format!("rdb_changes_since_last_save:{}", store.dirty()),
+                format!("rdb_last_save_time:{}", store.last_save()),
+                format!("rdb_bgsave_in_progress:{}", store.save_in_progress() as u8),
+                format!(
+                    "rdb_last_bgsave_status:{}",
+                    if error.is_some() { "err" } else { "ok" }
+                ),
+            ];
+            if let Some(error) = error {
+                lines.push(format!("rdb_last_save_error:{}", error));
+            }
+            lines.push(format!(
+                "aof_enabled:{}",
+                store.config().read().appendonly as u8
+            ));
+            lines
+        }
+        "stats" => vec![
+            format!("evicted_keys:{}", store.evicted_keys()),
+            format!("lazyfreed_objects:{}", lazyfree::freed_objects()),
+            format!(
+                "client_output_buffer_limit_disconnections:{}",
+                store.clients().output_limit_disconnections()
+            ),
+            format!(
+                "pubsub_dropped_messages:{}",
+                store.pubsub().dropped_messages()
+            ),
+        ],
+        "replication" => {
+            let replication = store.replication();
+            let mut lines = match replication.master() {
+                Some(master) => {
+                    let mut lines = vec![
+                    "role:slave".to_string(),
+                    format!("master_host:{}", master.host),
+                    format!("master_port:{}", master.port),
+                    format!(
+                        "master_link_status:{}",
+                        match master.state {
+                            LinkState::Connected => "up",
+                            _ => "down",
+                        }
+                    ),
+                    format!("master_last_io_seconds_ago:{}", master.last_io),
+                    format!(
+                        "master_sync_in_progress:{}",
+                        (master.state == LinkState::Sync) as u8
+                    ),
+                    format!("slave_read_repl_offset:{}", master.read_offset),
+                    format!("slave_repl_offset:{}", master.offset),
+                    ];
+                    if master.state != LinkState::Connected {
+                        lines.push(format!(
+                            "master_link_down_since_seconds:{}",
+                            master.down_since.map_or(-1, |since| since as i64)
+                        ));
+                    }
+                    let read_only = store.config().read().replica_read_only;
+                    lines.push(format!("slave_read_only:{}", read_only as u8));
+                    lines
+                }
+                None => vec!["role:master".to_string()],
+            };
+            let replicas = replication.replicas();
+            lines.push(format!("connected_slaves:{}", replicas.len()));
+            for (i, replica) in replicas.iter().enumerate() {
+                lines.push(format!(
+                    "slave{}:ip={},port={},state={},offset={},lag={}",
+                    i,
+                    replica.ip,
+                    replica.port,
+                    replica.state.name(),
+                    replica.ack_offset,
+                    replica.lag
+                ));
+            }
+            lines.push(format!("master_replid:{}", replication.replid()));
+            lines.push(format!("master_repl_offset:{}", replication.offset()));
+            let backlog = replication.backlog();
+            lines.push(format!("repl_backlog_active:{}", backlog.active as u8));
+            lines.push(format!("repl_backlog_size:{}", backlog.size));
+            // Offsets count from 1 here, as in Redis
+            lines.push(format!(
+                "repl_backlog_first_byte_offset:{}",
+                if backlog.active { backlog.start + 1 } else { 0 }
+            ));
+            lines.push(format!("repl_backlog_histlen:{}", backlog.len));
+            lines
+        }
+        "commandstats" => store
+            .command_stats()
+            .list()
+            .into_iter()
+            .map(|(name, stat)| {
+                format!(
+                    "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}",
+                    name,
+                    stat.calls,
+                    stat.usec,
+                    stat.usec_per_call(),
+                    stat.rejected_calls,
+                    stat.failed_calls
+                )
+            })
+            .collect(),
+        "latencystats" => store
+            .command_stats()
+            .list()
+            .into_iter()
+            .filter_map(|(name, stat)| {
+                let percentiles = stats::PERCENTILES
+                    .iter()
+                    .map(|p| Some(format!("p{}={:.3}", p, stat.percentile(*p)? as f64)))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(format!(
+                    "latency_percentiles_usec_{}:{}",
+                    name,
+                    percentiles.join(",")
+                ))
+            })
+            .collect(),
+        "keyspace" => store
+            .databases()
+            .filter(|db| db.dbsize() > 0)
+            .map(|db| {
+                format!(
+                    "db{}:keys={},expires={}",
+                    db.db_index(),
+                    db.dbsize(),
+                    db.expires_count()
+                )
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let mut title = section.to_string();
+    title[..1].make_ascii_uppercase();
+    let mut out = format!("# {}\r\n", title);
+    for line in lines {
+        out.push_str(&line);
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn handle_dbsize(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 1 {
+        return RespValue::Error("ERR wrong number of arguments for 'dbsize' command".to_string());
+    }
+
+    RespValue::Integer(store.dbsize() as i64)
+}
+
+fn handle_select(cmd_array: &[RespValue], conn: &mut ConnectionContext) -> RespValue {
+    // SELECT index
+    if cmd_array.len() != 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'select' command".to_string());
+    }
+    match parse_db_index(&cmd_array[1]) {
+        Ok(db) => {
+            conn.db = db;
+            RespValue::SimpleString("OK".to_string())
+        }
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn handle_swapdb(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // SWAPDB index1 index2
+    if cmd_array.len() != 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'swapdb' command".to_string());
+    }
+    let swapped = parse_db_index(&cmd_array[1]).and_then(|first| {
+        let second = parse_db_index(&cmd_array[2])?;
+        store.swap_databases(first, second)
+    });
+    match swapped {
+        Ok(()) => RespValue::SimpleString("OK".to_string()),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+/// Parse a database index argument (SELECT, SWAPDB, MOVE)
+fn parse_db_index(value: &RespValue) -> Result<usize, String> {
+    let RespValue::BulkString(index) = value else {
+        return Err("ERR value is not an integer or out of range".to_string());
+    };
+    let index: i64 = index
+        .parse()
+        .map_err(|_| "ERR value is not an integer or out of range".to_string())?;
+    usize::try_from(index)
+        .ok()
+        .filter(|&index| index < crate::storage::DATABASES)
+        .ok_or_else(|| "ERR DB index is out of range".to_string())
+}
+
+fn handle_bgrewriteaof(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+) -> RespValue {
+    if cmd_array.len() != 1 {
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'bgrewriteaof' command".to_string(),
+        );
+    }
+    if let Some(aof) = aof
+        && !aof.start_rewrite()
+    {
+        return RespValue::Error(
+            "ERR Background append only file rewriting already in progress".to_string(),
+        );
+    }
+
+    match aof {
+        Some(aof) => spawn_aof_rewrite(store, aof),
+        None => {
+            let data = capture_databases(store);
+            let (paths, format) = {
+                let config = store.config().read();
+                let paths =
+                    crate::aof::AofPaths::new(&config.appenddirname, &config.appendfilename);
+                (paths, crate::aof::BaseFormat::of(&config))
+            };
+            tokio::spawn(async move {
+                report_aof_rewrite(crate::aof::rewrite_aof(data, &paths, format).await);
+            });
+        }
+    }
+
+    RespValue::SimpleString("Background AOF rewrite started".to_string())
+}
+
+/// Keys of every database, for an AOF rewrite
+fn capture_databases(store: &FerroStore) -> Vec<(usize, crate::aof::DatabaseData)> {
+    store
+        .databases()
+        .map(|db| (db.db_index(), db.get_all_data()))
+        .collect()
+}
+
+/// Capture the dataset for the rewrite `aof` has started and write it in
+/// the background
+pub fn spawn_aof_rewrite(store: &FerroStore, aof: &AofWriter) {
+    let data = capture_databases(store);
+    let format = crate::aof::BaseFormat::of(&store.config().read());
+    let aof = aof.clone();
+    tokio::spawn(async move {
+        report_aof_rewrite(aof.finish_rewrite(data, format).await);
+    });
+}
+
+/// REPLICAOF host port | REPLICAOF NO ONE (or SLAVEOF): follow a master,
+/// replacing the dataset with its own, or stop and keep the dataset
+fn handle_replicaof(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+) -> RespValue {
+    let [_, RespValue::BulkString(host), RespValue::BulkString(port)] = cmd_array else {
+        return RespValue::Error("ERR syntax error".to_string());
+    };
+    let replication = store.replication();
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        store.config().set_replicaof(None);
+        if replication.promote() {
+            println!("MASTER MODE enabled (user request)");
+        }
+        return RespValue::SimpleString("OK".to_string());
+    }
+    let Ok(port) = port.parse::<u16>() else {
+        return RespValue::Error("ERR Invalid master port".to_string());
+    };
+    if replication
+        .master()
+        .is_some_and(|master| master.host == host.as_str() && master.port == port)
+    {
+        return RespValue::SimpleString("OK Already connected to specified master".to_string());
+    }
+    store.config().set_replicaof(Some((host.to_string(), port)));
+    replication.replicate(store, aof.cloned(), host.to_string(), port);
+    println!(
+        "REPLICAOF {}:{} enabled (user request)",
+        host.as_str(),
+        port
+    );
+    RespValue::SimpleString("OK".to_string())
+}
+
+/// CLUSTER subcommands, in cluster mode:
+/// - KEYSLOT key: the hash slot `key` maps to
+/// - MYID: this node's ID
+/// - MEET ip port: add the node serving at `ip:port` to the cluster
+/// - SETSLOT slot MIGRATING|IMPORTING node-id, STABLE or NODE node-id:
+///   move a slot between this node and another
+/// - COUNTKEYSINSLOT slot, GETKEYSINSLOT slot count: the keys a slot holds
+///   here, to MIGRATE them
+///
+/// Nodes don't talk to each other: every node involved in a migration is
+/// told about it, as redis-cli --cluster reshard does
+async fn handle_cluster(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if !store.config().read().cluster_enabled {
+        return RespValue::Error("ERR This instance has cluster support disabled".to_string());
+    }
+    let mut args = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(arg) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        args.push(arg.as_str());
+    }
+    let subcommand = args[0].to_uppercase();
+    let slot = |arg: &str| match arg.parse::<u16>() {
+        Ok(slot) if slot < cluster::SLOTS => Ok(slot),
+        _ => Err(RespValue::Error(
+            "ERR Invalid or out of range slot".to_string(),
+        )),
+    };
+    let ok = || RespValue::SimpleString("OK".to_string());
+    match (subcommand.as_str(), &args[1..]) {
+        ("KEYSLOT", [key]) => RespValue::Integer(cluster::key_slot(key) as i64),
+        ("MYID", []) => RespValue::bulk(store.cluster().myid()),
+        ("MEET", [host, port]) => {
+            let Ok(port) = port.parse::<u16>() else {
+                return RespValue::Error(format!("ERR Invalid base port specified: {}", port));
+            };
+            match meet(store, host, port).await {
+                Ok(()) => ok(),
+                Err(e) => e,
+            }
+        }
+        ("SETSLOT", [slot_arg, action, rest @ ..]) => {
+            let slot = match slot(slot_arg) {
+                Ok(slot) => slot,
+                Err(e) => return e,
+            };
+            let cluster = store.cluster();
+            let result = match (action.to_uppercase().as_str(), rest) {
+                ("MIGRATING", [id]) => cluster.set_migrating(slot, id),
+                ("IMPORTING", [id]) => cluster.set_importing(slot, id),
+                ("STABLE", []) => {
+                    cluster.set_stable(slot);
+                    Ok(())
+                }
+                ("NODE", [id]) => {
+                    let holds_keys = !store.keys_in_slot(slot, 1).is_empty();
+                    cluster.set_node(slot, id, holds_keys)
+                }
+                _ => Err(
+                    "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+                        .to_string(),
+                ),
+            };
+            match result {
+                Ok(()) => ok(),
+                Err(e) => RespValue::Error(e),
+            }
+        }
+        ("COUNTKEYSINSLOT", [slot_arg]) => match slot(slot_arg) {
+            Ok(slot) => RespValue::Integer(store.keys_in_slot(slot, usize::MAX).len() as i64),
+            Err(e) => e,
+        },
+        ("GETKEYSINSLOT", [slot_arg, count]) => {
+            let slot = match slot(slot_arg) {
+                Ok(slot) => slot,
+                Err(e) => return e,
+            };
+            let Ok(count) = count.parse::<usize>() else {
+                return RespValue::Error("ERR Invalid number of keys".to_string());
+            };
+            RespValue::Array(
+                store
+                    .keys_in_slot(slot, count)
+                    .into_iter()
+                    .map(RespValue::bulk)
+                    .collect(),
+            )
+        }
+        ("KEYSLOT" | "MYID" | "MEET" | "SETSLOT" | "COUNTKEYSINSLOT" | "GETKEYSINSLOT", _) => {
+            RespValue::Error(format!(
+                "ERR wrong number of arguments for 'cluster|{}' command",
+                subcommand.to_lowercase()
+            ))
+        }
+        _ => RespValue::Error(format!("ERR unknown subcommand '{}'", args[0])),
+    }
+}
+
+/// CLUSTER MEET: learn the ID of the node at `host:port` by asking it
+async fn meet(store: &FerroStore, host: &str, port: u16) -> Result<(), RespValue> {
+    let timeout = Duration::from_secs(1);
+    let socket = match tokio::time::timeout(timeout, TcpStream::connect((host, port))).await {
+        Ok(Ok(socket)) => socket,
+        _ => {
+            return Err(RespValue::Error(format!(
+                "ERR Can't connect to node {}:{}",
+                host, port
+            )));
+        }
+    };
+    let mut node = MigrateTarget {
+        socket,
+        decoder: RespDecoder::new(),
+        timeout,
+    };
+    let replies = node
+        .send(&[RespValue::Array(vec![
+            RespValue::bulk("CLUSTER"),
+            RespValue::bulk("MYID"),
+        ])])
+        .await?;
+    match replies.first() {
+        Some(RespValue::BulkString(id)) if id.len() == 40 => {
+            let node = Node {
+                host: host.to_string(),
+                port,
+            };
+            store.cluster().meet(id.to_string(), node);
+            Ok(())
+        }
+        Some(RespValue::Error(e)) => Err(RespValue::Error(format!(
+            "ERR Node {}:{} replied with error: {}",
+            host, port, e
+        ))),
+        _ => Err(RespValue::Error(format!(
+            "ERR Node {}:{} is not a cluster node",
+            host, port
+        ))),
+    }
+}
+
+/// ASKING: let the connection's next command use a slot this node is
+/// importing, as a client does after an -ASK redirect
+fn handle_asking(store: &FerroStore, conn: &mut ConnectionContext) -> RespValue {
+    if !store.config().read().cluster_enabled {
+        return RespValue::Error("ERR This instance has cluster support disabled".to_string());
+    }
+    conn.asking = true;
+    RespValue::SimpleString("OK".to_string())
+}
+
+/// ROLE: whether this server is a master or a replica, with its replicas
+/// and how far each has acknowledged the stream, or its master and the
+/// state of the link to it
+fn handle_role(store: &FerroStore) -> RespValue {
+    let replication = store.replication();
+    let bulk = |value: String| RespValue::BulkString(value.into());
+    match replication.master() {
+        Some(master) => RespValue::Array(vec![
+            bulk("slave".to_string()),
+            bulk(master.host),
+            RespValue::Integer(master.port as i64),
+            bulk(master.state.name().to_string()),
+            RespValue::Integer(master.offset as i64),
+        ]),
+        None => RespValue::Array(vec![
+            bulk("master".to_string()),
+            RespValue::Integer(replication.offset() as i64),
+            RespValue::Array(
+                replication
+                    .replicas()
+                    .into_iter()
+                    .map(|replica| {
+                        RespValue::Array(vec![
+                            bulk(replica.ip.to_string()),
+                            bulk(replica.port.to_string()),
+                            bulk(replica.ack_offset.to_string()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]),
+    }
+}
+
+/// WAIT numreplicas timeout: block until `numreplicas` replicas have
+/// acknowledged every write made so far, or for `timeout` milliseconds (0
+/// waits forever), and return how many have. Replicas acknowledge every
+/// second, and are asked to right away if too few have
+async fn handle_wait(cmd_array: &[RespValue], store: &FerroStore, may_block: bool) -> RespValue {
+    let [
+        _,
+        RespValue::BulkString(numreplicas),
+        RespValue::BulkString(timeout),
+    ] = cmd_array
+    else {
+        return RespValue::Error("ERR syntax error".to_string());
+    };
+    let Ok(numreplicas) = numreplicas.parse::<i64>() else {
+        return RespValue::Error("ERR value is not an integer or out of range".to_string());
+    };
+    let timeout = match timeout.parse::<i64>() {
+        Ok(timeout) if timeout < 0 => {
+            return RespValue::Error("ERR timeout is negative".to_string());
+        }
+        Ok(timeout) => timeout as u64,
+        Err(_) => {
+            return RespValue::Error("ERR timeout is not an integer or out of range".to_string());
+        }
+    };
+    let replication = store.replication();
+    if replication.is_replica() {
+        return RespValue::Error("ERR WAIT cannot be used with replica instances.".to_string());
+    }
+    let offset = replication.offset();
+    let deadline =
+        (timeout > 0).then(|| tokio::time::Instant::now() + Duration::from_millis(timeout));
+    let mut asked = false;
+    loop {
+        let next_ack = replication.next_ack();
+        tokio::pin!(next_ack);
+        next_ack.as_mut().enable();
+        let acked = replication.acked(offset);
+        // Inside MULTI it can't block, so it reports how many have already
+        if acked as i64 >= numreplicas || !may_block {
+            return RespValue::Integer(acked as i64);
+        }
+        if !asked {
+            replication.request_acks();
+            asked = true;
+        }
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline, next_ack).await.is_err() {
+                    return RespValue::Integer(replication.acked(offset) as i64);
+                }
+            }
+            None => next_ack.await,
+        }
+    }
+}
+
+/// REPLCONF option value [option value ...]: what a replica tells the
+/// server about itself before and after SYNC / PSYNC
+fn handle_replconf(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    conn: &mut ConnectionContext,
+) -> RespValue {
+    if cmd_array.len().is_multiple_of(2) {
+        return RespValue::Error("ERR syntax error".to_string());
+    }
+    for pair in cmd_array[1..].chunks(2) {
+        let (RespValue::BulkString(option), RespValue::BulkString(value)) = (&pair[0], &pair[1])
+        else {
+            return RespValue::Error("ERR syntax error".to_string());
+        };
+        match option.to_lowercase().as_str() {
+            "listening-port" => match value.parse() {
+                Ok(port) => conn.listening_port = port,
+                Err(_) => return RespValue::Error("ERR invalid listening port".to_string()),
+            },
+            // No capabilities change what replicas are sent
+            "capa" => {}
+            // Sent by replicas every second; never replied to
+            "ack" => {
+                if let (Some(id), Ok(offset)) = (conn.id, value.parse()) {
+                    store.replication().ack(id, offset);
+                }
+            }
+            other => {
+                return RespValue::Error(format!("ERR Unrecognized REPLCONF option: {}", other));
+            }
+        }
+    }
+    RespValue::SimpleString("OK".to_string())
+}
+
+/// SYNC | PSYNC replicationid offset: make the connection a replica of
+/// this server. It's sent the writes from `offset` on when the backlog
+/// still holds them, or else a snapshot of the dataset then every write, in
+/// place of replies
+fn handle_sync(
+    cmd_name: &str,
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    conn: &mut ConnectionContext,
+) -> RespValue {
+    if conn.replica.is_some() {
+        return RespValue::Error("ERR the connection is already a replica".to_string());
+    }
+    let Some(info) = conn.id.and_then(|id| store.clients().get(id)) else {
+        return RespValue::Error(format!("ERR {} is not allowed in this context", cmd_name));
+    };
+    let replication = store.replication();
+    // Replicas ask for the byte after the last one they have (offset + 1);
+    // PSYNC ? -1 asks for a full resynchronization
+    if let [
+        _,
+        RespValue::BulkString(replid),
+        RespValue::BulkString(offset),
+    ] = cmd_array
+        && let Ok(offset @ 1..) = offset.parse::<u64>()
+        && let Some(link) = replication.resume(
+            info.id,
+            info.addr.ip(),
+            conn.listening_port,
+            replid.as_str(),
+            offset - 1,
+        )
+    {
+        conn.replica = Some(link);
+        store.clients().update(info.id, |info| info.replica = true);
+        println!(
+            "Replica {} attached, resuming from offset {}",
+            info.addr,
+            offset - 1
+        );
+        return RespValue::SimpleString("OK".to_string());
+    }
+    // SYNC predates the reply naming the stream the snapshot starts
+    let preamble = match cmd_name {
+        "PSYNC" => format!(
+            "+FULLRESYNC {} {}\r\n",
+            replication.replid(),
+            replication.offset()
+        ),
+        _ => String::new(),
+    };
+    let codec = store.config().read().rdbcompression;
+    conn.replica = Some(replication.attach(
+        info.id,
+        info.addr.ip(),
+        conn.listening_port,
+        preamble,
+        capture_databases(store),
+        codec,
+    ));
+    store.clients().update(info.id, |info| info.replica = true);
+    println!("Replica {} attached, sending a full resync", info.addr);
+    RespValue::SimpleString("OK".to_string())
+}
+
+fn report_aof_rewrite(result: std::io::Result<()>) {
+    match result {
+        Ok(_) => println!("AOF rewrite completed"),
+        Err(e) => eprintln!("AOF rewrite failed: {}", e),
+    }
+}
+
+fn handle_sadd(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'sadd' command".to_string());
+    }
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let mut members = Vec::new();
+
+        for val in &cmd_array[2..] {
+            if let RespValue::BulkString(v) = val {
+                members.push(v.to_string());
+            } else {
+                return RespValue::Error("ERR all members must be bulk strings".to_string());
+            }
+        }
+        match store.sadd(key, members) {
+            Ok(added) => RespValue::Integer(added as i64),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+fn handle_srem(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'srem' command".to_string());
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let mut members = Vec::new();
+
+        for val in &cmd_array[2..] {
+            if let RespValue::BulkString(v) = val {
+                members.push(v.to_string());
+            } else {
+                return RespValue::Error("ERR all members must be bulk strings".to_string());
+            }
+        }
+
+        match store.srem(key, members) {
+            Ok(removed) => RespValue::Integer(removed as i64),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_smembers(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 2 {
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'smembers' command".to_string(),
+        );
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        match store.smembers(key) {
+            Ok(members) => RespValue::Set(members.into_iter().map(RespValue::bulk).collect()),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_smove(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // SMOVE source destination member
+    if cmd_array.len() != 4 {
+        return RespValue::Error("ERR wrong number of arguments for 'smove' command".to_string());
+    }
+
+    if let (RespValue::BulkString(src), RespValue::BulkString(dst), RespValue::BulkString(member)) =
+        (&cmd_array[1], &cmd_array[2], &cmd_array[3])
+    {
+        match store.smove(src, dst, member) {
+            Ok(moved) => RespValue::Integer(if moved { 1 } else { 0 }),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_sismember(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 3 {
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'sismember' command".to_string(),
+        );
+    }
+
+    if let (RespValue::BulkString(key), RespValue::BulkString(member)) =
+        (&cmd_array[1], &cmd_array[2])
+    {
+        match store.sismember(key, member) {
+            Ok(exists) => RespValue::Integer(if exists { 1 } else { 0 }),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_scard(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'scard' command".to_string());
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        match store.scard(key) {
+            Ok(size) => RespValue::Integer(size as i64),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_sinter(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'sinter' command".to_string());
+    }
+
+    let mut keys = Vec::new();
+    for val in &cmd_array[1..] {
+        if let RespValue::BulkString(k) = val {
+            keys.push(k.to_string());
+        } else {
+            return RespValue::Error("ERR all keys must be bulk strings".to_string());
+        }
+    }
+
+    match store.sinter(keys) {
+        Ok(members) => RespValue::Set(members.into_iter().map(RespValue::bulk).collect()),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn handle_sunion(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'sunion' command".to_string());
+    }
+
+    let mut keys = Vec::new();
+    for val in &cmd_array[1..] {
+        if let RespValue::BulkString(k) = val {
+            keys.push(k.to_string());
+        } else {
+            return RespValue::Error("ERR all keys must be bulk strings".to_string());
+        }
+    }
+
+    match store.sunion(keys) {
+        Ok(members) => RespValue::Set(members.into_iter().map(RespValue::bulk).collect()),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn handle_sdiff(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'sdiff' command".to_string());
+    }
+
+    let mut keys = Vec::new();
+    for val in &cmd_array[1..] {
+        if let RespValue::BulkString(k) = val {
+            keys.push(k.to_string());
+        } else {
+            return RespValue::Error("ERR all keys must be bulk strings".to_string());
+        }
+    }
+
+    match store.sdiff(keys) {
+        Ok(members) => RespValue::Set(members.into_iter().map(RespValue::bulk).collect()),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+// ============ SORTED SET COMMAND HANDLERS ============
+
+fn handle_zadd(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // ZADD key score member [score member ...]
+    if cmd_array.len() < 4 || !(cmd_array.len() - 2).is_multiple_of(2) {
+        return RespValue::Error("ERR wrong number of arguments for 'zadd' command".to_string());
+    }
+
+    if let RespValue::BulkString(key) = &cmd_array[1] {
+        let mut members = Vec::new();
+
+        // Parse score-member pairs
+        let mut i = 2;
+        while i < cmd_array.len() {
+            if let (RespValue::BulkString(score_str), RespValue::BulkString(member)) =
+                (&cmd_array[i], &cmd_array[i + 1])
+            {
+                match score_str.parse::<f64>() {
+                    Ok(score) => members.push((score, member.to_string())),
+                    Err(_) => {
+                        return RespValue::Error("ERR value is not a valid float".to_string());
+                    }
+                }
+            } else {
+                return RespValue::Error("ERR syntax error".to_string());
+            }
+            i += 2;
+        }
+
+        match store.zadd(key, members) {
+            Ok(added) => RespValue::Integer(added as i64),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_zrem(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'zrem' command".to_string());
     }
+
     if let RespValue::BulkString(key) = &cmd_array[1] {
         let mut members = Vec::new();
 
         for val in &cmd_array[2..] {
             if let RespValue::BulkString(v) = val {
-                members.push(v.clone());
+                members.push(v.to_string());
+            } else {
+                return RespValue::Error("ERR all members must be bulk strings".to_string());
+            }
+        }
+
+        match store.zrem(key, members) {
+            Ok(removed) => RespValue::Integer(removed as i64),
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR key must be a bulk string".to_string())
+    }
+}
+
+fn handle_zscore(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    if cmd_array.len() != 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'zscore' command".to_string());
+    }
+
+    if let (RespValue::BulkString(key), RespValue::BulkString(member)) =
+        (&cmd_array[1], &cmd_array[2])
+    {
+        match store.zscore(key, member) {
+            Ok(Some(score)) => RespValue::Double(score),
+            Ok(None) => RespValue::Null,
+            Err(e) => RespValue::Error(e),
+        }
+    } else {
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
+    }
+}
+
+fn handle_zmscore(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // ZMSCORE key member [member ...]
+    if cmd_array.len() < 3 {
+        return RespValue::Error("ERR wrong number of arguments for 'zmscore' command".to_string());
+    }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+    let mut members = Vec::with_capacity(cmd_array.len() - 2);
+    for arg in &cmd_array[2..] {
+        match arg {
+            RespValue::BulkString(member) => members.push(member.to_string()),
+            _ => return RespValue::Error("ERR members must be bulk strings".to_string()),
+        }
+    }
+
+    match store.zmscore(key, &members) {
+        Ok(scores) => RespValue::Array(
+            scores
+                .into_iter()
+                .map(|score| match score {
+                    Some(score) => RespValue::BulkString(score.to_string().into()),
+                    None => RespValue::Null,
+                })
+                .collect(),
+        ),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn handle_zrandmember(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // ZRANDMEMBER key [count [WITHSCORES]]
+    if cmd_array.len() < 2 || cmd_array.len() > 4 {
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'zrandmember' command".to_string(),
+        );
+    }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+
+    let Some(count_arg) = cmd_array.get(2) else {
+        // Without a count, reply with a single member (or nil)
+        return match store.zrandmember(key, 1) {
+            Ok(mut members) if !members.is_empty() => {
+                RespValue::BulkString(members.remove(0).0.into())
+            }
+            Ok(_) => RespValue::Null,
+            Err(e) => RespValue::Error(e),
+        };
+    };
+    let count = match count_arg {
+        RespValue::BulkString(c) => match c.parse::<i64>() {
+            Ok(c) => c,
+            Err(_) => {
+                return RespValue::Error("ERR value is not an integer or out of range".to_string());
+            }
+        },
+        _ => return RespValue::Error("ERR count must be a bulk string".to_string()),
+    };
+    let with_scores = match cmd_array.get(3) {
+        None => false,
+        Some(RespValue::BulkString(flag)) if flag.eq_ignore_ascii_case("WITHSCORES") => true,
+        Some(_) => return RespValue::Error("ERR syntax error".to_string()),
+    };
+
+    match store.zrandmember(key, count) {
+        Ok(members) => scored_members_reply(members, with_scores),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn handle_zmpop(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // ZMPOP numkeys key [key ...] MIN|MAX [COUNT count]
+    let (keys, direction, count) = match parse_mpop_args(cmd_array, "zmpop") {
+        Ok(args) => args,
+        Err(e) => return RespValue::Error(e),
+    };
+    let end = match direction.as_str() {
+        "MIN" => ScoreEnd::Min,
+        "MAX" => ScoreEnd::Max,
+        _ => return RespValue::Error("ERR syntax error".to_string()),
+    };
+
+    match store.zmpop(&keys, end, count) {
+        Ok(Some((key, members))) => RespValue::Array(vec![
+            RespValue::BulkString(key.into()),
+            RespValue::Array(
+                members
+                    .into_iter()
+                    .map(|(member, score)| {
+                        RespValue::Array(vec![
+                            RespValue::BulkString(member.into()),
+                            RespValue::BulkString(score.to_string().into()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]),
+        Ok(None) => RespValue::Null,
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn handle_zpop(cmd_array: &[RespValue], store: &FerroStore, end: ScoreEnd) -> RespValue {
+    // ZPOPMIN key [count]
+    if cmd_array.len() < 2 || cmd_array.len() > 3 {
+        let name = match end {
+            ScoreEnd::Min => "zpopmin",
+            ScoreEnd::Max => "zpopmax",
+        };
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+    let count = match cmd_array.get(2) {
+        None => 1,
+        Some(RespValue::BulkString(c)) => match c.parse::<i64>() {
+            Ok(c) if c >= 0 => c as usize,
+            Ok(_) => {
+                return RespValue::Error("ERR value is out of range, must be positive".to_string());
+            }
+            Err(_) => {
+                return RespValue::Error("ERR value is not an integer or out of range".to_string());
+            }
+        },
+        Some(_) => return RespValue::Error("ERR count must be a bulk string".to_string()),
+    };
+
+    match store.zpop(key, end, count) {
+        Ok(members) => scored_members_reply(members, true),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+async fn handle_bzpop(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    end: ScoreEnd,
+    aof: Option<&AofWriter>,
+    may_block: bool,
+) -> RespValue {
+    // BZPOPMIN key [key ...] timeout
+    let name = match end {
+        ScoreEnd::Min => "bzpopmin",
+        ScoreEnd::Max => "bzpopmax",
+    };
+    let (keys, timeout) = match parse_blocking_keys(cmd_array, name) {
+        Ok(args) => args,
+        Err(e) => return RespValue::Error(e),
+    };
+
+    match block_on_keys(store, &keys, timeout, may_block, || {
+        let popped = store.zmpop(&keys, end, 1)?;
+        // Log the removal that actually happened so replay never blocks
+        if let (Some(aof_writer), Some((key, members))) = (aof, &popped) {
+            aof_writer.log_command(
+                store.db_index(),
+                &RespValue::Array(vec![
+                    RespValue::BulkString("ZREM".into()),
+                    RespValue::BulkString(key.clone().into()),
+                    RespValue::BulkString(members[0].0.clone().into()),
+                ]),
+            );
+        }
+        Ok(popped)
+    })
+    .await
+    {
+        Ok(Some((key, mut members))) => {
+            let (member, score) = members.remove(0);
+            RespValue::Array(vec![
+                RespValue::BulkString(key.into()),
+                RespValue::BulkString(member.into()),
+                RespValue::BulkString(score.to_string().into()),
+            ])
+        }
+        Ok(None) => RespValue::Null,
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+fn handle_zrange(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // ZRANGE key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+    if cmd_array.len() < 4 {
+        return RespValue::Error("ERR wrong number of arguments for 'zrange' command".to_string());
+    }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR arguments must be bulk strings".to_string());
+    };
+
+    let mut by_score = false;
+    let mut by_lex = false;
+    let mut rev = false;
+    let mut limit = None;
+    let mut with_scores = false;
+    let mut i = 4;
+    while i < cmd_array.len() {
+        let RespValue::BulkString(opt) = &cmd_array[i] else {
+            return RespValue::Error("ERR syntax error".to_string());
+        };
+        match opt.to_uppercase().as_str() {
+            "BYSCORE" => by_score = true,
+            "BYLEX" => by_lex = true,
+            "REV" => rev = true,
+            "WITHSCORES" => with_scores = true,
+            "LIMIT" => {
+                limit = match parse_range_limit(&cmd_array[i + 1..]) {
+                    Ok(limit) => Some(limit),
+                    Err(e) => return RespValue::Error(e),
+                };
+                i += 2;
+            }
+            _ => return RespValue::Error("ERR syntax error".to_string()),
+        }
+        i += 1;
+    }
+    if by_score && by_lex {
+        return RespValue::Error("ERR syntax error".to_string());
+    }
+    if limit.is_some() && !by_score && !by_lex {
+        return RespValue::Error(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                .to_string(),
+        );
+    }
+    if with_scores && by_lex {
+        return RespValue::Error(
+            "ERR syntax error, WITHSCORES not supported in combination with BYLEX".to_string(),
+        );
+    }
+
+    // With REV, score and lex ranges are given as max then min
+    let (first, second) = if rev && (by_score || by_lex) {
+        (&cmd_array[3], &cmd_array[2])
+    } else {
+        (&cmd_array[2], &cmd_array[3])
+    };
+    let by = if by_score {
+        match (parse_score_bound(first), parse_score_bound(second)) {
+            (Ok(min), Ok(max)) => ZRangeBy::Score(min, max),
+            (Err(e), _) | (_, Err(e)) => return RespValue::Error(e),
+        }
+    } else if by_lex {
+        match (parse_lex_bound(first), parse_lex_bound(second)) {
+            (Ok(min), Ok(max)) => ZRangeBy::Lex(min, max),
+            (Err(e), _) | (_, Err(e)) => return RespValue::Error(e),
+        }
+    } else {
+        let (RespValue::BulkString(start), RespValue::BulkString(stop)) = (first, second) else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        match (start.parse::<i64>(), stop.parse::<i64>()) {
+            (Ok(start), Ok(stop)) => ZRangeBy::Index(start, stop),
+            _ => return RespValue::Error("ERR value is not an integer".to_string()),
+        }
+    };
+
+    let (offset, count) = limit.unwrap_or((0, None));
+    let query = ZRangeQuery {
+        by,
+        rev,
+        offset,
+        count,
+    };
+    match store.zrange_query(key, &query) {
+        Ok(members) => scored_members_reply(members, with_scores),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+/// Rewrite a legacy range command (ZREVRANGE, ZRANGEBYSCORE, ZRANGEBYLEX) as
+/// the equivalent ZRANGE by inserting `flags` after the range arguments
+fn handle_zrange_variant(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    name: &str,
+    flags: &[&str],
+) -> RespValue {
+    if cmd_array.len() < 4 {
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    }
+    let mut rewritten = cmd_array[..4].to_vec();
+    rewritten.extend(
+        flags
+            .iter()
+            .map(|flag| RespValue::BulkString(flag.to_string().into())),
+    );
+    rewritten.extend_from_slice(&cmd_array[4..]);
+    handle_zrange(&rewritten, store)
+}
+
+/// Parse a score range bound: `1.5`, `(1.5` (exclusive), `-inf` or `+inf`
+fn parse_score_bound(arg: &RespValue) -> Result<ScoreBound, String> {
+    let err = || "ERR min or max is not a float".to_string();
+    let RespValue::BulkString(s) = arg else {
+        return Err(err());
+    };
+    let (text, exclusive) = match s.strip_prefix('(') {
+        Some(rest) => (rest, true),
+        None => (s.as_str(), false),
+    };
+    // f64 parsing accepts "inf", "+inf" and "-inf" (case-insensitively)
+    let value = text
+        .parse::<f64>()
+        .ok()
+        .filter(|v| !v.is_nan())
+        .ok_or_else(err)?;
+    Ok(ScoreBound { value, exclusive })
+}
+
+/// Parse a lexicographical range bound: `-`, `+`, `[member` or `(member`
+fn parse_lex_bound(arg: &RespValue) -> Result<LexBound, String> {
+    let RespValue::BulkString(s) = arg else {
+        return Err("ERR min or max not valid string range item".to_string());
+    };
+    if s == "-" {
+        Ok(LexBound::NegInf)
+    } else if s == "+" {
+        Ok(LexBound::PosInf)
+    } else if let Some(member) = s.strip_prefix('[') {
+        Ok(LexBound::Inclusive(member.to_string()))
+    } else if let Some(member) = s.strip_prefix('(') {
+        Ok(LexBound::Exclusive(member.to_string()))
+    } else {
+        Err("ERR min or max not valid string range item".to_string())
+    }
+}
+
+/// Parse the `offset count` arguments following LIMIT
+/// A negative offset yields nothing; a negative count means "all"
+fn parse_range_limit(args: &[RespValue]) -> Result<(usize, Option<usize>), String> {
+    let (Some(RespValue::BulkString(o)), Some(RespValue::BulkString(c))) =
+        (args.first(), args.get(1))
+    else {
+        return Err("ERR syntax error".to_string());
+    };
+    let (Ok(o), Ok(c)) = (o.parse::<i64>(), c.parse::<i64>()) else {
+        return Err("ERR value is not an integer or out of range".to_string());
+    };
+    let offset = if o < 0 { usize::MAX } else { o as usize };
+    Ok((offset, (c >= 0).then_some(c as usize)))
+}
+
+/// The command to log in the AOF for `cmd_array`, with relative expirations
+/// (EXPIRE, SETEX, SET ... EX, GETEX ... PX, RESTORE ttl) turned into Unix
+/// times in milliseconds, so replaying the log later doesn't push them back
+fn with_absolute_expiry(cmd_array: &[RespValue]) -> Vec<RespValue> {
+    let mut args = Vec::with_capacity(cmd_array.len());
+    for arg in cmd_array {
+        let RespValue::BulkString(s) = arg else {
+            return cmd_array.to_vec();
+        };
+        args.push(s.to_string());
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    // `amount` units of `unit_ms` from now. Invalid or non-positive amounts
+    // make the command fail or delete the key either way, so those commands
+    // are logged as they are
+    let deadline = |amount: &str, unit_ms: i64| {
+        let amount = amount.parse::<i64>().ok().filter(|&amount| amount > 0)?;
+        Some(amount.checked_mul(unit_ms)?.checked_add(now)?.to_string())
+    };
+
+    let name = args[0].to_uppercase();
+    let logged = match name.as_str() {
+        "EXPIRE" | "PEXPIRE" if args.len() >= 3 => {
+            let unit_ms = if name == "EXPIRE" { 1000 } else { 1 };
+            deadline(&args[2], unit_ms).map(|at| {
+                let mut logged = vec!["PEXPIREAT".to_string(), args[1].to_string(), at];
+                logged.extend_from_slice(&args[3..]);
+                logged
+            })
+        }
+        "SETEX" | "PSETEX" if args.len() == 4 => {
+            let unit_ms = if name == "SETEX" { 1000 } else { 1 };
+            deadline(&args[2], unit_ms).map(|at| {
+                let (key, value) = (args[1].clone(), args[3].clone());
+                vec![
+                    "SET".to_string(),
+                    key.to_string(),
+                    value.to_string(),
+                    "PXAT".to_string(),
+                    at,
+                ]
+            })
+        }
+        "SET" | "GETEX" => {
+            // Options start after the key, and the value for SET
+            let options = if name == "SET" { 3 } else { 2 };
+            (options..args.len().saturating_sub(1)).find_map(|i| {
+                let unit_ms = match args[i].to_uppercase().as_str() {
+                    "EX" => 1000,
+                    "PX" => 1,
+                    _ => return None,
+                };
+                let at = deadline(&args[i + 1], unit_ms)?;
+                let mut logged = args.clone();
+                logged[i] = "PXAT".into();
+                logged[i + 1] = at;
+                Some(logged)
+            })
+        }
+        "RESTORE" if args.len() >= 4 => {
+            let absttl = args[4..]
+                .iter()
+                .any(|option| option.eq_ignore_ascii_case("ABSTTL"));
+            if absttl {
+                None
             } else {
-                return RespValue::SimpleString("ERR all members must be bulk strings".to_string());
+                deadline(&args[2], 1).map(|at| {
+                    let mut logged = args.clone();
+                    logged[2] = at;
+                    logged.push("ABSTTL".into());
+                    logged
+                })
             }
         }
-        match store.sadd(key, members) {
-            Ok(added) => RespValue::Integer(added as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
-        }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+        _ => None,
+    };
+    match logged {
+        Some(logged) => logged.into_iter().map(RespValue::bulk).collect(),
+        None => cmd_array.to_vec(),
     }
 }
-fn handle_srem(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'srem' command".to_string(),
-        );
-    }
 
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        let mut members = Vec::new();
+/// Encode sorted set members as a flat reply, interleaving scores if requested
+fn scored_members_reply(members: Vec<ScoredMember>, with_scores: bool) -> RespValue {
+    RespValue::Array(
+        members
+            .into_iter()
+            .flat_map(|(member, score)| {
+                let mut items = vec![RespValue::BulkString(member.into())];
+                if with_scores {
+                    items.push(RespValue::BulkString(score.to_string().into()));
+                }
+                items
+            })
+            .collect(),
+    )
+}
 
-        for val in &cmd_array[2..] {
-            if let RespValue::BulkString(v) = val {
-                members.push(v.clone());
-            } else {
-                return RespValue::SimpleString("ERR all members must be bulk strings".to_string());
-            }
-        }
+fn handle_zcount(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // ZCOUNT key min max
+    if cmd_array.len() != 4 {
+        return RespValue::Error("ERR wrong number of arguments for 'zcount' command".to_string());
+    }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+    let (min, max) = match (
+        parse_score_bound(&cmd_array[2]),
+        parse_score_bound(&cmd_array[3]),
+    ) {
+        (Ok(min), Ok(max)) => (min, max),
+        (Err(e), _) | (_, Err(e)) => return RespValue::Error(e),
+    };
 
-        match store.srem(key, members) {
-            Ok(removed) => RespValue::Integer(removed as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
-        }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+    match store.zcount(key, min, max) {
+        Ok(count) => RespValue::Integer(count as i64),
+        Err(e) => RespValue::Error(e),
     }
 }
 
-fn handle_smembers(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'smembers' command".to_string(),
+fn handle_zlexcount(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // ZLEXCOUNT key min max
+    if cmd_array.len() != 4 {
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'zlexcount' command".to_string(),
         );
     }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR key must be a bulk string".to_string());
+    };
+    let (min, max) = match (
+        parse_lex_bound(&cmd_array[2]),
+        parse_lex_bound(&cmd_array[3]),
+    ) {
+        (Ok(min), Ok(max)) => (min, max),
+        (Err(e), _) | (_, Err(e)) => return RespValue::Error(e),
+    };
 
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        match store.smembers(key) {
-            Ok(members) => {
-                RespValue::Array(members.into_iter().map(RespValue::BulkString).collect())
-            }
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
-        }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+    match store.zlexcount(key, &min, &max) {
+        Ok(count) => RespValue::Integer(count as i64),
+        Err(e) => RespValue::Error(e),
     }
 }
 
-fn handle_sismember(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+fn handle_zrank(cmd_array: &[RespValue], store: &FerroStore, rev: bool) -> RespValue {
     if cmd_array.len() != 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'sismember' command".to_string(),
-        );
+        let name = if rev { "zrevrank" } else { "zrank" };
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
     }
 
     if let (RespValue::BulkString(key), RespValue::BulkString(member)) =
         (&cmd_array[1], &cmd_array[2])
     {
-        match store.sismember(key, member) {
-            Ok(exists) => RespValue::Integer(if exists { 1 } else { 0 }),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
+        let rank = if rev {
+            store.zrevrank(key, member)
+        } else {
+            store.zrank(key, member)
+        };
+        match rank {
+            Ok(Some(rank)) => RespValue::Integer(rank as i64),
+            Ok(None) => RespValue::Null,
+            Err(e) => RespValue::Error(e),
         }
     } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
     }
 }
 
-fn handle_scard(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+fn handle_zcard(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
     if cmd_array.len() != 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'scard' command".to_string(),
-        );
+        return RespValue::Error("ERR wrong number of arguments for 'zcard' command".to_string());
     }
 
     if let RespValue::BulkString(key) = &cmd_array[1] {
-        match store.scard(key) {
+        match store.zcard(key) {
             Ok(size) => RespValue::Integer(size as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
+            Err(e) => RespValue::Error(e),
         }
     } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+        RespValue::Error("ERR key must be a bulk string".to_string())
     }
 }
-
-fn handle_sinter(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+fn handle_sort(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // SORT key [BY pattern] [LIMIT offset count] [GET pattern ...] [ASC | DESC] [ALPHA] [STORE destination]
     if cmd_array.len() < 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'sinter' command".to_string(),
-        );
+        return RespValue::Error("ERR wrong number of arguments for 'sort' command".to_string());
     }
+    let RespValue::BulkString(key) = &cmd_array[1] else {
+        return RespValue::Error("ERR arguments must be bulk strings".to_string());
+    };
 
-    let mut keys = Vec::new();
-    for val in &cmd_array[1..] {
-        if let RespValue::BulkString(k) = val {
-            keys.push(k.clone());
-        } else {
-            return RespValue::SimpleString("ERR all keys must be bulk strings".to_string());
+    let mut options = SortOptions::default();
+    let mut dest = None;
+    let mut i = 2;
+    while i < cmd_array.len() {
+        let RespValue::BulkString(opt) = &cmd_array[i] else {
+            return RespValue::Error("ERR syntax error".to_string());
+        };
+        let value = match cmd_array.get(i + 1) {
+            Some(RespValue::BulkString(value)) => Some(value),
+            _ => None,
+        };
+        match (opt.to_uppercase().as_str(), value) {
+            ("ASC", _) => options.desc = false,
+            ("DESC", _) => options.desc = true,
+            ("ALPHA", _) => options.alpha = true,
+            ("BY", Some(pattern)) => {
+                options.by = Some(pattern.to_string());
+                i += 1;
+            }
+            ("GET", Some(pattern)) => {
+                options.get.push(pattern.to_string());
+                i += 1;
+            }
+            ("STORE", Some(destination)) => {
+                dest = Some(destination);
+                i += 1;
+            }
+            ("LIMIT", _) => {
+                let (offset, count) = match parse_range_limit(&cmd_array[i + 1..]) {
+                    Ok(limit) => limit,
+                    Err(e) => return RespValue::Error(e),
+                };
+                // Unlike ZRANGE, a negative offset is treated as 0
+                options.offset = if offset == usize::MAX { 0 } else { offset };
+                options.count = count;
+                i += 2;
+            }
+            _ => return RespValue::Error("ERR syntax error".to_string()),
         }
+        i += 1;
     }
 
-    match store.sinter(keys) {
-        Ok(members) => RespValue::Array(members.into_iter().map(RespValue::BulkString).collect()),
-        Err(e) => RespValue::SimpleString(format!("-{}", e)),
+    match dest {
+        Some(dest) => match store.sort_store(key, dest, &options) {
+            Ok(len) => RespValue::Integer(len as i64),
+            Err(e) => RespValue::Error(e),
+        },
+        None => match store.sort(key, &options) {
+            Ok(values) => RespValue::Array(
+                values
+                    .into_iter()
+                    .map(|value| value.map_or(RespValue::Null, RespValue::bulk))
+                    .collect(),
+            ),
+            Err(e) => RespValue::Error(e),
+        },
     }
 }
+fn is_blocking_command(cmd_name: &str) -> bool {
+    command_table::lookup(cmd_name).is_some_and(|spec| spec.flags.contains(CommandFlags::BLOCKING))
+}
 
-fn handle_sunion(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'sunion' command".to_string(),
-        );
+fn handle_multi(cmd_array: &[RespValue], tx: &mut Transaction) -> RespValue {
+    if cmd_array.len() != 1 {
+        return RespValue::Error("ERR wrong number of arguments for 'multi' command".to_string());
     }
-
-    let mut keys = Vec::new();
-    for val in &cmd_array[1..] {
-        if let RespValue::BulkString(k) = val {
-            keys.push(k.clone());
-        } else {
-            return RespValue::SimpleString("ERR all keys must be bulk strings".to_string());
-        }
+    if !tx.begin() {
+        return RespValue::Error("ERR MULTI calls can not be nested".to_string());
     }
+    RespValue::SimpleString("OK".to_string())
+}
 
-    match store.sunion(keys) {
-        Ok(members) => RespValue::Array(members.into_iter().map(RespValue::BulkString).collect()),
-        Err(e) => RespValue::SimpleString(format!("-{}", e)),
+/// Queue a command inside MULTI. Commands that need the connection's own
+/// state can't be run from EXEC, so they are rejected and abort the transaction
+fn queue_command(
+    cmd_name: &str,
+    cmd_array: Vec<RespValue>,
+    store: &FerroStore,
+    tx: &mut Transaction,
+) -> RespValue {
+    // Like Redis, commands that can't run make EXEC fail as a whole
+    if let Some(error) = validate_command(cmd_name, &cmd_array, store) {
+        tx.mark_dirty();
+        return error;
+    }
+    // Each command's keys are already in one slot; the transaction's must
+    // be too
+    if store.config().read().cluster_enabled
+        && let Ok(Some(slot)) = command_slot(cmd_name, &cmd_array)
+        && !tx.claim_slot(slot)
+    {
+        tx.mark_dirty();
+        return RespValue::Error(CrossSlot.to_string());
+    }
+    if matches!(
+        cmd_name,
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE"
+    ) {
+        tx.mark_dirty();
+        return RespValue::Error(format!(
+            "ERR Command {} is not allowed inside a transaction",
+            cmd_name.to_lowercase()
+        ));
     }
+    tx.queue(cmd_array);
+    RespValue::SimpleString("QUEUED".to_string())
 }
 
-fn handle_sdiff(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'sdiff' command".to_string(),
-        );
+async fn handle_exec(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    conn: &mut ConnectionContext,
+) -> RespValue {
+    if cmd_array.len() != 1 {
+        return RespValue::Error("ERR wrong number of arguments for 'exec' command".to_string());
+    }
+    if !conn.transaction.in_multi() {
+        return RespValue::Error("ERR EXEC without MULTI".to_string());
     }
 
-    let mut keys = Vec::new();
-    for val in &cmd_array[1..] {
-        if let RespValue::BulkString(k) = val {
-            keys.push(k.clone());
-        } else {
-            return RespValue::SimpleString("ERR all keys must be bulk strings".to_string());
+    // Keep every other command out until the transaction is done, so the
+    // watch check and the queued commands form one atomic step
+    let _exclusive = match unless_busy(store, store.exec_lock().write()).await {
+        Ok(exclusive) => exclusive,
+        Err(busy) => return busy,
+    };
+    match conn.transaction.exec() {
+        Some(ExecOutcome::Run(commands)) => {
+            let mut replies = Vec::with_capacity(commands.len());
+            for cmd_array in commands {
+                let cmd_name = match &cmd_array[0] {
+                    RespValue::BulkString(s) => s.to_uppercase(),
+                    _ => String::new(),
+                };
+                replies.push(execute_command(&cmd_name, cmd_array, store, aof, conn, false).await);
+            }
+            RespValue::Array(replies)
         }
+        Some(ExecOutcome::WatchFailed) => RespValue::Null,
+        Some(ExecOutcome::Aborted) | None => RespValue::Error(
+            "EXECABORT Transaction discarded because of previous errors.".to_string(),
+        ),
     }
+}
 
-    match store.sdiff(keys) {
-        Ok(members) => RespValue::Array(members.into_iter().map(RespValue::BulkString).collect()),
-        Err(e) => RespValue::SimpleString(format!("-{}", e)),
+fn handle_discard(cmd_array: &[RespValue], tx: &mut Transaction) -> RespValue {
+    if cmd_array.len() != 1 {
+        return RespValue::Error("ERR wrong number of arguments for 'discard' command".to_string());
     }
+    if !tx.discard() {
+        return RespValue::Error("ERR DISCARD without MULTI".to_string());
+    }
+    RespValue::SimpleString("OK".to_string())
 }
 
-// ============ SORTED SET COMMAND HANDLERS ============
-
-fn handle_zadd(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    // ZADD key score member [score member ...]
-    if cmd_array.len() < 4 || !(cmd_array.len() - 2).is_multiple_of(2) {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'zadd' command".to_string(),
-        );
+fn handle_watch(cmd_array: &[RespValue], store: &FerroStore, tx: &mut Transaction) -> RespValue {
+    // WATCH key [key ...]
+    if cmd_array.len() < 2 {
+        return RespValue::Error("ERR wrong number of arguments for 'watch' command".to_string());
     }
-
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        let mut members = Vec::new();
-
-        // Parse score-member pairs
-        let mut i = 2;
-        while i < cmd_array.len() {
-            if let (RespValue::BulkString(score_str), RespValue::BulkString(member)) =
-                (&cmd_array[i], &cmd_array[i + 1])
-            {
-                match score_str.parse::<f64>() {
-                    Ok(score) => members.push((score, member.clone())),
-                    Err(_) => {
-                        return RespValue::SimpleString(
-                            "ERR value is not a valid float".to_string(),
-                        );
-                    }
-                }
-            } else {
-                return RespValue::SimpleString("ERR syntax error".to_string());
-            }
-            i += 2;
-        }
-
-        match store.zadd(key, members) {
-            Ok(added) => RespValue::Integer(added as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
-        }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+    if tx.in_multi() {
+        return RespValue::Error("ERR WATCH inside MULTI is not allowed".to_string());
     }
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(key) = arg else {
+            return RespValue::Error("ERR arguments must be bulk strings".to_string());
+        };
+        tx.watch(store.versions(), key);
+    }
+    RespValue::SimpleString("OK".to_string())
 }
 
-fn handle_zrem(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() < 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'zrem' command".to_string(),
-        );
+/// Wait for `lock`, unless a script has run past `lua-time-limit` while
+/// holding it: then reply BUSY straight away, as Redis does
+async fn unless_busy<T>(store: &FerroStore, lock: impl Future<Output = T>) -> Result<T, RespValue> {
+    tokio::select! {
+        biased;
+        guard = lock => Ok(guard),
+        () = store.scripts().until_busy() => Err(RespValue::Error(
+            "BUSY Redis is busy running a script. You can only call SCRIPT KILL."
+                .to_string(),
+        )),
     }
+}
 
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        let mut members = Vec::new();
+fn handle_eval(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    conn: &ConnectionContext,
+    by_sha: bool,
+) -> RespValue {
+    // EVAL script numkeys [key ...] [arg ...]
+    // EVALSHA sha1 numkeys [key ...] [arg ...]
+    let name = if by_sha { "evalsha" } else { "eval" };
+    let (script, keys, args) = match parse_numkeys_call(cmd_array, name) {
+        Ok(parsed) => parsed,
+        Err(e) => return RespValue::Error(e),
+    };
 
-        for val in &cmd_array[2..] {
-            if let RespValue::BulkString(v) = val {
-                members.push(v.clone());
-            } else {
-                return RespValue::SimpleString("ERR all members must be bulk strings".to_string());
+    let body = if by_sha {
+        match store.scripts().get(&script) {
+            Some(body) => body,
+            None => {
+                return RespValue::Error(
+                    "NOSCRIPT No matching script. Please use EVAL.".to_string(),
+                );
             }
         }
+    } else {
+        store.scripts().load(&script);
+        script
+    };
 
-        match store.zrem(key, members) {
-            Ok(removed) => RespValue::Integer(removed as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
+    let time_limit = Duration::from_millis(store.config().read().lua_time_limit);
+    let scripts = store.scripts();
+    let script_conn = RefCell::new(script_context(conn));
+    let run = || {
+        scripting::eval(scripts, &body, &keys, &args, time_limit, &|command| {
+            let writes = matches!(&command[0], RespValue::BulkString(name)
+                if command_table::lookup(&name.to_uppercase())
+                    .is_some_and(|spec| spec.flags.contains(CommandFlags::WRITE)));
+            if writes {
+                scripts.mark_write();
+            }
+            run_script_command(command, store, aof, &mut script_conn.borrow_mut())
+        })
+    };
+    // The script runs on this worker thread, whose other tasks move to
+    // other workers meanwhile so a long script doesn't strand them
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) if runtime.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(run)
         }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+        _ => run(),
     }
 }
 
-fn handle_zscore(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'zscore' command".to_string(),
-        );
-    }
+fn handle_fcall(
+    cmd_array: &[RespValue],
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    conn: &ConnectionContext,
+) -> RespValue {
+    // FCALL function numkeys [key ...] [arg ...]
+    let (function, keys, args) = match parse_numkeys_call(cmd_array, "fcall") {
+        Ok(parsed) => parsed,
+        Err(e) => return RespValue::Error(e),
+    };
+    let script_conn = RefCell::new(script_context(conn));
+    store.functions().call(&function, &keys, &args, &|command| {
+        run_script_command(command, store, aof, &mut script_conn.borrow_mut())
+    })
+}
 
-    if let (RespValue::BulkString(key), RespValue::BulkString(member)) =
-        (&cmd_array[1], &cmd_array[2])
-    {
-        match store.zscore(key, member) {
-            Ok(Some(score)) => RespValue::BulkString(score.to_string()),
-            Ok(None) => RespValue::Null,
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
-        }
-    } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
+/// The context a script's commands run in: the caller's client, user and
+/// database, with a SELECT in the script lasting only until it ends
+fn script_context(conn: &ConnectionContext) -> ConnectionContext {
+    ConnectionContext {
+        id: conn.id,
+        db: conn.db,
+        user: conn.user.clone(),
+        ..ConnectionContext::new()
     }
 }
 
-fn handle_zrange(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    // ZRANGE key start stop [WITHSCORES]
-    if cmd_array.len() < 4 || cmd_array.len() > 5 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'zrange' command".to_string(),
-        );
+/// Parse `<name> numkeys [key ...] [arg ...]` as used by EVAL and FCALL
+fn parse_numkeys_call(
+    cmd_array: &[RespValue],
+    name: &str,
+) -> Result<(String, Vec<String>, Vec<String>), String> {
+    if cmd_array.len() < 3 {
+        return Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
     }
-
-    if let (
-        RespValue::BulkString(key),
-        RespValue::BulkString(start_str),
-        RespValue::BulkString(stop_str),
-    ) = (&cmd_array[1], &cmd_array[2], &cmd_array[3])
-    {
-        let start = match start_str.parse::<i64>() {
-            Ok(s) => s,
-            Err(_) => return RespValue::SimpleString("ERR value is not an integer".to_string()),
+    let mut strings = Vec::with_capacity(cmd_array.len() - 1);
+    for arg in &cmd_array[1..] {
+        let RespValue::BulkString(s) = arg else {
+            return Err("ERR arguments must be bulk strings".to_string());
         };
+        strings.push(s.to_string());
+    }
 
-        let stop = match stop_str.parse::<i64>() {
-            Ok(s) => s,
-            Err(_) => return RespValue::SimpleString("ERR value is not an integer".to_string()),
-        };
+    let numkeys = match strings[1].parse::<i64>() {
+        Ok(n) if n < 0 => return Err("ERR Number of keys can't be negative".to_string()),
+        Ok(n) if n as usize > strings.len() - 2 => {
+            return Err("ERR Number of keys can't be greater than number of args".to_string());
+        }
+        Ok(n) => n as usize,
+        Err(_) => return Err("ERR value is not an integer or out of range".to_string()),
+    };
+    let mut args = strings.split_off(2);
+    let keys: Vec<String> = args.drain(..numkeys).collect();
+    Ok((strings.swap_remove(0), keys, args))
+}
+
+fn handle_function(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // FUNCTION LOAD [REPLACE] library-name wasm-hex | FUNCTION DELETE library-name
+    // FUNCTION LIST | FUNCTION FLUSH [ASYNC | SYNC]
+    let Some(RespValue::BulkString(subcommand)) = cmd_array.get(1) else {
+        return RespValue::Error(
+            "ERR wrong number of arguments for 'function' command".to_string(),
+        );
+    };
 
-        // Check for WITHSCORES flag
-        let with_scores = if cmd_array.len() == 5 {
-            if let RespValue::BulkString(flag) = &cmd_array[4] {
-                flag.to_uppercase() == "WITHSCORES"
+    match (subcommand.to_uppercase().as_str(), &cmd_array[2..]) {
+        (
+            "LOAD",
+            [
+                rest @ ..,
+                RespValue::BulkString(name),
+                RespValue::BulkString(payload),
+            ],
+        ) => {
+            let replace = match rest {
+                [] => false,
+                [RespValue::BulkString(opt)] if opt.eq_ignore_ascii_case("REPLACE") => true,
+                _ => return RespValue::Error("ERR syntax error".to_string()),
+            };
+            // Bulk strings are UTF-8 on the wire, so the module is hex encoded
+            let Some(wasm) = from_hex(payload) else {
+                return RespValue::Error("ERR library payload must be hex encoded".to_string());
+            };
+            match store.functions().load(name, &wasm, replace) {
+                Ok(()) => RespValue::BulkString(name.clone()),
+                Err(e) => RespValue::Error(e),
+            }
+        }
+        ("DELETE", [RespValue::BulkString(name)]) => {
+            if store.functions().delete(name) {
+                RespValue::SimpleString("OK".to_string())
             } else {
-                return RespValue::SimpleString("ERR syntax error".to_string());
+                RespValue::Error("ERR Library not found".to_string())
             }
-        } else {
-            false
-        };
-
-        match store.zrange(key, start, stop, with_scores) {
-            Ok(values) => RespValue::Array(values.into_iter().map(RespValue::BulkString).collect()),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
         }
-    } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
+        ("LIST", []) => RespValue::Array(
+            store
+                .functions()
+                .list()
+                .into_iter()
+                .map(|(name, functions)| {
+                    RespValue::Array(vec![
+                        RespValue::BulkString("library_name".into()),
+                        RespValue::BulkString(name.into()),
+                        RespValue::BulkString("functions".into()),
+                        RespValue::Array(functions.into_iter().map(RespValue::bulk).collect()),
+                    ])
+                })
+                .collect(),
+        ),
+        ("FLUSH", []) => {
+            store.functions().flush();
+            RespValue::SimpleString("OK".to_string())
+        }
+        ("FLUSH", [RespValue::BulkString(mode)])
+            if mode.eq_ignore_ascii_case("ASYNC") || mode.eq_ignore_ascii_case("SYNC") =>
+        {
+            store.functions().flush();
+            RespValue::SimpleString("OK".to_string())
+        }
+        ("LOAD" | "DELETE" | "LIST" | "FLUSH", _) => RespValue::Error(format!(
+            "ERR wrong number of arguments for 'function|{}' command",
+            subcommand.to_lowercase()
+        )),
+        _ => RespValue::Error(format!(
+            "ERR unknown subcommand '{}'. Try FUNCTION HELP.",
+            subcommand.as_str()
+        )),
     }
 }
 
-fn handle_zrank(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'zrank' command".to_string(),
-        );
+/// Run a command issued by a script (redis.call / redis.pcall) or a function
+/// Writes are logged to the AOF individually, so replaying never runs Lua
+fn run_script_command(
+    mut cmd_array: Vec<RespValue>,
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    conn: &mut ConnectionContext,
+) -> RespValue {
+    let cmd_name = match &cmd_array[0] {
+        RespValue::BulkString(s) => s.to_uppercase(),
+        _ => String::new(),
+    };
+    let cmd_name = match resolve_renamed(&mut cmd_array, cmd_name, store, conn.user.as_deref()) {
+        Ok(name) => name,
+        Err(error) => return error,
+    };
+    if command_table::lookup(&cmd_name)
+        .is_some_and(|spec| spec.flags.contains(CommandFlags::NOSCRIPT))
+    {
+        return RespValue::Error("ERR This Redis command is not allowed from script".to_string());
     }
 
-    if let (RespValue::BulkString(key), RespValue::BulkString(member)) =
-        (&cmd_array[1], &cmd_array[2])
+    if let Some(error) = check_permissions(&cmd_array, store, conn.user.as_deref()) {
+        return error;
+    }
+
+    // With `may_block` unset no command handler ever suspends, so the
+    // future completes on its first poll and the script can stay synchronous
+    let command = execute_command(&cmd_name, cmd_array, store, aof, conn, false);
+    let mut command = std::pin::pin!(command);
+    match command
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
     {
-        match store.zrank(key, member) {
-            Ok(Some(rank)) => RespValue::Integer(rank as i64),
-            Ok(None) => RespValue::Null,
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
+        Poll::Ready(reply) => reply,
+        Poll::Pending => {
+            RespValue::Error("ERR This Redis command is not allowed from script".to_string())
         }
-    } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
     }
 }
 
-fn handle_zcard(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
-    if cmd_array.len() != 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'zcard' command".to_string(),
-        );
-    }
+fn handle_script(cmd_array: &[RespValue], store: &FerroStore) -> RespValue {
+    // SCRIPT LOAD script | SCRIPT EXISTS sha1 [sha1 ...] | SCRIPT FLUSH [ASYNC | SYNC]
+    // SCRIPT KILL
+    let Some(RespValue::BulkString(subcommand)) = cmd_array.get(1) else {
+        return RespValue::Error("ERR wrong number of arguments for 'script' command".to_string());
+    };
 
-    if let RespValue::BulkString(key) = &cmd_array[1] {
-        match store.zcard(key) {
-            Ok(size) => RespValue::Integer(size as i64),
-            Err(e) => RespValue::SimpleString(format!("-{}", e)),
+    match (subcommand.to_uppercase().as_str(), &cmd_array[2..]) {
+        ("LOAD", [RespValue::BulkString(body)]) => {
+            RespValue::BulkString(store.scripts().load(body).into())
         }
-    } else {
-        RespValue::SimpleString("ERR key must be a bulk string".to_string())
+        ("EXISTS", shas) if !shas.is_empty() => RespValue::Array(
+            shas.iter()
+                .map(|sha| match sha {
+                    RespValue::BulkString(sha) => {
+                        RespValue::Integer(store.scripts().exists(sha) as i64)
+                    }
+                    _ => RespValue::Integer(0),
+                })
+                .collect(),
+        ),
+        ("FLUSH", []) => {
+            store.scripts().flush();
+            RespValue::SimpleString("OK".to_string())
+        }
+        ("FLUSH", [RespValue::BulkString(mode)])
+            if mode.eq_ignore_ascii_case("ASYNC") || mode.eq_ignore_ascii_case("SYNC") =>
+        {
+            store.scripts().flush();
+            RespValue::SimpleString("OK".to_string())
+        }
+        ("KILL", []) => match store.scripts().kill() {
+            Ok(()) => RespValue::SimpleString("OK".to_string()),
+            Err(e) => RespValue::Error(e),
+        },
+        ("LOAD" | "EXISTS" | "FLUSH" | "KILL", _) => RespValue::Error(format!(
+            "ERR wrong number of arguments for 'script|{}' command",
+            subcommand.to_lowercase()
+        )),
+        _ => RespValue::Error(format!(
+            "ERR unknown subcommand '{}'. Try SCRIPT HELP.",
+            subcommand.as_str()
+        )),
     }
 }
+/// SUBSCRIBE, or SSUBSCRIBE for shard channels
 fn handle_subscribe(
     cmd_array: &[RespValue],
-    pubsub: Option<&PubSubHub>,
-    client_subs: Option<&mut ClientSubscriptions>,
+    store: &FerroStore,
+    conn: &mut ConnectionContext,
+    kind: ChannelKind,
 ) -> RespValue {
+    let name = match kind {
+        ChannelKind::Global => "subscribe",
+        ChannelKind::Shard => "ssubscribe",
+    };
     if cmd_array.len() < 2 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'subscribe' command".to_string(),
-        );
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
     }
 
-    let Some(hub) = pubsub else {
-        return RespValue::SimpleString("ERR pub/sub not available".to_string());
-    };
-
-    let Some(subs) = client_subs else {
-        return RespValue::SimpleString("ERR subscription tracking not available".to_string());
-    };
+    let hub = store.pubsub();
+    let subs = &mut conn.subscriptions;
 
     let mut responses = Vec::new();
 
     for channel_val in &cmd_array[1..] {
         if let RespValue::BulkString(channel) = channel_val {
             // Subscribe to channel
-            let receiver = hub.subscribe(channel);
-            subs.add(channel.clone(), receiver);
+            let receiver = hub.subscribe_to(kind, channel);
+            subs.add(kind, channel.to_string(), receiver);
 
             // Return subscription confirmation
             // Format: ["subscribe", channel, subscription_count]
-            responses.push(RespValue::Array(vec![
-                RespValue::BulkString("subscribe".to_string()),
+            responses.push(RespValue::Push(vec![
+                RespValue::BulkString(name.into()),
                 RespValue::BulkString(channel.clone()),
-                RespValue::Integer(subs.count() as i64),
+                RespValue::Integer(subs.count(kind) as i64),
             ]));
         } else {
-            return RespValue::SimpleString("ERR channel names must be bulk strings".to_string());
+            return RespValue::Error("ERR channel names must be bulk strings".to_string());
         }
     }
 
@@ -958,32 +5165,36 @@ fn handle_subscribe(
     }
 }
 
+/// UNSUBSCRIBE, or SUNSUBSCRIBE for shard channels
 fn handle_unsubscribe(
     cmd_array: &[RespValue],
-    client_subs: Option<&mut ClientSubscriptions>,
+    conn: &mut ConnectionContext,
+    kind: ChannelKind,
 ) -> RespValue {
-    let Some(subs) = client_subs else {
-        return RespValue::SimpleString("ERR subscription tracking not available".to_string());
+    let subs = &mut conn.subscriptions;
+    let name = match kind {
+        ChannelKind::Global => "unsubscribe",
+        ChannelKind::Shard => "sunsubscribe",
     };
 
     if cmd_array.len() == 1 {
         // UNSUBSCRIBE with no args = unsubscribe from all
-        let channels: Vec<String> = subs.channels();
+        let channels: Vec<String> = subs.channels(kind);
         let mut responses = Vec::new();
 
         for channel in channels {
-            subs.remove(&channel);
-            responses.push(RespValue::Array(vec![
-                RespValue::BulkString("unsubscribe".to_string()),
-                RespValue::BulkString(channel),
-                RespValue::Integer(subs.count() as i64),
+            subs.remove(kind, &channel);
+            responses.push(RespValue::Push(vec![
+                RespValue::BulkString(name.into()),
+                RespValue::BulkString(channel.into()),
+                RespValue::Integer(subs.count(kind) as i64),
             ]));
         }
 
         if responses.is_empty() {
             // Not subscribed to anything
-            return RespValue::Array(vec![
-                RespValue::BulkString("unsubscribe".to_string()),
+            return RespValue::Push(vec![
+                RespValue::BulkString(name.into()),
                 RespValue::Null,
                 RespValue::Integer(0),
             ]);
@@ -1000,16 +5211,14 @@ fn handle_unsubscribe(
 
         for channel_val in &cmd_array[1..] {
             if let RespValue::BulkString(channel) = channel_val {
-                subs.remove(channel);
-                responses.push(RespValue::Array(vec![
-                    RespValue::BulkString("unsubscribe".to_string()),
+                subs.remove(kind, channel);
+                responses.push(RespValue::Push(vec![
+                    RespValue::BulkString(name.into()),
                     RespValue::BulkString(channel.clone()),
-                    RespValue::Integer(subs.count() as i64),
+                    RespValue::Integer(subs.count(kind) as i64),
                 ]));
             } else {
-                return RespValue::SimpleString(
-                    "ERR channel names must be bulk strings".to_string(),
-                );
+                return RespValue::Error("ERR channel names must be bulk strings".to_string());
             }
         }
 
@@ -1021,23 +5230,26 @@ fn handle_unsubscribe(
     }
 }
 
-fn handle_publish(cmd_array: &[RespValue], pubsub: Option<&PubSubHub>) -> RespValue {
+/// PUBLISH, or SPUBLISH for shard channels
+fn handle_publish(cmd_array: &[RespValue], store: &FerroStore, kind: ChannelKind) -> RespValue {
     if cmd_array.len() != 3 {
-        return RespValue::SimpleString(
-            "ERR wrong number of arguments for 'publish' command".to_string(),
-        );
+        let name = match kind {
+            ChannelKind::Global => "publish",
+            ChannelKind::Shard => "spublish",
+        };
+        return RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
     }
 
-    let Some(hub) = pubsub else {
-        return RespValue::SimpleString("ERR pub/sub not available".to_string());
-    };
-
+    let hub = store.pubsub();
     if let (RespValue::BulkString(channel), RespValue::BulkString(message)) =
         (&cmd_array[1], &cmd_array[2])
     {
-        let count = hub.publish(channel, message.clone());
+        let count = hub.publish_to(kind, channel, message.to_string());
         RespValue::Integer(count as i64)
     } else {
-        RespValue::SimpleString("ERR arguments must be bulk strings".to_string())
+        RespValue::Error("ERR arguments must be bulk strings".to_string())
     }
 }
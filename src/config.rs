@@ -0,0 +1,1491 @@
+use crate::command_table;
+use crate::glob::glob_match;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+/// When the AOF is fsynced to disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every logged command
+    Always,
+    /// Once per second
+    EverySec,
+    /// Never; the OS decides when to flush
+    No,
+}
+
+/// How RDB snapshot values are compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RdbCompression {
+    No,
+    Lz4,
+    Zstd,
+}
+
+/// Server log verbosity, from most to least verbose
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Every command and reply
+    Debug,
+    /// Connections and disconnections
+    Verbose,
+    Notice,
+    Warning,
+}
+
+/// Whether TLS clients must present a certificate signed by `tls-ca-cert-file`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsAuthClients {
+    No,
+    Yes,
+    /// Certificates are verified when presented, but not required
+    Optional,
+}
+
+/// What performs connection reads and writes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoBackend {
+    /// The tokio runtime, through epoll
+    Tokio,
+    /// `io-threads` io_uring threads (Linux builds with the `io-uring`
+    /// feature)
+    IoUring,
+}
+
+/// Which key to evict when `maxmemory` is reached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    /// Evict nothing; commands that would add memory fail instead
+    NoEviction,
+    AllKeysRandom,
+    /// The least recently used key
+    AllKeysLru,
+    /// The least recently used key with a TTL
+    VolatileLru,
+    /// The least frequently used key
+    AllKeysLfu,
+    /// The least frequently used key with a TTL
+    VolatileLfu,
+    /// The key with a TTL that expires soonest
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    const ALL: [MaxmemoryPolicy; 7] = [
+        MaxmemoryPolicy::NoEviction,
+        MaxmemoryPolicy::AllKeysRandom,
+        MaxmemoryPolicy::AllKeysLru,
+        MaxmemoryPolicy::VolatileLru,
+        MaxmemoryPolicy::AllKeysLfu,
+        MaxmemoryPolicy::VolatileLfu,
+        MaxmemoryPolicy::VolatileTtl,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::NoEviction => "noeviction",
+            MaxmemoryPolicy::AllKeysRandom => "allkeys-random",
+            MaxmemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::AllKeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    /// Whether only keys with a TTL may be evicted
+    pub fn volatile(self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+}
+
+/// Classes of keyspace events published to `__keyspace@<db>__:<key>` and
+/// `__keyevent@<db>__:<event>` channels (notify-keyspace-events). Written
+/// as letters, like in redis.conf
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    pub const NONE: Self = Self(0);
+    /// Publish to `__keyspace@<db>__:<key>`, with the event as message
+    pub const KEYSPACE: Self = Self(1);
+    /// Publish to `__keyevent@<db>__:<event>`, with the key as message
+    pub const KEYEVENT: Self = Self(1 << 1);
+    pub const GENERIC: Self = Self(1 << 2);
+    pub const STRING: Self = Self(1 << 3);
+    pub const LIST: Self = Self(1 << 4);
+    pub const SET: Self = Self(1 << 5);
+    pub const HASH: Self = Self(1 << 6);
+    pub const ZSET: Self = Self(1 << 7);
+    /// A key's TTL ran out
+    pub const EXPIRED: Self = Self(1 << 8);
+    /// A key was evicted for maxmemory
+    pub const EVICTED: Self = Self(1 << 9);
+    pub const STREAM: Self = Self(1 << 10);
+    pub const MODULE: Self = Self(1 << 11);
+    pub const KEY_MISS: Self = Self(1 << 12);
+    pub const NEW: Self = Self(1 << 13);
+    /// The classes `A` stands for
+    const ALL: Self = Self(0b1111_1111_1100);
+
+    const LETTERS: [(char, Self); 14] = [
+        ('g', Self::GENERIC),
+        ('$', Self::STRING),
+        ('l', Self::LIST),
+        ('s', Self::SET),
+        ('h', Self::HASH),
+        ('z', Self::ZSET),
+        ('x', Self::EXPIRED),
+        ('e', Self::EVICTED),
+        ('t', Self::STREAM),
+        ('d', Self::MODULE),
+        ('K', Self::KEYSPACE),
+        ('E', Self::KEYEVENT),
+        ('m', Self::KEY_MISS),
+        ('n', Self::NEW),
+    ];
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        value.chars().try_fold(Self::NONE, |events, letter| {
+            let class = match letter {
+                'A' => Self::ALL,
+                _ => Self::LETTERS
+                    .iter()
+                    .find(|(l, _)| *l == letter)
+                    .map(|(_, class)| *class)
+                    .ok_or("Invalid event class character. Use 'Ag$lshzxeKEtmdn'.")?,
+            };
+            Ok(events.union(class))
+        })
+    }
+
+    /// The letters of the set classes, with `A` for all of its classes
+    pub fn letters(self) -> String {
+        let mut letters = String::new();
+        let mut rest = self;
+        if self.contains(Self::ALL) {
+            letters.push('A');
+            rest = Self(self.0 & !Self::ALL.0);
+        }
+        for (letter, class) in Self::LETTERS {
+            if rest.contains(class) {
+                letters.push(letter);
+            }
+        }
+        letters
+    }
+}
+
+/// How much output may pile up for a client that isn't reading it: a
+/// client is disconnected once it has `hard` bytes pending, or has had at
+/// least `soft` bytes pending for `soft_seconds`. 0 disables a limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputBufferLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Whether a client with `pending` bytes of output has gone over the
+    /// limit. `soft_since` is when it went over the soft limit, kept by the
+    /// caller between calls
+    pub fn exceeded(&self, pending: u64, soft_since: &mut Option<Instant>) -> bool {
+        if self.hard > 0 && pending >= self.hard {
+            return true;
+        }
+        if self.soft == 0 || pending < self.soft {
+            *soft_since = None;
+            return false;
+        }
+        let since = *soft_since.get_or_insert_with(Instant::now);
+        since.elapsed() >= Duration::from_secs(self.soft_seconds)
+    }
+}
+
+/// `client-output-buffer-limit` for each class of clients (see
+/// `ClientInfo::kind`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputBufferLimits {
+    pub normal: OutputBufferLimit,
+    pub replica: OutputBufferLimit,
+    pub pubsub: OutputBufferLimit,
+}
+
+impl OutputBufferLimits {
+    /// The limit for clients of `kind`: `normal`, `replica` or `pubsub`
+    pub fn class(&self, kind: &str) -> OutputBufferLimit {
+        match kind {
+            "replica" => self.replica,
+            "pubsub" => self.pubsub,
+            _ => self.normal,
+        }
+    }
+
+    fn class_mut(&mut self, kind: &str) -> Option<&mut OutputBufferLimit> {
+        match kind {
+            "normal" => Some(&mut self.normal),
+            "replica" | "slave" => Some(&mut self.replica),
+            "pubsub" => Some(&mut self.pubsub),
+            _ => None,
+        }
+    }
+}
+
+impl Default for OutputBufferLimits {
+    fn default() -> Self {
+        Self {
+            normal: OutputBufferLimit {
+                hard: 0,
+                soft: 0,
+                soft_seconds: 0,
+            },
+            replica: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+            pubsub: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_seconds: 60,
+            },
+        }
+    }
+}
+
+/// Snapshot after `seconds` if at least `changes` writes happened since the last save
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Server tunables, see `PARAMETERS` for their CONFIG names
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigValues {
+    /// Addresses to listen on; a `-` prefix marks one that may be
+    /// unavailable (e.g. `-::1` on hosts without IPv6)
+    pub bind: Vec<String>,
+    /// Refuse non-loopback clients while the default user needs no password
+    pub protected_mode: bool,
+    /// Close clients idle for this many seconds; 0 never does
+    pub timeout: u64,
+    /// Seconds of silence before TCP keepalive probes start; 0 disables them
+    pub tcp_keepalive: u64,
+    /// Disable Nagle's algorithm so replies go out immediately
+    pub tcp_nodelay: bool,
+    /// Plain TCP port; 0 disables it (e.g. to only accept TLS)
+    pub port: u16,
+    /// Accept loops per listening address. Above 1 each gets its own
+    /// SO_REUSEPORT socket and the kernel spreads new connections across
+    /// them (unix only). Also the number of io_uring threads
+    pub io_threads: usize,
+    pub io_backend: IoBackend,
+    /// TLS port; 0 disables TLS
+    pub tls_port: u16,
+    /// PEM certificate chain and private key served on `tls_port`
+    pub tls_cert_file: String,
+    pub tls_key_file: String,
+    /// PEM CA certificates used to verify client certificates
+    pub tls_ca_cert_file: String,
+    pub tls_auth_clients: TlsAuthClients,
+    /// Working directory; persistence files are relative to it
+    pub dir: String,
+    /// Detach from the terminal at startup (unix only)
+    pub daemonize: bool,
+    pub dbfilename: String,
+    pub rdbcompression: RdbCompression,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    /// Directory holding the AOF's manifest and files
+    pub appenddirname: String,
+    pub appendfsync: AppendFsync,
+    /// Load an AOF whose last command was cut short, truncating it away
+    pub aof_load_truncated: bool,
+    /// Write the base file of an AOF rewrite as an RDB file
+    pub aof_use_rdb_preamble: bool,
+    /// Snapshot rules; empty disables automatic snapshots
+    pub save: Vec<SaveRule>,
+    /// Active expiration cycles per second
+    pub hz: u32,
+    /// Memory limit in bytes, 0 for none
+    pub maxmemory: u64,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    /// Keys sampled per eviction, trading accuracy for speed
+    pub maxmemory_samples: usize,
+    /// Milliseconds a script runs before other clients get BUSY replies
+    /// and SCRIPT KILL is suggested
+    pub lua_time_limit: u64,
+    /// Free the values of evicted keys in the background
+    pub lazyfree_lazy_eviction: bool,
+    /// Free values that commands overwrite (e.g. SET on an existing key)
+    /// in the background
+    pub lazyfree_lazy_server_del: bool,
+    /// Longest bulk string a client may send, in bytes
+    pub proto_max_bulk_len: u64,
+    /// Lists stay packed up to this many elements if positive, or up to
+    /// 4kb, 8kb, 16kb, 32kb or 64kb for -1 to -5
+    pub list_max_listpack_size: i64,
+    /// Sets and sorted sets stay packed up to this many members, each at
+    /// most the `_value` limit long
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    pub zset_max_listpack_entries: usize,
+    pub zset_max_listpack_value: usize,
+    /// Password clients must AUTH with; empty for none
+    pub requirepass: String,
+    /// The master this server replicates, as host and port; set at
+    /// startup or with REPLICAOF
+    pub replicaof: Option<(String, u16)>,
+    /// Password this server AUTHs with to its master; empty for none
+    pub masterauth: String,
+    /// Refuse writes from clients while a replica
+    pub replica_read_only: bool,
+    /// Serve clients while a replica's link to its master is down, from a
+    /// dataset that may be out of date
+    pub replica_serve_stale_data: bool,
+    /// Bytes of the replication stream kept for replicas to resume from
+    pub repl_backlog_size: u64,
+    /// Cluster mode: multi-key commands and transactions must keep to
+    /// keys in one hash slot
+    pub cluster_enabled: bool,
+    pub client_output_buffer_limit: OutputBufferLimits,
+    pub loglevel: LogLevel,
+    /// Record events taking at least this many milliseconds (LATENCY); 0 disables
+    pub latency_monitor_threshold: u64,
+    /// Keyspace events published to subscribers; none by default
+    pub notify_keyspace_events: KeyspaceEvents,
+    /// `rename-command` directives as (original, new name) pairs, upper
+    /// case; an empty new name disables the command
+    pub rename_commands: Vec<(String, String)>,
+}
+
+impl Default for ConfigValues {
+    fn default() -> Self {
+        Self {
+            bind: vec!["127.0.0.1".to_string(), "-::1".to_string()],
+            protected_mode: true,
+            timeout: 0,
+            tcp_keepalive: 300,
+            tcp_nodelay: true,
+            port: 6379,
+            io_threads: 1,
+            io_backend: IoBackend::Tokio,
+            tls_port: 0,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            tls_ca_cert_file: String::new(),
+            tls_auth_clients: TlsAuthClients::No,
+            dir: ".".to_string(),
+            daemonize: false,
+            dbfilename: "dump.rdb".to_string(),
+            rdbcompression: RdbCompression::Lz4,
+            appendonly: true,
+            appendfilename: "appendonly.aof".to_string(),
+            appenddirname: "appendonlydir".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            aof_load_truncated: true,
+            aof_use_rdb_preamble: true,
+            save: vec![SaveRule {
+                seconds: 60,
+                changes: 1,
+            }],
+            hz: 10,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::NoEviction,
+            maxmemory_samples: 5,
+            lua_time_limit: 5000,
+            lazyfree_lazy_eviction: false,
+            lazyfree_lazy_server_del: false,
+            proto_max_bulk_len: crate::protocol::DEFAULT_MAX_BULK_LEN as u64,
+            list_max_listpack_size: -2,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+            zset_max_listpack_entries: 128,
+            zset_max_listpack_value: 64,
+            requirepass: String::new(),
+            replicaof: None,
+            masterauth: String::new(),
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            repl_backlog_size: crate::replication::DEFAULT_BACKLOG_SIZE as u64,
+            cluster_enabled: false,
+            client_output_buffer_limit: OutputBufferLimits::default(),
+            loglevel: LogLevel::Notice,
+            latency_monitor_threshold: 0,
+            notify_keyspace_events: KeyspaceEvents::NONE,
+            rename_commands: Vec::new(),
+        }
+    }
+}
+
+/// Runtime configuration shared by the server and command handlers (CONFIG GET / SET)
+#[derive(Clone, Default)]
+pub struct ServerConfig {
+    values: Arc<RwLock<ConfigValues>>,
+    /// The file the configuration was loaded from, for CONFIG REWRITE
+    file: Arc<Mutex<Option<PathBuf>>>,
+}
+
+/// A CONFIG parameter: how to render and parse it
+struct Parameter {
+    name: &'static str,
+    /// Whether CONFIG SET may change it; the others are only read at startup
+    mutable: bool,
+    get: fn(&ConfigValues) -> String,
+    set: fn(&mut ConfigValues, &str) -> Result<(), String>,
+}
+
+static PARAMETERS: &[Parameter] = &[
+    Parameter {
+        name: "bind",
+        mutable: false,
+        get: |c| c.bind.join(" "),
+        set: |c, v| {
+            let addresses: Vec<String> = v.split_whitespace().map(str::to_string).collect();
+            if addresses.is_empty() {
+                return Err("argument must be one or more addresses".to_string());
+            }
+            c.bind = addresses;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "protected-mode",
+        mutable: true,
+        get: |c| yes_no(c.protected_mode),
+        set: |c, v| {
+            c.protected_mode = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "timeout",
+        mutable: true,
+        get: |c| c.timeout.to_string(),
+        set: |c, v| {
+            c.timeout = v
+                .parse()
+                .map_err(|_| "argument must be a non-negative number of seconds".to_string())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tcp-keepalive",
+        mutable: true,
+        get: |c| c.tcp_keepalive.to_string(),
+        set: |c, v| {
+            c.tcp_keepalive = v
+                .parse()
+                .map_err(|_| "argument must be a non-negative number of seconds".to_string())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tcp-nodelay",
+        mutable: true,
+        get: |c| yes_no(c.tcp_nodelay),
+        set: |c, v| {
+            c.tcp_nodelay = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "io-threads",
+        mutable: false,
+        get: |c| c.io_threads.to_string(),
+        set: |c, v| {
+            c.io_threads = match v.parse() {
+                Ok(threads) if (1..=128).contains(&threads) => threads,
+                _ => return Err("argument must be between 1 and 128 inclusive".to_string()),
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "io-backend",
+        mutable: false,
+        get: |c| {
+            match c.io_backend {
+                IoBackend::Tokio => "tokio",
+                IoBackend::IoUring => "io-uring",
+            }
+            .to_string()
+        },
+        set: |c, v| {
+            c.io_backend = match v.to_lowercase().as_str() {
+                "tokio" => IoBackend::Tokio,
+                "io-uring" => IoBackend::IoUring,
+                _ => {
+                    return Err(
+                        "argument(s) must be one of the following: tokio, io-uring".to_string()
+                    );
+                }
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "port",
+        mutable: false,
+        get: |c| c.port.to_string(),
+        set: |c, v| {
+            c.port = v
+                .parse()
+                .map_err(|_| "argument must be a valid port".to_string())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-port",
+        mutable: false,
+        get: |c| c.tls_port.to_string(),
+        set: |c, v| {
+            c.tls_port = v
+                .parse()
+                .map_err(|_| "argument must be a valid port".to_string())?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-cert-file",
+        mutable: false,
+        get: |c| c.tls_cert_file.clone(),
+        set: |c, v| {
+            c.tls_cert_file = v.to_string();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-key-file",
+        mutable: false,
+        get: |c| c.tls_key_file.clone(),
+        set: |c, v| {
+            c.tls_key_file = v.to_string();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-ca-cert-file",
+        mutable: false,
+        get: |c| c.tls_ca_cert_file.clone(),
+        set: |c, v| {
+            c.tls_ca_cert_file = v.to_string();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "tls-auth-clients",
+        mutable: false,
+        get: |c| {
+            match c.tls_auth_clients {
+                TlsAuthClients::No => "no",
+                TlsAuthClients::Yes => "yes",
+                TlsAuthClients::Optional => "optional",
+            }
+            .to_string()
+        },
+        set: |c, v| {
+            c.tls_auth_clients = match v.to_lowercase().as_str() {
+                "no" => TlsAuthClients::No,
+                "yes" => TlsAuthClients::Yes,
+                "optional" => TlsAuthClients::Optional,
+                _ => {
+                    return Err(
+                        "argument(s) must be one of the following: no, yes, optional".to_string(),
+                    );
+                }
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "dir",
+        mutable: false,
+        get: |c| c.dir.clone(),
+        set: |c, v| {
+            c.dir = v.to_string();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "daemonize",
+        mutable: false,
+        get: |c| yes_no(c.daemonize),
+        set: |c, v| {
+            c.daemonize = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "dbfilename",
+        mutable: true,
+        get: |c| c.dbfilename.clone(),
+        set: |c, v| {
+            c.dbfilename = parse_filename(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "rdbcompression",
+        mutable: true,
+        get: |c| {
+            match c.rdbcompression {
+                RdbCompression::No => "no",
+                RdbCompression::Lz4 => "lz4",
+                RdbCompression::Zstd => "zstd",
+            }
+            .to_string()
+        },
+        set: |c, v| {
+            c.rdbcompression = match v.to_lowercase().as_str() {
+                "no" => RdbCompression::No,
+                // `yes` as in Redis configs, with the fast codec
+                "yes" | "lz4" => RdbCompression::Lz4,
+                "zstd" => RdbCompression::Zstd,
+                _ => {
+                    return Err(
+                        "argument(s) must be one of the following: no, yes, lz4, zstd".to_string(),
+                    );
+                }
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "appendonly",
+        mutable: true,
+        get: |c| yes_no(c.appendonly),
+        set: |c, v| {
+            c.appendonly = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "appendfilename",
+        mutable: false,
+        get: |c| c.appendfilename.clone(),
+        set: |c, v| {
+            c.appendfilename = parse_filename(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "appenddirname",
+        mutable: false,
+        get: |c| c.appenddirname.clone(),
+        set: |c, v| {
+            c.appenddirname = parse_filename(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "appendfsync",
+        mutable: true,
+        get: |c| {
+            match c.appendfsync {
+                AppendFsync::Always => "always",
+                AppendFsync::EverySec => "everysec",
+                AppendFsync::No => "no",
+            }
+            .to_string()
+        },
+        set: |c, v| {
+            c.appendfsync = match v.to_lowercase().as_str() {
+                "always" => AppendFsync::Always,
+                "everysec" => AppendFsync::EverySec,
+                "no" => AppendFsync::No,
+                _ => {
+                    return Err(
+                        "argument(s) must be one of the following: always, everysec, no"
+                            .to_string(),
+                    );
+                }
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "aof-load-truncated",
+        mutable: true,
+        get: |c| yes_no(c.aof_load_truncated),
+        set: |c, v| {
+            c.aof_load_truncated = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "aof-use-rdb-preamble",
+        mutable: true,
+        get: |c| yes_no(c.aof_use_rdb_preamble),
+        set: |c, v| {
+            c.aof_use_rdb_preamble = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "save",
+        mutable: true,
+        get: |c| {
+            c.save
+                .iter()
+                .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+        set: |c, v| {
+            c.save = parse_save_rules(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "hz",
+        mutable: true,
+        get: |c| c.hz.to_string(),
+        set: |c, v| {
+            c.hz = match v.parse() {
+                Ok(hz) if (1..=500).contains(&hz) => hz,
+                _ => return Err("argument must be between 1 and 500 inclusive".to_string()),
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxmemory",
+        mutable: true,
+        get: |c| c.maxmemory.to_string(),
+        set: |c, v| {
+            c.maxmemory = parse_memory(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxmemory-policy",
+        mutable: true,
+        get: |c| c.maxmemory_policy.name().to_string(),
+        set: |c, v| {
+            let v = v.to_lowercase();
+            c.maxmemory_policy = MaxmemoryPolicy::ALL
+                .into_iter()
+                .find(|policy| policy.name() == v)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = MaxmemoryPolicy::ALL
+                        .iter()
+                        .map(|policy| policy.name())
+                        .collect();
+                    format!(
+                        "argument(s) must be one of the following: {}",
+                        names.join(", ")
+                    )
+                })?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "maxmemory-samples",
+        mutable: true,
+        get: |c| c.maxmemory_samples.to_string(),
+        set: |c, v| {
+            c.maxmemory_samples = match v.parse() {
+                Ok(samples) if (1..=64).contains(&samples) => samples,
+                _ => return Err("argument must be between 1 and 64 inclusive".to_string()),
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "lua-time-limit",
+        mutable: true,
+        get: |c| c.lua_time_limit.to_string(),
+        set: |c, v| {
+            c.lua_time_limit = v.parse().map_err(|_| {
+                "argument must be a non-negative number of milliseconds".to_string()
+            })?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "lazyfree-lazy-eviction",
+        mutable: true,
+        get: |c| yes_no(c.lazyfree_lazy_eviction),
+        set: |c, v| {
+            c.lazyfree_lazy_eviction = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "lazyfree-lazy-server-del",
+        mutable: true,
+        get: |c| yes_no(c.lazyfree_lazy_server_del),
+        set: |c, v| {
+            c.lazyfree_lazy_server_del = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "proto-max-bulk-len",
+        mutable: true,
+        get: |c| c.proto_max_bulk_len.to_string(),
+        set: |c, v| {
+            c.proto_max_bulk_len = match parse_memory(v)? {
+                len if len >= 1024 * 1024 => len,
+                _ => return Err("argument must be at least 1mb".to_string()),
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "list-max-listpack-size",
+        mutable: true,
+        get: |c| c.list_max_listpack_size.to_string(),
+        set: |c, v| {
+            c.list_max_listpack_size = match v.parse() {
+                Ok(size) if size > 0 || (-5..=-1).contains(&size) => size,
+                _ => {
+                    return Err("argument must be positive or between -5 and -1".to_string());
+                }
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "set-max-listpack-entries",
+        mutable: true,
+        get: |c| c.set_max_listpack_entries.to_string(),
+        set: |c, v| {
+            c.set_max_listpack_entries = parse_count(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "set-max-listpack-value",
+        mutable: true,
+        get: |c| c.set_max_listpack_value.to_string(),
+        set: |c, v| {
+            c.set_max_listpack_value = parse_count(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "zset-max-listpack-entries",
+        mutable: true,
+        get: |c| c.zset_max_listpack_entries.to_string(),
+        set: |c, v| {
+            c.zset_max_listpack_entries = parse_count(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "zset-max-listpack-value",
+        mutable: true,
+        get: |c| c.zset_max_listpack_value.to_string(),
+        set: |c, v| {
+            c.zset_max_listpack_value = parse_count(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "requirepass",
+        mutable: true,
+        get: |c| c.requirepass.clone(),
+        set: |c, v| {
+            c.requirepass = v.to_string();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "replicaof",
+        mutable: false,
+        get: |c| match &c.replicaof {
+            Some((host, port)) => format!("{} {}", host, port),
+            None => String::new(),
+        },
+        set: |c, v| {
+            c.replicaof = match v.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => None,
+                [host, port] => {
+                    let port = port.parse().map_err(|_| "argument must be a port")?;
+                    Some((host.to_string(), port))
+                }
+                _ => return Err("argument must be host and port".to_string()),
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "masterauth",
+        mutable: true,
+        get: |c| c.masterauth.clone(),
+        set: |c, v| {
+            c.masterauth = v.to_string();
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "replica-read-only",
+        mutable: true,
+        get: |c| yes_no(c.replica_read_only),
+        set: |c, v| {
+            c.replica_read_only = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "replica-serve-stale-data",
+        mutable: true,
+        get: |c| yes_no(c.replica_serve_stale_data),
+        set: |c, v| {
+            c.replica_serve_stale_data = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "repl-backlog-size",
+        mutable: true,
+        get: |c| c.repl_backlog_size.to_string(),
+        set: |c, v| {
+            c.repl_backlog_size = match parse_memory(v)? {
+                size if size >= 16 * 1024 => size,
+                _ => return Err("argument must be at least 16kb".to_string()),
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "client-output-buffer-limit",
+        mutable: true,
+        get: |c| {
+            let limits = &c.client_output_buffer_limit;
+            [
+                ("normal", limits.normal),
+                ("replica", limits.replica),
+                ("pubsub", limits.pubsub),
+            ]
+            .iter()
+            .map(|(class, limit)| {
+                format!(
+                    "{} {} {} {}",
+                    class, limit.hard, limit.soft, limit.soft_seconds
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+        },
+        // `class hard soft soft-seconds`, for one or more classes; the
+        // others keep their limits
+        set: |c, v| {
+            let args: Vec<&str> = v.split_whitespace().collect();
+            if args.is_empty() || !args.len().is_multiple_of(4) {
+                return Err("wrong number of arguments".to_string());
+            }
+            for class in args.chunks(4) {
+                let limit = c
+                    .client_output_buffer_limit
+                    .class_mut(&class[0].to_lowercase())
+                    .ok_or_else(|| format!("invalid client class '{}'", class[0]))?;
+                *limit = OutputBufferLimit {
+                    hard: parse_memory(class[1])?,
+                    soft: parse_memory(class[2])?,
+                    soft_seconds: class[3]
+                        .parse()
+                        .map_err(|_| "soft-seconds must be a number of seconds".to_string())?,
+                };
+            }
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "cluster-enabled",
+        mutable: false,
+        get: |c| yes_no(c.cluster_enabled),
+        set: |c, v| {
+            c.cluster_enabled = parse_bool(v)?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "loglevel",
+        mutable: true,
+        get: |c| {
+            match c.loglevel {
+                LogLevel::Debug => "debug",
+                LogLevel::Verbose => "verbose",
+                LogLevel::Notice => "notice",
+                LogLevel::Warning => "warning",
+            }
+            .to_string()
+        },
+        set: |c, v| {
+            c.loglevel = match v.to_lowercase().as_str() {
+                "debug" => LogLevel::Debug,
+                "verbose" => LogLevel::Verbose,
+                "notice" => LogLevel::Notice,
+                "warning" => LogLevel::Warning,
+                _ => {
+                    return Err(
+                        "argument(s) must be one of the following: debug, verbose, notice, warning"
+                            .to_string(),
+                    );
+                }
+            };
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "latency-monitor-threshold",
+        mutable: true,
+        get: |c| c.latency_monitor_threshold.to_string(),
+        set: |c, v| {
+            c.latency_monitor_threshold = v.parse().map_err(|_| {
+                "argument must be a non-negative number of milliseconds".to_string()
+            })?;
+            Ok(())
+        },
+    },
+    Parameter {
+        name: "notify-keyspace-events",
+        mutable: true,
+        get: |c| c.notify_keyspace_events.letters(),
+        set: |c, v| {
+            c.notify_keyspace_events = KeyspaceEvents::parse(v)?;
+            Ok(())
+        },
+    },
+];
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current values; don't hold the guard across an await
+    pub fn read(&self) -> RwLockReadGuard<'_, ConfigValues> {
+        self.values.read().unwrap()
+    }
+
+    /// The real name of the command a client invoked as `name` (upper case):
+    /// a `rename-command` target runs the original command, while the
+    /// original name of a renamed or disabled command runs nothing
+    pub fn resolve_command(&self, name: &str) -> Option<String> {
+        let values = self.read();
+        for (original, renamed) in &values.rename_commands {
+            if !renamed.is_empty() && renamed == name {
+                return Some(original.clone());
+            }
+        }
+        if values
+            .rename_commands
+            .iter()
+            .any(|(original, _)| original == name)
+        {
+            return None;
+        }
+        Some(name.to_string())
+    }
+
+    /// Parameters matching any of the glob `patterns`, with their values (CONFIG GET)
+    pub fn get_matching(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        let values = self.read();
+        PARAMETERS
+            .iter()
+            .filter(|param| {
+                patterns
+                    .iter()
+                    .any(|pattern| glob_match(&pattern.to_lowercase(), param.name))
+            })
+            .map(|param| (param.name, (param.get)(&values)))
+            .collect()
+    }
+
+    /// Apply `name value` pairs (CONFIG SET). Either every pair is applied or,
+    /// if any is invalid, none is
+    pub fn set(&self, pairs: &[(String, String)]) -> Result<(), String> {
+        let mut values = self.values.write().unwrap();
+        let mut updated = values.clone();
+        for (i, (name, value)) in pairs.iter().enumerate() {
+            let name = name.to_lowercase();
+            let Some(param) = PARAMETERS.iter().find(|param| param.name == name) else {
+                return Err(format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ));
+            };
+            if pairs[..i]
+                .iter()
+                .any(|(other, _)| other.eq_ignore_ascii_case(&name))
+            {
+                return Err(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - duplicate parameter",
+                    name
+                ));
+            }
+            if !param.mutable {
+                return Err(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                    name
+                ));
+            }
+            (param.set)(&mut updated, value).map_err(|reason| {
+                format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                    name, reason
+                )
+            })?;
+        }
+        *values = updated;
+        Ok(())
+    }
+
+    /// Follow `master`, or none (REPLICAOF), which CONFIG SET can't change
+    pub fn set_replicaof(&self, master: Option<(String, u16)>) {
+        self.values.write().unwrap().replicaof = master;
+    }
+
+    /// Apply `name value` pairs given on the command line. Unlike CONFIG SET
+    /// this can change immutable parameters, since the server hasn't
+    /// started yet
+    pub fn apply_overrides(&self, pairs: &[(&str, String)]) -> Result<(), String> {
+        let mut values = self.values.write().unwrap();
+        let mut updated = values.clone();
+        for (name, value) in pairs {
+            let param = PARAMETERS
+                .iter()
+                .find(|param| param.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("Unknown option '--{}'", name))?;
+            (param.set)(&mut updated, value)
+                .map_err(|reason| format!("Invalid value for '--{}': {}", param.name, reason))?;
+        }
+        *values = updated;
+        Ok(())
+    }
+}
+
+/// How the config file differs from the running configuration
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FileChanges {
+    /// `name value` pairs of parameters CONFIG SET can change
+    pub reloadable: Vec<(String, String)>,
+    /// Parameters that only take effect on restart
+    pub restart_needed: Vec<&'static str>,
+}
+
+impl ServerConfig {
+    /// Load a redis.conf-style file: one `directive arg ...` per line, `#`
+    /// comments, and quoted arguments (`save ""` disables snapshots). The
+    /// file is remembered for CONFIG REWRITE
+    pub fn load_file(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let updated = self.parse_file(path)?;
+        *self.values.write().unwrap() = updated;
+        // Absolute, so CONFIG REWRITE still finds it after `dir` is applied
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        *self.file.lock().unwrap() = Some(path);
+        Ok(())
+    }
+
+    /// Re-read the file the configuration was loaded from (SIGHUP) and
+    /// compare it with the running configuration. Directives missing from
+    /// the file keep their current value
+    pub fn reload_changes(&self) -> Result<FileChanges, String> {
+        let Some(path) = self.file.lock().unwrap().clone() else {
+            return Err("The server is running without a config file".to_string());
+        };
+        let loaded = self.parse_file(&path)?;
+        let current = self.read().clone();
+        let mut changes = FileChanges::default();
+        for param in PARAMETERS {
+            let value = (param.get)(&loaded);
+            if value == (param.get)(&current) {
+                continue;
+            }
+            if param.mutable {
+                changes.reloadable.push((param.name.to_string(), value));
+            } else {
+                changes.restart_needed.push(param.name);
+            }
+        }
+        if loaded.rename_commands != current.rename_commands {
+            changes.restart_needed.push("rename-command");
+        }
+        Ok(changes)
+    }
+
+    /// The current values with the directives of the file at `path` applied
+    fn parse_file(&self, path: &Path) -> Result<ConfigValues, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't open config file '{}': {}", path.display(), e))?;
+
+        let mut updated = self.read().clone();
+        let mut save_rules: Option<Vec<SaveRule>> = None;
+        for (number, line) in text.lines().enumerate() {
+            let bad_line = |reason: &str| {
+                format!(
+                    "Bad directive or wrong number of arguments at line {} ('{}'): {}",
+                    number + 1,
+                    line.trim(),
+                    reason
+                )
+            };
+            let args = split_args(line).ok_or_else(|| bad_line("unbalanced quotes"))?;
+            let Some((directive, values)) = args.split_first() else {
+                continue;
+            };
+            let directive = directive.to_lowercase();
+            // rename-command ORIGINAL NEWNAME, or "" to disable ORIGINAL
+            if directive == "rename-command" {
+                let [original, renamed] = values else {
+                    return Err(bad_line("expected two arguments"));
+                };
+                let (original, renamed) = (original.to_uppercase(), renamed.to_uppercase());
+                if command_table::lookup(&original).is_none() {
+                    return Err(bad_line("no such command"));
+                }
+                if command_table::lookup(&renamed).is_some() {
+                    return Err(bad_line("the new name is already a command"));
+                }
+                updated
+                    .rename_commands
+                    .retain(|(name, _)| *name != original);
+                updated.rename_commands.push((original, renamed));
+                continue;
+            }
+            // Each save line adds a rule to those of the previous lines
+            if directive == "save" {
+                let rules = save_rules.get_or_insert_with(Vec::new);
+                rules.extend(parse_save_rules(&values.join(" ")).map_err(|e| bad_line(&e))?);
+                continue;
+            }
+            let param = PARAMETERS
+                .iter()
+                .find(|param| param.name == directive)
+                .ok_or_else(|| bad_line("unknown directive"))?;
+            // bind lists its addresses as separate arguments,
+            let value = match values {
+                [value] => value.clone(),
+                // as do replicaof's host and port and client-output-buffer-limit's
+                // class and limits
+                [_, ..]
+                    if matches!(
+                        param.name,
+                        "bind" | "replicaof" | "client-output-buffer-limit"
+                    ) =>
+                {
+                    values.join(" ")
+                }
+                _ => return Err(bad_line("expected one argument")),
+            };
+            (param.set)(&mut updated, &value).map_err(|e| bad_line(&e))?;
+        }
+        if let Some(rules) = save_rules {
+            updated.save = rules;
+        }
+        Ok(updated)
+    }
+
+    /// Write the current configuration back to the file it was loaded from
+    /// (CONFIG REWRITE). Comments and unknown lines are kept, known
+    /// directives are updated in place, and parameters that differ from
+    /// their default but weren't in the file are appended
+    pub fn rewrite(&self) -> Result<(), String> {
+        let Some(path) = self.file.lock().unwrap().clone() else {
+            return Err("ERR The server is running without a config file".to_string());
+        };
+        let old = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("ERR Rewriting config file: {}", e)),
+        };
+
+        let values = self.read().clone();
+        let defaults = ConfigValues::default();
+        let mut written: Vec<&str> = Vec::new();
+        let mut lines: Vec<String> = Vec::new();
+        for line in old.lines() {
+            let directive = split_args(line)
+                .and_then(|args| args.first().map(|d| d.to_lowercase()))
+                .and_then(|d| PARAMETERS.iter().find(|param| param.name == d));
+            match directive {
+                // Only the first occurrence is kept, holding every save rule
+                Some(param) if written.contains(&param.name) => {}
+                Some(param) => {
+                    written.push(param.name);
+                    lines.extend(config_lines(param, &values));
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+        for param in PARAMETERS {
+            if !written.contains(&param.name) && (param.get)(&values) != (param.get)(&defaults) {
+                lines.extend(config_lines(param, &values));
+            }
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, text)
+            .and_then(|_| std::fs::rename(&temp, &path))
+            .map_err(|e| format!("ERR Rewriting config file: {}", e))
+    }
+}
+
+/// Config file lines for `param`; one per save rule and per client class,
+/// and bind's addresses as separate arguments
+fn config_lines(param: &Parameter, values: &ConfigValues) -> Vec<String> {
+    if param.name == "save" && !values.save.is_empty() {
+        return values
+            .save
+            .iter()
+            .map(|rule| format!("save {} {}", rule.seconds, rule.changes))
+            .collect();
+    }
+    if param.name == "client-output-buffer-limit" {
+        let value = (param.get)(values);
+        let args: Vec<&str> = value.split(' ').collect();
+        return args
+            .chunks(4)
+            .map(|class| format!("{} {}", param.name, class.join(" ")))
+            .collect();
+    }
+    if param.name == "bind" {
+        let addresses: Vec<String> = values.bind.iter().cloned().map(quote).collect();
+        return vec![format!("bind {}", addresses.join(" "))];
+    }
+    if param.name == "replicaof"
+        && let Some((host, port)) = &values.replicaof
+    {
+        return vec![format!("replicaof {} {}", quote(host.clone()), port)];
+    }
+    vec![format!("{} {}", param.name, quote((param.get)(values)))]
+}
+
+fn quote(value: String) -> String {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return value;
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Split a config line into arguments, honouring double and single quotes
+/// and `\` escapes inside double quotes. Comments and blank lines yield no
+/// arguments; None means unbalanced quotes
+fn split_args(line: &str) -> Option<Vec<String>> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return Some(Vec::new());
+    }
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Some(args);
+        };
+        let mut arg = String::new();
+        match first {
+            '"' => loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => arg.push(chars.next()?),
+                    c => arg.push(c),
+                }
+            },
+            '\'' => loop {
+                match chars.next()? {
+                    '\'' => break,
+                    c => arg.push(c),
+                }
+            },
+            c => {
+                arg.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+            }
+        }
+        args.push(arg);
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| "argument must be a non-negative integer".to_string())
+}
+
+fn parse_filename(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains(['/', '\\']) {
+        return Err("argument can't be a path, just a filename".to_string());
+    }
+    Ok(value.to_string())
+}
+
+/// Parse `seconds changes [seconds changes ...]`; an empty string disables saving
+fn parse_save_rules(value: &str) -> Result<Vec<SaveRule>, String> {
+    let numbers: Vec<u64> = value
+        .split_whitespace()
+        .map(|n| n.parse().map_err(|_| "Invalid save parameters".to_string()))
+        .collect::<Result<_, _>>()?;
+    if !numbers.len().is_multiple_of(2) {
+        return Err("Invalid save parameters".to_string());
+    }
+    Ok(numbers
+        .chunks(2)
+        .map(|pair| SaveRule {
+            seconds: pair[0],
+            changes: pair[1],
+        })
+        .collect())
+}
+
+/// Parse a byte count with an optional unit: `k`/`m`/`g` (powers of 1000)
+/// or `kb`/`mb`/`gb` (powers of 1024), case-insensitive
+pub fn parse_memory(value: &str) -> Result<u64, String> {
+    let lower = value.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err("argument must be a memory value".to_string()),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| "argument must be a memory value".to_string())
+}
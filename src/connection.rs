@@ -0,0 +1,74 @@
+use crate::protocol::RESP2;
+use crate::pubsub::ClientSubscriptions;
+use crate::replication::ReplicaLink;
+use crate::transaction::Transaction;
+
+/// The state a client connection carries from one command to the next:
+/// who it is and runs as, the database and protocol it chose, and its
+/// transaction, pub/sub, AUTH, replica and ASKING state.
+///
+/// Its name, database, user and subscriptions are also copied into the
+/// client's `clients` entry, for CLIENT LIST
+pub struct ConnectionContext {
+    /// The client's id in `ClientRegistry`; None for internal callers (AOF
+    /// replay, the master's stream, tests)
+    pub id: Option<u64>,
+    /// Set with CLIENT SETNAME or HELLO ... SETNAME
+    pub name: String,
+    /// The database commands run on (SELECT)
+    pub db: usize,
+    /// The ACL user commands run as; None for internal callers, which may
+    /// run anything
+    pub user: Option<String>,
+    /// The protocol version (2 or 3) replies are encoded in, chosen with
+    /// HELLO
+    pub protocol: u8,
+    /// MULTI queue and WATCHed keys
+    pub transaction: Transaction,
+    /// Channels the connection is subscribed to
+    pub subscriptions: ClientSubscriptions,
+    /// Logged in with AUTH (or HELLO ... AUTH), as `requirepass` demands
+    pub authenticated: bool,
+    /// The port the client serves on if it's a replica (REPLCONF
+    /// listening-port)
+    pub listening_port: u16,
+    /// Set once SYNC or PSYNC made the connection a replica
+    pub replica: Option<ReplicaLink>,
+    /// Set by ASKING for the next command, which may then use a slot being
+    /// imported
+    pub asking: bool,
+}
+
+impl Default for ConnectionContext {
+    fn default() -> Self {
+        Self {
+            id: None,
+            name: String::new(),
+            db: 0,
+            user: None,
+            protocol: RESP2,
+            transaction: Transaction::default(),
+            subscriptions: ClientSubscriptions::default(),
+            authenticated: false,
+            listening_port: 0,
+            replica: None,
+            asking: false,
+        }
+    }
+}
+
+impl ConnectionContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The context of client `id`'s connection, which runs as `default`
+    /// until it AUTHs as another ACL user
+    pub fn for_client(id: u64) -> Self {
+        Self {
+            id: Some(id),
+            user: Some("default".to_string()),
+            ..Self::default()
+        }
+    }
+}
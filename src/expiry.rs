@@ -0,0 +1,97 @@
+use crate::keymap::KeyMap;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+/// Keys with a TTL in one database
+#[derive(Default)]
+struct Schedule {
+    /// (Unix time in milliseconds, key), soonest first
+    by_time: BTreeSet<(u64, String)>,
+    /// Each key's entry in `by_time`, by position for random samples
+    by_key: KeyMap<u64>,
+}
+
+/// When keys expire, per database, so active expiration only visits keys
+/// that are due instead of scanning the keyspace.
+///
+/// Deletions don't update the index: an entry may outlive its key, or
+/// outlive the TTL after the key was overwritten, until it comes due. Callers
+/// check the key itself before deleting it. Each key has at most one entry
+#[derive(Clone)]
+pub struct ExpiryIndex {
+    databases: Arc<Vec<Mutex<Schedule>>>,
+}
+
+impl ExpiryIndex {
+    pub fn new(databases: usize) -> Self {
+        Self {
+            databases: Arc::new((0..databases).map(|_| Mutex::default()).collect()),
+        }
+    }
+
+    /// Note that `key` in database `db` expires at `at`, replacing the time
+    /// noted before
+    pub fn schedule(&self, db: usize, key: &str, at: u64) {
+        let mut schedule = self.databases[db].lock().unwrap();
+        if let Some(previous) = schedule.by_key.insert(key.to_string(), at) {
+            schedule.by_time.remove(&(previous, key.to_string()));
+        }
+        schedule.by_time.insert((at, key.to_string()));
+    }
+
+    /// Forget when `key` in database `db` expires (PERSIST)
+    pub fn cancel(&self, db: usize, key: &str) {
+        let mut schedule = self.databases[db].lock().unwrap();
+        if let Some(previous) = schedule.by_key.remove(key) {
+            schedule.by_time.remove(&(previous, key.to_string()));
+        }
+    }
+
+    /// Remove and return up to `limit` keys of database `db` due by `now`,
+    /// soonest first
+    pub fn take_due(&self, db: usize, now: u64, limit: usize) -> Vec<String> {
+        let mut schedule = self.databases[db].lock().unwrap();
+        let mut due = Vec::new();
+        while due.len() < limit {
+            match schedule.by_time.first() {
+                Some((at, _)) if *at <= now => {}
+                _ => break,
+            }
+            let (_, key) = schedule.by_time.pop_first().unwrap();
+            schedule.by_key.remove(&key);
+            due.push(key);
+        }
+        due
+    }
+
+    /// Up to `count` keys of database `db` with a TTL, picked at random
+    /// (volatile eviction policies), or all of them if there are no more
+    /// than `count`. A key may be picked more than once
+    pub fn sample(&self, db: usize, count: usize) -> Vec<String> {
+        let schedule = self.databases[db].lock().unwrap();
+        let len = schedule.by_key.len();
+        if count >= len {
+            return schedule.by_key.iter().map(|(key, _)| key.clone()).collect();
+        }
+        (0..count)
+            .filter_map(|_| schedule.by_key.get_index(fastrand::usize(..len)))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Exchange two databases' schedules (SWAPDB)
+    pub fn swap(&self, first: usize, second: usize) {
+        if first == second {
+            return;
+        }
+        let (low, high) = (first.min(second), first.max(second));
+        let mut low = self.databases[low].lock().unwrap();
+        let mut high = self.databases[high].lock().unwrap();
+        std::mem::swap(&mut *low, &mut *high);
+    }
+
+    /// Forget every key of database `db`
+    pub fn clear(&self, db: usize) {
+        *self.databases[db].lock().unwrap() = Schedule::default();
+    }
+}
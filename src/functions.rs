@@ -0,0 +1,268 @@
+use crate::protocol::{RespValue, parse_resp};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use wasmi::core::{TrapCode, ValType};
+use wasmi::{Caller, Config, Engine, Extern, ExternType, Linker, Memory, Module, Store};
+
+/// Fuel granted to each FCALL, roughly one unit per executed instruction
+const FUEL_LIMIT: u64 = 50_000_000;
+
+/// Wall-clock budget of each FCALL, checked whenever the function calls the host
+const TIME_LIMIT: Duration = Duration::from_secs(5);
+
+/// Libraries of WebAssembly functions (FUNCTION LOAD / FCALL)
+///
+/// A library is a wasm module exporting its linear memory as `memory` and
+/// one `() -> i32` function per callable function. Functions talk to the
+/// server through the `ferro` import module, exchanging RESP-encoded bytes
+/// through their memory:
+///
+/// - `input_len() -> i32` / `read_input(ptr)`: the call's input, encoded as
+///   an array of two arrays, `[[key ...], [arg ...]]`
+/// - `call(ptr, len) -> i32`: run the command encoded at `ptr` and return
+///   the length of its encoded reply, which `read_reply(ptr)` then copies
+/// - `reply(ptr, len)`: set the FCALL reply; without it the function's
+///   return value is replied as an integer
+///
+/// Every call runs in a fresh instance with a fuel and time budget, so a
+/// function can't keep state between calls or stall the server.
+#[derive(Clone)]
+pub struct FunctionRegistry {
+    engine: Engine,
+    libraries: Arc<RwLock<HashMap<String, Library>>>,
+}
+
+struct Library {
+    module: Arc<Module>,
+    functions: Vec<String>,
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Self {
+            engine: Engine::new(&config),
+            libraries: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+/// Per-call state visible to the host functions
+struct HostState<'a> {
+    input: Vec<u8>,
+    last_reply: Vec<u8>,
+    result: Option<RespValue>,
+    deadline: Instant,
+    call: &'a dyn Fn(Vec<RespValue>) -> RespValue,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compile and register a library. Fails if the library exists and
+    /// `replace` is not set, or if one of its functions is already provided
+    /// by another library
+    pub fn load(&self, name: &str, wasm: &[u8], replace: bool) -> Result<(), String> {
+        let module = Module::new(&self.engine, wasm)
+            .map_err(|e| format!("ERR Error compiling function: {}", e))?;
+        let mut functions: Vec<String> = module
+            .exports()
+            .filter(|export| match export.ty() {
+                ExternType::Func(ty) => ty.params().is_empty() && ty.results() == [ValType::I32],
+                _ => false,
+            })
+            .map(|export| export.name().to_string())
+            .collect();
+        functions.sort();
+        if functions.is_empty() {
+            return Err("ERR No functions registered".to_string());
+        }
+
+        let mut libraries = self.libraries.write().unwrap();
+        if libraries.contains_key(name) && !replace {
+            return Err(format!("ERR Library '{}' already exists", name));
+        }
+        for (other, library) in libraries.iter() {
+            if other == name {
+                continue;
+            }
+            if let Some(function) = functions.iter().find(|f| library.functions.contains(f)) {
+                return Err(format!("ERR Function {} already exists", function));
+            }
+        }
+        libraries.insert(
+            name.to_string(),
+            Library {
+                module: Arc::new(module),
+                functions,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn delete(&self, name: &str) -> bool {
+        self.libraries.write().unwrap().remove(name).is_some()
+    }
+
+    pub fn flush(&self) {
+        self.libraries.write().unwrap().clear();
+    }
+
+    /// Library names with their functions, both sorted by name
+    pub fn list(&self) -> Vec<(String, Vec<String>)> {
+        let libraries = self.libraries.read().unwrap();
+        let mut list: Vec<(String, Vec<String>)> = libraries
+            .iter()
+            .map(|(name, library)| (name.clone(), library.functions.clone()))
+            .collect();
+        list.sort();
+        list
+    }
+
+    /// Run `function` (FCALL), executing the commands it issues with `call`
+    pub fn call(
+        &self,
+        function: &str,
+        keys: &[String],
+        args: &[String],
+        call: &dyn Fn(Vec<RespValue>) -> RespValue,
+    ) -> RespValue {
+        let module = {
+            let libraries = self.libraries.read().unwrap();
+            let library = libraries
+                .values()
+                .find(|library| library.functions.iter().any(|f| f == function));
+            match library {
+                Some(library) => Arc::clone(&library.module),
+                None => return RespValue::Error("ERR Function not found".to_string()),
+            }
+        };
+
+        let bulk = |items: &[String]| {
+            RespValue::Array(items.iter().cloned().map(RespValue::bulk).collect())
+        };
+        let input = RespValue::Array(vec![bulk(keys), bulk(args)]).encode();
+        let state = HostState {
+            input: input.into_bytes(),
+            last_reply: Vec::new(),
+            result: None,
+            deadline: Instant::now() + TIME_LIMIT,
+            call,
+        };
+
+        match self.run(&module, function, state) {
+            Ok(reply) => reply,
+            Err(e) if e.as_trap_code() == Some(TrapCode::OutOfFuel) => {
+                RespValue::Error("ERR Function exceeded its instruction budget".to_string())
+            }
+            Err(e) => RespValue::Error(format!("ERR Error running function: {}", e)),
+        }
+    }
+
+    fn run(
+        &self,
+        module: &Module,
+        function: &str,
+        state: HostState<'_>,
+    ) -> Result<RespValue, wasmi::Error> {
+        let mut store = Store::new(&self.engine, state);
+        store.set_fuel(FUEL_LIMIT)?;
+
+        let mut linker = Linker::new(&self.engine);
+        linker.func_wrap("ferro", "input_len", |caller: Caller<'_, HostState>| {
+            caller.data().input.len() as i32
+        })?;
+        linker.func_wrap(
+            "ferro",
+            "read_input",
+            |mut caller: Caller<'_, HostState>, ptr: i32| {
+                let input = std::mem::take(&mut caller.data_mut().input);
+                let result = write_memory(&mut caller, ptr, &input);
+                caller.data_mut().input = input;
+                result
+            },
+        )?;
+        linker.func_wrap(
+            "ferro",
+            "call",
+            |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                if Instant::now() > caller.data().deadline {
+                    return Err(wasmi::Error::new("function exceeded its time limit"));
+                }
+                let command = read_command(&caller, ptr, len)?;
+                let RespValue::Array(command) = command else {
+                    return Err(wasmi::Error::new("commands must be arrays"));
+                };
+                if command.is_empty() {
+                    return Err(wasmi::Error::new("commands must not be empty"));
+                }
+                let reply = (caller.data().call)(command).encode().into_bytes();
+                let len = reply.len() as i32;
+                caller.data_mut().last_reply = reply;
+                Ok(len)
+            },
+        )?;
+        linker.func_wrap(
+            "ferro",
+            "read_reply",
+            |mut caller: Caller<'_, HostState>, ptr: i32| {
+                let reply = std::mem::take(&mut caller.data_mut().last_reply);
+                let result = write_memory(&mut caller, ptr, &reply);
+                caller.data_mut().last_reply = reply;
+                result
+            },
+        )?;
+        linker.func_wrap(
+            "ferro",
+            "reply",
+            |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                let reply = read_command(&caller, ptr, len)?;
+                caller.data_mut().result = Some(reply);
+                Ok(())
+            },
+        )?;
+
+        let instance = linker.instantiate(&mut store, module)?.start(&mut store)?;
+        let func = instance.get_typed_func::<(), i32>(&store, function)?;
+        let returned = func.call(&mut store, ())?;
+        Ok(store
+            .into_data()
+            .result
+            .unwrap_or(RespValue::Integer(returned as i64)))
+    }
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("module must export its memory as 'memory'"))
+}
+
+fn write_memory(
+    caller: &mut Caller<'_, HostState>,
+    ptr: i32,
+    data: &[u8],
+) -> Result<(), wasmi::Error> {
+    memory(caller)?
+        .write(caller, ptr as u32 as usize, data)
+        .map_err(|e| wasmi::Error::new(e.to_string()))
+}
+
+/// Read and parse the RESP value the guest encoded at `ptr..ptr + len`
+fn read_command(
+    caller: &Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+) -> Result<RespValue, wasmi::Error> {
+    let mut buffer = vec![0; len as u32 as usize];
+    memory(caller)?
+        .read(caller, ptr as u32 as usize, &mut buffer)
+        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    let text = String::from_utf8(buffer).map_err(|e| wasmi::Error::new(e.to_string()))?;
+    parse_resp(&text).map_err(wasmi::Error::new)
+}
@@ -0,0 +1,105 @@
+/// Redis-style glob matching used by SCAN MATCH (and anything else taking a pattern)
+///
+/// Supported syntax:
+/// - `*` matches any sequence of characters (including none)
+/// - `?` matches exactly one character
+/// - `[abc]`, `[a-z]`, `[^abc]` match a character class (optionally negated)
+/// - `\x` matches the character `x` literally
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+
+    while p < pattern.len() {
+        match pattern[p] {
+            '*' => {
+                // Collapse consecutive stars
+                while p < pattern.len() && pattern[p] == '*' {
+                    p += 1;
+                }
+                if p == pattern.len() {
+                    return true;
+                }
+                // Try every possible split point for the rest of the pattern
+                return (t..=text.len()).any(|start| match_from(&pattern[p..], &text[start..]));
+            }
+            '?' => {
+                if t >= text.len() {
+                    return false;
+                }
+                p += 1;
+                t += 1;
+            }
+            '[' => {
+                if t >= text.len() {
+                    return false;
+                }
+                let (matched, next) = match_class(pattern, p + 1, text[t]);
+                if !matched {
+                    return false;
+                }
+                p = next;
+                t += 1;
+            }
+            c => {
+                let literal = if c == '\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    pattern[p]
+                } else {
+                    c
+                };
+                if t >= text.len() || text[t] != literal {
+                    return false;
+                }
+                p += 1;
+                t += 1;
+            }
+        }
+    }
+
+    t == text.len()
+}
+
+/// Match `c` against the character class starting at `start` (just after `[`)
+/// Returns whether it matched and the pattern index just past the closing `]`
+fn match_class(pattern: &[char], start: usize, c: char) -> (bool, usize) {
+    let mut p = start;
+    let negate = p < pattern.len() && pattern[p] == '^';
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != ']' {
+        if pattern[p] == '\\' && p + 1 < pattern.len() {
+            p += 1;
+            if pattern[p] == c {
+                matched = true;
+            }
+        } else if p + 2 < pattern.len() && pattern[p + 1] == '-' && pattern[p + 2] != ']' {
+            let (lo, hi) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            if lo <= c && c <= hi {
+                matched = true;
+            }
+            p += 2;
+        } else if pattern[p] == c {
+            matched = true;
+        }
+        p += 1;
+    }
+
+    // Skip the closing bracket (an unterminated class just ends the pattern)
+    if p < pattern.len() {
+        p += 1;
+    }
+    (matched != negate, p)
+}
@@ -0,0 +1,204 @@
+use hashbrown::HashTable;
+use std::hash::{BuildHasher, RandomState};
+
+#[cfg(not(feature = "imbl"))]
+type Entries<V> = Vec<(String, V)>;
+/// Persistent, so a copy of the entries shares their structure
+#[cfg(feature = "imbl")]
+pub type Entries<V> = imbl::Vector<(String, V)>;
+
+/// A map from keys to values that also keeps its entries in a dense array,
+/// so any entry can be reached by its position: a random one in O(1)
+/// (RANDOMKEY, eviction samples), or the ones below a SCAN cursor.
+///
+/// Removing an entry moves the last one into its place, and new entries
+/// are added at the end, so an entry only ever moves to a lower position
+#[derive(Clone)]
+pub struct KeyMap<V> {
+    entries: Entries<V>,
+    /// Positions in `entries`, by the hash of their key
+    table: HashTable<usize>,
+    hasher: RandomState,
+}
+
+impl<V: Clone> Default for KeyMap<V> {
+    fn default() -> Self {
+        Self {
+            entries: Entries::new(),
+            table: HashTable::new(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V: std::fmt::Debug> std::fmt::Debug for KeyMap<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.entries.iter().map(|(key, value)| (key, value)))
+            .finish()
+    }
+}
+
+impl<V: Clone> FromIterator<(String, V)> for KeyMap<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(entries: I) -> Self {
+        let mut map = Self::new();
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl<V: Clone> KeyMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn position(&self, key: &str) -> Option<usize> {
+        let entries = &self.entries;
+        self.table
+            .find(self.hasher.hash_one(key), |&i| entries[i].0 == key)
+            .copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.position(key).is_some()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.position(key).map(|i| &self.entries[i].1)
+    }
+
+    pub fn get_key_value(&self, key: &str) -> Option<(&String, &V)> {
+        self.position(key).map(|i| {
+            let (key, value) = &self.entries[i];
+            (key, value)
+        })
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let i = self.position(key)?;
+        Some(&mut self.entries[i].1)
+    }
+
+    /// The entry at position `index`
+    pub fn get_index(&self, index: usize) -> Option<(&String, &V)> {
+        self.entries.get(index).map(|(key, value)| (key, value))
+    }
+
+    /// The value of `key`, inserting `default()` first if there is none
+    pub fn get_or_insert_with(&mut self, key: &str, default: impl FnOnce() -> V) -> &mut V {
+        let i = match self.position(key) {
+            Some(i) => i,
+            None => self.push(key.to_string(), default()),
+        };
+        &mut self.entries[i].1
+    }
+
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        match self.position(&key) {
+            Some(i) => Some(std::mem::replace(&mut self.entries[i].1, value)),
+            None => {
+                self.push(key, value);
+                None
+            }
+        }
+    }
+
+    /// Add an entry for a key that has none, returning its position
+    fn push(&mut self, key: String, value: V) -> usize {
+        let i = self.entries.len();
+        let (entries, hasher) = (&self.entries, &self.hasher);
+        self.table.insert_unique(hasher.hash_one(&key), i, |&j| {
+            hasher.hash_one(&entries[j].0)
+        });
+        #[cfg(not(feature = "imbl"))]
+        self.entries.push((key, value));
+        #[cfg(feature = "imbl")]
+        self.entries.push_back((key, value));
+        i
+    }
+
+    /// Remove `key`'s entry, moving the last entry into its position
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let entries = &self.entries;
+        let i = match self
+            .table
+            .find_entry(self.hasher.hash_one(key), |&i| entries[i].0 == key)
+        {
+            Ok(found) => found.remove().0,
+            Err(_) => return None,
+        };
+        let last = self.entries.len() - 1;
+        if i != last {
+            let hash = self.hasher.hash_one(&self.entries[last].0);
+            *self
+                .table
+                .find_mut(hash, |&j| j == last)
+                .expect("every entry is indexed") = i;
+        }
+        #[cfg(not(feature = "imbl"))]
+        let (_, value) = self.entries.swap_remove(i);
+        #[cfg(feature = "imbl")]
+        let (_, value) = {
+            let moved = self.entries.pop_back().expect("entry removed");
+            if i == last {
+                moved
+            } else {
+                std::mem::replace(&mut self.entries[i], moved)
+            }
+        };
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    /// The keys, in position order
+    pub fn keys(&self) -> Keys<'_, V> {
+        Keys { map: self, next: 0 }
+    }
+
+    /// Approximate heap bytes of the entry array and its index, leaving
+    /// out what the keys and values themselves own
+    pub fn overhead(&self) -> usize {
+        self.len() * size_of::<(String, V)>() + self.table.capacity() * (size_of::<usize>() + 1)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.table.clear();
+    }
+
+    /// A copy of the entries in their current order, made in O(1) by
+    /// sharing their structure
+    #[cfg(feature = "imbl")]
+    pub fn entries(&self) -> Entries<V> {
+        self.entries.clone()
+    }
+}
+
+/// The keys of a `KeyMap`, in position order
+pub struct Keys<'a, V> {
+    map: &'a KeyMap<V>,
+    next: usize,
+}
+
+impl<'a, V: Clone> Iterator for Keys<'a, V> {
+    type Item = &'a String;
+
+    fn next(&mut self) -> Option<&'a String> {
+        let (key, _) = self.map.get_index(self.next)?;
+        self.next += 1;
+        Some(key)
+    }
+}
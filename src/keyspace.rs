@@ -0,0 +1,925 @@
+#[cfg(feature = "imbl")]
+pub use imp::snapshot;
+pub use imp::{EntryRef, Keys, Keyspace, Ref, RefMut, Snapshot};
+#[cfg(not(feature = "imbl"))]
+use std::collections::HashMap;
+#[cfg(not(feature = "imbl"))]
+use std::marker::PhantomData;
+#[cfg(not(feature = "imbl"))]
+use std::sync::Mutex;
+#[cfg(not(feature = "imbl"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(all(feature = "dashmap", feature = "imbl"))]
+compile_error!("the dashmap and imbl features are mutually exclusive");
+
+/// The default keyspace: one `KeyMap` behind one lock, so a command on one
+/// key waits for a command on any other to finish writing. With `imbl` the
+/// map's entries are persistent instead, so a snapshot is an O(1) copy
+/// sharing their structure
+#[cfg(not(feature = "dashmap"))]
+mod imp {
+    #[cfg(not(feature = "imbl"))]
+    use super::Journal;
+    use crate::keymap::KeyMap;
+    use crate::storage::scan_positions;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+    type Map<V> = KeyMap<V>;
+
+    #[cfg(not(feature = "imbl"))]
+    pub use super::KeyList as Snapshot;
+
+    /// A value borrowed for reading. Like `RefMut` and `EntryRef`, a guard
+    /// like `dashmap`'s, rather than a plain reference, so code written
+    /// against one backend compiles against the other: its (empty) `Drop`
+    /// keeps the keyspace borrowed until it goes out of scope, as holding
+    /// a shard lock does
+    pub struct Ref<'a, V>(&'a V);
+
+    impl<V> Drop for Ref<'_, V> {
+        fn drop(&mut self) {}
+    }
+
+    impl<V> Deref for Ref<'_, V> {
+        type Target = V;
+
+        fn deref(&self) -> &V {
+            self.0
+        }
+    }
+
+    /// A value borrowed for writing
+    pub struct RefMut<'a, V>(&'a mut V);
+
+    impl<V> Drop for RefMut<'_, V> {
+        fn drop(&mut self) {}
+    }
+
+    impl<V> Deref for RefMut<'_, V> {
+        type Target = V;
+
+        fn deref(&self) -> &V {
+            self.0
+        }
+    }
+
+    impl<V> DerefMut for RefMut<'_, V> {
+        fn deref_mut(&mut self) -> &mut V {
+            self.0
+        }
+    }
+
+    /// The keys and values of one database. Commands on a single key lock
+    /// it with `read_key` / `write_key`; commands on several keys, or on
+    /// the whole keyspace, with `read` / `write`, which see and change them
+    /// all at once. Here both kinds take the same lock
+    pub struct Keyspace<V> {
+        map: RwLock<Map<V>>,
+        #[cfg(not(feature = "imbl"))]
+        journal: Journal<V>,
+    }
+
+    impl<V: Clone> Default for Keyspace<V> {
+        fn default() -> Self {
+            Self {
+                map: RwLock::new(Map::new()),
+                #[cfg(not(feature = "imbl"))]
+                journal: Journal::default(),
+            }
+        }
+    }
+
+    impl<V: Clone> Keyspace<V> {
+        pub fn read(&self) -> Keys<'_, V> {
+            self.keys(Lock::Read(self.map.read().unwrap()))
+        }
+
+        pub fn write(&self) -> Keys<'_, V> {
+            self.keys(Lock::Write(self.map.write().unwrap()))
+        }
+
+        fn keys<'a>(&'a self, lock: Lock<'a, V>) -> Keys<'a, V> {
+            Keys {
+                lock,
+                #[cfg(not(feature = "imbl"))]
+                journal: &self.journal,
+            }
+        }
+
+        pub fn read_key(&self, _key: &str) -> Keys<'_, V> {
+            self.read()
+        }
+
+        pub fn write_key(&self, _key: &str) -> Keys<'_, V> {
+            self.write()
+        }
+
+        #[cfg(not(feature = "imbl"))]
+        pub(super) fn journal(&self) -> &Journal<V> {
+            &self.journal
+        }
+    }
+
+    enum Lock<'a, V> {
+        Read(RwLockReadGuard<'a, Map<V>>),
+        Write(RwLockWriteGuard<'a, Map<V>>),
+    }
+
+    /// A locked keyspace. Changing it through a `read` guard panics
+    pub struct Keys<'a, V> {
+        lock: Lock<'a, V>,
+        #[cfg(not(feature = "imbl"))]
+        journal: &'a Journal<V>,
+    }
+
+    impl<V: Clone> Keys<'_, V> {
+        fn map(&self) -> &Map<V> {
+            match &self.lock {
+                Lock::Read(map) => map,
+                Lock::Write(map) => map,
+            }
+        }
+
+        fn map_mut(&mut self) -> &mut Map<V> {
+            match &mut self.lock {
+                Lock::Write(map) => map,
+                Lock::Read(_) => panic!("keyspace locked for reading"),
+            }
+        }
+
+        /// Note what `key` holds for a snapshot being written, before it
+        /// changes (see `Journal`)
+        #[cfg(not(feature = "imbl"))]
+        fn record(&self, key: &str) {
+            self.journal.record(key, || self.map().get(key).cloned());
+        }
+
+        /// Note every key, before the whole keyspace changes
+        #[cfg(not(feature = "imbl"))]
+        fn record_all(&self) {
+            if self.journal.active() {
+                for (key, value) in self.map().iter() {
+                    self.journal.record(key, || Some(value.clone()));
+                }
+            }
+        }
+
+        // A snapshot is a persistent copy, so there is nothing to note
+        #[cfg(feature = "imbl")]
+        fn record(&self, _key: &str) {}
+
+        #[cfg(feature = "imbl")]
+        fn record_all(&self) {}
+
+        pub fn get(&self, key: &str) -> Option<Ref<'_, V>> {
+            self.map().get(key).map(Ref)
+        }
+
+        pub fn get_mut(&mut self, key: &str) -> Option<RefMut<'_, V>> {
+            self.record(key);
+            self.map_mut().get_mut(key).map(RefMut)
+        }
+
+        /// The value of `key`, inserting `default()` first if there is none
+        pub fn get_or_insert_with(
+            &mut self,
+            key: &str,
+            default: impl FnOnce() -> V,
+        ) -> RefMut<'_, V> {
+            self.record(key);
+            RefMut(self.map_mut().get_or_insert_with(key, default))
+        }
+
+        pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+            self.record(&key);
+            self.map_mut().insert(key, value)
+        }
+
+        pub fn remove(&mut self, key: &str) -> Option<V> {
+            self.record(key);
+            self.map_mut().remove(key)
+        }
+
+        pub fn len(&self) -> usize {
+            self.map().len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.map().is_empty()
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = EntryRef<'_, V>> {
+            self.map()
+                .iter()
+                .map(|(key, value)| EntryRef { key, value })
+        }
+
+        /// The entry at `index` of the keyspace's positions, `0..len()`.
+        /// See `KeyMap` for how entries move between positions
+        pub fn get_index(&self, index: usize) -> Option<EntryRef<'_, V>> {
+            self.map()
+                .get_index(index)
+                .map(|(key, value)| EntryRef { key, value })
+        }
+
+        /// The entries SCAN returns for `cursor`, and the cursor to
+        /// continue from: those below it by position, see `KeyMap`
+        pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<EntryRef<'_, V>>) {
+            let (next_cursor, positions) = scan_positions(self.len(), cursor, count);
+            let entries = positions
+                .filter_map(|index| self.get_index(index))
+                .collect();
+            (next_cursor, entries)
+        }
+
+        pub fn clear(&mut self) {
+            self.record_all();
+            self.map_mut().clear();
+        }
+
+        /// Exchange the contents of two keyspaces (SWAPDB)
+        pub fn swap(&mut self, other: &mut Keys<'_, V>) {
+            self.record_all();
+            other.record_all();
+            std::mem::swap(self.map_mut(), other.map_mut());
+        }
+    }
+
+    /// A key and its value, as visited by `Keys::iter`
+    pub struct EntryRef<'a, V> {
+        key: &'a String,
+        value: &'a V,
+    }
+
+    impl<V> EntryRef<'_, V> {
+        pub fn key(&self) -> &String {
+            self.key
+        }
+
+        pub fn value(&self) -> &V {
+            self.value
+        }
+    }
+
+    impl<V> Drop for EntryRef<'_, V> {
+        fn drop(&mut self) {}
+    }
+
+    impl<V> Deref for EntryRef<'_, V> {
+        type Target = V;
+
+        fn deref(&self) -> &V {
+            self.value
+        }
+    }
+
+    /// A copy of a keyspace as it was when taken. Values changed since
+    /// are copied on write, the first time each is changed
+    #[cfg(feature = "imbl")]
+    pub struct Snapshot<V> {
+        entries: crate::keymap::Entries<V>,
+    }
+
+    /// Take a snapshot of each of `keyspaces` at the same moment, locking
+    /// them all at once in index order, as commands on several databases do
+    #[cfg(feature = "imbl")]
+    pub fn snapshot<V: Clone>(keyspaces: &[Keyspace<V>]) -> Vec<Snapshot<V>> {
+        let locked: Vec<_> = keyspaces.iter().map(Keyspace::read).collect();
+        locked
+            .iter()
+            .map(|keys| Snapshot {
+                entries: keys.map().entries(),
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "imbl")]
+    impl<V: Clone> Snapshot<V> {
+        pub fn len(&self) -> usize {
+            self.entries.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.entries.is_empty()
+        }
+
+        /// Run `f` on the `index`th key and the value it had
+        pub fn with<T>(
+            &self,
+            _keyspace: &Keyspace<V>,
+            index: usize,
+            f: impl FnOnce(&String, &V) -> T,
+        ) -> Option<T> {
+            self.entries.get(index).map(|(key, value)| f(key, value))
+        }
+
+        /// Done with the snapshot. The copy shares nothing that needs
+        /// letting go of but itself
+        pub fn finish(&self, _keyspace: &Keyspace<V>) {}
+    }
+}
+
+/// The `dashmap` keyspace: a `DashMap`, locked per shard, so commands on
+/// different keys run in parallel. A single-key command holds its key's
+/// entry, and so its shard's lock, from the first lookup to the last;
+/// `read` shares the keyspace with them, while `write` waits for every one
+/// to finish. The keys of each shard are also listed in a `KeyMap`, for
+/// access by position, only changed under that shard's lock
+#[cfg(feature = "dashmap")]
+mod imp {
+    use super::Journal;
+    use crate::keymap::KeyMap;
+    use dashmap::DashMap;
+    use dashmap::mapref::entry::Entry;
+    use dashmap::mapref::one;
+    use std::hash::RandomState;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::{Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+    type Map<V> = DashMap<String, V>;
+
+    pub use super::KeyList as Snapshot;
+
+    pub type EntryRef<'a, V> = dashmap::mapref::multiple::RefMulti<'a, String, V>;
+
+    /// Bits of a SCAN cursor holding the position in a shard; the shard's
+    /// number is above them
+    const SHARD_BITS: u32 = 32;
+
+    /// A value borrowed for reading: through its shard's read lock, or
+    /// through the entry a `write_key` lock holds
+    pub struct Ref<'a, V>(Borrowed<'a, V>);
+
+    enum Borrowed<'a, V> {
+        Shard(one::Ref<'a, String, V>),
+        Entry(&'a String, &'a V),
+    }
+
+    impl<V> Ref<'_, V> {
+        pub fn key(&self) -> &String {
+            match &self.0 {
+                Borrowed::Shard(guard) => guard.key(),
+                Borrowed::Entry(key, _) => key,
+            }
+        }
+
+        pub fn value(&self) -> &V {
+            match &self.0 {
+                Borrowed::Shard(guard) => guard.value(),
+                Borrowed::Entry(_, value) => value,
+            }
+        }
+    }
+
+    impl<V> Deref for Ref<'_, V> {
+        type Target = V;
+
+        fn deref(&self) -> &V {
+            self.value()
+        }
+    }
+
+    /// A value borrowed for writing
+    pub struct RefMut<'a, V>(BorrowedMut<'a, V>);
+
+    enum BorrowedMut<'a, V> {
+        Shard(one::RefMut<'a, String, V>),
+        Entry(&'a mut V),
+    }
+
+    impl<V> Deref for RefMut<'_, V> {
+        type Target = V;
+
+        fn deref(&self) -> &V {
+            match &self.0 {
+                BorrowedMut::Shard(guard) => guard.value(),
+                BorrowedMut::Entry(value) => value,
+            }
+        }
+    }
+
+    impl<V> DerefMut for RefMut<'_, V> {
+        fn deref_mut(&mut self) -> &mut V {
+            match &mut self.0 {
+                BorrowedMut::Shard(guard) => guard.value_mut(),
+                BorrowedMut::Entry(value) => value,
+            }
+        }
+    }
+
+    /// The hasher every keyspace's map shares, so each key lands in the
+    /// same shard of any of them and SWAPDB can exchange them shard by
+    /// shard
+    fn hasher() -> RandomState {
+        static HASHER: OnceLock<RandomState> = OnceLock::new();
+        HASHER.get_or_init(RandomState::new).clone()
+    }
+
+    /// The keys and values of one database. Commands on a single key lock
+    /// it with `read_key` / `write_key`; commands on several keys, or on
+    /// the whole keyspace, with `read`, which sees each key as it is when
+    /// looked up, or `write`, which sees and changes them all at once and
+    /// so waits for every single-key command to finish
+    pub struct Keyspace<V> {
+        /// Shared by `read` and single-key commands, exclusive for `write`
+        lock: RwLock<()>,
+        map: Map<V>,
+        /// The keys in each shard of `map`, by position
+        index: Box<[Mutex<KeyMap<()>>]>,
+        journal: Journal<V>,
+    }
+
+    impl<V> Default for Keyspace<V> {
+        fn default() -> Self {
+            let map = DashMap::with_hasher(hasher());
+            Self {
+                lock: RwLock::new(()),
+                index: map.shards().iter().map(|_| Mutex::default()).collect(),
+                map,
+                journal: Journal::default(),
+            }
+        }
+    }
+
+    impl<V> Keyspace<V> {
+        pub fn read(&self) -> Keys<'_, V> {
+            self.keys(Lock::Read {
+                _keyspace: self.lock.read().unwrap(),
+            })
+        }
+
+        pub fn write(&self) -> Keys<'_, V> {
+            self.keys(Lock::All {
+                _keyspace: self.lock.write().unwrap(),
+            })
+        }
+
+        /// Like `read`: each lookup takes its shard's read lock, for as
+        /// long as the value is borrowed
+        pub fn read_key(&self, _key: &str) -> Keys<'_, V> {
+            self.read()
+        }
+
+        /// Lock `key`'s entry, and with it its shard, until the guard is
+        /// dropped. Only `key` may be looked up or changed through it
+        pub fn write_key(&self, key: &str) -> Keys<'_, V> {
+            let keyspace = self.lock.read().unwrap();
+            self.keys(Lock::Key {
+                _keyspace: keyspace,
+                entry: Some(self.map.entry(key.to_string())),
+            })
+        }
+
+        fn keys<'a>(&'a self, lock: Lock<'a, V>) -> Keys<'a, V> {
+            Keys {
+                lock,
+                keyspace: self,
+            }
+        }
+
+        /// The position index of the shard `key` is in
+        fn index_of(&self, key: &str) -> &Mutex<KeyMap<()>> {
+            &self.index[self.map.determine_map(key)]
+        }
+
+        pub(super) fn journal(&self) -> &Journal<V> {
+            &self.journal
+        }
+    }
+
+    enum Lock<'a, V> {
+        Read {
+            _keyspace: RwLockReadGuard<'a, ()>,
+        },
+        Key {
+            _keyspace: RwLockReadGuard<'a, ()>,
+            /// None only while it is being replaced
+            entry: Option<Entry<'a, String, V>>,
+        },
+        All {
+            _keyspace: RwLockWriteGuard<'a, ()>,
+        },
+    }
+
+    /// A locked keyspace. Changing it through a `read` guard panics. A
+    /// shard's index is only locked while holding the shard, or while
+    /// holding nothing else
+    pub struct Keys<'a, V> {
+        lock: Lock<'a, V>,
+        keyspace: &'a Keyspace<V>,
+    }
+
+    impl<'a, V: Clone> Keys<'a, V> {
+        fn map(&self) -> &Map<V> {
+            &self.keyspace.map
+        }
+
+        /// The entry a `write_key` guard holds, which must be `key`'s
+        fn entry(&self, key: &str) -> Option<&Entry<'a, String, V>> {
+            match &self.lock {
+                Lock::Key { entry, .. } => {
+                    let entry = entry.as_ref().unwrap();
+                    assert_eq!(entry.key(), key, "keyspace locked for another key");
+                    Some(entry)
+                }
+                _ => None,
+            }
+        }
+
+        fn entry_mut(&mut self, key: &str) -> Option<&mut Entry<'a, String, V>> {
+            match &mut self.lock {
+                Lock::Key { entry, .. } => {
+                    let entry = entry.as_mut().unwrap();
+                    assert_eq!(entry.key(), key, "keyspace locked for another key");
+                    Some(entry)
+                }
+                Lock::Read { .. } => panic!("keyspace locked for reading"),
+                Lock::All { .. } => None,
+            }
+        }
+
+        /// Note what `key` holds for a snapshot being written, before it
+        /// changes (see `Journal`). Under `write_key` the value is read
+        /// from the held entry, as its shard is already locked
+        fn record(&self, key: &str) {
+            self.journal().record(key, || match self.entry(key) {
+                Some(Entry::Occupied(entry)) => Some(entry.get().clone()),
+                Some(Entry::Vacant(_)) => None,
+                None => self.map().get(key).map(|value| value.clone()),
+            });
+        }
+
+        /// Note every key, before the whole keyspace changes. Only with
+        /// the whole map locked, when no one else can be recording
+        fn record_all(&self) {
+            if self.journal().active() {
+                for entry in self.map().iter() {
+                    self.journal()
+                        .record(entry.key(), || Some(entry.value().clone()));
+                }
+            }
+        }
+
+        fn journal(&self) -> &Journal<V> {
+            &self.keyspace.journal
+        }
+
+        fn locked_whole(&self) {
+            match &self.lock {
+                Lock::All { .. } => {}
+                Lock::Read { .. } => panic!("keyspace locked for reading"),
+                Lock::Key { .. } => panic!("keyspace locked for a single key"),
+            }
+        }
+
+        pub fn get(&self, key: &str) -> Option<Ref<'_, V>> {
+            match self.entry(key) {
+                Some(Entry::Occupied(entry)) => {
+                    Some(Ref(Borrowed::Entry(entry.key(), entry.get())))
+                }
+                Some(Entry::Vacant(_)) => None,
+                None => self.map().get(key).map(|guard| Ref(Borrowed::Shard(guard))),
+            }
+        }
+
+        pub fn get_mut(&mut self, key: &str) -> Option<RefMut<'_, V>> {
+            self.record(key);
+            if self.entry_mut(key).is_none() {
+                return self
+                    .map()
+                    .get_mut(key)
+                    .map(|guard| RefMut(BorrowedMut::Shard(guard)));
+            }
+            match self.entry_mut(key) {
+                Some(Entry::Occupied(entry)) => Some(RefMut(BorrowedMut::Entry(entry.get_mut()))),
+                _ => None,
+            }
+        }
+
+        /// The value of `key`, inserting `default()` first if there is none
+        pub fn get_or_insert_with(
+            &mut self,
+            key: &str,
+            default: impl FnOnce() -> V,
+        ) -> RefMut<'_, V> {
+            self.record(key);
+            let keyspace = self.keyspace;
+            if !matches!(self.lock, Lock::Key { .. }) {
+                self.locked_whole();
+                let guard = match keyspace.map.entry(key.to_string()) {
+                    Entry::Occupied(entry) => entry.into_ref(),
+                    Entry::Vacant(entry) => {
+                        keyspace
+                            .index_of(key)
+                            .lock()
+                            .unwrap()
+                            .insert(key.to_string(), ());
+                        entry.insert(default())
+                    }
+                };
+                return RefMut(BorrowedMut::Shard(guard));
+            }
+            let Lock::Key { entry, .. } = &mut self.lock else {
+                unreachable!()
+            };
+            let held = entry.take().unwrap();
+            assert_eq!(held.key(), key, "keyspace locked for another key");
+            let occupied = match held {
+                Entry::Occupied(entry) => entry,
+                Entry::Vacant(entry) => {
+                    keyspace
+                        .index_of(key)
+                        .lock()
+                        .unwrap()
+                        .insert(key.to_string(), ());
+                    entry.insert_entry(default())
+                }
+            };
+            let Entry::Occupied(entry) = entry.insert(Entry::Occupied(occupied)) else {
+                unreachable!()
+            };
+            RefMut(BorrowedMut::Entry(entry.get_mut()))
+        }
+
+        pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+            self.record(&key);
+            let keyspace = self.keyspace;
+            let Lock::Key { entry, .. } = &mut self.lock else {
+                self.locked_whole();
+                let old = self.map().insert(key.clone(), value);
+                if old.is_none() {
+                    keyspace.index_of(&key).lock().unwrap().insert(key, ());
+                }
+                return old;
+            };
+            let held = entry.take().unwrap();
+            assert_eq!(*held.key(), key, "keyspace locked for another key");
+            let (occupied, old) = match held {
+                Entry::Occupied(mut entry) => {
+                    let old = entry.insert(value);
+                    (entry, Some(old))
+                }
+                Entry::Vacant(entry) => {
+                    keyspace.index_of(&key).lock().unwrap().insert(key, ());
+                    (entry.insert_entry(value), None)
+                }
+            };
+            *entry = Some(Entry::Occupied(occupied));
+            old
+        }
+
+        /// Removing the held entry of a `write_key` guard lets go of its
+        /// shard for a moment, before locking the key again
+        pub fn remove(&mut self, key: &str) -> Option<V> {
+            self.record(key);
+            let keyspace = self.keyspace;
+            let Lock::Key { entry, .. } = &mut self.lock else {
+                self.locked_whole();
+                let (_, value) = self.map().remove(key)?;
+                keyspace.index_of(key).lock().unwrap().remove(key);
+                return Some(value);
+            };
+            let Some(Entry::Occupied(held)) = entry.take_if(|entry| {
+                assert_eq!(entry.key(), key, "keyspace locked for another key");
+                matches!(entry, Entry::Occupied(_))
+            }) else {
+                return None;
+            };
+            // Out of the index while the shard is still locked
+            keyspace.index_of(key).lock().unwrap().remove(key);
+            let value = held.remove();
+            *entry = Some(keyspace.map.entry(key.to_string()));
+            Some(value)
+        }
+
+        /// Counted from the shards' indexes, so a `write_key` guard, which
+        /// holds a shard, may ask too
+        pub fn len(&self) -> usize {
+            let index = &self.keyspace.index;
+            index.iter().map(|keys| keys.lock().unwrap().len()).sum()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = EntryRef<'_, V>> {
+            self.map().iter()
+        }
+
+        /// The entry at `index` of the keyspace's positions, `0..len()`:
+        /// those of each shard in turn, so an entry may move to any other
+        /// as keys come and go (see `scan`). None if the key there is
+        /// being removed by another command
+        pub fn get_index(&self, mut index: usize) -> Option<Ref<'_, V>> {
+            let mut key = None;
+            for keys in &self.keyspace.index {
+                let keys = keys.lock().unwrap();
+                if index < keys.len() {
+                    key = keys.get_index(index).map(|(key, _)| key.clone());
+                    break;
+                }
+                index -= keys.len();
+            }
+            self.get(&key?)
+        }
+
+        /// The entries SCAN returns for `cursor`, and the cursor to
+        /// continue from. Shards are visited in turn, each walked by
+        /// position like `KeyMap` ones are, so that only entries present
+        /// throughout can be missed: the cursor holds the shard and the
+        /// position in it, which keys coming and going in other shards
+        /// don't move
+        pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Ref<'_, V>>) {
+            let shards = self.keyspace.index.len();
+            let (mut shard, mut end) = match cursor {
+                0 => (shards - 1, usize::MAX),
+                cursor => (
+                    ((cursor >> SHARD_BITS) as usize).min(shards - 1),
+                    cursor as u32 as usize,
+                ),
+            };
+            let mut remaining = count.max(1);
+            let mut keys = Vec::new();
+            let next_cursor = loop {
+                let index = self.keyspace.index[shard].lock().unwrap();
+                let top = end.min(index.len());
+                let start = top.saturating_sub(remaining);
+                keys.extend(
+                    (start..top)
+                        .rev()
+                        .filter_map(|position| index.get_index(position))
+                        .map(|(key, _)| key.clone()),
+                );
+                remaining -= top - start;
+                if start > 0 {
+                    break ((shard as u64) << SHARD_BITS) | start as u64;
+                }
+                if shard == 0 {
+                    break 0;
+                }
+                shard -= 1;
+                end = usize::MAX;
+                if remaining == 0 {
+                    break ((shard as u64) << SHARD_BITS) | u32::MAX as u64;
+                }
+            };
+            let entries = keys.iter().filter_map(|key| self.get(key)).collect();
+            (next_cursor, entries)
+        }
+
+        pub fn clear(&mut self) {
+            self.locked_whole();
+            self.record_all();
+            self.map().clear();
+            for keys in &self.keyspace.index {
+                keys.lock().unwrap().clear();
+            }
+        }
+
+        /// Exchange the contents of two keyspaces (SWAPDB), shard by
+        /// shard. Both must be locked whole
+        pub fn swap(&mut self, other: &mut Keys<'_, V>) {
+            self.locked_whole();
+            other.locked_whole();
+            self.record_all();
+            other.record_all();
+            let (ours, theirs) = (self.map().shards(), other.map().shards());
+            for (a, b) in ours.iter().zip(theirs) {
+                std::mem::swap(&mut *a.write(), &mut *b.write());
+            }
+            let (ours, theirs) = (&self.keyspace.index, &other.keyspace.index);
+            for (a, b) in ours.iter().zip(theirs) {
+                std::mem::swap(&mut *a.lock().unwrap(), &mut *b.lock().unwrap());
+            }
+        }
+    }
+}
+
+/// What keys held when a snapshot was taken, so it can be written out as
+/// of that moment while commands go on changing the keyspace. A key's
+/// value, or that it had none, is recorded the first time it is written
+/// or removed after the snapshot, until the snapshot is finished: only
+/// the values changed meanwhile are copied, much like the pages Redis'
+/// fork copies on write
+#[cfg(not(feature = "imbl"))]
+struct Journal<V> {
+    /// Whether a snapshot is being written. Only changed with the whole
+    /// keyspace locked, so a command holding any of its locks sees it
+    active: AtomicBool,
+    old: Mutex<HashMap<String, Option<V>>>,
+}
+
+#[cfg(not(feature = "imbl"))]
+impl<V> Default for Journal<V> {
+    fn default() -> Self {
+        Self {
+            active: AtomicBool::new(false),
+            old: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[cfg(not(feature = "imbl"))]
+impl<V> Journal<V> {
+    fn active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn start(&self) {
+        *self.old.lock().unwrap() = HashMap::new();
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    fn finish(&self) {
+        self.active.store(false, Ordering::Relaxed);
+        *self.old.lock().unwrap() = HashMap::new();
+    }
+
+    /// Record `value()` as what `key` held, unless it already is
+    fn record(&self, key: &str, value: impl FnOnce() -> Option<V>) {
+        if !self.active() {
+            return;
+        }
+        let mut old = self.old.lock().unwrap();
+        if !old.contains_key(key) {
+            old.insert(key.to_string(), value());
+        }
+    }
+
+    /// What `key` held when the snapshot was taken, if it changed since:
+    /// `Some(None)` if it had no value. Taken out, as a snapshot visits
+    /// each key once
+    fn take(&self, key: &str) -> Option<Option<V>> {
+        self.old.lock().unwrap().get_mut(key).map(Option::take)
+    }
+}
+
+/// A snapshot taken by listing the keys, whose values are read only as
+/// they are visited, each under its key's lock. Values changed since the
+/// snapshot are read from the keyspace's `Journal` instead, so it is still
+/// of a single moment while only the key names are copied up front
+#[cfg(not(feature = "imbl"))]
+pub struct KeyList<V> {
+    keys: Vec<String>,
+    values: PhantomData<fn() -> V>,
+}
+
+/// Take a snapshot of each of `keyspaces` at the same moment, locking them
+/// all at once in index order, as commands on several databases do, and
+/// for writing, as under `dashmap` reading leaves single-key commands
+/// running. Only one may be in progress at a time; each ends with
+/// `KeyList::finish`
+#[cfg(not(feature = "imbl"))]
+pub fn snapshot<V: Clone>(keyspaces: &[Keyspace<V>]) -> Vec<KeyList<V>> {
+    let locked: Vec<_> = keyspaces.iter().map(Keyspace::write).collect();
+    keyspaces
+        .iter()
+        .zip(&locked)
+        .map(|(keyspace, keys)| {
+            keyspace.journal().start();
+            KeyList {
+                keys: keys.iter().map(|entry| entry.key().clone()).collect(),
+                values: PhantomData,
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "imbl"))]
+impl<V: Clone> KeyList<V> {
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Run `f` on the `index`th key and the value it had, if it had one.
+    /// `keyspace` is the one the snapshot was taken of
+    pub fn with<T>(
+        &self,
+        keyspace: &Keyspace<V>,
+        index: usize,
+        f: impl FnOnce(&String, &V) -> T,
+    ) -> Option<T> {
+        let key = self.keys.get(index)?;
+        // Borrowing the current value keeps the key from being changed,
+        // and so recorded, until the journal has been looked at
+        let keys = keyspace.read_key(key);
+        let current = keys.get(key);
+        match keyspace.journal().take(key) {
+            Some(old) => old.map(|value| f(key, &value)),
+            None => current.map(|value| f(key, &value)),
+        }
+    }
+
+    /// Done with the snapshot: stop recording changes for it
+    pub fn finish(&self, keyspace: &Keyspace<V>) {
+        let _keys = keyspace.write();
+        keyspace.journal().finish();
+    }
+}
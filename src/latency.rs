@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Running a command, excluding time spent blocked or paused
+pub const COMMAND: &str = "command";
+/// Writing and syncing the AOF to disk
+pub const AOF_FSYNC: &str = "aof-fsync";
+/// Writing an RDB snapshot
+pub const RDB_SAVE: &str = "rdb-save";
+/// One pass of the active expiration loop
+pub const EXPIRE_CYCLE: &str = "expire-cycle";
+
+/// Samples kept per event
+const HISTORY_LEN: usize = 160;
+
+/// A latency spike: when it happened (unix seconds) and how long it took (ms)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencySample {
+    pub time: u64,
+    pub latency: u64,
+}
+
+#[derive(Default)]
+struct EventHistory {
+    samples: VecDeque<LatencySample>,
+    /// Highest latency since the last reset, including evicted samples
+    max: u64,
+}
+
+/// Latency spikes per event (LATENCY LATEST / HISTORY / RESET). Only
+/// durations at or above `latency-monitor-threshold` are recorded
+#[derive(Clone, Default)]
+pub struct LatencyMonitor {
+    events: Arc<Mutex<BTreeMap<String, EventHistory>>>,
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `event` took `elapsed`, if that reaches `threshold_ms`;
+    /// a threshold of 0 disables monitoring. Spikes within the same second
+    /// share a sample holding the highest
+    pub fn record(&self, event: &str, elapsed: Duration, threshold_ms: u64) {
+        let latency = elapsed.as_millis() as u64;
+        if threshold_ms == 0 || latency < threshold_ms {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut events = self.events.lock().unwrap();
+        let history = events.entry(event.to_string()).or_default();
+        history.max = history.max.max(latency);
+        match history.samples.back_mut() {
+            Some(last) if last.time == time => last.latency = last.latency.max(latency),
+            _ => {
+                if history.samples.len() == HISTORY_LEN {
+                    history.samples.pop_front();
+                }
+                history.samples.push_back(LatencySample { time, latency });
+            }
+        }
+    }
+
+    /// Each event's most recent sample and its all-time maximum
+    pub fn latest(&self) -> Vec<(String, LatencySample, u64)> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(event, history)| {
+                let last = history.samples.back()?;
+                Some((event.clone(), *last, history.max))
+            })
+            .collect()
+    }
+
+    /// `event`'s samples, oldest first
+    pub fn history(&self, event: &str) -> Vec<LatencySample> {
+        self.events
+            .lock()
+            .unwrap()
+            .get(event)
+            .map(|history| history.samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget the given events, or every event if none are given. Returns
+    /// how many had samples
+    pub fn reset(&self, events: &[String]) -> usize {
+        let mut recorded = self.events.lock().unwrap();
+        if events.is_empty() {
+            let count = recorded.len();
+            recorded.clear();
+            return count;
+        }
+        events
+            .iter()
+            .filter(|event| recorded.remove(event.as_str()).is_some())
+            .count()
+    }
+}
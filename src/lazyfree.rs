@@ -0,0 +1,65 @@
+use crate::storage::DataType;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+/// Values with more elements than this are freed on the background thread
+/// (same threshold Redis uses for lazyfree)
+pub const LAZYFREE_THRESHOLD: usize = 64;
+
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicU64 = AtomicU64::new(0);
+
+/// Rough cost of dropping a value: the number of heap allocations to release
+pub fn free_effort(data: &DataType) -> usize {
+    match data {
+        DataType::String(_) => 1,
+        DataType::List(list) => list.len(),
+        DataType::Set(set) => set.len(),
+        DataType::SortedSet(zset) => zset.len(),
+    }
+}
+
+/// Drop a value that has already been unlinked from the keyspace.
+/// Big collections are handed to a background thread so their destructors
+/// never run while the caller holds the store lock; small values are dropped inline.
+pub fn free_value(data: DataType) {
+    if free_effort(&data) <= LAZYFREE_THRESHOLD {
+        return;
+    }
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    if let Err(mpsc::SendError(data)) = sender().send(data) {
+        // Background thread is gone: fall back to freeing inline
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+        drop(data);
+    }
+}
+
+/// Number of values queued for background freeing that haven't been dropped yet
+pub fn pending_objects() -> usize {
+    PENDING.load(Ordering::Relaxed)
+}
+
+/// Number of values freed on the background thread since startup
+pub fn freed_objects() -> u64 {
+    FREED.load(Ordering::Relaxed)
+}
+
+fn sender() -> &'static Sender<DataType> {
+    static SENDER: OnceLock<Sender<DataType>> = OnceLock::new();
+    SENDER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<DataType>();
+        thread::Builder::new()
+            .name("ferrodb-lazyfree".to_string())
+            .spawn(move || {
+                for data in rx {
+                    drop(data);
+                    PENDING.fetch_sub(1, Ordering::Relaxed);
+                    FREED.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .expect("failed to spawn lazyfree thread");
+        tx
+    })
+}
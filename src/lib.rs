@@ -1,6 +1,33 @@
+#![allow(non_snake_case)]
+
+pub mod acl;
 pub mod aof;
+pub mod blocking;
+pub mod clients;
+pub mod cluster;
+pub mod command_table;
 pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod expiry;
+pub mod functions;
+pub mod glob;
+pub mod keymap;
+pub mod keyspace;
+pub mod latency;
+pub mod lazyfree;
+pub mod listpack;
+pub mod memory;
+pub mod modules;
 pub mod persistance;
 pub mod protocol;
 pub mod pubsub;
+pub mod replication;
+pub mod scripting;
+pub mod skiplist;
+pub mod stats;
 pub mod storage;
+pub mod tls;
+pub mod transaction;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
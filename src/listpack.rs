@@ -0,0 +1,181 @@
+/// Compact encoding for small collections, after Redis' listpack
+///
+/// Entries are stored back to back in one buffer, each as its length (a
+/// LEB128 varint, one byte for entries under 128 bytes) followed by its
+/// bytes. A five-element list of short strings thus takes a single
+/// allocation of a few dozen bytes instead of a `VecDeque` plus a `String`
+/// per element. Everything but appending is O(n) in the buffer size, so
+/// callers convert to a full data structure once a listpack grows past a
+/// configured size.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+/// Append `len` as a LEB128 varint
+fn write_len(buf: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        buf.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+}
+
+/// Read the varint at the start of `buf`, returning it and its size in bytes
+fn read_len(buf: &[u8]) -> (usize, usize) {
+    let mut len = 0;
+    for (i, &byte) in buf.iter().enumerate() {
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte < 0x80 {
+            return (len, i + 1);
+        }
+    }
+    unreachable!("truncated listpack entry")
+}
+
+/// Bytes `entry` takes in a listpack
+pub fn encoded_size(entry: &str) -> usize {
+    let mut header = 1;
+    while entry.len() >> (7 * header) != 0 {
+        header += 1;
+    }
+    header + entry.len()
+}
+
+/// The encoded form of `entry`: its length, then its bytes
+fn encode(entry: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(entry.len() + 2);
+    write_len(&mut buf, entry.len());
+    buf.extend_from_slice(entry.as_bytes());
+    buf
+}
+
+impl Listpack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Encoded size in bytes, which `list-max-listpack-size` limits
+    pub fn bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Bytes allocated for the buffer
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            buf: &self.buf,
+            remaining: self.len,
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.iter().nth(index)
+    }
+
+    /// Byte offset of entry `index`; the end of the buffer for `len()`
+    fn offset(&self, index: usize) -> usize {
+        let mut offset = 0;
+        for _ in 0..index {
+            let (len, header) = read_len(&self.buf[offset..]);
+            offset += header + len;
+        }
+        offset
+    }
+
+    pub fn push_back(&mut self, entry: &str) {
+        write_len(&mut self.buf, entry.len());
+        self.buf.extend_from_slice(entry.as_bytes());
+        self.len += 1;
+    }
+
+    /// Insert `entry` before entry `index` (or append, for `len()`)
+    pub fn insert(&mut self, index: usize, entry: &str) {
+        assert!(index <= self.len, "listpack index out of bounds");
+        let offset = self.offset(index);
+        self.buf.splice(offset..offset, encode(entry));
+        self.len += 1;
+    }
+
+    /// Replace entry `index` with `entry`
+    pub fn replace(&mut self, index: usize, entry: &str) {
+        assert!(index < self.len, "listpack index out of bounds");
+        let start = self.offset(index);
+        let (len, header) = read_len(&self.buf[start..]);
+        self.buf.splice(start..start + header + len, encode(entry));
+    }
+
+    /// Remove entries `start..end`
+    pub fn remove_range(&mut self, start: usize, end: usize) {
+        assert!(
+            start <= end && end <= self.len,
+            "listpack range out of bounds"
+        );
+        let from = self.offset(start);
+        let mut to = from;
+        for _ in start..end {
+            let (len, header) = read_len(&self.buf[to..]);
+            to += header + len;
+        }
+        self.buf.drain(from..to);
+        self.len -= end - start;
+    }
+
+    /// Remove and return entry `index`
+    pub fn remove(&mut self, index: usize) -> String {
+        assert!(index < self.len, "listpack index out of bounds");
+        let entry = self.iter().nth(index).unwrap().to_string();
+        self.remove_range(index, index + 1);
+        entry
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Listpack {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(entries: I) -> Self {
+        let mut listpack = Listpack::new();
+        for entry in entries {
+            listpack.push_back(entry);
+        }
+        listpack
+    }
+}
+
+/// Entries of a listpack, front to back
+pub struct Iter<'a> {
+    buf: &'a [u8],
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let (len, header) = read_len(self.buf);
+        let (entry, rest) = self.buf[header..].split_at(len);
+        self.buf = rest;
+        self.remaining -= 1;
+        // SAFETY: entries are only ever written from `&str`s, whole
+        Some(unsafe { std::str::from_utf8_unchecked(entry) })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
@@ -1,267 +1,1043 @@
-use FerroDB::aof::{AofWriter, load_aof};
-use FerroDB::commands::handle_command;
+#![allow(non_snake_case)]
+
+use FerroDB::aof::{AofPaths, AofWriter, replay_aof};
+use FerroDB::clients::Client;
+use FerroDB::commands::{apply_config, authenticate, handle_command, protected_mode_denial};
+use FerroDB::config::{IoBackend, LogLevel};
+use FerroDB::connection::ConnectionContext;
+use FerroDB::latency;
 use FerroDB::persistance::load_rdb;
-use FerroDB::protocol::{RespValue, parse_resp};
-use FerroDB::pubsub::{ClientSubscriptions, PubSubHub};
+use FerroDB::protocol::{BulkStr, Decoded, RESP2, RespDecoder, RespValue};
+use FerroDB::pubsub::{ChannelKind, SubscriptionChange, SubscriptionReceivers};
+use FerroDB::replication::{ReplicaLink, ReplicaStream};
 use FerroDB::storage::FerroStore;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use FerroDB::tls;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use FerroDB::uring::{UringStream, Workers};
+use bytes::{Buf, Bytes, BytesMut};
+use clap::Parser;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Poll;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{Duration, interval, sleep};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant, interval, sleep};
+use tokio_rustls::TlsAcceptor;
+
+/// FerroDB, a Redis-compatible in-memory key-value server
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// redis.conf-style configuration file (same as --config)
+    #[arg(value_name = "CONFIG_FILE", conflicts_with = "config")]
+    config_file: Option<PathBuf>,
+    /// Configuration file; the flags below override its directives
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// TCP port to listen on
+    #[arg(long)]
+    port: Option<u16>,
+    /// Addresses to listen on, space separated (e.g. "127.0.0.1 ::1")
+    #[arg(long)]
+    bind: Option<String>,
+    /// Working directory for the RDB and AOF files
+    #[arg(long)]
+    dir: Option<String>,
+    /// RDB snapshot file name
+    #[arg(long)]
+    dbfilename: Option<String>,
+    /// Whether to log writes to the AOF
+    #[arg(long, value_name = "yes|no")]
+    appendonly: Option<String>,
+    /// Replicate the master at this address (e.g. "10.0.0.1 6379")
+    #[arg(long, value_name = "HOST PORT")]
+    replicaof: Option<String>,
+    /// Detach from the terminal and run in the background (unix only)
+    #[arg(long)]
+    daemonize: bool,
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+impl Cli {
+    /// Flags given on the command line, as `(parameter, value)` pairs
+    fn overrides(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = Vec::new();
+        if let Some(port) = self.port {
+            pairs.push(("port", port.to_string()));
+        }
+        if let Some(bind) = &self.bind {
+            pairs.push(("bind", bind.clone()));
+        }
+        if let Some(dir) = &self.dir {
+            pairs.push(("dir", dir.clone()));
+        }
+        if let Some(dbfilename) = &self.dbfilename {
+            pairs.push(("dbfilename", dbfilename.clone()));
+        }
+        if let Some(appendonly) = &self.appendonly {
+            pairs.push(("appendonly", appendonly.clone()));
+        }
+        if let Some(replicaof) = &self.replicaof {
+            pairs.push(("replicaof", replicaof.clone()));
+        }
+        if self.daemonize {
+            pairs.push(("daemonize", "yes".to_string()));
+        }
+        pairs
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
     let store = FerroStore::new();
-    if let Err(e) = load_rdb(&store, "dump.rdb").await {
+    register_modules(&store)?;
+    if let Some(path) = cli.config.as_ref().or(cli.config_file.as_ref()) {
+        store.config().load_file(path)?;
+        println!("Loaded configuration from {}", path.display());
+    }
+    let overrides = cli.overrides();
+    store.config().apply_overrides(&overrides)?;
+    let overridden = overrides.iter().map(|(name, _)| *name).collect();
+
+    // Fork before the runtime starts its worker threads
+    if store.config().read().daemonize {
+        daemonize()?;
+    }
+    tokio::runtime::Runtime::new()?.block_on(run(store, overridden))
+}
+
+/// Detach from the terminal: fork, let the parent exit, start a new
+/// session and point stdio at /dev/null
+#[cfg(unix)]
+fn daemonize() -> Result<(), String> {
+    // SAFETY: the process is still single-threaded, and the child only
+    // makes async-signal-safe calls before returning to normal startup
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(format!("fork failed: {}", std::io::Error::last_os_error())),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            return Err(format!(
+                "setsid failed: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if null != -1 {
+            libc::dup2(null, libc::STDIN_FILENO);
+            libc::dup2(null, libc::STDOUT_FILENO);
+            libc::dup2(null, libc::STDERR_FILENO);
+            if null > libc::STDERR_FILENO {
+                libc::close(null);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn daemonize() -> Result<(), String> {
+    Err("daemonize is only supported on unix".to_string())
+}
+
+/// `overridden` names the parameters set by command-line flags
+async fn run(
+    store: FerroStore,
+    overridden: Vec<&'static str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = store.config().read().clone();
+    std::env::set_current_dir(&config.dir)
+        .map_err(|e| format!("Can't chdir to '{}': {}", config.dir, e))?;
+    if let Err(e) = load_rdb(&store, &config.dbfilename).await {
         println!("No existing database found or failed to load: {}", e);
         println!("Starting with empty database");
     } else {
-        println!("Loaded {} keys from dump.rdb", store.dbsize());
+        println!("Loaded {} keys from {}", store.dbsize(), config.dbfilename);
     }
-    let store_clone = store.clone();
-    let commands_replayed = load_aof("appendonly.aof", move |cmd| {
-        // Replay command without logging back to AOF
-        let rt = tokio::runtime::Handle::current();
-        let store_ref = store_clone.clone();
-        rt.spawn(async move {
-            handle_command(cmd, &store_ref, None, None, None).await;
-        });
-    })
-    .await?;
-    if commands_replayed > 0 {
-        println!("Replayed {} commands from AOF", commands_replayed);
+    // Replayed in full before any connection is accepted. With appendonly
+    // off it may have stopped being written to, so it's left alone
+    let aof_paths = AofPaths::new(&config.appenddirname, &config.appendfilename);
+    let aof_entries = if config.appendonly {
+        replay_aof(&aof_paths, config.aof_load_truncated, &store).await?
+    } else {
+        0
+    };
+    if aof_entries > 0 {
+        println!("Loaded {} keys and commands from AOF", aof_entries);
         println!("Total keys after AOF replay: {}", store.dbsize());
     }
-    let (aof_writer, aof_handle) = AofWriter::new("appendonly.aof".to_string());
+    // Running even with appendonly off, so CONFIG SET can turn it on
+    let (aof_writer, aof_handle) = AofWriter::new(
+        aof_paths,
+        store.config().clone(),
+        store.latency().clone(),
+        store.replication().clone(),
+    );
     tokio::spawn(async move {
         if let Err(e) = aof_handle.run().await {
             eprintln!("AOF writer error: {}", e);
         }
     });
+    let aof_writer = Some(aof_writer);
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let hangups = signal(SignalKind::hangup())?;
+        tokio::spawn(reload_on_sighup(
+            hangups,
+            store.clone(),
+            aof_writer.clone(),
+            overridden,
+        ));
+    }
+    #[cfg(not(unix))]
+    let _ = overridden;
+    store
+        .replication()
+        .set_backlog_size(config.repl_backlog_size as usize);
+    store
+        .replication()
+        .set_output_limit(config.client_output_buffer_limit.replica);
+    // The master's snapshot replaces what was just loaded once it arrives
+    if let Some((host, port)) = config.replicaof.clone() {
+        store
+            .replication()
+            .replicate(&store, aof_writer.clone(), host, port);
+    }
 
-    let pubsub = PubSubHub::new();
-
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
-    println!("FerroDB listening on port 6379");
+    if config.port == 0 && config.tls_port == 0 {
+        return Err("port and tls-port can't both be 0".into());
+    }
+    let tls_acceptor = if config.tls_port != 0 {
+        Some(tls::acceptor(&config)?)
+    } else {
+        None
+    };
+    let backend = match config.io_backend {
+        IoBackend::Tokio => Backend::Tokio,
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        IoBackend::IoUring => Backend::Uring(std::sync::Arc::new(
+            Workers::start(config.io_threads)
+                .map_err(|e| format!("Can't start io_uring threads: {}", e))?,
+        )),
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        IoBackend::IoUring => {
+            return Err(
+                "io-backend io-uring requires a Linux build with the io-uring feature".into(),
+            );
+        }
+    };
     let store_clone = store.clone();
     tokio::spawn(async move { active_expiration_loop(store_clone).await });
-    // Periodic auto-save task (every 60 seconds)
+    // Snapshot task driven by the `save` rules
     let store_clone = store.clone();
     tokio::spawn(async move {
         auto_save_loop(store_clone).await;
     });
 
+    let mut listeners = JoinSet::new();
+    if config.port != 0 {
+        for shards in bind_all(&config.bind, config.port, config.io_threads).await? {
+            println!("FerroDB listening on {}", shards[0].local_addr()?);
+            for listener in shards {
+                listeners.spawn(accept_loop(
+                    listener,
+                    None,
+                    backend.clone(),
+                    store.clone(),
+                    aof_writer.clone(),
+                ));
+            }
+        }
+    }
+    if let Some(acceptor) = tls_acceptor {
+        for shards in bind_all(&config.bind, config.tls_port, config.io_threads).await? {
+            println!("FerroDB listening for TLS on {}", shards[0].local_addr()?);
+            for listener in shards {
+                listeners.spawn(accept_loop(
+                    listener,
+                    Some(acceptor.clone()),
+                    backend.clone(),
+                    store.clone(),
+                    aof_writer.clone(),
+                ));
+            }
+        }
+    }
+    // Listeners only return when accepting fails
+    while let Some(result) = listeners.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Listen on `port` at each `bind` address. `*` and `::*` stand for every
+/// IPv4 and IPv6 interface; an address prefixed with `-` is skipped if it
+/// can't be bound. Each address gets `shards` listeners, grouped together
+async fn bind_all(
+    addresses: &[String],
+    port: u16,
+    shards: usize,
+) -> std::io::Result<Vec<Vec<TcpListener>>> {
+    let mut listeners = Vec::new();
+    for address in addresses {
+        let (optional, address) = match address.strip_prefix('-') {
+            Some(address) => (true, address),
+            None => (false, address.as_str()),
+        };
+        let host = match address {
+            "*" => "0.0.0.0",
+            "::*" => "::",
+            host => host,
+        };
+        match bind_shards(host, port, shards).await {
+            Ok(shards) => listeners.push(shards),
+            Err(e) if optional => eprintln!("Skipping bind address {}: {}", address, e),
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!("Can't bind {} port {}: {}", address, port, e),
+                ));
+            }
+        }
+    }
+    if listeners.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "None of the bind addresses are available for port {}",
+            port
+        )));
+    }
+    Ok(listeners)
+}
+
+/// Bind `shards` listeners to the same address through SO_REUSEPORT, so
+/// the kernel spreads incoming connections across their accept loops
+/// instead of one loop accepting for every core
+#[cfg(unix)]
+async fn bind_shards(host: &str, port: u16, shards: usize) -> std::io::Result<Vec<TcpListener>> {
+    if shards == 1 {
+        return Ok(vec![TcpListener::bind((host, port)).await?]);
+    }
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        match (0..shards).map(|_| reuse_port_listener(addr)).collect() {
+            Ok(listeners) => return Ok(listeners),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no addresses to bind")
+    }))
+}
+
+#[cfg(not(unix))]
+async fn bind_shards(host: &str, port: u16, _shards: usize) -> std::io::Result<Vec<TcpListener>> {
+    Ok(vec![TcpListener::bind((host, port)).await?])
+}
+
+#[cfg(unix)]
+fn reuse_port_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // As TcpListener::bind does
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Where accepted connections are served (`io-backend`)
+#[derive(Clone)]
+enum Backend {
+    Tokio,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(std::sync::Arc<Workers>),
+}
+
+/// Accept clients on `listener`, completing the TLS handshake first when
+/// `tls` is set
+async fn accept_loop(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    backend: Backend,
+    store: FerroStore,
+    aof_writer: Option<AofWriter>,
+) -> std::io::Result<()> {
     loop {
         let (socket, addr) = listener.accept().await?;
-        println!("New connection from: {}", addr);
+        let laddr = socket.local_addr()?;
+        if log_enabled(&store, LogLevel::Verbose) {
+            println!("New connection from: {}", addr);
+        }
+        if let Err(e) = tune_socket(&socket, &store) {
+            eprintln!("Can't set socket options for {}: {}", addr, e);
+        }
 
         let store_clone = store.clone();
         let aof_clone = aof_writer.clone();
-        let pubsubclone = pubsub.clone();
-        tokio::spawn(async move {
-            if let Err(e) = process_connection(socket, store_clone, aof_clone, pubsubclone).await {
-                eprintln!("Connection error: {}", e);
+        let tls = tls.clone();
+        match &backend {
+            Backend::Tokio => {
+                tokio::spawn(async move {
+                    let result = match tls {
+                        Some(acceptor) => match acceptor.accept(socket).await {
+                            Ok(stream) => {
+                                process_connection(stream, addr, laddr, store_clone, aof_clone)
+                                    .await
+                            }
+                            Err(e) => {
+                                Err(format!("TLS handshake with {} failed: {}", addr, e).into())
+                            }
+                        },
+                        None => {
+                            process_connection(socket, addr, laddr, store_clone, aof_clone).await
+                        }
+                    };
+                    if let Err(e) = result {
+                        eprintln!("Connection error: {}", e);
+                    }
+                });
+            }
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Backend::Uring(workers) => {
+                serve_on_uring(workers, socket, addr, laddr, tls, store_clone, aof_clone)
             }
-        });
+        }
+    }
+}
+
+/// Hand an accepted client to an io_uring worker, whose ring does its
+/// reads and writes from then on
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn serve_on_uring(
+    workers: &Workers,
+    socket: TcpStream,
+    addr: SocketAddr,
+    laddr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    store: FerroStore,
+    aof: Option<AofWriter>,
+) {
+    let socket = match socket.into_std() {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Can't hand {} to io_uring: {}", addr, e);
+            return;
+        }
+    };
+    workers.spawn(move || async move {
+        let socket = UringStream::from_std(socket);
+        let result = match tls {
+            Some(acceptor) => match acceptor.accept(socket).await {
+                Ok(stream) => process_local_connection(stream, addr, laddr, store, aof).await,
+                Err(e) => Err(format!("TLS handshake with {} failed: {}", addr, e).into()),
+            },
+            None => process_local_connection(socket, addr, laddr, store, aof).await,
+        };
+        if let Err(e) = result {
+            eprintln!("Connection error: {}", e);
+        }
+    });
+}
+
+/// Apply `tcp-nodelay` and `tcp-keepalive` to an accepted socket. Keepalive
+/// probes keep NAT and firewall entries of quiet clients (e.g. subscribers)
+/// alive, and detect peers that vanished without closing the connection
+fn tune_socket(socket: &TcpStream, store: &FerroStore) -> std::io::Result<()> {
+    let (keepalive, nodelay) = {
+        let config = store.config().read();
+        (config.tcp_keepalive, config.tcp_nodelay)
+    };
+    socket.set_nodelay(nodelay)?;
+    if keepalive > 0 {
+        let params = TcpKeepalive::new().with_time(Duration::from_secs(keepalive));
+        SockRef::from(socket).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+/// Re-read the config file on every SIGHUP and apply the parameters CONFIG
+/// SET could change, without touching connections. Flags given on the
+/// command line keep precedence over the file
+#[cfg(unix)]
+async fn reload_on_sighup(
+    mut hangups: tokio::signal::unix::Signal,
+    store: FerroStore,
+    aof: Option<AofWriter>,
+    overridden: Vec<&'static str>,
+) {
+    while hangups.recv().await.is_some() {
+        // Exclusive like CONFIG SET: turning appendonly on captures the
+        // dataset for the AOF, so no command may be writing meanwhile
+        let _exclusive = store.exec_lock().write().await;
+        reload_config(&store, aof.as_ref(), &overridden);
     }
 }
 
+#[cfg(unix)]
+fn reload_config(store: &FerroStore, aof: Option<&AofWriter>, overridden: &[&str]) {
+    let changes = match store.config().reload_changes() {
+        Ok(changes) => changes,
+        Err(e) => {
+            eprintln!("Config reload failed: {}", e);
+            return;
+        }
+    };
+    for name in changes.restart_needed {
+        if !overridden.contains(&name) {
+            eprintln!("Config reload: {} changed, restart to apply it", name);
+        }
+    }
+    let pairs: Vec<(String, String)> = changes
+        .reloadable
+        .into_iter()
+        .filter(|(name, _)| !overridden.contains(&name.as_str()))
+        .collect();
+    if pairs.is_empty() {
+        println!("Config reloaded: no changes");
+        return;
+    }
+    match apply_config(store, aof, &pairs) {
+        Ok(()) => {
+            let applied: Vec<String> = pairs
+                .iter()
+                .map(|(name, value)| format!("{} {}", name, value))
+                .collect();
+            println!("Config reloaded: {}", applied.join(", "));
+        }
+        Err(e) => eprintln!("Config reload failed: {}", e),
+    }
+}
+
+fn log_enabled(store: &FerroStore, level: LogLevel) -> bool {
+    store.config().read().loglevel <= level
+}
+
+/// Startup hook for command modules. Register custom commands here, before
+/// the AOF is replayed, e.g. `store.modules().register(MyCommand)?;`
+fn register_modules(_store: &FerroStore) -> Result<(), String> {
+    Ok(())
+}
+
 async fn active_expiration_loop(store: FerroStore) {
-    let mut ticker = interval(Duration::from_millis(100)); //Run every 100 ms
     loop {
-        ticker.tick().await;
-        let deleted = store.delete_expired_keys();
+        // `hz` cycles per second, re-read so CONFIG SET hz applies right away
+        let hz = store.config().read().hz.max(1);
+        let period = Duration::from_millis(1000 / hz as u64);
+        sleep(period).await;
+        // Measure the keys written since the last cycle, so their backlog
+        // stays short even when nothing else asks for the total
+        store.used_memory();
+        // Keys don't expire during CLIENT PAUSE, so the data stays frozen
+        if !store.active_expire_enabled() || store.clients().is_paused() {
+            continue;
+        }
+        let started = Instant::now();
+        // Like Redis, spend at most a quarter of the period expiring keys;
+        // a backlog carries over to the next cycles
+        let deleted = store.active_expire_cycle(period / 4);
+        store.record_latency(latency::EXPIRE_CYCLE, started.elapsed());
         if deleted > 0 {
             println!("Active expiration: deleted {} expired keys", deleted);
         }
     }
 }
+/// Snapshot whenever one of the `save` rules is met
 async fn auto_save_loop(store: FerroStore) {
-    let mut ticker = interval(Duration::from_secs(60));
+    let mut ticker = interval(Duration::from_secs(1));
 
     loop {
         ticker.tick().await;
 
-        if store.dbsize() > 0 {
-            match FerroDB::persistance::save_rdb(&store, "dump.rdb").await {
-                Ok(_) => println!("Auto-save: saved {} keys to dump.rdb", store.dbsize()),
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if FerroDB::persistance::save_due(&store, now) {
+            let path = store.config().read().dbfilename.clone();
+            // Shared like a command's while the dataset is captured, so a
+            // transaction is saved whole or not at all
+            let save = {
+                let _shared = store.exec_lock().read().await;
+                FerroDB::persistance::begin_save(store.clone(), path.clone())
+            };
+            let result = match save {
+                Ok(save) => save.await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) => println!("Auto-save: saved {} keys to {}", store.dbsize(), path),
                 Err(e) => eprintln!("Auto-save failed: {}", e),
             }
         }
     }
 }
 
-async fn process_connection(
-    mut socket: TcpStream,
+/// Buffered replies are handed to the writer once they reach this size,
+/// even in the middle of a pipeline
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+/// Reply batches a reader may queue for a slow client before it stops
+/// reading more requests
+const OUTGOING_CAPACITY: usize = 64;
+
+/// What a connection's reader hands its writer, in the order to act on it
+enum Outgoing {
+    /// Encoded replies
+    Replies(Bytes),
+    Subscription(SubscriptionChange),
+    /// The protocol published messages are encoded with from now on (HELLO)
+    Protocol(u8),
+}
+
+async fn send(outgoing: &mpsc::Sender<Outgoing>, item: Outgoing) -> std::io::Result<()> {
+    outgoing.send(item).await.map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::BrokenPipe, "connection writer stopped")
+    })
+}
+
+/// Hand the replies buffered in `out` to the writer
+async fn flush_replies(
+    outgoing: &mpsc::Sender<Outgoing>,
+    out: &mut BytesMut,
+) -> std::io::Result<()> {
+    if !out.is_empty() {
+        send(outgoing, Outgoing::Replies(out.split().freeze())).await?;
+    }
+    Ok(())
+}
+
+/// Register the client, unless protected mode turns it away
+async fn admit<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    peer: SocketAddr,
+    local: SocketAddr,
+    store: &FerroStore,
+) -> std::io::Result<Option<Client>> {
+    if let Some(reply) = protected_mode_denial(store, peer.ip()) {
+        socket.write_all(reply.encode().as_bytes()).await?;
+        socket.flush().await?;
+        return Ok(None);
+    }
+    Ok(Some(store.clients().register(peer, local)))
+}
+
+async fn process_connection<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    mut socket: S,
+    peer: SocketAddr,
+    local: SocketAddr,
     store: FerroStore,
-    aof: AofWriter,
-    pubsub: PubSubHub, // ✅ Add this
+    aof: Option<AofWriter>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buffer = Vec::new();
-    let mut temp = [0u8; 1024];
-    let mut client_subs = ClientSubscriptions::new(); // ✅ Add this
+    // Listed in CLIENT LIST until this function returns
+    let Some(client) = admit(&mut socket, peer, local, &store).await? else {
+        return Ok(());
+    };
 
-    loop {
-        // Check for pub/sub messages if subscribed
-        if client_subs.is_subscribed() {
-            // Non-blocking check for messages
-            while let Some(msg) = client_subs.try_recv() {
-                // Send message to client
-                // Format: ["message", channel, message_content]
-                let response = RespValue::Array(vec![
-                    RespValue::BulkString("message".to_string()),
-                    RespValue::BulkString(msg.channel),
-                    RespValue::BulkString(msg.message),
-                ]);
-                socket.write_all(response.encode().as_bytes()).await?;
-            }
-        }
-
-        // Try to read from socket (with timeout if subscribed)
-        let n = if client_subs.is_subscribed() {
-            // Use timeout to periodically check for pub/sub messages
-            tokio::select! {
-                result = socket.read(&mut temp) => result?,
-                _ = sleep(Duration::from_millis(100)) => {
-                    // Timeout - continue to check for pub/sub messages
-                    continue;
-                }
-            }
-        } else {
-            socket.read(&mut temp).await?
-        };
+    // Replies and published messages are written by a task of their own, so
+    // a message goes out as soon as it is published whatever the reader is
+    // doing, and never in the middle of a reply
+    let (reader, writer) = tokio::io::split(socket);
+    let (outgoing, incoming) = mpsc::channel(OUTGOING_CAPACITY);
+    let writer = tokio::spawn(write_loop(
+        writer,
+        incoming,
+        OutputLimit::new(&store, &client),
+    ));
+    let read_result = read_requests(reader, outgoing, peer, &client, store, aof).await;
+    // The reader dropped its sender, so the writer finishes what it was
+    // handed and closes the connection. Its error, if any, is why the
+    // reader stopped
+    writer.await??;
+    Ok(read_result?)
+}
 
-        if n == 0 {
-            println!("Client disconnected");
-            return Ok(());
-        }
+/// `process_connection` for sockets that belong to the io_uring worker
+/// they run on, so the writer is a task on that thread too
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+async fn process_local_connection<S: AsyncRead + AsyncWrite + Unpin + 'static>(
+    mut socket: S,
+    peer: SocketAddr,
+    local: SocketAddr,
+    store: FerroStore,
+    aof: Option<AofWriter>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(client) = admit(&mut socket, peer, local, &store).await? else {
+        return Ok(());
+    };
+    let (reader, writer) = tokio::io::split(socket);
+    let (outgoing, incoming) = mpsc::channel(OUTGOING_CAPACITY);
+    let writer = tokio::task::spawn_local(write_loop(
+        writer,
+        incoming,
+        OutputLimit::new(&store, &client),
+    ));
+    let read_result = read_requests(reader, outgoing, peer, &client, store, aof).await;
+    writer.await??;
+    Ok(read_result?)
+}
 
-        buffer.extend_from_slice(&temp[..n]);
-
-        while let Some((msg, consumed)) = extract_message(&buffer) {
-            println!("Received: {}", msg.escape_debug());
-
-            match parse_resp(&msg) {
-                Ok(parsed) => {
-                    let response = handle_command(
-                        parsed,
-                        &store,
-                        Some(&aof),
-                        Some(&pubsub),
-                        Some(&mut client_subs),
-                    )
-                    .await;
-                    let encoded = response.encode();
-                    socket.write_all(encoded.as_bytes()).await?;
-                    println!("Sent: {}", encoded.escape_debug());
+/// Write what the reader hands over, and the messages published to the
+/// connection's channels as they arrive. Messages are taken even while the
+/// client isn't reading and held until it does, up to its class's
+/// `client-output-buffer-limit`, past which the client is disconnected
+async fn write_loop<W: AsyncWrite + Unpin>(
+    mut socket: W,
+    mut incoming: mpsc::Receiver<Outgoing>,
+    mut limit: OutputLimit,
+) -> std::io::Result<()> {
+    let mut receivers = SubscriptionReceivers::new();
+    let mut protover = RESP2;
+    let mut out = BytesMut::new();
+    let mut pending = PendingOutput::default();
+    loop {
+        tokio::select! {
+            // Whatever the reader handed over first, so subscriptions and
+            // protocol switches take effect between the right replies.
+            // Nothing is taken from it until earlier output is written, so
+            // a client that doesn't read its replies stops its reader
+            biased;
+            item = incoming.recv(), if pending.is_empty() => match item {
+                Some(Outgoing::Replies(replies)) => pending.push(replies),
+                Some(Outgoing::Subscription(change)) => receivers.apply(change),
+                Some(Outgoing::Protocol(version)) => protover = version,
+                None => break,
+            },
+            // Cancel safe: nothing is written unless this branch completes
+            written = socket.write(pending.front()), if !pending.is_empty() => {
+                match written? {
+                    0 => return Err(std::io::ErrorKind::WriteZero.into()),
+                    written => pending.advance(written),
                 }
-                Err(e) => {
-                    let err_msg = format!("-ERR {}\r\n", e);
-                    socket.write_all(err_msg.as_bytes()).await?;
+            }
+            msg = receivers.recv() => {
+                // Format: ["message" or "smessage", channel, message_content]
+                let message = RespValue::Push(vec![
+                    RespValue::BulkString(BulkStr::from_static(msg.kind.message_kind())),
+                    RespValue::bulk(msg.channel),
+                    RespValue::bulk(msg.message),
+                ]);
+                message.encode_into(&mut out, protover);
+                pending.push(out.split().freeze());
+                limit.store.pubsub().record_dropped(receivers.take_dropped());
+                // Replies are only taken as fast as the client reads them, so
+                // messages are what piles up
+                if limit.exceeded(pending.len) {
+                    // What was held for the client is dropped with it
+                    return Ok(());
                 }
             }
-
-            buffer.drain(..consumed);
         }
+        limit.record(pending.len);
     }
+    while !pending.is_empty() {
+        let chunk = pending.chunks.pop_front().unwrap_or_default();
+        socket.write_all(&chunk).await?;
+    }
+    // The client may have gone already
+    socket.shutdown().await.ok();
+    Ok(())
+}
+
+/// Output a connection's writer has yet to write, in order
+#[derive(Default)]
+struct PendingOutput {
+    chunks: VecDeque<Bytes>,
+    /// Bytes in `chunks`
+    len: usize,
 }
-fn extract_message(buffer: &[u8]) -> Option<(String, usize)> {
-    let s = String::from_utf8_lossy(buffer);
-    let mut lines = s.split("\r\n");
 
-    let first = lines.next()?.trim();
-    if first.is_empty() {
-        return None;
+impl PendingOutput {
+    fn push(&mut self, chunk: Bytes) {
+        if !chunk.is_empty() {
+            self.len += chunk.len();
+            self.chunks.push_back(chunk);
+        }
     }
 
-    let prefix = first.chars().next()?;
+    fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
 
-    match prefix {
-        '+' | '-' | ':' => {
-            let msg = format!("{}\r\n", first);
-            Some((msg.clone(), msg.len()))
-        }
-        '$' => {
-            let len: i64 = first[1..].parse().ok()?;
+    /// The bytes to write next; empty when there are none
+    fn front(&self) -> &[u8] {
+        self.chunks.front().map_or(&[], |chunk| chunk)
+    }
 
-            if len == -1 {
-                let msg = "$-1\r\n".to_string();
-                return Some((msg.clone(), msg.len()));
+    /// Drop the first `written` bytes, which are out
+    fn advance(&mut self, written: usize) {
+        self.len -= written;
+        if let Some(chunk) = self.chunks.front_mut() {
+            chunk.advance(written);
+            if chunk.is_empty() {
+                self.chunks.pop_front();
             }
-
-            let data = lines.next()?;
-            let msg = format!("{}\r\n{}\r\n", first, data);
-            Some((msg.clone(), msg.len()))
         }
-        '*' => parse_array_from_buffer(&s),
-        _ => None,
     }
 }
-fn parse_array_from_buffer(input: &str) -> Option<(String, usize)> {
-    let mut pos = 0;
-    let bytes = input.as_bytes();
 
-    let (first_line, line_end) = read_line(bytes, pos)?;
-    pos = line_end;
+/// A writer's client, for `client-output-buffer-limit`
+struct OutputLimit {
+    store: FerroStore,
+    id: u64,
+    /// Where the pending output's size is published (CLIENT LIST omem)
+    memory: Arc<AtomicUsize>,
+    /// Since when the client has been over its soft limit
+    soft_since: Option<std::time::Instant>,
+    /// The soft limit at the last check
+    soft: usize,
+}
 
-    let count: usize = first_line.trim_start_matches('*').parse().ok()?;
-    let mut result = first_line.to_string() + "\r\n";
+impl OutputLimit {
+    fn new(store: &FerroStore, client: &Client) -> Self {
+        Self {
+            store: store.clone(),
+            id: client.id(),
+            memory: client.output_memory(),
+            soft_since: None,
+            soft: 0,
+        }
+    }
 
-    for _ in 0..count {
-        let (element, elem_end) = parse_element_from_pos(bytes, pos)?;
-        result.push_str(&element);
-        pos = elem_end;
+    /// Note that `pending` bytes of output are left
+    fn record(&mut self, pending: usize) {
+        self.memory.store(pending, Ordering::Relaxed);
+        if pending < self.soft {
+            self.soft_since = None;
+        }
     }
 
-    Some((result, pos))
+    /// Record `pending` bytes of output, closing the client if that's over
+    /// the limit for its class. Returns whether it was closed
+    fn exceeded(&mut self, pending: usize) -> bool {
+        self.memory.store(pending, Ordering::Relaxed);
+        let Some(kind) = self.store.clients().kind(self.id) else {
+            return false;
+        };
+        let limit = self
+            .store
+            .config()
+            .read()
+            .client_output_buffer_limit
+            .class(kind);
+        self.soft = limit.soft as usize;
+        if !limit.exceeded(pending as u64, &mut self.soft_since) {
+            return false;
+        }
+        if log_enabled(&self.store, LogLevel::Warning) {
+            println!(
+                "Client id={} closed for overcoming of output buffer limits ({} bytes pending)",
+                self.id, pending
+            );
+        }
+        self.store.clients().kill_for_output(self.id);
+        true
+    }
 }
 
-fn parse_element_from_pos(bytes: &[u8], start: usize) -> Option<(String, usize)> {
-    let mut pos = start;
+/// Read and run the connection's requests, handing replies to the writer
+async fn read_requests<R: AsyncRead + Unpin>(
+    mut socket: R,
+    outgoing: mpsc::Sender<Outgoing>,
+    peer: SocketAddr,
+    client: &Client,
+    store: FerroStore,
+    aof: Option<AofWriter>,
+) -> std::io::Result<()> {
+    let mut decoder = RespDecoder::for_requests();
+    // Replies to the requests of one read are encoded into this buffer and
+    // handed over together, so a pipeline costs one write rather than one
+    // per command
+    let mut out = BytesMut::new();
+    let mut conn = ConnectionContext::for_client(client.id());
+    let mut protover = conn.protocol;
 
-    let (type_line, line_end) = read_line(bytes, pos)?;
-    pos = line_end;
+    loop {
+        // Subscribers and replicas are exempt: they legitimately sit silent
+        // for long
+        let idle_timeout = match conn.subscriptions.is_subscribed() || conn.replica.is_some() {
+            true => 0,
+            false => store.config().read().timeout,
+        };
+        let idle = async {
+            if idle_timeout == 0 {
+                std::future::pending::<()>().await
+            } else {
+                sleep(Duration::from_secs(idle_timeout)).await
+            }
+        };
+        let n = tokio::select! {
+            result = decoder.read_from(&mut socket) => result?,
+            _ = client.killed() => return Ok(()),
+            _ = idle => {
+                if log_enabled(&store, LogLevel::Verbose) {
+                    println!("Closing client idle for {}s", idle_timeout);
+                }
+                return Ok(());
+            }
+        };
 
-    let prefix = type_line.chars().next()?;
+        if n == 0 {
+            if log_enabled(&store, LogLevel::Verbose) {
+                println!("Client disconnected");
+            }
+            return Ok(());
+        }
 
-    match prefix {
-        '+' | '-' | ':' => Some((format!("{}\r\n", type_line), pos)),
-        '$' => {
-            let len: i64 = type_line[1..].parse().ok()?;
+        // Applies to what arrives from now on, like CONFIG SET's other changes
+        let max_bulk_len = store.config().read().proto_max_bulk_len;
+        decoder.set_max_bulk_len(usize::try_from(max_bulk_len).unwrap_or(usize::MAX));
 
-            if len == -1 {
-                return Some(("$-1\r\n".to_string(), pos));
+        loop {
+            let parsed = match decoder.decode() {
+                Decoded::Frame(parsed) => parsed,
+                Decoded::NeedMoreData => {
+                    flush_replies(&outgoing, &mut out).await?;
+                    break;
+                }
+                // The rest of the stream can't be framed, so tell the client
+                // why and close rather than guess where the next request starts
+                Decoded::Error(e) => {
+                    if log_enabled(&store, LogLevel::Verbose) {
+                        println!("{} from client {}, closing", e, peer);
+                    }
+                    let reply = RespValue::Error(format!("ERR {}", e));
+                    reply.encode_into(&mut out, conn.protocol);
+                    flush_replies(&outgoing, &mut out).await?;
+                    return Ok(());
+                }
+            };
+            if log_enabled(&store, LogLevel::Debug) {
+                println!("Received: {:?}", parsed);
             }
 
-            let (data_line, data_end) = read_line(bytes, pos)?;
-            pos = data_end;
-
-            Some((format!("{}\r\n{}\r\n", type_line, data_line), pos))
-        }
-        '*' => {
-            let count: usize = type_line[1..].parse().ok()?;
-            let mut result = format!("{}\r\n", type_line);
+            if let RespValue::Array(args) = &parsed
+                && let Some(RespValue::BulkString(name)) = args.first()
+            {
+                store.clients().command_started(client.id(), name);
+            }
+            let response = match authenticate(&parsed, &store, &mut conn) {
+                Some(reply) => reply,
+                // The command borrows the subscriptions until it completes
+                None => {
+                    let command = handle_command(parsed, &store, aof.as_ref(), Some(&mut conn));
+                    tokio::pin!(command);
+                    match poll_fn(|cx| Poll::Ready(command.as_mut().poll(cx))).await {
+                        Poll::Ready(response) => response,
+                        Poll::Pending => {
+                            // The command is about to wait (BLPOP, CLIENT
+                            // PAUSE...): earlier commands' replies mustn't
+                            // wait with it
+                            flush_replies(&outgoing, &mut out).await?;
+                            // Keep reading while the command runs: blocking
+                            // commands may wait a long time, and a disconnect
+                            // must cancel them so they don't pop an element
+                            // nobody will receive
+                            loop {
+                                tokio::select! {
+                                    biased;
+                                    response = &mut command => break response,
+                                    // CLIENT KILL also cancels a blocked command
+                                    _ = client.killed() => return Ok(()),
+                                    // Reads straight into the decoder's buffer
+                                    result = decoder.read_from(&mut socket) => {
+                                        if result? == 0 {
+                                            if log_enabled(&store, LogLevel::Verbose) {
+                                                println!("Client disconnected");
+                                            }
+                                            return Ok(());
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            };
 
-            for _ in 0..count {
-                let (elem, elem_end) = parse_element_from_pos(bytes, pos)?;
-                result.push_str(&elem);
-                pos = elem_end;
+            // The writer stops delivering a channel's messages before the
+            // UNSUBSCRIBE reply and starts after the SUBSCRIBE one
+            let changes = conn.subscriptions.take_changes();
+            let (subscribed, unsubscribed): (Vec<_>, Vec<_>) = changes
+                .into_iter()
+                .partition(|change| matches!(change, SubscriptionChange::Subscribed(..)));
+            if !unsubscribed.is_empty() {
+                flush_replies(&outgoing, &mut out).await?;
+                for change in unsubscribed {
+                    send(&outgoing, Outgoing::Subscription(change)).await?;
+                }
+            }
+            // CLIENT REPLY OFF / SKIP drop replies
+            if store.clients().take_reply(client.id()) {
+                // Encoded after the command, so HELLO's reply uses the
+                // protocol it switched to
+                if log_enabled(&store, LogLevel::Debug) {
+                    println!("Sent: {:?}", response);
+                }
+                response.encode_into(&mut out, conn.protocol);
+            }
+            if !subscribed.is_empty() || conn.protocol != protover || out.len() >= FLUSH_THRESHOLD {
+                flush_replies(&outgoing, &mut out).await?;
+            }
+            for change in subscribed {
+                send(&outgoing, Outgoing::Subscription(change)).await?;
+            }
+            if conn.protocol != protover {
+                protover = conn.protocol;
+                send(&outgoing, Outgoing::Protocol(protover)).await?;
+            }
+            // SYNC / PSYNC made the connection a replica: after the replies
+            // to earlier commands, it's sent the replication stream
+            if let Some(stream) = conn.replica.as_mut().and_then(ReplicaLink::take_stream) {
+                flush_replies(&outgoing, &mut out).await?;
+                tokio::spawn(stream_to_replica(store.clone(), stream, outgoing.clone()));
             }
 
-            Some((result, pos))
+            sync_client(&store, client.id(), &conn);
         }
-        _ => None,
     }
 }
-
-fn read_line(bytes: &[u8], start: usize) -> Option<(&str, usize)> {
-    let remaining = &bytes[start..];
-
-    for i in 0..remaining.len().saturating_sub(1) {
-        if remaining[i] == b'\r' && remaining[i + 1] == b'\n' {
-            let line = std::str::from_utf8(&remaining[..i]).ok()?;
-            return Some((line, start + i + 2));
+/// Hand a replica's stream to its connection's writer until the replica
+/// is detached or the writer stops. A replica detached for going over its
+/// `client-output-buffer-limit` has its connection closed
+async fn stream_to_replica(
+    store: FerroStore,
+    mut stream: ReplicaStream,
+    outgoing: mpsc::Sender<Outgoing>,
+) {
+    while let Some(bytes) = stream.next().await {
+        if send(&outgoing, Outgoing::Replies(bytes)).await.is_err() {
+            return;
+        }
+    }
+    if stream.overflowed() {
+        if log_enabled(&store, LogLevel::Warning) {
+            println!(
+                "Client id={} closed for overcoming of output buffer limits ({} bytes pending)",
+                stream.id(),
+                stream.queued()
+            );
         }
+        store.clients().kill_for_output(stream.id());
     }
+}
 
-    None
+/// Copy the connection state CLIENT LIST shows into the client's entry
+fn sync_client(store: &FerroStore, id: u64, conn: &ConnectionContext) {
+    store.clients().update(id, |info| {
+        info.db = conn.db;
+        info.subscriptions = conn.subscriptions.count(ChannelKind::Global);
+        info.shard_subscriptions = conn.subscriptions.count(ChannelKind::Shard);
+        info.user = conn.user.clone().unwrap_or_default();
+    });
 }
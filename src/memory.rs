@@ -0,0 +1,196 @@
+use crate::config::MaxmemoryPolicy;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Candidates an `EvictionPool` keeps
+const EVICTION_POOL_SIZE: usize = 16;
+
+/// Counter of new keys, so they get a chance to be read before being
+/// evicted as the least frequently used
+const LFU_INIT: u8 = 5;
+/// How slowly the counter grows: it takes about a million accesses to
+/// saturate at 255
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// The counter drops by one for each of these periods without an access
+const LFU_DECAY_MINUTES: u32 = 1;
+/// Bits of a `Frequency` holding the counter; the rest hold the minute it
+/// was last decayed
+const LFU_COUNTER_BITS: u32 = 8;
+
+/// Bytes used by the keys and values of every database (`maxmemory`).
+///
+/// Writes only note which key they changed, since they hold the database
+/// lock; the entry is measured later, when the total is asked for. Each
+/// entry remembers what it was counted as and gives it back when dropped,
+/// so deleted, expired and overwritten values need no bookkeeping
+#[derive(Clone, Default)]
+pub struct MemoryTracker {
+    used: Arc<AtomicUsize>,
+    /// (database, key) written since the last `take_written`
+    written: Arc<Mutex<Vec<(usize, String)>>>,
+    /// Keys removed by maxmemory eviction
+    evicted: Arc<AtomicU64>,
+}
+
+impl MemoryTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes counted so far; see `FerroStore::used_memory` for an up to
+    /// date total
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Note that `key` in database `db` changed and must be measured again
+    pub fn written(&self, db: usize, key: &str) {
+        self.written.lock().unwrap().push((db, key.to_string()));
+    }
+
+    pub fn take_written(&self) -> Vec<(usize, String)> {
+        std::mem::take(&mut *self.written.lock().unwrap())
+    }
+
+    /// Count `entry` as `bytes`, replacing what it was counted as before
+    pub fn account(&self, entry: &Accounted, bytes: usize) {
+        let used = entry.used.get_or_init(|| self.used.clone());
+        // Add before subtracting so the total never dips below zero
+        used.fetch_add(bytes, Ordering::Relaxed);
+        used.fetch_sub(
+            entry.bytes.swap(bytes, Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    pub fn record_eviction(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// An entry's share of `MemoryTracker::used`, given back on drop. Clones of
+/// an entry start out uncounted
+#[derive(Debug, Default)]
+pub struct Accounted {
+    bytes: AtomicUsize,
+    /// The total this entry was counted in, once it has been measured
+    used: OnceLock<Arc<AtomicUsize>>,
+}
+
+impl Drop for Accounted {
+    fn drop(&mut self) {
+        if let Some(used) = self.used.get() {
+            used.fetch_sub(*self.bytes.get_mut(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// How often an entry is accessed (LFU eviction, OBJECT FREQ): an 8-bit
+/// logarithmic (Morris) counter that is incremented with decreasing
+/// probability and decays while the entry goes unused. Packed with the
+/// minute of its last decay so it takes 4 bytes. Times are minutes on the
+/// caller's clock
+#[derive(Debug)]
+pub struct Frequency(AtomicU32);
+
+impl Frequency {
+    pub fn new(now: u32) -> Self {
+        Self(AtomicU32::new(pack(now, LFU_INIT)))
+    }
+
+    /// The counter, decayed for the time since the last access
+    pub fn count(&self, now: u32) -> u8 {
+        decayed(self.0.load(Ordering::Relaxed), now)
+    }
+
+    /// Record an access. Concurrent readers may race and lose an
+    /// increment, which the counter's approximation absorbs
+    pub fn increment(&self, now: u32) {
+        let mut counter = self.count(now);
+        if counter < u8::MAX {
+            let base = counter.saturating_sub(LFU_INIT) as f64;
+            if fastrand::f64() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                counter += 1;
+            }
+        }
+        self.0.store(pack(now, counter), Ordering::Relaxed);
+    }
+}
+
+impl Clone for Frequency {
+    fn clone(&self) -> Self {
+        Self(AtomicU32::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+fn pack(minutes: u32, counter: u8) -> u32 {
+    (minutes << LFU_COUNTER_BITS) | counter as u32
+}
+
+fn decayed(packed: u32, now: u32) -> u8 {
+    let mask = (1 << LFU_COUNTER_BITS) - 1;
+    let counter = (packed & mask) as u8;
+    // Minutes are kept modulo 2^24, so shift `now` the same way before
+    // subtracting
+    let elapsed = (now << LFU_COUNTER_BITS).wrapping_sub(packed & !mask) >> LFU_COUNTER_BITS;
+    let periods = elapsed / LFU_DECAY_MINUTES;
+    counter.saturating_sub(periods.min(u8::MAX as u32) as u8)
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// The policy the candidates were ranked for
+    policy: Option<MaxmemoryPolicy>,
+    /// (rank, database, key) in ascending rank; the last is evicted first
+    candidates: Vec<(u64, usize, String)>,
+}
+
+/// The best eviction candidates seen so far. Kept between evictions, so
+/// each one picks from more keys than it samples itself, which brings
+/// sampled LRU much closer to the real thing without tracking every key
+#[derive(Clone, Default)]
+pub struct EvictionPool {
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl EvictionPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop the candidates if they were ranked for another policy
+    pub fn prepare(&self, policy: MaxmemoryPolicy) {
+        let mut state = self.state.lock().unwrap();
+        if state.policy != Some(policy) {
+            state.policy = Some(policy);
+            state.candidates.clear();
+        }
+    }
+
+    /// Consider `key` in database `db`, whose `rank` is how strongly it
+    /// should be evicted (e.g. its idle time)
+    pub fn offer(&self, rank: u64, db: usize, key: &str) {
+        let candidates = &mut self.state.lock().unwrap().candidates;
+        if let Some(existing) = candidates.iter().position(|(_, d, k)| *d == db && k == key) {
+            candidates.remove(existing);
+        }
+        if candidates.len() == EVICTION_POOL_SIZE && candidates[0].0 >= rank {
+            return;
+        }
+        let at = candidates.partition_point(|(r, ..)| *r < rank);
+        candidates.insert(at, (rank, db, key.to_string()));
+        if candidates.len() > EVICTION_POOL_SIZE {
+            candidates.remove(0);
+        }
+    }
+
+    /// Take the highest ranked candidate
+    pub fn pop(&self) -> Option<(usize, String)> {
+        let candidates = &mut self.state.lock().unwrap().candidates;
+        candidates.pop().map(|(_, db, key)| (db, key))
+    }
+}
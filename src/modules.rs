@@ -0,0 +1,92 @@
+use crate::command_table;
+pub use crate::command_table::CommandFlags;
+use crate::protocol::RespValue;
+use crate::storage::FerroStore;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+/// Future returned by `CommandModule::execute`
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = RespValue> + Send + 'a>>;
+
+/// A command provided outside commands.rs
+///
+/// Register modules on the store at startup with
+/// `store.modules().register(...)`. The server checks a module's arity
+/// before running it and logs it to the AOF when it is flagged `WRITE`.
+///
+/// ```ignore
+/// struct Hello;
+///
+/// impl CommandModule for Hello {
+///     fn name(&self) -> &str {
+///         "HELLO.WORLD"
+///     }
+///     fn arity(&self) -> i32 {
+///         -1
+///     }
+///     fn flags(&self) -> CommandFlags {
+///         CommandFlags::READONLY
+///     }
+///     fn execute<'a>(&'a self, _: &'a FerroStore, args: &'a [String]) -> CommandFuture<'a> {
+///         Box::pin(async move { RespValue::Integer(args.len() as i64) })
+///     }
+/// }
+/// ```
+pub trait CommandModule: Send + Sync {
+    /// Command name, matched case-insensitively
+    fn name(&self) -> &str;
+
+    /// Number of arguments including the command name; a negative arity
+    /// `-n` means at least `n`
+    fn arity(&self) -> i32;
+
+    fn flags(&self) -> CommandFlags;
+
+    /// Run the command. `args` holds every argument, starting with the name
+    fn execute<'a>(&'a self, store: &'a FerroStore, args: &'a [String]) -> CommandFuture<'a>;
+}
+
+/// Command modules registered at startup, keyed by upper-case name
+#[derive(Clone, Default)]
+pub struct ModuleRegistry {
+    modules: Arc<RwLock<HashMap<String, Arc<dyn CommandModule>>>>,
+}
+
+impl ModuleRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a command. Fails if a built-in command or another module already
+    /// provides its name
+    pub fn register(&self, module: impl CommandModule + 'static) -> Result<(), String> {
+        let name = module.name().to_uppercase();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("ERR invalid command name '{}'", module.name()));
+        }
+        if command_table::lookup(&name).is_some() {
+            return Err(format!("ERR command '{}' is a built-in command", name));
+        }
+        let mut modules = self.modules.write().unwrap();
+        if modules.contains_key(&name) {
+            return Err(format!("ERR command '{}' is already registered", name));
+        }
+        modules.insert(name, Arc::new(module));
+        Ok(())
+    }
+
+    /// Every registered module, sorted by name
+    pub fn list(&self) -> Vec<Arc<dyn CommandModule>> {
+        let modules = self.modules.read().unwrap();
+        let mut list: Vec<Arc<dyn CommandModule>> = modules.values().cloned().collect();
+        list.sort_by_key(|module| module.name().to_uppercase());
+        list
+    }
+
+    /// The module providing `name` (upper-case)
+    pub fn get(&self, name: &str) -> Option<Arc<dyn CommandModule>> {
+        self.modules.read().unwrap().get(name).cloned()
+    }
+}
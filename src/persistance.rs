@@ -1,98 +1,356 @@
-use crate::storage::{DataType, FerroStore, SortedSetData};
-use ordered_float::OrderedFloat;
+use crate::aof::DatabaseData;
+use crate::config::RdbCompression;
+use crate::latency;
+use crate::storage::{DATABASES, DataType, DbSnapshot, FerroStore, StoreSnapshot};
+use crc::{CRC_64_REDIS, Crc, Digest};
 use std::collections::{HashSet, VecDeque};
-use std::io;
-use std::time::{Duration, Instant};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 
 const MAGIC: &[u8] = b"FERRODB\0";
-const VERSION: u8 = 1;
+/// Version 5 records the codec values are compressed with in the header,
+/// and marks each value as compressed or not. Version 4 ends each database
+/// with an end marker rather than starting it with a key count, so a file
+/// is written in one pass, and ends with a CRC64 of everything before it.
+/// Version 3 stores expirations as Unix times in milliseconds, so keys
+/// expire on time whenever the file is loaded; versions 1 and 2 stored the
+/// seconds remaining at save time, which are counted from load instead.
+/// Version 2 stores a section per non-empty database; version 1 files hold
+/// a single database and are still loaded, into database 0
+const VERSION: u8 = 5;
 
-/// Serialize the database to RDB format
-pub async fn save_rdb(store: &FerroStore, path: &str) -> io::Result<()> {
-    let snapshot = store.snapshot();
+/// Precedes each key-value pair of a database (version 4)
+const ENTRY: u8 = 1;
+/// Follows the last key-value pair of a database (version 4)
+const END_OF_DB: u8 = 0;
 
-    // Write to temp file first
-    let temp_path = format!("{}.tmp", path);
-    let mut file = File::create(&temp_path).await?;
+/// Precedes a value stored as is (version 5)
+const RAW: u8 = 0;
+/// Precedes a compressed value, its encoded length and its compressed
+/// length (version 5)
+const COMPRESSED: u8 = 1;
+/// Shorter encoded values are not worth compressing
+const COMPRESS_MIN: usize = 64;
 
-    // Write header
-    file.write_all(MAGIC).await?;
-    file.write_u8(VERSION).await?;
+/// Version of the DUMP payload format
+const DUMP_VERSION: u16 = 1;
 
-    // Write number of keys
-    file.write_u64(snapshot.len() as u64).await?;
+static CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
 
-    // Write each key-value pair
-    for (key, (data, expiry)) in snapshot {
-        // Write key
-        write_string(&mut file, &key).await?;
+/// Bytes of encoded entries buffered before they are written out
+const WRITE_CHUNK: usize = 64 * 1024;
 
-        // Write data type and value
-        match data {
-            DataType::String(s) => {
-                file.write_u8(0).await?; // Type: String
-                write_string(&mut file, &s).await?;
-            }
-            DataType::List(list) => {
-                file.write_u8(1).await?; // Type: List
-                file.write_u64(list.len() as u64).await?;
-                for item in list {
-                    write_string(&mut file, &item).await?;
-                }
-            }
-            DataType::Set(set) => {
-                file.write_u8(2).await?; // Type: Set
-                file.write_u64_le(set.len() as u64).await?;
-                for member in set {
-                    write_string(&mut file, &member).await?;
-                }
-            }
-            DataType::SortedSet(zset) => {
-                file.write_u8(3).await?; // Type: SortedSet
-                file.write_u64_le(zset.len() as u64).await?;
-                for (member, score) in &zset.members {
-                    write_string(&mut file, member).await?;
-                    file.write_f64_le(score.0).await?;
-                }
-            }
+/// Serialize the database to RDB format
+///
+/// Every database is snapshotted at once (see `FerroStore::snapshot`) and
+/// its values encoded one key at a time, streaming them to the file, so
+/// writers are never held up for long and saving only copies the values
+/// written meanwhile. The file is a point-in-time image of the moment the
+/// save started.
+///
+/// Fails without writing anything while another snapshot is in progress.
+pub async fn save_rdb(store: &FerroStore, path: &str) -> io::Result<()> {
+    begin_save(store.clone(), path.to_string())?.await
+}
+
+/// Start saving the database in the background (BGSAVE), failing
+/// straight away if another snapshot is in progress. The dataset is
+/// captured before this returns
+pub fn bgsave(store: &FerroStore, path: &str) -> io::Result<()> {
+    let save = begin_save(store.clone(), path.to_string())?;
+    tokio::spawn(async move {
+        match save.await {
+            Ok(_) => println!("Background save completed"),
+            Err(e) => println!("Background save failed : {}", e),
         }
+    });
+    Ok(())
+}
 
-        // Write expiry
-        match expiry {
-            Some(instant) => {
-                file.write_u8(1).await?; // Has expiry
-                let now = Instant::now();
-                let remaining = if instant > now {
-                    instant.duration_since(now).as_secs() as i64
-                } else {
-                    0 // Already expired
-                };
-                file.write_i64(remaining).await?;
-            }
-            None => {
-                file.write_u8(0).await?; // No expiry
-            }
+/// Snapshot every database for saving to `path`, returning the write to
+/// await. Called holding the exec lock, as commands are, so the snapshot
+/// lands between transactions. Fails straight away if another snapshot is
+/// in progress
+pub fn begin_save(
+    store: FerroStore,
+    path: String,
+) -> io::Result<impl Future<Output = io::Result<()>>> {
+    let saving = store.start_save().ok_or_else(save_in_progress)?;
+    let started = Instant::now();
+    let dirty = store.dirty();
+    let snapshot = store.snapshot(saving);
+    Ok(async move {
+        let result = write_snapshot(&store, &path, &snapshot, dirty, started).await;
+        if let Err(e) = &result {
+            store.record_save_error(e.to_string());
         }
+        // Only now may another snapshot start
+        drop(snapshot);
+        result
+    })
+}
+
+/// Whether a `save` rule calls for a snapshot at Unix time `now`
+/// (seconds): one rule's interval has passed since the last save with at
+/// least its number of writes made since. Nothing is due while the dataset
+/// is clean or another snapshot is in progress.
+pub fn save_due(store: &FerroStore, now: u64) -> bool {
+    let dirty = store.dirty();
+    if dirty == 0 || store.save_in_progress() {
+        return false;
     }
+    let elapsed = now.saturating_sub(store.last_save());
+    store
+        .config()
+        .read()
+        .save
+        .iter()
+        .any(|rule| dirty >= rule.changes && elapsed >= rule.seconds)
+}
+
+fn save_in_progress() -> io::Error {
+    io::Error::other("Background save already in progress")
+}
+
+/// Write the snapshot, taken when `dirty` writes were pending
+async fn write_snapshot(
+    store: &FerroStore,
+    path: &str,
+    snapshot: &StoreSnapshot,
+    dirty: u64,
+    started: Instant,
+) -> io::Result<()> {
+    let codec = store.config().read().rdbcompression;
+    let databases: Vec<_> = snapshot
+        .databases()
+        .iter()
+        .filter(|db| !db.is_empty())
+        .collect();
+
+    // Write to temp file first
+    let temp_path = format!("{}.tmp", path);
+    let mut out = RdbWriter::new(File::create(&temp_path).await?);
+
+    // Write header, then the number of databases and each one's index
+    // and entries
+    out.write_header(codec, databases.len());
+    for db in databases {
+        out.buf
+            .extend_from_slice(&(db.index() as u64).to_be_bytes());
+        write_entries(&mut out, db, codec).await?;
+    }
+    let file = out.finish().await?;
 
     file.sync_all().await?;
     drop(file);
 
     // Atomic rename
     tokio::fs::rename(&temp_path, path).await?;
+    store.record_save(dirty);
+    store.record_latency(latency::RDB_SAVE, started.elapsed());
+
+    Ok(())
+}
+
+/// Write an RDB file at `path` holding `databases`, given as (index, keys)
+/// pairs as captured for an AOF rewrite, which uses it as the AOF's base
+pub async fn write_rdb_data(
+    databases: &[(usize, DatabaseData)],
+    codec: RdbCompression,
+    path: &Path,
+) -> io::Result<()> {
+    let databases: Vec<_> = databases
+        .iter()
+        .filter(|(_, keys)| !keys.is_empty())
+        .collect();
+    let mut out = RdbWriter::new(File::create(path).await?);
+    out.write_header(codec, databases.len());
+    let mut value = Vec::new();
+    for (index, keys) in databases {
+        out.buf.extend_from_slice(&(*index as u64).to_be_bytes());
+        for (key, data, expiry) in keys {
+            write_entry(&mut out.buf, &mut value, key, data, *expiry, codec);
+            out.flush_full().await?;
+        }
+        out.buf.push(END_OF_DB);
+    }
+    let file = out.finish().await?;
+    file.sync_all().await
+}
+
+/// An RDB file holding `databases`, built in memory, as sent to a replica
+pub fn rdb_bytes(databases: &[(usize, DatabaseData)], codec: RdbCompression) -> Vec<u8> {
+    let databases: Vec<_> = databases
+        .iter()
+        .filter(|(_, keys)| !keys.is_empty())
+        .collect();
+    let mut buf = Vec::new();
+    write_header(&mut buf, codec, databases.len());
+    let mut value = Vec::new();
+    for (index, keys) in databases {
+        buf.extend_from_slice(&(*index as u64).to_be_bytes());
+        for (key, data, expiry) in keys {
+            write_entry(&mut buf, &mut value, key, data, *expiry, codec);
+        }
+        buf.push(END_OF_DB);
+    }
+    let checksum = CRC64.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_be_bytes());
+    buf
+}
 
+/// Whether `contents` start like an RDB file
+pub fn is_rdb(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Buffers the contents of an RDB file on their way to disk, keeping a
+/// running checksum of them
+struct RdbWriter {
+    file: File,
+    buf: Vec<u8>,
+    digest: Digest<'static, u64>,
+}
+
+impl RdbWriter {
+    fn new(file: File) -> Self {
+        Self {
+            file,
+            buf: Vec::new(),
+            digest: CRC64.digest(),
+        }
+    }
+
+    /// Buffer the header: magic, version and codec, then the number of
+    /// databases that follow
+    fn write_header(&mut self, codec: RdbCompression, databases: usize) {
+        write_header(&mut self.buf, codec, databases);
+    }
+
+    /// Write out the buffer once it holds `WRITE_CHUNK` bytes
+    async fn flush_full(&mut self) -> io::Result<()> {
+        if self.buf.len() >= WRITE_CHUNK {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.digest.update(&self.buf);
+        self.file.write_all(&self.buf).await?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Write out the rest of the buffer followed by the checksum
+    async fn finish(mut self) -> io::Result<File> {
+        self.flush().await?;
+        self.file.write_u64(self.digest.finalize()).await?;
+        Ok(self.file)
+    }
+}
+
+/// Buffer the header: magic, version and codec, then the number of
+/// databases that follow
+fn write_header(buf: &mut Vec<u8>, codec: RdbCompression, databases: usize) {
+    buf.extend_from_slice(MAGIC);
+    buf.push(VERSION);
+    buf.push(codec_id(codec));
+    buf.extend_from_slice(&(databases as u64).to_be_bytes());
+}
+
+/// Write the key-value pairs of a database snapshot, then the end marker
+async fn write_entries(
+    out: &mut RdbWriter,
+    snapshot: &DbSnapshot,
+    codec: RdbCompression,
+) -> io::Result<()> {
+    let mut value = Vec::new();
+    for index in 0..snapshot.len() {
+        let buf = &mut out.buf;
+        snapshot.with_entry(index, |key, data, expiry| {
+            write_entry(buf, &mut value, key, data, expiry, codec)
+        });
+        out.flush_full().await?;
+    }
+    out.buf.push(END_OF_DB);
     Ok(())
 }
 
+/// Buffer one key-value pair, encoding the value in `value` first
+fn write_entry(
+    buf: &mut Vec<u8>,
+    value: &mut Vec<u8>,
+    key: &str,
+    data: &DataType,
+    expiry: Option<u64>,
+    codec: RdbCompression,
+) {
+    buf.push(ENTRY);
+
+    // Write key
+    write_string(buf, key);
+
+    // Write data type and value
+    value.clear();
+    encode_value(value, data);
+    write_compressed(buf, value, codec);
+
+    // Write expiry
+    match expiry {
+        Some(unix_ms) => {
+            buf.push(1); // Has expiry
+            buf.extend_from_slice(&unix_ms.to_be_bytes());
+        }
+        None => {
+            buf.push(0); // No expiry
+        }
+    }
+}
+
 /// Deserialize RDB file and load into database
 pub async fn load_rdb(store: &FerroStore, path: &str) -> io::Result<()> {
-    let mut file = File::open(path).await?;
+    let contents = tokio::fs::read(path).await?;
+    let mut db = store.clone();
+    read_rdb(&contents, |index, key, data, expiry| {
+        if db.db_index() != index {
+            db = store
+                .database(index)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid DB index"))?;
+        }
+        db.load_entry(key, data, expiry);
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// What an RDB file's header says about it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RdbHeader {
+    pub version: u8,
+    /// Codec its values are compressed with (version 5)
+    pub compression: RdbCompression,
+    /// Whether it ends with a CRC64, which was verified (version 4)
+    pub checksummed: bool,
+}
+
+/// Parse the contents of an RDB file, calling `visit` with the database
+/// index, key, value and expiry (Unix time in milliseconds) of each entry
+///
+/// A checksummed file is verified before anything is visited; in older
+/// formats an error may come after some entries have been.
+pub fn read_rdb(
+    contents: &[u8],
+    mut visit: impl FnMut(usize, String, DataType, Option<u64>) -> io::Result<()>,
+) -> io::Result<RdbHeader> {
+    let mut reader = contents;
 
     // Read and verify header
     let mut magic = vec![0u8; 8];
-    file.read_exact(&mut magic).await?;
+    reader.read_exact(&mut magic)?;
     if magic != MAGIC {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
@@ -100,103 +358,367 @@ pub async fn load_rdb(store: &FerroStore, path: &str) -> io::Result<()> {
         ));
     }
 
-    let version = file.read_u8().await?;
-    if version != VERSION {
+    let version = read_u8(&mut reader)?;
+    if version >= 4 {
+        // Check the whole file before loading any of it
+        let Some((body, checksum)) = reader.split_last_chunk::<8>() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "RDB file is truncated",
+            ));
+        };
+        let end = contents.len() - checksum.len();
+        if CRC64.checksum(&contents[..end]) != u64::from_be_bytes(*checksum) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "RDB checksum mismatch, the file is truncated or corrupt",
+            ));
+        }
+        reader = body;
+    }
+    let header = RdbHeader {
+        version,
+        compression: if version >= 5 {
+            codec_from_id(read_u8(&mut reader)?)?
+        } else {
+            RdbCompression::No
+        },
+        checksummed: version >= 4,
+    };
+    match version {
+        1 => read_entries(&mut reader, 0, header, &mut visit)?,
+        2..=VERSION => {
+            let num_databases = read_u64_be(&mut reader)?;
+            for _ in 0..num_databases {
+                let index = read_u64_be(&mut reader)? as usize;
+                read_entries(&mut reader, index, header, &mut visit)?;
+            }
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported version: {}", version),
+            ));
+        }
+    }
+    if !reader.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected data after the last database",
+        ));
+    }
+    Ok(header)
+}
+
+/// Read the key-value pairs of database `index` from a file described by
+/// `header`, passing each to `visit`
+fn read_entries(
+    reader: &mut &[u8],
+    index: usize,
+    header: RdbHeader,
+    visit: &mut impl FnMut(usize, String, DataType, Option<u64>) -> io::Result<()>,
+) -> io::Result<()> {
+    if index >= DATABASES {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            format!("Unsupported version: {}", version),
+            "Invalid DB index",
         ));
     }
 
-    // Read number of keys
-    let num_keys = file.read_u64().await?;
+    if header.version >= 4 {
+        loop {
+            match read_u8(reader)? {
+                ENTRY => {
+                    let (key, data, expiry) = read_entry(reader, header)?;
+                    visit(index, key, data, expiry)?;
+                }
+                END_OF_DB => break,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Invalid entry marker",
+                    ));
+                }
+            }
+        }
+    } else {
+        let num_keys = read_u64_be(reader)?;
+        for _ in 0..num_keys {
+            let (key, data, expiry) = read_entry(reader, header)?;
+            visit(index, key, data, expiry)?;
+        }
+    }
 
-    // Read each key-value pair
-    for _ in 0..num_keys {
-        let key = read_string(&mut file).await?;
+    Ok(())
+}
 
-        let data_type = file.read_u8().await?;
-        let data = match data_type {
-            0 => {
-                // String
-                let value = read_string(&mut file).await?;
-                DataType::String(value)
-            }
-            1 => {
-                // List
-                let list_len = file.read_u64().await?;
-                let mut list = VecDeque::new();
-                for _ in 0..list_len {
-                    let item = read_string(&mut file).await?;
-                    list.push_back(item);
-                }
-                DataType::List(list)
+/// Read a key-value pair and its expiry
+fn read_entry(
+    reader: &mut &[u8],
+    header: RdbHeader,
+) -> io::Result<(String, DataType, Option<u64>)> {
+    let key = read_string(reader)?;
+    let data = if header.version >= 5 {
+        read_compressed(reader, header.compression)?
+    } else {
+        decode_value(reader)?
+    };
+
+    let has_expiry = read_u8(reader)?;
+    let expiry = if has_expiry == 1 {
+        let expiry = read_u64_be(reader)?;
+        if header.version < 3 {
+            // Seconds remaining when the file was saved
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let remaining = Duration::from_secs(expiry.min(i64::MAX as u64));
+            Some((now + remaining).as_millis() as u64)
+        } else {
+            Some(expiry)
+        }
+    } else {
+        None
+    };
+
+    Ok((key, data, expiry))
+}
+
+/// The byte recording `codec` in the header
+fn codec_id(codec: RdbCompression) -> u8 {
+    match codec {
+        RdbCompression::No => 0,
+        RdbCompression::Lz4 => 1,
+        RdbCompression::Zstd => 2,
+    }
+}
+
+fn codec_from_id(id: u8) -> io::Result<RdbCompression> {
+    match id {
+        0 => Ok(RdbCompression::No),
+        1 => Ok(RdbCompression::Lz4),
+        2 => Ok(RdbCompression::Zstd),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown compression codec: {}", id),
+        )),
+    }
+}
+
+/// Write an encoded value, compressed with `codec` if that makes it smaller
+fn write_compressed(buf: &mut Vec<u8>, value: &[u8], codec: RdbCompression) {
+    let compressed = match codec {
+        _ if value.len() < COMPRESS_MIN => None,
+        RdbCompression::No => None,
+        RdbCompression::Lz4 => Some(lz4_flex::block::compress(value)),
+        RdbCompression::Zstd => zstd::bulk::compress(value, zstd::DEFAULT_COMPRESSION_LEVEL).ok(),
+    };
+    match compressed {
+        Some(compressed) if compressed.len() < value.len() => {
+            buf.push(COMPRESSED);
+            buf.extend_from_slice(&(value.len() as u64).to_be_bytes());
+            buf.extend_from_slice(&(compressed.len() as u64).to_be_bytes());
+            buf.extend_from_slice(&compressed);
+        }
+        _ => {
+            buf.push(RAW);
+            buf.extend_from_slice(value);
+        }
+    }
+}
+
+/// Read a value written by `write_compressed`
+fn read_compressed(reader: &mut &[u8], codec: RdbCompression) -> io::Result<DataType> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    match read_u8(reader)? {
+        RAW => decode_value(reader),
+        COMPRESSED => {
+            let len = read_u64_be(reader)? as usize;
+            let compressed_len = read_u64_be(reader)? as usize;
+            if compressed_len > reader.len() {
+                return Err(invalid("Compressed value is truncated"));
             }
-            2 => {
-                // Set
-                let set_len = file.read_u64_le().await?;
-                let mut set = HashSet::new();
-                for _ in 0..set_len {
-                    let member = read_string(&mut file).await?;
-                    set.insert(member);
+            let (compressed, rest) = reader.split_at(compressed_len);
+            *reader = rest;
+            let value = match codec {
+                RdbCompression::No => {
+                    return Err(invalid("Compressed value in an uncompressed file"));
                 }
-                DataType::Set(set)
+                RdbCompression::Lz4 => lz4_flex::block::decompress(compressed, len)
+                    .map_err(|e| invalid(&e.to_string()))?,
+                RdbCompression::Zstd => zstd::bulk::decompress(compressed, len)?,
+            };
+            if value.len() != len {
+                return Err(invalid("Compressed value has the wrong length"));
             }
-            3 => {
-                let zset_len = file.read_u64_le().await?;
-                let mut zset = SortedSetData::new();
-                for _ in 0..zset_len {
-                    let member = read_string(&mut file).await?;
-                    let score = file.read_f64_le().await?;
-
-                    let score_key = OrderedFloat(score);
-                    zset.scores
-                        .entry(score_key)
-                        .or_default()
-                        .insert(member.clone());
-                    zset.members.insert(member, score_key);
-                }
-                DataType::SortedSet(zset)
+            decode_value(&mut value.as_slice())
+        }
+        _ => Err(invalid("Invalid value marker")),
+    }
+}
+
+/// Serialize a single value for DUMP
+/// Layout: RDB value encoding | format version (u16 LE) | CRC64 of everything before (u64 LE)
+pub fn dump_value(data: &DataType) -> Vec<u8> {
+    let mut buf = Vec::new();
+    encode_value(&mut buf, data);
+    buf.extend_from_slice(&DUMP_VERSION.to_le_bytes());
+    let checksum = CRC64.checksum(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+/// Size in bytes of a value in the RDB on-disk format (DEBUG OBJECT)
+pub fn serialized_length(data: &DataType) -> usize {
+    let mut buf = Vec::new();
+    encode_value(&mut buf, data);
+    buf.len()
+}
+
+/// Deserialize a DUMP payload, verifying its version and checksum
+pub fn restore_value(payload: &[u8]) -> io::Result<DataType> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DUMP payload version or checksum are wrong",
+        )
+    };
+    if payload.len() < 10 {
+        return Err(invalid());
+    }
+
+    let (body, checksum) = payload.split_at(payload.len() - 8);
+    if CRC64.checksum(body).to_le_bytes() != checksum {
+        return Err(invalid());
+    }
+    let (mut value, version) = body.split_at(body.len() - 2);
+    if u16::from_le_bytes([version[0], version[1]]) != DUMP_VERSION {
+        return Err(invalid());
+    }
+
+    let data = decode_value(&mut value)?;
+    if !value.is_empty() {
+        return Err(invalid());
+    }
+    Ok(data)
+}
+
+/// Encode a type byte followed by the value, in the RDB on-disk format
+fn encode_value(buf: &mut Vec<u8>, data: &DataType) {
+    match data {
+        DataType::String(s) => {
+            buf.push(0); // Type: String
+            write_string(buf, &s.as_str());
+        }
+        DataType::List(list) => {
+            buf.push(1); // Type: List
+            buf.extend_from_slice(&(list.len() as u64).to_be_bytes());
+            for item in list {
+                write_string(buf, item);
             }
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Unknown data type: {}", data_type),
-                ));
+        }
+        DataType::Set(set) => {
+            buf.push(2); // Type: Set
+            buf.extend_from_slice(&(set.len() as u64).to_le_bytes());
+            for member in set {
+                write_string(buf, member);
             }
-        };
-
-        let has_expiry = file.read_u8().await?;
-        let expiry = if has_expiry == 1 {
-            let remaining_secs = file.read_i64().await?;
-            if remaining_secs > 0 {
-                Some(Duration::from_secs(remaining_secs as u64))
-            } else {
-                None // Already expired
+        }
+        DataType::SortedSet(zset) => {
+            buf.push(3); // Type: SortedSet
+            buf.extend_from_slice(&(zset.len() as u64).to_le_bytes());
+            for (member, score) in zset {
+                write_string(buf, member);
+                buf.extend_from_slice(&score.to_le_bytes());
             }
-        } else {
-            None
-        };
-
-        // Load into store
-        store.load_entry(key, data, expiry);
+        }
     }
+}
 
-    Ok(())
+/// Decode a type byte followed by the value, in the RDB on-disk format
+fn decode_value(reader: &mut &[u8]) -> io::Result<DataType> {
+    let data_type = read_u8(reader)?;
+    let data = match data_type {
+        0 => {
+            // String
+            let value = read_string(reader)?;
+            DataType::String(value.into())
+        }
+        1 => {
+            // List
+            let list_len = read_u64_be(reader)?;
+            let mut list = VecDeque::new();
+            for _ in 0..list_len {
+                let item = read_string(reader)?;
+                list.push_back(item);
+            }
+            DataType::List(list.into())
+        }
+        2 => {
+            // Set
+            let set_len = read_u64_le(reader)?;
+            let mut set = HashSet::new();
+            for _ in 0..set_len {
+                let member = read_string(reader)?;
+                set.insert(member);
+            }
+            DataType::Set(set.into())
+        }
+        3 => {
+            let zset_len = read_u64_le(reader)?;
+            let mut zset = Vec::new();
+            for _ in 0..zset_len {
+                let member = read_string(reader)?;
+                let score = f64::from_bits(read_u64_le(reader)?);
+                zset.push((member, score));
+            }
+            DataType::SortedSet(zset.into_iter().collect())
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown data type: {}", data_type),
+            ));
+        }
+    };
+    Ok(data)
 }
 
 /// Helper: Write a string with length prefix
-async fn write_string(file: &mut File, s: &str) -> io::Result<()> {
+fn write_string(buf: &mut Vec<u8>, s: &str) {
     let bytes = s.as_bytes();
-    file.write_u64(bytes.len() as u64).await?;
-    file.write_all(bytes).await?;
-    Ok(())
+    buf.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    buf.extend_from_slice(bytes);
 }
 
 /// Helper: Read a length-prefixed string
-async fn read_string(file: &mut File) -> io::Result<String> {
-    let len = file.read_u64().await?;
+fn read_string(reader: &mut &[u8]) -> io::Result<String> {
+    let len = read_u64_be(reader)?;
+    if len > reader.len() as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "String length exceeds remaining data",
+        ));
+    }
     let mut bytes = vec![0u8; len as usize];
-    file.read_exact(&mut bytes).await?;
+    reader.read_exact(&mut bytes)?;
     String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
+
+fn read_u8(reader: &mut &[u8]) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u64_be(reader: &mut &[u8]) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_u64_le(reader: &mut &[u8]) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -1,76 +1,691 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::ops::Deref;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Bytes a connection reads at a time
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Longest bulk string a decoder accepts unless told otherwise
+/// (`proto-max-bulk-len`)
+pub const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most elements an aggregate may declare
+pub const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+/// Longest line (inline command or header) to wait for the end of
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Deepest aggregates may nest
+const MAX_DEPTH: usize = 128;
+
+/// The text of a bulk string. Arguments decoded from a request are slices
+/// of the buffer the request was read into rather than copies.
+///
+/// There's deliberately no `Display`: `to_string()` then goes through the
+/// `str` deref, which allocates exactly the length instead of growing a
+/// buffer (keys' capacity counts towards `used_memory`)
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BulkStr(Bytes);
+
+impl BulkStr {
+    pub const fn from_static(s: &'static str) -> Self {
+        Self(Bytes::from_static(s.as_bytes()))
+    }
+
+    /// The text in `bytes`, copied only if it isn't valid UTF-8 (invalid
+    /// sequences become U+FFFD)
+    pub fn from_utf8(bytes: Bytes) -> Self {
+        match std::str::from_utf8(&bytes) {
+            Ok(_) => Self(bytes),
+            Err(_) => Self::from(String::from_utf8_lossy(&bytes).into_owned()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: every constructor checks for or starts from valid UTF-8
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for BulkStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for BulkStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for BulkStr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Hash for BulkStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // As `str` does, so `Borrow<str>` lookups work
+        self.as_str().hash(state)
+    }
+}
+
+impl fmt::Debug for BulkStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl From<String> for BulkStr {
+    fn from(s: String) -> Self {
+        Self(Bytes::from(s.into_bytes()))
+    }
+}
+
+impl From<&str> for BulkStr {
+    fn from(s: &str) -> Self {
+        Self(Bytes::copy_from_slice(s.as_bytes()))
+    }
+}
+
+impl From<&String> for BulkStr {
+    fn from(s: &String) -> Self {
+        Self::from(s.as_str())
+    }
+}
+
+impl From<BulkStr> for String {
+    fn from(s: BulkStr) -> Self {
+        // SAFETY: a `BulkStr` always holds valid UTF-8
+        unsafe { String::from_utf8_unchecked(Vec::from(s.0)) }
+    }
+}
+
+impl PartialEq<str> for BulkStr {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for BulkStr {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for BulkStr {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<BulkStr> for str {
+    fn eq(&self, other: &BulkStr) -> bool {
+        self == other.as_str()
+    }
+}
+
+impl PartialEq<BulkStr> for &str {
+    fn eq(&self, other: &BulkStr) -> bool {
+        *self == other.as_str()
+    }
+}
+
+impl PartialEq<BulkStr> for String {
+    fn eq(&self, other: &BulkStr) -> bool {
+        self == other.as_str()
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum RespValue {
     SimpleString(String),
-    BulkString(String),
+    /// An error reply: an upper-case code (`ERR`, `WRONGTYPE`...) and a
+    /// message, without the `-` prefix
+    Error(String),
+    BulkString(BulkStr),
     Array(Vec<RespValue>),
     Null, // Represents $-1\r\n
     Integer(i64),
+    /// Field-value pairs; a flat array in RESP2
+    Map(Vec<(RespValue, RespValue)>),
+    /// Unordered distinct elements; an array in RESP2
+    Set(Vec<RespValue>),
+    /// A bulk string in RESP2
+    Double(f64),
+    /// The integer 1 or 0 in RESP2
+    Boolean(bool),
+    /// Out-of-band data such as pub/sub messages; an array in RESP2
+    Push(Vec<RespValue>),
+    /// Text to show as is, with a three-letter format (`txt`, `mkd`); a
+    /// bulk string of the text in RESP2
+    Verbatim {
+        format: String,
+        text: String,
+    },
+    /// An integer of any size, as its decimal digits with an optional
+    /// leading `-`; a bulk string in RESP2
+    BigNumber(String),
 }
 
+/// Protocol versions a connection can negotiate with HELLO
+pub const RESP2: u8 = 2;
+pub const RESP3: u8 = 3;
+
+/// Parse one complete RESP value from `input`
 pub fn parse_resp(input: &str) -> Result<RespValue, String> {
-    // We convert our string into an iterator of lines.
-    // .peekable() lets us look at the next item without consuming it.
-    let mut lines = input.split("\r\n").peekable();
-    parse_recursive(&mut lines)
-}
-
-// We create a helper function to handle the recursion
-fn parse_recursive(
-    lines: &mut std::iter::Peekable<std::str::Split<&str>>,
-) -> Result<RespValue, String> {
-    let mut line = lines.next().ok_or("Empty input")?;
-    while line.is_empty() {
-        line = lines.next().ok_or("Empty input")?;
-    }
-    let prefix = line.chars().next().ok_or("Missing prefix")?;
-
-    match prefix {
-        '+' => Ok(RespValue::SimpleString(line[1..].to_string())),
-        '$' => {
-            let _len: i64 = line[1..].parse().map_err(|_| "Invalid length")?;
-            if _len == -1 {
-                return Ok(RespValue::Null);
+    let mut decoder = RespDecoder::new();
+    decoder.extend(input.as_bytes());
+    match decoder.decode() {
+        Decoded::Frame(value) => Ok(value),
+        Decoded::NeedMoreData => Err("Incomplete RESP value".to_string()),
+        Decoded::Error(e) => Err(e),
+    }
+}
+
+/// What `RespDecoder::decode` found in the bytes received so far
+#[derive(Debug, PartialEq)]
+pub enum Decoded {
+    /// The bytes end partway through a frame
+    NeedMoreData,
+    Frame(RespValue),
+    /// The bytes aren't RESP; the stream can't be resynchronised after this
+    Error(String),
+}
+
+#[derive(Clone, Copy)]
+enum AggregateKind {
+    Array,
+    Map,
+    Set,
+    Push,
+}
+
+/// An aggregate whose header was decoded but not all of its elements
+struct Partial {
+    kind: AggregateKind,
+    remaining: usize,
+    items: Vec<RespValue>,
+}
+
+impl Partial {
+    fn finish(self) -> RespValue {
+        match self.kind {
+            AggregateKind::Array => RespValue::Array(self.items),
+            AggregateKind::Set => RespValue::Set(self.items),
+            AggregateKind::Push => RespValue::Push(self.items),
+            AggregateKind::Map => {
+                let mut items = self.items.into_iter();
+                let mut pairs = Vec::with_capacity(items.len() / 2);
+                while let (Some(field), Some(value)) = (items.next(), items.next()) {
+                    pairs.push((field, value));
+                }
+                RespValue::Map(pairs)
             }
-            if _len < 0 {
-                return Err("Invalid negative length for bulk string".to_string());
+        }
+    }
+}
+
+/// One element read from the buffer: a complete value, or the header of an
+/// aggregate whose elements follow
+enum Element {
+    Value(RespValue),
+    Aggregate(Partial),
+}
+
+/// Decodes a stream of RESP frames as bytes arrive. Each element is split
+/// off the buffer as soon as it is complete and the frame built so far is
+/// kept, so each byte is decoded once however the stream is split into
+/// reads, and bulk strings share the buffer instead of being copied out of
+/// it. Lengths are taken from the headers, so bulk strings may contain
+/// `\r\n`. A line not starting with a RESP type is an inline command
+/// (`PING`, as typed into telnet), split on whitespace.
+///
+/// Lengths are checked against limits before anything is buffered for
+/// them, so a client claiming `$999999999999` or `*1000000000` gets a
+/// protocol error instead of holding the connection (and its memory) open
+pub struct RespDecoder {
+    /// Received bytes not yet decoded into `stack` or a frame
+    buffer: BytesMut,
+    /// Aggregates of the current frame still waiting for elements,
+    /// outermost first
+    stack: Vec<Partial>,
+    max_bulk_len: usize,
+    /// Only accept client requests, see `for_requests`
+    requests: bool,
+}
+
+impl Default for RespDecoder {
+    fn default() -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            stack: Vec::new(),
+            max_bulk_len: DEFAULT_MAX_BULK_LEN,
+            requests: false,
+        }
+    }
+}
+
+impl RespDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A decoder for what clients send, as Redis reads it: a request is an
+    /// array of bulk strings, any line not starting with `*` is an inline
+    /// command and empty arrays are skipped. Anything else inside an array
+    /// is a protocol error
+    pub fn for_requests() -> Self {
+        Self {
+            requests: true,
+            ..Self::default()
+        }
+    }
+
+    /// Reject bulk strings longer than `len` from now on
+    pub fn set_max_bulk_len(&mut self, len: usize) {
+        self.max_bulk_len = len;
+    }
+
+    /// Append bytes received from the stream
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Bytes received but not yet decoded. Right after a frame, all those
+    /// before them belong to it or to earlier frames
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Read whatever `reader` has available straight into the buffer.
+    /// Returns the bytes read, 0 at end of stream. Cancel safe
+    pub async fn read_from<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<usize> {
+        self.buffer.reserve(READ_CHUNK);
+        reader.read_buf(&mut self.buffer).await
+    }
+
+    /// Decode the next frame, if it has arrived in full
+    pub fn decode(&mut self) -> Decoded {
+        'frames: loop {
+            if self.stack.is_empty()
+                && let Some(&first) = self.buffer.first()
+                && !self.starts_frame(first)
+            {
+                match self.inline() {
+                    Ok(Some(args)) if args.is_empty() => continue,
+                    Ok(Some(args)) => return Decoded::Frame(RespValue::Array(args)),
+                    Ok(None) => return Decoded::NeedMoreData,
+                    Err(e) => return Decoded::Error(e),
+                }
+            }
+            let mut value = match self.element() {
+                Ok(Some(Element::Value(value))) => value,
+                Ok(Some(Element::Aggregate(partial))) if partial.remaining > 0 => {
+                    if self.stack.len() >= MAX_DEPTH {
+                        return Decoded::Error("Protocol error: too deeply nested".to_string());
+                    }
+                    self.stack.push(partial);
+                    continue;
+                }
+                Ok(Some(Element::Aggregate(partial))) => partial.finish(),
+                Ok(None) => return Decoded::NeedMoreData,
+                Err(e) => return Decoded::Error(e),
+            };
+            // Hand the value to its aggregate, closing those now complete
+            loop {
+                let Some(parent) = self.stack.last_mut() else {
+                    // `*0` and `*-1` carry no command
+                    if self.requests
+                        && (value == RespValue::Null || value == RespValue::Array(Vec::new()))
+                    {
+                        continue 'frames;
+                    }
+                    return Decoded::Frame(value);
+                };
+                parent.items.push(value);
+                parent.remaining -= 1;
+                if parent.remaining > 0 {
+                    break;
+                }
+                value = self.stack.pop().unwrap().finish();
+            }
+        }
+    }
+
+    /// Whether a line starting with `first` is RESP rather than inline
+    fn starts_frame(&self, first: u8) -> bool {
+        if self.requests {
+            first == b'*'
+        } else {
+            is_type_prefix(first)
+        }
+    }
+
+    /// Split off an inline command and return its arguments, empty for a
+    /// blank line; None until the whole line has arrived
+    fn inline(&mut self) -> Result<Option<Vec<RespValue>>, String> {
+        let Some(end) = find_crlf(&self.buffer) else {
+            if self.buffer.len() > MAX_LINE_LEN {
+                return Err("Protocol error: too big inline request".to_string());
+            }
+            return Ok(None);
+        };
+        let line = self.buffer.split_to(end + 2).freeze();
+        let mut args = Vec::new();
+        let mut start = None;
+        for (i, byte) in line[..end].iter().enumerate() {
+            match (byte.is_ascii_whitespace(), start) {
+                (false, None) => start = Some(i),
+                (true, Some(from)) => {
+                    args.push(RespValue::BulkString(BulkStr::from_utf8(
+                        line.slice(from..i),
+                    )));
+                    start = None;
+                }
+                _ => {}
             }
+        }
+        if let Some(from) = start {
+            args.push(RespValue::BulkString(BulkStr::from_utf8(
+                line.slice(from..end),
+            )));
+        }
+        Ok(Some(args))
+    }
 
-            let data = lines.next().ok_or("Missing bulk data")?;
-            if data.len() != _len as usize {
-                return Err("Bulk string length does not match with provided length".to_string());
+    /// Split off the element at the front of the buffer once it is complete
+    fn element(&mut self) -> Result<Option<Element>, String> {
+        let Some(end) = find_crlf(&self.buffer) else {
+            if self.buffer.len() > MAX_LINE_LEN {
+                return Err("Protocol error: too big header line".to_string());
             }
-            Ok(RespValue::BulkString(data.to_string()))
+            return Ok(None);
+        };
+        let Some(&prefix) = self.buffer.first().filter(|_| end > 0) else {
+            return Err("Protocol error: empty line".to_string());
+        };
+        if self.requests && !self.stack.is_empty() && prefix != b'$' {
+            return Err(format!(
+                "Protocol error: expected '$', got '{}'",
+                (prefix as char).escape_default()
+            ));
         }
-        '*' => {
-            // 1. Parse number of elements
-            let count: usize = line[1..].parse().map_err(|_| "Invalid array length")?;
-            let mut items = Vec::with_capacity(count);
-
-            // 2. Recursively parse each element
-            for _ in 0..count {
-                items.push(parse_recursive(lines)?);
+        let header = std::str::from_utf8(&self.buffer[1..end])
+            .map_err(|_| "Protocol error: invalid UTF-8 in header".to_string())?;
+
+        let element = match prefix {
+            b'$' | b'=' => {
+                let len: i64 = header
+                    .parse()
+                    .map_err(|_| "Protocol error: invalid bulk length".to_string())?;
+                if len == -1 && prefix == b'$' && !self.requests {
+                    self.buffer.advance(end + 2);
+                    return Ok(Some(Element::Value(RespValue::Null)));
+                }
+                let len = usize::try_from(len)
+                    .ok()
+                    .filter(|&len| len <= self.max_bulk_len)
+                    .ok_or_else(|| "Protocol error: invalid bulk length".to_string())?;
+                let data_start = end + 2;
+                let data_end = data_start + len;
+                if self.buffer.len() < data_end + 2 {
+                    return Ok(None);
+                }
+                if &self.buffer[data_end..data_end + 2] != b"\r\n" {
+                    return Err("Protocol error: bulk string longer than its length".into());
+                }
+                let chunk = self.buffer.split_to(data_end + 2).freeze();
+                let data = BulkStr::from_utf8(chunk.slice(data_start..data_end));
+                if prefix == b'$' {
+                    Element::Value(RespValue::BulkString(data))
+                } else {
+                    Element::Value(parse_verbatim(&data)?)
+                }
+            }
+            b'*' | b'%' | b'~' | b'>' => {
+                let len: i64 = header
+                    .parse()
+                    .map_err(|_| "Protocol error: invalid multibulk length".to_string())?;
+                let element = if len == -1 && prefix == b'*' {
+                    Element::Value(RespValue::Null)
+                } else {
+                    let len = usize::try_from(len)
+                        .ok()
+                        .filter(|&len| len <= MAX_MULTIBULK_LEN)
+                        .ok_or_else(|| "Protocol error: invalid multibulk length".to_string())?;
+                    let (kind, remaining) = match prefix {
+                        b'*' => (AggregateKind::Array, len),
+                        b'%' => (AggregateKind::Map, len * 2),
+                        b'~' => (AggregateKind::Set, len),
+                        _ => (AggregateKind::Push, len),
+                    };
+                    Element::Aggregate(Partial {
+                        kind,
+                        remaining,
+                        items: Vec::with_capacity(remaining.min(1024)),
+                    })
+                };
+                self.buffer.advance(end + 2);
+                element
+            }
+            _ => {
+                let value = parse_simple(prefix, header)?;
+                self.buffer.advance(end + 2);
+                Element::Value(value)
             }
+        };
+        Ok(Some(element))
+    }
+}
 
-            Ok(RespValue::Array(items))
+fn find_crlf(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(2).position(|pair| pair == b"\r\n")
+}
+
+fn is_type_prefix(byte: u8) -> bool {
+    b"+-:$*_,#(=%~>".contains(&byte)
+}
+
+/// A value that fits on its header line
+fn parse_simple(prefix: u8, text: &str) -> Result<RespValue, String> {
+    Ok(match prefix {
+        b'+' => RespValue::SimpleString(text.to_string()),
+        b'-' => RespValue::Error(text.to_string()),
+        b':' => RespValue::Integer(
+            text.parse()
+                .map_err(|_| "Protocol error: invalid integer".to_string())?,
+        ),
+        b'_' if text.is_empty() => RespValue::Null,
+        // Rust reads RESP3's `inf`, `-inf` and `nan` too
+        b',' => RespValue::Double(
+            text.parse()
+                .map_err(|_| "Protocol error: invalid double".to_string())?,
+        ),
+        b'#' => RespValue::Boolean(match text {
+            "t" => true,
+            "f" => false,
+            _ => return Err("Protocol error: invalid boolean".to_string()),
+        }),
+        b'(' => {
+            let digits = text.strip_prefix('-').unwrap_or(text);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err("Protocol error: invalid big number".to_string());
+            }
+            RespValue::BigNumber(text.to_string())
         }
-        _ => Err(format!("Unknown prefix: {}", prefix)),
+        _ => {
+            return Err(format!(
+                "Protocol error: unexpected '{}'",
+                (prefix as char).escape_default()
+            ));
+        }
+    })
+}
+
+fn parse_verbatim(data: &str) -> Result<RespValue, String> {
+    match data.split_at_checked(3) {
+        Some((format, text)) if text.starts_with(':') => Ok(RespValue::Verbatim {
+            format: format.to_string(),
+            text: text[1..].to_string(),
+        }),
+        _ => Err("Protocol error: verbatim string must start with a format like 'txt:'".into()),
     }
 }
 
 impl RespValue {
+    /// A bulk string of `s`, e.g. to map over strings
+    pub fn bulk(s: impl Into<BulkStr>) -> Self {
+        RespValue::BulkString(s.into())
+    }
+
+    /// The message of an error reply
+    pub fn error_message(&self) -> Option<&str> {
+        match self {
+            RespValue::Error(message) => Some(message),
+            _ => None,
+        }
+    }
+
+    /// Encode for a RESP2 connection
     pub fn encode(&self) -> String {
+        self.encode_for(RESP2)
+    }
+
+    /// Encode for a connection speaking protocol version `protover`
+    pub fn encode_for(&self, protover: u8) -> String {
+        let mut out = BytesMut::new();
+        self.encode_into(&mut out, protover);
+        // Every part is either a `String` or a `BulkStr`
+        String::from_utf8(out.to_vec()).expect("RESP encoding is valid UTF-8")
+    }
+
+    /// Append the encoding for a connection speaking protocol version
+    /// `protover` to `out`. RESP2 has no maps, sets, doubles or booleans,
+    /// so they are sent as the types Redis has always used for them
+    pub fn encode_into(&self, out: &mut BytesMut, protover: u8) {
+        let resp3 = protover >= RESP3;
         match self {
-            RespValue::SimpleString(s) => format!("+{}\r\n", s),
-            RespValue::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s),
-            RespValue::Array(elements) => {
-                let mut out = format!("*{}\r\n", elements.len());
+            RespValue::SimpleString(s) => put_line(out, b'+', s),
+            RespValue::Error(message) => put_line(out, b'-', message),
+            RespValue::BulkString(s) => put_bulk(out, b'$', &[s.as_bytes()]),
+            // One command's several push messages (SUBSCRIBE a b) are
+            // separate frames in RESP3
+            RespValue::Array(elements)
+                if resp3
+                    && !elements.is_empty()
+                    && elements.iter().all(|el| matches!(el, RespValue::Push(_))) =>
+            {
                 for el in elements {
-                    out.push_str(&el.encode());
+                    el.encode_into(out, protover);
+                }
+            }
+            RespValue::Array(elements) => put_aggregate(out, b'*', elements, protover),
+            RespValue::Null if resp3 => out.put_slice(b"_\r\n"),
+            RespValue::Null => out.put_slice(b"$-1\r\n"),
+            RespValue::Integer(x) => put_header(out, b':', x),
+            RespValue::Map(pairs) => {
+                if resp3 {
+                    put_header(out, b'%', pairs.len());
+                } else {
+                    put_header(out, b'*', pairs.len() * 2);
                 }
-                out
+                for (field, value) in pairs {
+                    field.encode_into(out, protover);
+                    value.encode_into(out, protover);
+                }
+            }
+            RespValue::Set(elements) if resp3 => put_aggregate(out, b'~', elements, protover),
+            RespValue::Set(elements) => put_aggregate(out, b'*', elements, protover),
+            RespValue::Double(x) if resp3 => put_line(out, b',', &format_double(*x)),
+            RespValue::Double(x) => put_bulk(out, b'$', &[format_double(*x).as_bytes()]),
+            RespValue::Boolean(b) if resp3 => out.put_slice(if *b { b"#t\r\n" } else { b"#f\r\n" }),
+            RespValue::Boolean(b) => put_header(out, b':', *b as i64),
+            RespValue::Push(elements) if resp3 => put_aggregate(out, b'>', elements, protover),
+            RespValue::Push(elements) => put_aggregate(out, b'*', elements, protover),
+            RespValue::Verbatim { format, text } if resp3 => {
+                put_bulk(out, b'=', &[format.as_bytes(), b":", text.as_bytes()])
+            }
+            RespValue::Verbatim { text, .. } => put_bulk(out, b'$', &[text.as_bytes()]),
+            RespValue::BigNumber(n) if resp3 => put_line(out, b'(', n),
+            RespValue::BigNumber(n) => put_bulk(out, b'$', &[n.as_bytes()]),
+        }
+    }
+
+    /// The same reply with RESP3-only types replaced by what RESP2 sends
+    /// for them, for callers that only understand RESP2 (scripts)
+    pub fn into_resp2(self) -> RespValue {
+        match self {
+            RespValue::Array(elements) | RespValue::Set(elements) | RespValue::Push(elements) => {
+                RespValue::Array(elements.into_iter().map(RespValue::into_resp2).collect())
             }
-            RespValue::Null => "$-1\r\n".to_string(),
-            RespValue::Integer(x) => format!(":{}\r\n", x),
+            RespValue::Map(pairs) => RespValue::Array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(field, value)| [field.into_resp2(), value.into_resp2()])
+                    .collect(),
+            ),
+            RespValue::Double(x) => RespValue::BulkString(format_double(x).into()),
+            RespValue::Boolean(b) => RespValue::Integer(b as i64),
+            RespValue::Verbatim { text, .. } => RespValue::BulkString(text.into()),
+            RespValue::BigNumber(n) => RespValue::BulkString(n.into()),
+            other => other,
         }
     }
 }
+
+/// `prefix`, `value` and `\r\n`, formatting `value` without allocating
+fn put_header(out: &mut BytesMut, prefix: u8, value: impl fmt::Display) {
+    let mut digits = [0u8; 24];
+    let mut cursor = &mut digits[..];
+    write!(cursor, "{}", value).expect("integers fit in 24 bytes");
+    let len = 24 - cursor.len();
+    out.put_u8(prefix);
+    out.put_slice(&digits[..len]);
+    out.put_slice(b"\r\n");
+}
+
+fn put_line(out: &mut BytesMut, prefix: u8, text: &str) {
+    out.put_u8(prefix);
+    out.put_slice(text.as_bytes());
+    out.put_slice(b"\r\n");
+}
+
+/// A length-prefixed string made of `parts`
+fn put_bulk(out: &mut BytesMut, prefix: u8, parts: &[&[u8]]) {
+    put_header(
+        out,
+        prefix,
+        parts.iter().map(|part| part.len()).sum::<usize>(),
+    );
+    for part in parts {
+        out.put_slice(part);
+    }
+    out.put_slice(b"\r\n");
+}
+
+fn put_aggregate(out: &mut BytesMut, prefix: u8, elements: &[RespValue], protover: u8) {
+    put_header(out, prefix, elements.len());
+    for el in elements {
+        el.encode_into(out, protover);
+    }
+}
+
+/// Doubles as replies have always formatted scores, with RESP3's spelling
+/// of infinities and NaN
+fn format_double(x: f64) -> String {
+    if x.is_nan() {
+        "nan".to_string()
+    } else {
+        x.to_string()
+    }
+}
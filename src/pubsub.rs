@@ -1,24 +1,44 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::future::poll_fn;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
+use std::task::Poll;
 use tokio::sync::broadcast;
 
+/// The namespace of a channel: SUBSCRIBE / PUBLISH channels and Redis 7
+/// shard channels (SSUBSCRIBE / SPUBLISH) are separate, so the same name
+/// in each is two different channels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChannelKind {
+    Global,
+    Shard,
+}
+
+impl ChannelKind {
+    /// The push frame kind a message on such a channel is delivered as
+    pub fn message_kind(self) -> &'static str {
+        match self {
+            ChannelKind::Global => "message",
+            ChannelKind::Shard => "smessage",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct PubSubMessage {
+    pub kind: ChannelKind,
     pub channel: String,
     pub message: String,
 }
 
-#[derive(Clone)]
-pub struct PubSubHub {
-    channels: Arc<RwLock<HashMap<String, broadcast::Sender<PubSubMessage>>>>,
-}
+type Channels = Arc<RwLock<HashMap<String, broadcast::Sender<PubSubMessage>>>>;
 
-impl Default for PubSubHub {
-    fn default() -> Self {
-        Self {
-            channels: Arc::new(RwLock::new(HashMap::new())),
-        }
-    }
+#[derive(Clone, Default)]
+pub struct PubSubHub {
+    channels: Channels,
+    shard_channels: Channels,
+    /// Messages subscribers missed because their channel's buffer filled up
+    dropped: Arc<AtomicU64>,
 }
 
 impl PubSubHub {
@@ -26,10 +46,24 @@ impl PubSubHub {
         Self::default()
     }
 
+    fn namespace(&self, kind: ChannelKind) -> &Channels {
+        match kind {
+            ChannelKind::Global => &self.channels,
+            ChannelKind::Shard => &self.shard_channels,
+        }
+    }
+
     pub fn publish(&self, channel: &str, message: String) -> usize {
-        let channels = self.channels.read().unwrap();
+        self.publish_to(ChannelKind::Global, channel, message)
+    }
+
+    /// Publish on a channel of either namespace, returning how many
+    /// subscribers it reached
+    pub fn publish_to(&self, kind: ChannelKind, channel: &str, message: String) -> usize {
+        let channels = self.namespace(kind).read().unwrap();
         if let Some(sender) = channels.get(channel) {
             let msg = PubSubMessage {
+                kind,
                 channel: channel.to_string(),
                 message,
             };
@@ -40,13 +74,22 @@ impl PubSubHub {
     }
 
     pub fn subscribe(&self, channel: &str) -> broadcast::Receiver<PubSubMessage> {
-        let mut channels = self.channels.write().unwrap();
+        self.subscribe_to(ChannelKind::Global, channel)
+    }
+
+    pub fn subscribe_to(
+        &self,
+        kind: ChannelKind,
+        channel: &str,
+    ) -> broadcast::Receiver<PubSubMessage> {
+        let mut channels = self.namespace(kind).write().unwrap();
         let sender = channels.entry(channel.to_string()).or_insert_with(|| {
             let (tx, _) = broadcast::channel(100);
             tx
         });
         sender.subscribe()
     }
+
     pub fn num_subscribers(&self, channel: &str) -> usize {
         let channels = self.channels.read().unwrap();
         if let Some(sender) = channels.get(channel) {
@@ -56,93 +99,168 @@ impl PubSubHub {
         }
     }
 
+    /// Count messages a subscriber missed
+    pub fn record_dropped(&self, messages: u64) {
+        self.dropped.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    /// Messages subscribers missed since startup
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
     pub fn cleanup_empty_channels(&self) {
-        let mut channels = self.channels.write().unwrap();
-        channels.retain(|_, sender| sender.receiver_count() > 0);
+        for kind in [ChannelKind::Global, ChannelKind::Shard] {
+            let mut channels = self.namespace(kind).write().unwrap();
+            channels.retain(|_, sender| sender.receiver_count() > 0);
+        }
     }
 }
 
+/// A connection's subscriptions. The receivers themselves belong to the
+/// connection's writer, which waits on them alongside replies: every
+/// subscribe or unsubscribe is recorded as a change for it to pick up
 pub struct ClientSubscriptions {
-    subscriptions: HashMap<String, broadcast::Receiver<PubSubMessage>>,
+    channels: HashSet<String>,
+    shard_channels: HashSet<String>,
+    /// Changes not yet taken by `take_changes`
+    changes: Vec<SubscriptionChange>,
+}
+
+/// A subscription made or dropped, see `ClientSubscriptions::take_changes`
+pub enum SubscriptionChange {
+    Subscribed(ChannelKind, String, broadcast::Receiver<PubSubMessage>),
+    Unsubscribed(ChannelKind, String),
 }
+
 impl ClientSubscriptions {
     pub fn new() -> Self {
         Self {
-            subscriptions: HashMap::new(),
+            channels: HashSet::new(),
+            shard_channels: HashSet::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    fn namespace(&mut self, kind: ChannelKind) -> &mut HashSet<String> {
+        match kind {
+            ChannelKind::Global => &mut self.channels,
+            ChannelKind::Shard => &mut self.shard_channels,
         }
     }
 
-    /// Add a subscription
-    pub fn add(&mut self, channel: String, receiver: broadcast::Receiver<PubSubMessage>) {
-        self.subscriptions.insert(channel, receiver);
+    /// Add a subscription; subscribing to a channel again changes nothing
+    pub fn add(
+        &mut self,
+        kind: ChannelKind,
+        channel: String,
+        receiver: broadcast::Receiver<PubSubMessage>,
+    ) {
+        if self.namespace(kind).insert(channel.clone()) {
+            self.changes
+                .push(SubscriptionChange::Subscribed(kind, channel, receiver));
+        }
     }
 
     /// Remove a subscription
-    pub fn remove(&mut self, channel: &str) -> bool {
-        self.subscriptions.remove(channel).is_some()
+    pub fn remove(&mut self, kind: ChannelKind, channel: &str) -> bool {
+        let removed = self.namespace(kind).remove(channel);
+        if removed {
+            self.changes
+                .push(SubscriptionChange::Unsubscribed(kind, channel.to_string()));
+        }
+        removed
     }
 
-    /// Get all subscribed channels
-    pub fn channels(&self) -> Vec<String> {
-        self.subscriptions.keys().cloned().collect()
+    /// Get all subscribed channels of a namespace
+    pub fn channels(&self, kind: ChannelKind) -> Vec<String> {
+        match kind {
+            ChannelKind::Global => self.channels.iter().cloned().collect(),
+            ChannelKind::Shard => self.shard_channels.iter().cloned().collect(),
+        }
     }
 
-    /// Check if subscribed to any channels
+    /// Check if subscribed to any channels, of either namespace
     pub fn is_subscribed(&self) -> bool {
-        !self.subscriptions.is_empty()
+        !self.channels.is_empty() || !self.shard_channels.is_empty()
     }
 
-    /// Get number of active subscriptions
-    pub fn count(&self) -> usize {
-        self.subscriptions.len()
+    /// Get number of active subscriptions in a namespace
+    pub fn count(&self, kind: ChannelKind) -> usize {
+        match kind {
+            ChannelKind::Global => self.channels.len(),
+            ChannelKind::Shard => self.shard_channels.len(),
+        }
     }
 
-    /// Try to receive a message from any subscribed channel (non-blocking)
-    pub fn try_recv(&mut self) -> Option<PubSubMessage> {
-        // Try each receiver until we get a message
-        for receiver in self.subscriptions.values_mut() {
-            match receiver.try_recv() {
-                Ok(msg) => return Some(msg),
-                Err(broadcast::error::TryRecvError::Empty) => continue,
-                Err(broadcast::error::TryRecvError::Lagged(_)) => {
-                    // Message was lost due to buffer overflow - skip
-                    continue;
-                }
-                Err(broadcast::error::TryRecvError::Closed) => {
-                    // Channel closed - should clean up, but continue for now
-                    continue;
-                }
-            }
-        }
-        None
+    /// The subscriptions made and dropped since the last call, in order
+    pub fn take_changes(&mut self) -> Vec<SubscriptionChange> {
+        std::mem::take(&mut self.changes)
     }
+}
 
-    /// Async receive from any channel
-    pub async fn recv(&mut self) -> Option<PubSubMessage> {
-        if self.subscriptions.is_empty() {
-            return None;
-        }
+impl Default for ClientSubscriptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        // Create a vec of futures from all receivers
-        let mut receivers: Vec<_> = self.subscriptions.values_mut().collect();
+/// The receivers of a connection's subscriptions, owned by its writer
+#[derive(Default)]
+pub struct SubscriptionReceivers {
+    receivers: HashMap<(ChannelKind, String), broadcast::Receiver<PubSubMessage>>,
+    /// Messages skipped since the last `take_dropped`
+    dropped: u64,
+}
 
-        if receivers.is_empty() {
-            return None;
-        }
+impl SubscriptionReceivers {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // Use select! to wait on all receivers simultaneously
-        // For simplicity, we'll just wait on the first one for now
-        // A production implementation would use FuturesUnordered
-        if let Some(receiver) = receivers.first_mut() {
-            (receiver.recv().await).ok()
-        } else {
-            None
+    pub fn apply(&mut self, change: SubscriptionChange) {
+        match change {
+            SubscriptionChange::Subscribed(kind, channel, receiver) => {
+                self.receivers.insert((kind, channel), receiver);
+            }
+            SubscriptionChange::Unsubscribed(kind, channel) => {
+                self.receivers.remove(&(kind, channel));
+            }
         }
     }
-}
 
-impl Default for ClientSubscriptions {
-    fn default() -> Self {
-        Self::new()
+    /// Messages lost to full channel buffers since the last call
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// Wait for a message on any subscribed channel; never returns while
+    /// there are none. Cancel safe: an unfinished wait loses no message
+    pub async fn recv(&mut self) -> PubSubMessage {
+        loop {
+            let mut waits: Vec<_> = self
+                .receivers
+                .iter_mut()
+                .map(|(channel, receiver)| (channel.clone(), Box::pin(receiver.recv())))
+                .collect();
+            let (channel, received) = poll_fn(|cx| {
+                for (channel, wait) in &mut waits {
+                    if let Poll::Ready(result) = wait.as_mut().poll(cx) {
+                        return Poll::Ready((channel.clone(), result));
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+            drop(waits);
+            match received {
+                Ok(message) => return message,
+                // Messages lost to a full buffer are skipped
+                Err(broadcast::error::RecvError::Lagged(skipped)) => self.dropped += skipped,
+                Err(broadcast::error::RecvError::Closed) => {
+                    self.receivers.remove(&channel);
+                }
+            }
+        }
     }
 }
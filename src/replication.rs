@@ -0,0 +1,970 @@
+use crate::aof::{AofWriter, DatabaseData};
+use crate::commands::{handle_command, spawn_aof_rewrite};
+use crate::config::{OutputBufferLimit, OutputBufferLimits, RdbCompression};
+use crate::connection::ConnectionContext;
+use crate::persistance;
+use crate::protocol::{Decoded, RespDecoder, RespValue};
+use crate::storage::FerroStore;
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::futures::Notified;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::AbortHandle;
+use tokio::time::{interval, sleep};
+
+/// Wait before reconnecting to a master after the link drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How often a replica tells its master how far it has got (REPLCONF ACK)
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+/// repl-backlog-size's default
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// Where a replica is in its synchronization, as INFO shows it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplicaState {
+    /// Attached; its snapshot hasn't been sent yet
+    WaitBgsave,
+    /// Sent its snapshot, now receiving the stream of writes
+    Online,
+}
+
+impl ReplicaState {
+    pub fn name(self) -> &'static str {
+        match self {
+            ReplicaState::WaitBgsave => "wait_bgsave",
+            ReplicaState::Online => "online",
+        }
+    }
+}
+
+/// A replica attached to this server, as INFO replication lists it
+#[derive(Clone, Debug)]
+pub struct ReplicaInfo {
+    /// Client id of its connection
+    pub id: u64,
+    pub ip: IpAddr,
+    /// The port it serves clients on (REPLCONF listening-port), 0 if unknown
+    pub port: u16,
+    pub state: ReplicaState,
+    /// Stream offset it last acknowledged (REPLCONF ACK)
+    pub ack_offset: u64,
+    /// Seconds since it last acknowledged, or attached
+    pub lag: u64,
+}
+
+struct Replica {
+    ip: IpAddr,
+    port: u16,
+    state: ReplicaState,
+    ack_offset: u64,
+    last_ack: Instant,
+    /// Writes for the replica's connection to send
+    sender: mpsc::UnboundedSender<Bytes>,
+    output: Arc<ReplicaOutput>,
+    /// Since when `output` has been over the soft limit
+    soft_since: Option<Instant>,
+}
+
+/// What a replica's writes sent to its connection amount to, shared with
+/// the connection's `ReplicaStream`
+#[derive(Default)]
+struct ReplicaOutput {
+    /// Bytes sent that the connection hasn't taken yet
+    queued: AtomicUsize,
+    /// Set when the replica was detached for going over the replica class
+    /// of `client-output-buffer-limit`
+    overflowed: AtomicBool,
+}
+
+/// Where a replica's link to its master is, as INFO shows it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    /// Connecting, or waiting to reconnect
+    Connecting,
+    /// Connected, handshaking or receiving the snapshot
+    Sync,
+    /// Loaded the snapshot, applying the master's writes
+    Connected,
+}
+
+impl LinkState {
+    pub fn name(self) -> &'static str {
+        match self {
+            LinkState::Connecting => "connecting",
+            LinkState::Sync => "sync",
+            LinkState::Connected => "connected",
+        }
+    }
+}
+
+/// The master of a replica, as INFO replication shows it
+#[derive(Clone, Debug)]
+pub struct MasterInfo {
+    pub host: String,
+    pub port: u16,
+    pub state: LinkState,
+    /// Offset in the master's stream up to which its writes are applied
+    pub offset: u64,
+    /// Offset in the master's stream up to which it has been received
+    pub read_offset: u64,
+    /// Seconds since the master last sent anything
+    pub last_io: u64,
+    /// Seconds since the link went down, if it has been up before
+    pub down_since: Option<u64>,
+}
+
+/// The replication backlog, as INFO replication shows it
+#[derive(Clone, Copy, Debug)]
+pub struct BacklogInfo {
+    /// Whether it's kept; only once a replica has attached
+    pub active: bool,
+    /// repl-backlog-size
+    pub size: usize,
+    /// Offset of the stream it starts at
+    pub start: u64,
+    /// Bytes it holds
+    pub len: usize,
+}
+
+/// The master a replica follows, and the task doing so
+struct Master {
+    host: String,
+    port: u16,
+    state: LinkState,
+    /// The master's replication ID, once synchronized with it; with
+    /// `offset`, where a partial resynchronization would resume
+    replid: Option<String>,
+    offset: u64,
+    read_offset: u64,
+    last_io: Instant,
+    down_since: Option<Instant>,
+    /// Tells this master's link task apart from those of earlier masters,
+    /// which may not have stopped yet
+    link: u64,
+    task: AbortHandle,
+}
+
+/// The replication stream: every write the server runs, in the order it
+/// ran, sent to each replica attached to the server after the snapshot it
+/// was given. Commands reach it through `AofWriter::log_command`, so
+/// replicas receive exactly what the AOF logs. A server that is itself a
+/// replica (REPLICAOF) streams the writes it applies from its master.
+///
+/// Once a replica has attached, the end of the stream is kept in a
+/// backlog, so a replica that reconnects after losing its link is sent the
+/// writes it missed (PSYNC replid offset) instead of a new snapshot
+#[derive(Clone)]
+pub struct Replication {
+    stream: Arc<Mutex<Stream>>,
+    /// Woken whenever a replica acknowledges some of the stream, for WAIT
+    acks: Arc<Notify>,
+}
+
+struct Stream {
+    /// Names this server's history of writes (master_replid)
+    replid: String,
+    /// Bytes of stream produced so far (master_repl_offset)
+    offset: u64,
+    /// Database the stream's commands run against; a SELECT precedes the
+    /// first command sent to a new replica and every change of database
+    db: Option<usize>,
+    /// The last `backlog_size` bytes of the stream, up to `offset`; None
+    /// until the first replica attaches
+    backlog: Option<VecDeque<u8>>,
+    backlog_size: usize,
+    replicas: BTreeMap<u64, Replica>,
+    /// The replica class of `client-output-buffer-limit`
+    output_limit: OutputBufferLimit,
+    /// Set while this server is a replica
+    master: Option<Master>,
+    /// Links started so far
+    links: u64,
+}
+
+impl Stream {
+    /// Add `encoded` to the stream, sending it to the replicas
+    fn append(&mut self, encoded: String) {
+        self.offset += encoded.len() as u64;
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.extend(encoded.as_bytes());
+            let excess = backlog.len().saturating_sub(self.backlog_size);
+            backlog.drain(..excess);
+        }
+        let encoded = Bytes::from(encoded);
+        let limit = self.output_limit;
+        // A replica that can't keep up is detached once the writes its
+        // connection hasn't taken go over the limit, rather than queued
+        // without end. One whose connection has gone is detached along with it
+        self.replicas.retain(|_, replica| {
+            let queued = replica
+                .output
+                .queued
+                .fetch_add(encoded.len(), Ordering::Relaxed)
+                + encoded.len();
+            if limit.exceeded(queued as u64, &mut replica.soft_since) {
+                replica.output.overflowed.store(true, Ordering::Relaxed);
+                return false;
+            }
+            let _ = replica.sender.send(encoded.clone());
+            true
+        });
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            stream: Arc::new(Mutex::new(Stream {
+                replid: new_replid(),
+                offset: 0,
+                db: None,
+                backlog: None,
+                backlog_size: DEFAULT_BACKLOG_SIZE,
+                replicas: BTreeMap::new(),
+                output_limit: OutputBufferLimits::default().replica,
+                master: None,
+                links: 0,
+            })),
+            acks: Arc::new(Notify::new()),
+        }
+    }
+}
+
+/// 40 random hex digits, like a Redis replication ID
+fn new_replid() -> String {
+    (0..40)
+        .map(|_| char::from_digit(fastrand::u32(..16), 16).unwrap())
+        .collect()
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn replid(&self) -> String {
+        self.stream.lock().unwrap().replid.clone()
+    }
+
+    /// Bytes of stream produced so far
+    pub fn offset(&self) -> u64 {
+        self.stream.lock().unwrap().offset
+    }
+
+    pub fn backlog(&self) -> BacklogInfo {
+        let stream = self.stream.lock().unwrap();
+        let len = stream.backlog.as_ref().map_or(0, VecDeque::len);
+        BacklogInfo {
+            active: stream.backlog.is_some(),
+            size: stream.backlog_size,
+            start: stream.offset - len as u64,
+            len,
+        }
+    }
+
+    /// Keep the last `size` bytes of the stream (repl-backlog-size)
+    pub fn set_backlog_size(&self, size: usize) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.backlog_size = size;
+        if let Some(backlog) = stream.backlog.as_mut() {
+            let excess = backlog.len().saturating_sub(size);
+            backlog.drain(..excess);
+        }
+    }
+
+    /// Detach replicas whose pending writes go over `limit` (the replica
+    /// class of `client-output-buffer-limit`)
+    pub fn set_output_limit(&self, limit: OutputBufferLimit) {
+        self.stream.lock().unwrap().output_limit = limit;
+    }
+
+    /// Send a write command run against database `db` to the replicas
+    pub fn feed(&self, db: usize, command: &RespValue) {
+        let mut stream = self.stream.lock().unwrap();
+        if stream.replicas.is_empty() && stream.backlog.is_none() {
+            // The next replica to attach starts with a SELECT
+            stream.db = None;
+            return;
+        }
+        let mut encoded = String::new();
+        if stream.db != Some(db) {
+            encoded = RespValue::Array(vec![
+                RespValue::BulkString("SELECT".into()),
+                RespValue::BulkString(db.to_string().into()),
+            ])
+            .encode();
+            stream.db = Some(db);
+        }
+        encoded.push_str(&command.encode());
+        stream.append(encoded);
+    }
+
+    /// Ask every replica to acknowledge how far it has got right away
+    /// rather than within the next second (REPLCONF GETACK *)
+    pub fn request_acks(&self) {
+        let mut stream = self.stream.lock().unwrap();
+        if !stream.replicas.is_empty() {
+            stream.append(command(&["REPLCONF", "GETACK", "*"]).encode());
+        }
+    }
+
+    /// Replicas that have acknowledged the stream up to `offset`
+    pub fn acked(&self, offset: u64) -> usize {
+        let stream = self.stream.lock().unwrap();
+        stream
+            .replicas
+            .values()
+            .filter(|replica| replica.ack_offset >= offset)
+            .count()
+    }
+
+    /// Resolves at the next acknowledgement from a replica. Enable it before
+    /// checking `acked` so that none is missed in between
+    pub fn next_ack(&self) -> Notified<'_> {
+        self.acks.notified()
+    }
+
+    /// Attach client `id` as a replica (SYNC / PSYNC) and prepare what it
+    /// is sent: `preamble`, then `databases` as an RDB payload, then every
+    /// write fed from now on. The dataset must be captured with no command
+    /// running, so each write is either in the snapshot or in the stream
+    pub fn attach(
+        &self,
+        id: u64,
+        ip: IpAddr,
+        port: u16,
+        preamble: String,
+        databases: Vec<(usize, DatabaseData)>,
+        codec: RdbCompression,
+    ) -> ReplicaLink {
+        let mut stream = self.stream.lock().unwrap();
+        stream.db = None;
+        stream.backlog.get_or_insert_with(VecDeque::new);
+        let resync = Resync::Full(Snapshot {
+            preamble,
+            databases,
+            codec,
+        });
+        self.add_replica(&mut stream, id, ip, port, 0, resync)
+    }
+
+    /// Attach client `id` as a replica that has the stream of history
+    /// `replid` up to `offset` (PSYNC replid offset+1), if the backlog holds
+    /// everything after that. It's sent +CONTINUE and the writes it missed,
+    /// then every write fed from now on
+    pub fn resume(
+        &self,
+        id: u64,
+        ip: IpAddr,
+        port: u16,
+        replid: &str,
+        offset: u64,
+    ) -> Option<ReplicaLink> {
+        let mut stream = self.stream.lock().unwrap();
+        let backlog = stream.backlog.as_ref()?;
+        let start = stream.offset - backlog.len() as u64;
+        if replid != stream.replid || offset < start || offset > stream.offset {
+            return None;
+        }
+        let mut payload = format!("+CONTINUE {}\r\n", stream.replid).into_bytes();
+        payload.extend(backlog.range((offset - start) as usize..));
+        let resync = Resync::Partial(payload.into());
+        Some(self.add_replica(&mut stream, id, ip, port, offset, resync))
+    }
+
+    /// Attach a replica known to have the stream up to `ack_offset`
+    fn add_replica(
+        &self,
+        stream: &mut Stream,
+        id: u64,
+        ip: IpAddr,
+        port: u16,
+        ack_offset: u64,
+        resync: Resync,
+    ) -> ReplicaLink {
+        let (sender, updates) = mpsc::unbounded_channel();
+        let output = Arc::new(ReplicaOutput::default());
+        stream.replicas.insert(
+            id,
+            Replica {
+                ip,
+                port,
+                state: ReplicaState::WaitBgsave,
+                ack_offset,
+                last_ack: Instant::now(),
+                sender,
+                output: output.clone(),
+                soft_since: None,
+            },
+        );
+        ReplicaLink {
+            id,
+            replication: self.clone(),
+            stream: Some(ReplicaStream {
+                id,
+                replication: self.clone(),
+                resync: Some(resync),
+                online: false,
+                updates,
+                output,
+            }),
+        }
+    }
+
+    /// Start a new history, as the dataset has been replaced with a
+    /// master's: replicas can no longer resume from the backlog
+    fn new_history(&self) {
+        let mut stream = self.stream.lock().unwrap();
+        stream.replid = new_replid();
+        stream.db = None;
+        if let Some(backlog) = stream.backlog.as_mut() {
+            backlog.clear();
+        }
+    }
+
+    /// Record that replica `id` has processed the stream up to `offset`
+    pub fn ack(&self, id: u64, offset: u64) {
+        let mut stream = self.stream.lock().unwrap();
+        if let Some(replica) = stream.replicas.get_mut(&id) {
+            replica.ack_offset = replica.ack_offset.max(offset);
+            replica.last_ack = Instant::now();
+            self.acks.notify_waiters();
+        }
+    }
+
+    /// Attached replicas, by client id
+    pub fn replicas(&self) -> Vec<ReplicaInfo> {
+        let stream = self.stream.lock().unwrap();
+        stream
+            .replicas
+            .iter()
+            .map(|(&id, replica)| ReplicaInfo {
+                id,
+                ip: replica.ip,
+                port: replica.port,
+                state: replica.state,
+                ack_offset: replica.ack_offset,
+                lag: replica.last_ack.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    /// Whether this server replicates a master (REPLICAOF)
+    pub fn is_replica(&self) -> bool {
+        self.stream.lock().unwrap().master.is_some()
+    }
+
+    /// The master this server replicates, if any
+    pub fn master(&self) -> Option<MasterInfo> {
+        let stream = self.stream.lock().unwrap();
+        stream.master.as_ref().map(|master| MasterInfo {
+            host: master.host.clone(),
+            port: master.port,
+            state: master.state,
+            offset: master.offset,
+            read_offset: master.read_offset,
+            last_io: master.last_io.elapsed().as_secs(),
+            down_since: master.down_since.map(|since| since.elapsed().as_secs()),
+        })
+    }
+
+    /// Become a replica of the master at `host:port`, dropping the link to
+    /// the current one if any: connect to it (again whenever the link
+    /// drops), replace the dataset with its snapshot, then apply its writes
+    /// through `store`, logging them to `aof`
+    pub fn replicate(&self, store: &FerroStore, aof: Option<AofWriter>, host: String, port: u16) {
+        let mut stream = self.stream.lock().unwrap();
+        if let Some(master) = stream.master.take() {
+            master.task.abort();
+        }
+        stream.links += 1;
+        let link = stream.links;
+        let task = tokio::spawn(follow(
+            self.clone(),
+            link,
+            store.clone(),
+            aof,
+            host.clone(),
+            port,
+        ));
+        stream.master = Some(Master {
+            host,
+            port,
+            state: LinkState::Connecting,
+            replid: None,
+            offset: 0,
+            read_offset: 0,
+            last_io: Instant::now(),
+            down_since: None,
+            link,
+            task: task.abort_handle(),
+        });
+    }
+
+    /// Stop replicating (REPLICAOF NO ONE), keeping the dataset. False if
+    /// this server wasn't a replica
+    pub fn promote(&self) -> bool {
+        match self.stream.lock().unwrap().master.take() {
+            Some(master) => {
+                master.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The master's replication ID and the offset applied up to, if link
+    /// `link` has synchronized with it
+    fn resume_point(&self, link: u64) -> Option<(String, u64)> {
+        let stream = self.stream.lock().unwrap();
+        let master = stream
+            .master
+            .as_ref()
+            .filter(|master| master.link == link)?;
+        Some((master.replid.clone()?, master.offset))
+    }
+
+    /// Update the master followed by link `link`, unless it has been replaced
+    fn update_master(&self, link: u64, update: impl FnOnce(&mut Master)) {
+        let mut stream = self.stream.lock().unwrap();
+        if let Some(master) = stream.master.as_mut()
+            && master.link == link
+        {
+            update(master);
+        }
+    }
+
+    fn set_online(&self, id: u64) {
+        if let Some(replica) = self.stream.lock().unwrap().replicas.get_mut(&id) {
+            replica.state = ReplicaState::Online;
+        }
+    }
+
+    fn detach(&self, id: u64) {
+        self.stream.lock().unwrap().replicas.remove(&id);
+    }
+}
+
+/// A connection's attachment as a replica. Dropped with the connection,
+/// detaching the replica
+pub struct ReplicaLink {
+    id: u64,
+    replication: Replication,
+    stream: Option<ReplicaStream>,
+}
+
+impl ReplicaLink {
+    /// What the connection is to send the replica, the first time it's asked
+    pub fn take_stream(&mut self) -> Option<ReplicaStream> {
+        self.stream.take()
+    }
+}
+
+impl Drop for ReplicaLink {
+    fn drop(&mut self) {
+        self.replication.detach(self.id);
+    }
+}
+
+/// What a replica is sent first
+enum Resync {
+    Full(Snapshot),
+    /// +CONTINUE and the writes it missed
+    Partial(Bytes),
+}
+
+struct Snapshot {
+    /// The reply to SYNC / PSYNC
+    preamble: String,
+    databases: Vec<(usize, DatabaseData)>,
+    codec: RdbCompression,
+}
+
+/// What a replica is sent, in order
+pub struct ReplicaStream {
+    id: u64,
+    replication: Replication,
+    /// Taken once sent
+    resync: Option<Resync>,
+    online: bool,
+    updates: mpsc::UnboundedReceiver<Bytes>,
+    output: Arc<ReplicaOutput>,
+}
+
+impl ReplicaStream {
+    /// The next bytes to send: the preamble and snapshot, encoded as an RDB
+    /// file in a bulk string without the trailing CRLF (as Redis sends it),
+    /// or +CONTINUE and the missed writes, then the writes as they're fed.
+    /// None once the replica is detached
+    pub async fn next(&mut self) -> Option<Bytes> {
+        if self.overflowed() {
+            return None;
+        }
+        match self.resync.take() {
+            Some(Resync::Full(snapshot)) => {
+                let rdb = persistance::rdb_bytes(&snapshot.databases, snapshot.codec);
+                let mut payload = format!("{}${}\r\n", snapshot.preamble, rdb.len()).into_bytes();
+                payload.extend_from_slice(&rdb);
+                return Some(payload.into());
+            }
+            Some(Resync::Partial(payload)) => return Some(payload),
+            None => {}
+        }
+        if !self.online {
+            self.online = true;
+            self.replication.set_online(self.id);
+        }
+        let bytes = self.updates.recv().await?;
+        self.output.queued.fetch_sub(bytes.len(), Ordering::Relaxed);
+        (!self.overflowed()).then_some(bytes)
+    }
+
+    /// The client id of the replica's connection
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Whether the replica was detached for going over its output buffer
+    /// limit, so its connection is to be closed
+    pub fn overflowed(&self) -> bool {
+        self.output.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of writes waiting to be taken by `next`
+    pub fn queued(&self) -> usize {
+        self.output.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Follow the master at `host:port` until aborted, reconnecting after the
+/// link drops
+async fn follow(
+    replication: Replication,
+    link: u64,
+    store: FerroStore,
+    aof: Option<AofWriter>,
+    host: String,
+    port: u16,
+) {
+    // Kept from one connection to the next, so after a partial
+    // resynchronization the writes carry on in the database (and
+    // transaction) the stream left off in
+    let mut conn = ConnectionContext::new();
+    loop {
+        println!("Connecting to master {}:{}", host, port);
+        let result = sync_with_master(
+            &replication,
+            link,
+            &store,
+            aof.as_ref(),
+            &mut conn,
+            &host,
+            port,
+        )
+        .await;
+        if let Err(e) = result {
+            eprintln!("Link to master {}:{} lost: {}", host, port, e);
+        }
+        replication.update_master(link, |master| {
+            if master.state == LinkState::Connected {
+                master.down_since = Some(Instant::now());
+            }
+            master.state = LinkState::Connecting;
+        });
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// One connection to the master: handshake, resume from where the last
+/// connection left off or else load its snapshot, then apply its writes
+/// until the connection fails
+async fn sync_with_master(
+    replication: &Replication,
+    link: u64,
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    conn: &mut ConnectionContext,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    let mut master = MasterConnection {
+        socket: TcpStream::connect((host, port)).await?,
+        buffer: BytesMut::new(),
+    };
+    replication.update_master(link, |master| {
+        master.state = LinkState::Sync;
+        master.last_io = Instant::now();
+    });
+
+    let (masterauth, listening_port) = {
+        let config = store.config().read();
+        (config.masterauth.clone(), config.port)
+    };
+    if !masterauth.is_empty() {
+        expect_ok(master.request(&["AUTH", &masterauth]).await?, "AUTH")?;
+    }
+    let pong = master.request(&["PING"]).await?;
+    if pong.starts_with('-') {
+        return Err(io::Error::other(format!(
+            "master replied to PING with {}",
+            pong
+        )));
+    }
+    // Older masters may not know these; they're only informative
+    master
+        .request(&["REPLCONF", "listening-port", &listening_port.to_string()])
+        .await?;
+    master.request(&["REPLCONF", "capa", "psync2"]).await?;
+
+    let resume = replication.resume_point(link);
+    let reply = match &resume {
+        Some((replid, offset)) => {
+            let next = (offset + 1).to_string();
+            master.request(&["PSYNC", replid, &next]).await?
+        }
+        None => master.request(&["PSYNC", "?", "-1"]).await?,
+    };
+    let unexpected = || io::Error::other(format!("master replied to PSYNC with {}", reply));
+    let (replid, offset) = if let Some(new_replid) = reply.strip_prefix("+CONTINUE") {
+        let (replid, offset) = resume.ok_or_else(unexpected)?;
+        println!(
+            "Partial resync with master {}:{} from offset {}",
+            host, port, offset
+        );
+        // A master names a new history after a failover
+        match new_replid.trim() {
+            "" => (replid, offset),
+            new_replid => (new_replid.to_string(), offset),
+        }
+    } else {
+        let mut fields = reply
+            .strip_prefix("+FULLRESYNC ")
+            .ok_or_else(unexpected)?
+            .split_whitespace();
+        let (Some(replid), Some(Ok(offset))) = (fields.next(), fields.next().map(str::parse))
+        else {
+            return Err(unexpected());
+        };
+        let replid = replid.to_string();
+        let rdb = master.read_payload().await?;
+        let keys = load_snapshot(store, aof, &rdb).await?;
+        println!(
+            "Full resync from master {}:{}: loaded {} keys",
+            host, port, keys
+        );
+        *conn = ConnectionContext::new();
+        (replid, offset)
+    };
+    // Writes may have come in along with the reply or the snapshot
+    let read_offset = offset + master.buffer.len() as u64;
+    replication.update_master(link, |master| {
+        master.state = LinkState::Connected;
+        master.replid = Some(replid);
+        master.offset = offset;
+        master.read_offset = read_offset;
+        master.down_since = None;
+        master.last_io = Instant::now();
+    });
+
+    // The master's writes, run like a client's but with no replies
+    let mut decoder = RespDecoder::for_requests();
+    decoder.extend(&master.buffer);
+    let MasterConnection { mut socket, .. } = master;
+    let mut received = read_offset;
+    let mut applied = offset;
+    let mut ack = interval(ACK_PERIOD);
+    loop {
+        loop {
+            match decoder.decode() {
+                Decoded::Frame(request) => {
+                    // Answered at once, with what it adds to the stream
+                    let getack = is_getack(&request);
+                    if !getack {
+                        handle_command(request, store, aof, Some(&mut *conn)).await;
+                    }
+                    applied = received - decoder.buffered() as u64;
+                    replication.update_master(link, |master| master.offset = applied);
+                    if getack {
+                        let ack = command(&["REPLCONF", "ACK", &applied.to_string()]);
+                        socket.write_all(ack.encode().as_bytes()).await?;
+                    }
+                }
+                Decoded::NeedMoreData => break,
+                Decoded::Error(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+        let read = tokio::select! {
+            read = decoder.read_from(&mut socket) => Some(read?),
+            _ = ack.tick() => None,
+        };
+        match read {
+            Some(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "master closed the connection",
+                ));
+            }
+            Some(n) => {
+                received += n as u64;
+                replication.update_master(link, |master| {
+                    master.read_offset = received;
+                    master.last_io = Instant::now();
+                });
+            }
+            None => {
+                let ack = command(&["REPLCONF", "ACK", &applied.to_string()]);
+                socket.write_all(ack.encode().as_bytes()).await?;
+            }
+        }
+    }
+}
+
+fn command(args: &[&str]) -> RespValue {
+    RespValue::Array(
+        args.iter()
+            .map(|arg| RespValue::BulkString(arg.to_string().into()))
+            .collect(),
+    )
+}
+
+/// Whether `request` is the master asking for an acknowledgement
+fn is_getack(request: &RespValue) -> bool {
+    let RespValue::Array(args) = request else {
+        return false;
+    };
+    matches!(
+        &args[..],
+        [RespValue::BulkString(name), RespValue::BulkString(option), ..]
+            if name.eq_ignore_ascii_case("REPLCONF") && option.eq_ignore_ascii_case("GETACK")
+    )
+}
+
+fn expect_ok(reply: String, request: &str) -> io::Result<()> {
+    match reply.as_str() {
+        "+OK" => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "master replied to {} with {}",
+            request, reply
+        ))),
+    }
+}
+
+/// The connection to the master up to the end of its snapshot, read a
+/// line or a payload at a time
+struct MasterConnection {
+    socket: TcpStream,
+    /// Bytes received and not read yet
+    buffer: BytesMut,
+}
+
+impl MasterConnection {
+    /// Send a command and read the one-line reply
+    async fn request(&mut self, args: &[&str]) -> io::Result<String> {
+        self.socket
+            .write_all(command(args).encode().as_bytes())
+            .await?;
+        self.read_line().await
+    }
+
+    async fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buffer.split_to(end + 2);
+                return Ok(String::from_utf8_lossy(&line[..end]).into_owned());
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// The snapshot: a bulk string header, then as many bytes as it says
+    /// with no CRLF after them
+    async fn read_payload(&mut self) -> io::Result<Bytes> {
+        // Masters send newlines to keep the link alive while they prepare it
+        let header = loop {
+            while self.buffer.first() == Some(&b'\n') {
+                self.buffer.advance(1);
+            }
+            if !self.buffer.is_empty() {
+                break self.read_line().await?;
+            }
+            self.fill().await?;
+        };
+        let len = header
+            .strip_prefix('$')
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| {
+                io::Error::other(format!("expected the snapshot from master, got {}", header))
+            })?;
+        while self.buffer.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buffer.split_to(len).freeze())
+    }
+
+    async fn fill(&mut self) -> io::Result<()> {
+        if self.socket.read_buf(&mut self.buffer).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "master closed the connection",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Replace the dataset with the master's snapshot, returning how many keys
+/// it holds. Replicas of this server are disconnected and the backlog
+/// dropped, so they resync from the new dataset, and the AOF is rewritten
+/// from it
+async fn load_snapshot(
+    store: &FerroStore,
+    aof: Option<&AofWriter>,
+    rdb: &[u8],
+) -> io::Result<usize> {
+    if !persistance::is_rdb(rdb) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the master's snapshot isn't in FerroDB's RDB format",
+        ));
+    }
+    // Checked in full before anything is replaced
+    let mut entries = Vec::new();
+    persistance::read_rdb(rdb, |index, key, data, expiry| {
+        entries.push((index, key, data, expiry));
+        Ok(())
+    })?;
+    let keys = entries.len();
+
+    let _exclusive = store.exec_lock().write().await;
+    store.flush_all();
+    let mut db = store.database(0).unwrap();
+    for (index, key, data, expiry) in entries {
+        if db.db_index() != index {
+            db = store
+                .database(index)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        db.load_entry(key, data, expiry);
+    }
+    for replica in store.replication().replicas() {
+        store.clients().kill(replica.id);
+    }
+    store.replication().new_history();
+    if let Some(aof) = aof
+        && aof.is_enabled()
+    {
+        if aof.start_rewrite() {
+            spawn_aof_rewrite(store, aof);
+        } else {
+            eprintln!("Can't rewrite the AOF after a full resync: a rewrite is already running");
+        }
+    }
+    Ok(keys)
+}
@@ -0,0 +1,319 @@
+use crate::protocol::RespValue;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Lua instructions a script runs between checks for SCRIPT KILL and
+/// `lua-time-limit`
+const HOOK_INTERVAL: u32 = 1000;
+
+const KILLED: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+/// Scripts loaded with SCRIPT LOAD or run with EVAL, addressed by SHA1,
+/// and the one being run, if any
+#[derive(Clone, Default)]
+pub struct ScriptCache {
+    scripts: Arc<RwLock<HashMap<String, String>>>,
+    running: Arc<Running>,
+}
+
+/// The script being run. Scripts hold the exec lock, so only one runs at
+/// a time
+#[derive(Default)]
+struct Running {
+    active: AtomicBool,
+    /// Set once it has run past `lua-time-limit`, when other clients are
+    /// told BUSY instead of waiting for it
+    busy: watch::Sender<bool>,
+    /// Set by SCRIPT KILL
+    kill: AtomicBool,
+    /// Whether it has run a write command, after which it can't be killed
+    /// without leaving the dataset half changed
+    wrote: AtomicBool,
+}
+
+impl ScriptCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cache `body` and return its SHA1 digest
+    pub fn load(&self, body: &str) -> String {
+        let sha = sha1_hex(body);
+        let mut scripts = self.scripts.write().unwrap();
+        scripts
+            .entry(sha.clone())
+            .or_insert_with(|| body.to_string());
+        sha
+    }
+
+    pub fn get(&self, sha: &str) -> Option<String> {
+        let scripts = self.scripts.read().unwrap();
+        scripts.get(&sha.to_lowercase()).cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        let scripts = self.scripts.read().unwrap();
+        scripts.contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.scripts.write().unwrap().clear();
+    }
+
+    /// Whether a script has run past `lua-time-limit` and is still running
+    pub fn busy(&self) -> bool {
+        *self.running.busy.borrow()
+    }
+
+    /// Completes once a script has run past `lua-time-limit`
+    pub async fn until_busy(&self) {
+        let mut busy = self.running.busy.subscribe();
+        // The sender lives as long as the cache
+        let _ = busy.wait_for(|busy| *busy).await;
+    }
+
+    /// Note that the running script is about to write, so SCRIPT KILL can
+    /// no longer stop it
+    pub fn mark_write(&self) {
+        self.running.wrote.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop the running script at its next check (SCRIPT KILL)
+    pub fn kill(&self) -> Result<(), String> {
+        let running = &self.running;
+        if !running.active.load(Ordering::Relaxed) {
+            return Err("NOTBUSY No scripts in execution right now.".to_string());
+        }
+        if running.wrote.load(Ordering::Relaxed) {
+            return Err("UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or restart the server.".to_string());
+        }
+        running.kill.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Running {
+    fn start(&self) {
+        self.kill.store(false, Ordering::Relaxed);
+        self.wrote.store(false, Ordering::Relaxed);
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the script was killed
+    fn finish(&self) -> bool {
+        self.active.store(false, Ordering::Relaxed);
+        self.busy.send_replace(false);
+        self.kill.swap(false, Ordering::Relaxed)
+    }
+
+    /// Called every `HOOK_INTERVAL` instructions of a script started at
+    /// `started`: fails once it is killed
+    fn check(&self, started: Instant, time_limit: Duration) -> mlua::Result<()> {
+        if self.kill.load(Ordering::Relaxed) {
+            return Err(mlua::Error::RuntimeError(KILLED.to_string()));
+        }
+        let busy = *self.busy.borrow();
+        if !busy && started.elapsed() > time_limit {
+            self.busy.send_replace(true);
+        }
+        Ok(())
+    }
+}
+
+pub fn sha1_hex(body: &str) -> String {
+    Sha1::digest(body.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Run a Lua script with the `KEYS` and `ARGV` globals set
+///
+/// `redis.call` and `redis.pcall` hand their arguments to `call` as a command
+/// array. Each script gets a fresh interpreter with only the table, string
+/// and math libraries, so scripts can't touch the filesystem or leak state
+/// into each other. Nothing bounds how long a script runs, but once it has
+/// run for `time_limit` other clients get BUSY (see `busy`), and SCRIPT
+/// KILL can stop it unless it has written.
+pub fn eval(
+    cache: &ScriptCache,
+    body: &str,
+    keys: &[String],
+    args: &[String],
+    time_limit: Duration,
+    call: &dyn Fn(Vec<RespValue>) -> RespValue,
+) -> RespValue {
+    let lua = match Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    ) {
+        Ok(lua) => lua,
+        Err(e) => return RespValue::Error(format!("ERR {}", e)),
+    };
+    let running = cache.running.clone();
+    let started = Instant::now();
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+        move |_, _| running.check(started, time_limit),
+    );
+    cache.running.start();
+
+    let result = lua.scope(|scope| {
+        let redis = lua.create_table()?;
+        redis.set(
+            "call",
+            scope.create_function(|lua, args: Variadic<Value>| {
+                let reply = call(lua_to_command(args)?);
+                // A failed redis.call aborts the script with the command's error
+                if let Some(message) = reply.error_message() {
+                    return Err(mlua::Error::RuntimeError(message.to_string()));
+                }
+                resp_to_lua(lua, reply)
+            })?,
+        )?;
+        redis.set(
+            "pcall",
+            scope.create_function(|lua, args: Variadic<Value>| {
+                let reply = match lua_to_command(args) {
+                    Ok(command) => call(command),
+                    Err(e) => RespValue::Error(e.to_string()),
+                };
+                resp_to_lua(lua, reply)
+            })?,
+        )?;
+        redis.set(
+            "status_reply",
+            lua.create_function(|lua, status: String| reply_table(lua, "ok", status))?,
+        )?;
+        redis.set(
+            "error_reply",
+            lua.create_function(|lua, error: String| reply_table(lua, "err", error))?,
+        )?;
+        redis.set(
+            "sha1hex",
+            lua.create_function(|_, body: String| Ok(sha1_hex(&body)))?,
+        )?;
+
+        let globals = lua.globals();
+        globals.set("redis", redis)?;
+        globals.set("KEYS", keys)?;
+        globals.set("ARGV", args)?;
+
+        let value: Value = lua.load(body).set_name("@user_script").eval()?;
+        Ok(lua_to_resp(value))
+    });
+
+    if cache.running.finish() {
+        return RespValue::Error(KILLED.to_string());
+    }
+    match result {
+        Ok(reply) => reply,
+        Err(e) => script_error(&e),
+    }
+}
+
+/// Error reply for a failed script. Errors raised by redis.call are passed
+/// through unchanged; anything else is reported as a script failure
+fn script_error(error: &mlua::Error) -> RespValue {
+    if let mlua::Error::CallbackError { cause, .. } = error {
+        let mut cause = cause.as_ref();
+        while let mlua::Error::CallbackError { cause: inner, .. } = cause {
+            cause = inner.as_ref();
+        }
+        if let mlua::Error::RuntimeError(message) = cause {
+            return RespValue::Error(message.clone());
+        }
+    }
+    RespValue::Error(format!("ERR Error running script: {}", error))
+}
+
+fn reply_table<'lua>(lua: &'lua Lua, field: &str, message: String) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(field, message)?;
+    Ok(table)
+}
+
+fn lua_to_command(args: Variadic<Value>) -> mlua::Result<Vec<RespValue>> {
+    if args.is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "ERR Please specify at least one argument for this redis lib call".to_string(),
+        ));
+    }
+    args.iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(RespValue::BulkString(
+                s.to_string_lossy().into_owned().into(),
+            )),
+            Value::Integer(i) => Ok(RespValue::BulkString(i.to_string().into())),
+            Value::Number(n) if n.fract() == 0.0 => {
+                Ok(RespValue::BulkString(((*n as i64).to_string()).into()))
+            }
+            Value::Number(n) => Ok(RespValue::BulkString(n.to_string().into())),
+            _ => Err(mlua::Error::RuntimeError(
+                "ERR Lua redis lib command arguments must be strings or integers".to_string(),
+            )),
+        })
+        .collect()
+}
+
+/// Convert a command reply for Lua: bulk strings become strings, integers
+/// numbers, arrays tables, Null false, and status/error replies
+/// `{ok = ...}` / `{err = ...}` tables
+fn resp_to_lua(lua: &Lua, reply: RespValue) -> mlua::Result<Value<'_>> {
+    Ok(match reply {
+        RespValue::Integer(i) => Value::Integer(i),
+        RespValue::BulkString(s) => Value::String(lua.create_string(s.as_bytes())?),
+        RespValue::Null => Value::Boolean(false),
+        RespValue::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for item in items {
+                table.raw_push(resp_to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        RespValue::SimpleString(s) => Value::Table(reply_table(lua, "ok", s)?),
+        RespValue::Error(message) => Value::Table(reply_table(lua, "err", message)?),
+        // Scripts see replies as a RESP2 client would
+        reply @ (RespValue::Map(_)
+        | RespValue::Set(_)
+        | RespValue::Double(_)
+        | RespValue::Boolean(_)
+        | RespValue::Push(_)
+        | RespValue::Verbatim { .. }
+        | RespValue::BigNumber(_)) => return resp_to_lua(lua, reply.into_resp2()),
+    })
+}
+
+/// Convert a script's return value into a reply, with the same conventions
+/// as `resp_to_lua` in reverse. Numbers are truncated to integers and a
+/// table is read as an array up to its first nil
+fn lua_to_resp(value: Value) -> RespValue {
+    match value {
+        Value::Integer(i) => RespValue::Integer(i),
+        Value::Number(n) => RespValue::Integer(n as i64),
+        Value::String(s) => RespValue::BulkString(s.to_string_lossy().into_owned().into()),
+        Value::Boolean(true) => RespValue::Integer(1),
+        Value::Table(table) => {
+            if let Ok(Value::String(status)) = table.raw_get::<_, Value>("ok") {
+                return RespValue::SimpleString(status.to_string_lossy().into_owned());
+            }
+            if let Ok(Value::String(error)) = table.raw_get::<_, Value>("err") {
+                return RespValue::Error(error.to_string_lossy().into_owned());
+            }
+            let mut items = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, Value>(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(item) => items.push(lua_to_resp(item)),
+                }
+            }
+            RespValue::Array(items)
+        }
+        _ => RespValue::Null,
+    }
+}
@@ -0,0 +1,333 @@
+/// Skip list ordered by (score, member), used as the sorted set index
+///
+/// Every forward link records its span (how many elements it jumps over), the
+/// same trick Redis uses, so rank lookups and "how many elements come before
+/// X" queries are O(log n) instead of walking the list. Nodes live in an arena
+/// (`Vec<Node>`) and link to each other by index; freed slots are reused.
+const MAX_LEVEL: usize = 32;
+
+/// Index of the head sentinel in the arena
+const HEAD: usize = 0;
+
+#[derive(Clone, Debug)]
+struct Level {
+    forward: Option<usize>,
+    span: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    member: String,
+    score: f64,
+    levels: Vec<Level>,
+    backward: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    tail: Option<usize>,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList::new()
+    }
+}
+
+/// Whether (score_a, member_a) sorts strictly before (score_b, member_b)
+fn precedes(score_a: f64, member_a: &str, score_b: f64, member_b: &str) -> bool {
+    score_a < score_b || (score_a == score_b && member_a < member_b)
+}
+
+/// Random level with a 1/4 chance of growing at each step
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && fastrand::u32(..4) == 0 {
+        level += 1;
+    }
+    level
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        let head = Node {
+            member: String::new(),
+            score: f64::NEG_INFINITY,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                MAX_LEVEL
+            ],
+            backward: None,
+        };
+        Self {
+            nodes: vec![head],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            tail: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Approximate heap bytes: the arena, plus each node's member and
+    /// levels extrapolated from the first `samples` elements (all for 0)
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        let arena = self.nodes.capacity() * size_of::<Node>()
+            + self.free.capacity() * size_of::<usize>()
+            + MAX_LEVEL * size_of::<Level>();
+        let take = if samples == 0 {
+            self.len
+        } else {
+            samples.min(self.len)
+        };
+        if take == 0 {
+            return arena;
+        }
+        let mut sampled = 0;
+        let mut next = self.nodes[HEAD].levels[0].forward;
+        for _ in 0..take {
+            let Some(index) = next else {
+                break;
+            };
+            let node = &self.nodes[index];
+            sampled += node.member.capacity() + node.levels.capacity() * size_of::<Level>();
+            next = node.levels[0].forward;
+        }
+        arena + sampled * self.len / take
+    }
+
+    /// Insert a new element. The caller guarantees (score, member) isn't present
+    pub fn insert(&mut self, member: String, score: f64) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0usize; MAX_LEVEL];
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                let node = &self.nodes[next];
+                if !precedes(node.score, &node.member, score, &member) {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let new = self.alloc(Node {
+            member,
+            score,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                level
+            ],
+            backward: None,
+        });
+
+        for i in 0..level {
+            let prev = update[i];
+            let jumped = rank[0] - rank[i];
+            self.nodes[new].levels[i].forward = self.nodes[prev].levels[i].forward;
+            self.nodes[new].levels[i].span = self.nodes[prev].levels[i].span - jumped;
+            self.nodes[prev].levels[i].forward = Some(new);
+            self.nodes[prev].levels[i].span = jumped + 1;
+        }
+        // Links above the new node's height now jump over one more element
+        for (i, &prev) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[prev].levels[i].span += 1;
+        }
+
+        self.nodes[new].backward = (update[0] != HEAD).then_some(update[0]);
+        match self.nodes[new].levels[0].forward {
+            Some(next) => self.nodes[next].backward = Some(new),
+            None => self.tail = Some(new),
+        }
+        self.len += 1;
+    }
+
+    /// Remove an element, returning whether it was present
+    pub fn remove(&mut self, member: &str, score: f64) -> bool {
+        let mut update = [HEAD; MAX_LEVEL];
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                let node = &self.nodes[next];
+                if !precedes(node.score, &node.member, score, member) {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let Some(target) = self.nodes[update[0]].levels[0].forward else {
+            return false;
+        };
+        if self.nodes[target].score != score || self.nodes[target].member != member {
+            return false;
+        }
+
+        for (i, &prev) in update.iter().enumerate().take(self.level) {
+            if self.nodes[prev].levels[i].forward == Some(target) {
+                let target_level = self.nodes[target].levels[i].clone();
+                self.nodes[prev].levels[i].span += target_level.span;
+                self.nodes[prev].levels[i].span -= 1;
+                self.nodes[prev].levels[i].forward = target_level.forward;
+            } else {
+                self.nodes[prev].levels[i].span -= 1;
+            }
+        }
+
+        let backward = self.nodes[target].backward;
+        match self.nodes[target].levels[0].forward {
+            Some(next) => self.nodes[next].backward = backward,
+            None => self.tail = backward,
+        }
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+
+        // Release the member's memory now rather than when the slot is reused
+        self.nodes[target].member = String::new();
+        self.nodes[target].levels = Vec::new();
+        self.free.push(target);
+        self.len -= 1;
+        true
+    }
+
+    /// Number of leading elements for which `before(member, score)` holds.
+    /// `before` must be true for a prefix of the list and false afterwards,
+    /// e.g. "score < 5" or "(score, member) < (s, m)".
+    pub fn count_while(&self, before: impl Fn(&str, f64) -> bool) -> usize {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                let node = &self.nodes[next];
+                if !before(&node.member, node.score) {
+                    break;
+                }
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+        }
+        rank
+    }
+
+    /// Iterate over the elements with rank in `start..end` (0-based),
+    /// from `start` upward, or from `end - 1` downward if `rev`
+    pub fn range(&self, start: usize, end: usize, rev: bool) -> Iter<'_> {
+        let end = end.min(self.len);
+        if start >= end {
+            return Iter {
+                list: self,
+                next: None,
+                remaining: 0,
+                rev,
+            };
+        }
+        let first = if rev { end - 1 } else { start };
+        Iter {
+            list: self,
+            next: self.node_at(first),
+            remaining: end - start,
+            rev,
+        }
+    }
+
+    /// Arena index of the element with the given 0-based rank
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        if rank >= self.len {
+            return None;
+        }
+        // Descend using the spans; ranks are 1-based while walking from the head
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if traversed + self.nodes[x].levels[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(slot) => {
+                self.nodes[slot] = node;
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+}
+
+/// Iterator over a rank range of a `SkipList`
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    next: Option<usize>,
+    remaining: usize,
+    rev: bool,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a String, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.list.nodes[self.next?];
+        self.remaining -= 1;
+        self.next = if self.rev {
+            node.backward
+        } else {
+            node.levels[0].forward
+        };
+        Some((&node.member, node.score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Percentiles reported by INFO latencystats
+pub const PERCENTILES: [f64; 3] = [50.0, 99.0, 99.9];
+
+/// Linear sub-buckets per power of two, bounding the error of a reported
+/// percentile to 1/16 of its value
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u32 = SUB_BUCKETS.trailing_zeros();
+
+/// Calls of one command (INFO commandstats)
+#[derive(Clone, Debug, Default)]
+pub struct CommandStat {
+    /// Times the command ran
+    pub calls: u64,
+    /// Total microseconds spent running it
+    pub usec: u64,
+    /// Calls refused before running, e.g. for wrong arity or ACL denial
+    pub rejected_calls: u64,
+    /// Calls that ran and replied with an error
+    pub failed_calls: u64,
+    /// Calls per duration bucket, see `bucket`
+    histogram: Vec<u64>,
+}
+
+impl CommandStat {
+    /// Average microseconds per call
+    pub fn usec_per_call(&self) -> f64 {
+        if self.calls == 0 {
+            0.0
+        } else {
+            self.usec as f64 / self.calls as f64
+        }
+    }
+
+    /// The duration (µs) that `percentile`% of calls took at most, or None
+    /// before the first call
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        let recorded: u64 = self.histogram.iter().sum();
+        if recorded == 0 {
+            return None;
+        }
+        // Rounded to the nearest call, as HdrHistogram does
+        let rank = ((percentile / 100.0 * recorded as f64 + 0.5) as u64).clamp(1, recorded);
+        let mut seen = 0;
+        for (index, count) in self.histogram.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_max(index));
+            }
+        }
+        None
+    }
+
+    fn observe(&mut self, usec: u64) {
+        let index = bucket(usec);
+        if self.histogram.len() <= index {
+            self.histogram.resize(index + 1, 0);
+        }
+        self.histogram[index] += 1;
+    }
+}
+
+/// Histogram bucket for a duration: exact below 16µs, then 16 equal
+/// buckets per power of two
+fn bucket(usec: u64) -> usize {
+    if usec < SUB_BUCKETS {
+        return usec as usize;
+    }
+    let magnitude = usec.ilog2() - SUB_BUCKET_BITS;
+    let sub = (usec >> magnitude) & (SUB_BUCKETS - 1);
+    (SUB_BUCKETS * (magnitude as u64 + 1) + sub) as usize
+}
+
+/// Largest duration falling in bucket `index`
+fn bucket_max(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let magnitude = index / SUB_BUCKETS - 1;
+    let sub = index % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub + 1) << magnitude).wrapping_sub(1)
+}
+
+/// Call statistics per command, by lower-case name. Shared by every
+/// connection and reset by CONFIG RESETSTAT
+#[derive(Clone, Default)]
+pub struct CommandStats {
+    commands: Arc<Mutex<BTreeMap<String, CommandStat>>>,
+}
+
+impl CommandStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a call of `command` that took `elapsed`. Blocking commands
+    /// pass None so time spent waiting isn't counted
+    pub fn record(&self, command: &str, elapsed: Option<Duration>, failed: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let stat = commands.entry(command.to_lowercase()).or_default();
+        stat.calls += 1;
+        if failed {
+            stat.failed_calls += 1;
+        }
+        if let Some(elapsed) = elapsed {
+            let usec = elapsed.as_micros() as u64;
+            stat.usec += usec;
+            stat.observe(usec);
+        }
+    }
+
+    /// Record that a call of `command` was refused before it ran
+    pub fn reject(&self, command: &str) {
+        let mut commands = self.commands.lock().unwrap();
+        commands
+            .entry(command.to_lowercase())
+            .or_default()
+            .rejected_calls += 1;
+    }
+
+    /// Every command called since the last reset, by name
+    pub fn list(&self) -> Vec<(String, CommandStat)> {
+        self.commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stat)| (name.clone(), stat.clone()))
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        self.commands.lock().unwrap().clear();
+    }
+}
@@ -1,100 +1,1289 @@
+use crate::acl::AclRegistry;
+use crate::blocking::KeyWaiters;
+use crate::clients::ClientRegistry;
+use crate::cluster::{self, Cluster};
+use crate::config::{KeyspaceEvents, MaxmemoryPolicy, ServerConfig};
+use crate::expiry::ExpiryIndex;
+use crate::functions::FunctionRegistry;
+use crate::glob::glob_match;
+use crate::keymap::{self, KeyMap};
+use crate::keyspace::{self, Keys, Keyspace, Snapshot};
+use crate::latency::LatencyMonitor;
+use crate::lazyfree;
+use crate::listpack::{self, Listpack};
+use crate::memory::{Accounted, EvictionPool, Frequency, MemoryTracker};
+use crate::modules::ModuleRegistry;
+use crate::pubsub::PubSubHub;
+use crate::replication::Replication;
+use crate::scripting::ScriptCache;
+use crate::skiplist::{self, SkipList};
+use crate::stats::CommandStats;
+use crate::transaction::KeyVersions;
 use ordered_float::OrderedFloat;
-use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant};
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::iter::Rev;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Number of databases, selected with SELECT
+pub const DATABASES: usize = 16;
+
+type Database = Keyspace<ValueWithExpiry>;
+
+/// Writes made through a store handle, so a command can tell whether it
+/// changed anything. Copied on clone
+#[derive(Default)]
+struct WriteCount(AtomicU64);
+
+impl Clone for WriteCount {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
 
 #[derive(Clone)]
 pub struct FerroStore {
-    db: Arc<RwLock<HashMap<String, ValueWithExpiry>>>,
+    databases: Arc<Vec<Database>>,
+    /// The database this handle works on; see `database`
+    db: usize,
+    writes: WriteCount,
+    /// Connected clients (CLIENT LIST)
+    clients: ClientRegistry,
+    /// Clients blocked until a key is pushed to
+    waiters: KeyWaiters,
+    /// Modification counters for WATCHed keys
+    versions: KeyVersions,
+    /// Held shared by every command and exclusively by EXEC, so a
+    /// transaction runs without other clients' commands interleaving
+    exec_lock: Arc<tokio::sync::RwLock<()>>,
+    /// Lua scripts by SHA1 (EVAL / EVALSHA / SCRIPT LOAD)
+    scripts: ScriptCache,
+    /// WebAssembly function libraries (FUNCTION LOAD / FCALL)
+    functions: FunctionRegistry,
+    /// Commands added through the module API
+    modules: ModuleRegistry,
+    /// Runtime configuration (CONFIG GET / SET)
+    config: ServerConfig,
+    /// Channels clients subscribe to, including keyspace notifications
+    pubsub: PubSubHub,
+    /// Users and their permissions (ACL SETUSER / AUTH)
+    acl: AclRegistry,
+    /// Writes streamed to attached replicas
+    replication: Replication,
+    /// Nodes and slot assignments (cluster-enabled)
+    cluster: Cluster,
+    /// Latency spikes (LATENCY LATEST / HISTORY)
+    latency: LatencyMonitor,
+    /// Calls per command (INFO commandstats / latencystats)
+    command_stats: CommandStats,
+    /// Bytes used by keys and values (maxmemory)
+    memory: MemoryTracker,
+    /// Best candidates for the next eviction
+    eviction_pool: EvictionPool,
+    /// When the store was created, i.e. server startup
+    started: Instant,
+    /// Writes since the last successful snapshot
+    dirty: Arc<AtomicU64>,
+    /// Unix time (seconds) of the last successful snapshot, or of startup
+    last_save: Arc<AtomicU64>,
+    /// Set while a snapshot is being written, so only one runs at a time
+    saving: Arc<AtomicBool>,
+    /// Why the last snapshot failed, cleared by the next successful one
+    last_save_error: Arc<RwLock<Option<String>>>,
+    /// Whether the background loop deletes expired keys (DEBUG SET-ACTIVE-EXPIRE)
+    active_expire: Arc<AtomicBool>,
+    /// When keys with a TTL expire, for active expiration
+    expiries: ExpiryIndex,
+}
+
+/// Held while a snapshot is written; see `FerroStore::start_save`
+pub struct SaveGuard(Arc<AtomicBool>);
+
+impl Drop for SaveGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// A string value. Integers in canonical form (no sign on positives, no
+/// leading zeros) are kept as an `i64`, needing no heap allocation and
+/// ready to do arithmetic on
+#[derive(Clone, Debug, PartialEq)]
+pub enum StringData {
+    Int(i64),
+    Raw(String),
+}
+
+impl From<String> for StringData {
+    fn from(value: String) -> Self {
+        // Nothing longer than i64::MIN's 20 characters is an i64
+        if value.len() <= 20
+            && let Ok(int) = value.parse::<i64>()
+            && int.to_string() == value
+        {
+            return StringData::Int(int);
+        }
+        StringData::Raw(value)
+    }
+}
+
+impl From<StringData> for String {
+    fn from(value: StringData) -> Self {
+        match value {
+            StringData::Int(int) => int.to_string(),
+            StringData::Raw(value) => value,
+        }
+    }
+}
+
+impl std::fmt::Display for StringData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.as_str())
+    }
+}
+
+impl StringData {
+    /// The value as text, formatting integers
+    pub fn as_str(&self) -> Cow<'_, str> {
+        match self {
+            StringData::Int(int) => Cow::Owned(int.to_string()),
+            StringData::Raw(value) => Cow::Borrowed(value),
+        }
+    }
+
+    /// Length of the value as text
+    pub fn len(&self) -> usize {
+        match self {
+            StringData::Int(int) => int.to_string().len(),
+            StringData::Raw(value) => value.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Sizes up to which collections are kept in a listpack, from the
+/// `*-max-listpack-*` parameters. Growing past them converts a value to its
+/// full encoding for good
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PackLimits {
+    /// Positive: most elements. -1 to -5: most bytes, 4kb to 64kb
+    pub list_size: i64,
+    pub set_entries: usize,
+    /// Longest member, in bytes
+    pub set_value: usize,
+    pub zset_entries: usize,
+    pub zset_value: usize,
+}
+
+impl PackLimits {
+    /// Whether a list of `len` elements taking `bytes` packed may stay packed
+    fn list_fits(&self, len: usize, bytes: usize) -> bool {
+        match self.list_size {
+            size if size > 0 => len <= size as usize,
+            size => bytes <= 4096 << (-size - 1).clamp(0, 4),
+        }
+    }
+}
+
+/// A list: packed while small, a `VecDeque` once it outgrows
+/// `list-max-listpack-size`
+#[derive(Clone, Debug)]
+pub enum ListData {
+    Packed(Listpack),
+    Deque(VecDeque<String>),
+}
+
+impl PartialEq for ListData {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl Default for ListData {
+    fn default() -> Self {
+        ListData::new()
+    }
+}
+
+impl From<VecDeque<String>> for ListData {
+    fn from(list: VecDeque<String>) -> Self {
+        ListData::Deque(list)
+    }
+}
+
+impl FromIterator<String> for ListData {
+    fn from_iter<I: IntoIterator<Item = String>>(items: I) -> Self {
+        ListData::Deque(items.into_iter().collect())
+    }
+}
+
+impl ListData {
+    pub fn new() -> Self {
+        ListData::Packed(Listpack::new())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ListData::Packed(list) => list.len(),
+            ListData::Deque(list) => list.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> ListIter<'_> {
+        match self {
+            ListData::Packed(list) => ListIter::Packed(list.iter()),
+            ListData::Deque(list) => ListIter::Deque(list.iter()),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        match self {
+            ListData::Packed(list) => list.get(index),
+            ListData::Deque(list) => list.get(index).map(String::as_str),
+        }
+    }
+
+    pub fn push_front(&mut self, value: String, limits: &PackLimits) {
+        match self {
+            ListData::Packed(list) => list.insert(0, &value),
+            ListData::Deque(list) => list.push_front(value),
+        }
+        self.fit(limits);
+    }
+
+    pub fn push_back(&mut self, value: String, limits: &PackLimits) {
+        match self {
+            ListData::Packed(list) => list.push_back(&value),
+            ListData::Deque(list) => list.push_back(value),
+        }
+        self.fit(limits);
+    }
+
+    /// Insert `value` before the element at `index`
+    pub fn insert(&mut self, index: usize, value: String, limits: &PackLimits) {
+        match self {
+            ListData::Packed(list) => list.insert(index, &value),
+            ListData::Deque(list) => list.insert(index, value),
+        }
+        self.fit(limits);
+    }
+
+    /// Replace the element at `index`
+    pub fn set(&mut self, index: usize, value: String, limits: &PackLimits) {
+        match self {
+            ListData::Packed(list) => list.replace(index, &value),
+            ListData::Deque(list) => list[index] = value,
+        }
+        self.fit(limits);
+    }
+
+    pub fn pop_front(&mut self) -> Option<String> {
+        match self {
+            ListData::Packed(list) if list.is_empty() => None,
+            ListData::Packed(list) => Some(list.remove(0)),
+            ListData::Deque(list) => list.pop_front(),
+        }
+    }
+
+    pub fn pop_back(&mut self) -> Option<String> {
+        match self {
+            ListData::Packed(list) if list.is_empty() => None,
+            ListData::Packed(list) => Some(list.remove(list.len() - 1)),
+            ListData::Deque(list) => list.pop_back(),
+        }
+    }
+
+    /// Remove and return the first `n` elements
+    pub fn drain_front(&mut self, n: usize) -> Vec<String> {
+        match self {
+            ListData::Packed(list) => {
+                let drained = list.iter().take(n).map(str::to_string).collect();
+                list.remove_range(0, n.min(list.len()));
+                drained
+            }
+            ListData::Deque(list) => list.drain(..n.min(list.len())).collect(),
+        }
+    }
+
+    /// Unpack a list that has grown past `limits`
+    fn fit(&mut self, limits: &PackLimits) {
+        if let ListData::Packed(list) = self
+            && !limits.list_fits(list.len(), list.bytes())
+        {
+            *self = ListData::Deque(list.iter().map(str::to_string).collect());
+        }
+    }
+
+    /// Choose the encoding afresh, e.g. for a list just loaded
+    pub fn repack(&mut self, limits: &PackLimits) {
+        let bytes = self.iter().map(listpack::encoded_size).sum();
+        if limits.list_fits(self.len(), bytes) {
+            if let ListData::Deque(list) = self {
+                *self = ListData::Packed(list.iter().map(String::as_str).collect());
+            }
+        } else {
+            self.fit(limits);
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a ListData {
+    type Item = &'a str;
+    type IntoIter = ListIter<'a>;
+
+    fn into_iter(self) -> ListIter<'a> {
+        self.iter()
+    }
+}
+
+/// Elements of a list, front to back
+pub enum ListIter<'a> {
+    Packed(listpack::Iter<'a>),
+    Deque(std::collections::vec_deque::Iter<'a, String>),
+}
+
+impl<'a> Iterator for ListIter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        match self {
+            ListIter::Packed(iter) => iter.next(),
+            ListIter::Deque(iter) => iter.next().map(String::as_str),
+        }
+    }
+}
+
+/// A set: packed while small, a `KeyMap` once it outgrows
+/// `set-max-listpack-entries` or gets a member longer than
+/// `set-max-listpack-value`, whose positions SSCAN's cursor walks
+#[derive(Clone, Debug)]
+pub enum SetData {
+    Packed(Listpack),
+    Table(KeyMap<()>),
+}
+
+impl PartialEq for SetData {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|member| other.contains(member))
+    }
+}
+
+impl Default for SetData {
+    fn default() -> Self {
+        SetData::new()
+    }
+}
+
+impl From<HashSet<String>> for SetData {
+    fn from(set: HashSet<String>) -> Self {
+        set.into_iter().collect()
+    }
+}
+
+impl FromIterator<String> for SetData {
+    fn from_iter<I: IntoIterator<Item = String>>(members: I) -> Self {
+        SetData::Table(members.into_iter().map(|member| (member, ())).collect())
+    }
+}
+
+impl SetData {
+    pub fn new() -> Self {
+        SetData::Packed(Listpack::new())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SetData::Packed(set) => set.len(),
+            SetData::Table(set) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Members in no particular order
+    pub fn iter(&self) -> SetIter<'_> {
+        match self {
+            SetData::Packed(set) => SetIter::Packed(set.iter()),
+            SetData::Table(set) => SetIter::Table(set.keys()),
+        }
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match self {
+            SetData::Packed(set) => set.iter().any(|m| m == member),
+            SetData::Table(set) => set.contains_key(member),
+        }
+    }
+
+    /// Add `member`. Returns true if it was newly added
+    pub fn insert(&mut self, member: String, limits: &PackLimits) -> bool {
+        if let SetData::Packed(set) = self {
+            if set.iter().any(|m| m == member) {
+                return false;
+            }
+            if set.len() < limits.set_entries && member.len() <= limits.set_value {
+                set.push_back(&member);
+                return true;
+            }
+            self.unpack();
+        }
+        match self {
+            SetData::Table(set) => set.insert(member, ()).is_none(),
+            SetData::Packed(_) => unreachable!("set unpacked above"),
+        }
+    }
+
+    /// Remove `member`. Returns true if it was present
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self {
+            SetData::Packed(set) => match set.iter().position(|m| m == member) {
+                Some(index) => {
+                    set.remove_range(index, index + 1);
+                    true
+                }
+                None => false,
+            },
+            SetData::Table(set) => set.remove(member).is_some(),
+        }
+    }
+
+    fn unpack(&mut self) {
+        if let SetData::Packed(set) = self {
+            *self = set.iter().map(str::to_string).collect();
+        }
+    }
+
+    /// The members SSCAN returns for `cursor` and the cursor to continue
+    /// from. A table is walked by position like the keyspace in `scan`, so
+    /// each call costs O(count); a packed set is small and returned whole,
+    /// as in Redis
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&str>) {
+        match self {
+            SetData::Packed(set) => (0, set.iter().collect()),
+            SetData::Table(set) => {
+                let (next_cursor, positions) = scan_positions(set.len(), cursor, count);
+                let members = positions
+                    .filter_map(|index| set.get_index(index))
+                    .map(|(member, _)| member.as_str())
+                    .collect();
+                (next_cursor, members)
+            }
+        }
+    }
+
+    /// Choose the encoding afresh, e.g. for a set just loaded
+    pub fn repack(&mut self, limits: &PackLimits) {
+        let fits = self.len() <= limits.set_entries
+            && self.iter().all(|member| member.len() <= limits.set_value);
+        match self {
+            SetData::Table(set) if fits => {
+                *self = SetData::Packed(set.keys().map(String::as_str).collect());
+            }
+            SetData::Packed(_) if !fits => self.unpack(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a SetData {
+    type Item = &'a str;
+    type IntoIter = SetIter<'a>;
+
+    fn into_iter(self) -> SetIter<'a> {
+        self.iter()
+    }
+}
+
+/// Members of a set, in no particular order
+pub enum SetIter<'a> {
+    Packed(listpack::Iter<'a>),
+    Table(keymap::Keys<'a, ()>),
+}
+
+impl<'a> Iterator for SetIter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        match self {
+            SetIter::Packed(iter) => iter.next(),
+            SetIter::Table(iter) => iter.next().map(String::as_str),
+        }
+    }
+}
+
+/// A sorted set: packed while small, a skip list indexed by a `KeyMap`
+/// once it outgrows `zset-max-listpack-entries` or gets a member longer
+/// than `zset-max-listpack-value`
+#[derive(Clone, Debug)]
+pub struct SortedSetData {
+    encoding: ZSetEncoding,
+}
+
+#[derive(Clone, Debug)]
+enum ZSetEncoding {
+    /// Each member followed by its score, ordered by (score, member)
+    Packed(Listpack),
+    SkipList {
+        /// Members ordered by (score, member), with O(log n) rank lookups
+        index: SkipList,
+        /// Member -> score, kept in sync with the index. ZSCAN's cursor
+        /// walks its positions
+        members: KeyMap<OrderedFloat<f64>>,
+    },
+}
+
+/// A score as stored in a packed sorted set. Debug formatting round-trips
+/// exactly and switches to exponents for very large or small values
+fn pack_score(score: f64) -> String {
+    format!("{score:?}")
+}
+
+/// The (member, score) pairs of a packed sorted set, in order
+fn packed_entries(zset: &Listpack) -> impl Iterator<Item = (&str, f64)> {
+    let mut entries = zset.iter();
+    std::iter::from_fn(move || {
+        let member = entries.next()?;
+        let score = entries.next()?.parse().expect("packed score");
+        Some((member, score))
+    })
+}
+
+impl PartialEq for SortedSetData {
+    fn eq(&self, other: &Self) -> bool {
+        // Both iterate in (score, member) order, whatever their encoding
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl Default for SortedSetData {
+    fn default() -> Self {
+        SortedSetData::new()
+    }
+}
+
+impl FromIterator<(String, f64)> for SortedSetData {
+    fn from_iter<I: IntoIterator<Item = (String, f64)>>(entries: I) -> Self {
+        let mut index = SkipList::new();
+        let mut members = KeyMap::new();
+        for (member, score) in entries {
+            if let Some(old) = members.insert(member.clone(), OrderedFloat(score)) {
+                index.remove(&member, old.0);
+            }
+            index.insert(member, score);
+        }
+        Self {
+            encoding: ZSetEncoding::SkipList { index, members },
+        }
+    }
+}
+
+impl SortedSetData {
+    pub fn new() -> Self {
+        Self {
+            encoding: ZSetEncoding::Packed(Listpack::new()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            ZSetEncoding::Packed(zset) => zset.len() / 2,
+            ZSetEncoding::SkipList { members, .. } => members.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_packed(&self) -> bool {
+        matches!(self.encoding, ZSetEncoding::Packed(_))
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        match &self.encoding {
+            ZSetEncoding::Packed(zset) => packed_entries(zset)
+                .find(|&(m, _)| m == member)
+                .map(|(_, score)| score),
+            ZSetEncoding::SkipList { members, .. } => members.get(member).map(|score| score.0),
+        }
+    }
+
+    /// Add `member` or update its score. Returns true if it was newly added
+    pub fn insert(&mut self, member: String, score: f64, limits: &PackLimits) -> bool {
+        if let ZSetEncoding::Packed(zset) = &self.encoding
+            && (zset.len() / 2 >= limits.zset_entries || member.len() > limits.zset_value)
+            && self.score(&member).is_none()
+        {
+            self.unpack();
+        }
+        match &mut self.encoding {
+            ZSetEncoding::Packed(zset) => {
+                let existing = packed_entries(zset)
+                    .enumerate()
+                    .find(|(_, (m, _))| *m == member)
+                    .map(|(rank, (_, old))| (rank, old));
+                if let Some((rank, old)) = existing {
+                    if old == score {
+                        return false;
+                    }
+                    zset.remove_range(2 * rank, 2 * rank + 2);
+                }
+                let rank = packed_entries(zset)
+                    .take_while(|&(m, s)| s < score || (s == score && m < member.as_str()))
+                    .count();
+                zset.insert(2 * rank, &member);
+                zset.insert(2 * rank + 1, &pack_score(score));
+                existing.is_none()
+            }
+            ZSetEncoding::SkipList { index, members } => {
+                let old = members.insert(member.clone(), OrderedFloat(score));
+                if let Some(old) = old {
+                    if old.0 == score {
+                        return false;
+                    }
+                    index.remove(&member, old.0);
+                }
+                index.insert(member, score);
+                old.is_none()
+            }
+        }
+    }
+
+    /// Remove `member`, returning its score if it was present
+    pub fn remove(&mut self, member: &str) -> Option<f64> {
+        match &mut self.encoding {
+            ZSetEncoding::Packed(zset) => {
+                let (rank, score) = packed_entries(zset)
+                    .enumerate()
+                    .find(|(_, (m, _))| *m == member)
+                    .map(|(rank, (_, score))| (rank, score))?;
+                zset.remove_range(2 * rank, 2 * rank + 2);
+                Some(score)
+            }
+            ZSetEncoding::SkipList { index, members } => {
+                let score = members.remove(member)?.0;
+                index.remove(member, score);
+                Some(score)
+            }
+        }
+    }
+
+    fn unpack(&mut self) {
+        if let ZSetEncoding::Packed(zset) = &self.encoding {
+            *self = packed_entries(zset)
+                .map(|(member, score)| (member.to_string(), score))
+                .collect();
+        }
+    }
+
+    /// Choose the encoding afresh, e.g. for a sorted set just loaded
+    pub fn repack(&mut self, limits: &PackLimits) {
+        let fits = self.len() <= limits.zset_entries
+            && self
+                .iter()
+                .all(|(member, _)| member.len() <= limits.zset_value);
+        if fits && !self.is_packed() {
+            let mut zset = Listpack::new();
+            for (member, score) in self.iter() {
+                zset.push_back(member);
+                zset.push_back(&pack_score(score));
+            }
+            self.encoding = ZSetEncoding::Packed(zset);
+        } else if !fits {
+            self.unpack();
+        }
+    }
+
+    /// The members ZSCAN returns for `cursor` and the cursor to continue
+    /// from, like `SetData::scan`: the member index is walked by position,
+    /// while a packed sorted set is returned whole
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&str, f64)>) {
+        match &self.encoding {
+            ZSetEncoding::Packed(zset) => (0, packed_entries(zset).collect()),
+            ZSetEncoding::SkipList { members, .. } => {
+                let (next_cursor, positions) = scan_positions(members.len(), cursor, count);
+                let batch = positions
+                    .filter_map(|index| members.get_index(index))
+                    .map(|(member, score)| (member.as_str(), score.0))
+                    .collect();
+                (next_cursor, batch)
+            }
+        }
+    }
+
+    /// All members in score order (ties broken lexicographically)
+    pub fn iter(&self) -> ZSetIter<'_> {
+        self.range(0, self.len(), false)
+    }
+
+    /// Members with rank in `start..end`, from `start` upward, or from
+    /// `end - 1` downward if `rev`
+    fn range(&self, start: usize, end: usize, rev: bool) -> ZSetIter<'_> {
+        match &self.encoding {
+            ZSetEncoding::Packed(zset) => {
+                let mut range: Vec<_> = packed_entries(zset)
+                    .skip(start)
+                    .take(end.saturating_sub(start))
+                    .collect();
+                if rev {
+                    range.reverse();
+                }
+                ZSetIter::Packed(range.into_iter())
+            }
+            ZSetEncoding::SkipList { index, .. } => {
+                ZSetIter::SkipList(index.range(start, end, rev))
+            }
+        }
+    }
+
+    /// Number of leading members for which `before(member, score)` holds,
+    /// see `SkipList::count_while`
+    fn count_while(&self, before: impl Fn(&str, f64) -> bool) -> usize {
+        match &self.encoding {
+            ZSetEncoding::Packed(zset) => packed_entries(zset)
+                .take_while(|&(member, score)| before(member, score))
+                .count(),
+            ZSetEncoding::SkipList { index, .. } => index.count_while(before),
+        }
+    }
+
+    /// Members whose score lies within `min..max`, ordered by score and then
+    /// lexicographically
+    pub fn range_by_score(&self, min: ScoreBound, max: ScoreBound) -> ZSetIter<'_> {
+        let (start, end) = self.score_ranks(min, max);
+        self.range(start, end, false)
+    }
+
+    /// Members whose value lies within the lexicographical range `min..max`,
+    /// in sorted set order. Meant for sets where every member has the same
+    /// score (as in Redis, mixed scores give unspecified results).
+    pub fn range_by_lex(&self, min: &LexBound, max: &LexBound) -> ZSetIter<'_> {
+        let (start, end) = self.lex_ranks(min, max);
+        self.range(start, end, false)
+    }
+
+    /// Run a ZRANGE query: select by index, score or lex range, optionally
+    /// in reverse order, then apply the LIMIT offset/count
+    pub fn query(&self, query: &ZRangeQuery) -> Vec<ScoredMember> {
+        let (start, end) = match &query.by {
+            ZRangeBy::Index(start, stop) => {
+                // Indices count from the first element in iteration order
+                let len = self.len() as i64;
+                let start = if *start < 0 {
+                    (len + start).max(0)
+                } else {
+                    *start
+                };
+                let stop = if *stop < 0 {
+                    len + stop
+                } else {
+                    (*stop).min(len - 1)
+                };
+                if start > stop || start >= len {
+                    return vec![];
+                }
+                if query.rev {
+                    ((len - 1 - stop) as usize, (len - start) as usize)
+                } else {
+                    (start as usize, stop as usize + 1)
+                }
+            }
+            ZRangeBy::Score(min, max) => self.score_ranks(*min, *max),
+            ZRangeBy::Lex(min, max) => self.lex_ranks(min, max),
+        };
+
+        self.range(start, end, query.rev)
+            .skip(query.offset)
+            .take(query.count.unwrap_or(usize::MAX))
+            .map(|(member, score)| (member.to_string(), score))
+            .collect()
+    }
+
+    /// 0-based position of `member` in score order (ties broken lexicographically)
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.count_while(|m, s| s < score || (s == score && m < member)))
+    }
+
+    /// Number of members whose score lies within `min..max`
+    pub fn count_by_score(&self, min: ScoreBound, max: ScoreBound) -> usize {
+        let (start, end) = self.score_ranks(min, max);
+        end - start
+    }
+
+    /// Number of members within the lexicographical range `min..max`
+    pub fn count_by_lex(&self, min: &LexBound, max: &LexBound) -> usize {
+        let (start, end) = self.lex_ranks(min, max);
+        end - start
+    }
+
+    /// Rank interval `start..end` of the members with a score in `min..max`
+    fn score_ranks(&self, min: ScoreBound, max: ScoreBound) -> (usize, usize) {
+        let start =
+            self.count_while(|_, score| score < min.value || (min.exclusive && score == min.value));
+        let end = self
+            .count_while(|_, score| score < max.value || (!max.exclusive && score == max.value));
+        (start, end.max(start))
+    }
+
+    /// Rank interval `start..end` of the members within the lex range `min..max`
+    fn lex_ranks(&self, min: &LexBound, max: &LexBound) -> (usize, usize) {
+        let start = self.count_while(|member, _| !min.allows_above(member));
+        let end = self.count_while(|member, _| max.allows_below(member));
+        (start, end.max(start))
+    }
+
+    /// Remove and return the member with the lowest or highest score
+    /// Members sharing a score are taken in lexicographical order
+    pub fn pop(&mut self, end: ScoreEnd) -> Option<(String, f64)> {
+        let len = self.len();
+        let (member, score) = match end {
+            ScoreEnd::Min => self.range(0, 1, false).next()?,
+            ScoreEnd::Max => self.range(len.checked_sub(1)?, len, true).next()?,
+        };
+        let member = member.to_string();
+        self.remove(&member);
+        Some((member, score))
+    }
+}
+
+impl<'a> IntoIterator for &'a SortedSetData {
+    type Item = (&'a str, f64);
+    type IntoIter = ZSetIter<'a>;
+
+    fn into_iter(self) -> ZSetIter<'a> {
+        self.iter()
+    }
+}
+
+/// Members of a sorted set with their scores, in rank order
+pub enum ZSetIter<'a> {
+    Packed(std::vec::IntoIter<(&'a str, f64)>),
+    SkipList(skiplist::Iter<'a>),
+}
+
+impl<'a> Iterator for ZSetIter<'a> {
+    type Item = (&'a str, f64);
+
+    fn next(&mut self) -> Option<(&'a str, f64)> {
+        match self {
+            ZSetIter::Packed(iter) => iter.next(),
+            ZSetIter::SkipList(iter) => iter.next().map(|(member, score)| (member.as_str(), score)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum DataType {
+    String(StringData),
+    List(ListData),
+    Set(SetData),
+    SortedSet(SortedSetData),
+}
+
+/// Condition under which SET writes the value (NX / XX)
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum SetCondition {
+    #[default]
+    Always,
+    IfNotExists,
+    IfExists,
+}
+
+/// What SET does with the key's expiry (EX/PX/EXAT/PXAT, KEEPTTL, or none)
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum SetExpiry {
+    #[default]
+    Clear,
+    Keep,
+    After(Duration),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct SetOptions {
+    pub condition: SetCondition,
+    pub expiry: SetExpiry,
+    /// Return the previous value (SET ... GET)
+    pub get: bool,
+}
+
+/// End of a list to pop from or push to (LEFT / RIGHT)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// End of a sorted set to pop from (MIN / MAX)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScoreEnd {
+    Min,
+    Max,
+}
+
+/// One end of a score range, e.g. `5`, `(5` (exclusive) or `-inf`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    pub fn inclusive(value: f64) -> Self {
+        Self {
+            value,
+            exclusive: false,
+        }
+    }
+
+    pub fn exclusive(value: f64) -> Self {
+        Self {
+            value,
+            exclusive: true,
+        }
+    }
+}
+
+/// One end of a lexicographical range: `-`, `+`, `[member` or `(member`
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    /// Whether `member` is on the allowed side of this bound used as a minimum
+    fn allows_above(&self, member: &str) -> bool {
+        match self {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(min) => member >= min.as_str(),
+            LexBound::Exclusive(min) => member > min.as_str(),
+        }
+    }
+
+    /// Whether `member` is on the allowed side of this bound used as a maximum
+    fn allows_below(&self, member: &str) -> bool {
+        match self {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(max) => member <= max.as_str(),
+            LexBound::Exclusive(max) => member < max.as_str(),
+        }
+    }
+}
+
+/// How a ZRANGE query selects members
+#[derive(Clone, Debug, PartialEq)]
+pub enum ZRangeBy {
+    /// Start and stop positions; negative values count from the end
+    Index(i64, i64),
+    /// Minimum and maximum score
+    Score(ScoreBound, ScoreBound),
+    /// Minimum and maximum member
+    Lex(LexBound, LexBound),
 }
 
+/// A ZRANGE query (also serves ZREVRANGE, ZRANGEBYSCORE and ZRANGEBYLEX)
 #[derive(Clone, Debug, PartialEq)]
-pub struct SortedSetData {
-    pub scores: BTreeMap<OrderedFloat<f64>, HashSet<String>>,
-    pub members: HashMap<String, OrderedFloat<f64>>,
+pub struct ZRangeQuery {
+    pub by: ZRangeBy,
+    /// Iterate from the highest score down. Score and lex bounds are still
+    /// given as (min, max); index positions count from the highest score.
+    pub rev: bool,
+    /// LIMIT offset
+    pub offset: usize,
+    /// LIMIT count (None for all)
+    pub count: Option<usize>,
 }
 
-impl Default for SortedSetData {
-    fn default() -> Self {
-        SortedSetData::new()
+impl ZRangeQuery {
+    pub fn new(by: ZRangeBy) -> Self {
+        Self {
+            by,
+            rev: false,
+            offset: 0,
+            count: None,
+        }
     }
 }
 
-impl SortedSetData {
-    pub fn new() -> Self {
-        Self {
-            scores: BTreeMap::new(),
-            members: HashMap::new(),
+/// A sorted set member together with its score
+pub type ScoredMember = (String, f64);
+
+/// Options for SORT
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct SortOptions {
+    /// Sort by the string keys named by this pattern (`*` is replaced by the
+    /// element) instead of the elements; a pattern without `*` skips sorting
+    pub by: Option<String>,
+    /// Patterns to look up for each element instead of returning it
+    /// (`#` is the element itself)
+    pub get: Vec<String>,
+    /// LIMIT offset count (a missing count returns everything after offset)
+    pub offset: usize,
+    pub count: Option<usize>,
+    pub desc: bool,
+    /// Compare lexicographically instead of as numbers
+    pub alpha: bool,
+}
+
+/// Condition under which EXPIRE and friends update the TTL (NX / XX / GT / LT)
+/// A key without an expiry counts as having an infinite TTL for GT and LT
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct ExpireCondition {
+    /// Only set if the key has no expiry
+    pub nx: bool,
+    /// Only set if the key already has an expiry
+    pub xx: bool,
+    /// Only set if the new expiry is later than the current one
+    pub gt: bool,
+    /// Only set if the new expiry is earlier than the current one
+    pub lt: bool,
+}
+
+impl ExpireCondition {
+    fn allows(&self, current: Option<u64>, new: u64) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) => !self.nx && (!self.gt || new > current) && (!self.lt || new < current),
+        }
+    }
+}
+
+impl DataType {
+    /// Type name as reported by TYPE / SCAN ... TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            DataType::String(_) => "string",
+            DataType::List(_) => "list",
+            DataType::Set(_) => "set",
+            DataType::SortedSet(_) => "zset",
         }
     }
 
-    pub fn len(&self) -> usize {
-        self.members.len()
+    /// Approximate heap bytes held by the value. Collections extrapolate
+    /// their elements' sizes from the first `samples` (all of them for 0)
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            DataType::String(StringData::Int(_)) => 0,
+            DataType::String(StringData::Raw(s)) => s.capacity(),
+            DataType::List(ListData::Packed(list)) => list.capacity(),
+            DataType::List(ListData::Deque(list)) => {
+                list.capacity() * size_of::<String>()
+                    + sampled_size(list.iter(), list.len(), samples)
+            }
+            DataType::Set(SetData::Packed(set)) => set.capacity(),
+            DataType::Set(SetData::Table(set)) => {
+                set.overhead() + sampled_size(set.keys(), set.len(), samples)
+            }
+            DataType::SortedSet(zset) => match &zset.encoding {
+                ZSetEncoding::Packed(zset) => zset.capacity(),
+                ZSetEncoding::SkipList { index, members } => {
+                    members.overhead()
+                        + sampled_size(members.keys(), members.len(), samples)
+                        + index.memory_usage(samples)
+                }
+            },
+        }
     }
-    pub fn is_empty(&self) -> bool {
-        self.members.is_empty()
+
+    /// Name of the in-memory representation, as reported by DEBUG OBJECT
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            DataType::String(StringData::Int(_)) => "int",
+            DataType::String(s) if s.len() <= 44 => "embstr",
+            DataType::String(_) => "raw",
+            DataType::List(ListData::Packed(_)) | DataType::Set(SetData::Packed(_)) => "listpack",
+            DataType::SortedSet(zset) if zset.is_packed() => "listpack",
+            DataType::List(_) => "linkedlist",
+            DataType::Set(_) => "hashtable",
+            DataType::SortedSet(_) => "skiplist",
+        }
+    }
+
+    /// Choose the encoding of a collection afresh, for values that were
+    /// loaded or restored rather than built up by commands
+    pub fn repack(&mut self, limits: &PackLimits) {
+        match self {
+            DataType::String(_) => {}
+            DataType::List(list) => list.repack(limits),
+            DataType::Set(set) => set.repack(limits),
+            DataType::SortedSet(zset) => zset.repack(limits),
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum DataType {
-    String(String),
-    List(VecDeque<String>),
-    Set(HashSet<String>),
-    SortedSet(SortedSetData),
+/// Elements sampled when measuring written keys for `used_memory`
+const MEMORY_SAMPLES: usize = 5;
+
+/// Keys active expiration takes from the expiry index per database lock
+const ACTIVE_EXPIRE_BATCH: usize = 20;
+
+/// Heap bytes of `len` strings, extrapolated from the first `samples` of
+/// them (all of them for 0)
+fn sampled_size<'a>(
+    strings: impl Iterator<Item = &'a String>,
+    len: usize,
+    samples: usize,
+) -> usize {
+    let take = if samples == 0 { len } else { samples.min(len) };
+    if take == 0 {
+        return 0;
+    }
+    let sampled: usize = strings.take(take).map(|s| s.capacity()).sum();
+    sampled * len / take
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 struct ValueWithExpiry {
     data: DataType,
-    expires_at: Option<Instant>,
+    /// Unix time in milliseconds the entry expires at. Wall-clock rather
+    /// than monotonic, so it means the same after a restart
+    expires_at: Option<u64>,
+    /// Last access time on the LRU clock (see `lru_clock`). Atomic so that
+    /// read paths holding only the read lock can update it
+    last_access: AtomicU32,
+    /// Access frequency on the LFU clock (see `lfu_clock`)
+    frequency: Frequency,
+    /// This entry's share of the store's used memory
+    accounted: Accounted,
 }
 
-impl ValueWithExpiry {
-    fn new_string(value: String) -> Self {
+impl Clone for ValueWithExpiry {
+    fn clone(&self) -> Self {
         Self {
-            data: DataType::String(value),
-            expires_at: None,
+            data: self.data.clone(),
+            expires_at: self.expires_at,
+            last_access: AtomicU32::new(self.last_access.load(Ordering::Relaxed)),
+            frequency: self.frequency.clone(),
+            accounted: Accounted::default(),
         }
     }
-    fn new_string_with_expiry(value: String, ttl: Duration) -> Self {
+}
+
+/// Milliseconds elapsed since the store clock origin (first use in the process)
+fn clock_ms() -> u64 {
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    ORIGIN.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Milliseconds per tick of the LRU clock
+const LRU_CLOCK_RESOLUTION_MS: u64 = 10;
+
+/// The store clock in `LRU_CLOCK_RESOLUTION_MS` ticks, truncated to 32 bits
+/// so each entry's last access takes 4 bytes. Idle times are computed with
+/// wrapping arithmetic, so they are exact for up to ~497 days
+fn lru_clock() -> u32 {
+    (clock_ms() / LRU_CLOCK_RESOLUTION_MS) as u32
+}
+
+/// The store clock in minutes, which is what LFU counters decay by
+fn lfu_clock() -> u32 {
+    (clock_ms() / 60_000) as u32
+}
+
+/// Current wall-clock time as a duration since the Unix epoch
+fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Current Unix time in milliseconds, the clock expirations are kept on
+fn unix_ms() -> u64 {
+    unix_now().as_millis() as u64
+}
+
+/// Unix time in milliseconds `ttl` from now
+fn expiry_after(ttl: Duration) -> u64 {
+    unix_ms().saturating_add(ttl.as_millis() as u64)
+}
+
+impl ValueWithExpiry {
+    fn new(data: DataType, expires_at: Option<u64>) -> Self {
         Self {
-            data: DataType::String(value),
-            expires_at: Some(Instant::now() + ttl),
+            data,
+            expires_at,
+            last_access: AtomicU32::new(lru_clock()),
+            frequency: Frequency::new(lfu_clock()),
+            accounted: Accounted::default(),
         }
     }
 
+    fn new_string(value: String) -> Self {
+        Self::new(DataType::String(value.into()), None)
+    }
+    fn new_string_with_expiry(value: String, ttl: Duration) -> Self {
+        Self::new(DataType::String(value.into()), Some(expiry_after(ttl)))
+    }
+
     fn new_list() -> Self {
-        Self {
-            data: DataType::List(VecDeque::new()),
-            expires_at: None,
-        }
+        Self::new(DataType::List(ListData::new()), None)
     }
 
     fn new_set() -> Self {
-        Self {
-            data: DataType::Set(HashSet::new()),
-            expires_at: None,
-        }
+        Self::new(DataType::Set(SetData::new()), None)
+    }
+
+    /// Record an access to this entry (OBJECT IDLETIME and FREQ)
+    fn touch(&self) {
+        self.last_access.store(lru_clock(), Ordering::Relaxed);
+        self.frequency.increment(lfu_clock());
+    }
+
+    /// Logarithmic access counter, see `Frequency`
+    fn frequency(&self) -> u8 {
+        self.frequency.count(lfu_clock())
+    }
+
+    /// Seconds since the entry was last accessed
+    fn idle_seconds(&self) -> u64 {
+        self.idle_ms() / 1000
+    }
+
+    /// Milliseconds since the entry was last accessed, to the LRU clock's
+    /// resolution
+    fn idle_ms(&self) -> u64 {
+        let ticks = lru_clock().wrapping_sub(self.last_access.load(Ordering::Relaxed));
+        ticks as u64 * LRU_CLOCK_RESOLUTION_MS
+    }
+
+    /// Approximate bytes taken by the entry and `key`, including the table
+    /// slot, sampling `samples` elements of collections (all for 0)
+    fn memory_usage(&self, key: &str, samples: usize) -> usize {
+        size_of::<(String, ValueWithExpiry)>() + 1 + key.len() + self.data.memory_usage(samples)
     }
 
     fn is_expired(&self) -> bool {
         match self.expires_at {
             None => false,
-            Some(expiry) => expiry <= Instant::now(),
+            Some(expiry) => expiry <= unix_ms(),
         }
     }
-    // NOTE: -2 => Expired , -1 => No expiry , i => i seconds till expiry
-    fn ttl_seconds(&self) -> Option<i64> {
+    // NOTE: -2 => Expired , -1 => No expiry , i => i milliseconds till expiry
+    fn ttl_millis(&self) -> Option<i64> {
         match self.expires_at {
             None => Some(-1),
             Some(expiry) => {
-                let now = Instant::now();
+                let now = unix_ms();
                 if now >= expiry {
                     Some(-2)
                 } else {
-                    let remaining = expiry.duration_since(now);
-                    Some(remaining.as_secs() as i64)
+                    Some(i64::try_from(expiry - now).unwrap_or(i64::MAX))
                 }
             }
         }
@@ -109,81 +1298,721 @@ impl Default for FerroStore {
 
 impl FerroStore {
     pub fn new() -> Self {
+        let modules = ModuleRegistry::new();
         Self {
-            db: Arc::new(RwLock::new(HashMap::new())),
+            databases: Arc::new((0..DATABASES).map(|_| Database::default()).collect()),
+            db: 0,
+            writes: WriteCount::default(),
+            clients: ClientRegistry::new(),
+            waiters: KeyWaiters::new(),
+            versions: KeyVersions::new(),
+            exec_lock: Arc::new(tokio::sync::RwLock::new(())),
+            scripts: ScriptCache::new(),
+            functions: FunctionRegistry::new(),
+            acl: AclRegistry::new(modules.clone()),
+            latency: LatencyMonitor::new(),
+            command_stats: CommandStats::new(),
+            memory: MemoryTracker::new(),
+            eviction_pool: EvictionPool::new(),
+            started: Instant::now(),
+            modules,
+            config: ServerConfig::new(),
+            pubsub: PubSubHub::new(),
+            replication: Replication::new(),
+            cluster: Cluster::new(),
+            dirty: Arc::new(AtomicU64::new(0)),
+            last_save: Arc::new(AtomicU64::new(unix_now().as_secs())),
+            saving: Arc::new(AtomicBool::new(false)),
+            last_save_error: Arc::new(RwLock::new(None)),
+            active_expire: Arc::new(AtomicBool::new(true)),
+            expiries: ExpiryIndex::new(DATABASES),
+        }
+    }
+
+    /// The database this handle works on
+    fn db(&self) -> &Database {
+        &self.databases[self.db]
+    }
+
+    /// Index of the database this handle works on
+    pub fn db_index(&self) -> usize {
+        self.db
+    }
+
+    /// A handle on database `index`, sharing everything else with this one.
+    /// Which database a client has selected is connection state (see
+    /// `ConnectionContext`); commands run on a handle for it
+    pub fn database(&self, index: usize) -> Result<FerroStore, String> {
+        if index >= DATABASES {
+            return Err("ERR DB index is out of range".to_string());
+        }
+        let mut handle = self.clone();
+        handle.db = index;
+        Ok(handle)
+    }
+
+    /// A handle on each database, in index order
+    pub fn databases(&self) -> impl Iterator<Item = FerroStore> + '_ {
+        (0..DATABASES).map(|index| {
+            let mut handle = self.clone();
+            handle.db = index;
+            handle
+        })
+    }
+
+    /// Registry used by blocking commands to wait for pushes
+    pub fn waiters(&self) -> &KeyWaiters {
+        &self.waiters
+    }
+
+    /// Modification counters used by WATCH
+    pub fn versions(&self) -> &KeyVersions {
+        &self.versions
+    }
+
+    pub fn exec_lock(&self) -> &tokio::sync::RwLock<()> {
+        &self.exec_lock
+    }
+
+    pub fn scripts(&self) -> &ScriptCache {
+        &self.scripts
+    }
+
+    pub fn functions(&self) -> &FunctionRegistry {
+        &self.functions
+    }
+
+    pub fn modules(&self) -> &ModuleRegistry {
+        &self.modules
+    }
+
+    pub fn pubsub(&self) -> &PubSubHub {
+        &self.pubsub
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn acl(&self) -> &AclRegistry {
+        &self.acl
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    pub fn cluster(&self) -> &Cluster {
+        &self.cluster
+    }
+
+    pub fn latency(&self) -> &LatencyMonitor {
+        &self.latency
+    }
+
+    /// Record that `event` took `elapsed`, if it reaches `latency-monitor-threshold`
+    pub fn record_latency(&self, event: &str, elapsed: Duration) {
+        let threshold = self.config.read().latency_monitor_threshold;
+        self.latency.record(event, elapsed, threshold);
+    }
+
+    pub fn command_stats(&self) -> &CommandStats {
+        &self.command_stats
+    }
+
+    /// Seconds since the server started
+    pub fn uptime(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn clients(&self) -> &ClientRegistry {
+        &self.clients
+    }
+
+    /// Writes made through this handle so far; a command changed the
+    /// dataset if it moved
+    pub fn writes(&self) -> u64 {
+        self.writes.0.load(Ordering::Relaxed)
+    }
+
+    /// Record a write to `key`, for WATCH, the snapshot rules, memory
+    /// accounting and the AOF
+    fn modified(&self, key: &str) {
+        self.versions.bump(key);
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+        self.writes.0.fetch_add(1, Ordering::Relaxed);
+        self.memory.written(self.db, key);
+    }
+
+    /// Approximate bytes used by the keys and values of every database,
+    /// measuring the keys written since the last call
+    pub fn used_memory(&self) -> usize {
+        for (index, key) in self.memory.take_written() {
+            let db = self.databases[index].read_key(&key);
+            if let Some(entry) = db.get(&key) {
+                // Sampled like MEMORY USAGE, so big collections stay cheap
+                let bytes = entry.memory_usage(&key, MEMORY_SAMPLES);
+                self.memory.account(&entry.accounted, bytes);
+            }
+        }
+        self.memory.used()
+    }
+
+    /// Keys removed by maxmemory eviction since startup
+    pub fn evicted_keys(&self) -> u64 {
+        self.memory.evicted()
+    }
+
+    /// Delete the key `policy` ranks highest among the eviction pool and
+    /// `samples` keys sampled from each database (maxmemory eviction).
+    /// Returns its database and name, or None if no key qualifies
+    pub fn evict(&self, policy: MaxmemoryPolicy, samples: usize) -> Option<(usize, String)> {
+        let rank: fn(&ValueWithExpiry) -> u64 = match policy {
+            MaxmemoryPolicy::NoEviction => return None,
+            MaxmemoryPolicy::AllKeysRandom => |_| fastrand::u64(..),
+            MaxmemoryPolicy::AllKeysLru | MaxmemoryPolicy::VolatileLru => |entry| entry.idle_ms(),
+            MaxmemoryPolicy::AllKeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                |entry| (u8::MAX - entry.frequency()) as u64
+            }
+            MaxmemoryPolicy::VolatileTtl => {
+                |entry| u64::MAX - entry.ttl_millis().unwrap_or(0).max(0) as u64
+            }
+        };
+        let eligible = |entry: &ValueWithExpiry| !policy.volatile() || entry.expires_at.is_some();
+
+        self.eviction_pool.prepare(policy);
+        for (index, database) in self.databases.iter().enumerate() {
+            let db = database.read();
+            if policy.volatile() {
+                // Sample the keys with a TTL rather than hope random keys have one
+                for key in self.expiries.sample(index, samples) {
+                    if let Some(entry) = db.get(&key).filter(|entry| eligible(entry)) {
+                        self.eviction_pool.offer(rank(&entry), index, &key);
+                    }
+                }
+                continue;
+            }
+            let len = db.len();
+            for i in 0..samples.min(len) {
+                // Every entry when the sample would cover the database
+                let position = if samples >= len {
+                    i
+                } else {
+                    fastrand::usize(..len)
+                };
+                let Some(entry) = db.get_index(position) else {
+                    continue;
+                };
+                self.eviction_pool
+                    .offer(rank(entry.value()), index, entry.key());
+            }
+        }
+        // Candidates may have been deleted, or lost their TTL, since they
+        // were sampled
+        while let Some((index, key)) = self.eviction_pool.pop() {
+            let db = self.databases[index].read_key(&key);
+            if !db.get(&key).is_some_and(|entry| eligible(&entry)) {
+                continue;
+            }
+            drop(db);
+            let handle = self.databases().nth(index)?;
+            if self.config.read().lazyfree_lazy_eviction {
+                handle.unlink(&key);
+            } else {
+                handle.delete(&key);
+            }
+            self.memory.record_eviction();
+            self.notify_keyspace_event(KeyspaceEvents::EVICTED, "evicted", index, &key);
+            return Some((index, key));
+        }
+        None
+    }
+
+    /// Number of writes since the last successful snapshot
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    /// Unix time (seconds) of the last successful snapshot (LASTSAVE)
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    /// Record a successful snapshot that started when `dirty` writes were
+    /// pending; writes made while it was being taken stay pending
+    pub fn record_save(&self, dirty: u64) {
+        self.dirty.fetch_sub(dirty, Ordering::Relaxed);
+        self.last_save
+            .store(unix_now().as_secs(), Ordering::Relaxed);
+        *self.last_save_error.write().unwrap() = None;
+    }
+
+    /// Record a failed snapshot; the writes it would have saved stay pending
+    pub fn record_save_error(&self, error: String) {
+        *self.last_save_error.write().unwrap() = Some(error);
+    }
+
+    /// Why the last snapshot failed, or `None` if it succeeded
+    pub fn last_save_error(&self) -> Option<String> {
+        self.last_save_error.read().unwrap().clone()
+    }
+
+    /// Claim the right to write a snapshot until the guard is dropped, or
+    /// `None` if another one is in progress
+    pub fn start_save(&self) -> Option<SaveGuard> {
+        self.saving
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(SaveGuard(self.saving.clone()))
+    }
+
+    /// Whether a snapshot is being written (SAVE, BGSAVE or a save rule)
+    pub fn save_in_progress(&self) -> bool {
+        self.saving.load(Ordering::Relaxed)
+    }
+
+    /// Whether the background loop should delete expired keys. Expired keys
+    /// are still removed lazily when accessed
+    pub fn active_expire_enabled(&self) -> bool {
+        self.active_expire.load(Ordering::Relaxed)
+    }
+
+    pub fn set_active_expire(&self, enabled: bool) {
+        self.active_expire.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Remove `key`, whose TTL ran out, from `db`, the selected database,
+    /// publishing an `expired` event
+    fn expire_key(&self, db: &mut Keys<'_, ValueWithExpiry>, key: &str) {
+        db.remove(key);
+        self.notify_keyspace_event(KeyspaceEvents::EXPIRED, "expired", self.db, key);
+    }
+
+    /// Delete `key` if it has expired, so the lookup that follows finds
+    /// nothing. Returns whether it did
+    fn expire_if_due(&self, db: &mut Keys<'_, ValueWithExpiry>, key: &str) -> bool {
+        if db.get(key).is_some_and(|entry| entry.is_expired()) {
+            self.expire_key(db, key);
+            return true;
+        }
+        false
+    }
+
+    /// Lock `key` for a command that only reads it. Normally that takes
+    /// just the read lock; an expired entry is first deleted under the
+    /// write lock, then the read lock is taken again
+    fn read_live(&self, key: &str) -> Keys<'_, ValueWithExpiry> {
+        let db = self.db().read_key(key);
+        if !db.get(key).is_some_and(|entry| entry.is_expired()) {
+            return db;
+        }
+        drop(db);
+        self.expire_if_due(&mut self.db().write_key(key), key);
+        self.db().read_key(key)
+    }
+
+    /// Publish `event` on `key` of database `db` to keyspace notification
+    /// subscribers, if `notify-keyspace-events` enables its class
+    pub fn notify_keyspace_event(&self, class: KeyspaceEvents, event: &str, db: usize, key: &str) {
+        let events = self.config.read().notify_keyspace_events;
+        if !events.contains(class) {
+            return;
+        }
+        if events.contains(KeyspaceEvents::KEYSPACE) {
+            let channel = format!("__keyspace@{}__:{}", db, key);
+            self.pubsub.publish(&channel, event.to_string());
+        }
+        if events.contains(KeyspaceEvents::KEYEVENT) {
+            let channel = format!("__keyevent@{}__:{}", db, event);
+            self.pubsub.publish(&channel, key.to_string());
+        }
+    }
+
+    /// Store `entry` under `key` in `db`, the selected database, noting its
+    /// expiry and releasing the value it replaces
+    fn insert(&self, db: &mut Keys<'_, ValueWithExpiry>, key: String, entry: ValueWithExpiry) {
+        if let Some(at) = entry.expires_at {
+            self.expiries.schedule(self.db, &key, at);
+        }
+        self.release(db.insert(key, entry));
+    }
+
+    /// Drop a value a command replaced, on the lazyfree thread if
+    /// `lazyfree-lazy-server-del` is set. Called with the write lock held
+    fn release(&self, replaced: Option<ValueWithExpiry>) {
+        if let Some(entry) = replaced
+            && self.config.read().lazyfree_lazy_server_del
+        {
+            lazyfree::free_value(entry.data);
+        }
+    }
+
+    /// Sizes up to which collections stay packed
+    fn pack_limits(&self) -> PackLimits {
+        let config = self.config.read();
+        PackLimits {
+            list_size: config.list_max_listpack_size,
+            set_entries: config.set_max_listpack_entries,
+            set_value: config.set_max_listpack_value,
+            zset_entries: config.zset_max_listpack_entries,
+            zset_value: config.zset_max_listpack_value,
         }
     }
 
     pub fn set(&self, key: String, value: String) {
-        let mut db = self.db.write().unwrap();
-        db.insert(key, ValueWithExpiry::new_string(value));
+        let mut db = self.db().write_key(&key);
+        self.modified(&key);
+        self.insert(&mut db, key, ValueWithExpiry::new_string(value));
     }
 
     pub fn set_with_expiry(&self, key: String, value: String, ttl_seconds: u64) {
-        let mut db = self.db.write().unwrap();
-        let ttl = Duration::from_secs(ttl_seconds);
-        db.insert(key, ValueWithExpiry::new_string_with_expiry(value, ttl));
+        self.set_with_expiry_ms(key, value, ttl_seconds.saturating_mul(1000));
+    }
+
+    pub fn set_with_expiry_ms(&self, key: String, value: String, ttl_millis: u64) {
+        let mut db = self.db().write_key(&key);
+        let ttl = Duration::from_millis(ttl_millis);
+        self.modified(&key);
+        self.insert(
+            &mut db,
+            key,
+            ValueWithExpiry::new_string_with_expiry(value, ttl),
+        );
+    }
+
+    /// SET with options, evaluated atomically under the write lock
+    /// Returns (whether the value was written, previous string value)
+    /// Errors with WRONGTYPE if GET is requested and the old value is not a string
+    pub fn set_with_options(
+        &self,
+        key: String,
+        value: String,
+        options: SetOptions,
+    ) -> Result<(bool, Option<String>), String> {
+        let mut db = self.db().write_key(&key);
+
+        let existing = db.get(&key).filter(|entry| !entry.is_expired());
+        let old_value = match &existing {
+            Some(entry) => match &entry.data {
+                DataType::String(s) => Some(s.to_string()),
+                _ if options.get => {
+                    return Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    );
+                }
+                _ => None,
+            },
+            None => None,
+        };
+        let old_expiry = existing.as_ref().and_then(|entry| entry.expires_at);
+        let exists = existing.is_some();
+        drop(existing);
+
+        let allowed = match options.condition {
+            SetCondition::Always => true,
+            SetCondition::IfNotExists => !exists,
+            SetCondition::IfExists => exists,
+        };
+        if !allowed {
+            return Ok((false, old_value));
+        }
+
+        let expires_at = match options.expiry {
+            SetExpiry::Clear => None,
+            SetExpiry::Keep => old_expiry,
+            SetExpiry::After(ttl) => Some(expiry_after(ttl)),
+        };
+        self.modified(&key);
+        self.insert(
+            &mut db,
+            key,
+            ValueWithExpiry::new(DataType::String(value.into()), expires_at),
+        );
+        Ok((true, old_value))
+    }
+
+    /// Set a key only if it does not already exist (SETNX)
+    /// Returns true if the key was set
+    pub fn setnx(&self, key: String, value: String) -> bool {
+        let mut db = self.db().write_key(&key);
+        if let Some(entry) = db.get(&key)
+            && !entry.is_expired()
+        {
+            return false;
+        }
+        self.modified(&key);
+        self.insert(&mut db, key, ValueWithExpiry::new_string(value));
+        true
+    }
+
+    /// Set multiple keys only if none of them exist (MSETNX)
+    /// All-or-nothing: if any key exists, nothing is written and false is returned
+    pub fn msetnx(&self, pairs: Vec<(String, String)>) -> bool {
+        let mut db = self.db().write();
+        let any_exists = pairs
+            .iter()
+            .any(|(key, _)| db.get(key).is_some_and(|entry| !entry.is_expired()));
+        if any_exists {
+            return false;
+        }
+        for (key, value) in pairs {
+            self.modified(&key);
+            self.insert(&mut db, key, ValueWithExpiry::new_string(value));
+        }
+        true
     }
 
     /// Get a value, returning None if expired or doesnt exist.
     /// This is passive exploration
     pub fn get(&self, key: &str) -> Option<String> {
-        let mut db = self.db.write().unwrap();
+        let db = self.read_live(key);
         if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return None;
-            }
+            entry.touch();
             return match &entry.data {
-                DataType::String(s) => Some(s.clone()),
+                DataType::String(s) => Some(s.to_string()),
                 _ => None,
             };
         };
         None
     }
 
+    /// Get a string value and delete the key atomically (GETDEL)
+    pub fn getdel(&self, key: &str) -> Result<Option<String>, String> {
+        let mut db = self.db().write_key(key);
+        if self.expire_if_due(&mut db, key) {
+            return Ok(None);
+        }
+        if db
+            .get(key)
+            .is_some_and(|entry| !matches!(entry.data, DataType::String(_)))
+        {
+            return Err(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            );
+        }
+        if let Some(ValueWithExpiry {
+            data: DataType::String(s),
+            ..
+        }) = db.remove(key)
+        {
+            self.modified(key);
+            return Ok(Some(s.into()));
+        }
+        Ok(None)
+    }
+
+    /// Get a string value and update its expiry atomically (GETEX)
+    /// SetExpiry::Keep leaves the TTL untouched, SetExpiry::Clear persists the key
+    pub fn getex(&self, key: &str, expiry: SetExpiry) -> Result<Option<String>, String> {
+        let mut db = self.db().write_key(key);
+        if self.expire_if_due(&mut db, key) {
+            return Ok(None);
+        }
+        if let Some(mut entry) = db.get_mut(key) {
+            entry.touch();
+            let DataType::String(s) = &entry.data else {
+                return Err(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+                );
+            };
+            let value = s.to_string();
+            match expiry {
+                SetExpiry::Keep => return Ok(Some(value)),
+                SetExpiry::Clear => {
+                    entry.expires_at = None;
+                    self.expiries.cancel(self.db, key);
+                }
+                SetExpiry::After(ttl) => {
+                    let at = expiry_after(ttl);
+                    entry.expires_at = Some(at);
+                    self.expiries.schedule(self.db, key, at);
+                }
+            }
+            self.modified(key);
+            return Ok(Some(value));
+        }
+        Ok(None)
+    }
+
     pub fn exists(&self, key: &str) -> bool {
-        let mut db = self.db.write().unwrap();
-        if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return false;
+        let db = self.read_live(key);
+        db.get(key).is_some()
+    }
+
+    pub fn delete(&self, key: &str) -> bool {
+        let mut db = self.db().write_key(key);
+        let removed = db.remove(key).is_some();
+        if removed {
+            self.modified(key);
+        }
+        removed
+    }
+
+    /// Remove a key without freeing its value under the lock (UNLINK)
+    /// Large collections are released on the lazyfree thread
+    pub fn unlink(&self, key: &str) -> bool {
+        let removed = {
+            let mut db = self.db().write_key(key);
+            db.remove(key)
+        };
+        match removed {
+            Some(entry) => {
+                self.modified(key);
+                let existed = !entry.is_expired();
+                lazyfree::free_value(entry.data);
+                existed
+            }
+            None => false,
+        }
+    }
+
+    /// Copy the value and expiry of `src` to `dst` (COPY)
+    /// Returns false if `src` doesn't exist, or `dst` exists and `replace` is not set
+    pub fn copy(&self, src: &str, dst: &str, replace: bool) -> bool {
+        let mut db = self.db().write();
+
+        let value = match db.get(src) {
+            Some(entry) if !entry.is_expired() => {
+                entry.touch();
+                entry.clone()
             }
+            _ => return false,
+        };
+        if !replace && db.get(dst).is_some_and(|entry| !entry.is_expired()) {
+            return false;
+        }
+
+        self.insert(&mut db, dst.to_string(), value);
+        self.modified(dst);
+        self.waiters.notify(dst);
+        true
+    }
+
+    pub fn expire(&self, key: &str, ttl_seconds: u64) -> bool {
+        self.pexpire(
+            key,
+            ttl_seconds.saturating_mul(1000),
+            ExpireCondition::default(),
+        )
+    }
+
+    /// Set a key's time to live in milliseconds (PEXPIRE)
+    /// Returns false if the key doesn't exist or the condition rejected the update
+    pub fn pexpire(&self, key: &str, ttl_millis: u64, condition: ExpireCondition) -> bool {
+        let now = unix_ms();
+        self.expire_at(key, now.saturating_add(ttl_millis), now, condition)
+    }
+
+    /// Set expiration as an absolute Unix timestamp in milliseconds (PEXPIREAT)
+    /// A timestamp in the past deletes the key.
+    /// Returns false if the key doesn't exist or the condition rejected the update
+    pub fn pexpire_at(&self, key: &str, unix_millis: u64, condition: ExpireCondition) -> bool {
+        self.expire_at(key, unix_millis, unix_ms(), condition)
+    }
+
+    fn expire_at(&self, key: &str, at: u64, now: u64, condition: ExpireCondition) -> bool {
+        let mut db = self.db().write_key(key);
+
+        if self.expire_if_due(&mut db, key) {
+            return false;
+        }
+        let Some(mut entry) = db.get_mut(key) else {
+            return false;
+        };
+        if !condition.allows(entry.expires_at, at) {
+            return false;
+        }
+
+        self.modified(key);
+        if at <= now {
+            drop(entry);
+            db.remove(key);
             return true;
         }
-        false
+        entry.touch();
+        entry.expires_at = Some(at);
+        self.expiries.schedule(self.db, key, at);
+        true
     }
 
-    pub fn delete(&self, key: &str) -> bool {
-        let mut db = self.db.write().unwrap();
-        db.remove(key).is_some()
+    /// Get the absolute expiration of a key as a Unix timestamp in milliseconds
+    /// Returns -1 if the key has no expiry, None if it doesn't exist
+    pub fn pexpire_time(&self, key: &str) -> Option<i64> {
+        let db = self.db().read_key(key);
+
+        let entry = db.get(key).filter(|entry| !entry.is_expired())?;
+        Some(
+            entry
+                .expires_at
+                .map_or(-1, |expiry| i64::try_from(expiry).unwrap_or(i64::MAX)),
+        )
     }
 
-    pub fn expire(&self, key: &str, ttl_seconds: u64) -> bool {
-        let mut db = self.db.write().unwrap();
+    /// Update the last access time of existing keys (TOUCH)
+    /// Returns the number of keys that exist
+    pub fn touch(&self, keys: &[String]) -> usize {
+        let db = self.db().read();
+        keys.iter()
+            .filter(|key| match db.get(key.as_str()) {
+                Some(entry) if !entry.is_expired() => {
+                    entry.touch();
+                    true
+                }
+                _ => false,
+            })
+            .count()
+    }
 
-        if let Some(entry) = db.get_mut(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return false;
-            }
+    /// Approximate bytes `key` and its value take up, including the table
+    /// slot, sampling `samples` elements of collections (all for 0)
+    pub fn memory_usage(&self, key: &str, samples: usize) -> Option<usize> {
+        let db = self.db().read_key(key);
+        let entry = db.get(key).filter(|entry| !entry.is_expired())?;
+        Some(entry.memory_usage(key, samples))
+    }
 
-            let ttl = Duration::from_secs(ttl_seconds);
-            entry.expires_at = Some(Instant::now() + ttl);
-            return true;
-        }
+    /// Seconds since the key was last read or written (OBJECT IDLETIME)
+    /// Does not itself count as an access
+    pub fn idle_time(&self, key: &str) -> Option<u64> {
+        let db = self.db().read_key(key);
+        db.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.idle_seconds())
+    }
 
-        false
+    /// Name of a key's in-memory representation (OBJECT ENCODING), or
+    /// None if it doesn't exist
+    pub fn encoding(&self, key: &str) -> Option<&'static str> {
+        let db = self.db().read_key(key);
+        db.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.data.encoding())
+    }
+
+    /// Logarithmic access frequency of a key (OBJECT FREQ), or None if it
+    /// doesn't exist
+    pub fn frequency(&self, key: &str) -> Option<u8> {
+        let db = self.db().read_key(key);
+        db.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.frequency())
     }
 
-    /// Get TTL of a key in seconds
+    /// Get TTL of a key in seconds (rounded to the nearest second)
     /// Returns: Some(seconds) if key exists, None if key doesn't exist
     /// Special values: -1 = no expiration, -2 = expired
     pub fn ttl(&self, key: &str) -> Option<i64> {
-        let db = self.db.read().unwrap();
+        self.pttl(key)
+            .map(|ms| if ms < 0 { ms } else { (ms + 500) / 1000 })
+    }
+
+    /// Get TTL of a key in milliseconds, with the same conventions as `ttl`
+    pub fn pttl(&self, key: &str) -> Option<i64> {
+        let db = self.db().read_key(key);
 
         if let Some(entry) = db.get(key) {
-            return entry.ttl_seconds();
+            return entry.ttl_millis();
         }
 
         None // Key doesn't exist
@@ -192,44 +2021,269 @@ impl FerroStore {
     /// Remove expiration from a key (PERSIST command)
     /// Returns true if expiration was removed
     pub fn persist(&self, key: &str) -> bool {
-        let mut db = self.db.write().unwrap();
-
-        if let Some(entry) = db.get_mut(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return false;
-            }
+        let mut db = self.db().write_key(key);
 
-            if entry.expires_at.is_some() {
-                entry.expires_at = None;
-                return true;
-            }
+        if self.expire_if_due(&mut db, key) {
+            return false;
+        }
+        if let Some(mut entry) = db.get_mut(key)
+            && entry.expires_at.is_some()
+        {
+            entry.expires_at = None;
+            self.expiries.cancel(self.db, key);
+            self.modified(key);
+            return true;
         }
 
         false
     }
 
-    /// Active expiration: Remove all expired keys
+    /// Active expiration: Remove all expired keys, in every database
     /// Returns count of keys deleted
     pub fn delete_expired_keys(&self) -> usize {
-        let mut db = self.db.write().unwrap();
-        let mut to_delete = Vec::new();
+        self.active_expire_cycle(Duration::MAX)
+    }
 
-        // Collect expired keys
-        for (key, entry) in db.iter() {
-            if entry.is_expired() {
-                to_delete.push(key.clone());
+    /// Remove expired keys in every database, visiting only keys due per
+    /// the expiry index, until none are left or `budget` is spent; the rest
+    /// wait for the next cycle. Keys are deleted in batches, so the write
+    /// lock is never held for long. Returns count of keys deleted
+    pub fn active_expire_cycle(&self, budget: Duration) -> usize {
+        let started = Instant::now();
+        let mut count = 0;
+        loop {
+            let mut due = false;
+            for (index, database) in self.databases.iter().enumerate() {
+                let keys = self
+                    .expiries
+                    .take_due(index, unix_ms(), ACTIVE_EXPIRE_BATCH);
+                if keys.is_empty() {
+                    continue;
+                }
+                due = true;
+                for key in keys {
+                    let mut db = database.write_key(&key);
+                    let Some(expires_at) = db.get(&key).map(|entry| entry.expires_at) else {
+                        continue;
+                    };
+                    if expires_at.is_some_and(|at| at <= unix_ms()) {
+                        db.remove(&key);
+                        self.modified(&key);
+                        self.notify_keyspace_event(KeyspaceEvents::EXPIRED, "expired", index, &key);
+                        count += 1;
+                    } else if let Some(at) = expires_at {
+                        // Expiry moved later since it was noted
+                        self.expiries.schedule(index, &key, at);
+                    }
+                }
             }
+            if !due || started.elapsed() >= budget {
+                return count;
+            }
+        }
+    }
+
+    /// Exchange the contents of two databases (SWAPDB). Clients keep their
+    /// selected index, so they see the other dataset from their next command
+    pub fn swap_databases(&self, first: usize, second: usize) -> Result<(), String> {
+        if first >= DATABASES || second >= DATABASES {
+            return Err("ERR DB index is out of range".to_string());
+        }
+        if first == second {
+            return Ok(());
+        }
+        // Lock in index order so concurrent swaps can't deadlock
+        let (low, high) = (first.min(second), first.max(second));
+        let mut low_db = self.databases[low].write();
+        let mut high_db = self.databases[high].write();
+        low_db.swap(&mut high_db);
+        self.expiries.swap(low, high);
+
+        // Every key in both databases changed for the clients watching or
+        // blocked on it
+        let keys: HashSet<String> = low_db
+            .iter()
+            .chain(high_db.iter())
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in &keys {
+            self.modified(key);
+            self.waiters.notify(key);
+        }
+        Ok(())
+    }
+
+    /// Move `key` from the selected database to database `index`, keeping its
+    /// expiry (MOVE). Returns false if the key doesn't exist or already
+    /// exists in the target database
+    pub fn move_key(&self, key: &str, index: usize) -> Result<bool, String> {
+        let current = self.db;
+        if index >= DATABASES {
+            return Err("ERR DB index is out of range".to_string());
+        }
+        if index == current {
+            return Err("ERR source and destination objects are the same".to_string());
+        }
+        let (mut src, mut dst) = if current < index {
+            let src = self.databases[current].write();
+            (src, self.databases[index].write())
+        } else {
+            let dst = self.databases[index].write();
+            (self.databases[current].write(), dst)
+        };
+
+        if src.get(key).is_none_or(|entry| entry.is_expired()) {
+            return Ok(false);
+        }
+        if dst.get(key).is_some_and(|entry| !entry.is_expired()) {
+            return Ok(false);
+        }
+        if let Some(entry) = src.remove(key) {
+            if let Some(at) = entry.expires_at {
+                self.expiries.schedule(index, key, at);
+            }
+            dst.insert(key.to_string(), entry);
+        }
+        self.modified(key);
+        self.waiters.notify(key);
+        Ok(true)
+    }
+
+    /// Return a uniformly random non-expired key (RANDOMKEY)
+    /// Picks a random position of the keyspace, in O(1); expired keys hit
+    /// along the way are deleted and the pick is retried.
+    pub fn random_key(&self) -> Option<String> {
+        let mut db = self.db().write();
+
+        // Bound the retries so a keyspace full of expired keys can't spin for long
+        for _ in 0..100 {
+            if db.is_empty() {
+                return None;
+            }
+            let index = fastrand::usize(..db.len());
+            let (key, expired) = {
+                let Some(entry) = db.get_index(index) else {
+                    continue;
+                };
+                (entry.key().clone(), entry.is_expired())
+            };
+            if !expired {
+                return Some(key);
+            }
+            self.expire_key(&mut db, &key);
         }
 
-        let count = to_delete.len();
+        db.iter()
+            .find(|entry| !entry.is_expired())
+            .map(|entry| entry.key().clone())
+    }
+
+    /// Incrementally iterate the keyspace (SCAN)
+    /// Returns the next cursor (0 when the iteration is complete) and a batch of keys.
+    ///
+    /// The cursor is a position in the keyspace (see `KeyMap`): each call
+    /// visits the `count` positions below it, highest first, and a cursor of
+    /// 0 starts from the end. A removed entry's place is taken by the last
+    /// one, and new keys are added at the end, so entries only ever move to
+    /// lower positions. A key present for the whole iteration is therefore
+    /// always returned, though possibly more than once, and each call resumes
+    /// straight from its cursor instead of walking the whole keyspace. With
+    /// `dashmap` the cursor also holds which shard it is in, see
+    /// `Keys::scan`.
+    pub fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&str>,
+        type_filter: Option<&str>,
+    ) -> (u64, Vec<String>) {
+        let db = self.db().read();
+        let (next_cursor, entries) = db.scan(cursor, count);
+
+        let keys = entries
+            .into_iter()
+            .filter(|entry| {
+                !entry.is_expired()
+                    && pattern.is_none_or(|p| glob_match(p, entry.key()))
+                    && type_filter.is_none_or(|t| entry.data.type_name().eq_ignore_ascii_case(t))
+            })
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        (next_cursor, keys)
+    }
+
+    /// Up to `count` keys of the selected database that hash to `slot`
+    /// (CLUSTER GETKEYSINSLOT / COUNTKEYSINSLOT)
+    pub fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<String> {
+        let db = self.db().read();
+        db.iter()
+            .filter(|entry| !entry.is_expired() && cluster::key_slot(entry.key()) == slot)
+            .take(count)
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Cursor-based iteration over the members of a set (SSCAN)
+    /// See `SetData::scan` for the cursor scheme
+    pub fn sscan(
+        &self,
+        key: &str,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&str>,
+    ) -> Result<(u64, Vec<String>), String> {
+        let db = self.db().read_key(key);
+        let Some(entry) = db.get(key).filter(|entry| !entry.is_expired()) else {
+            return Ok((0, vec![]));
+        };
 
-        // Delete them
-        for key in to_delete {
-            db.remove(&key);
+        entry.touch();
+        match &entry.data {
+            DataType::Set(set) => {
+                let (next_cursor, batch) = set.scan(cursor, count);
+                let members = batch
+                    .into_iter()
+                    .filter(|member| pattern.is_none_or(|p| glob_match(p, member)))
+                    .map(|member| member.to_string())
+                    .collect();
+                Ok((next_cursor, members))
+            }
+            _ => {
+                Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            }
         }
+    }
+
+    /// Cursor-based iteration over the members of a sorted set (ZSCAN)
+    /// See `SortedSetData::scan` for the cursor scheme
+    pub fn zscan(
+        &self,
+        key: &str,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&str>,
+    ) -> Result<(u64, Vec<ScoredMember>), String> {
+        let db = self.db().read_key(key);
+        let Some(entry) = db.get(key).filter(|entry| !entry.is_expired()) else {
+            return Ok((0, vec![]));
+        };
 
-        count
+        entry.touch();
+        match &entry.data {
+            DataType::SortedSet(zset) => {
+                let (next_cursor, batch) = zset.scan(cursor, count);
+                let members = batch
+                    .into_iter()
+                    .filter(|(member, _)| pattern.is_none_or(|p| glob_match(p, member)))
+                    .map(|(member, score)| (member.to_string(), score))
+                    .collect();
+                Ok((next_cursor, members))
+            }
+            _ => {
+                Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            }
+        }
     }
 
     // ====== LIST OPERATIONS =====
@@ -237,20 +2291,22 @@ impl FerroStore {
     /// Creates the list if it doesnt exist
     ///Returns new Length of the list
     pub fn lpush(&self, key: &str, values: Vec<String>) -> Result<usize, String> {
-        let mut db = self.db.write().unwrap();
+        let limits = self.pack_limits();
+        let mut db = self.db().write_key(key);
 
-        let entry = db
-            .entry(key.to_string())
-            .or_insert(ValueWithExpiry::new_list());
+        let mut entry = db.get_or_insert_with(key, ValueWithExpiry::new_list);
         if entry.is_expired() {
             *entry = ValueWithExpiry::new_list();
         }
 
+        entry.touch();
         match &mut entry.data {
             DataType::List(list) => {
                 for value in values.into_iter() {
-                    list.push_front(value);
+                    list.push_front(value, &limits);
                 }
+                self.modified(key);
+                self.waiters.notify(key);
                 Ok(list.len())
             }
             _ => {
@@ -259,20 +2315,22 @@ impl FerroStore {
         }
     }
     pub fn rpush(&self, key: &str, values: Vec<String>) -> Result<usize, String> {
-        let mut db = self.db.write().unwrap();
+        let limits = self.pack_limits();
+        let mut db = self.db().write_key(key);
 
-        let entry = db
-            .entry(key.to_string())
-            .or_insert(ValueWithExpiry::new_list());
+        let mut entry = db.get_or_insert_with(key, ValueWithExpiry::new_list);
         if entry.is_expired() {
             *entry = ValueWithExpiry::new_list();
         }
 
+        entry.touch();
         match &mut entry.data {
             DataType::List(list) => {
                 for value in values.into_iter() {
-                    list.push_back(value);
+                    list.push_back(value, &limits);
                 }
+                self.modified(key);
+                self.waiters.notify(key);
                 Ok(list.len())
             }
             _ => {
@@ -281,83 +2339,82 @@ impl FerroStore {
         }
     }
     pub fn lpop(&self, key: &str, count: Option<usize>) -> Result<Vec<String>, String> {
-        let mut db = self.db.write().unwrap();
+        let mut db = self.db().write_key(key);
 
-        if let Some(entry) = db.get_mut(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return Ok(vec![]);
-            }
-
-            match &mut entry.data {
-                DataType::List(list) => {
-                    let count = count.unwrap_or(1);
+        if self.expire_if_due(&mut db, key) {
+            return Ok(vec![]);
+        }
+        let Some(mut entry) = db.get_mut(key) else {
+            return Ok(vec![]);
+        };
+        entry.touch();
+        match &mut entry.data {
+            DataType::List(list) => {
+                let count = count.unwrap_or(1);
 
-                    let mut result: Vec<String> = Vec::new();
-                    for _ in 0..count {
-                        if let Some(value) = list.pop_front() {
-                            result.push(value);
-                        } else {
-                            break;
-                        }
-                    }
-                    if list.is_empty() {
-                        db.remove(key);
+                let mut result: Vec<String> = Vec::new();
+                for _ in 0..count {
+                    if let Some(value) = list.pop_front() {
+                        result.push(value);
+                    } else {
+                        break;
                     }
-                    Ok(result)
                 }
-                _ => Err(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                ),
+                if !result.is_empty() {
+                    self.modified(key);
+                }
+                if list.is_empty() {
+                    drop(entry);
+                    db.remove(key);
+                }
+                Ok(result)
+            }
+            _ => {
+                Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
             }
-        } else {
-            Ok(vec![])
         }
     }
     pub fn rpop(&self, key: &str, count: Option<usize>) -> Result<Vec<String>, String> {
-        let mut db = self.db.write().unwrap();
-
-        if let Some(entry) = db.get_mut(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return Ok(vec![]);
-            }
+        let mut db = self.db().write_key(key);
 
-            match &mut entry.data {
-                DataType::List(list) => {
-                    let count = count.unwrap_or(1);
+        if self.expire_if_due(&mut db, key) {
+            return Ok(vec![]);
+        }
+        let Some(mut entry) = db.get_mut(key) else {
+            return Ok(vec![]);
+        };
+        entry.touch();
+        match &mut entry.data {
+            DataType::List(list) => {
+                let count = count.unwrap_or(1);
 
-                    let mut result: Vec<String> = Vec::new();
-                    for _ in 0..count {
-                        if let Some(value) = list.pop_back() {
-                            result.push(value);
-                        } else {
-                            break;
-                        }
-                    }
-                    if list.is_empty() {
-                        db.remove(key);
+                let mut result: Vec<String> = Vec::new();
+                for _ in 0..count {
+                    if let Some(value) = list.pop_back() {
+                        result.push(value);
+                    } else {
+                        break;
                     }
-                    Ok(result)
                 }
-                _ => Err(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                ),
+                if !result.is_empty() {
+                    self.modified(key);
+                }
+                if list.is_empty() {
+                    drop(entry);
+                    db.remove(key);
+                }
+                Ok(result)
+            }
+            _ => {
+                Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
             }
-        } else {
-            Ok(vec![])
         }
     }
 
     pub fn llen(&self, key: &str) -> Result<usize, String> {
-        let mut db = self.db.write().unwrap();
-
+        let db = self.read_live(key);
         if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return Ok(0);
-            }
-
+            entry.touch();
             match &entry.data {
                 DataType::List(list) => Ok(list.len()),
                 _ => Err(
@@ -370,12 +2427,9 @@ impl FerroStore {
     }
 
     pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, String> {
-        let mut db = self.db.write().unwrap();
+        let db = self.read_live(key);
         if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return Ok(vec![]);
-            }
+            entry.touch();
             match &entry.data {
                 DataType::List(list) => {
                     let len = list.len() as i64;
@@ -396,7 +2450,7 @@ impl FerroStore {
                         .iter()
                         .skip(start as usize)
                         .take((stop - start + 1) as usize)
-                        .cloned()
+                        .map(str::to_string)
                         .collect();
                     Ok(result)
                 }
@@ -410,24 +2464,218 @@ impl FerroStore {
         }
     }
 
+    /// Atomically pop an element from one end of `src` and push it onto one end of `dst`
+    /// (LMOVE / RPOPLPUSH). Both keys are handled under a single lock.
+    /// Returns None if `src` doesn't exist
+    pub fn lmove(
+        &self,
+        src: &str,
+        dst: &str,
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<String>, String> {
+        let limits = self.pack_limits();
+        let mut db = self.db().write();
+
+        if db.get(dst).is_some_and(|entry| entry.is_expired()) {
+            db.remove(dst);
+        }
+        // Check the destination type before touching the source
+        if let Some(entry) = db.get(dst)
+            && !matches!(entry.data, DataType::List(_))
+        {
+            return Err(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            );
+        }
+
+        if db.get(src).is_some_and(|entry| entry.is_expired()) {
+            db.remove(src);
+            return Ok(None);
+        }
+        let Some(mut entry) = db.get_mut(src) else {
+            return Ok(None);
+        };
+        entry.touch();
+        let DataType::List(list) = &mut entry.data else {
+            return Err(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            );
+        };
+        let value = match from {
+            ListEnd::Left => list.pop_front(),
+            ListEnd::Right => list.pop_back(),
+        };
+        if value.is_some() {
+            self.modified(src);
+        }
+        let emptied = list.is_empty();
+        drop(entry);
+        if emptied {
+            db.remove(src);
+        }
+        let Some(value) = value else {
+            return Ok(None);
+        };
+
+        let mut entry = db.get_or_insert_with(dst, ValueWithExpiry::new_list);
+        entry.touch();
+        if let DataType::List(list) = &mut entry.data {
+            match to {
+                ListEnd::Left => list.push_front(value.clone(), &limits),
+                ListEnd::Right => list.push_back(value.clone(), &limits),
+            }
+        }
+        self.modified(dst);
+        self.waiters.notify(dst);
+        Ok(Some(value))
+    }
+
+    /// Pop up to `count` elements from the first non-empty list among `keys` (LMPOP)
+    /// Returns the key popped from along with the elements
+    pub fn lmpop(
+        &self,
+        keys: &[String],
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Option<(String, Vec<String>)>, String> {
+        let mut db = self.db().write();
+
+        for key in keys {
+            let Some(mut entry) = db.get_mut(key) else {
+                continue;
+            };
+            if entry.is_expired() {
+                drop(entry);
+                self.expire_key(&mut db, key);
+                continue;
+            }
+
+            entry.touch();
+            match &mut entry.data {
+                DataType::List(list) => {
+                    let n = count.min(list.len());
+                    let popped: Vec<String> = match end {
+                        ListEnd::Left => list.drain_front(n),
+                        ListEnd::Right => (0..n).filter_map(|_| list.pop_back()).collect(),
+                    };
+                    self.modified(key);
+                    if list.is_empty() {
+                        drop(entry);
+                        db.remove(key);
+                    }
+                    return Ok(Some((key.clone(), popped)));
+                }
+                _ => {
+                    return Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    );
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn lindex(&self, key: &str, index: i64) -> Result<Option<String>, String> {
+        let db = self.read_live(key);
+        if let Some(entry) = db.get(key) {
+            entry.touch();
+            match &entry.data {
+                DataType::List(list) => Ok(list_position(list.len(), index)
+                    .and_then(|i| list.get(i))
+                    .map(str::to_string)),
+                _ => Err(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+                ),
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn lset(&self, key: &str, index: i64, value: String) -> Result<(), String> {
+        let limits = self.pack_limits();
+        let mut db = self.db().write_key(key);
+        if self.expire_if_due(&mut db, key) {
+            return Err("ERR no such key".to_string());
+        }
+        if let Some(mut entry) = db.get_mut(key) {
+            entry.touch();
+            match &mut entry.data {
+                DataType::List(list) => match list_position(list.len(), index) {
+                    Some(i) => {
+                        list.set(i, value, &limits);
+                        self.modified(key);
+                        Ok(())
+                    }
+                    None => Err("ERR index out of range".to_string()),
+                },
+                _ => Err(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+                ),
+            }
+        } else {
+            Err("ERR no such key".to_string())
+        }
+    }
+
+    /// Insert `value` before or after the first occurrence of `pivot` (LINSERT)
+    /// Returns the new list length, -1 if the pivot wasn't found, or 0 if the key doesn't exist
+    pub fn linsert(
+        &self,
+        key: &str,
+        before: bool,
+        pivot: &str,
+        value: String,
+    ) -> Result<i64, String> {
+        let limits = self.pack_limits();
+        let mut db = self.db().write_key(key);
+        if self.expire_if_due(&mut db, key) {
+            return Ok(0);
+        }
+        if let Some(mut entry) = db.get_mut(key) {
+            entry.touch();
+            match &mut entry.data {
+                DataType::List(list) => match list.iter().position(|item| item == pivot) {
+                    Some(i) => {
+                        list.insert(if before { i } else { i + 1 }, value, &limits);
+                        self.modified(key);
+                        Ok(list.len() as i64)
+                    }
+                    None => Ok(-1),
+                },
+                _ => Err(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+                ),
+            }
+        } else {
+            Ok(0)
+        }
+    }
+
     // Set Functions
     pub fn sadd(&self, key: &str, members: Vec<String>) -> Result<usize, String> {
-        let mut db = self.db.write().unwrap();
-        let entry = db
-            .entry(key.to_string())
-            .or_insert(ValueWithExpiry::new_set());
+        let limits = self.pack_limits();
+        let mut db = self.db().write_key(key);
+        let mut entry = db.get_or_insert_with(key, ValueWithExpiry::new_set);
         if entry.is_expired() {
             *entry = ValueWithExpiry::new_set();
         }
 
+        entry.touch();
         match &mut entry.data {
             DataType::Set(set) => {
                 let mut added = 0;
                 for member in members {
-                    if set.insert(member) {
+                    if set.insert(member, &limits) {
                         added += 1;
                     }
                 }
+                if added > 0 {
+                    self.modified(key);
+                }
                 Ok(added)
             }
             _ => {
@@ -437,45 +2685,93 @@ impl FerroStore {
     }
 
     pub fn srem(&self, key: &str, members: Vec<String>) -> Result<usize, String> {
-        let mut db = self.db.write().unwrap();
-        if let Some(entry) = db.get_mut(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return Ok(0);
-            }
-
-            match &mut entry.data {
-                DataType::Set(set) => {
-                    let mut removed = 0;
-                    for member in members {
-                        if set.remove(&member) {
-                            removed += 1;
-                        }
-                    }
-                    if set.is_empty() {
-                        db.remove(key);
+        let mut db = self.db().write_key(key);
+        if self.expire_if_due(&mut db, key) {
+            return Ok(0);
+        }
+        let Some(mut entry) = db.get_mut(key) else {
+            return Ok(0);
+        };
+        entry.touch();
+        match &mut entry.data {
+            DataType::Set(set) => {
+                let mut removed = 0;
+                for member in members {
+                    if set.remove(&member) {
+                        removed += 1;
                     }
-                    Ok(removed)
                 }
-                _ => Err(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                ),
+                if removed > 0 {
+                    self.modified(key);
+                }
+                if set.is_empty() {
+                    drop(entry);
+                    db.remove(key);
+                }
+                Ok(removed)
             }
-        } else {
-            Ok(0)
+            _ => {
+                Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            }
+        }
+    }
+
+    /// Atomically move `member` from the set at `src` to the set at `dst` (SMOVE)
+    /// Returns false if `member` isn't in `src`
+    pub fn smove(&self, src: &str, dst: &str, member: &str) -> Result<bool, String> {
+        let limits = self.pack_limits();
+        let mut db = self.db().write();
+
+        if db.get(dst).is_some_and(|entry| entry.is_expired()) {
+            db.remove(dst);
+        }
+        // Check the destination type before touching the source
+        if let Some(entry) = db.get(dst)
+            && !matches!(entry.data, DataType::Set(_))
+        {
+            return Err(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            );
         }
+
+        if db.get(src).is_some_and(|entry| entry.is_expired()) {
+            db.remove(src);
+            return Ok(false);
+        }
+        let Some(mut entry) = db.get_mut(src) else {
+            return Ok(false);
+        };
+        entry.touch();
+        let DataType::Set(set) = &mut entry.data else {
+            return Err(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            );
+        };
+        if !set.remove(member) {
+            return Ok(false);
+        }
+        let emptied = set.is_empty();
+        drop(entry);
+        if emptied {
+            db.remove(src);
+        }
+
+        let mut entry = db.get_or_insert_with(dst, ValueWithExpiry::new_set);
+        entry.touch();
+        if let DataType::Set(set) = &mut entry.data {
+            set.insert(member.to_string(), &limits);
+        }
+        self.modified(src);
+        self.modified(dst);
+        Ok(true)
     }
 
     pub fn smembers(&self, key: &str) -> Result<Vec<String>, String> {
-        let mut db = self.db.write().unwrap();
-
+        let db = self.read_live(key);
         if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return Ok(vec![]);
-            }
+            entry.touch();
             match &entry.data {
-                DataType::Set(set) => Ok(set.iter().cloned().collect()),
+                DataType::Set(set) => Ok(set.iter().map(str::to_string).collect()),
                 _ => Err(
                     "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
                 ),
@@ -486,13 +2782,9 @@ impl FerroStore {
     }
 
     pub fn sismember(&self, key: &str, member: &str) -> Result<bool, String> {
-        let mut db = self.db.write().unwrap();
-
+        let db = self.read_live(key);
         if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return Ok(false);
-            }
+            entry.touch();
             match &entry.data {
                 DataType::Set(set) => Ok(set.contains(member)),
                 _ => Err(
@@ -505,13 +2797,9 @@ impl FerroStore {
     }
 
     pub fn scard(&self, key: &str) -> Result<usize, String> {
-        let mut db = self.db.write().unwrap();
-
+        let db = self.read_live(key);
         if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                db.remove(key);
-                return Ok(0);
-            }
+            entry.touch();
             match &entry.data {
                 DataType::Set(set) => Ok(set.len()),
                 _ => Err(
@@ -523,17 +2811,19 @@ impl FerroStore {
         }
     }
 
+    #[allow(clippy::collapsible_if)]
     pub fn sinter(&self, keys: Vec<String>) -> Result<Vec<String>, String> {
         if keys.is_empty() {
             return Ok(vec![]);
         }
-        let db = self.db.read().unwrap();
+        let db = self.db().read();
         let first_key = &keys[0];
         let mut result: Option<HashSet<String>> = None;
         if let Some(entry) = db.get(first_key) {
             if !entry.is_expired() {
+                entry.touch();
                 if let DataType::Set(set) = &entry.data {
-                    result = Some(set.clone());
+                    result = Some(set.iter().map(str::to_string).collect());
                 } else {
                     return Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -550,8 +2840,9 @@ impl FerroStore {
         for key in &keys[1..] {
             if let Some(entry) = db.get(key) {
                 if !entry.is_expired() {
+                    entry.touch();
                     if let DataType::Set(set) = &entry.data {
-                        result_set = result_set.intersection(set).cloned().collect();
+                        result_set.retain(|member| set.contains(member));
                     } else {
                         return Err(
                             "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -567,19 +2858,21 @@ impl FerroStore {
 
         Ok(result_set.into_iter().collect())
     }
+    #[allow(clippy::collapsible_if)]
     pub fn sunion(&self, keys: Vec<String>) -> Result<Vec<String>, String> {
         if keys.is_empty() {
             return Ok(vec![]);
         }
 
-        let db = self.db.read().unwrap();
+        let db = self.db().read();
         let mut result_set = HashSet::new();
 
         for key in keys {
             if let Some(entry) = db.get(&key) {
                 if !entry.is_expired() {
+                    entry.touch();
                     if let DataType::Set(set) = &entry.data {
-                        result_set = result_set.union(set).cloned().collect();
+                        result_set.extend(set.iter().map(str::to_string));
                     } else {
                         return Err(
                             "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -592,12 +2885,13 @@ impl FerroStore {
 
         Ok(result_set.into_iter().collect())
     }
+    #[allow(clippy::collapsible_if)]
     pub fn sdiff(&self, keys: Vec<String>) -> Result<Vec<String>, String> {
         if keys.is_empty() {
             return Ok(vec![]);
         }
 
-        let db = self.db.read().unwrap();
+        let db = self.db().read();
 
         // Get first set
         let first_key = &keys[0];
@@ -605,8 +2899,9 @@ impl FerroStore {
 
         if let Some(entry) = db.get(first_key) {
             if !entry.is_expired() {
+                entry.touch();
                 if let DataType::Set(set) = &entry.data {
-                    result_set = set.clone();
+                    result_set = set.iter().map(str::to_string).collect();
                 } else {
                     return Err(
                         "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -620,8 +2915,9 @@ impl FerroStore {
         for key in &keys[1..] {
             if let Some(entry) = db.get(key) {
                 if !entry.is_expired() {
+                    entry.touch();
                     if let DataType::Set(set) = &entry.data {
-                        result_set = result_set.difference(set).cloned().collect();
+                        result_set.retain(|member| !set.contains(member));
                     } else {
                         return Err(
                             "WRONGTYPE Operation against a key holding the wrong kind of value"
@@ -635,50 +2931,30 @@ impl FerroStore {
         Ok(result_set.into_iter().collect())
     }
     pub fn zadd(&self, key: &str, members: Vec<(f64, String)>) -> Result<usize, String> {
-        let mut db = self.db.write().unwrap();
+        let limits = self.pack_limits();
+        let mut db = self.db().write_key(key);
 
-        let entry = db
-            .entry(key.to_string())
-            .or_insert_with(|| ValueWithExpiry {
-                data: DataType::SortedSet(SortedSetData::new()),
-                expires_at: None,
-            });
+        let mut entry = db.get_or_insert_with(key, || {
+            ValueWithExpiry::new(DataType::SortedSet(SortedSetData::new()), None)
+        });
 
         if entry.is_expired() {
-            *entry = ValueWithExpiry {
-                data: DataType::SortedSet(SortedSetData::new()),
-                expires_at: None,
-            };
+            *entry = ValueWithExpiry::new(DataType::SortedSet(SortedSetData::new()), None);
         }
 
+        entry.touch();
         match &mut entry.data {
             DataType::SortedSet(zset) => {
                 let mut added = 0;
 
                 for (score, member) in members {
-                    let score_key = OrderedFloat(score);
-
-                    // Check if member already exists
-                    if let Some(old_score) = zset.members.get(&member) {
-                        // Remove from old score bucket
-                        if let Some(bucket) = zset.scores.get_mut(old_score) {
-                            bucket.remove(&member);
-                            if bucket.is_empty() {
-                                zset.scores.remove(old_score);
-                            }
-                        }
-                    } else {
+                    if zset.insert(member, score, &limits) {
                         added += 1;
                     }
-
-                    // Add to new score bucket
-                    zset.scores
-                        .entry(score_key)
-                        .or_insert_with(HashSet::new)
-                        .insert(member.clone());
-                    zset.members.insert(member, score_key);
                 }
 
+                self.modified(key);
+                self.waiters.notify(key);
                 Ok(added)
             }
             _ => {
@@ -689,58 +2965,271 @@ impl FerroStore {
 
     /// Remove members from sorted set
     pub fn zrem(&self, key: &str, members: Vec<String>) -> Result<usize, String> {
-        let mut db = self.db.write().unwrap();
+        let mut db = self.db().write_key(key);
+
+        if self.expire_if_due(&mut db, key) {
+            return Ok(0);
+        }
+        let Some(mut entry) = db.get_mut(key) else {
+            return Ok(0);
+        };
+        entry.touch();
+        match &mut entry.data {
+            DataType::SortedSet(zset) => {
+                let mut removed = 0;
+
+                for member in members {
+                    if zset.remove(&member).is_some() {
+                        removed += 1;
+                    }
+                }
 
-        if let Some(entry) = db.get_mut(key) {
+                if removed > 0 {
+                    self.modified(key);
+                }
+                // Remove key if empty
+                if zset.is_empty() {
+                    drop(entry);
+                    db.remove(key);
+                }
+
+                Ok(removed)
+            }
+            _ => {
+                Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            }
+        }
+    }
+
+    /// Pop up to `count` members from the first non-empty sorted set among `keys` (ZMPOP)
+    /// Returns the key popped from along with the (member, score) pairs
+    pub fn zmpop(
+        &self,
+        keys: &[String],
+        end: ScoreEnd,
+        count: usize,
+    ) -> Result<Option<(String, Vec<ScoredMember>)>, String> {
+        let mut db = self.db().write();
+
+        for key in keys {
+            let Some(mut entry) = db.get_mut(key) else {
+                continue;
+            };
             if entry.is_expired() {
-                db.remove(key);
-                return Ok(0);
+                drop(entry);
+                self.expire_key(&mut db, key);
+                continue;
             }
 
+            entry.touch();
             match &mut entry.data {
                 DataType::SortedSet(zset) => {
-                    let mut removed = 0;
+                    let popped: Vec<ScoredMember> =
+                        (0..count).map_while(|_| zset.pop(end)).collect();
+                    if !popped.is_empty() {
+                        self.modified(key);
+                    }
+                    if zset.is_empty() {
+                        drop(entry);
+                        db.remove(key);
+                    }
+                    return Ok(Some((key.clone(), popped)));
+                }
+                _ => {
+                    return Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    );
+                }
+            }
+        }
 
-                    for member in members {
-                        if let Some(score) = zset.members.remove(&member) {
-                            removed += 1;
+        Ok(None)
+    }
 
-                            if let Some(bucket) = zset.scores.get_mut(&score) {
-                                bucket.remove(&member);
-                                if bucket.is_empty() {
-                                    zset.scores.remove(&score);
-                                }
-                            }
-                        }
+    /// Pop up to `count` of the lowest or highest scored members (ZPOPMIN / ZPOPMAX)
+    pub fn zpop(
+        &self,
+        key: &str,
+        end: ScoreEnd,
+        count: usize,
+    ) -> Result<Vec<ScoredMember>, String> {
+        let popped = self.zmpop(&[key.to_string()], end, count)?;
+        Ok(popped.map(|(_, members)| members).unwrap_or_default())
+    }
+
+    /// Get the scores of several members at once (ZMSCORE)
+    pub fn zmscore(&self, key: &str, members: &[String]) -> Result<Vec<Option<f64>>, String> {
+        let db = self.db().read_key(key);
+
+        match db.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.touch();
+                match &entry.data {
+                    DataType::SortedSet(zset) => {
+                        Ok(members.iter().map(|member| zset.score(member)).collect())
                     }
+                    _ => Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    ),
+                }
+            }
+            _ => Ok(vec![None; members.len()]),
+        }
+    }
 
-                    // Remove key if empty
-                    if zset.is_empty() {
-                        db.remove(key);
+    /// Sample random members (ZRANDMEMBER)
+    /// A positive `count` returns up to `count` distinct members; a negative one
+    /// returns exactly `-count` members, possibly repeating
+    pub fn zrandmember(&self, key: &str, count: i64) -> Result<Vec<ScoredMember>, String> {
+        let db = self.db().read_key(key);
+
+        match db.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.touch();
+                match &entry.data {
+                    DataType::SortedSet(zset) => {
+                        let mut all: Vec<(&str, f64)> = zset.iter().collect();
+                        let picked: Vec<(&str, f64)> = if count < 0 {
+                            (0..count.unsigned_abs())
+                                .map(|_| all[fastrand::usize(..all.len())])
+                                .collect()
+                        } else {
+                            // Partial Fisher-Yates shuffle for distinct members
+                            let n = (count as usize).min(all.len());
+                            for i in 0..n {
+                                let j = fastrand::usize(i..all.len());
+                                all.swap(i, j);
+                            }
+                            all.truncate(n);
+                            all
+                        };
+                        Ok(picked
+                            .into_iter()
+                            .map(|(member, score)| (member.to_string(), score))
+                            .collect())
                     }
+                    _ => Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    ),
+                }
+            }
+            _ => Ok(vec![]),
+        }
+    }
 
-                    Ok(removed)
+    /// Count members with a score in `min..max` (ZCOUNT)
+    pub fn zcount(&self, key: &str, min: ScoreBound, max: ScoreBound) -> Result<usize, String> {
+        let db = self.db().read_key(key);
+
+        match db.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.touch();
+                match &entry.data {
+                    DataType::SortedSet(zset) => Ok(zset.count_by_score(min, max)),
+                    _ => Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    ),
                 }
-                _ => Err(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                ),
             }
-        } else {
-            Ok(0)
+            _ => Ok(0),
+        }
+    }
+
+    /// Get members with a score in `min..max`, skipping `offset` of them and
+    /// returning at most `count` (ZRANGEBYSCORE ... LIMIT offset count)
+    pub fn zrange_by_score(
+        &self,
+        key: &str,
+        min: ScoreBound,
+        max: ScoreBound,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Result<Vec<ScoredMember>, String> {
+        let query = ZRangeQuery {
+            offset,
+            count,
+            ..ZRangeQuery::new(ZRangeBy::Score(min, max))
+        };
+        self.zrange_query(key, &query)
+    }
+
+    /// Count members in the lexicographical range `min..max` (ZLEXCOUNT)
+    pub fn zlexcount(&self, key: &str, min: &LexBound, max: &LexBound) -> Result<usize, String> {
+        let db = self.db().read_key(key);
+
+        match db.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.touch();
+                match &entry.data {
+                    DataType::SortedSet(zset) => Ok(zset.count_by_lex(min, max)),
+                    _ => Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    ),
+                }
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Get members in the lexicographical range `min..max`, skipping `offset`
+    /// of them and returning at most `count` (ZRANGEBYLEX ... LIMIT offset count)
+    pub fn zrange_by_lex(
+        &self,
+        key: &str,
+        min: &LexBound,
+        max: &LexBound,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Result<Vec<String>, String> {
+        let query = ZRangeQuery {
+            offset,
+            count,
+            ..ZRangeQuery::new(ZRangeBy::Lex(min.clone(), max.clone()))
+        };
+        let members = self.zrange_query(key, &query)?;
+        Ok(members.into_iter().map(|(member, _)| member).collect())
+    }
+
+    /// Run a ZRANGE query (any of index / score / lex ranges, optionally reversed)
+    pub fn zrange_query(
+        &self,
+        key: &str,
+        query: &ZRangeQuery,
+    ) -> Result<Vec<ScoredMember>, String> {
+        let db = self.db().read_key(key);
+
+        match db.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.touch();
+                match &entry.data {
+                    DataType::SortedSet(zset) => Ok(zset.query(query)),
+                    _ => Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    ),
+                }
+            }
+            _ => Ok(vec![]),
         }
     }
 
     /// Get score of a member
     pub fn zscore(&self, key: &str, member: &str) -> Result<Option<f64>, String> {
-        let db = self.db.read().unwrap();
+        let db = self.db().read_key(key);
 
         if let Some(entry) = db.get(key) {
             if entry.is_expired() {
                 return Ok(None);
             }
 
+            entry.touch();
             match &entry.data {
-                DataType::SortedSet(zset) => Ok(zset.members.get(member).map(|s| s.0)),
+                DataType::SortedSet(zset) => Ok(zset.score(member)),
                 _ => Err(
                     "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
                 ),
@@ -759,120 +3248,59 @@ impl FerroStore {
         stop: i64,
         with_scores: bool,
     ) -> Result<Vec<String>, String> {
-        let db = self.db.read().unwrap();
-
-        if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                return Ok(vec![]);
-            }
-
-            match &entry.data {
-                DataType::SortedSet(zset) => {
-                    // Flatten to vector: (member, score)
-                    let mut all_members: Vec<(String, f64)> = Vec::new();
-                    for (score, members) in &zset.scores {
-                        for member in members {
-                            all_members.push((member.clone(), score.0));
-                        }
-                    }
-
-                    let len = all_members.len() as i64;
-
-                    // Convert negative indices
-                    let start = if start < 0 {
-                        (len + start).max(0)
-                    } else {
-                        start.min(len)
-                    };
-                    let stop = if stop < 0 {
-                        (len + stop).max(-1)
-                    } else {
-                        stop.min(len - 1)
-                    };
-
-                    if start > stop || start >= len {
-                        return Ok(vec![]);
-                    }
-
-                    let range: Vec<String> = all_members
-                        .into_iter()
-                        .skip(start as usize)
-                        .take((stop - start + 1) as usize)
-                        .flat_map(|(member, score)| {
-                            if with_scores {
-                                vec![member, score.to_string()]
-                            } else {
-                                vec![member]
-                            }
-                        })
-                        .collect();
-
-                    Ok(range)
+        let members = self.zrange_query(key, &ZRangeQuery::new(ZRangeBy::Index(start, stop)))?;
+        Ok(members
+            .into_iter()
+            .flat_map(|(member, score)| {
+                if with_scores {
+                    vec![member, score.to_string()]
+                } else {
+                    vec![member]
                 }
-                _ => Err(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                ),
-            }
-        } else {
-            Ok(vec![])
-        }
+            })
+            .collect())
     }
 
     /// Get rank (index) of member (0-based)
     pub fn zrank(&self, key: &str, member: &str) -> Result<Option<usize>, String> {
-        let db = self.db.read().unwrap();
-
-        if let Some(entry) = db.get(key) {
-            if entry.is_expired() {
-                return Ok(None);
-            }
-
-            match &entry.data {
-                DataType::SortedSet(zset) => {
-                    // Check if member exists
-                    if !zset.members.contains_key(member) {
-                        return Ok(None);
-                    }
+        self.zrank_in_order(key, member, false)
+    }
 
-                    // Count members before this one
-                    let target_score = zset.members.get(member).unwrap();
-                    let mut rank = 0;
-
-                    for (score, members) in &zset.scores {
-                        if score < target_score {
-                            rank += members.len();
-                        } else if score == target_score {
-                            // Count members in same score bucket that come before alphabetically
-                            for m in members {
-                                if m.as_str() < member {
-                                    rank += 1;
-                                } else if m == member {
-                                    return Ok(Some(rank));
-                                }
-                            }
-                        }
-                    }
+    /// Get rank of member counting from the highest score (ZREVRANK)
+    pub fn zrevrank(&self, key: &str, member: &str) -> Result<Option<usize>, String> {
+        self.zrank_in_order(key, member, true)
+    }
 
-                    Ok(Some(rank))
+    fn zrank_in_order(&self, key: &str, member: &str, rev: bool) -> Result<Option<usize>, String> {
+        let db = self.db().read_key(key);
+
+        match db.get(key) {
+            Some(entry) if !entry.is_expired() => {
+                entry.touch();
+                match &entry.data {
+                    DataType::SortedSet(zset) => Ok(zset
+                        .rank(member)
+                        .map(|rank| if rev { zset.len() - 1 - rank } else { rank })),
+                    _ => Err(
+                        "WRONGTYPE Operation against a key holding the wrong kind of value"
+                            .to_string(),
+                    ),
                 }
-                _ => Err(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                ),
             }
-        } else {
-            Ok(None)
+            _ => Ok(None),
         }
     }
 
     /// Get cardinality (size) of sorted set
     pub fn zcard(&self, key: &str) -> Result<usize, String> {
-        let db = self.db.read().unwrap();
+        let db = self.db().read_key(key);
 
         if let Some(entry) = db.get(key) {
             if entry.is_expired() {
                 return Ok(0);
             }
 
+            entry.touch();
             match &entry.data {
                 DataType::SortedSet(zset) => Ok(zset.len()),
                 _ => Err(
@@ -884,45 +3312,325 @@ impl FerroStore {
         }
     }
 
+    /// Sort the elements of a list, set or sorted set (SORT)
+    /// Returns one entry per element, or one per GET pattern per element;
+    /// lookups that find no string value give None
+    pub fn sort(&self, key: &str, options: &SortOptions) -> Result<Vec<Option<String>>, String> {
+        let db = self.db().read();
+        sort_elements(&db, key, options)
+    }
+
+    /// SORT ... STORE: replace `dest` with the sorted result as a list
+    /// Missing lookups are stored as empty strings and an empty result
+    /// deletes `dest`. Returns the number of stored elements
+    pub fn sort_store(
+        &self,
+        key: &str,
+        dest: &str,
+        options: &SortOptions,
+    ) -> Result<usize, String> {
+        let mut db = self.db().write();
+        let sorted = sort_elements(&db, key, options)?;
+
+        let len = sorted.len();
+        self.modified(dest);
+        if len == 0 {
+            db.remove(dest);
+            return Ok(0);
+        }
+        let mut list = DataType::List(sorted.into_iter().map(Option::unwrap_or_default).collect());
+        list.repack(&self.pack_limits());
+        self.insert(&mut db, dest.to_string(), ValueWithExpiry::new(list, None));
+        self.waiters.notify(dest);
+        Ok(len)
+    }
+
     // Storange Functions
-    /// Create a snapshot for the database for persistance
-    /// Returns: HashMap<Key, (DataType, Option<Instant>)>
-    pub fn snapshot(&self) -> HashMap<String, (DataType, Option<Instant>)> {
-        let db = self.db.read().unwrap();
-        db.iter()
-            .map(|(k, v)| (k.clone(), (v.data.clone(), v.expires_at)))
-            .collect()
+    /// Every database as SAVE writes it out, all as they are at this
+    /// moment. Only key names are copied, with values changed from then on
+    /// kept aside until the snapshot is dropped (or with `imbl` the maps
+    /// are shared until written to), so writers only wait while the keys
+    /// are listed. Take it holding the exec lock, shared or not, so no
+    /// transaction is caught half applied
+    pub fn snapshot(&self, saving: SaveGuard) -> StoreSnapshot {
+        let databases = keyspace::snapshot(&self.databases)
+            .into_iter()
+            .enumerate()
+            .map(|(index, snapshot)| DbSnapshot {
+                databases: self.databases.clone(),
+                index,
+                snapshot,
+            })
+            .collect();
+        StoreSnapshot {
+            databases,
+            _saving: saving,
+        }
+    }
+
+    /// Get a copy of a key's raw value (used by DUMP)
+    pub fn get_value(&self, key: &str) -> Option<DataType> {
+        let db = self.db().read_key(key);
+        db.get(key)
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| entry.data.clone())
+    }
+
+    /// Store a deserialized value under `key` (RESTORE)
+    /// Fails with BUSYKEY if the key exists and `replace` is not set. A
+    /// zero `ttl` has already run out, so the key is deleted instead
+    pub fn restore(
+        &self,
+        key: String,
+        mut data: DataType,
+        ttl: Option<Duration>,
+        replace: bool,
+    ) -> Result<(), String> {
+        let mut db = self.db().write_key(&key);
+        if !replace && db.get(&key).is_some_and(|entry| !entry.is_expired()) {
+            return Err("BUSYKEY Target key name already exists.".to_string());
+        }
+        if ttl == Some(Duration::ZERO) {
+            if db.remove(&key).is_some() {
+                self.modified(&key);
+            }
+            return Ok(());
+        }
+        let expires_at = ttl.map(expiry_after);
+        data.repack(&self.pack_limits());
+        self.insert(&mut db, key.clone(), ValueWithExpiry::new(data, expires_at));
+        self.modified(&key);
+        self.waiters.notify(&key);
+        Ok(())
+    }
+
+    /// Load single entry(used during restore), expiring at the given Unix
+    /// time in milliseconds. Keys that expired meanwhile are skipped
+    pub fn load_entry(&self, key: String, mut data: DataType, expires_at: Option<u64>) {
+        if expires_at.is_some_and(|at| at <= unix_ms()) {
+            return;
+        }
+        data.repack(&self.pack_limits());
+        let mut db = self.db().write_key(&key);
+        self.memory.written(self.db, &key);
+        if let Some(at) = expires_at {
+            self.expiries.schedule(self.db, &key, at);
+        }
+        db.insert(key, ValueWithExpiry::new(data, expires_at));
     }
-    /// Load single entry(used during restore)
-    pub fn load_entry(&self, key: String, data: DataType, ttl: Option<Duration>) {
-        let mut db = self.db.write().unwrap();
-        let expires_at = ttl.map(|d| Instant::now() + d);
-        db.insert(key, ValueWithExpiry { data, expires_at });
+
+    /// Remove every key from every database
+    pub fn flush_all(&self) {
+        for (index, database) in self.databases.iter().enumerate() {
+            let mut db = database.write();
+            for entry in db.iter() {
+                self.modified(entry.key());
+            }
+            db.clear();
+            self.expiries.clear(index);
+        }
     }
 
     /// Get number of keys (for stats)
     pub fn dbsize(&self) -> usize {
-        self.db.read().unwrap().len()
+        self.db().read().len()
     }
-    pub fn get_all_data(&self) -> Vec<(String, DataType, Option<Duration>)> {
-        let db = self.db.read().unwrap();
+
+    /// Number of keys with a TTL in the selected database
+    pub fn expires_count(&self) -> usize {
+        self.db()
+            .read()
+            .iter()
+            .filter(|entry| entry.expires_at.is_some())
+            .count()
+    }
+    /// Every live key with its value and expiry as a Unix time in
+    /// milliseconds (AOF rewrite)
+    pub fn get_all_data(&self) -> Vec<(String, DataType, Option<u64>)> {
+        let db = self.db().read();
 
         db.iter()
-            .filter_map(|(key, entry)| {
-                if entry.is_expired() {
-                    None
-                } else {
-                    let ttl = entry.expires_at.map(|instant| {
-                        let now = Instant::now();
-                        if instant > now {
-                            instant.duration_since(now)
-                        } else {
-                            Duration::from_secs(0)
-                        }
-                    });
-                    Some((key.clone(), entry.data.clone(), ttl))
-                }
-            })
+            .filter(|entry| !entry.is_expired())
+            .map(|entry| (entry.key().clone(), entry.data.clone(), entry.expires_at))
             .collect()
     }
 }
+
+/// Every database as captured by `FerroStore::snapshot`. No other
+/// snapshot can be taken until it is dropped
+pub struct StoreSnapshot {
+    databases: Vec<DbSnapshot>,
+    /// Dropped after the databases, which finish their snapshots
+    _saving: SaveGuard,
+}
+
+impl StoreSnapshot {
+    /// Each database, in index order
+    pub fn databases(&self) -> &[DbSnapshot] {
+        &self.databases
+    }
+}
+
+/// One database as captured by `FerroStore::snapshot`, see `keyspace::snapshot`
+pub struct DbSnapshot {
+    databases: Arc<Vec<Database>>,
+    index: usize,
+    snapshot: Snapshot<ValueWithExpiry>,
+}
+
+impl DbSnapshot {
+    /// Index of the database
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Keys captured, including any expired since
+    pub fn len(&self) -> usize {
+        self.snapshot.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshot.is_empty()
+    }
+
+    /// Run `f` on the `index`th key (of `0..len()`), its value and expiry (a
+    /// Unix time in milliseconds), unless it is gone or expired
+    pub fn with_entry<T>(
+        &self,
+        index: usize,
+        f: impl FnOnce(&str, &DataType, Option<u64>) -> T,
+    ) -> Option<T> {
+        self.snapshot
+            .with(&self.databases[self.index], index, |key, entry| {
+                (!entry.is_expired()).then(|| f(key, &entry.data, entry.expires_at))
+            })
+            .flatten()
+    }
+}
+
+impl Drop for DbSnapshot {
+    fn drop(&mut self) {
+        self.snapshot.finish(&self.databases[self.index]);
+    }
+}
+
+/// Resolve a possibly negative list index (-1 is the last element) to a position
+fn list_position(len: usize, index: i64) -> Option<usize> {
+    let len = len as i64;
+    let index = if index < 0 { len + index } else { index };
+    (0..len).contains(&index).then_some(index as usize)
+}
+
+/// Collect and order the elements of `key` for SORT
+fn sort_elements(
+    db: &Keys<'_, ValueWithExpiry>,
+    key: &str,
+    options: &SortOptions,
+) -> Result<Vec<Option<String>>, String> {
+    let mut elements: Vec<String> = match db.get(key) {
+        Some(entry) if !entry.is_expired() => match &entry.data {
+            DataType::List(list) => list.iter().map(str::to_string).collect(),
+            DataType::Set(set) => set.iter().map(str::to_string).collect(),
+            DataType::SortedSet(zset) => {
+                zset.iter().map(|(member, _)| member.to_string()).collect()
+            }
+            DataType::String(_) => {
+                return Err(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+                );
+            }
+        },
+        _ => vec![],
+    };
+
+    let sort = options
+        .by
+        .as_ref()
+        .is_none_or(|pattern| pattern.contains('*'));
+    if sort {
+        let weight = |element: &String| match &options.by {
+            Some(pattern) => lookup_by_pattern(db, pattern, element),
+            None => Some(element.clone()),
+        };
+        if options.alpha {
+            // Elements without a weight sort first
+            let mut keyed: Vec<(Option<String>, String)> =
+                elements.into_iter().map(|e| (weight(&e), e)).collect();
+            keyed.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+            elements = keyed.into_iter().map(|(_, e)| e).collect();
+        } else {
+            // Elements without a weight count as 0
+            let mut keyed = Vec::with_capacity(elements.len());
+            for element in elements {
+                let score = match weight(&element) {
+                    Some(w) => w.trim().parse::<f64>().ok().filter(|s| !s.is_nan()).ok_or(
+                        "ERR One or more scores can't be converted into double".to_string(),
+                    )?,
+                    None => 0.0,
+                };
+                keyed.push((score, element));
+            }
+            keyed.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+            elements = keyed.into_iter().map(|(_, e)| e).collect();
+        }
+        if options.desc {
+            elements.reverse();
+        }
+    } else if db
+        .get(key)
+        .is_some_and(|entry| matches!(entry.data, DataType::Set(_)))
+    {
+        // Set iteration order is arbitrary; keep unsorted output deterministic
+        elements.sort();
+    }
+
+    let selected = elements
+        .into_iter()
+        .skip(options.offset)
+        .take(options.count.unwrap_or(usize::MAX));
+    if options.get.is_empty() {
+        return Ok(selected.map(Some).collect());
+    }
+    Ok(selected
+        .flat_map(|element| {
+            options
+                .get
+                .iter()
+                .map(|pattern| lookup_by_pattern(db, pattern, &element))
+                .collect::<Vec<_>>()
+        })
+        .collect())
+}
+
+/// Resolve a SORT BY/GET pattern for `element`: `#` is the element itself,
+/// otherwise the first `*` is replaced by it and the string at that key read
+fn lookup_by_pattern(
+    db: &Keys<'_, ValueWithExpiry>,
+    pattern: &str,
+    element: &str,
+) -> Option<String> {
+    if pattern == "#" {
+        return Some(element.to_string());
+    }
+    if !pattern.contains('*') {
+        return None;
+    }
+    match db.get(&pattern.replacen('*', element, 1)) {
+        Some(entry) if !entry.is_expired() => match &entry.data {
+            DataType::String(value) => Some(value.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The positions a SCAN-style cursor visits next in a collection of `len`
+/// entries, highest first, and the cursor to continue from. See `scan`
+pub(crate) fn scan_positions(len: usize, cursor: u64, count: usize) -> (u64, Rev<Range<usize>>) {
+    let end = match usize::try_from(cursor) {
+        Ok(0) | Err(_) => len,
+        Ok(cursor) => cursor.min(len),
+    };
+    let start = end.saturating_sub(count.max(1));
+    (start as u64, (start..end).rev())
+}
@@ -0,0 +1,62 @@
+use crate::config::{ConfigValues, TlsAuthClients};
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig, crypto};
+
+/// Build the acceptor for the `tls-port` listener from the `tls-*`
+/// parameters: the served certificate chain and key, and, when client
+/// certificates are checked, the CA that must have signed them
+pub fn acceptor(config: &ConfigValues) -> Result<TlsAcceptor, String> {
+    if config.tls_cert_file.is_empty() || config.tls_key_file.is_empty() {
+        return Err("tls-port requires tls-cert-file and tls-key-file".to_string());
+    }
+    let certs = CertificateDer::pem_file_iter(&config.tls_cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Can't load tls-cert-file '{}': {}", config.tls_cert_file, e))?;
+    let key = PrivateKeyDer::from_pem_file(&config.tls_key_file)
+        .map_err(|e| format!("Can't load tls-key-file '{}': {}", config.tls_key_file, e))?;
+
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("TLS setup failed: {}", e))?;
+    let builder = match config.tls_auth_clients {
+        TlsAuthClients::No => builder.with_no_client_auth(),
+        auth => {
+            if config.tls_ca_cert_file.is_empty() {
+                return Err("tls-auth-clients requires tls-ca-cert-file".to_string());
+            }
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(&config.tls_ca_cert_file)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| {
+                    format!(
+                        "Can't load tls-ca-cert-file '{}': {}",
+                        config.tls_ca_cert_file, e
+                    )
+                })?
+            {
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if auth == TlsAuthClients::Optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            let verifier = verifier
+                .build()
+                .map_err(|e| format!("TLS client verifier: {}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -0,0 +1,177 @@
+use crate::protocol::RespValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Modification counters for WATCHed keys
+///
+/// Only keys that at least one client is watching are tracked; writes to
+/// other keys cost a single map lookup. A watcher records the version when
+/// it starts watching and EXEC compares it with the current one.
+#[derive(Clone, Default)]
+pub struct KeyVersions {
+    keys: Arc<Mutex<HashMap<String, WatchedKey>>>,
+}
+
+#[derive(Default)]
+struct WatchedKey {
+    version: u64,
+    watchers: usize,
+}
+
+impl KeyVersions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking `key` until the returned `Watch` is dropped
+    pub fn watch(&self, key: &str) -> Watch {
+        let mut keys = self.keys.lock().unwrap();
+        let entry = keys.entry(key.to_string()).or_default();
+        entry.watchers += 1;
+
+        Watch {
+            registry: self.clone(),
+            key: key.to_string(),
+            version: entry.version,
+        }
+    }
+
+    /// Record a modification of `key`
+    pub fn bump(&self, key: &str) {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(entry) = keys.get_mut(key) {
+            entry.version += 1;
+        }
+    }
+
+    /// Record a modification of every watched key (e.g. the keyspace was flushed)
+    pub fn bump_all(&self) {
+        let mut keys = self.keys.lock().unwrap();
+        for entry in keys.values_mut() {
+            entry.version += 1;
+        }
+    }
+
+    fn version(&self, key: &str) -> Option<u64> {
+        let keys = self.keys.lock().unwrap();
+        keys.get(key).map(|entry| entry.version)
+    }
+}
+
+/// A client's WATCH on one key; stops tracking on drop
+pub struct Watch {
+    registry: KeyVersions,
+    key: String,
+    version: u64,
+}
+
+impl Watch {
+    /// Whether the key was modified since the watch started
+    pub fn is_modified(&self) -> bool {
+        self.registry.version(&self.key) != Some(self.version)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut keys = self.registry.keys.lock().unwrap();
+        if let Some(entry) = keys.get_mut(&self.key) {
+            entry.watchers -= 1;
+            if entry.watchers == 0 {
+                keys.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Per-connection MULTI/EXEC state
+#[derive(Default)]
+pub struct Transaction {
+    /// Commands queued since MULTI, or None outside a transaction
+    queued: Option<Vec<Vec<RespValue>>>,
+    /// Set when a command was rejected while queuing; EXEC then aborts
+    dirty: bool,
+    watches: Vec<Watch>,
+    /// In cluster mode, the hash slot of the keys queued so far
+    slot: Option<u16>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn in_multi(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// Enter MULTI. Returns false if already inside a transaction
+    pub fn begin(&mut self) -> bool {
+        if self.queued.is_some() {
+            return false;
+        }
+        self.queued = Some(Vec::new());
+        self.dirty = false;
+        self.slot = None;
+        true
+    }
+
+    /// Whether a command on keys in `slot` may join the transaction: in
+    /// cluster mode, all of its keys must be in one slot
+    pub fn claim_slot(&mut self, slot: u16) -> bool {
+        *self.slot.get_or_insert(slot) == slot
+    }
+
+    pub fn queue(&mut self, command: Vec<RespValue>) {
+        if let Some(queued) = self.queued.as_mut() {
+            queued.push(command);
+        }
+    }
+
+    /// Make the next EXEC fail with EXECABORT
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn watch(&mut self, versions: &KeyVersions, key: &str) {
+        self.watches.push(versions.watch(key));
+    }
+
+    pub fn unwatch(&mut self) {
+        self.watches.clear();
+    }
+
+    /// Leave the transaction for EXEC, releasing all watches
+    /// Returns None if MULTI was never called
+    pub fn exec(&mut self) -> Option<ExecOutcome> {
+        let queued = self.queued.take()?;
+        let modified = self.watches.iter().any(Watch::is_modified);
+        self.watches.clear();
+        Some(if std::mem::take(&mut self.dirty) {
+            ExecOutcome::Aborted
+        } else if modified {
+            ExecOutcome::WatchFailed
+        } else {
+            ExecOutcome::Run(queued)
+        })
+    }
+
+    /// Leave the transaction without running it (DISCARD), releasing all
+    /// watches. Returns false if MULTI was never called
+    pub fn discard(&mut self) -> bool {
+        self.watches.clear();
+        self.dirty = false;
+        self.queued.take().is_some()
+    }
+}
+
+/// What EXEC should do with a finished transaction
+#[derive(Debug, PartialEq)]
+pub enum ExecOutcome {
+    /// Run the queued commands
+    Run(Vec<Vec<RespValue>>),
+    /// A command was rejected while queuing
+    Aborted,
+    /// A watched key was modified; reply Null without running anything
+    WatchFailed,
+}
@@ -0,0 +1,167 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, ready};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_uring::net::TcpStream;
+
+/// Bytes asked for by each read
+const READ_CHUNK: usize = 16 * 1024;
+
+type Op<T> = Pin<Box<dyn Future<Output = T>>>;
+type Job = Box<dyn FnOnce() -> Op<()> + Send>;
+
+/// Threads that each drive an io_uring runtime, for connections whose
+/// reads and writes go through the ring instead of one syscall apiece
+pub struct Workers {
+    jobs: Vec<mpsc::UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+impl Workers {
+    /// Start `threads` workers. Fails if the kernel doesn't offer io_uring
+    /// (or forbids it, as some container sandboxes do)
+    pub fn start(threads: usize) -> io::Result<Workers> {
+        let mut jobs = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let (sender, mut receiver) = mpsc::unbounded_channel::<Job>();
+            let (started, ready) = std::sync::mpsc::channel();
+            thread::Builder::new()
+                .name(format!("io-uring-{}", i))
+                .spawn(move || {
+                    let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                        Ok(runtime) => runtime,
+                        Err(e) => {
+                            started.send(Err(e)).ok();
+                            return;
+                        }
+                    };
+                    started.send(Ok(())).ok();
+                    runtime.block_on(async move {
+                        while let Some(job) = receiver.recv().await {
+                            tokio::task::spawn_local(job());
+                        }
+                    });
+                })?;
+            ready
+                .recv()
+                .map_err(|_| io::Error::other("io_uring worker exited"))??;
+            jobs.push(sender);
+        }
+        Ok(Workers {
+            jobs,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Run the future `job` builds on the next worker, round robin. The
+    /// future stays on that thread, so it needn't be `Send`
+    pub fn spawn<F, Fut>(&self, job: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.jobs.len();
+        // Workers run for as long as the process does
+        self.jobs[i].send(Box::new(move || Box::pin(job()))).ok();
+    }
+}
+
+/// A connection on an io_uring worker, as `AsyncRead` + `AsyncWrite` so it
+/// is served (and TLS wrapped) like any other. The ring owns the buffer of
+/// an operation until it completes, so reads and writes are copied through
+/// buffers of the stream's own; an operation abandoned by a cancelled
+/// future carries on and is picked up by the next call
+pub struct UringStream {
+    stream: Rc<TcpStream>,
+    read: Option<Op<(io::Result<usize>, Vec<u8>)>>,
+    /// Bytes read but not yet handed out
+    unread: Vec<u8>,
+    unread_pos: usize,
+    write: Option<Op<io::Result<usize>>>,
+}
+
+impl UringStream {
+    /// Take over a connection accepted elsewhere. Must be called on a worker
+    pub fn from_std(socket: std::net::TcpStream) -> UringStream {
+        UringStream {
+            stream: Rc::new(TcpStream::from_std(socket)),
+            read: None,
+            unread: Vec::new(),
+            unread_pos: 0,
+            write: None,
+        }
+    }
+
+    /// Wait for the write in flight, if any
+    fn poll_written(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let Some(write) = &mut self.write else {
+            return Poll::Ready(Ok(0));
+        };
+        let result = ready!(write.as_mut().poll(cx));
+        self.write = None;
+        Poll::Ready(result)
+    }
+}
+
+impl AsyncRead for UringStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.unread_pos == this.unread.len() {
+            let read = this.read.get_or_insert_with(|| {
+                let stream = this.stream.clone();
+                let mut chunk = std::mem::take(&mut this.unread);
+                this.unread_pos = 0;
+                chunk.clear();
+                chunk.reserve(READ_CHUNK);
+                Box::pin(async move { stream.read(chunk).await })
+            });
+            let (result, chunk) = ready!(read.as_mut().poll(cx));
+            this.read = None;
+            this.unread = chunk;
+            this.unread_pos = 0;
+            // Zero bytes, at end of stream, is passed on as such
+            result?;
+        }
+        let n = buf.remaining().min(this.unread.len() - this.unread_pos);
+        buf.put_slice(&this.unread[this.unread_pos..this.unread_pos + n]);
+        this.unread_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UringStream {
+    /// Like every completion based writer, expects to be called again with
+    /// the same `buf` after returning `Pending`: the bytes are already on
+    /// their way, and the count returned once done is of that first `buf`
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.write.is_none() {
+            let stream = self.stream.clone();
+            let data = buf.to_vec();
+            self.write = Some(Box::pin(async move { stream.write(data).await.0 }));
+        }
+        self.poll_written(cx)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_written(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_written(cx))?;
+        Poll::Ready(self.stream.shutdown(std::net::Shutdown::Write))
+    }
+}
@@ -1,18 +1,44 @@
-use FerroDB::aof::{AofWriter, load_aof, rewrite_aof};
+use FerroDB::aof::{
+    AofPaths, AofWriter, BaseFormat, Manifest, check_aof, load_aof, replay_aof, rewrite_aof,
+};
 use FerroDB::commands::handle_command;
-use FerroDB::protocol::parse_resp;
+use FerroDB::config::ServerConfig;
+use FerroDB::connection::ConnectionContext;
+use FerroDB::latency::LatencyMonitor;
+use FerroDB::protocol::{RespValue, parse_resp};
+use FerroDB::replication::Replication;
 use FerroDB::storage::{DataType, FerroStore};
 use std::collections::VecDeque;
 use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time::{Duration, sleep};
 
+/// An AOF in a fresh directory under /tmp named after the test
+fn aof_paths(test: &str) -> AofPaths {
+    let root = format!("/tmp/{}", test);
+    fs::remove_dir_all(&root).ok();
+    fs::create_dir_all(&root).unwrap();
+    AofPaths::new(format!("{}/appendonlydir", root), "appendonly.aof")
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
 #[tokio::test]
 async fn test_aof_logging_and_replay() {
-    let path = "/tmp/test_aof.log";
-    fs::remove_file(path).ok();
+    let paths = aof_paths("test_aof");
 
     // Create AOF writer
-    let (aof_writer, aof_handle) = AofWriter::new(path.to_string());
+    let (aof_writer, aof_handle) = AofWriter::new(
+        paths.clone(),
+        ServerConfig::new(),
+        LatencyMonitor::new(),
+        Replication::new(),
+    );
 
     // Spawn AOF background task
     tokio::spawn(async move {
@@ -23,40 +49,58 @@ async fn test_aof_logging_and_replay() {
 
     // Execute some commands
     let cmd1 = parse_resp("*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n").unwrap();
-    handle_command(cmd1, &store, Some(&aof_writer), None, None).await;
+    handle_command(cmd1, &store, Some(&aof_writer), None).await;
 
     let cmd2 = parse_resp("*3\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n").unwrap();
-    handle_command(cmd2, &store, Some(&aof_writer), None, None).await;
+    handle_command(cmd2, &store, Some(&aof_writer), None).await;
 
     // Wait for AOF to flush
     sleep(Duration::from_secs(2)).await;
 
     // Create new store and replay
     let new_store = FerroStore::new();
-    let store_clone = new_store.clone();
-
-    let count = load_aof(path, move |cmd| {
-        let s = store_clone.clone();
-        tokio::spawn(async move {
-            handle_command(cmd, &s, None, None, None).await;
-        });
-    })
-    .await
-    .unwrap();
+    let count = replay_aof(&paths, true, &new_store).await.unwrap();
 
-    sleep(Duration::from_millis(100)).await; // Wait for async replays
-
-    assert_eq!(count, 2);
+    // SELECT 0 followed by the two SETs
+    assert_eq!(count, 3);
     assert_eq!(new_store.get("key1"), Some("value1".to_string()));
     assert_eq!(new_store.get("key2"), Some("value2".to_string()));
 
-    fs::remove_file(path).ok();
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_aof_replays_in_order() {
+    let paths = aof_paths("test_aof_replay_order");
+    let mut log = String::new();
+    for i in 0..200 {
+        log += &format!(
+            "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n${}\r\n{}\r\n",
+            i.to_string().len(),
+            i
+        );
+        log += "*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n";
+        log += &format!(
+            "*3\r\n$5\r\nRPUSH\r\n$1\r\nl\r\n${}\r\n{}\r\n",
+            i.to_string().len(),
+            i
+        );
+    }
+    fs::write(paths.legacy(), log).unwrap();
+
+    // Done by the time replay_aof returns, each command after the last
+    let store = FerroStore::new();
+    assert_eq!(replay_aof(&paths, true, &store).await.unwrap(), 600);
+    assert_eq!(store.get("k"), None);
+    let expected: Vec<String> = (0..200).map(|i| i.to_string()).collect();
+    assert_eq!(store.lrange("l", 0, -1).unwrap(), expected);
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
 }
 
 #[tokio::test]
 async fn test_aof_rewrite() {
-    let path = "/tmp/test_aof_rewrite.log";
-    fs::remove_file(path).ok();
+    let paths = aof_paths("test_aof_rewrite");
 
     // Create data to rewrite
     let mut list = VecDeque::new();
@@ -66,41 +110,730 @@ async fn test_aof_rewrite() {
     let data = vec![
         (
             "key1".to_string(),
-            DataType::String("value1".to_string()),
+            DataType::String("value1".to_string().into()),
             None,
         ),
         (
             "key2".to_string(),
-            DataType::String("value2".to_string()),
-            Some(Duration::from_secs(100)),
+            DataType::String("value2".to_string().into()),
+            Some(unix_ms() + 100_000),
         ),
-        ("mylist".to_string(), DataType::List(list), None),
+        ("mylist".to_string(), DataType::List(list.into()), None),
     ];
 
-    rewrite_aof(data, path).await.unwrap();
+    rewrite_aof(vec![(0, data)], &paths, BaseFormat::Commands)
+        .await
+        .unwrap();
 
     // Replay and verify
     let store = FerroStore::new();
-    let store_clone = store.clone();
-
-    let command_count = load_aof(path, move |cmd| {
-        let s = store_clone.clone();
-        tokio::spawn(async move {
-            handle_command(cmd, &s, None, None, None).await;
-        });
-    })
-    .await
-    .unwrap();
-
-    assert_eq!(command_count, 3);
-    sleep(Duration::from_millis(100)).await;
+    let command_count = replay_aof(&paths, true, &store).await.unwrap();
+    assert_eq!(command_count, 4);
 
     assert_eq!(store.get("key1"), Some("value1".to_string()));
     assert_eq!(store.get("key2"), Some("value2".to_string()));
+    assert!(matches!(store.ttl("key2"), Some(99..=100)));
     assert_eq!(
         store.lrange("mylist", 0, -1).unwrap(),
         vec!["item1", "item2"]
     );
 
-    fs::remove_file(path).ok();
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_aof_logs_absolute_expiry() {
+    let paths = aof_paths("test_aof_absolute_expiry");
+    let (aof_writer, aof_handle) = AofWriter::new(
+        paths.clone(),
+        ServerConfig::new(),
+        LatencyMonitor::new(),
+        Replication::new(),
+    );
+    tokio::spawn(async move {
+        aof_handle.run().await.ok();
+    });
+
+    fn command(args: &[&str]) -> FerroDB::protocol::RespValue {
+        FerroDB::protocol::RespValue::Array(
+            args.iter()
+                .map(|arg| FerroDB::protocol::RespValue::BulkString(arg.to_string().into()))
+                .collect(),
+        )
+    }
+    let store = FerroStore::new();
+    let before = unix_ms();
+    for args in [
+        &["SETEX", "a", "100", "v"][..],
+        &["SET", "b", "v", "PX", "100000", "NX"],
+        &["SET", "c", "v"],
+        &["EXPIRE", "c", "100", "NX"],
+        &["GETEX", "c", "EX", "100"],
+    ] {
+        handle_command(command(args), &store, Some(&aof_writer), None).await;
+    }
+    let after = unix_ms();
+    sleep(Duration::from_secs(2)).await;
+
+    let mut logged = Vec::new();
+    load_aof(&paths, true, |cmd| logged.push(cmd))
+        .await
+        .unwrap();
+    let logged: Vec<Vec<String>> = logged
+        .into_iter()
+        .map(|cmd| match cmd {
+            FerroDB::protocol::RespValue::Array(args) => args
+                .into_iter()
+                .map(|arg| match arg {
+                    FerroDB::protocol::RespValue::BulkString(arg) => arg.to_string(),
+                    other => panic!("unexpected argument {:?}", other),
+                })
+                .collect(),
+            other => panic!("unexpected command {:?}", other),
+        })
+        .collect();
+    let deadline = |at: &str| {
+        let at: u64 = at.parse().unwrap();
+        assert!((before + 100_000..=after + 100_000).contains(&at));
+    };
+    // SELECT 0 comes first
+    assert_eq!(logged.len(), 6);
+    assert_eq!(logged[1][..4], ["SET", "a", "v", "PXAT"]);
+    deadline(&logged[1][4]);
+    assert_eq!(logged[2][..4], ["SET", "b", "v", "PXAT"]);
+    deadline(&logged[2][4]);
+    assert_eq!(logged[2][5], "NX");
+    assert_eq!(logged[3], ["SET", "c", "v"]);
+    assert_eq!(logged[4][..2], ["PEXPIREAT", "c"]);
+    deadline(&logged[4][2]);
+    assert_eq!(logged[4][3], "NX");
+    assert_eq!(logged[5][..3], ["GETEX", "c", "PXAT"]);
+    deadline(&logged[5][3]);
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_aof_selects_databases() {
+    let paths = aof_paths("test_aof_select");
+
+    let (aof_writer, aof_handle) = AofWriter::new(
+        paths.clone(),
+        ServerConfig::new(),
+        LatencyMonitor::new(),
+        Replication::new(),
+    );
+    tokio::spawn(async move {
+        aof_handle.run().await.ok();
+    });
+
+    let store = FerroStore::new();
+    let mut conn = ConnectionContext::new();
+    for command in [
+        "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$4\r\nzero\r\n",
+        "*2\r\n$6\r\nSELECT\r\n$1\r\n3\r\n",
+        "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$5\r\nthree\r\n",
+        "*3\r\n$4\r\nMOVE\r\n$1\r\na\r\n$1\r\n5\r\n",
+    ] {
+        let cmd = parse_resp(command).unwrap();
+        handle_command(cmd, &store, Some(&aof_writer), Some(&mut conn)).await;
+    }
+    sleep(Duration::from_secs(2)).await;
+
+    // Replay in order as one connection, the way the server does
+    let mut commands = Vec::new();
+    let count = load_aof(&paths, true, |cmd| commands.push(cmd))
+        .await
+        .unwrap();
+    // SELECT 0, SET, SELECT 3, SET, MOVE
+    assert_eq!(count, 5);
+    let new_store = FerroStore::new();
+    let mut replay = ConnectionContext::new();
+    for cmd in commands {
+        handle_command(cmd, &new_store, None, Some(&mut replay)).await;
+    }
+
+    assert_eq!(new_store.get("a"), Some("zero".to_string()));
+    assert_eq!(new_store.database(3).unwrap().get("a"), None);
+    assert_eq!(
+        new_store.database(5).unwrap().get("a"),
+        Some("three".to_string())
+    );
+
+    // A rewrite keeps every database
+    let data = new_store
+        .databases()
+        .map(|db| (db.db_index(), db.get_all_data()))
+        .collect();
+    rewrite_aof(data, &paths, BaseFormat::Commands)
+        .await
+        .unwrap();
+    let mut commands = Vec::new();
+    load_aof(&paths, true, |cmd| commands.push(cmd))
+        .await
+        .unwrap();
+    let rewritten = FerroStore::new();
+    let mut replay = ConnectionContext::new();
+    for cmd in commands {
+        handle_command(cmd, &rewritten, None, Some(&mut replay)).await;
+    }
+    assert_eq!(rewritten.get("a"), Some("zero".to_string()));
+    assert_eq!(
+        rewritten.database(5).unwrap().get("a"),
+        Some("three".to_string())
+    );
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_load_aof_with_binary_values() {
+    let paths = aof_paths("test_aof_binary");
+    // A value containing \r\n, then a command cut short by a crash
+    fs::write(
+        paths.legacy(),
+        "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n*2\r\n$3\r\nDEL\r\n$1\r\n",
+    )
+    .unwrap();
+    let mut commands = Vec::new();
+    let count = load_aof(&paths, true, |cmd| commands.push(cmd))
+        .await
+        .unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(
+        commands[0],
+        parse_resp("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n").unwrap()
+    );
+
+    fs::write(paths.legacy(), "*1\r\n$4\r\nPING\r\n*x\r\n").unwrap();
+    assert!(load_aof(&paths, true, |_| {}).await.is_err());
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_load_truncated_aof() {
+    let paths = aof_paths("test_aof_load_truncated");
+    let set = "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+    fs::write(paths.legacy(), format!("{set}*2\r\n$3\r\nDEL\r\n$1")).unwrap();
+
+    // Refused, leaving the file alone, unless aof-load-truncated is set
+    let error = load_aof(&paths, false, |_| {}).await.unwrap_err();
+    assert!(
+        error.to_string().contains("aof-load-truncated"),
+        "{}",
+        error
+    );
+    assert_eq!(fs::metadata(paths.legacy()).unwrap().len(), 42);
+
+    // Then the partial command is cut off so nothing is appended after it
+    assert_eq!(load_aof(&paths, true, |_| {}).await.unwrap(), 1);
+    assert_eq!(fs::read_to_string(paths.legacy()).unwrap(), set);
+    assert_eq!(load_aof(&paths, false, |_| {}).await.unwrap(), 1);
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[test]
+fn test_check_aof() {
+    let set = "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+    let check = check_aof(format!("{set}{set}").as_bytes());
+    assert_eq!(check.commands, 2);
+    assert_eq!(check.valid_len, 2 * set.len() as u64);
+    assert_eq!(check.error, None);
+
+    // A command cut short, garbage, or a frame that isn't a command
+    for tail in ["*2\r\n$3\r\nDEL\r\n$1\r\n", "*x\r\n", "+OK\r\n"] {
+        let check = check_aof(format!("{set}{tail}").as_bytes());
+        assert_eq!(check.commands, 1, "{:?}", tail);
+        assert_eq!(check.valid_len, set.len() as u64, "{:?}", tail);
+        assert!(check.error.is_some(), "{:?}", tail);
+    }
+}
+
+#[tokio::test]
+async fn test_check_aof_fix() {
+    let paths = aof_paths("test_aof_check_fix");
+    let path = paths.legacy();
+    let set = "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+    fs::write(&path, format!("{set}*2\r\n$3\r\nDEL\r\n$1")).unwrap();
+    let check = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_ferrodb-check-aof"))
+            .args(args)
+            .arg(&path)
+            .output()
+            .unwrap()
+    };
+
+    let output = check(&[]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(
+        stdout.contains("ok_up_to=27, ok_up_to_commands=1, diff=15"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("AOF is not valid"), "{}", stdout);
+
+    assert!(check(&["--fix"]).status.success());
+    assert_eq!(fs::read_to_string(&path).unwrap(), set);
+    let output = check(&[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("AOF is valid"));
+
+    let mut commands = Vec::new();
+    assert_eq!(
+        load_aof(&paths, true, |cmd| commands.push(cmd))
+            .await
+            .unwrap(),
+        1
+    );
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_aof_rewrite_chunks_large_collections() {
+    let paths = aof_paths("test_aof_rewrite_chunks");
+    let members = |n: usize| (0..n).map(|i| format!("m{}", i));
+    let expiry = unix_ms() + 100_000;
+    let data = vec![
+        (
+            "list".to_string(),
+            DataType::List(members(100).collect::<VecDeque<_>>().into()),
+            Some(expiry),
+        ),
+        (
+            "set".to_string(),
+            DataType::Set(members(150).collect()),
+            None,
+        ),
+        (
+            "zset".to_string(),
+            DataType::SortedSet(
+                members(130)
+                    .enumerate()
+                    .map(|(i, m)| (m, i as f64 / 4.0))
+                    .chain([("top".to_string(), f64::INFINITY)])
+                    .collect(),
+            ),
+            None,
+        ),
+    ];
+    rewrite_aof(vec![(0, data)], &paths, BaseFormat::Commands)
+        .await
+        .unwrap();
+
+    let mut commands = Vec::new();
+    load_aof(&paths, true, |cmd| commands.push(cmd))
+        .await
+        .unwrap();
+    let names: Vec<String> = commands
+        .iter()
+        .map(|cmd| match cmd {
+            RespValue::Array(args) => match &args[0] {
+                RespValue::BulkString(name) => name.to_string(),
+                _ => panic!("not a command"),
+            },
+            _ => panic!("not a command"),
+        })
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "SELECT",
+            "RPUSH",
+            "RPUSH",
+            "PEXPIREAT",
+            "SADD",
+            "SADD",
+            "SADD",
+            "ZADD",
+            "ZADD",
+            "ZADD"
+        ]
+    );
+    for cmd in &commands {
+        if let RespValue::Array(args) = cmd {
+            assert!(args.len() <= 2 + 2 * 64);
+        }
+    }
+
+    let store = FerroStore::new();
+    for cmd in commands {
+        handle_command(cmd, &store, None, None).await;
+    }
+    assert_eq!(
+        store.lrange("list", 0, -1).unwrap(),
+        members(100).collect::<Vec<_>>()
+    );
+    assert!(store.ttl("list").is_some_and(|ttl| ttl > 0));
+    assert_eq!(store.scard("set").unwrap(), 150);
+    assert_eq!(store.zcard("zset").unwrap(), 131);
+    assert_eq!(store.zscore("zset", "m5").unwrap(), Some(1.25));
+    assert_eq!(store.zscore("zset", "top").unwrap(), Some(f64::INFINITY));
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_aof_rewrite_keeps_concurrent_writes() {
+    let paths = aof_paths("test_aof_rewrite_concurrent");
+    let config = ServerConfig::new();
+    config
+        .set(&[("appendfsync".to_string(), "always".to_string())])
+        .unwrap();
+    let (aof, aof_handle) = AofWriter::new(
+        paths.clone(),
+        config,
+        LatencyMonitor::new(),
+        Replication::new(),
+    );
+    tokio::spawn(async move {
+        aof_handle.run().await.ok();
+    });
+    let store = FerroStore::new();
+    let other_db = store.database(1).unwrap();
+    let run = |store: &FerroStore, cmd: &str| {
+        let args = cmd
+            .split(' ')
+            .map(|arg| RespValue::BulkString(arg.into()))
+            .collect();
+        let store = store.clone();
+        let aof = aof.clone();
+        async move { handle_command(RespValue::Array(args), &store, Some(&aof), None).await }
+    };
+
+    run(&store, "RPUSH list 1").await;
+    run(&store, "RPUSH list 2").await;
+
+    // Capture the dataset, then keep writing while the rewrite is written
+    assert!(aof.start_rewrite());
+    assert!(!aof.start_rewrite());
+    let data = store
+        .databases()
+        .map(|db| (db.db_index(), db.get_all_data()))
+        .collect();
+    run(&store, "RPUSH list 3").await;
+    run(&other_db, "SET other x").await;
+    aof.finish_rewrite(data, BaseFormat::Commands)
+        .await
+        .unwrap();
+    // The writer carries on with the new file
+    run(&store, "RPUSH list 4").await;
+    sleep(Duration::from_millis(100)).await;
+
+    let mut commands = Vec::new();
+    load_aof(&paths, true, |cmd| commands.push(cmd))
+        .await
+        .unwrap();
+    let replayed = FerroStore::new();
+    let mut replay = ConnectionContext::new();
+    for cmd in commands {
+        handle_command(cmd, &replayed, None, Some(&mut replay)).await;
+    }
+    assert_eq!(
+        replayed.lrange("list", 0, -1).unwrap(),
+        ["1", "2", "3", "4"]
+    );
+    assert_eq!(
+        replayed.database(1).unwrap().get("other"),
+        Some("x".to_string())
+    );
+
+    // The new base is followed by the file started with the rewrite, and
+    // the files it replaces are gone
+    let manifest = Manifest::read(&paths).await.unwrap().unwrap();
+    let names: Vec<&str> = manifest.files().map(|file| file.name.as_str()).collect();
+    assert_eq!(
+        names,
+        ["appendonly.aof.1.base.aof", "appendonly.aof.2.incr.aof"]
+    );
+    let mut files: Vec<String> = fs::read_dir(&paths.dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            "appendonly.aof.1.base.aof",
+            "appendonly.aof.2.incr.aof",
+            "appendonly.aof.manifest"
+        ]
+    );
+
+    // Another rewrite may start once this one is done
+    assert!(aof.start_rewrite());
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_aof_adopts_single_file() {
+    let paths = aof_paths("test_aof_adopts_single_file");
+    fs::write(
+        paths.legacy(),
+        "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$3\r\nold\r\n",
+    )
+    .unwrap();
+    let config = ServerConfig::new();
+    config
+        .set(&[("appendfsync".to_string(), "always".to_string())])
+        .unwrap();
+    let (aof, aof_handle) = AofWriter::new(
+        paths.clone(),
+        config,
+        LatencyMonitor::new(),
+        Replication::new(),
+    );
+    tokio::spawn(async move {
+        aof_handle.run().await.ok();
+    });
+    let store = FerroStore::new();
+    let cmd = parse_resp("*3\r\n$3\r\nSET\r\n$1\r\nj\r\n$3\r\nnew\r\n").unwrap();
+    handle_command(cmd, &store, Some(&aof), None).await;
+    sleep(Duration::from_millis(100)).await;
+
+    // The old file became the base, new commands go to an incremental file
+    assert!(!fs::exists(paths.legacy()).unwrap());
+    let manifest = Manifest::read(&paths).await.unwrap().unwrap();
+    assert_eq!(manifest.base.unwrap().name, "appendonly.aof.1.base.aof");
+    assert_eq!(manifest.incrs.len(), 1);
+    assert_eq!(manifest.incrs[0].name, "appendonly.aof.2.incr.aof");
+
+    let mut commands = Vec::new();
+    load_aof(&paths, true, |cmd| commands.push(cmd))
+        .await
+        .unwrap();
+    let replayed = FerroStore::new();
+    for cmd in commands {
+        handle_command(cmd, &replayed, None, None).await;
+    }
+    assert_eq!(replayed.get("k"), Some("old".to_string()));
+    assert_eq!(replayed.get("j"), Some("new".to_string()));
+
+    // Only the last file may end in a command cut short
+    let base = paths.dir.join("appendonly.aof.1.base.aof");
+    let mut contents = fs::read(&base).unwrap();
+    contents.extend_from_slice(b"*2\r\n$3\r\nDEL");
+    fs::write(&base, contents).unwrap();
+    assert!(load_aof(&paths, true, |_| {}).await.is_err());
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[test]
+fn test_manifest_format() {
+    let text = "file appendonly.aof.3.base.aof seq 3 type b\n\
+                file appendonly.aof.5.incr.aof seq 5 type i\n\
+                file appendonly.aof.4.incr.aof seq 4 type i\n";
+    let manifest = Manifest::parse(text).unwrap();
+    assert_eq!(manifest.base.as_ref().unwrap().seq, 3);
+    let seqs: Vec<u64> = manifest.incrs.iter().map(|file| file.seq).collect();
+    assert_eq!(seqs, [4, 5]);
+    assert_eq!(Manifest::parse(&manifest.encode()).unwrap(), manifest);
+
+    assert!(Manifest::parse("file a seq x type i\n").is_err());
+    assert!(Manifest::parse("file a seq 1 type q\n").is_err());
+}
+
+#[tokio::test]
+async fn test_aof_rdb_preamble() {
+    let paths = aof_paths("test_aof_rdb_preamble");
+    let config = ServerConfig::new();
+    config
+        .set(&[("appendfsync".to_string(), "always".to_string())])
+        .unwrap();
+    let (aof, aof_handle) = AofWriter::new(
+        paths.clone(),
+        config,
+        LatencyMonitor::new(),
+        Replication::new(),
+    );
+    tokio::spawn(async move {
+        aof_handle.run().await.ok();
+    });
+    let store = FerroStore::new();
+    let db2 = store.database(2).unwrap();
+    let run = |store: &FerroStore, cmd: &str| {
+        let args = cmd
+            .split(' ')
+            .map(|arg| RespValue::BulkString(arg.into()))
+            .collect();
+        let store = store.clone();
+        let aof = aof.clone();
+        async move { handle_command(RespValue::Array(args), &store, Some(&aof), None).await }
+    };
+    run(&store, "SET s v").await;
+    run(&store, "PEXPIREAT s 99999999999999").await;
+    for i in 0..100 {
+        run(&store, &format!("RPUSH list {}", i)).await;
+    }
+    run(&db2, "SADD set a b c").await;
+
+    assert!(aof.start_rewrite());
+    let data = store
+        .databases()
+        .map(|db| (db.db_index(), db.get_all_data()))
+        .collect();
+    run(&db2, "SADD set d").await;
+    let format = BaseFormat::Rdb(FerroDB::config::RdbCompression::Lz4);
+    aof.finish_rewrite(data, format).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let manifest = Manifest::read(&paths).await.unwrap().unwrap();
+    assert_eq!(manifest.base.unwrap().name, "appendonly.aof.1.base.rdb");
+
+    // Keys are loaded straight from the base, then commands replayed
+    let replayed = FerroStore::new();
+    // 3 keys, then SELECT 2 and SADD
+    assert_eq!(replay_aof(&paths, true, &replayed).await.unwrap(), 5);
+    let expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+    assert_eq!(replayed.lrange("list", 0, -1).unwrap(), expected);
+    assert_eq!(replayed.get("s"), Some("v".to_string()));
+    assert!(replayed.ttl("s").is_some_and(|ttl| ttl > 0));
+    assert_eq!(replayed.database(2).unwrap().scard("set").unwrap(), 4);
+
+    // or, through load_aof, turned into the commands recreating them
+    let mut commands = Vec::new();
+    load_aof(&paths, true, |cmd| commands.push(cmd))
+        .await
+        .unwrap();
+    let from_commands = FerroStore::new();
+    let mut replay = ConnectionContext::new();
+    for cmd in commands {
+        handle_command(cmd, &from_commands, None, Some(&mut replay)).await;
+    }
+    assert_eq!(from_commands.lrange("list", 0, -1).unwrap(), expected);
+    assert_eq!(from_commands.database(2).unwrap().scard("set").unwrap(), 4);
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_config_set_appendonly() {
+    let paths = aof_paths("test_aof_config_set_appendonly");
+    let store = FerroStore::new();
+    store
+        .config()
+        .set(&[
+            ("appendonly".to_string(), "no".to_string()),
+            ("appendfsync".to_string(), "always".to_string()),
+        ])
+        .unwrap();
+    let (aof, aof_handle) = AofWriter::new(
+        paths.clone(),
+        store.config().clone(),
+        LatencyMonitor::new(),
+        store.replication().clone(),
+    );
+    tokio::spawn(async move {
+        aof_handle.run().await.ok();
+    });
+    let run = |cmd: &str| {
+        let args = cmd
+            .split(' ')
+            .map(|arg| RespValue::BulkString(arg.into()))
+            .collect();
+        let store = store.clone();
+        let aof = aof.clone();
+        async move { handle_command(RespValue::Array(args), &store, Some(&aof), None).await }
+    };
+    let replayed = || async {
+        let replayed = FerroStore::new();
+        replay_aof(&paths, true, &replayed).await.unwrap();
+        replayed
+    };
+
+    run("SET a 1").await;
+    sleep(Duration::from_millis(100)).await;
+    assert!(!fs::exists(&paths.dir).unwrap());
+
+    // Turning it on seeds the AOF with the dataset, then logs writes
+    let ok = RespValue::SimpleString("OK".to_string());
+    assert_eq!(run("CONFIG SET appendonly yes").await, ok);
+    run("SET b 2").await;
+    sleep(Duration::from_millis(200)).await;
+    let manifest = Manifest::read(&paths).await.unwrap().unwrap();
+    assert!(manifest.base.is_some());
+    let store = replayed().await;
+    assert_eq!(store.get("a"), Some("1".to_string()));
+    assert_eq!(store.get("b"), Some("2".to_string()));
+
+    // and off again stops logging
+    assert_eq!(run("CONFIG SET appendonly no").await, ok);
+    run("SET c 3").await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(replayed().await.get("c"), None);
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
+}
+
+#[tokio::test]
+async fn test_aof_logs_only_effective_writes() {
+    let paths = aof_paths("test_aof_effective_writes");
+    let config = ServerConfig::new();
+    config
+        .set(&[("appendfsync".to_string(), "always".to_string())])
+        .unwrap();
+    let (aof, aof_handle) = AofWriter::new(
+        paths.clone(),
+        config,
+        LatencyMonitor::new(),
+        Replication::new(),
+    );
+    tokio::spawn(async move {
+        aof_handle.run().await.ok();
+    });
+    let store = FerroStore::new();
+    for cmd in [
+        "SET k v",
+        "DEL missing",
+        "SET k other NX",
+        "LPUSH k x",
+        "SADD s a",
+        "SADD s a",
+        "SREM s b",
+        "EXPIRE missing 100",
+        "SORT s ALPHA",
+        "SORT s ALPHA STORE sorted",
+        "DEL k",
+    ] {
+        let args = cmd
+            .split(' ')
+            .map(|arg| RespValue::BulkString(arg.into()))
+            .collect();
+        handle_command(RespValue::Array(args), &store, Some(&aof), None).await;
+    }
+    sleep(Duration::from_millis(500)).await;
+
+    let mut logged = Vec::new();
+    load_aof(&paths, true, |cmd| logged.push(cmd))
+        .await
+        .unwrap();
+    let logged: Vec<String> = logged
+        .into_iter()
+        .map(|cmd| match cmd {
+            RespValue::Array(args) => args
+                .into_iter()
+                .map(|arg| match arg {
+                    RespValue::BulkString(arg) => arg.to_string(),
+                    other => panic!("unexpected argument {:?}", other),
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+            other => panic!("unexpected command {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        logged,
+        [
+            "SELECT 0",
+            "SET k v",
+            "SADD s a",
+            "SORT s ALPHA STORE sorted",
+            "DEL k"
+        ]
+    );
+
+    fs::remove_dir_all(paths.dir.parent().unwrap()).ok();
 }
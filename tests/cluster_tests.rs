@@ -0,0 +1,411 @@
+use FerroDB::cluster::{CrossSlot, common_slot, hash_tag, key_slot};
+use FerroDB::commands::handle_command;
+use FerroDB::connection::ConnectionContext;
+use FerroDB::protocol::{Decoded, RespDecoder, RespValue};
+use FerroDB::storage::FerroStore;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+fn command(args: &[&str]) -> RespValue {
+    RespValue::Array(
+        args.iter()
+            .map(|arg| RespValue::BulkString((*arg).into()))
+            .collect(),
+    )
+}
+
+fn cross_slot() -> RespValue {
+    RespValue::Error("CROSSSLOT Keys in request don't hash to the same slot".to_string())
+}
+
+#[test]
+fn test_key_slots() {
+    // Values from Redis' CLUSTER KEYSLOT
+    assert_eq!(key_slot("123456789"), 12739);
+    assert_eq!(key_slot("foo"), 12182);
+    assert_eq!(key_slot("bar"), 5061);
+    assert_eq!(key_slot(""), 0);
+
+    assert_eq!(hash_tag("{user1000}.following"), "user1000");
+    assert_eq!(hash_tag("foo{}{bar}"), "foo{}{bar}");
+    assert_eq!(hash_tag("foo{{bar}}zap"), "{bar");
+    assert_eq!(hash_tag("foo{bar}{zap}"), "bar");
+    assert_eq!(hash_tag("foo{bar"), "foo{bar");
+    assert_eq!(
+        key_slot("{user1000}.following"),
+        key_slot("{user1000}.followers")
+    );
+
+    assert_eq!(common_slot([]), Ok(None));
+    assert_eq!(common_slot(["{a}1", "{a}2"]), Ok(Some(key_slot("a"))));
+    assert_eq!(common_slot(["foo", "bar"]), Err(CrossSlot));
+}
+
+#[tokio::test]
+async fn test_cross_slot_commands() {
+    let store = FerroStore::new();
+
+    // Standalone servers take keys from any slots
+    let response = handle_command(
+        command(&["MSET", "foo", "1", "bar", "2"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    let response =
+        handle_command(command(&["CLUSTER", "KEYSLOT", "foo"]), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR This instance has cluster support disabled".to_string())
+    );
+
+    let path = "/tmp/test_FerroDB_cluster_enabled.conf";
+    std::fs::write(path, "cluster-enabled yes\n").unwrap();
+    store.config().load_file(path).unwrap();
+    std::fs::remove_file(path).ok();
+    let response =
+        handle_command(command(&["CLUSTER", "KEYSLOT", "foo"]), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(12182));
+
+    let response = handle_command(
+        command(&["MSET", "foo", "1", "bar", "2"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, cross_slot());
+    let response = handle_command(command(&["SINTER", "{s}a", "other"]), &store, None, None).await;
+    assert_eq!(response, cross_slot());
+    let response = handle_command(
+        command(&["EVAL", "return 1", "2", "foo", "bar"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, cross_slot());
+
+    // Shard channels are held to one slot too, though they aren't keys
+    let mut conn = ConnectionContext::new();
+    let response = handle_command(
+        command(&["SSUBSCRIBE", "foo", "bar"]),
+        &store,
+        None,
+        Some(&mut conn),
+    )
+    .await;
+    assert_eq!(response, cross_slot());
+
+    // Keys sharing a hash tag share a slot
+    let response = handle_command(
+        command(&["MSET", "{user}.name", "ann", "{user}.age", "30"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    store.sadd("{s}a", vec!["x".to_string()]).unwrap();
+    let response =
+        handle_command(command(&["SMOVE", "{s}a", "{s}b", "x"]), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+
+    // A transaction keeps to one slot: a command on another aborts it
+    let mut conn = ConnectionContext::new();
+    for (args, reply) in [
+        (&["MULTI"][..], "OK"),
+        (&["SET", "{user}.name", "bob"], "QUEUED"),
+        (&["GET", "{user}.age"], "QUEUED"),
+    ] {
+        let response = handle_command(command(args), &store, None, Some(&mut conn)).await;
+        assert_eq!(response, RespValue::SimpleString(reply.to_string()));
+    }
+    let response = handle_command(command(&["GET", "foo"]), &store, None, Some(&mut conn)).await;
+    assert_eq!(response, cross_slot());
+    let response = handle_command(command(&["EXEC"]), &store, None, Some(&mut conn)).await;
+    assert_eq!(
+        response,
+        RespValue::Error("EXECABORT Transaction discarded because of previous errors.".to_string())
+    );
+    assert_eq!(store.get("{user}.name"), Some("ann".to_string()));
+
+    // The next one starts over on any slot
+    for args in [&["MULTI"][..], &["SET", "foo", "2"]] {
+        handle_command(command(args), &store, None, Some(&mut conn)).await;
+    }
+    let response = handle_command(command(&["EXEC"]), &store, None, Some(&mut conn)).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![RespValue::SimpleString("OK".to_string())])
+    );
+}
+
+/// A store in cluster mode
+fn cluster_node(name: &str) -> FerroStore {
+    let store = FerroStore::new();
+    let path = format!("/tmp/test_FerroDB_{}.conf", name);
+    std::fs::write(&path, "cluster-enabled yes\n").unwrap();
+    store.config().load_file(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    store
+}
+
+/// Serve `store`'s commands on a local port, for other nodes to reach
+async fn serve(store: &FerroStore) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let store = store.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let mut decoder = RespDecoder::for_requests();
+                let mut conn = ConnectionContext::new();
+                let mut buffer = [0; 16 * 1024];
+                loop {
+                    match decoder.decode() {
+                        Decoded::Frame(request) => {
+                            let reply =
+                                handle_command(request, &store, None, Some(&mut conn)).await;
+                            socket.write_all(reply.encode().as_bytes()).await.unwrap();
+                            continue;
+                        }
+                        Decoded::NeedMoreData => {}
+                        Decoded::Error(_) => return,
+                    }
+                    match socket.read(&mut buffer).await {
+                        Ok(read) if read > 0 => decoder.extend(&buffer[..read]),
+                        _ => return,
+                    }
+                }
+            });
+        }
+    });
+    port
+}
+
+async fn run(store: &FerroStore, conn: Option<&mut ConnectionContext>, args: &[&str]) -> RespValue {
+    handle_command(command(args), store, None, conn).await
+}
+
+fn ok() -> RespValue {
+    RespValue::SimpleString("OK".to_string())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_slot_migration() {
+    let source = cluster_node("cluster_source");
+    let target = cluster_node("cluster_target");
+    let source_port = serve(&source).await;
+    let target_port = serve(&target).await;
+    let source_id = source.cluster().myid();
+    let target_id = target.cluster().myid();
+    let source_addr = format!("127.0.0.1:{}", source_port);
+    let target_addr = format!("127.0.0.1:{}", target_port);
+
+    let reply = run(&source, None, &["CLUSTER", "MYID"]).await;
+    assert_eq!(reply, RespValue::bulk(source_id.clone()));
+    let reply = run(
+        &source,
+        None,
+        &["CLUSTER", "SETSLOT", "0", "MIGRATING", &target_id],
+    )
+    .await;
+    assert_eq!(
+        reply,
+        RespValue::Error(format!("ERR I don't know about node {}", target_id))
+    );
+    assert_eq!(
+        run(
+            &source,
+            None,
+            &["CLUSTER", "MEET", "127.0.0.1", &target_port.to_string()]
+        )
+        .await,
+        ok()
+    );
+    assert_eq!(
+        run(
+            &target,
+            None,
+            &["CLUSTER", "MEET", "127.0.0.1", &source_port.to_string()]
+        )
+        .await,
+        ok()
+    );
+
+    // The source serves slot {m}; the target starts importing it from there
+    let slot = key_slot("m");
+    let slot_arg = slot.to_string();
+    for (node, args) in [
+        (
+            &target,
+            &["CLUSTER", "SETSLOT", &slot_arg, "NODE", &source_id][..],
+        ),
+        (
+            &target,
+            &["CLUSTER", "SETSLOT", &slot_arg, "IMPORTING", &source_id],
+        ),
+        (
+            &source,
+            &["CLUSTER", "SETSLOT", &slot_arg, "MIGRATING", &target_id],
+        ),
+    ] {
+        assert_eq!(run(node, None, args).await, ok());
+    }
+    source.set("{m}a".to_string(), "1".to_string());
+    source.set("{m}b".to_string(), "2".to_string());
+    let moved = RespValue::Error(format!("MOVED {} {}", slot, source_addr));
+    let ask = RespValue::Error(format!("ASK {} {}", slot, target_addr));
+
+    // Keys still at the source are served there, those gone are asked for
+    // at the target
+    let reply = run(&source, None, &["GET", "{m}a"]).await;
+    assert_eq!(reply, RespValue::bulk("1"));
+    assert_eq!(run(&source, None, &["GET", "{m}new"]).await, ask);
+    assert_eq!(run(&source, None, &["SET", "{m}new", "3"]).await, ask);
+    assert_eq!(
+        run(&source, None, &["MGET", "{m}a", "{m}new"]).await,
+        RespValue::Error("TRYAGAIN Multiple keys request during rehashing of slot".to_string())
+    );
+    assert_eq!(
+        run(&source, None, &["CLUSTER", "COUNTKEYSINSLOT", &slot_arg]).await,
+        RespValue::Integer(2)
+    );
+
+    // The target only serves the slot right after ASKING
+    assert_eq!(run(&target, None, &["GET", "{m}new"]).await, moved);
+    let mut conn = ConnectionContext::new();
+    assert_eq!(run(&target, Some(&mut conn), &["ASKING"]).await, ok());
+    let reply = run(&target, Some(&mut conn), &["SET", "{m}new", "3"]).await;
+    assert_eq!(reply, ok());
+    assert_eq!(
+        run(&target, Some(&mut conn), &["GET", "{m}new"]).await,
+        moved
+    );
+
+    // MIGRATE moves the keys over
+    let reply = run(
+        &source,
+        None,
+        &[
+            "MIGRATE",
+            "127.0.0.1",
+            &target_port.to_string(),
+            "",
+            "0",
+            "1000",
+            "KEYS",
+            "{m}a",
+            "{m}b",
+        ],
+    )
+    .await;
+    assert_eq!(reply, ok());
+    assert_eq!(run(&source, None, &["GET", "{m}a"]).await, ask);
+    assert_eq!(
+        run(
+            &source,
+            None,
+            &["CLUSTER", "GETKEYSINSLOT", &slot_arg, "10"]
+        )
+        .await,
+        RespValue::Array(vec![])
+    );
+    run(&target, Some(&mut conn), &["ASKING"]).await;
+    let reply = run(
+        &target,
+        Some(&mut conn),
+        &["MGET", "{m}a", "{m}b", "{m}new"],
+    )
+    .await;
+    assert_eq!(
+        reply,
+        RespValue::Array(vec![
+            RespValue::bulk("1"),
+            RespValue::bulk("2"),
+            RespValue::bulk("3")
+        ])
+    );
+
+    // Both nodes give the slot to the target, ending the migration
+    for (node, id) in [(&target, &target_id), (&source, &target_id)] {
+        let reply = run(node, None, &["CLUSTER", "SETSLOT", &slot_arg, "NODE", id]).await;
+        assert_eq!(reply, ok());
+    }
+    let reply = run(&target, None, &["GET", "{m}a"]).await;
+    assert_eq!(reply, RespValue::bulk("1"));
+    assert_eq!(
+        run(&source, None, &["GET", "{m}a"]).await,
+        RespValue::Error(format!("MOVED {} {}", slot, target_addr))
+    );
+}
+
+#[tokio::test]
+async fn test_setslot_errors() {
+    let store = cluster_node("cluster_setslot");
+    let other = cluster_node("cluster_setslot_other");
+    let port = serve(&other).await;
+    run(
+        &store,
+        None,
+        &["CLUSTER", "MEET", "127.0.0.1", &port.to_string()],
+    )
+    .await;
+    let myid = store.cluster().myid();
+    let other_id = other.cluster().myid();
+    let slot = key_slot("k").to_string();
+    store.set("k".to_string(), "1".to_string());
+
+    for (args, error) in [
+        (
+            &["CLUSTER", "SETSLOT", "16384", "STABLE"][..],
+            "ERR Invalid or out of range slot".to_string(),
+        ),
+        (
+            &["CLUSTER", "SETSLOT", &slot, "MIGRATING", &myid],
+            "ERR Can't MIGRATE to myself".to_string(),
+        ),
+        (
+            &["CLUSTER", "SETSLOT", &slot, "IMPORTING", &other_id],
+            format!("ERR I'm already the owner of hash slot {}", slot),
+        ),
+        (
+            &["CLUSTER", "SETSLOT", &slot, "NODE", &other_id],
+            format!(
+                "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                slot
+            ),
+        ),
+    ] {
+        assert_eq!(run(&store, None, args).await, RespValue::Error(error));
+    }
+
+    // STABLE calls off a migration
+    let reply = run(
+        &store,
+        None,
+        &["CLUSTER", "SETSLOT", &slot, "MIGRATING", &other_id],
+    )
+    .await;
+    assert_eq!(reply, ok());
+    let reply = run(&store, None, &["GET", "missing{k}"]).await;
+    assert!(reply.error_message().unwrap().starts_with("ASK "));
+    let reply = run(&store, None, &["CLUSTER", "SETSLOT", &slot, "STABLE"]).await;
+    assert_eq!(reply, ok());
+    assert_eq!(
+        run(&store, None, &["GET", "missing{k}"]).await,
+        RespValue::Null
+    );
+
+    // ASKING is for cluster mode
+    let standalone = FerroStore::new();
+    let mut conn = ConnectionContext::new();
+    assert_eq!(
+        run(&standalone, Some(&mut conn), &["ASKING"]).await,
+        RespValue::Error("ERR This instance has cluster support disabled".to_string())
+    );
+}
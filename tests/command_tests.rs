@@ -1,5 +1,9 @@
+use FerroDB::command_table::COMMAND_TABLE;
 use FerroDB::commands::*;
+use FerroDB::connection::ConnectionContext;
+use FerroDB::modules::{CommandFlags, CommandFuture, CommandModule};
 use FerroDB::protocol::*;
+use FerroDB::pubsub::ChannelKind;
 use FerroDB::storage::*;
 #[tokio::test]
 async fn test_set_get_flow() {
@@ -8,15 +12,2890 @@ async fn test_set_get_flow() {
     // 1. Simulate: SET "greet" "hello"
     let set_input = "*3\r\n$3\r\nSET\r\n$5\r\ngreet\r\n$5\r\nhello\r\n";
     let parsed_set = parse_resp(set_input).unwrap();
-    let response_set = handle_command(parsed_set, &store, None, None, None).await;
+    let response_set = handle_command(parsed_set, &store, None, None).await;
     assert_eq!(response_set, RespValue::SimpleString("OK".to_string()));
 
     // 2. Simulate: GET "greet"
     let get_input = "*2\r\n$3\r\nGET\r\n$5\r\ngreet\r\n";
     let parsed_get = parse_resp(get_input).unwrap();
-    let response_get = handle_command(parsed_get, &store, None, None, None).await;
-    assert_eq!(response_get, RespValue::BulkString("hello".to_string()));
+    let response_get = handle_command(parsed_get, &store, None, None).await;
+    assert_eq!(response_get, RespValue::BulkString("hello".into()));
 }
+#[tokio::test]
+async fn test_set_nx_xx_options() {
+    let store = FerroStore::new();
+
+    // SET key v1 XX -> key missing, not written
+    let input = "*4\r\n$3\r\nSET\r\n$3\r\nkey\r\n$2\r\nv1\r\n$2\r\nXX\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Null);
+    assert_eq!(store.get("key"), None);
+
+    // SET key v1 EX 10 NX -> written with a TTL
+    let input = "*6\r\n$3\r\nSET\r\n$3\r\nkey\r\n$2\r\nv1\r\n$2\r\nEX\r\n$2\r\n10\r\n$2\r\nNX\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    assert!(store.ttl("key").unwrap() > 0);
+
+    // SET key v2 NX -> already exists
+    let input = "*4\r\n$3\r\nSET\r\n$3\r\nkey\r\n$2\r\nv2\r\n$2\r\nNX\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Null);
+    assert_eq!(store.get("key"), Some("v1".to_string()));
+}
+
+#[tokio::test]
+async fn test_set_get_and_keepttl_options() {
+    let store = FerroStore::new();
+    store.set_with_expiry("key".to_string(), "old".to_string(), 100);
+
+    // SET key new XX GET KEEPTTL -> returns old value, keeps expiry
+    let input =
+        "*6\r\n$3\r\nSET\r\n$3\r\nkey\r\n$3\r\nnew\r\n$2\r\nXX\r\n$3\r\nGET\r\n$7\r\nKEEPTTL\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("old".into()));
+    assert_eq!(store.get("key"), Some("new".to_string()));
+    assert!(store.ttl("key").unwrap() > 0);
+
+    // Plain SET clears the expiry
+    let input = "*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\nv\r\n";
+    handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(store.ttl("key"), Some(-1));
+
+    // Conflicting expiry options are a syntax error
+    let input =
+        "*6\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\n1\r\n$7\r\nKEEPTTL\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Error("ERR syntax error".to_string()));
+}
+
+#[tokio::test]
+async fn test_getdel_command() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+
+    let input = "*2\r\n$6\r\nGETDEL\r\n$3\r\nkey\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("value".into()));
+    assert!(!store.exists("key"));
+
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Null);
+}
+
+#[tokio::test]
+async fn test_getex_command() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+
+    // GETEX key EX 100 -> sets a TTL
+    let input = "*4\r\n$5\r\nGETEX\r\n$3\r\nkey\r\n$2\r\nEX\r\n$3\r\n100\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("value".into()));
+    assert!(store.ttl("key").unwrap() > 0);
+
+    // GETEX key PERSIST -> removes it again
+    let input = "*3\r\n$5\r\nGETEX\r\n$3\r\nkey\r\n$7\r\nPERSIST\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("value".into()));
+    assert_eq!(store.ttl("key"), Some(-1));
+}
+
+#[tokio::test]
+async fn test_copy_command() {
+    let store = FerroStore::new();
+    store.set("src".to_string(), "value".to_string());
+    store.set("dst".to_string(), "taken".to_string());
+
+    let input = "*3\r\n$4\r\nCOPY\r\n$3\r\nsrc\r\n$3\r\ndst\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(0));
+
+    let input = "*4\r\n$4\r\nCOPY\r\n$3\r\nsrc\r\n$3\r\ndst\r\n$7\r\nREPLACE\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+    assert_eq!(store.get("dst"), Some("value".to_string()));
+}
+
+#[tokio::test]
+async fn test_dump_restore_commands() {
+    let store = FerroStore::new();
+    store
+        .rpush("src", vec!["a".to_string(), "b".to_string()])
+        .unwrap();
+
+    let input = "*2\r\n$4\r\nDUMP\r\n$3\r\nsrc\r\n";
+    let RespValue::BulkString(payload) =
+        handle_command(parse_resp(input).unwrap(), &store, None, None).await
+    else {
+        panic!("Expected bulk string payload");
+    };
+
+    let restore = |key: &str, extra: &[&str]| {
+        let mut parts = vec![
+            RespValue::BulkString("RESTORE".into()),
+            RespValue::BulkString(key.to_string().into()),
+            RespValue::BulkString("0".into()),
+            RespValue::BulkString(payload.clone()),
+        ];
+        parts.extend(
+            extra
+                .iter()
+                .map(|s| RespValue::BulkString(s.to_string().into())),
+        );
+        RespValue::Array(parts)
+    };
+
+    let response = handle_command(restore("dst", &[]), &store, None, None).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    assert_eq!(store.lrange("dst", 0, -1).unwrap(), vec!["a", "b"]);
+
+    // Existing key needs REPLACE
+    let response = handle_command(restore("dst", &[]), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("BUSYKEY Target key name already exists.".to_string())
+    );
+    let response = handle_command(restore("dst", &["REPLACE"]), &store, None, None).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+}
+
+#[tokio::test]
+async fn test_restore_absttl() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let store = FerroStore::new();
+    store.set("src".to_string(), "new".to_string());
+
+    let input = "*2\r\n$4\r\nDUMP\r\n$3\r\nsrc\r\n";
+    let RespValue::BulkString(payload) =
+        handle_command(parse_resp(input).unwrap(), &store, None, None).await
+    else {
+        panic!("Expected bulk string payload");
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let restore = |at: u64, payload: &str, extra: &[&str]| {
+        let mut parts = vec![
+            RespValue::BulkString("RESTORE".into()),
+            RespValue::BulkString("k".into()),
+            RespValue::BulkString(at.to_string().into()),
+            RespValue::BulkString(payload.to_string().into()),
+            RespValue::BulkString("ABSTTL".into()),
+        ];
+        parts.extend(
+            extra
+                .iter()
+                .map(|s| RespValue::BulkString(s.to_string().into())),
+        );
+        RespValue::Array(parts)
+    };
+    let ok = RespValue::SimpleString("OK".to_string());
+    let busy = RespValue::Error("BUSYKEY Target key name already exists.".to_string());
+
+    // A future time becomes the key's expiry
+    let future = now + 100_000;
+    let response = handle_command(restore(future, &payload, &[]), &store, None, None).await;
+    assert_eq!(response, ok);
+    assert_eq!(store.get("k"), Some("new".to_string()));
+    assert!(
+        store
+            .pttl("k")
+            .is_some_and(|ttl| ttl > 90_000 && ttl <= 100_000)
+    );
+    let response = handle_command(restore(future, &payload, &[]), &store, None, None).await;
+    assert_eq!(response, busy);
+    let response = handle_command(
+        restore(future + 50_000, &payload, &["REPLACE"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, ok);
+    assert!(store.pttl("k").is_some_and(|ttl| ttl > 100_000));
+
+    // A past time still checks the payload and the existing key
+    store.set("k".to_string(), "old".to_string());
+    let past = now - 100_000;
+    let response = handle_command(restore(past, "zzzz", &[]), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR DUMP payload version or checksum are wrong".to_string())
+    );
+    let response = handle_command(restore(past, &payload, &[]), &store, None, None).await;
+    assert_eq!(response, busy);
+    assert_eq!(store.get("k"), Some("old".to_string()));
+
+    // ... and with REPLACE deletes the key rather than restoring it
+    let response = handle_command(restore(past, &payload, &["REPLACE"]), &store, None, None).await;
+    assert_eq!(response, ok);
+    assert_eq!(store.get("k"), None);
+    let response = handle_command(restore(past, &payload, &[]), &store, None, None).await;
+    assert_eq!(response, ok);
+    assert_eq!(store.get("k"), None);
+}
+
+/// Serve `store` to one client over `listener`, like a server would
+async fn serve_one(listener: tokio::net::TcpListener, store: FerroStore) {
+    use tokio::io::AsyncWriteExt;
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut decoder = RespDecoder::for_requests();
+    let mut conn = ConnectionContext::new();
+    loop {
+        match decoder.decode() {
+            Decoded::Frame(request) => {
+                let reply = handle_command(request, &store, None, Some(&mut conn)).await;
+                socket.write_all(reply.encode().as_bytes()).await.unwrap();
+            }
+            Decoded::NeedMoreData => {
+                if decoder.read_from(&mut socket).await.unwrap() == 0 {
+                    return;
+                }
+            }
+            Decoded::Error(e) => panic!("bad request {}", e),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_migrate_command() {
+    let store = FerroStore::new();
+    let target = FerroStore::new();
+    store.set("a".to_string(), "1".to_string());
+    store
+        .rpush("b", vec!["x".to_string(), "y".to_string()])
+        .unwrap();
+    store.expire("b", 100);
+
+    let migrate = |port: u16, extra: &[&str]| {
+        let port = port.to_string();
+        let mut args = vec!["MIGRATE", "127.0.0.1", &port];
+        args.extend_from_slice(extra);
+        RespValue::Array(
+            args.iter()
+                .map(|arg| RespValue::BulkString(arg.to_string().into()))
+                .collect(),
+        )
+    };
+
+    // A single key, moved into database 2
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(serve_one(listener, target.clone()));
+    let response = handle_command(migrate(port, &["a", "2", "1000"]), &store, None, None).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    assert_eq!(store.get("a"), None);
+    assert_eq!(
+        target.databases().nth(2).unwrap().get("a"),
+        Some("1".to_string())
+    );
+
+    // Several keys with KEYS, copied, keeping the time to live; missing keys are skipped
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(serve_one(listener, target.clone()));
+    let response = handle_command(
+        migrate(port, &["", "0", "1000", "COPY", "KEYS", "b", "missing"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    assert_eq!(store.lrange("b", 0, -1).unwrap(), vec!["x", "y"]);
+    assert_eq!(target.lrange("b", 0, -1).unwrap(), vec!["x", "y"]);
+    assert!(matches!(target.ttl("b"), Some(ttl) if ttl > 90));
+
+    // The target refuses to overwrite without REPLACE, and the key stays here
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(serve_one(listener, target.clone()));
+    let response = handle_command(migrate(port, &["b", "0", "1000"]), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error(
+            "ERR Target instance replied with error: BUSYKEY Target key name already exists."
+                .to_string()
+        )
+    );
+    assert!(store.exists("b"));
+
+    let response =
+        handle_command(migrate(port, &["missing", "0", "1000"]), &store, None, None).await;
+    assert_eq!(response, RespValue::SimpleString("NOKEY".to_string()));
+    let response = handle_command(
+        migrate(port, &["b", "0", "1000", "KEYS", "b"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert!(matches!(response, RespValue::Error(e) if e.contains("empty string")));
+
+    // Nobody listening
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    let response = handle_command(migrate(port, &["b", "0", "1000"]), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("IOERR error or timeout connecting to the client".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_pexpire_pttl_psetex() {
+    let store = FerroStore::new();
+
+    // PSETEX key 1500 value
+    let input = "*4\r\n$6\r\nPSETEX\r\n$3\r\nkey\r\n$4\r\n1500\r\n$5\r\nvalue\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+
+    let input = "*2\r\n$4\r\nPTTL\r\n$3\r\nkey\r\n";
+    let RespValue::Integer(pttl) =
+        handle_command(parse_resp(input).unwrap(), &store, None, None).await
+    else {
+        panic!("Expected integer");
+    };
+    assert!(pttl > 1000 && pttl <= 1500);
+
+    // PEXPIRE key 200 -> gone shortly after
+    let input = "*3\r\n$7\r\nPEXPIRE\r\n$3\r\nkey\r\n$3\r\n200\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert_eq!(store.get("key"), None);
+}
+
+#[tokio::test]
+async fn test_expireat_expiretime() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+
+    let at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 100;
+    let input = format!(
+        "*3\r\n$8\r\nEXPIREAT\r\n$3\r\nkey\r\n${}\r\n{}\r\n",
+        at.to_string().len(),
+        at
+    );
+    let response = handle_command(parse_resp(&input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+
+    let input = "*2\r\n$10\r\nEXPIRETIME\r\n$3\r\nkey\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(at as i64));
+
+    let input = "*2\r\n$11\r\nPEXPIRETIME\r\n$7\r\nmissing\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(-2));
+
+    // The furthest expire times there are read back unchanged; one more
+    // second or millisecond is refused
+    let max_seconds = (i64::MAX / 1000).to_string();
+    let response = handle_command(
+        command(&["EXPIREAT", "key", &max_seconds]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, RespValue::Integer(1));
+    let response = handle_command(command(&["EXPIRETIME", "key"]), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(i64::MAX / 1000));
+    let max_millis = i64::MAX.to_string();
+    let response = handle_command(
+        command(&["PEXPIREAT", "key", &max_millis]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, RespValue::Integer(1));
+    let response = handle_command(command(&["PEXPIRETIME", "key"]), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(i64::MAX));
+
+    let past_max = (i64::MAX / 1000 + 1).to_string();
+    let response =
+        handle_command(command(&["EXPIREAT", "key", &past_max]), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR invalid expire time in 'expireat' command".to_string())
+    );
+    let response = handle_command(
+        command(&["PEXPIREAT", "key", "9223372036854775808"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR value is not an integer or out of range".to_string())
+    );
+    let response = handle_command(command(&["PEXPIRETIME", "key"]), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(i64::MAX));
+}
+
+#[tokio::test]
+async fn test_expire_time_out_of_range() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+
+    // Expire times past i64 milliseconds are refused, rather than wrapping
+    // around to a negative TTL or to no TTL at all
+    for (args, name) in [
+        (&["SET", "a", "1", "EX", "9223372036854775807"][..], "set"),
+        (&["SET", "a", "1", "PX", "9223372036854775807"], "set"),
+        (&["SET", "a", "1", "EXAT", "9223372036854776"], "set"),
+        (&["GETEX", "key", "EX", "9223372036854775807"], "getex"),
+        (&["SETEX", "a", "9223372036854775807", "1"], "setex"),
+        (&["EXPIRE", "key", "9223372036854775807"], "expire"),
+        (&["PEXPIRE", "key", "9223372036854775807"], "pexpire"),
+        (&["EXPIREAT", "key", "9223372036854776"], "expireat"),
+        (&["EXPIRE", "key", "-9223372036854775807"], "expire"),
+    ] {
+        let response = handle_command(command(args), &store, None, None).await;
+        assert_eq!(
+            response,
+            RespValue::Error(format!("ERR invalid expire time in '{}' command", name)),
+            "{:?}",
+            args
+        );
+    }
+    assert_eq!(store.get("a"), None);
+    assert_eq!(store.ttl("key"), Some(-1));
+
+    // A long but representable one is kept as is
+    let response = handle_command(
+        command(&["EXPIRE", "key", "100000000000"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, RespValue::Integer(1));
+    assert!(store.ttl("key").is_some_and(|ttl| ttl > 99_999_999_000));
+
+    // A negative one deletes the key, as a time in the past does
+    let response = handle_command(command(&["EXPIRE", "key", "-1"]), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+    assert_eq!(store.get("key"), None);
+}
+
+#[tokio::test]
+async fn test_expire_flags() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+
+    // EXPIRE key 100 XX -> no TTL yet, not applied
+    let input = "*4\r\n$6\r\nEXPIRE\r\n$3\r\nkey\r\n$3\r\n100\r\n$2\r\nXX\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(0));
+
+    // EXPIRE key 100 NX -> applied
+    let input = "*4\r\n$6\r\nEXPIRE\r\n$3\r\nkey\r\n$3\r\n100\r\n$2\r\nnx\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+
+    // EXPIRE key 50 GT -> would shorten, refused
+    let input = "*4\r\n$6\r\nEXPIRE\r\n$3\r\nkey\r\n$2\r\n50\r\n$2\r\nGT\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(0));
+    assert_eq!(store.ttl("key"), Some(100));
+
+    // EXPIRE key 50 GT LT -> incompatible
+    let input = "*5\r\n$6\r\nEXPIRE\r\n$3\r\nkey\r\n$2\r\n50\r\n$2\r\nGT\r\n$2\r\nLT\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR GT and LT options at the same time are not compatible".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_linsert_and_lindex() {
+    let store = FerroStore::new();
+    store
+        .rpush("list", vec!["a".to_string(), "c".to_string()])
+        .unwrap();
+
+    let input = "*5\r\n$7\r\nLINSERT\r\n$4\r\nlist\r\n$5\r\nafter\r\n$1\r\na\r\n$1\r\nb\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(3));
+
+    let input = "*3\r\n$6\r\nLINDEX\r\n$4\r\nlist\r\n$1\r\n1\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("b".into()));
+
+    let input = "*4\r\n$4\r\nLSET\r\n$4\r\nlist\r\n$2\r\n10\r\n$1\r\nx\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR index out of range".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_rpoplpush_and_lmove() {
+    let store = FerroStore::new();
+    store
+        .rpush("src", vec!["a".to_string(), "b".to_string()])
+        .unwrap();
+
+    let input = "*3\r\n$9\r\nRPOPLPUSH\r\n$3\r\nsrc\r\n$3\r\ndst\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("b".into()));
+
+    let input = "*5\r\n$5\r\nLMOVE\r\n$3\r\nsrc\r\n$3\r\ndst\r\n$4\r\nleft\r\n$5\r\nright\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("a".into()));
+    assert_eq!(store.lrange("dst", 0, -1).unwrap(), vec!["b", "a"]);
+
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Null);
+}
+
+#[tokio::test]
+async fn test_lmpop_command() {
+    let store = FerroStore::new();
+    store
+        .rpush("q2", vec!["x".to_string(), "y".to_string()])
+        .unwrap();
+
+    // LMPOP 2 q1 q2 LEFT COUNT 5
+    let input = "*7\r\n$5\r\nLMPOP\r\n$1\r\n2\r\n$2\r\nq1\r\n$2\r\nq2\r\n$4\r\nLEFT\r\n$5\r\nCOUNT\r\n$1\r\n5\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("q2".into()),
+            RespValue::Array(vec![
+                RespValue::BulkString("x".into()),
+                RespValue::BulkString("y".into()),
+            ]),
+        ])
+    );
+
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Null);
+}
+
+#[tokio::test]
+async fn test_blpop_wakes_on_push() {
+    let store = FerroStore::new();
+
+    let blocked_store = store.clone();
+    let blocked = tokio::spawn(async move {
+        // BLPOP q1 q2 0 (block forever)
+        let input = "*4\r\n$5\r\nBLPOP\r\n$2\r\nq1\r\n$2\r\nq2\r\n$1\r\n0\r\n";
+        handle_command(parse_resp(input).unwrap(), &blocked_store, None, None).await
+    });
+
+    // Wait for the client to block, then push from "another client"
+    while store.waiters().num_waiters("q2") == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    store.rpush("q2", vec!["job".to_string()]).unwrap();
+
+    let response = blocked.await.unwrap();
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("q2".into()),
+            RespValue::BulkString("job".into()),
+        ])
+    );
+    assert!(!store.exists("q2"));
+    assert_eq!(store.waiters().num_waiters("q1"), 0);
+}
+
+#[tokio::test]
+async fn test_blocked_pop_waits_for_exclusive_commands() {
+    let store = FerroStore::new();
+
+    let blocked_store = store.clone();
+    let blocked = tokio::spawn(async move {
+        handle_command(command(&["BLPOP", "q", "0"]), &blocked_store, None, None).await
+    });
+    while store.waiters().num_waiters("q") == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    // While EXEC, a script or an AOF rewrite holds the lock exclusively, a
+    // push wakes the client but its pop waits for them to finish
+    let exclusive = store.exec_lock().write().await;
+    store.rpush("q", vec!["job".to_string()]).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!blocked.is_finished());
+    assert_eq!(store.llen("q").unwrap(), 1);
+    drop(exclusive);
+
+    let response = blocked.await.unwrap();
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("q".into()),
+            RespValue::BulkString("job".into()),
+        ])
+    );
+    assert!(!store.exists("q"));
+}
+
+#[tokio::test]
+async fn test_blocking_pop_timeout() {
+    let store = FerroStore::new();
+
+    // BRPOP empty 0.1 -> times out
+    let input = "*3\r\n$5\r\nBRPOP\r\n$5\r\nempty\r\n$3\r\n0.1\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Null);
+
+    // BLMOVE src dst RIGHT LEFT 1 returns immediately when data is there
+    store.rpush("src", vec!["a".to_string()]).unwrap();
+    let input =
+        "*6\r\n$6\r\nBLMOVE\r\n$3\r\nsrc\r\n$3\r\ndst\r\n$5\r\nRIGHT\r\n$4\r\nLEFT\r\n$1\r\n1\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("a".into()));
+
+    let input = "*3\r\n$5\r\nBLPOP\r\n$3\r\ndst\r\n$2\r\n-1\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR timeout is negative".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_bzpopmin_wakes_on_zadd() {
+    let store = FerroStore::new();
+
+    let blocked_store = store.clone();
+    let blocked = tokio::spawn(async move {
+        // BZPOPMIN delayed 5
+        let input = "*3\r\n$8\r\nBZPOPMIN\r\n$7\r\ndelayed\r\n$1\r\n5\r\n";
+        handle_command(parse_resp(input).unwrap(), &blocked_store, None, None).await
+    });
+
+    while store.waiters().num_waiters("delayed") == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    store
+        .zadd(
+            "delayed",
+            vec![(20.0, "later".to_string()), (10.0, "soon".to_string())],
+        )
+        .unwrap();
+
+    let response = blocked.await.unwrap();
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("delayed".into()),
+            RespValue::BulkString("soon".into()),
+            RespValue::BulkString("10".into()),
+        ])
+    );
+    assert_eq!(store.zscore("delayed", "later"), Ok(Some(20.0)));
+}
+
+#[tokio::test]
+async fn test_smove_command() {
+    let store = FerroStore::new();
+    store.sadd("src", vec!["m".to_string()]).unwrap();
+
+    let input = "*4\r\n$5\r\nSMOVE\r\n$3\r\nsrc\r\n$3\r\ndst\r\n$1\r\nm\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(0));
+}
+
+#[tokio::test]
+async fn test_zscan_command() {
+    let store = FerroStore::new();
+    store.zadd("z", vec![(1.5, "a".to_string())]).unwrap();
+
+    let input = "*5\r\n$5\r\nZSCAN\r\n$1\r\nz\r\n$1\r\n0\r\n$5\r\nCOUNT\r\n$2\r\n10\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("0".into()),
+            RespValue::Array(vec![
+                RespValue::BulkString("a".into()),
+                RespValue::BulkString("1.5".into()),
+            ]),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn test_zrangebyscore_command() {
+    let store = FerroStore::new();
+    store
+        .zadd(
+            "z",
+            vec![
+                (1.0, "a".to_string()),
+                (2.0, "b".to_string()),
+                (3.0, "c".to_string()),
+            ],
+        )
+        .unwrap();
+
+    // ZRANGEBYSCORE z (1 +inf WITHSCORES LIMIT 0 1
+    let input = "*8\r\n$13\r\nZRANGEBYSCORE\r\n$1\r\nz\r\n$2\r\n(1\r\n$4\r\n+inf\r\n$10\r\nWITHSCORES\r\n$5\r\nLIMIT\r\n$1\r\n0\r\n$1\r\n1\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("b".into()),
+            RespValue::BulkString("2".into()),
+        ])
+    );
+
+    // ZCOUNT z -inf (3
+    let input = "*4\r\n$6\r\nZCOUNT\r\n$1\r\nz\r\n$4\r\n-inf\r\n$2\r\n(3\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(2));
+
+    let input = "*4\r\n$6\r\nZCOUNT\r\n$1\r\nz\r\n$3\r\nabc\r\n$1\r\n3\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR min or max is not a float".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_zrangebylex_command() {
+    let store = FerroStore::new();
+    store
+        .zadd(
+            "idx",
+            vec![
+                (0.0, "a".to_string()),
+                (0.0, "b".to_string()),
+                (0.0, "c".to_string()),
+            ],
+        )
+        .unwrap();
+
+    // ZRANGEBYLEX idx - [b
+    let input = "*4\r\n$11\r\nZRANGEBYLEX\r\n$3\r\nidx\r\n$1\r\n-\r\n$2\r\n[b\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("a".into()),
+            RespValue::BulkString("b".into()),
+        ])
+    );
+
+    // ZLEXCOUNT idx b +  -> invalid bound
+    let input = "*4\r\n$9\r\nZLEXCOUNT\r\n$3\r\nidx\r\n$1\r\nb\r\n$1\r\n+\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR min or max not valid string range item".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_zrange_rev_options() {
+    let store = FerroStore::new();
+    store
+        .zadd(
+            "board",
+            vec![
+                (1.0, "a".to_string()),
+                (2.0, "b".to_string()),
+                (3.0, "c".to_string()),
+            ],
+        )
+        .unwrap();
+
+    // ZRANGE board +inf 2 BYSCORE REV WITHSCORES
+    let input = "*7\r\n$6\r\nZRANGE\r\n$5\r\nboard\r\n$4\r\n+inf\r\n$1\r\n2\r\n$7\r\nBYSCORE\r\n$3\r\nREV\r\n$10\r\nWITHSCORES\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("c".into()),
+            RespValue::BulkString("3".into()),
+            RespValue::BulkString("b".into()),
+            RespValue::BulkString("2".into()),
+        ])
+    );
+
+    // ZREVRANGE board 0 0
+    let input = "*4\r\n$9\r\nZREVRANGE\r\n$5\r\nboard\r\n$1\r\n0\r\n$1\r\n0\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![RespValue::BulkString("c".into())])
+    );
+
+    // ZREVRANK board a
+    let input = "*3\r\n$8\r\nZREVRANK\r\n$5\r\nboard\r\n$1\r\na\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(2));
+
+    // LIMIT requires BYSCORE or BYLEX
+    let input = "*7\r\n$6\r\nZRANGE\r\n$5\r\nboard\r\n$1\r\n0\r\n$2\r\n-1\r\n$5\r\nLIMIT\r\n$1\r\n0\r\n$1\r\n1\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                .to_string()
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_zpopmin_command() {
+    let store = FerroStore::new();
+    store
+        .zadd("pq", vec![(5.0, "x".to_string()), (1.0, "y".to_string())])
+        .unwrap();
+
+    let input = "*2\r\n$7\r\nZPOPMIN\r\n$2\r\npq\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("y".into()),
+            RespValue::BulkString("1".into()),
+        ])
+    );
+
+    let input = "*3\r\n$7\r\nZPOPMAX\r\n$2\r\npq\r\n$2\r\n-1\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR value is out of range, must be positive".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_zmscore_command() {
+    let store = FerroStore::new();
+    store.zadd("z", vec![(2.5, "a".to_string())]).unwrap();
+
+    let input = "*4\r\n$7\r\nZMSCORE\r\n$1\r\nz\r\n$1\r\na\r\n$1\r\nb\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![RespValue::BulkString("2.5".into()), RespValue::Null,])
+    );
+
+    let input = "*2\r\n$11\r\nZRANDMEMBER\r\n$1\r\nz\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("a".into()));
+}
+
+#[tokio::test]
+async fn test_sort_command() {
+    let store = FerroStore::new();
+    store
+        .rpush("ids", vec!["1".to_string(), "2".to_string()])
+        .unwrap();
+    store.set("weight_1".to_string(), "5".to_string());
+    store.set("weight_2".to_string(), "9".to_string());
+    store.set("obj_2".to_string(), "second".to_string());
+
+    let input = "*7\r\n$4\r\nSORT\r\n$3\r\nids\r\n$2\r\nBY\r\n$8\r\nweight_*\r\n$3\r\nGET\r\n$5\r\nobj_*\r\n$4\r\nDESC\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("second".into()),
+            RespValue::Null,
+        ])
+    );
+
+    let input = "*8\r\n$4\r\nSORT\r\n$3\r\nids\r\n$5\r\nALPHA\r\n$5\r\nLIMIT\r\n$1\r\n0\r\n$1\r\n1\r\n$5\r\nSTORE\r\n$3\r\nout\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+    assert_eq!(store.lrange("out", 0, -1), Ok(vec!["1".to_string()]));
+
+    let input = "*4\r\n$4\r\nSORT\r\n$3\r\nids\r\n$5\r\nLIMIT\r\n$1\r\n0\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Error("ERR syntax error".to_string()));
+}
+
+#[tokio::test]
+async fn test_watch_multi_exec() {
+    let store = FerroStore::new();
+    store.set("acct".to_string(), "10".to_string());
+    let mut conn = ConnectionContext::new();
+
+    let watch = "*2\r\n$5\r\nWATCH\r\n$4\r\nacct\r\n";
+    let multi = "*1\r\n$5\r\nMULTI\r\n";
+    let set = "*3\r\n$3\r\nSET\r\n$4\r\nacct\r\n$2\r\n20\r\n";
+    let exec = "*1\r\n$4\r\nEXEC\r\n";
+
+    // Unmodified watched key: the transaction runs
+    for (input, expected) in [
+        (watch, RespValue::SimpleString("OK".to_string())),
+        (multi, RespValue::SimpleString("OK".to_string())),
+        (set, RespValue::SimpleString("QUEUED".to_string())),
+    ] {
+        let response =
+            handle_command(parse_resp(input).unwrap(), &store, None, Some(&mut conn)).await;
+        assert_eq!(response, expected);
+    }
+    assert_eq!(store.get("acct"), Some("10".to_string()));
+    let response = handle_command(parse_resp(exec).unwrap(), &store, None, Some(&mut conn)).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![RespValue::SimpleString("OK".to_string())])
+    );
+    assert_eq!(store.get("acct"), Some("20".to_string()));
+
+    // Another client modifies the watched key: EXEC aborts with Null
+    for input in [watch, multi, set] {
+        handle_command(parse_resp(input).unwrap(), &store, None, Some(&mut conn)).await;
+    }
+    store.set("acct".to_string(), "99".to_string());
+    let response = handle_command(parse_resp(exec).unwrap(), &store, None, Some(&mut conn)).await;
+    assert_eq!(response, RespValue::Null);
+    assert_eq!(store.get("acct"), Some("99".to_string()));
+
+    // EXEC ends the transaction; a command rejected while queuing aborts the next one
+    let response = handle_command(parse_resp(exec).unwrap(), &store, None, Some(&mut conn)).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR EXEC without MULTI".to_string())
+    );
+    handle_command(parse_resp(multi).unwrap(), &store, None, Some(&mut conn)).await;
+    let subscribe = "*2\r\n$9\r\nSUBSCRIBE\r\n$2\r\nch\r\n";
+    handle_command(
+        parse_resp(subscribe).unwrap(),
+        &store,
+        None,
+        Some(&mut conn),
+    )
+    .await;
+    let response = handle_command(parse_resp(exec).unwrap(), &store, None, Some(&mut conn)).await;
+    assert_eq!(
+        response,
+        RespValue::Error("EXECABORT Transaction discarded because of previous errors.".to_string())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_script_kill() {
+    let store = FerroStore::new();
+    let run = |args: &[&str]| handle_command(command(args), &store, None, None);
+    let ok = RespValue::SimpleString("OK".to_string());
+    assert_eq!(run(&["CONFIG", "SET", "lua-time-limit", "50"]).await, ok);
+    assert_eq!(
+        run(&["SCRIPT", "KILL"]).await,
+        RespValue::Error("NOTBUSY No scripts in execution right now.".to_string())
+    );
+
+    // Other clients get BUSY instead of waiting on a runaway script forever
+    let script = {
+        let store = store.clone();
+        tokio::spawn(async move {
+            let eval = command(&["EVAL", "while true do end", "0"]);
+            handle_command(eval, &store, None, None).await
+        })
+    };
+    // A PING sent before the script takes the lock still gets PONG
+    let busy = async {
+        loop {
+            match run(&["PING"]).await {
+                RespValue::Error(e) if e.starts_with("BUSY") => break,
+                _ => tokio::time::sleep(std::time::Duration::from_millis(5)).await,
+            }
+        }
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(5), busy)
+        .await
+        .expect("PING waited for the script");
+
+    assert_eq!(run(&["SCRIPT", "KILL"]).await, ok);
+    let killed = tokio::time::timeout(std::time::Duration::from_secs(5), script)
+        .await
+        .expect("script not killed")
+        .unwrap();
+    assert!(matches!(killed, RespValue::Error(e) if e.contains("SCRIPT KILL")));
+    assert_eq!(
+        run(&["PING"]).await,
+        RespValue::SimpleString("PONG".to_string())
+    );
+
+    // A script that has written can't be killed, as that would leave its
+    // writes half done
+    let script = {
+        let store = store.clone();
+        tokio::spawn(async move {
+            let body = "redis.call('SET', 'k', 'v') local n = 0 \
+                        while n < 200000000 and redis.call('EXISTS', 'stop') == 0 do \
+                        n = n + 1 end return n";
+            handle_command(command(&["EVAL", body, "0"]), &store, None, None).await
+        })
+    };
+    while !store.scripts().busy() {
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    assert!(matches!(
+        run(&["SCRIPT", "KILL"]).await,
+        RespValue::Error(e) if e.starts_with("UNKILLABLE")
+    ));
+    store.set("stop".to_string(), "1".to_string());
+    assert!(matches!(script.await.unwrap(), RespValue::Integer(_)));
+    assert_eq!(store.get("k"), Some("v".to_string()));
+}
+
+#[tokio::test]
+async fn test_eval_and_script_cache() {
+    let store = FerroStore::new();
+
+    let input = "*5\r\n$4\r\nEVAL\r\n$112\r\nlocal v = redis.call('GET', KEYS[1]) or 0; redis.call('SET', KEYS[1], v + ARGV[1]); return {v + ARGV[1], 'done'}\r\n$1\r\n1\r\n$7\r\ncounter\r\n$1\r\n5\r\n";
+    for expected in [5, 10] {
+        let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+        assert_eq!(
+            response,
+            RespValue::Array(vec![
+                RespValue::Integer(expected),
+                RespValue::BulkString("done".into()),
+            ])
+        );
+    }
+    assert_eq!(store.get("counter"), Some("10".to_string()));
+
+    // redis.call raises the command's error, redis.pcall returns it as {err = ...}
+    store.set("str".to_string(), "v".to_string());
+    let input = "*4\r\n$4\r\nEVAL\r\n$40\r\nreturn redis.call('LPUSH', KEYS[1], 'x')\r\n$1\r\n1\r\n$3\r\nstr\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert!(matches!(response, RespValue::Error(e) if e.starts_with("WRONGTYPE")));
+    let input = "*4\r\n$4\r\nEVAL\r\n$68\r\nlocal r = redis.pcall('LPUSH', KEYS[1], 'x'); return r['err'] ~= nil\r\n$1\r\n1\r\n$3\r\nstr\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+
+    // The sandbox has no os library
+    let input = "*3\r\n$4\r\nEVAL\r\n$16\r\nreturn os.exit()\r\n$1\r\n0\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert!(matches!(response, RespValue::Error(e) if e.starts_with("ERR Error running script")));
+
+    let input = "*3\r\n$6\r\nSCRIPT\r\n$4\r\nLOAD\r\n$14\r\nreturn ARGV[1]\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    let sha = "098e0f0d1448c0a81dafe820f66d460eb09263da";
+    assert_eq!(response, RespValue::BulkString(sha.to_string().into()));
+
+    let evalsha = "*4\r\n$7\r\nEVALSHA\r\n$40\r\n098e0f0d1448c0a81dafe820f66d460eb09263da\r\n$1\r\n0\r\n$2\r\nhi\r\n";
+    let response = handle_command(parse_resp(evalsha).unwrap(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("hi".into()));
+
+    let input = "*4\r\n$6\r\nSCRIPT\r\n$6\r\nEXISTS\r\n$40\r\n098e0f0d1448c0a81dafe820f66d460eb09263da\r\n$4\r\nffff\r\n";
+    let response = handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![RespValue::Integer(1), RespValue::Integer(0)])
+    );
+
+    let input = "*2\r\n$6\r\nSCRIPT\r\n$5\r\nFLUSH\r\n";
+    handle_command(parse_resp(input).unwrap(), &store, None, None).await;
+    let response = handle_command(parse_resp(evalsha).unwrap(), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("NOSCRIPT No matching script. Please use EVAL.".to_string())
+    );
+}
+
+/// A library with three functions, assembled by hand:
+///
+/// (import "ferro" "input_len" (func (result i32)))
+/// (import "ferro" "read_input" (func (param i32)))
+/// (import "ferro" "call" (func (param i32 i32) (result i32)))
+/// (import "ferro" "reply" (func (param i32 i32)))
+/// (memory (export "memory") 1)
+/// (data (i32.const 0) "*3\r\n$3\r\nSET\r\n$5\r\nwasm1\r\n$2\r\nhi\r\n")
+/// (func (export "set_hi") (result i32) (drop (call 2 (i32.const 0) (i32.const 32))) (i32.const 42))
+/// (func (export "spin") (result i32) (loop (br 0)) (i32.const 0))
+/// (func (export "echo") (result i32) (local i32)
+///   (local.set 0 (call 0)) (call 1 (i32.const 1024))
+///   (call 3 (i32.const 1024) (local.get 0)) (i32.const 0))
+const TEST_LIBRARY: &str = "0061736d010000000114046000017f60017f0060027f7f017f60027f7f0002410405666572726f09696e7075745f6c656e000005666572726f0a726561645f696e707574000105666572726f0463616c6c000205666572726f057265706c7900030304030000000503010001072104066d656d6f72790200067365745f68690004047370696e0005046563686f00060a2e030b004100412010021a412a0b090003400c000b41000b1601017f1000210041800810014180082000100341000b0b26010041000b202a330d0a24330d0a5345540d0a24350d0a7761736d310d0a24320d0a68690d0a";
+
+fn command(args: &[&str]) -> RespValue {
+    RespValue::Array(
+        args.iter()
+            .map(|arg| RespValue::BulkString(arg.to_string().into()))
+            .collect(),
+    )
+}
+
+#[tokio::test]
+async fn test_function_load_and_fcall() {
+    let store = FerroStore::new();
+
+    let load = command(&["FUNCTION", "LOAD", "mylib", TEST_LIBRARY]);
+    let response = handle_command(load.clone(), &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("mylib".into()));
+    let response = handle_command(load, &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR Library 'mylib' already exists".to_string())
+    );
+    let load = command(&["FUNCTION", "LOAD", "REPLACE", "mylib", TEST_LIBRARY]);
+    let response = handle_command(load, &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("mylib".into()));
+
+    let list = command(&["FUNCTION", "LIST"]);
+    let response = handle_command(list, &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![RespValue::Array(vec![
+            RespValue::BulkString("library_name".into()),
+            RespValue::BulkString("mylib".into()),
+            RespValue::BulkString("functions".into()),
+            RespValue::Array(vec![
+                RespValue::BulkString("echo".into()),
+                RespValue::BulkString("set_hi".into()),
+                RespValue::BulkString("spin".into()),
+            ]),
+        ])])
+    );
+
+    // Without an explicit reply the return value is the result
+    let fcall = command(&["FCALL", "set_hi", "0"]);
+    let response = handle_command(fcall, &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(42));
+    assert_eq!(store.get("wasm1"), Some("hi".to_string()));
+
+    let fcall = command(&["FCALL", "echo", "1", "k", "a"]);
+    let response = handle_command(fcall, &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::Array(vec![RespValue::BulkString("k".into())]),
+            RespValue::Array(vec![RespValue::BulkString("a".into())]),
+        ])
+    );
+
+    // Runaway functions are stopped once they run out of fuel
+    let fcall = command(&["FCALL", "spin", "0"]);
+    let response = handle_command(fcall, &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR Function exceeded its instruction budget".to_string())
+    );
+
+    let delete = command(&["FUNCTION", "DELETE", "mylib"]);
+    let response = handle_command(delete.clone(), &store, None, None).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    let response = handle_command(delete, &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR Library not found".to_string())
+    );
+    let fcall = command(&["FCALL", "set_hi", "0"]);
+    let response = handle_command(fcall, &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR Function not found".to_string())
+    );
+}
+
+/// Appends its arguments to a list, like a module shipped by another crate
+struct AppendAll;
+
+impl CommandModule for AppendAll {
+    fn name(&self) -> &str {
+        "test.appendall"
+    }
+
+    fn arity(&self) -> i32 {
+        -3
+    }
+
+    fn flags(&self) -> CommandFlags {
+        CommandFlags::WRITE
+    }
+
+    fn execute<'a>(&'a self, store: &'a FerroStore, args: &'a [String]) -> CommandFuture<'a> {
+        Box::pin(async move {
+            match store.rpush(&args[1], args[2..].to_vec()) {
+                Ok(len) => RespValue::Integer(len as i64),
+                Err(e) => RespValue::SimpleString(e),
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_command_module() {
+    let store = FerroStore::new();
+    store.modules().register(AppendAll).unwrap();
+    assert!(store.modules().register(AppendAll).is_err());
+
+    let response = handle_command(
+        command(&["TEST.APPENDALL", "list", "a", "b"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(response, RespValue::Integer(2));
+    assert_eq!(
+        store.lrange("list", 0, -1),
+        Ok(vec!["a".to_string(), "b".to_string()])
+    );
+
+    let response = handle_command(command(&["test.appendall", "list"]), &store, None, None).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR wrong number of arguments for 'test.appendall' command".to_string())
+    );
+
+    // Modules run inside transactions like any other command
+    let mut conn = ConnectionContext::new();
+    for args in [&["MULTI"][..], &["TEST.APPENDALL", "list", "c"], &["EXEC"]] {
+        handle_command(command(args), &store, None, Some(&mut conn)).await;
+    }
+    assert_eq!(store.llen("list"), Ok(3));
+}
+
+#[tokio::test]
+async fn test_command_table_validation() {
+    let store = FerroStore::new();
+
+    // Every command in the table is dispatched (skipping SAVE and friends,
+    // which would write to the working directory)
+    for spec in COMMAND_TABLE {
+        if spec.flags.contains(CommandFlags::ADMIN) {
+            continue;
+        }
+        let response = handle_command(command(&[spec.name]), &store, None, None).await;
+        assert_ne!(
+            response,
+            RespValue::Error(format!("ERR unknown command {}", spec.name))
+        );
+    }
+
+    for args in [
+        &["GET"][..],
+        &["GET", "a", "b"],
+        &["LINSERT", "l", "BEFORE", "x"],
+    ] {
+        let response = handle_command(command(args), &store, None, None).await;
+        assert_eq!(
+            response,
+            RespValue::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                args[0].to_lowercase()
+            ))
+        );
+    }
+
+    // Invalid commands are rejected when queued and abort the transaction
+    let mut conn = ConnectionContext::new();
+    let mut responses = Vec::new();
+    for args in [
+        &["MULTI"][..],
+        &["SET", "k", "v"],
+        &["GET"],
+        &["NOSUCHCOMMAND"],
+        &["EXEC"],
+    ] {
+        responses.push(handle_command(command(args), &store, None, Some(&mut conn)).await);
+    }
+    assert_eq!(
+        responses[2],
+        RespValue::Error("ERR wrong number of arguments for 'get' command".to_string())
+    );
+    assert_eq!(
+        responses[3],
+        RespValue::Error("ERR unknown command NOSUCHCOMMAND".to_string())
+    );
+    assert!(matches!(&responses[4], RespValue::Error(e) if e.starts_with("EXECABORT")));
+    assert_eq!(store.get("k"), None);
+
+    // Modules can't shadow built-in commands
+    struct Get;
+    impl CommandModule for Get {
+        fn name(&self) -> &str {
+            "get"
+        }
+        fn arity(&self) -> i32 {
+            2
+        }
+        fn flags(&self) -> CommandFlags {
+            CommandFlags::READONLY
+        }
+        fn execute<'a>(&'a self, _: &'a FerroStore, _: &'a [String]) -> CommandFuture<'a> {
+            Box::pin(async { RespValue::Null })
+        }
+    }
+    assert!(store.modules().register(Get).is_err());
+}
+
+#[tokio::test]
+async fn test_command_introspection() {
+    let store = FerroStore::new();
+
+    let response = handle_command(command(&["COMMAND", "COUNT"]), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(COMMAND_TABLE.len() as i64));
+    let response = handle_command(command(&["COMMAND"]), &store, None, None).await;
+    assert!(matches!(response, RespValue::Array(infos) if infos.len() == COMMAND_TABLE.len()));
+
+    let info = |name: &str, arity: i64, flags: &[&str], keys: [i64; 3]| {
+        let mut info = vec![
+            RespValue::BulkString(name.to_string().into()),
+            RespValue::Integer(arity),
+            RespValue::Array(
+                flags
+                    .iter()
+                    .map(|flag| RespValue::SimpleString(flag.to_string()))
+                    .collect(),
+            ),
+        ];
+        info.extend(keys.map(RespValue::Integer));
+        info.extend((0..4).map(|_| RespValue::Array(vec![])));
+        RespValue::Array(info)
+    };
+    let response = handle_command(
+        command(&["COMMAND", "INFO", "get", "MSET", "blpop", "nosuch"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            info("get", 2, &["readonly"], [1, 1, 1]),
+            info("mset", -3, &["write", "denyoom"], [1, -1, 2]),
+            info("blpop", -3, &["write", "blocking"], [1, -2, 1]),
+            RespValue::Null,
+        ])
+    );
+
+    let response = handle_command(
+        command(&["COMMAND", "DOCS", "zadd", "nosuch"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![
+            RespValue::BulkString("zadd".into()),
+            RespValue::Array(vec![
+                RespValue::BulkString("summary".into()),
+                RespValue::BulkString(
+                    "Adds one or more members to a sorted set, or updates their scores".into()
+                ),
+                RespValue::BulkString("group".into()),
+                RespValue::BulkString("sorted-set".into()),
+            ]),
+        ])
+    );
+
+    // Module commands are listed too
+    store.modules().register(AppendAll).unwrap();
+    let response = handle_command(command(&["COMMAND", "COUNT"]), &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(COMMAND_TABLE.len() as i64 + 1));
+    let response = handle_command(
+        command(&["COMMAND", "INFO", "test.appendall"]),
+        &store,
+        None,
+        None,
+    )
+    .await;
+    assert_eq!(
+        response,
+        RespValue::Array(vec![info("test.appendall", -3, &["write"], [0, 0, 0])])
+    );
+}
+
+#[tokio::test]
+async fn test_config_get_set() {
+    let store = FerroStore::new();
+    let config = |args: &[&str]| handle_command(command(args), &store, None, None);
+
+    let response = config(&["CONFIG", "GET", "append*", "HZ"]).await;
+    assert_eq!(
+        response,
+        RespValue::Map(
+            [
+                ("appendonly", "yes"),
+                ("appendfilename", "appendonly.aof"),
+                ("appenddirname", "appendonlydir"),
+                ("appendfsync", "everysec"),
+                ("hz", "10")
+            ]
+            .iter()
+            .map(|(name, value)| {
+                (
+                    RespValue::BulkString(name.to_string().into()),
+                    RespValue::BulkString(value.to_string().into()),
+                )
+            })
+            .collect()
+        )
+    );
+
+    let response = config(&["CONFIG", "SET", "hz", "50", "maxmemory", "2mb", "save", ""]).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    assert_eq!(store.config().read().hz, 50);
+    assert_eq!(store.config().read().maxmemory, 2 * 1024 * 1024);
+    assert!(store.config().read().save.is_empty());
+
+    let response = config(&["CONFIG", "SET", "timeout", "300"]).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    assert_eq!(store.config().read().timeout, 300);
+    let response = config(&["CONFIG", "SET", "timeout", "-1"]).await;
+    assert!(matches!(response, RespValue::Error(e) if e.starts_with("ERR CONFIG SET failed")));
+    let response = config(&["CONFIG", "SET", "tcp-keepalive", "60", "tcp-nodelay", "no"]).await;
+    assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+    assert_eq!(store.config().read().tcp_keepalive, 60);
+    assert!(!store.config().read().tcp_nodelay);
+
+    // An invalid pair leaves every parameter unchanged
+    let response = config(&["CONFIG", "SET", "hz", "20", "appendfsync", "sometimes"]).await;
+    assert!(
+        matches!(response, RespValue::Error(e) if e.starts_with("ERR CONFIG SET failed (possibly related to argument 'appendfsync')"))
+    );
+    assert_eq!(store.config().read().hz, 50);
+
+    let response = config(&["CONFIG", "SET", "port", "7000"]).await;
+    assert_eq!(
+        response,
+        RespValue::Error(
+            "ERR CONFIG SET failed (possibly related to argument 'port') - can't set immutable config"
+                .to_string()
+        )
+    );
+    let response = config(&["CONFIG", "SET", "nosuch", "1"]).await;
+    assert_eq!(
+        response,
+        RespValue::Error(
+            "ERR Unknown option or number of arguments for CONFIG SET - 'nosuch'".to_string()
+        )
+    );
+    let response = config(&["CONFIG", "SET", "hz"]).await;
+    assert_eq!(
+        response,
+        RespValue::Error("ERR wrong number of arguments for 'config|set' command".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_requirepass_and_auth() {
+    let store = FerroStore::new();
+    let mut conn = ConnectionContext::new();
+
+    // Without a password every command may run, and AUTH is an error
+    assert_eq!(
+        authenticate(&command(&["GET", "k"]), &store, &mut conn),
+        None
+    );
+    let reply = authenticate(&command(&["AUTH", "pw"]), &store, &mut conn);
+    assert!(matches!(reply, Some(RespValue::Error(e)) if e.starts_with("ERR AUTH")));
+
+    store
+        .config()
+        .set(&[("requirepass".to_string(), "pw".to_string())])
+        .unwrap();
+    assert_eq!(
+        authenticate(&command(&["GET", "k"]), &store, &mut conn),
+        Some(RespValue::Error(
+            "NOAUTH Authentication required.".to_string()
+        ))
+    );
+    assert_eq!(
+        authenticate(&command(&["AUTH", "wrong"]), &store, &mut conn),
+        Some(RespValue::Error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_string()
+        ))
+    );
+    assert!(!conn.authenticated);
+    assert_eq!(
+        authenticate(&command(&["AUTH", "default", "pw"]), &store, &mut conn),
+        Some(RespValue::SimpleString("OK".to_string()))
+    );
+    assert!(conn.authenticated);
+    assert_eq!(
+        authenticate(&command(&["GET", "k"]), &store, &mut conn),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_select_swapdb_and_move() {
+    let store = FerroStore::new();
+    let mut conn = ConnectionContext::new();
+    async fn run(store: &FerroStore, conn: &mut ConnectionContext, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, Some(conn)).await
+    }
+    let ok = RespValue::SimpleString("OK".to_string());
+
+    run(&store, &mut conn, &["SET", "color", "blue"]).await;
+    assert_eq!(run(&store, &mut conn, &["SELECT", "1"]).await, ok);
+    assert_eq!(
+        run(&store, &mut conn, &["GET", "color"]).await,
+        RespValue::Null
+    );
+    run(&store, &mut conn, &["SET", "color", "green"]).await;
+    assert_eq!(
+        run(&store, &mut conn, &["SELECT", "16"]).await,
+        RespValue::Error("ERR DB index is out of range".to_string())
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["SELECT", "x"]).await,
+        RespValue::Error("ERR value is not an integer or out of range".to_string())
+    );
+
+    // The selected database is the connection's; handles keep their own
+    let db1 = store.database(1).unwrap();
+    assert_eq!(db1.get("color"), Some("green".to_string()));
+    assert_eq!(store.get("color"), Some("blue".to_string()));
+
+    // SWAPDB changes what every client on either database sees
+    assert_eq!(run(&store, &mut conn, &["SWAPDB", "0", "1"]).await, ok);
+    assert_eq!(db1.get("color"), Some("blue".to_string()));
+    assert_eq!(store.get("color"), Some("green".to_string()));
+    assert_eq!(run(&store, &mut conn, &["SWAPDB", "0", "0"]).await, ok);
+
+    // MOVE fails when the key exists on the target database
+    assert_eq!(
+        run(&store, &mut conn, &["MOVE", "color", "0"]).await,
+        RespValue::Integer(0)
+    );
+    run(&store, &mut conn, &["SET", "temp", "1"]).await;
+    run(&store, &mut conn, &["EXPIRE", "temp", "100"]).await;
+    assert_eq!(
+        run(&store, &mut conn, &["MOVE", "temp", "2"]).await,
+        RespValue::Integer(1)
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["EXISTS", "temp"]).await,
+        RespValue::Integer(0)
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["MOVE", "missing", "2"]).await,
+        RespValue::Integer(0)
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["MOVE", "color", "1"]).await,
+        RespValue::Error("ERR source and destination objects are the same".to_string())
+    );
+    run(&store, &mut conn, &["SELECT", "2"]).await;
+    assert_eq!(
+        run(&store, &mut conn, &["GET", "temp"]).await,
+        RespValue::BulkString("1".into())
+    );
+    assert!(
+        matches!(run(&store, &mut conn, &["TTL", "temp"]).await, RespValue::Integer(ttl) if ttl > 0)
+    );
+
+    // A SELECT queued in MULTI applies to the rest of the transaction and
+    // stays selected after it
+    run(&store, &mut conn, &["MULTI"]).await;
+    run(&store, &mut conn, &["SELECT", "3"]).await;
+    run(&store, &mut conn, &["SET", "queued", "1"]).await;
+    run(&store, &mut conn, &["EXEC"]).await;
+    assert_eq!(conn.db, 3);
+    assert_eq!(
+        store.database(3).unwrap().get("queued"),
+        Some("1".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_debug_subcommands() {
+    let store = FerroStore::new();
+    let mut conn = ConnectionContext::new();
+    async fn run(store: &FerroStore, conn: &mut ConnectionContext, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, Some(conn)).await
+    }
+    let ok = RespValue::SimpleString("OK".to_string());
+
+    assert_eq!(
+        run(&store, &mut conn, &["DEBUG", "SLEEP", "0.01"]).await,
+        ok
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["DEBUG", "SLEEP", "soon"]).await,
+        RespValue::Error("ERR value is not a valid float".to_string())
+    );
+
+    run(&store, &mut conn, &["SET", "counter", "42"]).await;
+    run(&store, &mut conn, &["RPUSH", "queue", "a", "b"]).await;
+    run(&store, &mut conn, &["EXPIRE", "queue", "100"]).await;
+    assert_eq!(
+        run(&store, &mut conn, &["DEBUG", "OBJECT", "counter"]).await,
+        RespValue::SimpleString(
+            "type:string encoding:int serializedlength:11 lru_seconds_idle:0 ttl:-1".to_string()
+        )
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["DEBUG", "OBJECT", "queue"]).await,
+        RespValue::SimpleString(
+            "type:list encoding:listpack serializedlength:27 lru_seconds_idle:0 ttl:100"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["DEBUG", "OBJECT", "missing"]).await,
+        RespValue::Error("ERR no such key".to_string())
+    );
+
+    assert_eq!(
+        run(&store, &mut conn, &["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await,
+        ok
+    );
+    assert!(!store.active_expire_enabled());
+    assert_eq!(
+        run(&store, &mut conn, &["DEBUG", "SET-ACTIVE-EXPIRE", "1"]).await,
+        ok
+    );
+    assert!(store.active_expire_enabled());
+
+    // RELOAD round-trips every database through the snapshot file
+    let path = "test_debug_reload.rdb";
+    store
+        .config()
+        .set(&[("dbfilename".to_string(), path.to_string())])
+        .unwrap();
+    run(&store, &mut conn, &["SELECT", "4"]).await;
+    run(&store, &mut conn, &["SET", "other", "db"]).await;
+    assert_eq!(run(&store, &mut conn, &["DEBUG", "RELOAD"]).await, ok);
+    std::fs::remove_file(path).ok();
+    assert_eq!(
+        run(&store, &mut conn, &["GET", "other"]).await,
+        RespValue::BulkString("db".into())
+    );
+    run(&store, &mut conn, &["SELECT", "0"]).await;
+    assert_eq!(
+        run(&store, &mut conn, &["GET", "counter"]).await,
+        RespValue::BulkString("42".into())
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["LLEN", "queue"]).await,
+        RespValue::Integer(2)
+    );
+
+    assert_eq!(
+        run(&store, &mut conn, &["DEBUG", "NOPE"]).await,
+        RespValue::Error("ERR unknown subcommand 'NOPE'".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_acl_users_and_permissions() {
+    let store = FerroStore::new();
+    let admin = |args: &[&str]| handle_command(command(args), &store, None, None);
+    async fn run(store: &FerroStore, conn: &mut ConnectionContext, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, Some(conn)).await
+    }
+    let ok = RespValue::SimpleString("OK".to_string());
+    let bulk = |items: &[&str]| {
+        RespValue::Array(
+            items
+                .iter()
+                .map(|item| RespValue::BulkString(item.to_string().into()))
+                .collect(),
+        )
+    };
+
+    assert_eq!(
+        admin(&["ACL", "SETUSER", "analyst", "on", ">secret", "~*", "+@read"]).await,
+        ok
+    );
+    assert_eq!(
+        admin(&[
+            "ACL", "SETUSER", "ops", "on", ">opspw", "allkeys", "+@all", "-debug"
+        ])
+        .await,
+        ok
+    );
+    assert_eq!(
+        admin(&[
+            "ACL", "SETUSER", "cache", "on", "nopass", "~cache:*", "+@all"
+        ])
+        .await,
+        ok
+    );
+    assert_eq!(
+        admin(&["ACL", "SETUSER", "typo", "+nosuchcommand"]).await,
+        RespValue::Error(
+            "ERR Error in ACL SETUSER modifier '+nosuchcommand': Unknown command or category name in ACL"
+                .to_string()
+        )
+    );
+    assert_eq!(admin(&["ACL", "GETUSER", "typo"]).await, RespValue::Null);
+    assert_eq!(
+        admin(&["ACL", "GETUSER", "analyst"]).await,
+        RespValue::Array(vec![
+            RespValue::BulkString("flags".into()),
+            bulk(&["on"]),
+            RespValue::BulkString("passwords".into()),
+            bulk(&["2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"]),
+            RespValue::BulkString("commands".into()),
+            RespValue::BulkString("-@all +@read".into()),
+            RespValue::BulkString("keys".into()),
+            RespValue::BulkString("~*".into()),
+        ])
+    );
+    assert_eq!(
+        admin(&["ACL", "USERS"]).await,
+        bulk(&["analyst", "cache", "default", "ops"])
+    );
+    assert!(matches!(
+        admin(&["ACL", "LIST"]).await,
+        RespValue::Array(lines) if lines.contains(&RespValue::BulkString("user default on nopass ~* +@all".into()))
+    ));
+    admin(&["SET", "report", "42"]).await;
+
+    // A connection starts as `default` and switches user with AUTH
+    let mut analyst = ConnectionContext::new();
+    analyst.user = Some("default".to_string());
+    assert_eq!(
+        authenticate(
+            &command(&["AUTH", "analyst", "wrong"]),
+            &store,
+            &mut analyst
+        ),
+        Some(RespValue::Error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_string()
+        ))
+    );
+    assert_eq!(
+        authenticate(
+            &command(&["AUTH", "analyst", "secret"]),
+            &store,
+            &mut analyst
+        ),
+        Some(RespValue::SimpleString("OK".to_string()))
+    );
+    assert_eq!(analyst.user.as_deref(), Some("analyst"));
+    assert_eq!(
+        run(&store, &mut analyst, &["GET", "report"]).await,
+        RespValue::BulkString("42".into())
+    );
+    assert_eq!(
+        run(&store, &mut analyst, &["SET", "report", "0"]).await,
+        RespValue::Error(
+            "NOPERM User analyst has no permissions to run the 'set' command".to_string()
+        )
+    );
+    assert_eq!(
+        run(&store, &mut analyst, &["ACL", "WHOAMI"]).await,
+        RespValue::Error(
+            "NOPERM User analyst has no permissions to run the 'acl' command".to_string()
+        )
+    );
+
+    // Ops may write, but not run DEBUG
+    let mut ops = ConnectionContext::new();
+    ops.user = Some("ops".to_string());
+    assert_eq!(run(&store, &mut ops, &["SET", "report", "43"]).await, ok);
+    assert_eq!(
+        run(&store, &mut ops, &["ACL", "WHOAMI"]).await,
+        RespValue::BulkString("ops".into())
+    );
+    assert_eq!(
+        run(&store, &mut ops, &["DEBUG", "SLEEP", "0"]).await,
+        RespValue::Error(
+            "NOPERM User ops has no permissions to run the 'debug' command".to_string()
+        )
+    );
+
+    // Key patterns apply to every key a command touches
+    let mut cache = ConnectionContext::new();
+    cache.user = Some("cache".to_string());
+    assert_eq!(run(&store, &mut cache, &["SET", "cache:1", "a"]).await, ok);
+    let denied = RespValue::Error("NOPERM No permissions to access a key".to_string());
+    assert_eq!(
+        run(&store, &mut cache, &["MGET", "cache:1", "report"]).await,
+        denied
+    );
+    assert_eq!(
+        run(&store, &mut cache, &["EVAL", "return 1", "1", "report"]).await,
+        denied
+    );
+
+    // Disabled and deleted users lose access right away
+    admin(&["ACL", "SETUSER", "cache", "off"]).await;
+    assert!(
+        matches!(run(&store, &mut cache, &["GET", "cache:1"]).await, RespValue::Error(e) if e.starts_with("NOPERM"))
+    );
+    assert_eq!(
+        admin(&["ACL", "DELUSER", "analyst", "nobody"]).await,
+        RespValue::Integer(1)
+    );
+    assert_eq!(
+        admin(&["ACL", "DELUSER", "default"]).await,
+        RespValue::Error("ERR The 'default' user cannot be removed".to_string())
+    );
+    assert!(matches!(
+        admin(&["ACL", "CAT", "read"]).await,
+        RespValue::Array(names) if names.contains(&RespValue::BulkString("get".into()))
+    ));
+}
+
+#[tokio::test]
+async fn test_protected_mode() {
+    let store = FerroStore::new();
+    let remote: std::net::IpAddr = "192.0.2.7".parse().unwrap();
+    let denied = |store: &FerroStore, ip: &str| {
+        protected_mode_denial(store, ip.parse().unwrap())
+            .is_some_and(|reply| matches!(reply, RespValue::Error(s) if s.starts_with("DENIED")))
+    };
+
+    // Loopback clients, including IPv4-mapped ones, are always accepted
+    assert!(!denied(&store, "127.0.0.1"));
+    assert!(!denied(&store, "::1"));
+    assert!(!denied(&store, "::ffff:127.0.0.1"));
+    assert!(denied(&store, "192.0.2.7"));
+    assert!(denied(&store, "2001:db8::1"));
+
+    // A password for the default user lifts the restriction
+    store
+        .config()
+        .set(&[("requirepass".to_string(), "s3cret".to_string())])
+        .unwrap();
+    assert_eq!(protected_mode_denial(&store, remote), None);
+    store
+        .config()
+        .set(&[("requirepass".to_string(), String::new())])
+        .unwrap();
+    assert!(denied(&store, "192.0.2.7"));
+    let run = |args: &[&str]| handle_command(command(args), &store, None, None);
+    run(&["ACL", "SETUSER", "default", "resetpass", ">s3cret"]).await;
+    assert_eq!(protected_mode_denial(&store, remote), None);
+    run(&["ACL", "SETUSER", "default", "nopass"]).await;
+
+    // So does turning protected mode off
+    assert_eq!(
+        run(&["CONFIG", "SET", "protected-mode", "no"]).await,
+        RespValue::SimpleString("OK".to_string())
+    );
+    assert_eq!(protected_mode_denial(&store, remote), None);
+}
+
+#[tokio::test]
+async fn test_renamed_and_disabled_commands() {
+    let path = "/tmp/test_FerroDB_rename_dispatch.conf";
+    std::fs::write(
+        path,
+        "rename-command DEBUG secret-debug\nrename-command DEL \"\"\n",
+    )
+    .unwrap();
+    let store = FerroStore::new();
+    store.config().load_file(path).unwrap();
+    std::fs::remove_file(path).ok();
+    let mut client = ConnectionContext::new();
+    client.user = Some("default".to_string());
+    async fn run(store: &FerroStore, conn: &mut ConnectionContext, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, Some(conn)).await
+    }
+
+    assert_eq!(
+        run(&store, &mut client, &["DEBUG", "SET-ACTIVE-EXPIRE", "1"]).await,
+        RespValue::Error("ERR unknown command DEBUG".to_string())
+    );
+    assert_eq!(
+        run(
+            &store,
+            &mut client,
+            &["secret-debug", "SET-ACTIVE-EXPIRE", "1"]
+        )
+        .await,
+        RespValue::SimpleString("OK".to_string())
+    );
+    run(&store, &mut client, &["SET", "k", "v"]).await;
+    assert_eq!(
+        run(&store, &mut client, &["DEL", "k"]).await,
+        RespValue::Error("ERR unknown command DEL".to_string())
+    );
+    assert!(matches!(
+        run(&store, &mut client, &["EVAL", "return redis.call('DEL', 'k')", "0"]).await,
+        RespValue::Error(e) if e.contains("unknown command DEL")
+    ));
+    assert_eq!(store.get("k"), Some("v".to_string()));
+
+    // Internal callers such as AOF replay keep the real names
+    assert_eq!(
+        handle_command(command(&["DEL", "k"]), &store, None, None).await,
+        RespValue::Integer(1)
+    );
+}
+
+#[tokio::test]
+async fn test_client_registry() {
+    let store = FerroStore::new();
+    let addr = |s: &str| s.parse::<std::net::SocketAddr>().unwrap();
+    let first_client = store
+        .clients()
+        .register(addr("10.0.0.1:5000"), addr("127.0.0.1:6379"));
+    let mut first = ConnectionContext::for_client(first_client.id());
+    let second_client = store
+        .clients()
+        .register(addr("10.0.0.2:5001"), addr("127.0.0.1:6379"));
+    let mut second = ConnectionContext::for_client(second_client.id());
+    store
+        .clients()
+        .update(second_client.id(), |info| info.subscriptions = 1);
+
+    async fn run(
+        store: &FerroStore,
+        conn: Option<&mut ConnectionContext>,
+        args: &[&str],
+    ) -> RespValue {
+        handle_command(command(args), store, None, conn).await
+    }
+    // The ids of the clients CLIENT LIST returns
+    async fn list(store: &FerroStore, args: &[&str]) -> Vec<String> {
+        match run(store, None, args).await {
+            RespValue::BulkString(lines) => lines
+                .lines()
+                .map(|line| line.split(' ').next().unwrap().to_string())
+                .collect(),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+    assert_eq!(
+        run(&store, Some(&mut first), &["CLIENT", "ID"]).await,
+        RespValue::Integer(1)
+    );
+    assert_eq!(
+        run(&store, Some(&mut second), &["CLIENT", "ID"]).await,
+        RespValue::Integer(2)
+    );
+    assert_eq!(
+        run(&store, Some(&mut first), &["CLIENT", "GETNAME"]).await,
+        RespValue::Null
+    );
+    assert_eq!(
+        run(&store, Some(&mut first), &["CLIENT", "SETNAME", "worker-1"]).await,
+        RespValue::SimpleString("OK".to_string())
+    );
+    assert!(matches!(
+        run(&store, Some(&mut first), &["CLIENT", "SETNAME", "bad name"]).await,
+        RespValue::Error(e) if e.starts_with("ERR Client names cannot contain spaces")
+    ));
+    assert_eq!(
+        run(&store, Some(&mut first), &["CLIENT", "GETNAME"]).await,
+        RespValue::BulkString("worker-1".into())
+    );
+
+    let RespValue::BulkString(info) = run(&store, Some(&mut first), &["CLIENT", "INFO"]).await
+    else {
+        panic!("CLIENT INFO should return a bulk string");
+    };
+    assert!(info.starts_with("id=1 addr=10.0.0.1:5000 laddr=127.0.0.1:6379 name=worker-1 "));
+    assert!(info.ends_with("\n"));
+
+    assert_eq!(
+        list(&store, &["CLIENT", "LIST"]).await,
+        vec!["id=1", "id=2"]
+    );
+    assert_eq!(
+        list(&store, &["CLIENT", "LIST", "TYPE", "pubsub"]).await,
+        vec!["id=2"]
+    );
+    assert_eq!(
+        list(&store, &["CLIENT", "LIST", "ID", "1", "3"]).await,
+        vec!["id=1"]
+    );
+    assert!(matches!(
+        run(&store, None, &["CLIENT", "LIST", "TYPE", "nosuch"]).await,
+        RespValue::Error(e) if e.starts_with("ERR Unknown client type")
+    ));
+
+    // Disconnected clients leave the list
+    drop(second_client);
+    assert_eq!(list(&store, &["CLIENT", "LIST"]).await, vec!["id=1"]);
+    assert_eq!(
+        run(&store, None, &["CLIENT", "ID"]).await,
+        RespValue::Error("ERR no client for this connection".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_client_kill() {
+    let store = FerroStore::new();
+    let addr = |s: &str| s.parse::<std::net::SocketAddr>().unwrap();
+    let clients: Vec<_> = ["10.0.0.1:5000", "10.0.0.2:5001", "10.0.0.3:5002"]
+        .iter()
+        .map(|peer| store.clients().register(addr(peer), addr("127.0.0.1:6379")))
+        .collect();
+    store
+        .clients()
+        .update(clients[2].id(), |info| info.subscriptions = 1);
+    let mut me = ConnectionContext::for_client(clients[0].id());
+
+    async fn run(store: &FerroStore, conn: &mut ConnectionContext, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, Some(conn)).await
+    }
+    async fn killed(client: &FerroDB::clients::Client) -> bool {
+        tokio::time::timeout(std::time::Duration::from_millis(20), client.killed())
+            .await
+            .is_ok()
+    }
+
+    assert_eq!(
+        run(&store, &mut me, &["CLIENT", "KILL", "TYPE", "pubsub"]).await,
+        RespValue::Integer(1)
+    );
+    assert!(killed(&clients[2]).await);
+    assert!(!killed(&clients[1]).await);
+
+    // The caller is skipped unless SKIPME no
+    assert_eq!(
+        run(
+            &store,
+            &mut me,
+            &[
+                "CLIENT",
+                "KILL",
+                "LADDR",
+                "127.0.0.1:6379",
+                "TYPE",
+                "normal"
+            ]
+        )
+        .await,
+        RespValue::Integer(1)
+    );
+    assert!(killed(&clients[1]).await);
+    assert!(!killed(&clients[0]).await);
+    assert_eq!(
+        run(
+            &store,
+            &mut me,
+            &["CLIENT", "KILL", "ID", "1", "SKIPME", "no"]
+        )
+        .await,
+        RespValue::Integer(1)
+    );
+    assert!(killed(&clients[0]).await);
+
+    assert_eq!(
+        run(&store, &mut me, &["CLIENT", "KILL", "10.0.0.2:5001"]).await,
+        RespValue::SimpleString("OK".to_string())
+    );
+    assert!(killed(&clients[1]).await);
+    assert_eq!(
+        run(&store, &mut me, &["CLIENT", "KILL", "10.9.9.9:1"]).await,
+        RespValue::Error("ERR No such client".to_string())
+    );
+    assert_eq!(
+        run(&store, &mut me, &["CLIENT", "KILL", "ADDR", "10.9.9.9:1"]).await,
+        RespValue::Integer(0)
+    );
+    assert_eq!(
+        run(&store, &mut me, &["CLIENT", "KILL", "ID", "x"]).await,
+        RespValue::Error("ERR Invalid client ID".to_string())
+    );
+    assert_eq!(
+        run(&store, &mut me, &["CLIENT", "KILL", "COLOR", "red"]).await,
+        RespValue::Error("ERR syntax error".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_client_pause_and_reply() {
+    use std::time::Duration;
+    let store = FerroStore::new();
+    let addr = |s: &str| s.parse::<std::net::SocketAddr>().unwrap();
+    let registered = store
+        .clients()
+        .register(addr("10.0.0.1:5000"), addr("127.0.0.1:6379"));
+    let client = registered.id();
+    // Each command on a connection of its own, so one can wait meanwhile
+    async fn run(store: &FerroStore, client: u64, args: &[&str]) -> RespValue {
+        let mut conn = ConnectionContext::for_client(client);
+        handle_command(command(args), store, None, Some(&mut conn)).await
+    }
+    let ok = RespValue::SimpleString("OK".to_string());
+
+    // WRITE pauses writes only; reads go through
+    assert_eq!(
+        run(&store, client, &["CLIENT", "PAUSE", "10000", "WRITE"]).await,
+        ok
+    );
+    assert!(store.clients().is_paused());
+    assert_eq!(run(&store, client, &["GET", "k"]).await, RespValue::Null);
+    let set = run(&store, client, &["SET", "k", "v"]);
+    let mut set = std::pin::pin!(set);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), set.as_mut())
+            .await
+            .is_err()
+    );
+    assert_eq!(run(&store, client, &["CLIENT", "UNPAUSE"]).await, ok);
+    assert_eq!(set.await, ok);
+
+    // ALL pauses reads too, until the timeout runs out
+    assert_eq!(run(&store, client, &["CLIENT", "PAUSE", "100"]).await, ok);
+    let started = std::time::Instant::now();
+    assert_eq!(
+        run(&store, client, &["GET", "k"]).await,
+        RespValue::BulkString("v".into())
+    );
+    assert!(started.elapsed() >= Duration::from_millis(80));
+    // Internal callers, such as AOF replay, are never paused
+    assert_eq!(run(&store, client, &["CLIENT", "PAUSE", "10000"]).await, ok);
+    assert_eq!(
+        handle_command(command(&["SET", "k", "w"]), &store, None, None).await,
+        ok
+    );
+    run(&store, client, &["CLIENT", "UNPAUSE"]).await;
+    assert!(matches!(
+        run(&store, client, &["CLIENT", "PAUSE", "soon"]).await,
+        RespValue::Error(e) if e.starts_with("ERR timeout is not an integer")
+    ));
+
+    // CLIENT REPLY: which of the following replies the connection sends
+    run(&store, client, &["CLIENT", "REPLY", "SKIP"]).await;
+    assert!(!store.clients().take_reply(client)); // CLIENT REPLY SKIP itself
+    assert!(!store.clients().take_reply(client)); // the next command
+    assert!(store.clients().take_reply(client));
+    run(&store, client, &["CLIENT", "REPLY", "OFF"]).await;
+    assert!(!store.clients().take_reply(client));
+    assert!(!store.clients().take_reply(client));
+    run(&store, client, &["CLIENT", "REPLY", "ON"]).await;
+    assert!(store.clients().take_reply(client));
+    assert_eq!(
+        run(&store, client, &["CLIENT", "REPLY", "MAYBE"]).await,
+        RespValue::Error("ERR syntax error".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_latency_monitor() {
+    let store = FerroStore::new();
+    let run = |args: &[&str]| handle_command(command(args), &store, None, None);
+
+    // Disabled by default
+    run(&["DEBUG", "SLEEP", "0.03"]).await;
+    assert_eq!(run(&["LATENCY", "LATEST"]).await, RespValue::Array(vec![]));
+
+    run(&["CONFIG", "SET", "latency-monitor-threshold", "20"]).await;
+    run(&["GET", "fast"]).await;
+    assert_eq!(run(&["LATENCY", "LATEST"]).await, RespValue::Array(vec![]));
+    run(&["DEBUG", "SLEEP", "0.03"]).await;
+    let RespValue::Array(latest) = run(&["LATENCY", "LATEST"]).await else {
+        panic!("LATENCY LATEST should return an array");
+    };
+    let [RespValue::Array(entry)] = latest.as_slice() else {
+        panic!("expected one event, got {:?}", latest);
+    };
+    assert_eq!(entry[0], RespValue::BulkString("command".into()));
+    assert!(matches!(entry[2], RespValue::Integer(ms) if ms >= 30));
+    assert_eq!(entry[2], entry[3]);
+
+    let RespValue::Array(history) = run(&["LATENCY", "HISTORY", "command"]).await else {
+        panic!("LATENCY HISTORY should return an array");
+    };
+    assert_eq!(history.len(), 1);
+    assert_eq!(
+        run(&["LATENCY", "HISTORY", "rdb-save"]).await,
+        RespValue::Array(vec![])
+    );
+
+    assert_eq!(
+        run(&["LATENCY", "RESET", "command", "rdb-save"]).await,
+        RespValue::Integer(1)
+    );
+    assert_eq!(run(&["LATENCY", "LATEST"]).await, RespValue::Array(vec![]));
+    assert_eq!(run(&["LATENCY", "RESET"]).await, RespValue::Integer(0));
+}
+
+#[tokio::test]
+async fn test_info_commandstats() {
+    let store = FerroStore::new();
+    async fn info(store: &FerroStore, section: &str) -> String {
+        match handle_command(command(&["INFO", section]), store, None, None).await {
+            RespValue::BulkString(info) => info.to_string(),
+            other => panic!("INFO should return a bulk string, got {:?}", other),
+        }
+    }
+
+    handle_command(command(&["SET", "a", "1"]), &store, None, None).await;
+    handle_command(command(&["GET", "a"]), &store, None, None).await;
+    handle_command(command(&["GET", "a"]), &store, None, None).await;
+    handle_command(command(&["GET"]), &store, None, None).await;
+    handle_command(command(&["LPUSH", "a", "x"]), &store, None, None).await;
+
+    let stats = info(&store, "commandstats").await;
+    assert!(stats.starts_with("# Commandstats\r\n"));
+    let get = stats
+        .lines()
+        .find(|line| line.starts_with("cmdstat_get:"))
+        .unwrap();
+    assert!(get.starts_with("cmdstat_get:calls=2,usec="));
+    assert!(get.ends_with(",rejected_calls=1,failed_calls=0"));
+    assert!(stats.contains("cmdstat_lpush:calls=1,"));
+    assert!(stats.contains(",rejected_calls=0,failed_calls=1\r\n"));
+
+    let latency = info(&store, "latencystats").await;
+    assert!(latency.contains("latency_percentiles_usec_get:p50="));
+    assert!(latency.contains(",p99.9="));
+
+    // The default sections leave out per-command statistics
+    let default = match handle_command(command(&["INFO"]), &store, None, None).await {
+        RespValue::BulkString(info) => info,
+        other => panic!("INFO should return a bulk string, got {:?}", other),
+    };
+    assert!(default.contains("# Server\r\n"));
+    assert!(default.contains("# Keyspace\r\ndb0:keys=1,expires=0\r\n"));
+    assert!(!default.contains("cmdstat_"));
+    assert!(info(&store, "all").await.contains("cmdstat_get:"));
+
+    assert_eq!(
+        handle_command(command(&["CONFIG", "RESETSTAT"]), &store, None, None).await,
+        RespValue::SimpleString("OK".to_string())
+    );
+    // Only the RESETSTAT itself, recorded once it finished
+    let stats = info(&store, "commandstats").await;
+    assert!(!stats.contains("cmdstat_get:"));
+    assert!(stats.contains("cmdstat_config:calls=1,"));
+}
+
+#[tokio::test]
+async fn test_memory_usage() {
+    let store = FerroStore::new();
+    async fn usage(store: &FerroStore, args: &[&str]) -> i64 {
+        let mut cmd = vec!["MEMORY", "USAGE"];
+        cmd.extend_from_slice(args);
+        match handle_command(command(&cmd), store, None, None).await {
+            RespValue::Integer(bytes) => bytes,
+            other => panic!("MEMORY USAGE should return an integer, got {:?}", other),
+        }
+    }
+
+    let long = "x".repeat(1000);
+    handle_command(command(&["SET", "short", "v"]), &store, None, None).await;
+    handle_command(command(&["SET", "long", &long]), &store, None, None).await;
+    let short = usage(&store, &["short"]).await;
+    assert!(short > 0);
+    assert!(usage(&store, &["long"]).await >= short + 990);
+
+    // Every collection type accounts for its members
+    let members: Vec<String> = (0..100).map(|i| format!("{:0100}", i)).collect();
+    for (cmd, key) in [("RPUSH", "list"), ("SADD", "set")] {
+        let mut args = vec![cmd, key];
+        args.extend(members.iter().map(String::as_str));
+        handle_command(command(&args), &store, None, None).await;
+        assert!(usage(&store, &[key, "SAMPLES", "0"]).await > 100 * 100);
+    }
+    let mut zadd = vec!["ZADD", "zset"];
+    for member in &members {
+        zadd.extend(["1", member.as_str()]);
+    }
+    handle_command(command(&zadd), &store, None, None).await;
+    // Members are held by both the index and the score table
+    assert!(usage(&store, &["zset", "SAMPLES", "0"]).await > 2 * 100 * 100);
+
+    // By default only a few elements are sampled and the rest extrapolated
+    let mut rpush = vec!["RPUSH", "skewed", "a", "b", "c", "d", "e"];
+    rpush.extend(std::iter::repeat_n(long.as_str(), 20));
+    handle_command(command(&rpush), &store, None, None).await;
+    let estimated = usage(&store, &["skewed"]).await;
+    let exact = usage(&store, &["skewed", "SAMPLES", "0"]).await;
+    assert!(exact >= 20 * 1000);
+    assert!(estimated < exact);
+    assert_eq!(usage(&store, &["skewed", "SAMPLES", "25"]).await, exact);
+
+    assert_eq!(
+        handle_command(command(&["MEMORY", "USAGE", "missing"]), &store, None, None).await,
+        RespValue::Null
+    );
+    for args in [
+        vec!["MEMORY", "USAGE", "short", "SAMPLES", "-1"],
+        vec!["MEMORY", "USAGE", "short", "COUNT", "5"],
+    ] {
+        assert_eq!(
+            handle_command(command(&args), &store, None, None).await,
+            RespValue::Error("ERR syntax error".to_string())
+        );
+    }
+}
+
+/// `used_memory` from INFO memory
+async fn used_memory(store: &FerroStore) -> u64 {
+    let RespValue::BulkString(info) =
+        handle_command(command(&["INFO", "memory"]), store, None, None).await
+    else {
+        panic!("INFO should return a bulk string");
+    };
+    info.lines()
+        .find_map(|line| line.strip_prefix("used_memory:"))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_maxmemory_noeviction() {
+    let store = FerroStore::new();
+    async fn run(store: &FerroStore, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, None).await
+    }
+    let value = "v".repeat(100);
+    run(&store, &["SET", "a", &value]).await;
+    run(&store, &["SET", "b", &value]).await;
+    let used = used_memory(&store).await;
+    assert!(used > 200);
+
+    let limit = (used - 1).to_string();
+    assert_eq!(
+        run(&store, &["CONFIG", "SET", "maxmemory", &limit]).await,
+        RespValue::SimpleString("OK".to_string())
+    );
+    let oom =
+        RespValue::Error("OOM command not allowed when used memory > 'maxmemory'.".to_string());
+    assert_eq!(run(&store, &["SET", "c", "1"]).await, oom);
+    assert_eq!(run(&store, &["RPUSH", "list", "1"]).await, oom);
+    // Reads and deletions still work, and free memory
+    assert_eq!(
+        run(&store, &["GET", "a"]).await,
+        RespValue::BulkString(value.clone().into())
+    );
+    assert_eq!(run(&store, &["DEL", "b"]).await, RespValue::Integer(1));
+    assert!(used_memory(&store).await < used / 2 + 100);
+    assert_eq!(
+        run(&store, &["SET", "c", "1"]).await,
+        RespValue::SimpleString("OK".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_maxmemory_eviction_policies() {
+    async fn run(store: &FerroStore, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, None).await
+    }
+    let value = "v".repeat(100);
+
+    // allkeys-lru keeps the recently read key
+    let store = FerroStore::new();
+    for i in 0..10 {
+        run(&store, &["SET", &format!("key{}", i), &value]).await;
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    run(&store, &["GET", "key0"]).await;
+    let limit = used_memory(&store).await.to_string();
+    run(&store, &["CONFIG", "SET", "maxmemory", &limit]).await;
+    run(
+        &store,
+        &[
+            "CONFIG",
+            "SET",
+            "maxmemory-policy",
+            "allkeys-lru",
+            "maxmemory-samples",
+            "64",
+        ],
+    )
+    .await;
+    for i in 10..15 {
+        let reply = run(&store, &["SET", &format!("key{}", i), &value]).await;
+        assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+    }
+    eprintln!("DBG used={} limit={}", used_memory(&store).await, limit);
+    assert!(used_memory(&store).await <= limit.parse::<u64>().unwrap() + 200);
+    assert_eq!(
+        run(&store, &["EXISTS", "key0"]).await,
+        RespValue::Integer(1)
+    );
+    assert!(matches!(run(&store, &["DBSIZE"]).await, RespValue::Integer(n) if n < 15));
+    let RespValue::BulkString(stats) = run(&store, &["INFO", "stats"]).await else {
+        panic!("INFO should return a bulk string");
+    };
+    assert!(!stats.contains("evicted_keys:0\r\n"));
+
+    // volatile-ttl evicts the key expiring soonest, and never keys without
+    // a TTL
+    let store = FerroStore::new();
+    run(&store, &["SET", "persistent", &value]).await;
+    run(&store, &["SET", "later", &value, "EX", "1000"]).await;
+    run(&store, &["SET", "sooner", &value, "EX", "100"]).await;
+    let limit = (used_memory(&store).await - 1).to_string();
+    run(
+        &store,
+        &[
+            "CONFIG",
+            "SET",
+            "maxmemory",
+            &limit,
+            "maxmemory-policy",
+            "volatile-ttl",
+        ],
+    )
+    .await;
+    run(&store, &["SET", "new", "1"]).await;
+    assert_eq!(
+        run(&store, &["EXISTS", "sooner"]).await,
+        RespValue::Integer(0)
+    );
+    assert_eq!(
+        run(&store, &["EXISTS", "later"]).await,
+        RespValue::Integer(1)
+    );
+    run(&store, &["SET", "big", &"v".repeat(1000)]).await;
+    assert_eq!(
+        run(&store, &["EXISTS", "persistent"]).await,
+        RespValue::Integer(1)
+    );
+    assert!(matches!(
+        run(&store, &["SET", "bigger", &"v".repeat(2000)]).await,
+        RespValue::Error(e) if e.starts_with("OOM")
+    ));
+
+    assert!(matches!(
+        run(&store, &["CONFIG", "SET", "maxmemory-policy", "sometimes"]).await,
+        RespValue::Error(e) if e.starts_with("ERR CONFIG SET failed")
+    ));
+}
+
+#[tokio::test]
+async fn test_object_freq_and_lfu_eviction() {
+    async fn run(store: &FerroStore, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, None).await
+    }
+
+    let store = FerroStore::new();
+    for i in 0..10 {
+        run(&store, &["SET", &format!("key{}", i), "value"]).await;
+    }
+    // New keys start with a small count so they aren't evicted right away
+    assert_eq!(
+        run(&store, &["OBJECT", "FREQ", "key0"]).await,
+        RespValue::Integer(5)
+    );
+    assert_eq!(
+        run(&store, &["OBJECT", "FREQ", "missing"]).await,
+        RespValue::Null
+    );
+    for _ in 0..100 {
+        run(&store, &["GET", "key0"]).await;
+    }
+    let RespValue::Integer(hot) = run(&store, &["OBJECT", "FREQ", "key0"]).await else {
+        panic!("OBJECT FREQ should return an integer");
+    };
+    assert!(hot > 5 && hot < 100, "frequency {}", hot);
+
+    // allkeys-lfu evicts cold keys before the frequently read one
+    let limit = (used_memory(&store).await - 1).to_string();
+    run(
+        &store,
+        &[
+            "CONFIG",
+            "SET",
+            "maxmemory",
+            &limit,
+            "maxmemory-policy",
+            "allkeys-lfu",
+        ],
+    )
+    .await;
+    for i in 10..20 {
+        run(&store, &["SET", &format!("key{}", i), "value"]).await;
+    }
+    assert_eq!(
+        run(&store, &["EXISTS", "key0"]).await,
+        RespValue::Integer(1)
+    );
+    assert!(matches!(run(&store, &["DBSIZE"]).await, RespValue::Integer(n) if n < 20));
+}
+
+#[tokio::test]
+async fn test_lazyfree_server_del() {
+    async fn run(store: &FerroStore, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, None).await
+    }
+    async fn freed(store: &FerroStore) -> u64 {
+        let RespValue::BulkString(info) = run(store, &["INFO", "stats"]).await else {
+            panic!("INFO should return a bulk string");
+        };
+        info.lines()
+            .find_map(|line| line.strip_prefix("lazyfreed_objects:"))
+            .expect("INFO stats should report lazyfreed_objects")
+            .parse()
+            .unwrap()
+    }
+
+    let store = FerroStore::new();
+    assert_eq!(
+        run(&store, &["CONFIG", "GET", "lazyfree-lazy-server-del"]).await,
+        RespValue::Map(vec![(
+            RespValue::BulkString("lazyfree-lazy-server-del".into()),
+            RespValue::BulkString("no".into()),
+        )])
+    );
+    run(
+        &store,
+        &["CONFIG", "SET", "lazyfree-lazy-server-del", "yes"],
+    )
+    .await;
+    let members: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+    let mut args = vec!["SADD", "big"];
+    args.extend(members.iter().map(String::as_str));
+    run(&store, &args).await;
+
+    // Overwriting the set hands it to the lazyfree thread
+    let before = freed(&store).await;
+    run(&store, &["SET", "big", "small"]).await;
+    assert_eq!(
+        run(&store, &["GET", "big"]).await,
+        RespValue::BulkString("small".into())
+    );
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while freed(&store).await == before {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "set was never lazily freed"
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_expired_and_evicted_notifications() {
+    async fn run(store: &FerroStore, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, None).await
+    }
+
+    let store = FerroStore::new();
+    assert!(matches!(
+        run(&store, &["CONFIG", "SET", "notify-keyspace-events", "Kx?"]).await,
+        RespValue::Error(e) if e.starts_with("ERR")
+    ));
+    run(&store, &["CONFIG", "SET", "notify-keyspace-events", "xeKE"]).await;
+    assert_eq!(
+        run(&store, &["CONFIG", "GET", "notify-keyspace-events"]).await,
+        RespValue::Map(vec![(
+            RespValue::BulkString("notify-keyspace-events".into()),
+            RespValue::BulkString("xeKE".into()),
+        )])
+    );
+    let mut expired = store.pubsub().subscribe("__keyevent@0__:expired");
+    let mut evicted = store.pubsub().subscribe("__keyspace@0__:victim");
+
+    // Lazily, when an expired key is accessed
+    run(&store, &["SET", "lazy", "v", "PX", "10"]).await;
+    tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+    assert_eq!(
+        run(&store, &["EXISTS", "lazy"]).await,
+        RespValue::Integer(0)
+    );
+    assert_eq!(expired.try_recv().unwrap().message, "lazy");
+
+    // Actively, by the expiration cycle
+    run(&store, &["SET", "active", "v", "PX", "10"]).await;
+    tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+    assert_eq!(store.delete_expired_keys(), 1);
+    assert_eq!(expired.try_recv().unwrap().message, "active");
+    assert!(expired.try_recv().is_err());
+
+    // By maxmemory eviction
+    run(&store, &["SET", "victim", "v"]).await;
+    let limit = (used_memory(&store).await - 1).to_string();
+    run(
+        &store,
+        &[
+            "CONFIG",
+            "SET",
+            "maxmemory",
+            &limit,
+            "maxmemory-policy",
+            "allkeys-random",
+        ],
+    )
+    .await;
+    run(&store, &["SET", "other", "v"]).await;
+    let event = evicted.try_recv().unwrap();
+    assert_eq!(event.channel, "__keyspace@0__:victim");
+    assert_eq!(event.message, "evicted");
+}
+
+#[tokio::test]
+async fn test_hello_switches_protocol() {
+    let store = FerroStore::new();
+    let mut conn = ConnectionContext::new();
+    async fn run(store: &FerroStore, conn: &mut ConnectionContext, args: &[&str]) -> RespValue {
+        handle_command(command(args), store, None, Some(conn)).await
+    }
+    let field = |reply: &RespValue, name: &str| match reply {
+        RespValue::Map(pairs) => pairs
+            .iter()
+            .find(|(field, _)| *field == RespValue::BulkString(name.to_string().into()))
+            .map(|(_, value)| value.clone()),
+        _ => None,
+    };
+
+    // Without a version HELLO reports the current protocol
+    assert_eq!(conn.protocol, 2);
+    let reply = run(&store, &mut conn, &["HELLO"]).await;
+    assert_eq!(field(&reply, "proto"), Some(RespValue::Integer(2)));
+    assert_eq!(
+        field(&reply, "server"),
+        Some(RespValue::BulkString("ferrodb".into()))
+    );
+
+    let reply = run(&store, &mut conn, &["HELLO", "3"]).await;
+    assert_eq!(field(&reply, "proto"), Some(RespValue::Integer(3)));
+    assert_eq!(conn.protocol, 3);
+    run(&store, &mut conn, &["SADD", "s", "a"]).await;
+    assert_eq!(
+        run(&store, &mut conn, &["SMEMBERS", "s"])
+            .await
+            .encode_for(conn.protocol),
+        "~1\r\n$1\r\na\r\n"
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["GET", "missing"])
+            .await
+            .encode_for(conn.protocol),
+        "_\r\n"
+    );
+
+    assert_eq!(
+        run(&store, &mut conn, &["HELLO", "4"]).await,
+        RespValue::Error("NOPROTO unsupported protocol version".to_string())
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["HELLO", "three"]).await,
+        RespValue::Error("ERR Protocol version is not an integer or out of range".to_string())
+    );
+    assert_eq!(
+        run(&store, &mut conn, &["HELLO", "2", "SETNAME"]).await,
+        RespValue::Error("ERR Syntax error in HELLO option 'SETNAME'".to_string())
+    );
+    assert_eq!(conn.protocol, 3);
+
+    // AUTH lets an unauthenticated client log in while switching
+    store
+        .config()
+        .set(&[("requirepass".to_string(), "pw".to_string())])
+        .unwrap();
+    assert_eq!(
+        authenticate(&command(&["HELLO", "2"]), &store, &mut conn),
+        Some(RespValue::Error(
+            "NOAUTH Authentication required.".to_string()
+        ))
+    );
+    let reply = authenticate(
+        &command(&["HELLO", "2", "AUTH", "default", "wrong"]),
+        &store,
+        &mut conn,
+    );
+    assert!(matches!(reply, Some(RespValue::Error(e)) if e.starts_with("WRONGPASS")));
+    assert!(!conn.authenticated);
+    assert_eq!(conn.protocol, 3);
+    let reply = authenticate(
+        &command(&["HELLO", "2", "AUTH", "default", "pw"]),
+        &store,
+        &mut conn,
+    )
+    .unwrap();
+    assert!(conn.authenticated);
+    assert_eq!(field(&reply, "proto"), Some(RespValue::Integer(2)));
+    assert_eq!(conn.protocol, 2);
+}
+
+#[tokio::test]
+async fn test_resp3_subscribers_receive_push_frames() {
+    let store = FerroStore::new();
+    let mut conn = ConnectionContext::new();
+
+    let reply = handle_command(
+        command(&["SUBSCRIBE", "a", "b"]),
+        &store,
+        None,
+        Some(&mut conn),
+    )
+    .await;
+    // RESP2 keeps sending arrays, and refuses other commands
+    assert_eq!(
+        reply.encode(),
+        "*2\r\n*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n"
+    );
+    let reply = handle_command(command(&["GET", "k"]), &store, None, Some(&mut conn)).await;
+    assert!(matches!(reply, RespValue::Error(e) if e.contains("allowed in this context")));
+
+    // RESP3 sends one push frame per channel, and runs any command
+    conn.protocol = 3;
+    assert_eq!(
+        reply_for(&store, &mut conn, &["UNSUBSCRIBE", "a", "b"]).await,
+        ">3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:1\r\n>3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:0\r\n"
+    );
+    reply_for(&store, &mut conn, &["SUBSCRIBE", "a"]).await;
+    assert_eq!(reply_for(&store, &mut conn, &["GET", "k"]).await, "_\r\n");
+
+    async fn reply_for(store: &FerroStore, conn: &mut ConnectionContext, args: &[&str]) -> String {
+        handle_command(command(args), store, None, Some(&mut *conn))
+            .await
+            .encode_for(conn.protocol)
+    }
+}
+
+#[tokio::test]
+async fn test_subscription_receivers_follow_changes() {
+    use FerroDB::pubsub::SubscriptionReceivers;
+    let store = FerroStore::new();
+    let hub = store.pubsub().clone();
+    let mut conn = ConnectionContext::new();
+    let mut receivers = SubscriptionReceivers::new();
+
+    for args in [&["SUBSCRIBE", "a", "b", "a"][..], &["UNSUBSCRIBE", "a"]] {
+        handle_command(command(args), &store, None, Some(&mut conn)).await;
+        for change in conn.subscriptions.take_changes() {
+            receivers.apply(change);
+        }
+    }
+    assert_eq!(
+        conn.subscriptions.channels(ChannelKind::Global),
+        vec!["b".to_string()]
+    );
+    assert!(conn.subscriptions.take_changes().is_empty());
+
+    // Messages arrive from whichever channel has one
+    hub.publish("a", "dropped".to_string());
+    hub.publish("b", "kept".to_string());
+    let message = tokio::time::timeout(std::time::Duration::from_secs(1), receivers.recv())
+        .await
+        .unwrap();
+    assert_eq!(
+        (message.channel.as_str(), message.message.as_str()),
+        ("b", "kept")
+    );
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(50), receivers.recv())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_subscription_receivers_count_dropped_messages() {
+    use FerroDB::pubsub::SubscriptionReceivers;
+    let store = FerroStore::new();
+    let hub = store.pubsub().clone();
+    let mut conn = ConnectionContext::new();
+    let mut receivers = SubscriptionReceivers::new();
+    handle_command(
+        command(&["SUBSCRIBE", "fast"]),
+        &store,
+        None,
+        Some(&mut conn),
+    )
+    .await;
+    for change in conn.subscriptions.take_changes() {
+        receivers.apply(change);
+    }
+
+    // A channel buffers a bounded number of messages for a subscriber that
+    // isn't reading; the oldest are dropped and counted
+    for i in 0..1000 {
+        hub.publish("fast", i.to_string());
+    }
+    let message = receivers.recv().await;
+    let dropped = receivers.take_dropped();
+    assert!(dropped > 0);
+    assert_eq!(message.message, dropped.to_string());
+    assert_eq!(receivers.take_dropped(), 0);
+}
+
+#[tokio::test]
+async fn test_shard_channels() {
+    use FerroDB::pubsub::SubscriptionReceivers;
+    let store = FerroStore::new();
+    let mut conn = ConnectionContext::new();
+    let mut receivers = SubscriptionReceivers::new();
+
+    // Shard channels are counted apart from the others
+    handle_command(
+        command(&["SUBSCRIBE", "news"]),
+        &store,
+        None,
+        Some(&mut conn),
+    )
+    .await;
+    let reply = handle_command(
+        command(&["SSUBSCRIBE", "news", "orders"]),
+        &store,
+        None,
+        Some(&mut conn),
+    )
+    .await;
+    assert_eq!(
+        reply.encode(),
+        "*2\r\n*3\r\n$10\r\nssubscribe\r\n$4\r\nnews\r\n:1\r\n*3\r\n$10\r\nssubscribe\r\n$6\r\norders\r\n:2\r\n"
+    );
+    for change in conn.subscriptions.take_changes() {
+        receivers.apply(change);
+    }
+
+    // and are a separate namespace: each publish reaches its own subscribers
+    let publish = |name: &str, message: &str| command(&[name, "news", message]);
+    let reply = handle_command(publish("SPUBLISH", "sharded"), &store, None, None).await;
+    assert_eq!(reply, RespValue::Integer(1));
+    let message = tokio::time::timeout(std::time::Duration::from_secs(1), receivers.recv())
+        .await
+        .unwrap();
+    assert_eq!(
+        (message.kind.message_kind(), message.message.as_str()),
+        ("smessage", "sharded")
+    );
+    let reply = handle_command(publish("PUBLISH", "global"), &store, None, None).await;
+    assert_eq!(reply, RespValue::Integer(1));
+    let message = tokio::time::timeout(std::time::Duration::from_secs(1), receivers.recv())
+        .await
+        .unwrap();
+    assert_eq!(
+        (message.kind.message_kind(), message.message.as_str()),
+        ("message", "global")
+    );
+    let reply = handle_command(command(&["SPUBLISH", "nobody", "x"]), &store, None, None).await;
+    assert_eq!(reply, RespValue::Integer(0));
+
+    // SUNSUBSCRIBE leaves the other subscriptions alone, and RESP2
+    // subscribers may run it
+    let reply = handle_command(command(&["SUNSUBSCRIBE"]), &store, None, Some(&mut conn)).await;
+    assert!(matches!(reply, RespValue::Array(replies) if replies.len() == 2));
+    assert_eq!(conn.subscriptions.count(ChannelKind::Shard), 0);
+    assert_eq!(
+        conn.subscriptions.channels(ChannelKind::Global),
+        vec!["news".to_string()]
+    );
+    let reply = handle_command(command(&["SUNSUBSCRIBE"]), &store, None, Some(&mut conn)).await;
+    assert_eq!(
+        reply,
+        RespValue::Push(vec![
+            RespValue::BulkString("sunsubscribe".into()),
+            RespValue::Null,
+            RespValue::Integer(0),
+        ])
+    );
+
+    // Not inside a transaction
+    let mut conn = ConnectionContext::new();
+    handle_command(command(&["MULTI"]), &store, None, Some(&mut conn)).await;
+    let reply = handle_command(command(&["SSUBSCRIBE", "a"]), &store, None, Some(&mut conn)).await;
+    assert!(matches!(reply, RespValue::Error(e) if e.contains("not allowed inside a transaction")));
+}
+
 #[tokio::test]
 async fn test_case_insensitive_commands() {
     let store = FerroStore::new();
@@ -24,14 +2903,14 @@ async fn test_case_insensitive_commands() {
     // SET in lowercase
     let set_input = "*3\r\n$3\r\nset\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
     let parsed = parse_resp(set_input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(response, RespValue::SimpleString("OK".to_string()));
 
     // GET in mixed case
     let get_input = "*2\r\n$3\r\nGeT\r\n$3\r\nkey\r\n";
     let parsed = parse_resp(get_input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
-    assert_eq!(response, RespValue::BulkString("value".to_string()));
+    let response = handle_command(parsed, &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("value".into()));
 }
 #[tokio::test]
 async fn test_del_command() {
@@ -43,7 +2922,7 @@ async fn test_del_command() {
     // DEL returns number of keys removed
     let input = "*2\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(response, RespValue::Integer(1));
 
     // Key should be gone
@@ -59,7 +2938,7 @@ async fn test_del_single_key() {
     // DEL mykey
     let input = "*2\r\n$3\r\nDEL\r\n$5\r\nmykey\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     // Should return integer 1 (one key deleted)
     assert_eq!(response, RespValue::Integer(1));
@@ -74,7 +2953,7 @@ async fn test_del_nonexistent_key() {
     // DEL nonexistent
     let input = "*2\r\n$3\r\nDEL\r\n$11\r\nnonexistent\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     // Should return integer 0 (no keys deleted)
     assert_eq!(response, RespValue::Integer(0));
@@ -91,7 +2970,7 @@ async fn test_del_multiple_keys() {
     // DEL key1 key2 key3 (key3 doesn't exist)
     let input = "*4\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     // Should return 2 (two keys deleted)
     assert_eq!(response, RespValue::Integer(2));
@@ -105,7 +2984,7 @@ async fn test_exists_single_key() {
     // EXISTS mykey
     let input = "*2\r\n$6\r\nEXISTS\r\n$5\r\nmykey\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     assert_eq!(response, RespValue::Integer(1));
 }
@@ -117,7 +2996,7 @@ async fn test_exists_nonexistent_key() {
     // EXISTS nonexistent
     let input = "*2\r\n$6\r\nEXISTS\r\n$11\r\nnonexistent\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     assert_eq!(response, RespValue::Integer(0));
 }
@@ -131,7 +3010,7 @@ async fn test_exists_multiple_keys() {
     // EXISTS key1 key2 key3 (key3 doesn't exist)
     let input = "*4\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     // Should return 2 (two keys exist)
     assert_eq!(response, RespValue::Integer(2));
@@ -148,19 +3027,49 @@ async fn test_mget_multiple_keys() {
     // MGET key1 key2 key3
     let input = "*4\r\n$4\r\nMGET\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     // Should return array with: ["value1", "value2", null]
     assert_eq!(
         response,
         RespValue::Array(vec![
-            RespValue::BulkString("value1".to_string()),
-            RespValue::BulkString("value2".to_string()),
+            RespValue::BulkString("value1".into()),
+            RespValue::BulkString("value2".into()),
             RespValue::Null,
         ])
     );
 }
 
+#[tokio::test]
+async fn test_setnx_command() {
+    let store = FerroStore::new();
+
+    let input = "*3\r\n$5\r\nSETNX\r\n$4\r\nlock\r\n$2\r\nv1\r\n";
+    let parsed = parse_resp(input).unwrap();
+    let response = handle_command(parsed, &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(1));
+
+    let input = "*3\r\n$5\r\nSETNX\r\n$4\r\nlock\r\n$2\r\nv2\r\n";
+    let parsed = parse_resp(input).unwrap();
+    let response = handle_command(parsed, &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(0));
+    assert_eq!(store.get("lock"), Some("v1".to_string()));
+}
+
+#[tokio::test]
+async fn test_msetnx_command() {
+    let store = FerroStore::new();
+    store.set("key2".to_string(), "old".to_string());
+
+    // MSETNX key1 a key2 b -> key2 exists, nothing set
+    let input = "*5\r\n$6\r\nMSETNX\r\n$4\r\nkey1\r\n$1\r\na\r\n$4\r\nkey2\r\n$1\r\nb\r\n";
+    let parsed = parse_resp(input).unwrap();
+    let response = handle_command(parsed, &store, None, None).await;
+    assert_eq!(response, RespValue::Integer(0));
+    assert_eq!(store.get("key1"), None);
+    assert_eq!(store.get("key2"), Some("old".to_string()));
+}
+
 #[tokio::test]
 async fn test_mget_all_nonexistent() {
     let store = FerroStore::new();
@@ -168,7 +3077,7 @@ async fn test_mget_all_nonexistent() {
     // MGET key1 key2
     let input = "*3\r\n$4\r\nMGET\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     // Should return array of nulls
     assert_eq!(
@@ -184,11 +3093,11 @@ async fn test_mget_no_arguments() {
     // MGET with no keys
     let input = "*1\r\n$4\r\nMGET\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     // Should return error
     match response {
-        RespValue::SimpleString(msg) => assert!(msg.contains("ERR")),
+        RespValue::Error(msg) => assert!(msg.contains("ERR")),
         _ => panic!("Expected error message"),
     }
 }
@@ -200,7 +3109,7 @@ async fn test_mset_multiple_pairs() {
     // MSET key1 value1 key2 value2
     let input = "*5\r\n$4\r\nMSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n$4\r\nkey2\r\n$6\r\nvalue2\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     assert_eq!(response, RespValue::SimpleString("OK".to_string()));
 
@@ -218,7 +3127,7 @@ async fn test_mset_overwrites_existing() {
     // MSET key1 new_value
     let input = "*3\r\n$4\r\nMSET\r\n$4\r\nkey1\r\n$9\r\nnew_value\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     assert_eq!(response, RespValue::SimpleString("OK".to_string()));
     assert_eq!(store.get("key1"), Some("new_value".to_string()));
@@ -231,11 +3140,11 @@ async fn test_mset_odd_arguments() {
     // MSET key1 value1 key2 (missing value for key2)
     let input = "*4\r\n$4\r\nMSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n$4\r\nkey2\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     // Should return error
     match response {
-        RespValue::SimpleString(msg) => {
+        RespValue::Error(msg) => {
             assert!(msg.contains("ERR") || msg.contains("Incorrect"))
         }
         _ => panic!("Expected error message"),
@@ -249,10 +3158,10 @@ async fn test_mset_no_arguments() {
     // MSET with no pairs
     let input = "*1\r\n$4\r\nMSET\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     match response {
-        RespValue::SimpleString(msg) => assert!(msg.contains("Wrong") || msg.contains("ERR")),
+        RespValue::Error(msg) => assert!(msg.contains("Wrong") || msg.contains("ERR")),
         _ => panic!("Expected error message"),
     }
 }
@@ -263,14 +3172,14 @@ async fn test_lpush_lpop_flow() {
     // LPUSH mylist "world" "hello"
     let input = "*4\r\n$5\r\nLPUSH\r\n$6\r\nmylist\r\n$5\r\nworld\r\n$5\r\nhello\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(response, RespValue::Integer(2));
 
     // LPOP mylist
     let input = "*2\r\n$4\r\nLPOP\r\n$6\r\nmylist\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
-    assert_eq!(response, RespValue::BulkString("hello".to_string()));
+    let response = handle_command(parsed, &store, None, None).await;
+    assert_eq!(response, RespValue::BulkString("hello".into()));
 }
 
 #[tokio::test]
@@ -280,18 +3189,18 @@ async fn test_rpush_rpop_flow() {
     // RPUSH mylist "a" "b" "c"
     let input = "*5\r\n$5\r\nRPUSH\r\n$6\r\nmylist\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(response, RespValue::Integer(3));
 
     // RPOP mylist 2
     let input = "*3\r\n$4\r\nRPOP\r\n$6\r\nmylist\r\n$1\r\n2\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(
         response,
         RespValue::Array(vec![
-            RespValue::BulkString("c".to_string()),
-            RespValue::BulkString("b".to_string()),
+            RespValue::BulkString("c".into()),
+            RespValue::BulkString("b".into()),
         ])
     );
 }
@@ -317,13 +3226,13 @@ async fn test_lrange_command() {
     // LRANGE mylist 0 2
     let input = "*4\r\n$6\r\nLRANGE\r\n$6\r\nmylist\r\n$1\r\n0\r\n$1\r\n2\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(
         response,
         RespValue::Array(vec![
-            RespValue::BulkString("a".to_string()),
-            RespValue::BulkString("b".to_string()),
-            RespValue::BulkString("c".to_string()),
+            RespValue::BulkString("a".into()),
+            RespValue::BulkString("b".into()),
+            RespValue::BulkString("c".into()),
         ])
     );
 }
@@ -343,7 +3252,7 @@ async fn test_llen_command() {
     // LLEN mylist
     let input = "*2\r\n$4\r\nLLEN\r\n$6\r\nmylist\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(response, RespValue::Integer(3));
 }
 
@@ -357,9 +3266,9 @@ async fn test_lpush_on_string_key() {
     // LPUSH mykey "item" - should fail
     let input = "*3\r\n$5\r\nLPUSH\r\n$5\r\nmykey\r\n$4\r\nitem\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
-    if let RespValue::SimpleString(msg) = response {
+    if let RespValue::Error(msg) = response {
         assert!(msg.contains("WRONGTYPE"));
     } else {
         panic!("Expected error message");
@@ -371,17 +3280,17 @@ async fn test_sadd_smembers() {
 
     let input = "*4\r\n$4\r\nSADD\r\n$5\r\nmyset\r\n$5\r\napple\r\n$6\r\nbanana\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(response, RespValue::Integer(2));
 
     let input = "*2\r\n$8\r\nSMEMBERS\r\n$5\r\nmyset\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
-    if let RespValue::Array(members) = response {
+    if let RespValue::Set(members) = response {
         assert_eq!(members.len(), 2);
     } else {
-        panic!("Expected array response");
+        panic!("Expected set response");
     }
 }
 
@@ -404,12 +3313,12 @@ async fn test_sinter() {
 
     let input = "*3\r\n$6\r\nSINTER\r\n$4\r\nset1\r\n$4\r\nset2\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
-    if let RespValue::Array(members) = response {
+    if let RespValue::Set(members) = response {
         assert_eq!(members.len(), 2);
     } else {
-        panic!("Expected array response");
+        panic!("Expected set response");
     }
 }
 
@@ -421,18 +3330,18 @@ async fn test_zadd_zrange() {
 
     let input = "*6\r\n$4\r\nZADD\r\n$11\r\nleaderboard\r\n$3\r\n100\r\n$5\r\nalice\r\n$3\r\n200\r\n$3\r\nbob\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(response, RespValue::Integer(2));
 
     let input = "*4\r\n$6\r\nZRANGE\r\n$11\r\nleaderboard\r\n$1\r\n0\r\n$2\r\n-1\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
 
     assert_eq!(
         response,
         RespValue::Array(vec![
-            RespValue::BulkString("alice".to_string()),
-            RespValue::BulkString("bob".to_string()),
+            RespValue::BulkString("alice".into()),
+            RespValue::BulkString("bob".into()),
         ])
     );
 }
@@ -454,11 +3363,11 @@ async fn test_zscore_zrank() {
 
     let input = "*3\r\n$6\r\nZSCORE\r\n$11\r\nleaderboard\r\n$5\r\nalice\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
-    assert_eq!(response, RespValue::BulkString("100".to_string()));
+    let response = handle_command(parsed, &store, None, None).await;
+    assert_eq!(response, RespValue::Double(100.0));
 
     let input = "*3\r\n$5\r\nZRANK\r\n$11\r\nleaderboard\r\n$3\r\nbob\r\n";
     let parsed = parse_resp(input).unwrap();
-    let response = handle_command(parsed, &store, None, None, None).await;
+    let response = handle_command(parsed, &store, None, None).await;
     assert_eq!(response, RespValue::Integer(2));
 }
@@ -0,0 +1,384 @@
+use FerroDB::config::{
+    FileChanges, IoBackend, LogLevel, OutputBufferLimit, RdbCompression, SaveRule, ServerConfig,
+};
+use std::fs;
+
+#[test]
+fn test_load_config_file() {
+    let path = "/tmp/test_FerroDB_load.conf";
+    fs::write(
+        path,
+        "# FerroDB test config\n\
+         port 7000\n\
+         bind 0.0.0.0 -::1\n\
+         \n\
+         save 900 1\n\
+         save 300 10\n\
+         appendonly no\n\
+         requirepass \"open sesame\"\n\
+         loglevel debug\n",
+    )
+    .unwrap();
+
+    let config = ServerConfig::new();
+    config.load_file(path).unwrap();
+    let values = config.read().clone();
+    assert_eq!(values.port, 7000);
+    assert_eq!(values.bind, vec!["0.0.0.0", "-::1"]);
+    assert_eq!(
+        values.save,
+        vec![
+            SaveRule {
+                seconds: 900,
+                changes: 1
+            },
+            SaveRule {
+                seconds: 300,
+                changes: 10
+            },
+        ]
+    );
+    assert!(!values.appendonly);
+    assert_eq!(values.requirepass, "open sesame");
+    assert_eq!(values.loglevel, LogLevel::Debug);
+    // Untouched parameters keep their defaults
+    assert_eq!(values.dbfilename, "dump.rdb");
+
+    fs::write(path, "port 7000\nsave \"\"\n").unwrap();
+    config.load_file(path).unwrap();
+    assert!(config.read().save.is_empty());
+
+    fs::write(path, "port 7000\nnosuchdirective yes\n").unwrap();
+    let err = config.load_file(path).unwrap_err();
+    assert!(err.contains("line 2"), "{}", err);
+    fs::write(path, "port not-a-port\n").unwrap();
+    assert!(config.load_file(path).is_err());
+
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_rewrite_config_file() {
+    let config = ServerConfig::new();
+    assert!(config.rewrite().is_err());
+
+    let path = "/tmp/test_FerroDB_rewrite.conf";
+    fs::write(
+        path,
+        "# keep this comment\nport 7001\nbind 10.0.0.1 ::1\nsave 900 1\nsave 300 10\nhz 10\n",
+    )
+    .unwrap();
+    config.load_file(path).unwrap();
+    config
+        .set(&[
+            ("hz".to_string(), "25".to_string()),
+            ("save".to_string(), "60 5".to_string()),
+            ("requirepass".to_string(), "s3cret".to_string()),
+        ])
+        .unwrap();
+    config.rewrite().unwrap();
+
+    assert_eq!(
+        fs::read_to_string(path).unwrap(),
+        "# keep this comment\nport 7001\nbind 10.0.0.1 ::1\nsave 60 5\nhz 25\nrequirepass s3cret\n"
+    );
+    let reloaded = ServerConfig::new();
+    reloaded.load_file(path).unwrap();
+    assert_eq!(*reloaded.read(), *config.read());
+
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_apply_overrides() {
+    let path = "/tmp/test_FerroDB_overrides.conf";
+    fs::write(path, "port 7000\ndbfilename from-file.rdb\n").unwrap();
+
+    let config = ServerConfig::new();
+    config.load_file(path).unwrap();
+    // Command-line flags win over the file, immutable parameters included
+    config
+        .apply_overrides(&[
+            ("port", "7001".to_string()),
+            ("dir", "/tmp".to_string()),
+            ("appendonly", "no".to_string()),
+            ("daemonize", "yes".to_string()),
+        ])
+        .unwrap();
+    let values = config.read().clone();
+    assert_eq!(values.port, 7001);
+    assert_eq!(values.dir, "/tmp");
+    assert!(!values.appendonly);
+    assert!(values.daemonize);
+    assert_eq!(values.dbfilename, "from-file.rdb");
+
+    // A bad value leaves every parameter untouched
+    assert!(
+        config
+            .apply_overrides(&[
+                ("port", "7002".to_string()),
+                ("appendonly", "maybe".to_string())
+            ])
+            .is_err()
+    );
+    assert!(
+        config
+            .apply_overrides(&[("nosuch", "1".to_string())])
+            .is_err()
+    );
+    assert_eq!(config.read().port, 7001);
+
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_reload_changes() {
+    let config = ServerConfig::new();
+    assert!(config.reload_changes().is_err());
+
+    let path = "/tmp/test_FerroDB_reload.conf";
+    fs::write(path, "port 7000\nloglevel notice\nsave 60 1\nmaxmemory 0\n").unwrap();
+    config.load_file(path).unwrap();
+    assert_eq!(config.reload_changes().unwrap(), FileChanges::default());
+
+    fs::write(
+        path,
+        "port 7001\nloglevel debug\nsave 900 1\nsave 300 10\nmaxmemory 100mb\n\
+         latency-monitor-threshold 5\nrename-command DEBUG \"\"\n",
+    )
+    .unwrap();
+    let changes = config.reload_changes().unwrap();
+    assert_eq!(
+        changes.reloadable,
+        vec![
+            ("save".to_string(), "900 1 300 10".to_string()),
+            ("maxmemory".to_string(), "104857600".to_string()),
+            ("loglevel".to_string(), "debug".to_string()),
+            ("latency-monitor-threshold".to_string(), "5".to_string()),
+        ]
+    );
+    assert_eq!(changes.restart_needed, vec!["port", "rename-command"]);
+    // Only compared; applying the pairs is up to the caller
+    assert_eq!(config.read().port, 7000);
+    config.set(&changes.reloadable).unwrap();
+    let values = config.read().clone();
+    assert_eq!(values.loglevel, LogLevel::Debug);
+    assert_eq!(values.maxmemory, 100 * 1024 * 1024);
+    assert_eq!(values.save.len(), 2);
+
+    fs::write(path, "port 7000\nhz nope\n").unwrap();
+    assert!(config.reload_changes().is_err());
+
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_rename_command() {
+    let path = "/tmp/test_FerroDB_rename.conf";
+    fs::write(
+        path,
+        "rename-command CONFIG ferro-config-4d2a\nrename-command swapdb \"\"\n",
+    )
+    .unwrap();
+    let config = ServerConfig::new();
+    config.load_file(path).unwrap();
+    assert_eq!(
+        config.resolve_command("FERRO-CONFIG-4D2A"),
+        Some("CONFIG".to_string())
+    );
+    assert_eq!(config.resolve_command("CONFIG"), None);
+    assert_eq!(config.resolve_command("SWAPDB"), None);
+    assert_eq!(config.resolve_command("GET"), Some("GET".to_string()));
+
+    for bad in [
+        "rename-command NOSUCH x\n",
+        "rename-command GET SET\n",
+        "rename-command GET\n",
+    ] {
+        fs::write(path, bad).unwrap();
+        assert!(ServerConfig::new().load_file(path).is_err(), "{}", bad);
+    }
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_proto_max_bulk_len() {
+    let config = ServerConfig::new();
+    assert_eq!(config.read().proto_max_bulk_len, 512 * 1024 * 1024);
+    config
+        .set(&[("proto-max-bulk-len".to_string(), "2mb".to_string())])
+        .unwrap();
+    assert_eq!(config.read().proto_max_bulk_len, 2 * 1024 * 1024);
+    // Below 1mb ordinary values would no longer fit
+    assert!(
+        config
+            .set(&[("proto-max-bulk-len".to_string(), "1000".to_string())])
+            .is_err()
+    );
+    assert_eq!(config.read().proto_max_bulk_len, 2 * 1024 * 1024);
+}
+
+#[test]
+fn test_io_threads() {
+    let path = "/tmp/test_FerroDB_io_threads.conf";
+    fs::write(path, "io-threads 4\n").unwrap();
+    let config = ServerConfig::new();
+    assert_eq!(config.read().io_threads, 1);
+    config.load_file(path).unwrap();
+    assert_eq!(config.read().io_threads, 4);
+    // The listeners are bound once at startup
+    assert!(
+        config
+            .set(&[("io-threads".to_string(), "8".to_string())])
+            .is_err()
+    );
+
+    for bad in ["io-threads 0\n", "io-threads 129\n"] {
+        fs::write(path, bad).unwrap();
+        assert!(ServerConfig::new().load_file(path).is_err(), "{}", bad);
+    }
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_io_backend() {
+    let path = "/tmp/test_FerroDB_io_backend.conf";
+    fs::write(path, "io-backend IO-URING\n").unwrap();
+    let config = ServerConfig::new();
+    assert_eq!(config.read().io_backend, IoBackend::Tokio);
+    config.load_file(path).unwrap();
+    assert_eq!(config.read().io_backend, IoBackend::IoUring);
+    assert!(
+        config
+            .set(&[("io-backend".to_string(), "tokio".to_string())])
+            .is_err()
+    );
+
+    fs::write(path, "io-backend epoll\n").unwrap();
+    assert!(ServerConfig::new().load_file(path).is_err());
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_listpack_limits() {
+    let config = ServerConfig::new();
+    assert_eq!(config.read().list_max_listpack_size, -2);
+    assert_eq!(config.read().zset_max_listpack_entries, 128);
+    let set = |name: &str, value: &str| config.set(&[(name.to_string(), value.to_string())]);
+    set("list-max-listpack-size", "-5").unwrap();
+    set("list-max-listpack-size", "1000").unwrap();
+    assert_eq!(config.read().list_max_listpack_size, 1000);
+    set("set-max-listpack-value", "0").unwrap();
+    assert_eq!(config.read().set_max_listpack_value, 0);
+
+    for (name, bad) in [
+        ("list-max-listpack-size", "0"),
+        ("list-max-listpack-size", "-6"),
+        ("zset-max-listpack-entries", "-1"),
+        ("set-max-listpack-entries", "many"),
+    ] {
+        assert!(set(name, bad).is_err(), "{} {}", name, bad);
+    }
+    assert_eq!(config.read().list_max_listpack_size, 1000);
+}
+
+#[test]
+fn test_rdbcompression() {
+    let config = ServerConfig::new();
+    assert_eq!(config.read().rdbcompression, RdbCompression::Lz4);
+    let set = |value: &str| config.set(&[("rdbcompression".to_string(), value.to_string())]);
+    set("zstd").unwrap();
+    assert_eq!(
+        config.get_matching(&["rdbcompression".to_string()]),
+        vec![("rdbcompression", "zstd".to_string())]
+    );
+    set("no").unwrap();
+    assert_eq!(config.read().rdbcompression, RdbCompression::No);
+    set("yes").unwrap();
+    assert_eq!(config.read().rdbcompression, RdbCompression::Lz4);
+    assert!(set("gzip").is_err());
+}
+
+#[test]
+fn test_client_output_buffer_limit() {
+    let config = ServerConfig::new();
+    let get = || {
+        config.get_matching(&["client-output-buffer-limit".to_string()])[0]
+            .1
+            .clone()
+    };
+    assert_eq!(
+        get(),
+        "normal 0 0 0 replica 268435456 67108864 60 pubsub 33554432 8388608 60"
+    );
+
+    // Classes not given keep their limits
+    let set =
+        |value: &str| config.set(&[("client-output-buffer-limit".to_string(), value.to_string())]);
+    set("pubsub 1mb 256kb 10 slave 0 0 0").unwrap();
+    assert_eq!(get(), "normal 0 0 0 replica 0 0 0 pubsub 1048576 262144 10");
+    for bad in [
+        "pubsub 1mb 256kb",
+        "other 0 0 0",
+        "pubsub lots 0 0",
+        "pubsub 0 0 soon",
+    ] {
+        assert!(set(bad).is_err(), "{}", bad);
+    }
+
+    // redis.conf gives each class a line of its own
+    let path = "/tmp/test_FerroDB_output_limits.conf";
+    fs::write(
+        path,
+        "client-output-buffer-limit normal 0 0 0\n\
+         client-output-buffer-limit pubsub 64mb 16mb 90\n",
+    )
+    .unwrap();
+    let loaded = ServerConfig::new();
+    loaded.load_file(path).unwrap();
+    assert_eq!(
+        loaded.read().client_output_buffer_limit.class("pubsub"),
+        OutputBufferLimit {
+            hard: 64 * 1024 * 1024,
+            soft: 16 * 1024 * 1024,
+            soft_seconds: 90,
+        }
+    );
+    loaded.rewrite().unwrap();
+    let text = fs::read_to_string(path).unwrap();
+    fs::remove_file(path).ok();
+    assert!(text.contains("client-output-buffer-limit pubsub 67108864 16777216 90\n"));
+    assert!(text.contains("client-output-buffer-limit replica 268435456 67108864 60\n"));
+}
+
+#[test]
+fn test_output_buffer_limit_exceeded() {
+    let limit = OutputBufferLimit {
+        hard: 1000,
+        soft: 100,
+        soft_seconds: 60,
+    };
+    let mut soft_since = None;
+    assert!(!limit.exceeded(50, &mut soft_since));
+    // Over the soft limit, but not for long enough yet
+    assert!(!limit.exceeded(500, &mut soft_since));
+    assert!(soft_since.is_some());
+    assert!(limit.exceeded(1000, &mut soft_since));
+    // Dropping under the soft limit starts the clock over
+    assert!(!limit.exceeded(99, &mut soft_since));
+    assert!(soft_since.is_none());
+
+    // Without soft seconds, the soft limit is as good as a hard one
+    let limit = OutputBufferLimit {
+        hard: 0,
+        soft: 100,
+        soft_seconds: 0,
+    };
+    assert!(limit.exceeded(100, &mut None));
+    let unlimited = OutputBufferLimit {
+        hard: 0,
+        soft: 0,
+        soft_seconds: 0,
+    };
+    assert!(!unlimited.exceeded(usize::MAX as u64, &mut None));
+}
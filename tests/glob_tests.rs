@@ -0,0 +1,27 @@
+use FerroDB::glob::glob_match;
+
+#[test]
+fn test_glob_literal_and_wildcards() {
+    assert!(glob_match("hello", "hello"));
+    assert!(!glob_match("hello", "hell"));
+    assert!(glob_match("h*o", "hello"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("h?llo", "hallo"));
+    assert!(!glob_match("h?llo", "hllo"));
+}
+
+#[test]
+fn test_glob_character_classes() {
+    assert!(glob_match("h[ae]llo", "hello"));
+    assert!(!glob_match("h[ae]llo", "hillo"));
+    assert!(glob_match("h[^e]llo", "hallo"));
+    assert!(!glob_match("h[^e]llo", "hello"));
+    assert!(glob_match("key:[0-9]", "key:7"));
+    assert!(!glob_match("key:[0-9]", "key:x"));
+}
+
+#[test]
+fn test_glob_escape() {
+    assert!(glob_match("a\\*b", "a*b"));
+    assert!(!glob_match("a\\*b", "axb"));
+}
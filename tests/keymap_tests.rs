@@ -0,0 +1,73 @@
+use FerroDB::keymap::KeyMap;
+
+#[test]
+fn test_keymap_lookups() {
+    let mut map = KeyMap::new();
+    assert!(map.is_empty());
+    assert_eq!(map.insert("a".to_string(), 1), None);
+    assert_eq!(map.insert("b".to_string(), 2), None);
+    assert_eq!(map.insert("a".to_string(), 3), Some(1));
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.get("a"), Some(&3));
+    assert_eq!(map.get("missing"), None);
+
+    *map.get_mut("b").unwrap() += 10;
+    *map.get_or_insert_with("c", || 0) += 1;
+    *map.get_or_insert_with("c", || 0) += 1;
+    assert_eq!(map.get("b"), Some(&12));
+    assert_eq!(map.get("c"), Some(&2));
+
+    map.clear();
+    assert!(map.is_empty());
+    assert_eq!(map.get("a"), None);
+}
+
+#[test]
+fn test_keymap_positions() {
+    let mut map = KeyMap::new();
+    for i in 0..5 {
+        map.insert(format!("key{}", i), i);
+    }
+    // Entries keep the order they were added in
+    let keys: Vec<_> = (0..map.len())
+        .map(|i| map.get_index(i).unwrap().0.clone())
+        .collect();
+    assert_eq!(keys, ["key0", "key1", "key2", "key3", "key4"]);
+    assert_eq!(map.get_index(5), None);
+
+    // The last entry moves into a removed one's place
+    assert_eq!(map.remove("key1"), Some(1));
+    assert_eq!(map.remove("key1"), None);
+    assert_eq!(map.get_index(1), Some((&"key4".to_string(), &4)));
+    assert_eq!(map.get("key4"), Some(&4));
+    assert_eq!(map.remove("key3"), Some(3));
+    assert_eq!(map.len(), 3);
+    assert_eq!(map.get_index(2), Some((&"key2".to_string(), &2)));
+
+    // Removing the last entry moves nothing
+    assert_eq!(map.remove("key2"), Some(2));
+    let keys: Vec<_> = map.iter().map(|(key, _)| key.clone()).collect();
+    assert_eq!(keys, ["key0", "key4"]);
+    assert!(map.contains_key("key0"));
+    assert!(!map.contains_key("key2"));
+}
+
+#[test]
+fn test_keymap_grows() {
+    let mut map = KeyMap::new();
+    for i in 0..10_000 {
+        map.insert(i.to_string(), i);
+    }
+    for i in (0..10_000).step_by(2) {
+        assert_eq!(map.remove(&i.to_string()), Some(i));
+    }
+    assert_eq!(map.len(), 5_000);
+    for i in 0..10_000 {
+        let expected = (i % 2 == 1).then_some(i);
+        assert_eq!(map.get(&i.to_string()).copied(), expected);
+    }
+    for i in 0..map.len() {
+        let (key, value) = map.get_index(i).unwrap();
+        assert_eq!(key, &value.to_string());
+    }
+}
@@ -1,6 +1,8 @@
-use FerroDB::persistance::{load_rdb, save_rdb};
-use FerroDB::storage::FerroStore;
+use FerroDB::persistance::{bgsave, dump_value, load_rdb, restore_value, save_due, save_rdb};
+use FerroDB::storage::{DataType, FerroStore};
 use std::fs;
+use std::time::Duration;
+#[allow(clippy::single_component_path_imports)]
 use tokio;
 
 #[tokio::test]
@@ -92,6 +94,28 @@ async fn test_save_and_load_with_expiry() {
     fs::remove_file(path).ok();
 }
 
+#[tokio::test]
+async fn test_expiry_survives_downtime() {
+    let store = FerroStore::new();
+    store.set_with_expiry_ms("short".to_string(), "value".to_string(), 1500);
+    store.set_with_expiry("long".to_string(), "value".to_string(), 100);
+    let expire_time = store.pexpire_time("long").unwrap();
+
+    let path = "/tmp/test_FerroDB_downtime.rdb";
+    save_rdb(&store, path).await.unwrap();
+
+    // Expirations are absolute, so time spent "down" counts towards them
+    tokio::time::sleep(std::time::Duration::from_millis(2000)).await;
+    let new_store = FerroStore::new();
+    load_rdb(&new_store, path).await.unwrap();
+
+    assert_eq!(new_store.get("short"), None);
+    assert_eq!(new_store.dbsize(), 1);
+    assert_eq!(new_store.pexpire_time("long"), Some(expire_time));
+
+    fs::remove_file(path).ok();
+}
+
 #[tokio::test]
 async fn test_save_empty_database() {
     let store = FerroStore::new();
@@ -142,3 +166,448 @@ async fn test_mixed_data_types() {
 
     fs::remove_file(path).ok();
 }
+
+#[test]
+fn test_dump_restore_roundtrip() {
+    let store = FerroStore::new();
+    store
+        .zadd("z", vec![(1.5, "a".to_string()), (2.0, "b".to_string())])
+        .unwrap();
+
+    let payload = dump_value(&store.get_value("z").unwrap());
+    match restore_value(&payload).unwrap() {
+        DataType::SortedSet(zset) => {
+            assert_eq!(zset.len(), 2);
+            assert_eq!(zset.score("a"), Some(1.5));
+        }
+        other => panic!("Expected sorted set, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_restore_rejects_corrupt_payload() {
+    let mut payload = dump_value(&DataType::String("hello".to_string().into()));
+    payload[3] ^= 0xff;
+    assert!(restore_value(&payload).is_err());
+    assert!(restore_value(&[]).is_err());
+}
+
+#[tokio::test]
+async fn test_save_resets_dirty_counter() {
+    let store = FerroStore::new();
+    store.set("a".to_string(), "1".to_string());
+    store.set("b".to_string(), "2".to_string());
+    store.delete("a");
+    store.delete("missing");
+    assert_eq!(store.dirty(), 3);
+
+    let path = "/tmp/test_FerroDB_dirty.rdb";
+    save_rdb(&store, path).await.unwrap();
+    assert_eq!(store.dirty(), 0);
+    assert!(store.last_save() > 0);
+
+    fs::remove_file(path).ok();
+}
+
+fn set_save_rules(store: &FerroStore, rules: &str) {
+    store
+        .config()
+        .set(&[("save".to_string(), rules.to_string())])
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_save_rules_skip_clean_dataset() {
+    let store = FerroStore::new();
+    set_save_rules(&store, "3600 1 300 100 60 10000");
+    let later = store.last_save() + 7200;
+    assert!(!save_due(&store, later));
+
+    store.set("a".to_string(), "1".to_string());
+    assert!(save_due(&store, later));
+
+    let path = "/tmp/test_FerroDB_save_rules_clean.rdb";
+    save_rdb(&store, path).await.unwrap();
+    assert!(!save_due(&store, store.last_save() + 7200));
+
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_save_rules_save_sooner_under_write_load() {
+    let store = FerroStore::new();
+    set_save_rules(&store, "3600 1 300 100 60 10000");
+    let saved = store.last_save();
+
+    store.set("a".to_string(), "1".to_string());
+    assert!(!save_due(&store, saved + 61));
+    assert!(!save_due(&store, saved + 301));
+    assert!(save_due(&store, saved + 3600));
+
+    for i in 0..99 {
+        store.set(format!("key{}", i), "v".to_string());
+    }
+    assert!(!save_due(&store, saved + 61));
+    assert!(save_due(&store, saved + 300));
+
+    for i in 0..9900 {
+        store.set(format!("key{}", i), "v".to_string());
+    }
+    assert!(!save_due(&store, saved + 59));
+    assert!(save_due(&store, saved + 60));
+
+    set_save_rules(&store, "");
+    assert!(!save_due(&store, saved + 3600));
+}
+
+#[tokio::test]
+async fn test_save_status() {
+    let store = FerroStore::new();
+    store.set("a".to_string(), "1".to_string());
+    let path = "/tmp/test_FerroDB_status.rdb";
+
+    // Only one snapshot is written at a time
+    let saving = store.start_save().unwrap();
+    assert!(store.save_in_progress());
+    assert!(save_rdb(&store, path).await.is_err());
+    assert!(bgsave(&store, path).is_err());
+    drop(saving);
+    assert!(!store.save_in_progress());
+
+    // A failed save is remembered until the next one succeeds
+    assert!(save_rdb(&store, "/nonexistent/dump.rdb").await.is_err());
+    assert!(store.last_save_error().is_some());
+    assert_eq!(store.dirty(), 1);
+
+    bgsave(&store, path).unwrap();
+    while store.save_in_progress() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(store.last_save_error(), None);
+    assert_eq!(store.dirty(), 0);
+
+    fs::remove_file(path).ok();
+}
+
+#[tokio::test]
+async fn test_save_and_load_multiple_databases() {
+    let store = FerroStore::new();
+    store.set("shared".to_string(), "db0".to_string());
+    let db7 = store.database(7).unwrap();
+    db7.set("shared".to_string(), "db7".to_string());
+    db7.set("only7".to_string(), "x".to_string());
+
+    let path = "/tmp/test_FerroDB_databases.rdb";
+    save_rdb(&store, path).await.unwrap();
+
+    let new_store = FerroStore::new();
+    load_rdb(&new_store, path).await.unwrap();
+    assert_eq!(new_store.get("shared"), Some("db0".to_string()));
+    assert_eq!(new_store.dbsize(), 1);
+    let db7 = new_store.database(7).unwrap();
+    assert_eq!(db7.get("shared"), Some("db7".to_string()));
+    assert_eq!(db7.dbsize(), 2);
+
+    fs::remove_file(path).ok();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_save_while_keys_are_deleted() {
+    let store = FerroStore::new();
+    for i in 0..20_000 {
+        store.set(format!("key{}", i), format!("value{}", i));
+    }
+
+    // Keys deleted while the save runs are saved as they were when it
+    // started, and the file's key count still matches what was written
+    let deleter = store.clone();
+    let deleting = std::thread::spawn(move || {
+        for i in (0..20_000).step_by(2) {
+            deleter.delete(&format!("key{}", i));
+        }
+    });
+    let path = "/tmp/test_FerroDB_streaming.rdb";
+    save_rdb(&store, path).await.unwrap();
+    deleting.join().unwrap();
+
+    let new_store = FerroStore::new();
+    load_rdb(&new_store, path).await.unwrap();
+    assert!(new_store.dbsize() >= 10_000);
+    for i in (1..20_000).step_by(2) {
+        assert_eq!(
+            new_store.get(&format!("key{}", i)),
+            Some(format!("value{}", i))
+        );
+    }
+
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn test_snapshot_sees_deletions_and_updates() {
+    let store = FerroStore::new();
+    store.set("kept".to_string(), "old".to_string());
+    store.set("deleted".to_string(), "v".to_string());
+    let snapshot = store.snapshot(store.start_save().unwrap());
+    store.set("kept".to_string(), "new".to_string());
+    store.delete("deleted");
+    store.set("created".to_string(), "v".to_string());
+
+    // Values are read as they were when the snapshot was taken
+    let db = &snapshot.databases()[0];
+    assert_eq!(db.len(), 2);
+    let mut entries: Vec<_> = (0..db.len())
+        .filter_map(|i| db.with_entry(i, |key, data, _| (key.to_string(), string_value(data))))
+        .collect();
+    entries.sort();
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    assert_eq!(entries, [("deleted", "v"), ("kept", "old")]);
+
+    // No other snapshot is taken until it is done with
+    assert!(store.start_save().is_none());
+    drop(snapshot);
+    assert!(!store.save_in_progress());
+}
+
+#[cfg(feature = "dashmap")]
+#[test]
+fn test_dashmap_snapshot_while_writing() {
+    let store = FerroStore::new();
+    for i in 0..1000 {
+        store.set(format!("key{}", i), "old".to_string());
+    }
+    let snapshot = store.snapshot(store.start_save().unwrap());
+
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for i in (t..1000).step_by(4) {
+                    if i % 2 == 0 {
+                        store.set(format!("key{}", i), "new".to_string());
+                    } else {
+                        store.delete(&format!("key{}", i));
+                    }
+                    store.set(format!("created{}", i), "new".to_string());
+                }
+            })
+        })
+        .collect();
+    // Read while the keys are being changed, and after
+    let db = &snapshot.databases()[0];
+    let mut values: Vec<_> = (0..db.len())
+        .filter_map(|i| db.with_entry(i, |key, data, _| (key.to_string(), string_value(data))))
+        .collect();
+    for thread in writers {
+        thread.join().unwrap();
+    }
+
+    // Every key as it was when the snapshot was taken
+    assert_eq!(values.len(), 1000);
+    values.sort();
+    values.dedup();
+    assert_eq!(values.len(), 1000);
+    assert!(
+        values
+            .iter()
+            .all(|(key, value)| key.starts_with("key") && value == "old")
+    );
+    drop(snapshot);
+    assert_eq!(store.dbsize(), 1500);
+}
+
+#[tokio::test]
+async fn test_bgsave_is_point_in_time() {
+    use FerroDB::storage::ListEnd;
+
+    let store = FerroStore::new();
+    store.set("moved".to_string(), "a".to_string());
+    store.set("changed".to_string(), "old".to_string());
+    store.set("deleted".to_string(), "d".to_string());
+    store
+        .rpush("list", vec!["x".to_string(), "y".to_string()])
+        .unwrap();
+    store.sadd("set", vec!["m".to_string()]).unwrap();
+    store
+        .database(1)
+        .unwrap()
+        .set("in1".to_string(), "b".to_string());
+
+    // The dataset is captured before BGSAVE returns; the background write
+    // only runs once this test yields
+    let path = "/tmp/test_FerroDB_point_in_time.rdb";
+    bgsave(&store, path).unwrap();
+    assert!(store.move_key("moved", 1).unwrap());
+    store.set("changed".to_string(), "new".to_string());
+    store.delete("deleted");
+    store.set("created".to_string(), "c".to_string());
+    store
+        .lmove("list", "other", ListEnd::Left, ListEnd::Left)
+        .unwrap();
+    assert!(store.smove("set", "other_set", "m").unwrap());
+    store.swap_databases(0, 1).unwrap();
+    store.flush_all();
+    while store.save_in_progress() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(store.last_save_error(), None);
+
+    let new_store = FerroStore::new();
+    load_rdb(&new_store, path).await.unwrap();
+    assert_eq!(new_store.dbsize(), 5);
+    assert_eq!(new_store.get("moved"), Some("a".to_string()));
+    assert_eq!(new_store.get("changed"), Some("old".to_string()));
+    assert_eq!(new_store.get("deleted"), Some("d".to_string()));
+    assert_eq!(new_store.get("created"), None);
+    assert_eq!(new_store.lrange("list", 0, -1).unwrap(), ["x", "y"]);
+    assert_eq!(new_store.smembers("set").unwrap(), ["m"]);
+    let db1 = new_store.database(1).unwrap();
+    assert_eq!(db1.dbsize(), 1);
+    assert_eq!(db1.get("in1"), Some("b".to_string()));
+
+    fs::remove_file(path).ok();
+}
+
+fn string_value(data: &DataType) -> String {
+    match data {
+        DataType::String(value) => value.to_string(),
+        _ => panic!("not a string"),
+    }
+}
+
+#[tokio::test]
+async fn test_load_version_1_file() {
+    // Header, one key "k" holding the string "v", no expiry
+    let mut bytes = b"FERRODB\0".to_vec();
+    bytes.push(1);
+    bytes.extend_from_slice(&1u64.to_be_bytes());
+    bytes.extend_from_slice(&1u64.to_be_bytes());
+    bytes.push(b'k');
+    bytes.push(0);
+    bytes.extend_from_slice(&1u64.to_be_bytes());
+    bytes.push(b'v');
+    bytes.push(0);
+
+    let path = "/tmp/test_FerroDB_v1.rdb";
+    fs::write(path, bytes).unwrap();
+    let store = FerroStore::new();
+    load_rdb(&store, path).await.unwrap();
+    assert_eq!(store.get("k"), Some("v".to_string()));
+
+    fs::remove_file(path).ok();
+}
+
+#[tokio::test]
+async fn test_load_rejects_corrupt_file() {
+    let store = FerroStore::new();
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_string());
+    }
+    let path = "/tmp/test_FerroDB_corrupt.rdb";
+    save_rdb(&store, path).await.unwrap();
+    let bytes = fs::read(path).unwrap();
+
+    // A flipped byte or a truncated file is refused before anything loads
+    let mut flipped = bytes.clone();
+    flipped[bytes.len() / 2] ^= 0xff;
+    for damaged in [flipped, bytes[..bytes.len() - 100].to_vec()] {
+        fs::write(path, damaged).unwrap();
+        let store = FerroStore::new();
+        let err = load_rdb(&store, path).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(store.dbsize(), 0);
+    }
+
+    fs::write(path, bytes).unwrap();
+    let store = FerroStore::new();
+    load_rdb(&store, path).await.unwrap();
+    assert_eq!(store.dbsize(), 100);
+
+    fs::remove_file(path).ok();
+}
+
+#[tokio::test]
+async fn test_save_and_load_compressed() {
+    let store = FerroStore::new();
+    let json = r#"{"user":"alice","roles":["admin","dev"],"active":true}"#.repeat(20);
+    for i in 0..50 {
+        store.set(format!("json{}", i), json.clone());
+        store.set(format!("small{}", i), i.to_string());
+    }
+    store.rpush("list", vec![json.clone(); 10]).unwrap();
+
+    let path = "/tmp/test_FerroDB_compressed.rdb";
+    let mut sizes = Vec::new();
+    for codec in ["no", "lz4", "zstd"] {
+        store
+            .config()
+            .set(&[("rdbcompression".to_string(), codec.to_string())])
+            .unwrap();
+        save_rdb(&store, path).await.unwrap();
+        sizes.push(fs::metadata(path).unwrap().len());
+
+        // The file records its codec, whatever the setting when loading
+        store
+            .config()
+            .set(&[("rdbcompression".to_string(), "no".to_string())])
+            .unwrap();
+        let loaded = FerroStore::new();
+        load_rdb(&loaded, path).await.unwrap();
+        assert_eq!(loaded.dbsize(), 101);
+        assert_eq!(loaded.get("json7"), Some(json.clone()));
+        assert_eq!(loaded.get("small7"), Some("7".to_string()));
+        assert_eq!(
+            loaded.lrange("list", 0, -1).unwrap(),
+            vec![json.clone(); 10]
+        );
+    }
+    assert!(sizes[1] * 5 < sizes[0], "{:?}", sizes);
+    assert!(sizes[2] * 5 < sizes[0], "{:?}", sizes);
+
+    fs::remove_file(path).ok();
+}
+
+#[tokio::test]
+async fn test_check_rdb() {
+    let store = FerroStore::new();
+    store.set("a".to_string(), "1".to_string());
+    store.set_with_expiry("b".to_string(), "2".to_string(), 100);
+    store.rpush("list", vec!["x".to_string()]).unwrap();
+    let path = "/tmp/test_FerroDB_check.rdb";
+    save_rdb(&store, path).await.unwrap();
+
+    let check = || {
+        std::process::Command::new(env!("CARGO_BIN_EXE_ferrodb-check-rdb"))
+            .arg(path)
+            .output()
+            .unwrap()
+    };
+    let output = check();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("db0: 3 keys"), "{}", stdout);
+    assert!(stdout.contains("string   2"), "{}", stdout);
+    assert!(
+        stdout.contains("with a TTL: 1 (0 already expired)"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("RDB looks OK"), "{}", stdout);
+
+    let mut bytes = fs::read(path).unwrap();
+    bytes.truncate(bytes.len() - 3);
+    fs::write(path, bytes).unwrap();
+    let output = check();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success());
+    assert!(
+        stdout.contains("RDB ERROR: RDB checksum mismatch"),
+        "{}",
+        stdout
+    );
+
+    fs::remove_file(path).ok();
+}
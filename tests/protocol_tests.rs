@@ -1,4 +1,6 @@
+use FerroDB::commands::handle_command;
 use FerroDB::protocol::*;
+use FerroDB::storage::FerroStore;
 #[test]
 fn test_parse_simple_string() {
     let input = "+OK\r\n";
@@ -10,15 +12,15 @@ fn test_parse_simple_string() {
 fn test_parse_bulk_string() {
     let input = "$5\r\nhello\r\n";
     let result = parse_resp(input).unwrap();
-    assert_eq!(result, RespValue::BulkString("hello".to_string()));
+    assert_eq!(result, RespValue::BulkString("hello".into()));
 }
 #[test]
 fn test_parse_array() {
     let input = "*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
     let result = parse_resp(input).unwrap();
     let expected = RespValue::Array(vec![
-        RespValue::BulkString("GET".to_string()),
-        RespValue::BulkString("key".to_string()),
+        RespValue::BulkString("GET".into()),
+        RespValue::BulkString("key".into()),
     ]);
     assert_eq!(result, expected);
 }
@@ -30,7 +32,7 @@ fn test_encode_simple_string() {
 
 #[test]
 fn test_encode_bulk_string() {
-    let value = RespValue::BulkString("hello".to_string());
+    let value = RespValue::BulkString("hello".into());
     assert_eq!(value.encode(), "$5\r\nhello\r\n");
 }
 
@@ -42,8 +44,8 @@ fn test_encode_null() {
 #[test]
 fn test_encode_array() {
     let value = RespValue::Array(vec![
-        RespValue::BulkString("GET".to_string()),
-        RespValue::BulkString("key".to_string()),
+        RespValue::BulkString("GET".into()),
+        RespValue::BulkString("key".into()),
     ]);
     assert_eq!(value.encode(), "*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
 }
@@ -67,3 +69,319 @@ fn test_encode_integer() {
     let negative = RespValue::Integer(-10);
     assert_eq!(negative.encode(), ":-10\r\n");
 }
+
+#[test]
+fn test_encode_resp3_types() {
+    let map = RespValue::Map(vec![(
+        RespValue::BulkString("a".into()),
+        RespValue::Integer(1),
+    )]);
+    assert_eq!(map.encode_for(RESP3), "%1\r\n$1\r\na\r\n:1\r\n");
+    assert_eq!(map.encode(), "*2\r\n$1\r\na\r\n:1\r\n");
+
+    let set = RespValue::Set(vec![RespValue::BulkString("x".into())]);
+    assert_eq!(set.encode_for(RESP3), "~1\r\n$1\r\nx\r\n");
+    assert_eq!(set.encode(), "*1\r\n$1\r\nx\r\n");
+
+    assert_eq!(RespValue::Double(1.5).encode_for(RESP3), ",1.5\r\n");
+    assert_eq!(RespValue::Double(1.5).encode(), "$3\r\n1.5\r\n");
+    assert_eq!(
+        RespValue::Double(f64::INFINITY).encode_for(RESP3),
+        ",inf\r\n"
+    );
+
+    assert_eq!(RespValue::Boolean(true).encode_for(RESP3), "#t\r\n");
+    assert_eq!(RespValue::Boolean(false).encode(), ":0\r\n");
+
+    assert_eq!(RespValue::Null.encode_for(RESP3), "_\r\n");
+    assert_eq!(RespValue::Null.encode_for(RESP2), "$-1\r\n");
+}
+
+#[test]
+fn test_error_roundtrip() {
+    let value = parse_resp("-WRONGTYPE Operation against a key\r\n").unwrap();
+    assert_eq!(
+        value,
+        RespValue::Error("WRONGTYPE Operation against a key".to_string())
+    );
+    assert_eq!(
+        value.error_message(),
+        Some("WRONGTYPE Operation against a key")
+    );
+    assert_eq!(value.encode(), "-WRONGTYPE Operation against a key\r\n");
+    assert_eq!(
+        RespValue::SimpleString("OK".to_string()).error_message(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_non_bulk_command_name_is_an_error() {
+    let store = FerroStore::new();
+    let request = RespValue::Array(vec![RespValue::Integer(1)]);
+    let reply = handle_command(request, &store, None, None).await;
+    assert_eq!(
+        reply,
+        RespValue::Error("ERR command must be a bulk string".to_string())
+    );
+    assert_eq!(reply.encode(), "-ERR command must be a bulk string\r\n");
+}
+
+#[test]
+fn test_encode_push() {
+    let push = RespValue::Push(vec![
+        RespValue::BulkString("message".into()),
+        RespValue::BulkString("ch".into()),
+        RespValue::BulkString("hi".into()),
+    ]);
+    assert_eq!(
+        push.encode_for(RESP3),
+        ">3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$2\r\nhi\r\n"
+    );
+    assert_eq!(
+        push.encode(),
+        "*3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$2\r\nhi\r\n"
+    );
+}
+
+#[test]
+fn test_verbatim_string_and_big_number() {
+    let verbatim = parse_resp("=15\r\ntxt:Some string\r\n").unwrap();
+    assert_eq!(
+        verbatim,
+        RespValue::Verbatim {
+            format: "txt".to_string(),
+            text: "Some string".to_string(),
+        }
+    );
+    assert_eq!(verbatim.encode_for(RESP3), "=15\r\ntxt:Some string\r\n");
+    assert_eq!(verbatim.encode(), "$11\r\nSome string\r\n");
+    assert!(parse_resp("=3\r\ntxt\r\n").is_err());
+    assert!(parse_resp("=4\r\ntxta\r\n").is_err());
+
+    let big = parse_resp("(-3492890328409238509324850943850943825024385\r\n").unwrap();
+    assert_eq!(
+        big,
+        RespValue::BigNumber("-3492890328409238509324850943850943825024385".to_string())
+    );
+    assert_eq!(
+        big.encode_for(RESP3),
+        "(-3492890328409238509324850943850943825024385\r\n"
+    );
+    assert_eq!(
+        big.encode(),
+        "$44\r\n-3492890328409238509324850943850943825024385\r\n"
+    );
+    assert!(parse_resp("(12a\r\n").is_err());
+    assert!(parse_resp("(-\r\n").is_err());
+}
+
+#[test]
+fn test_decoder_resumes_across_reads() {
+    let stream = b"*2\r\n$3\r\nGET\r\n$4\r\na\r\nb\r\n*1\r\n$4\r\nPING\r\n";
+    let mut decoder = RespDecoder::new();
+    let mut frames = Vec::new();
+    // One byte at a time, as if every read were tiny
+    for byte in stream {
+        decoder.extend(std::slice::from_ref(byte));
+        while let Decoded::Frame(frame) = decoder.decode() {
+            frames.push(frame);
+        }
+    }
+    assert_eq!(
+        frames,
+        vec![
+            RespValue::Array(vec![
+                RespValue::BulkString("GET".into()),
+                RespValue::BulkString("a\r\nb".into()),
+            ]),
+            RespValue::Array(vec![RespValue::BulkString("PING".into())]),
+        ]
+    );
+    assert_eq!(decoder.decode(), Decoded::NeedMoreData);
+}
+
+#[test]
+fn test_decoder_pipelined_and_inline_commands() {
+    let mut decoder = RespDecoder::new();
+    decoder.extend(b"\r\nSET k  v\r\n*1\r\n$4\r\nPING\r\n%1\r\n+a\r\n:1\r\n*2\r\n$1\r\nx");
+    assert_eq!(
+        decoder.decode(),
+        Decoded::Frame(RespValue::Array(vec![
+            RespValue::BulkString("SET".into()),
+            RespValue::BulkString("k".into()),
+            RespValue::BulkString("v".into()),
+        ]))
+    );
+    assert_eq!(
+        decoder.decode(),
+        Decoded::Frame(RespValue::Array(vec![RespValue::BulkString("PING".into())]))
+    );
+    assert_eq!(
+        decoder.decode(),
+        Decoded::Frame(RespValue::Map(vec![(
+            RespValue::SimpleString("a".to_string()),
+            RespValue::Integer(1),
+        )]))
+    );
+    assert_eq!(decoder.decode(), Decoded::NeedMoreData);
+    decoder.extend(b"\r\n$-1\r\n");
+    assert_eq!(
+        decoder.decode(),
+        Decoded::Frame(RespValue::Array(vec![
+            RespValue::BulkString("x".into()),
+            RespValue::Null,
+        ]))
+    );
+}
+
+#[test]
+fn test_decoder_rejects_malformed_frames() {
+    let mut decoder = RespDecoder::new();
+    decoder.extend(b"*1\r\n$3\r\nabcd\r\n");
+    assert!(matches!(decoder.decode(), Decoded::Error(_)));
+
+    let mut decoder = RespDecoder::new();
+    decoder.extend(b"*1\r\n$x\r\n");
+    assert!(matches!(decoder.decode(), Decoded::Error(_)));
+
+    assert!(parse_resp("*2\r\n$3\r\nGET\r\n").is_err());
+}
+
+#[test]
+fn test_encode_into_matches_encode() {
+    let values = vec![
+        RespValue::SimpleString("OK".to_string()),
+        RespValue::Error("ERR nope".to_string()),
+        RespValue::Integer(-42),
+        RespValue::BulkString("caf\u{e9}\r\n".into()),
+        RespValue::Null,
+        RespValue::Array(vec![
+            RespValue::BulkString(BulkStr::from_static("a")),
+            RespValue::Array(vec![]),
+        ]),
+        RespValue::Map(vec![(
+            RespValue::BulkString("k".into()),
+            RespValue::Double(1.5),
+        )]),
+        RespValue::Set(vec![RespValue::Boolean(true)]),
+        RespValue::Verbatim {
+            format: "txt".to_string(),
+            text: "hi".to_string(),
+        },
+        RespValue::BigNumber("123456789012345678901234567890".to_string()),
+    ];
+    let mut out = bytes::BytesMut::new();
+    for value in &values {
+        for protover in [RESP2, RESP3] {
+            out.clear();
+            value.encode_into(&mut out, protover);
+            assert_eq!(&out[..], value.encode_for(protover).as_bytes());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_decoder_reads_from_async_source() {
+    let payload = "x".repeat(100_000);
+    let stream = format!("*2\r\n$4\r\nECHO\r\n${}\r\n{}\r\n", payload.len(), payload);
+    let mut reader = stream.as_bytes();
+    let mut decoder = RespDecoder::new();
+    let frame = loop {
+        match decoder.decode() {
+            Decoded::Frame(frame) => break frame,
+            Decoded::NeedMoreData => {
+                assert!(decoder.read_from(&mut reader).await.unwrap() > 0);
+            }
+            Decoded::Error(e) => panic!("unexpected error {}", e),
+        }
+    };
+    let RespValue::Array(args) = frame else {
+        panic!("expected an array");
+    };
+    assert_eq!(args[0], RespValue::BulkString("ECHO".into()));
+    let RespValue::BulkString(arg) = &args[1] else {
+        panic!("expected a bulk string");
+    };
+    assert_eq!(arg.as_bytes(), payload.as_bytes());
+    assert_eq!(decoder.read_from(&mut reader).await.unwrap(), 0);
+}
+
+#[test]
+fn test_decoder_enforces_limits() {
+    // Rejected from the header alone, before any payload is buffered
+    let mut decoder = RespDecoder::new();
+    decoder.extend(b"*1\r\n$999999999999\r\n");
+    assert_eq!(
+        decoder.decode(),
+        Decoded::Error("Protocol error: invalid bulk length".to_string())
+    );
+
+    let mut decoder = RespDecoder::new();
+    decoder.extend(b"*1000000000\r\n");
+    assert_eq!(
+        decoder.decode(),
+        Decoded::Error("Protocol error: invalid multibulk length".to_string())
+    );
+    let mut decoder = RespDecoder::new();
+    decoder.extend(b"%600000\r\n");
+    assert_eq!(decoder.decode(), Decoded::NeedMoreData);
+
+    // A lower configured limit applies to the next bulk string
+    let mut decoder = RespDecoder::new();
+    decoder.set_max_bulk_len(4);
+    decoder.extend(b"*2\r\n$4\r\nPING\r\n$5\r\nhello\r\n");
+    assert!(matches!(decoder.decode(), Decoded::Error(_)));
+
+    // Lines that never end
+    let mut decoder = RespDecoder::new();
+    decoder.extend(&vec![b'a'; 70 * 1024]);
+    assert!(matches!(decoder.decode(), Decoded::Error(_)));
+    let mut decoder = RespDecoder::new();
+    decoder.extend(b"*");
+    decoder.extend(&vec![b'1'; 70 * 1024]);
+    assert!(matches!(decoder.decode(), Decoded::Error(_)));
+
+    let mut decoder = RespDecoder::new();
+    decoder.extend("*1\r\n".repeat(200).as_bytes());
+    assert!(matches!(decoder.decode(), Decoded::Error(_)));
+}
+
+#[test]
+fn test_request_decoder_is_strict() {
+    let mut decoder = RespDecoder::for_requests();
+    // Empty requests are skipped and other prefixes start inline commands
+    decoder.extend(b"*0\r\n*-1\r\n+PING\r\n");
+    assert_eq!(
+        decoder.decode(),
+        Decoded::Frame(RespValue::Array(vec![RespValue::BulkString(
+            "+PING".into()
+        )]))
+    );
+    assert_eq!(decoder.decode(), Decoded::NeedMoreData);
+
+    for (input, error) in [
+        (
+            &b"*1\r\n:1\r\n"[..],
+            "Protocol error: expected '$', got ':'",
+        ),
+        (
+            b"*1\r\n*1\r\n$1\r\na\r\n",
+            "Protocol error: expected '$', got '*'",
+        ),
+        (b"*1\r\n$-1\r\n", "Protocol error: invalid bulk length"),
+        (
+            b"*1\r\n$2\r\nabc\r\n",
+            "Protocol error: bulk string longer than its length",
+        ),
+        (b"*x\r\n", "Protocol error: invalid multibulk length"),
+        (
+            b"*1\r\n$\xff\r\n",
+            "Protocol error: invalid UTF-8 in header",
+        ),
+    ] {
+        let mut decoder = RespDecoder::for_requests();
+        decoder.extend(input);
+        assert_eq!(decoder.decode(), Decoded::Error(error.to_string()));
+    }
+}
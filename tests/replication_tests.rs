@@ -0,0 +1,635 @@
+use FerroDB::aof::{AofPaths, AofWriter};
+use FerroDB::clients::Client;
+use FerroDB::commands::handle_command;
+use FerroDB::config::RdbCompression;
+use FerroDB::connection::ConnectionContext;
+use FerroDB::persistance::{rdb_bytes, read_rdb};
+use FerroDB::protocol::{Decoded, RespDecoder, RespValue};
+use FerroDB::replication::ReplicaStream;
+use FerroDB::storage::{DataType, FerroStore};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+fn command(args: &[&str]) -> RespValue {
+    RespValue::Array(
+        args.iter()
+            .map(|arg| RespValue::BulkString((*arg).into()))
+            .collect(),
+    )
+}
+
+/// The connection of a client connected from `addr`, registered until the
+/// `Client` is dropped
+fn connect(store: &FerroStore, addr: &str) -> (ConnectionContext, Client) {
+    let client = store.clients().register(
+        addr.parse::<SocketAddr>().unwrap(),
+        "127.0.0.1:6379".parse().unwrap(),
+    );
+    (ConnectionContext::for_client(client.id()), client)
+}
+
+async fn info_replication(store: &FerroStore) -> String {
+    match handle_command(command(&["INFO", "replication"]), store, None, None).await {
+        RespValue::BulkString(info) => info.to_string(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_replica_gets_snapshot_then_writes() {
+    let store = FerroStore::new();
+    // The AOF is off, yet its writer still feeds the replicas
+    store
+        .config()
+        .set(&[("appendonly".to_string(), "no".to_string())])
+        .unwrap();
+    let (aof, _aof_handle) = AofWriter::new(
+        AofPaths::new("/tmp/test_replication/appendonlydir", "appendonly.aof"),
+        store.config().clone(),
+        store.latency().clone(),
+        store.replication().clone(),
+    );
+    store.set("before".to_string(), "1".to_string());
+
+    let (mut conn, _replica_client) = connect(&store, "10.0.0.7:41000");
+    for args in [
+        &["REPLCONF", "listening-port", "7000"][..],
+        &["REPLCONF", "capa", "eof", "capa", "psync2"],
+        &["PSYNC", "?", "-1"],
+    ] {
+        let reply = handle_command(command(args), &store, Some(&aof), Some(&mut conn)).await;
+        assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+    }
+    // Replicas are sent the stream rather than replies
+    let info = store.clients().get(conn.id.unwrap()).unwrap();
+    assert_eq!(info.kind(), "replica");
+    assert!(!store.clients().take_reply(info.id));
+
+    // The reply to PSYNC, then the dataset as an RDB bulk payload
+    let mut stream = conn.replica.as_mut().unwrap().take_stream().unwrap();
+    let payload = stream.next().await.unwrap();
+    let header = format!("+FULLRESYNC {} 0\r\n$", store.replication().replid());
+    assert!(payload.starts_with(header.as_bytes()));
+    let rdb_start = payload.windows(2).position(|w| w == b"\r\n").unwrap() + 2;
+    let len_end = rdb_start
+        + payload[rdb_start..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .unwrap();
+    let len: usize = std::str::from_utf8(&payload[rdb_start + 1..len_end])
+        .unwrap()
+        .parse()
+        .unwrap();
+    let rdb = &payload[len_end + 2..];
+    assert_eq!(rdb.len(), len);
+    let mut keys = Vec::new();
+    read_rdb(rdb, |db, key, data, _| {
+        assert!(matches!(data, DataType::String(_)));
+        keys.push((db, key));
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(keys, vec![(0, "before".to_string())]);
+
+    // Then the writes, starting with the database they run against;
+    // reads and no-ops aren't sent
+    let (mut client, _client) = connect(&store, "10.0.0.8:41001");
+    for args in [
+        &["SELECT", "2"][..],
+        &["GET", "before"],
+        &["DEL", "missing"],
+        &["SET", "after", "2"],
+    ] {
+        handle_command(command(args), &store, Some(&aof), Some(&mut client)).await;
+    }
+    let expected = command(&["SELECT", "2"]).encode() + &command(&["SET", "after", "2"]).encode();
+    let mut received = Vec::new();
+    while received.len() < expected.len() {
+        received.extend_from_slice(&stream.next().await.unwrap());
+    }
+    assert_eq!(String::from_utf8(received).unwrap(), expected);
+    assert_eq!(store.replication().offset(), expected.len() as u64);
+
+    handle_command(
+        command(&["REPLCONF", "ACK", &expected.len().to_string()]),
+        &store,
+        Some(&aof),
+        Some(&mut conn),
+    )
+    .await;
+    let info = info_replication(&store).await;
+    assert!(info.contains("role:master\r\n"));
+    assert!(info.contains("connected_slaves:1\r\n"));
+    assert!(info.contains(&format!(
+        "slave0:ip=10.0.0.7,port=7000,state=online,offset={},lag=0\r\n",
+        expected.len()
+    )));
+    assert!(info.contains(&format!("master_repl_offset:{}\r\n", expected.len())));
+    let role = handle_command(command(&["ROLE"]), &store, None, Some(&mut client)).await;
+    let offset = expected.len().to_string();
+    assert_eq!(
+        role,
+        RespValue::Array(vec![
+            RespValue::BulkString("master".into()),
+            RespValue::Integer(expected.len() as i64),
+            RespValue::Array(vec![RespValue::Array(vec![
+                RespValue::BulkString("10.0.0.7".into()),
+                RespValue::BulkString("7000".into()),
+                RespValue::BulkString(offset.into()),
+            ])]),
+        ])
+    );
+
+    // WAIT counts the replicas that acknowledged every write so far, asking
+    // them to acknowledge when too few have
+    let wait = |args: &'static [&'static str]| {
+        let (store, aof) = (store.clone(), aof.clone());
+        tokio::spawn(async move { handle_command(command(args), &store, Some(&aof), None).await })
+    };
+    assert_eq!(
+        wait(&["WAIT", "1", "0"]).await.unwrap(),
+        RespValue::Integer(1)
+    );
+    handle_command(
+        command(&["SET", "later", "3"]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(
+        wait(&["WAIT", "1", "20"]).await.unwrap(),
+        RespValue::Integer(0)
+    );
+    let expected =
+        command(&["SET", "later", "3"]).encode() + &command(&["REPLCONF", "GETACK", "*"]).encode();
+    let mut received = Vec::new();
+    while received.len() < expected.len() {
+        received.extend_from_slice(&stream.next().await.unwrap());
+    }
+    assert_eq!(String::from_utf8(received).unwrap(), expected);
+    let waiting = wait(&["WAIT", "1", "0"]);
+    let offset = store.replication().offset().to_string();
+    handle_command(
+        command(&["REPLCONF", "ACK", &offset]),
+        &store,
+        Some(&aof),
+        Some(&mut conn),
+    )
+    .await;
+    assert_eq!(waiting.await.unwrap(), RespValue::Integer(1));
+    let reply = handle_command(
+        command(&["WAIT", "1", "-1"]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(
+        reply,
+        RespValue::Error("ERR timeout is negative".to_string())
+    );
+
+    // Closing the connection detaches the replica
+    drop(conn);
+    assert!(stream.next().await.is_none());
+    assert!(
+        info_replication(&store)
+            .await
+            .contains("connected_slaves:0\r\n")
+    );
+}
+
+/// Attach client `addr` as a replica with PSYNC replid offset, returning its
+/// connection and the first payload it's sent
+async fn psync(
+    store: &FerroStore,
+    aof: &AofWriter,
+    addr: &str,
+    replid: &str,
+    offset: &str,
+) -> (ConnectionContext, ReplicaStream, String) {
+    let (mut conn, _client) = connect(store, addr);
+    let reply = handle_command(
+        command(&["PSYNC", replid, offset]),
+        store,
+        Some(aof),
+        Some(&mut conn),
+    )
+    .await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+    let mut stream = conn.replica.as_mut().unwrap().take_stream().unwrap();
+    let payload = stream.next().await.unwrap();
+    let payload = String::from_utf8_lossy(&payload).into_owned();
+    (conn, stream, payload)
+}
+
+#[tokio::test]
+async fn test_psync_resumes_from_backlog() {
+    let store = FerroStore::new();
+    store
+        .config()
+        .set(&[("appendonly".to_string(), "no".to_string())])
+        .unwrap();
+    let (aof, _aof_handle) = AofWriter::new(
+        AofPaths::new("/tmp/test_psync/appendonlydir", "appendonly.aof"),
+        store.config().clone(),
+        store.latency().clone(),
+        store.replication().clone(),
+    );
+    let (mut client, _client) = connect(&store, "10.0.0.8:41001");
+    let replid = store.replication().replid();
+    // No backlog is kept until a replica attaches
+    handle_command(
+        command(&["SET", "a", "1"]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    assert!(
+        info_replication(&store)
+            .await
+            .contains("repl_backlog_active:0\r\n")
+    );
+
+    let (conn, _stream, payload) = psync(&store, &aof, "10.0.0.7:41000", "?", "-1").await;
+    assert!(payload.starts_with(&format!("+FULLRESYNC {} 0\r\n", replid)));
+    handle_command(
+        command(&["SET", "b", "2"]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    let synced = store.replication().offset();
+    drop(conn);
+
+    // Written while the replica is away, then sent when it resumes
+    handle_command(
+        command(&["SET", "c", "3"]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    let next = (synced + 1).to_string();
+    let (_conn, mut stream, payload) = psync(&store, &aof, "10.0.0.7:41002", &replid, &next).await;
+    assert_eq!(
+        payload,
+        format!("+CONTINUE {}\r\n", replid) + &command(&["SET", "c", "3"]).encode()
+    );
+    handle_command(
+        command(&["SET", "d", "4"]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    let update = stream.next().await.unwrap();
+    assert_eq!(update, command(&["SET", "d", "4"]).encode().as_bytes());
+    let info = info_replication(&store).await;
+    assert!(info.contains("repl_backlog_active:1\r\n"));
+    assert!(info.contains("repl_backlog_first_byte_offset:1\r\n"));
+    assert!(info.contains(&format!(
+        "repl_backlog_histlen:{}\r\n",
+        store.replication().offset()
+    )));
+
+    // Another history, or writes no longer in the backlog, need a full resync
+    let (_conn, _stream, payload) =
+        psync(&store, &aof, "10.0.0.7:41003", &"0".repeat(40), &next).await;
+    assert!(payload.starts_with("+FULLRESYNC "));
+    let reply = handle_command(
+        command(&["CONFIG", "SET", "repl-backlog-size", "16kb"]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+    let value = "x".repeat(20 * 1024);
+    handle_command(
+        command(&["SET", "big", &value]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    let (_conn, _stream, payload) = psync(&store, &aof, "10.0.0.7:41004", &replid, &next).await;
+    assert!(payload.starts_with("+FULLRESYNC "));
+    assert!(
+        info_replication(&store)
+            .await
+            .contains("repl_backlog_histlen:16384\r\n")
+    );
+}
+
+#[tokio::test]
+async fn test_slow_replica_is_detached_over_output_limit() {
+    let store = FerroStore::new();
+    store
+        .config()
+        .set(&[("appendonly".to_string(), "no".to_string())])
+        .unwrap();
+    let (aof, _aof_handle) = AofWriter::new(
+        AofPaths::new("/tmp/test_replica_output/appendonlydir", "appendonly.aof"),
+        store.config().clone(),
+        store.latency().clone(),
+        store.replication().clone(),
+    );
+    let (mut client, _client) = connect(&store, "10.0.0.8:41001");
+    let reply = handle_command(
+        command(&[
+            "CONFIG",
+            "SET",
+            "client-output-buffer-limit",
+            "replica 64kb 0 0",
+        ]),
+        &store,
+        Some(&aof),
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+
+    let (_fast_conn, mut fast, _) = psync(&store, &aof, "10.0.0.7:41000", "?", "-1").await;
+    let (_slow_conn, mut slow, _) = psync(&store, &aof, "10.0.0.7:41002", "?", "-1").await;
+    let value = "x".repeat(20 * 1024);
+    for i in 0..4 {
+        let key = format!("key{}", i);
+        handle_command(
+            command(&["SET", &key, &value]),
+            &store,
+            Some(&aof),
+            Some(&mut client),
+        )
+        .await;
+        // The fast replica takes each write as it comes
+        assert!(fast.next().await.is_some());
+    }
+    // The slow one took none and went over 64kb with the fourth
+    assert!(slow.overflowed());
+    assert!(slow.next().await.is_none());
+    assert!(!fast.overflowed());
+    assert_eq!(fast.queued(), 0);
+    assert!(
+        info_replication(&store)
+            .await
+            .contains("connected_slaves:1\r\n")
+    );
+}
+
+#[tokio::test]
+async fn test_replication_commands_need_a_connection() {
+    let store = FerroStore::new();
+    let reply = handle_command(command(&["SYNC"]), &store, None, None).await;
+    assert_eq!(
+        reply,
+        RespValue::Error("ERR SYNC is not allowed in this context".to_string())
+    );
+
+    let mut conn = ConnectionContext::new();
+    let reply = handle_command(
+        command(&["REPLCONF", "listening-port"]),
+        &store,
+        None,
+        Some(&mut conn),
+    )
+    .await;
+    assert_eq!(reply, RespValue::Error("ERR syntax error".to_string()));
+    let reply = handle_command(
+        command(&["REPLCONF", "speed", "fast"]),
+        &store,
+        None,
+        Some(&mut conn),
+    )
+    .await;
+    assert_eq!(
+        reply,
+        RespValue::Error("ERR Unrecognized REPLCONF option: speed".to_string())
+    );
+    let reply = handle_command(command(&["PSYNC", "?"]), &store, None, Some(&mut conn)).await;
+    assert_eq!(
+        reply,
+        RespValue::Error("ERR wrong number of arguments for 'psync' command".to_string())
+    );
+}
+
+/// Serve a replica like a master would: reply to its handshake, then
+/// answer its PSYNC with `reply`. Returns the connection and the PSYNC
+/// arguments
+async fn fake_master(listener: &TcpListener, reply: &[u8]) -> (TcpStream, Vec<String>) {
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut decoder = RespDecoder::for_requests();
+    loop {
+        let args: Vec<String> = match decoder.decode() {
+            Decoded::Frame(RespValue::Array(args)) => args
+                .iter()
+                .map(|arg| match arg {
+                    RespValue::BulkString(arg) => arg.to_string(),
+                    other => panic!("unexpected argument {:?}", other),
+                })
+                .collect(),
+            Decoded::NeedMoreData => {
+                assert!(decoder.read_from(&mut socket).await.unwrap() > 0);
+                continue;
+            }
+            other => panic!("unexpected request {:?}", other),
+        };
+        match args[0].to_ascii_uppercase().as_str() {
+            "PING" => socket.write_all(b"+PONG\r\n").await.unwrap(),
+            "REPLCONF" => socket.write_all(b"+OK\r\n").await.unwrap(),
+            "PSYNC" => {
+                socket.write_all(reply).await.unwrap();
+                return (socket, args[1..].to_vec());
+            }
+            other => panic!("unexpected request {}", other),
+        }
+    }
+}
+
+/// The value of `key` in database `db` once it's been replicated
+async fn replicated(store: &FerroStore, db: usize, key: &str) -> Option<String> {
+    let handle = store.databases().nth(db).unwrap();
+    for _ in 0..100 {
+        if let Some(value) = handle.get(key) {
+            return Some(value);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    None
+}
+
+#[tokio::test]
+async fn test_replicaof_follows_master() {
+    let master = FerroStore::new();
+    master.set("loaded".to_string(), "1".to_string());
+    let databases: Vec<_> = master
+        .databases()
+        .map(|db| (db.db_index(), db.get_all_data()))
+        .collect();
+    let rdb = rdb_bytes(&databases, RdbCompression::No);
+    let writes = command(&["SELECT", "1"]).encode() + &command(&["SET", "streamed", "2"]).encode();
+    let replid = "f".repeat(40);
+    // Newlines keep the link alive while the snapshot is prepared
+    let mut full_resync =
+        format!("+FULLRESYNC {} 100\r\n\n${}\r\n", replid, rdb.len()).into_bytes();
+    full_resync.extend_from_slice(&rdb);
+    full_resync.extend_from_slice(writes.as_bytes());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+
+    let store = FerroStore::new();
+    store.set("stale".to_string(), "x".to_string());
+    let (mut client, _client) = connect(&store, "10.0.0.8:41001");
+    let reply = handle_command(
+        command(&["REPLICAOF", "127.0.0.1", &port]),
+        &store,
+        None,
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+    let (master_socket, psync) = fake_master(&listener, &full_resync).await;
+    assert_eq!(psync, ["?", "-1"]);
+
+    // The snapshot replaces the dataset, then the writes are applied
+    assert_eq!(
+        replicated(&store, 1, "streamed").await.as_deref(),
+        Some("2")
+    );
+    assert_eq!(store.get("loaded").as_deref(), Some("1"));
+    assert_eq!(store.get("stale"), None);
+
+    let reply = handle_command(command(&["SET", "k", "v"]), &store, None, Some(&mut client)).await;
+    assert_eq!(
+        reply,
+        RespValue::Error("READONLY You can't write against a read only replica.".to_string())
+    );
+    let info = info_replication(&store).await;
+    assert!(info.contains("role:slave\r\n"));
+    assert!(info.contains(&format!("master_port:{}\r\n", port)));
+    assert!(info.contains("master_link_status:up\r\n"));
+    let offset = 100 + writes.len();
+    assert!(info.contains(&format!("slave_read_repl_offset:{}\r\n", offset)));
+    assert!(info.contains(&format!("slave_repl_offset:{}\r\n", offset)));
+    assert!(info.contains("slave_read_only:1\r\n"));
+    assert!(!info.contains("master_link_down_since_seconds"));
+    let role = |state: &str| {
+        RespValue::Array(vec![
+            RespValue::BulkString("slave".into()),
+            RespValue::BulkString("127.0.0.1".into()),
+            RespValue::Integer(port.parse().unwrap()),
+            RespValue::BulkString(state.to_string().into()),
+            RespValue::Integer(offset as i64),
+        ])
+    };
+    let reply = handle_command(command(&["ROLE"]), &store, None, Some(&mut client)).await;
+    assert_eq!(reply, role("connected"));
+    let reply = handle_command(
+        command(&["REPLICAOF", "127.0.0.1", &port]),
+        &store,
+        None,
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(
+        reply,
+        RespValue::SimpleString("OK Already connected to specified master".to_string())
+    );
+
+    // Without the master, only commands that don't read the dataset run
+    // unless stale data may be served
+    store
+        .config()
+        .set(&[("replica-serve-stale-data".to_string(), "no".to_string())])
+        .unwrap();
+    drop(master_socket);
+    for _ in 0..100 {
+        if info_replication(&store)
+            .await
+            .contains("master_link_status:down\r\n")
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let reply = handle_command(command(&["GET", "loaded"]), &store, None, Some(&mut client)).await;
+    assert_eq!(
+        reply,
+        RespValue::Error(
+            "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                .to_string()
+        )
+    );
+    let reply = handle_command(command(&["SELECT", "1"]), &store, None, Some(&mut client)).await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+    let reply = handle_command(command(&["ROLE"]), &store, None, Some(&mut client)).await;
+    assert_eq!(reply, role("connecting"));
+    assert!(
+        info_replication(&store)
+            .await
+            .contains("master_link_down_since_seconds:0\r\n")
+    );
+
+    // After losing the link it asks to resume after the last byte applied,
+    // and carries on in the database the stream left off in
+    let writes = command(&["SET", "resumed", "3"]).encode()
+        + &command(&["REPLCONF", "GETACK", "*"]).encode();
+    let resumed = "+CONTINUE\r\n".to_string() + &writes;
+    let (mut master_socket, psync) = fake_master(&listener, resumed.as_bytes()).await;
+    assert_eq!(psync, [replid, (offset + 1).to_string()]);
+    // Acknowledged as soon as it's asked, counting the request itself
+    let acked = command(&["REPLCONF", "ACK", &(offset + writes.len()).to_string()]);
+    let mut decoder = RespDecoder::for_requests();
+    loop {
+        match decoder.decode() {
+            Decoded::Frame(ack) if ack == acked => break,
+            Decoded::Frame(_) => {}
+            _ => assert!(decoder.read_from(&mut master_socket).await.unwrap() > 0),
+        }
+    }
+    assert_eq!(replicated(&store, 1, "resumed").await.as_deref(), Some("3"));
+    assert_eq!(store.get("loaded").as_deref(), Some("1"));
+    let reply = handle_command(
+        command(&["GET", "resumed"]),
+        &store,
+        None,
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(reply, RespValue::BulkString("3".into()));
+
+    // Writable replicas take writes from clients too
+    let reply = handle_command(
+        command(&["CONFIG", "SET", "replica-read-only", "no"]),
+        &store,
+        None,
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+    let reply = handle_command(
+        command(&["SET", "local", "4"]),
+        &store,
+        None,
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+
+    let reply = handle_command(
+        command(&["REPLICAOF", "NO", "ONE"]),
+        &store,
+        None,
+        Some(&mut client),
+    )
+    .await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+    assert!(info_replication(&store).await.contains("role:master\r\n"));
+    let reply = handle_command(command(&["SET", "k", "v"]), &store, None, Some(&mut client)).await;
+    assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+}
@@ -0,0 +1,190 @@
+use FerroDB::protocol::{Decoded, RespDecoder, RespValue};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+/// Replies the server buffers before handing them to the writer
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// The server binary, killed when dropped
+struct Server {
+    child: Child,
+    port: u16,
+}
+
+impl Server {
+    fn start(dir: &str) -> Server {
+        std::fs::create_dir_all(dir).unwrap();
+        // Free now, so most likely still free when the server binds it
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = Command::new(env!("CARGO_BIN_EXE_FerroDB"))
+            .args([
+                "--port",
+                &port.to_string(),
+                "--bind",
+                "127.0.0.1",
+                "--dir",
+                dir,
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Server { child, port }
+    }
+
+    async fn connect(&self) -> Client {
+        for _ in 0..100 {
+            if let Ok(socket) = TcpStream::connect(("127.0.0.1", self.port)).await {
+                return Client {
+                    socket,
+                    decoder: RespDecoder::new(),
+                };
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("server didn't start listening");
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+struct Client {
+    socket: TcpStream,
+    decoder: RespDecoder,
+}
+
+impl Client {
+    async fn send(&mut self, bytes: &[u8]) {
+        self.socket.write_all(bytes).await.unwrap();
+    }
+
+    /// The next reply, or None once the server has closed the connection
+    async fn reply(&mut self) -> Option<RespValue> {
+        let read = async {
+            loop {
+                match self.decoder.decode() {
+                    Decoded::Frame(reply) => return Some(reply),
+                    Decoded::NeedMoreData => {
+                        if self.decoder.read_from(&mut self.socket).await.unwrap() == 0 {
+                            return None;
+                        }
+                    }
+                    Decoded::Error(e) => panic!("bad reply: {}", e),
+                }
+            }
+        };
+        timeout(Duration::from_secs(5), read)
+            .await
+            .expect("reply didn't arrive")
+    }
+}
+
+fn encode(args: &[&str]) -> String {
+    RespValue::Array(args.iter().map(|arg| RespValue::bulk(*arg)).collect()).encode()
+}
+
+#[tokio::test]
+async fn test_pipelined_replies_arrive_in_order() {
+    let server = Server::start("/tmp/test_FerroDB_pipeline");
+    let mut client = server.connect().await;
+    let pipeline = encode(&["RPUSH", "list", "x"]).repeat(1000);
+    client.send(pipeline.as_bytes()).await;
+    for n in 1..=1000 {
+        assert_eq!(client.reply().await, Some(RespValue::Integer(n)));
+    }
+    let _ = std::fs::remove_dir_all("/tmp/test_FerroDB_pipeline");
+}
+
+#[tokio::test]
+async fn test_large_pipeline_flushes_mid_batch() {
+    let server = Server::start("/tmp/test_FerroDB_pipeline_flush");
+    let mut client = server.connect().await;
+    let mut admin = server.connect().await;
+    admin
+        .send(encode(&["CONFIG", "SET", "lua-time-limit", "10"]).as_bytes())
+        .await;
+    assert_eq!(
+        admin.reply().await,
+        Some(RespValue::SimpleString("OK".into()))
+    );
+    // Few, big replies: after enough commands the reader yields and
+    // flushes anyway, which would hide a missing threshold flush
+    let payload = "x".repeat(16 * 1024);
+    admin
+        .send(encode(&["SET", "big", &payload]).as_bytes())
+        .await;
+    assert_eq!(
+        admin.reply().await,
+        Some(RespValue::SimpleString("OK".into()))
+    );
+    let value = Some(RespValue::bulk(payload.as_str()));
+
+    // Replies past the threshold, then a script that runs until killed:
+    // they can only be read meanwhile if they were flushed before it
+    let gets = 2 * FLUSH_THRESHOLD / payload.len();
+    let mut pipeline = encode(&["GET", "big"]).repeat(gets);
+    pipeline.push_str(&encode(&["EVAL", "while true do end", "0"]));
+    client.send(pipeline.as_bytes()).await;
+    let mut received = 0;
+    while received * payload.len() < FLUSH_THRESHOLD {
+        assert!(client.reply().await == value);
+        received += 1;
+    }
+    loop {
+        admin.send(encode(&["SCRIPT", "KILL"]).as_bytes()).await;
+        match admin.reply().await {
+            Some(RespValue::SimpleString(ok)) if ok == "OK" => break,
+            // Still running GETs, or not yet past lua-time-limit
+            _ => sleep(Duration::from_millis(10)).await,
+        }
+    }
+    for _ in received..gets {
+        assert!(client.reply().await == value);
+    }
+    assert!(matches!(client.reply().await, Some(RespValue::Error(e)) if e.contains("SCRIPT KILL")));
+
+    // Replies to the complete commands of a read go out while the rest of
+    // a split command is awaited
+    let pipeline = encode(&["GET", "big"]).repeat(gets) + &encode(&["PING"]);
+    let (complete, partial) = pipeline.split_at(pipeline.len() - 5);
+    client.send(complete.as_bytes()).await;
+    for _ in 0..gets {
+        assert!(client.reply().await == value);
+    }
+    client.send(partial.as_bytes()).await;
+    assert_eq!(
+        client.reply().await,
+        Some(RespValue::SimpleString("PONG".into()))
+    );
+
+    // Replies ahead of a protocol error are sent before the connection closes
+    let mut pipeline = encode(&["PING"]).repeat(3);
+    pipeline.push_str("*x\r\n");
+    client.send(pipeline.as_bytes()).await;
+    for _ in 0..3 {
+        assert_eq!(
+            client.reply().await,
+            Some(RespValue::SimpleString("PONG".into()))
+        );
+    }
+    assert_eq!(
+        client.reply().await,
+        Some(RespValue::Error(
+            "ERR Protocol error: invalid multibulk length".to_string()
+        ))
+    );
+    assert_eq!(client.reply().await, None);
+    let _ = std::fs::remove_dir_all("/tmp/test_FerroDB_pipeline_flush");
+}
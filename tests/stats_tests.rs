@@ -0,0 +1,45 @@
+use FerroDB::stats::CommandStats;
+use std::time::Duration;
+
+#[test]
+fn test_command_stats_counts() {
+    let stats = CommandStats::new();
+    stats.record("GET", Some(Duration::from_micros(10)), false);
+    stats.record("get", Some(Duration::from_micros(30)), true);
+    stats.record("BLPOP", None, false);
+    stats.reject("GET");
+
+    let list = stats.list();
+    let names: Vec<&str> = list.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["blpop", "get"]);
+
+    let get = &list[1].1;
+    assert_eq!(get.calls, 2);
+    assert_eq!(get.usec, 40);
+    assert_eq!(get.usec_per_call(), 20.0);
+    assert_eq!(get.failed_calls, 1);
+    assert_eq!(get.rejected_calls, 1);
+
+    let blpop = &list[0].1;
+    assert_eq!(blpop.calls, 1);
+    assert_eq!(blpop.percentile(50.0), None);
+
+    stats.reset();
+    assert!(stats.list().is_empty());
+}
+
+#[test]
+fn test_command_stats_percentiles() {
+    let stats = CommandStats::new();
+    for _ in 0..999 {
+        stats.record("zrange", Some(Duration::from_micros(7)), false);
+    }
+    stats.record("zrange", Some(Duration::from_micros(1000)), false);
+    let (_, zrange) = &stats.list()[0];
+
+    // Small durations are exact, larger ones within 1/16
+    assert_eq!(zrange.percentile(50.0), Some(7));
+    assert_eq!(zrange.percentile(99.9), Some(7));
+    let max = zrange.percentile(100.0).unwrap();
+    assert!((1000..1000 + 1000 / 16).contains(&max), "{}", max);
+}
@@ -1,6 +1,6 @@
 use FerroDB::storage::*;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 #[test]
 fn test_set_and_get() {
     let store = FerroStore::new();
@@ -37,6 +37,86 @@ fn test_exists() {
     assert!(store.exists("key1"));
     assert!(!store.exists("nonexistent"));
 }
+#[test]
+fn test_setnx() {
+    let store = FerroStore::new();
+
+    assert!(store.setnx("lock".to_string(), "owner1".to_string()));
+    // Second attempt must not overwrite
+    assert!(!store.setnx("lock".to_string(), "owner2".to_string()));
+    assert_eq!(store.get("lock"), Some("owner1".to_string()));
+}
+
+#[test]
+fn test_msetnx_all_or_nothing() {
+    let store = FerroStore::new();
+    store.set("b".to_string(), "existing".to_string());
+
+    // One key exists, so nothing is written
+    assert!(!store.msetnx(vec![
+        ("a".to_string(), "1".to_string()),
+        ("b".to_string(), "2".to_string()),
+    ]));
+    assert_eq!(store.get("a"), None);
+    assert_eq!(store.get("b"), Some("existing".to_string()));
+
+    assert!(store.msetnx(vec![
+        ("a".to_string(), "1".to_string()),
+        ("c".to_string(), "3".to_string()),
+    ]));
+    assert_eq!(store.get("a"), Some("1".to_string()));
+    assert_eq!(store.get("c"), Some("3".to_string()));
+}
+
+#[test]
+fn test_getdel_wrong_type() {
+    let store = FerroStore::new();
+    store.lpush("mylist", vec!["a".to_string()]).unwrap();
+
+    assert!(store.getdel("mylist").is_err());
+    // The list must survive the failed GETDEL
+    assert_eq!(store.llen("mylist").unwrap(), 1);
+}
+
+#[test]
+fn test_copy_preserves_value_and_ttl() {
+    let store = FerroStore::new();
+    store
+        .sadd("src", vec!["a".to_string(), "b".to_string()])
+        .unwrap();
+    store.expire("src", 100);
+
+    assert!(store.copy("src", "dst", false));
+    assert_eq!(store.scard("dst").unwrap(), 2);
+    assert!(store.ttl("dst").unwrap() > 0);
+
+    // The copy is independent of the source
+    store.srem("src", vec!["a".to_string()]).unwrap();
+    assert_eq!(store.scard("dst").unwrap(), 2);
+
+    // Existing destination needs REPLACE
+    store.set("other".to_string(), "x".to_string());
+    assert!(!store.copy("other", "dst", false));
+    assert!(store.copy("other", "dst", true));
+    assert_eq!(store.get("dst"), Some("x".to_string()));
+
+    assert!(!store.copy("missing", "dst2", false));
+}
+
+#[test]
+fn test_unlink_large_set() {
+    let store = FerroStore::new();
+    let members: Vec<String> = (0..10_000).map(|i| i.to_string()).collect();
+    store.sadd("big", members).unwrap();
+    store.set("small".to_string(), "v".to_string());
+
+    assert!(store.unlink("big"));
+    assert!(store.unlink("small"));
+    assert!(!store.unlink("big"));
+    assert!(!store.exists("big"));
+    assert_eq!(store.dbsize(), 0);
+}
+
 #[test]
 fn test_set_with_expiry() {
     let store = FerroStore::new();
@@ -101,6 +181,23 @@ fn test_ttl_with_expiration() {
     assert!((8..=10).contains(&ttl));
 }
 
+#[test]
+fn test_far_expiry_times() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+
+    // The furthest expire time commands accept reads back unchanged
+    let max = i64::MAX as u64;
+    assert!(store.pexpire_at("key", max, ExpireCondition::default()));
+    assert_eq!(store.pexpire_time("key"), Some(i64::MAX));
+    assert!(store.pttl("key").is_some_and(|pttl| pttl > 0));
+
+    // One past it never reads as no expiry or as a negative time
+    assert!(store.pexpire_at("key", u64::MAX, ExpireCondition::default()));
+    assert_eq!(store.pexpire_time("key"), Some(i64::MAX));
+    assert_eq!(store.pttl("key"), Some(i64::MAX));
+}
+
 #[test]
 fn test_ttl_nonexistent_key() {
     let store = FerroStore::new();
@@ -145,6 +242,26 @@ fn test_exists_with_expired_key() {
     assert!(!store.exists("key"));
 }
 
+#[test]
+fn test_reads_delete_expired_keys() {
+    let store = FerroStore::new();
+    store.set_with_expiry_ms("string".to_string(), "value".to_string(), 20);
+    store.rpush("list", vec!["a".to_string()]).unwrap();
+    store.sadd("set", vec!["m".to_string()]).unwrap();
+    for key in ["list", "set"] {
+        assert!(store.pexpire(key, 20, ExpireCondition::default()));
+    }
+
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.dbsize(), 3);
+
+    // Read only commands see expired keys as missing, and delete them
+    assert_eq!(store.get("string"), None);
+    assert_eq!(store.llen("list"), Ok(0));
+    assert_eq!(store.smembers("set"), Ok(vec![]));
+    assert_eq!(store.dbsize(), 0);
+}
+
 #[test]
 fn test_delete_expired_keys() {
     let store = FerroStore::new();
@@ -167,6 +284,34 @@ fn test_delete_expired_keys() {
     assert_eq!(store.get("medium"), Some("val2".to_string()));
     assert_eq!(store.get("permanent"), Some("val3".to_string()));
 }
+
+#[test]
+fn test_active_expire_cycle_uses_expiry_index() {
+    let store = FerroStore::new();
+    for i in 0..100 {
+        store.set_with_expiry_ms(format!("short{}", i), "v".to_string(), 10);
+    }
+    store.set_with_expiry_ms("extended".to_string(), "v".to_string(), 10);
+    assert!(store.pexpire("extended", 60_000, Default::default()));
+    store.set_with_expiry_ms("persisted".to_string(), "v".to_string(), 10);
+    assert!(store.persist("persisted"));
+    store.set_with_expiry_ms("updated".to_string(), "v".to_string(), 10);
+    store.set("updated".to_string(), "v".to_string());
+    store
+        .database(3)
+        .unwrap()
+        .set_with_expiry_ms("other".to_string(), "v".to_string(), 10);
+    thread::sleep(Duration::from_millis(30));
+
+    // A spent budget still deletes one batch per database
+    assert_eq!(store.active_expire_cycle(Duration::ZERO), 21);
+    assert_eq!(store.delete_expired_keys(), 80);
+    assert_eq!(store.delete_expired_keys(), 0);
+    assert_eq!(store.dbsize(), 3);
+    assert!(store.exists("extended"));
+    assert!(store.exists("persisted"));
+    assert!(store.exists("updated"));
+}
 #[test]
 fn test_lpush_single_value() {
     let store = FerroStore::new();
@@ -428,6 +573,7 @@ fn test_smembers() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_sismember() {
     let store = FerroStore::new();
 
@@ -666,3 +812,1160 @@ fn test_zcard() {
 
     assert_eq!(store.zcard("leaderboard").unwrap(), 2);
 }
+
+// ============ SCAN TESTS ============
+
+#[test]
+fn test_scan_visits_every_key_once() {
+    let store = FerroStore::new();
+    for i in 0..100 {
+        store.set(format!("key:{}", i), "v".to_string());
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut cursor = 0;
+    loop {
+        let (next, keys) = store.scan(cursor, 7, None, None);
+        for key in keys {
+            assert!(seen.insert(key), "key returned twice");
+        }
+        // Concurrent writes between calls must not disturb the iteration
+        store.set(format!("new:{}", cursor), "v".to_string());
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+
+    for i in 0..100 {
+        assert!(seen.contains(&format!("key:{}", i)));
+    }
+}
+
+#[test]
+fn test_scan_survives_deletions() {
+    let store = FerroStore::new();
+    for i in 0..100 {
+        store.set(format!("key:{}", i), "v".to_string());
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut cursor = 0;
+    let mut round = 0;
+    loop {
+        let (next, keys) = store.scan(cursor, 5, None, None);
+        seen.extend(keys);
+        // Deleting keys moves others between positions mid-iteration
+        store.delete(&format!("key:{}", round * 2));
+        round += 1;
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+
+    for i in 0..100 {
+        let key = format!("key:{}", i);
+        if i % 2 == 1 || i >= round * 2 {
+            assert!(seen.contains(&key), "{} was never returned", key);
+        }
+    }
+}
+
+// With `dashmap` the cursor also holds a shard, see
+// test_dashmap_scan_resumes_at_cursor
+#[cfg(not(feature = "dashmap"))]
+#[test]
+fn test_scan_resumes_at_cursor() {
+    let store = FerroStore::new();
+    for i in 0..1000 {
+        store.set(format!("key:{}", i), "v".to_string());
+    }
+
+    let (cursor, keys) = store.scan(0, 10, None, None);
+    assert_eq!(keys.len(), 10);
+    assert_eq!(cursor, 990);
+    let (cursor, keys) = store.scan(cursor, 10, None, None);
+    assert_eq!(keys.len(), 10);
+    assert_eq!(cursor, 980);
+
+    // A cursor past the end of a shrunken keyspace continues from the end
+    let (cursor, keys) = store.scan(5000, 10, None, None);
+    assert_eq!((cursor, keys.len()), (990, 10));
+}
+
+#[test]
+fn test_scan_match_and_type() {
+    let store = FerroStore::new();
+    store.set("user:1".to_string(), "a".to_string());
+    store.set("user:2".to_string(), "b".to_string());
+    store.set("order:1".to_string(), "c".to_string());
+    store.lpush("user:list", vec!["x".to_string()]).unwrap();
+
+    let (cursor, mut keys) = store.scan(0, 100, Some("user:*"), Some("string"));
+    keys.sort();
+    assert_eq!(cursor, 0);
+    assert_eq!(keys, vec!["user:1", "user:2"]);
+}
+
+#[test]
+fn test_random_key() {
+    let store = FerroStore::new();
+    assert_eq!(store.random_key(), None);
+
+    store.set("a".to_string(), "1".to_string());
+    store.set("b".to_string(), "2".to_string());
+    store.set("c".to_string(), "3".to_string());
+
+    let mut seen = std::collections::HashSet::new();
+    for _ in 0..200 {
+        seen.insert(store.random_key().unwrap());
+    }
+    assert_eq!(seen.len(), 3);
+
+    // Keys removed in between are never picked
+    for i in 0..1000 {
+        store.set(format!("key{}", i), "v".to_string());
+    }
+    for i in 0..1000 {
+        if i != 500 {
+            store.delete(&format!("key{}", i));
+        }
+    }
+    store.delete("a");
+    store.delete("c");
+    let mut seen = std::collections::HashSet::new();
+    for _ in 0..200 {
+        seen.insert(store.random_key().unwrap());
+    }
+    let mut seen: Vec<_> = seen.into_iter().collect();
+    seen.sort();
+    assert_eq!(seen, ["b", "key500"]);
+}
+
+#[test]
+fn test_touch_and_idle_time() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(store.idle_time("key"), Some(1));
+
+    // TOUCH counts existing keys and resets the idle clock
+    assert_eq!(store.touch(&["key".to_string(), "missing".to_string()]), 1);
+    assert_eq!(store.idle_time("key"), Some(0));
+
+    thread::sleep(Duration::from_millis(1100));
+    // A read is an access too
+    store.get("key");
+    assert_eq!(store.idle_time("key"), Some(0));
+
+    assert_eq!(store.idle_time("missing"), None);
+}
+
+#[test]
+fn test_pexpire_and_pttl() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+    assert_eq!(store.pttl("key"), Some(-1));
+
+    assert!(store.pexpire("key", 1400, ExpireCondition::default()));
+    let pttl = store.pttl("key").unwrap();
+    assert!(pttl > 1000 && pttl <= 1400);
+    // TTL rounds the same remaining time to seconds
+    assert_eq!(store.ttl("key"), Some(1));
+
+    assert!(store.pexpire("key", 50, ExpireCondition::default()));
+    thread::sleep(Duration::from_millis(100));
+    // Expired but not yet reaped reads as -2
+    assert_eq!(store.pttl("key"), Some(-2));
+    assert!(!store.pexpire("missing", 100, ExpireCondition::default()));
+}
+
+#[test]
+fn test_pexpire_at_and_expire_time() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+    assert_eq!(store.pexpire_time("key"), Some(-1));
+    assert_eq!(store.pexpire_time("missing"), None);
+
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let at = now_ms + 10_000;
+    assert!(store.pexpire_at("key", at, ExpireCondition::default()));
+    assert_eq!(store.pexpire_time("key"), Some(at as i64));
+    assert!(store.ttl("key").unwrap() > 8);
+
+    // A timestamp in the past deletes the key
+    assert!(store.pexpire_at("key", now_ms - 1000, ExpireCondition::default()));
+    assert_eq!(store.get("key"), None);
+    assert!(!store.pexpire_at("key", now_ms + 1000, ExpireCondition::default()));
+}
+
+#[test]
+fn test_expire_conditions() {
+    let store = FerroStore::new();
+    store.set("key".to_string(), "value".to_string());
+
+    let gt = ExpireCondition {
+        gt: true,
+        ..Default::default()
+    };
+    let lt = ExpireCondition {
+        lt: true,
+        ..Default::default()
+    };
+    let nx = ExpireCondition {
+        nx: true,
+        ..Default::default()
+    };
+    let xx = ExpireCondition {
+        xx: true,
+        ..Default::default()
+    };
+
+    // No TTL counts as infinite: GT and XX refuse, LT and NX apply
+    assert!(!store.pexpire("key", 10_000, gt));
+    assert!(!store.pexpire("key", 10_000, xx));
+    assert!(store.pexpire("key", 10_000, nx));
+    assert_eq!(store.ttl("key"), Some(10));
+
+    // Extend but never shorten
+    assert!(!store.pexpire("key", 5_000, gt));
+    assert!(store.pexpire("key", 20_000, gt));
+    assert!(!store.pexpire("key", 30_000, lt));
+    assert!(store.pexpire("key", 15_000, lt));
+    assert!(!store.pexpire("key", 1_000, nx));
+    assert_eq!(store.ttl("key"), Some(15));
+}
+
+#[test]
+fn test_lindex_lset_linsert() {
+    let store = FerroStore::new();
+    store
+        .rpush(
+            "list",
+            vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        )
+        .unwrap();
+
+    assert_eq!(store.lindex("list", 0).unwrap(), Some("a".to_string()));
+    assert_eq!(store.lindex("list", -1).unwrap(), Some("c".to_string()));
+    assert_eq!(store.lindex("list", 3).unwrap(), None);
+
+    store.lset("list", -2, "B".to_string()).unwrap();
+    assert_eq!(
+        store.lset("list", 5, "x".to_string()),
+        Err("ERR index out of range".to_string())
+    );
+    assert_eq!(
+        store.lset("missing", 0, "x".to_string()),
+        Err("ERR no such key".to_string())
+    );
+
+    assert_eq!(store.linsert("list", true, "B", "x".to_string()), Ok(4));
+    assert_eq!(store.linsert("list", false, "c", "y".to_string()), Ok(5));
+    assert_eq!(store.linsert("list", true, "zzz", "w".to_string()), Ok(-1));
+    assert_eq!(store.linsert("missing", true, "a", "w".to_string()), Ok(0));
+    assert_eq!(
+        store.lrange("list", 0, -1).unwrap(),
+        vec!["a", "x", "B", "c", "y"]
+    );
+}
+
+#[test]
+fn test_lmove() {
+    let store = FerroStore::new();
+    store
+        .rpush("queue", vec!["job1".to_string(), "job2".to_string()])
+        .unwrap();
+
+    assert_eq!(
+        store.lmove("queue", "processing", ListEnd::Left, ListEnd::Right),
+        Ok(Some("job1".to_string()))
+    );
+    assert_eq!(
+        store.lmove("queue", "processing", ListEnd::Left, ListEnd::Right),
+        Ok(Some("job2".to_string()))
+    );
+    // Source drained and removed
+    assert!(!store.exists("queue"));
+    assert_eq!(
+        store.lmove("queue", "processing", ListEnd::Left, ListEnd::Right),
+        Ok(None)
+    );
+    assert_eq!(
+        store.lrange("processing", 0, -1).unwrap(),
+        vec!["job1", "job2"]
+    );
+
+    // Same key rotates the list
+    store
+        .lmove("processing", "processing", ListEnd::Right, ListEnd::Left)
+        .unwrap();
+    assert_eq!(
+        store.lrange("processing", 0, -1).unwrap(),
+        vec!["job2", "job1"]
+    );
+
+    // Wrong destination type leaves the source untouched
+    store.set("str".to_string(), "v".to_string());
+    assert!(
+        store
+            .lmove("processing", "str", ListEnd::Left, ListEnd::Left)
+            .is_err()
+    );
+    assert_eq!(store.llen("processing"), Ok(2));
+}
+
+#[test]
+fn test_lmpop_and_zmpop() {
+    let store = FerroStore::new();
+    store
+        .rpush(
+            "low",
+            vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        )
+        .unwrap();
+    let keys = vec!["high".to_string(), "low".to_string()];
+
+    // Skips the missing key and pops from the first non-empty one
+    assert_eq!(
+        store.lmpop(&keys, ListEnd::Right, 2),
+        Ok(Some((
+            "low".to_string(),
+            vec!["c".to_string(), "b".to_string()]
+        )))
+    );
+    assert_eq!(
+        store.lmpop(&keys, ListEnd::Left, 10),
+        Ok(Some(("low".to_string(), vec!["a".to_string()])))
+    );
+    assert_eq!(store.lmpop(&keys, ListEnd::Left, 1), Ok(None));
+
+    store
+        .zadd(
+            "jobs",
+            vec![
+                (2.0, "b".to_string()),
+                (1.0, "a".to_string()),
+                (3.0, "c".to_string()),
+            ],
+        )
+        .unwrap();
+    let keys = vec!["jobs".to_string()];
+    assert_eq!(
+        store.zmpop(&keys, ScoreEnd::Min, 2),
+        Ok(Some((
+            "jobs".to_string(),
+            vec![("a".to_string(), 1.0), ("b".to_string(), 2.0)]
+        )))
+    );
+    assert_eq!(
+        store.zmpop(&keys, ScoreEnd::Max, 5),
+        Ok(Some(("jobs".to_string(), vec![("c".to_string(), 3.0)])))
+    );
+    assert!(!store.exists("jobs"));
+}
+
+#[test]
+fn test_smove() {
+    let store = FerroStore::new();
+    store
+        .sadd("pending", vec!["a".to_string(), "b".to_string()])
+        .unwrap();
+
+    assert_eq!(store.smove("pending", "done", "a"), Ok(true));
+    assert_eq!(store.smove("pending", "done", "a"), Ok(false));
+    assert_eq!(store.sismember("done", "a"), Ok(true));
+    assert_eq!(store.sismember("pending", "a"), Ok(false));
+
+    // Moving the last member removes the source
+    assert_eq!(store.smove("pending", "done", "b"), Ok(true));
+    assert!(!store.exists("pending"));
+    assert_eq!(store.scard("done"), Ok(2));
+
+    // Wrong destination type leaves the source untouched
+    store.set("str".to_string(), "v".to_string());
+    assert!(store.smove("done", "str", "a").is_err());
+    assert_eq!(store.sismember("done", "a"), Ok(true));
+}
+
+#[test]
+fn test_sscan_and_zscan_visit_every_member() {
+    let store = FerroStore::new();
+    // Past set-max-listpack-entries and zset-max-listpack-entries
+    let members: Vec<String> = (0..300).map(|i| format!("m{}", i)).collect();
+    store.sadd("set", members.clone()).unwrap();
+    store
+        .zadd(
+            "zset",
+            members
+                .iter()
+                .enumerate()
+                .map(|(i, m)| (i as f64, m.clone()))
+                .collect(),
+        )
+        .unwrap();
+
+    let mut seen = std::collections::HashSet::new();
+    let mut cursor = 0;
+    let mut round = 0;
+    loop {
+        let (next, batch) = store.sscan("set", cursor, 7, None).unwrap();
+        assert!(batch.len() <= 7);
+        seen.extend(batch);
+        // Removing members moves others between positions mid-iteration
+        store.srem("set", vec![format!("m{}", round * 2)]).unwrap();
+        round += 1;
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    for i in 0..300 {
+        let member = format!("m{}", i);
+        if i % 2 == 1 || i >= round * 2 {
+            assert!(seen.contains(&member), "{} was never returned", member);
+        }
+    }
+
+    let mut scores = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, batch) = store.zscan("zset", cursor, 7, Some("m1?")).unwrap();
+        scores.extend(batch.into_iter().map(|(_, score)| score as i64));
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    scores.sort();
+    assert_eq!(scores, (10..20).collect::<Vec<_>>());
+
+    // Each call resumes at its cursor rather than walking the whole set
+    let (cursor, batch) = store.zscan("zset", 0, 10, None).unwrap();
+    assert_eq!((cursor, batch.len()), (290, 10));
+
+    // Small, packed collections come back whole
+    store
+        .sadd("small", vec!["a".to_string(), "b".to_string()])
+        .unwrap();
+    let (cursor, mut batch) = store.sscan("small", 0, 1, None).unwrap();
+    batch.sort();
+    assert_eq!((cursor, batch), (0, vec!["a".to_string(), "b".to_string()]));
+
+    assert_eq!(store.sscan("missing", 0, 10, None), Ok((0, vec![])));
+    assert!(store.zscan("set", 0, 10, None).is_err());
+}
+
+#[test]
+fn test_zrange_by_score_and_zcount() {
+    let store = FerroStore::new();
+    store
+        .zadd(
+            "z",
+            vec![
+                (1.0, "a".to_string()),
+                (2.0, "b".to_string()),
+                (2.0, "c".to_string()),
+                (3.0, "d".to_string()),
+            ],
+        )
+        .unwrap();
+
+    let all = ScoreBound::inclusive(f64::NEG_INFINITY);
+    let inf = ScoreBound::inclusive(f64::INFINITY);
+    assert_eq!(store.zcount("z", all, inf), Ok(4));
+    assert_eq!(
+        store.zcount("z", ScoreBound::exclusive(1.0), ScoreBound::inclusive(2.0)),
+        Ok(2)
+    );
+    // Empty and inverted ranges
+    assert_eq!(
+        store.zcount("z", ScoreBound::exclusive(2.0), ScoreBound::inclusive(2.0)),
+        Ok(0)
+    );
+    assert_eq!(
+        store.zcount("z", ScoreBound::inclusive(3.0), ScoreBound::inclusive(1.0)),
+        Ok(0)
+    );
+
+    // Ties are ordered lexicographically; LIMIT skips and caps
+    assert_eq!(
+        store.zrange_by_score("z", all, ScoreBound::exclusive(3.0), 1, Some(2)),
+        Ok(vec![("b".to_string(), 2.0), ("c".to_string(), 2.0)])
+    );
+    assert_eq!(
+        store.zrange_by_score("missing", all, inf, 0, None),
+        Ok(vec![])
+    );
+}
+
+#[test]
+fn test_zrange_by_lex_and_zlexcount() {
+    let store = FerroStore::new();
+    let words = ["apple", "apricot", "banana", "blueberry", "cherry"];
+    store
+        .zadd("idx", words.iter().map(|w| (0.0, w.to_string())).collect())
+        .unwrap();
+
+    // Prefix lookup: [ap (aq
+    let min = LexBound::Inclusive("ap".to_string());
+    let max = LexBound::Exclusive("aq".to_string());
+    assert_eq!(
+        store.zrange_by_lex("idx", &min, &max, 0, None),
+        Ok(vec!["apple".to_string(), "apricot".to_string()])
+    );
+    assert_eq!(store.zlexcount("idx", &min, &max), Ok(2));
+
+    assert_eq!(
+        store.zrange_by_lex(
+            "idx",
+            &LexBound::Exclusive("banana".to_string()),
+            &LexBound::PosInf,
+            1,
+            Some(5)
+        ),
+        Ok(vec!["cherry".to_string()])
+    );
+    assert_eq!(
+        store.zlexcount("idx", &LexBound::NegInf, &LexBound::PosInf),
+        Ok(5)
+    );
+    assert_eq!(
+        store.zlexcount("idx", &LexBound::PosInf, &LexBound::NegInf),
+        Ok(0)
+    );
+}
+
+#[test]
+fn test_zrank_ties_and_zrevrank() {
+    let store = FerroStore::new();
+    let members: Vec<(f64, String)> = (0..20).map(|i| (1.0, format!("m{:02}", i))).collect();
+    store.zadd("z", members).unwrap();
+    store.zadd("z", vec![(0.0, "low".to_string())]).unwrap();
+
+    // Ties are ranked lexicographically regardless of hash order
+    for i in 0..20 {
+        let member = format!("m{:02}", i);
+        assert_eq!(store.zrank("z", &member), Ok(Some(i + 1)));
+        assert_eq!(store.zrevrank("z", &member), Ok(Some(19 - i)));
+    }
+    assert_eq!(store.zrevrank("z", "low"), Ok(Some(20)));
+    assert_eq!(store.zrevrank("z", "missing"), Ok(None));
+}
+
+#[test]
+fn test_zrange_query_rev() {
+    let store = FerroStore::new();
+    store
+        .zadd(
+            "board",
+            vec![
+                (10.0, "a".to_string()),
+                (30.0, "b".to_string()),
+                (20.0, "c".to_string()),
+                (40.0, "d".to_string()),
+            ],
+        )
+        .unwrap();
+
+    // Top 2 by index
+    let query = ZRangeQuery {
+        rev: true,
+        ..ZRangeQuery::new(ZRangeBy::Index(0, 1))
+    };
+    assert_eq!(
+        store.zrange_query("board", &query),
+        Ok(vec![("d".to_string(), 40.0), ("b".to_string(), 30.0)])
+    );
+
+    // Scores below 40, highest first, skipping one
+    let query = ZRangeQuery {
+        rev: true,
+        offset: 1,
+        count: Some(5),
+        ..ZRangeQuery::new(ZRangeBy::Score(
+            ScoreBound::inclusive(f64::NEG_INFINITY),
+            ScoreBound::exclusive(40.0),
+        ))
+    };
+    assert_eq!(
+        store.zrange_query("board", &query),
+        Ok(vec![("c".to_string(), 20.0), ("a".to_string(), 10.0)])
+    );
+}
+
+#[test]
+fn test_zpop() {
+    let store = FerroStore::new();
+    store
+        .zadd(
+            "pq",
+            vec![
+                (3.0, "c".to_string()),
+                (1.0, "a".to_string()),
+                (2.0, "b".to_string()),
+            ],
+        )
+        .unwrap();
+
+    assert_eq!(
+        store.zpop("pq", ScoreEnd::Max, 1),
+        Ok(vec![("c".to_string(), 3.0)])
+    );
+    assert_eq!(
+        store.zpop("pq", ScoreEnd::Min, 5),
+        Ok(vec![("a".to_string(), 1.0), ("b".to_string(), 2.0)])
+    );
+    assert!(!store.exists("pq"));
+    assert_eq!(store.zpop("pq", ScoreEnd::Min, 1), Ok(vec![]));
+}
+
+#[test]
+fn test_zmscore_and_zrandmember() {
+    let store = FerroStore::new();
+    store
+        .zadd(
+            "z",
+            vec![
+                (1.0, "a".to_string()),
+                (2.0, "b".to_string()),
+                (3.0, "c".to_string()),
+            ],
+        )
+        .unwrap();
+
+    assert_eq!(
+        store.zmscore("z", &["c".to_string(), "nope".to_string(), "a".to_string()]),
+        Ok(vec![Some(3.0), None, Some(1.0)])
+    );
+    assert_eq!(store.zmscore("missing", &["a".to_string()]), Ok(vec![None]));
+
+    // Positive count: distinct, capped at the set size
+    let mut picked: Vec<String> = store
+        .zrandmember("z", 10)
+        .unwrap()
+        .into_iter()
+        .map(|(m, _)| m)
+        .collect();
+    picked.sort();
+    assert_eq!(picked, vec!["a", "b", "c"]);
+    assert_eq!(store.zrandmember("z", 2).unwrap().len(), 2);
+
+    // Negative count: exactly that many, repeats allowed, scores match
+    let sampled = store.zrandmember("z", -20).unwrap();
+    assert_eq!(sampled.len(), 20);
+    assert!(
+        sampled
+            .iter()
+            .all(|(m, score)| store.zscore("z", m) == Ok(Some(*score)))
+    );
+    assert_eq!(store.zrandmember("missing", 3), Ok(vec![]));
+}
+
+#[test]
+fn test_sorted_set_large_rank_and_range() {
+    let store = FerroStore::new();
+    let n = 5000;
+
+    // Scores in a scrambled order, with every score shared by two members
+    let members: Vec<(f64, String)> = (0..n)
+        .map(|i| (((i * 7919) % n / 2) as f64, format!("m{:05}", i)))
+        .collect();
+    store.zadd("board", members.clone()).unwrap();
+
+    let mut expected = members.clone();
+    expected.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+    for (rank, (_, member)) in expected.iter().enumerate().step_by(97) {
+        assert_eq!(store.zrank("board", member), Ok(Some(rank)));
+        assert_eq!(store.zrevrank("board", member), Ok(Some(n - 1 - rank)));
+    }
+
+    let middle = store.zrange("board", 2000, 2004, false).unwrap();
+    let want: Vec<String> = expected[2000..2005]
+        .iter()
+        .map(|(_, m)| m.clone())
+        .collect();
+    assert_eq!(middle, want);
+
+    // Remove every other member and re-check the remaining order
+    let removed: Vec<String> = expected.iter().step_by(2).map(|(_, m)| m.clone()).collect();
+    assert_eq!(store.zrem("board", removed), Ok(n / 2));
+    let remaining: Vec<&(f64, String)> = expected.iter().skip(1).step_by(2).collect();
+    for (rank, (_, member)) in remaining.iter().enumerate().step_by(101) {
+        assert_eq!(store.zrank("board", member), Ok(Some(rank)));
+    }
+
+    // Score updates move members
+    let last = &remaining[remaining.len() - 1].1;
+    store.zadd("board", vec![(-1.0, last.clone())]).unwrap();
+    assert_eq!(store.zrank("board", last), Ok(Some(0)));
+    assert_eq!(
+        store.zcount(
+            "board",
+            ScoreBound::inclusive(100.0),
+            ScoreBound::exclusive(200.0)
+        ),
+        Ok(remaining
+            .iter()
+            .filter(|(s, _)| (100.0..200.0).contains(s))
+            .count())
+    );
+}
+
+#[test]
+fn test_sort() {
+    let store = FerroStore::new();
+    let list: Vec<String> = ["3", "10", "1", "2"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    store.rpush("nums", list).unwrap();
+
+    let values = |opts: &SortOptions| -> Vec<String> {
+        store
+            .sort("nums", opts)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.unwrap_or_else(|| "nil".to_string()))
+            .collect()
+    };
+
+    assert_eq!(values(&SortOptions::default()), vec!["1", "2", "3", "10"]);
+    let alpha_desc = SortOptions {
+        alpha: true,
+        desc: true,
+        ..Default::default()
+    };
+    assert_eq!(values(&alpha_desc), vec!["3", "2", "10", "1"]);
+    let limited = SortOptions {
+        offset: 1,
+        count: Some(2),
+        ..Default::default()
+    };
+    assert_eq!(values(&limited), vec!["2", "3"]);
+
+    // BY and GET patterns; a missing weight counts as 0, a missing GET is nil
+    store.set("weight_1".to_string(), "30".to_string());
+    store.set("weight_2".to_string(), "20".to_string());
+    store.set("weight_3".to_string(), "10".to_string());
+    store.set("obj_1".to_string(), "one".to_string());
+    store.set("obj_3".to_string(), "three".to_string());
+    let by_weight = SortOptions {
+        by: Some("weight_*".to_string()),
+        get: vec!["#".to_string(), "obj_*".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        values(&by_weight),
+        vec!["10", "nil", "3", "three", "2", "nil", "1", "one"]
+    );
+
+    // A BY pattern without `*` keeps the original order
+    let nosort = SortOptions {
+        by: Some("nosort".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(values(&nosort), vec!["3", "10", "1", "2"]);
+
+    store
+        .rpush("words", vec!["b".to_string(), "a".to_string()])
+        .unwrap();
+    assert_eq!(
+        store.sort("words", &SortOptions::default()),
+        Err("ERR One or more scores can't be converted into double".to_string())
+    );
+    assert_eq!(store.sort("missing", &SortOptions::default()), Ok(vec![]));
+
+    // STORE replaces the destination with a list
+    store
+        .sadd("s", vec!["5".to_string(), "4".to_string()])
+        .unwrap();
+    store.set("dest".to_string(), "old".to_string());
+    assert_eq!(
+        store.sort_store("s", "dest", &SortOptions::default()),
+        Ok(2)
+    );
+    assert_eq!(
+        store.lrange("dest", 0, -1),
+        Ok(vec!["4".to_string(), "5".to_string()])
+    );
+    assert_eq!(
+        store.sort_store("missing", "dest", &SortOptions::default()),
+        Ok(0)
+    );
+    assert!(!store.exists("dest"));
+}
+
+#[test]
+fn test_watched_key_versions() {
+    let store = FerroStore::new();
+    store.rpush("list", vec!["a".to_string()]).unwrap();
+
+    let watch = store.versions().watch("list");
+    let untouched = store.versions().watch("other");
+    assert!(!watch.is_modified());
+
+    // Reads and no-op writes leave the version alone
+    store.lrange("list", 0, -1).unwrap();
+    store.srem("other", vec!["x".to_string()]).unwrap();
+    assert!(!watch.is_modified());
+    assert!(!untouched.is_modified());
+
+    store.lpop("list", None).unwrap();
+    assert!(watch.is_modified());
+    assert!(!untouched.is_modified());
+
+    // Creating a watched key counts as a modification too
+    store.set("other".to_string(), "v".to_string());
+    assert!(untouched.is_modified());
+}
+
+#[test]
+fn test_used_memory_accounting() {
+    let store = FerroStore::new();
+    let empty = store.used_memory();
+    assert_eq!(empty, 0);
+
+    store.set("key".to_string(), "v".repeat(1000));
+    let one = store.used_memory();
+    assert!(one >= 1000);
+    store
+        .rpush("list", (0..100).map(|i| format!("{:0100}", i)).collect())
+        .unwrap();
+    let both = store.used_memory();
+    assert!(both >= one + 100 * 100);
+
+    // Overwrites, moves and deletions give memory back
+    store.set("key".to_string(), "v".to_string());
+    assert!(store.used_memory() <= both - 999);
+    store.move_key("list", 1).unwrap();
+    assert!(store.used_memory() >= 100 * 100);
+    store.flush_all();
+    assert_eq!(store.used_memory(), 0);
+}
+
+#[test]
+fn test_volatile_eviction_samples_keys_with_ttl() {
+    use FerroDB::config::MaxmemoryPolicy;
+
+    let store = FerroStore::new();
+    for i in 0..10_000 {
+        store.set(format!("key{}", i), "v".to_string());
+    }
+    store.set("volatile".to_string(), "v".to_string());
+    store.expire("volatile", 100);
+
+    // A single sample still finds the one key with a TTL
+    assert_eq!(
+        store.evict(MaxmemoryPolicy::VolatileTtl, 1),
+        Some((0, "volatile".to_string()))
+    );
+    assert_eq!(store.evict(MaxmemoryPolicy::VolatileTtl, 1), None);
+    assert_eq!(store.dbsize(), 10_000);
+}
+
+#[test]
+fn test_eviction_pool_keeps_oldest_candidates() {
+    use FerroDB::config::MaxmemoryPolicy;
+
+    let store = FerroStore::new();
+    for i in 0..10 {
+        store
+            .sadd(&format!("key{}", i), vec!["member".to_string()])
+            .unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+    // Set algebra counts as an access
+    store
+        .sunion((5..10).map(|i| format!("key{}", i)).collect())
+        .unwrap();
+
+    // The first eviction sees every key; later ones sample a single key but
+    // still pick the idlest candidates remembered from it
+    assert!(store.evict(MaxmemoryPolicy::AllKeysLru, 64).is_some());
+    for _ in 0..4 {
+        let (_, key) = store.evict(MaxmemoryPolicy::AllKeysLru, 1).unwrap();
+        assert!(key.as_str() < "key5", "evicted recently used {}", key);
+    }
+    for i in 5..10 {
+        assert!(store.exists(&format!("key{}", i)));
+    }
+
+    // Candidates deleted since they were sampled are skipped
+    store.flush_all();
+    store.set("only".to_string(), "v".to_string());
+    assert_eq!(
+        store.evict(MaxmemoryPolicy::AllKeysLru, 1),
+        Some((0, "only".to_string()))
+    );
+    assert_eq!(store.evict(MaxmemoryPolicy::AllKeysLru, 1), None);
+}
+
+#[test]
+fn test_small_collections_are_packed() {
+    let store = FerroStore::new();
+    let config = |name: &str, value: &str| {
+        store
+            .config()
+            .set(&[(name.to_string(), value.to_string())])
+            .unwrap()
+    };
+    config("list-max-listpack-size", "4");
+    config("set-max-listpack-entries", "3");
+    config("zset-max-listpack-value", "8");
+
+    let items = |n: usize| (0..n).map(|i| format!("v{}", i)).collect::<Vec<_>>();
+    store.rpush("list", items(3)).unwrap();
+    store.lpush("list", vec!["first".to_string()]).unwrap();
+    assert_eq!(store.lpop("list", None).unwrap(), vec!["first"]);
+    store
+        .linsert("list", false, "v1", "mid".to_string())
+        .unwrap();
+    store.lset("list", -1, "last".to_string()).unwrap();
+    assert_eq!(store.encoding("list"), Some("listpack"));
+    store.rpush("list", items(1)).unwrap();
+    assert_eq!(store.encoding("list"), Some("linkedlist"));
+    assert_eq!(
+        store.lrange("list", 0, -1).unwrap(),
+        vec!["v0", "v1", "mid", "last", "v0"]
+    );
+
+    store.sadd("set", items(3)).unwrap();
+    assert_eq!(store.sadd("set", items(3)).unwrap(), 0);
+    assert_eq!(store.encoding("set"), Some("listpack"));
+    assert!(store.srem("set", vec!["v1".to_string()]).unwrap() == 1);
+    assert!(!store.sismember("set", "v1").unwrap());
+    store.sadd("set", items(4)).unwrap();
+    assert_eq!(store.encoding("set"), Some("hashtable"));
+    assert_eq!(store.scard("set").unwrap(), 4);
+
+    let zadd = |member: &str, score: f64| store.zadd("zset", vec![(score, member.to_string())]);
+    zadd("b", 2.0).unwrap();
+    zadd("a", 2.0).unwrap();
+    zadd("c", 1e300).unwrap();
+    zadd("d", -0.5).unwrap();
+    zadd("c", 1.5).unwrap();
+    assert_eq!(store.encoding("zset"), Some("listpack"));
+    assert_eq!(store.zscore("zset", "c").unwrap(), Some(1.5));
+    assert_eq!(store.zrank("zset", "a").unwrap(), Some(2));
+    assert_eq!(
+        store.zrange("zset", 0, -1, false).unwrap(),
+        ["d", "c", "a", "b"]
+    );
+    zadd("a long member", 0.0).unwrap();
+    assert_eq!(store.encoding("zset"), Some("skiplist"));
+    assert_eq!(
+        store.zrange("zset", 0, -1, false).unwrap(),
+        ["d", "a long member", "c", "a", "b"]
+    );
+
+    // Restored values are packed again when they fit
+    let dump = store.get_value("set").unwrap();
+    store.srem("set", items(2)).unwrap();
+    store
+        .restore("copy".to_string(), dump, None, false)
+        .unwrap();
+    assert_eq!(store.encoding("copy"), Some("hashtable"));
+    let dump = store.get_value("set").unwrap();
+    store.restore("copy".to_string(), dump, None, true).unwrap();
+    assert_eq!(store.encoding("copy"), Some("listpack"));
+    assert_eq!(store.encoding("missing"), None);
+}
+
+#[test]
+fn test_integer_strings_are_stored_as_integers() {
+    let store = FerroStore::new();
+    for (value, encoding) in [
+        ("42", "int"),
+        ("-9223372036854775808", "int"),
+        ("0", "int"),
+        ("007", "embstr"),
+        ("+5", "embstr"),
+        ("-0", "embstr"),
+        ("9223372036854775808", "embstr"),
+        (" 1", "embstr"),
+    ] {
+        store.set("key".to_string(), value.to_string());
+        assert_eq!(store.encoding("key"), Some(encoding), "{}", value);
+        assert_eq!(store.get("key").as_deref(), Some(value));
+    }
+
+    store.set("key".to_string(), "12".to_string());
+    let dump = store.get_value("key").unwrap();
+    store
+        .restore("copy".to_string(), dump, None, false)
+        .unwrap();
+    assert_eq!(store.encoding("copy"), Some("int"));
+    assert_eq!(store.getdel("copy").unwrap().as_deref(), Some("12"));
+}
+
+#[cfg(feature = "dashmap")]
+#[test]
+fn test_dashmap_scan_resumes_at_cursor() {
+    let store = FerroStore::new();
+    for i in 0..1000 {
+        store.set(format!("key:{}", i), "v".to_string());
+    }
+
+    let (cursor, first) = store.scan(0, 10, None, None);
+    assert_eq!(first.len(), 10);
+    assert_ne!(cursor, 0);
+    let (_, second) = store.scan(cursor, 10, None, None);
+    assert_eq!(second.len(), 10);
+    assert!(second.iter().all(|key| !first.contains(key)));
+
+    // Keys added in other shards meanwhile don't move the ones still to visit
+    let mut seen = std::collections::HashSet::new();
+    let mut cursor = 0;
+    let mut added = 0;
+    loop {
+        let (next, keys) = store.scan(cursor, 25, None, None);
+        seen.extend(keys);
+        store.set(format!("added:{}", added), "v".to_string());
+        added += 1;
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    for i in 0..1000 {
+        assert!(
+            seen.contains(&format!("key:{}", i)),
+            "key:{} was never returned",
+            i
+        );
+    }
+}
+
+#[cfg(feature = "dashmap")]
+#[test]
+fn test_dashmap_concurrent_single_key_commands() {
+    let store = FerroStore::new();
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let won = store.setnx("winner".to_string(), t.to_string());
+                for i in 0..500 {
+                    store.rpush("list", vec![format!("{}:{}", t, i)]).unwrap();
+                    store.set(format!("own:{}", t), i.to_string());
+                    assert!(store.get("winner").is_some());
+                }
+                won
+            })
+        })
+        .collect();
+    let winners = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .filter(|&won| won)
+        .count();
+
+    // Each command saw and changed its key as a whole
+    assert_eq!(winners, 1);
+    assert_eq!(store.llen("list").unwrap(), 4000);
+    for t in 0..8 {
+        assert_eq!(store.get(&format!("own:{}", t)).as_deref(), Some("499"));
+    }
+    assert_eq!(store.dbsize(), 10);
+}
+
+#[cfg(feature = "dashmap")]
+#[test]
+fn test_dashmap_concurrent_multi_key_commands() {
+    let store = FerroStore::new();
+    let members: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+    store.sadd("left", members.clone()).unwrap();
+
+    let movers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            let members = members.clone();
+            thread::spawn(move || {
+                for round in 0..20 {
+                    let (src, dst) = if (t + round) % 2 == 0 {
+                        ("left", "right")
+                    } else {
+                        ("right", "left")
+                    };
+                    for member in &members {
+                        store.smove(src, dst, member).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..500 {
+                    store
+                        .sadd(&format!("other:{}", t), vec![i.to_string()])
+                        .unwrap();
+                }
+                assert!(store.msetnx(vec![(format!("fresh:{}", t), "v".to_string())]));
+            })
+        })
+        .collect();
+    for thread in movers.into_iter().chain(writers) {
+        thread.join().unwrap();
+    }
+
+    // Every member moved whole from one set to the other
+    let left = store.scard("left").unwrap();
+    let right = store.scard("right").unwrap();
+    assert_eq!(left + right, 100);
+    for t in 0..4 {
+        assert_eq!(store.scard(&format!("other:{}", t)).unwrap(), 500);
+        assert!(store.exists(&format!("fresh:{}", t)));
+    }
+}
+
+#[cfg(feature = "dashmap")]
+#[test]
+fn test_dashmap_delete_expired_keys_while_writing() {
+    let store = FerroStore::new();
+    for i in 0..1000 {
+        store.set_with_expiry_ms(format!("expiring:{}", i), "v".to_string(), 1);
+        store.set(format!("kept:{}", i), "v".to_string());
+    }
+    thread::sleep(Duration::from_millis(10));
+
+    let expirers: Vec<_> = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || store.delete_expired_keys())
+        })
+        .collect();
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in (t..1000).step_by(4) {
+                    assert_eq!(store.get(&format!("expiring:{}", i)), None);
+                    store.set(format!("kept:{}", i), "new".to_string());
+                }
+            })
+        })
+        .collect();
+    let deleted: usize = expirers
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .sum();
+    for thread in writers {
+        thread.join().unwrap();
+    }
+
+    // Each expired key was deleted once, by the cycle or a lookup
+    assert!(deleted <= 1000);
+    assert_eq!(store.dbsize(), 1000);
+    for i in 0..1000 {
+        assert_eq!(store.get(&format!("kept:{}", i)).as_deref(), Some("new"));
+    }
+}
@@ -0,0 +1,170 @@
+use FerroDB::commands::handle_command;
+use FerroDB::config::{ConfigValues, TlsAuthClients};
+use FerroDB::protocol::parse_resp;
+use FerroDB::storage::FerroStore;
+use FerroDB::tls::acceptor;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+use std::fs;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, crypto};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// PEM files for a CA and the server and client certificates it signs
+struct Certificates {
+    ca: String,
+    server_cert: String,
+    server_key: String,
+    client_cert: String,
+    client_key: String,
+}
+
+fn write_certificates(dir: &str) -> Certificates {
+    fs::create_dir_all(dir).unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+    let issuer = Issuer::new(ca_params, ca_key);
+
+    let path = |name: &str| format!("{}/{}", dir, name);
+    let certs = Certificates {
+        ca: path("ca.pem"),
+        server_cert: path("server.pem"),
+        server_key: path("server.key"),
+        client_cert: path("client.pem"),
+        client_key: path("client.key"),
+    };
+    fs::write(&certs.ca, ca_cert.pem()).unwrap();
+    for (name, cert_path, key_path) in [
+        ("localhost", &certs.server_cert, &certs.server_key),
+        ("client", &certs.client_cert, &certs.client_key),
+    ] {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &issuer)
+            .unwrap();
+        fs::write(cert_path, cert.pem()).unwrap();
+        fs::write(key_path, key.serialize_pem()).unwrap();
+    }
+    certs
+}
+
+fn server_config(certs: &Certificates, auth: TlsAuthClients) -> ConfigValues {
+    ConfigValues {
+        tls_port: 6380,
+        tls_cert_file: certs.server_cert.clone(),
+        tls_key_file: certs.server_key.clone(),
+        tls_ca_cert_file: certs.ca.clone(),
+        tls_auth_clients: auth,
+        ..ConfigValues::default()
+    }
+}
+
+/// Serve one TLS client, answering each command it sends
+async fn serve_one(acceptor: TlsAcceptor) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let Ok(mut stream) = acceptor.accept(socket).await else {
+            return;
+        };
+        let store = FerroStore::new();
+        let mut buffer = [0u8; 1024];
+        while let Ok(n) = stream.read(&mut buffer).await {
+            if n == 0 {
+                break;
+            }
+            let command = parse_resp(&String::from_utf8_lossy(&buffer[..n])).unwrap();
+            let response = handle_command(command, &store, None, None).await;
+            stream
+                .write_all(response.encode().as_bytes())
+                .await
+                .unwrap();
+        }
+    });
+    port
+}
+
+/// Send PING over TLS, optionally presenting the client certificate
+async fn ping(port: u16, certs: &Certificates, with_client_cert: bool) -> std::io::Result<String> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(&certs.ca).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let builder = ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let config = if with_client_cert {
+        let chain = CertificateDer::pem_file_iter(&certs.client_cert)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_file(&certs.client_key).unwrap();
+        builder.with_client_auth_cert(chain, key).unwrap()
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let socket = TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), socket)
+        .await?;
+    stream.write_all(b"*1\r\n$4\r\nPING\r\n").await?;
+    let mut buffer = [0u8; 64];
+    let n = stream.read(&mut buffer).await?;
+    Ok(String::from_utf8_lossy(&buffer[..n]).to_string())
+}
+
+#[tokio::test]
+async fn test_tls_ping() {
+    let certs = write_certificates("/tmp/test_FerroDB_tls_ping");
+    let acceptor = acceptor(&server_config(&certs, TlsAuthClients::No)).unwrap();
+    let port = serve_one(acceptor).await;
+    assert_eq!(ping(port, &certs, false).await.unwrap(), "+PONG\r\n");
+    let _ = fs::remove_dir_all("/tmp/test_FerroDB_tls_ping");
+}
+
+#[tokio::test]
+async fn test_tls_auth_clients() {
+    let certs = write_certificates("/tmp/test_FerroDB_tls_auth");
+
+    // yes: a certificate signed by the CA is required
+    let config = server_config(&certs, TlsAuthClients::Yes);
+    let port = serve_one(acceptor(&config).unwrap()).await;
+    // With TLS 1.3 the rejection may only surface on the first read
+    assert!(!matches!(ping(port, &certs, false).await, Ok(reply) if reply == "+PONG\r\n"));
+    let port = serve_one(acceptor(&config).unwrap()).await;
+    assert_eq!(ping(port, &certs, true).await.unwrap(), "+PONG\r\n");
+
+    // optional: a certificate is checked if sent
+    let config = server_config(&certs, TlsAuthClients::Optional);
+    let port = serve_one(acceptor(&config).unwrap()).await;
+    assert_eq!(ping(port, &certs, false).await.unwrap(), "+PONG\r\n");
+    let _ = fs::remove_dir_all("/tmp/test_FerroDB_tls_auth");
+}
+
+#[test]
+fn test_tls_acceptor_errors() {
+    let certs = write_certificates("/tmp/test_FerroDB_tls_errors");
+    let config = ConfigValues {
+        tls_port: 6380,
+        ..ConfigValues::default()
+    };
+    assert!(acceptor(&config).is_err());
+
+    let mut config = server_config(&certs, TlsAuthClients::Yes);
+    config.tls_ca_cert_file = String::new();
+    assert!(acceptor(&config).is_err());
+
+    let mut config = server_config(&certs, TlsAuthClients::No);
+    config.tls_key_file = "/tmp/test_FerroDB_tls_errors/missing.key".to_string();
+    assert!(acceptor(&config).is_err());
+    let _ = fs::remove_dir_all("/tmp/test_FerroDB_tls_errors");
+}